@@ -429,7 +429,9 @@ local function resolve_provider(domain, routing_domain)
       'unspecified',
       mx.site_name
     )
-    return path_config.provider_name
+    -- Fall back to the provider definitions, if the shaping layer
+    -- didn't identify the provider
+    return path_config.provider_name or kumo.identify_provider(routing_domain)
   end)
   if is_ok then
     return result
@@ -443,6 +445,26 @@ local cached_resolve_provider = kumo.memoize(resolve_provider, {
   capacity = 128,
 })
 
+-- Returns the egress pool that the traffic shaping data defines for the
+-- destination, such as one applied by a tsa-daemon SwitchPool action,
+-- or nil if there is none, or the shaping helper is not in use.
+local function resolve_egress_pool_override(domain, routing_domain)
+  local shaping = package.loaded['policy-extras.shaping']
+  if not shaping or not shaping.CONFIGURED then
+    return nil
+  end
+  local is_ok, result = pcall(function()
+    local routing_domain = routing_domain or domain
+    local mx = kumo.dns.lookup_mx(routing_domain)
+    local data = shaping.CONFIGURED.load_shaping_data()
+    return data:get_egress_pool(routing_domain, mx.site_name)
+  end)
+  if is_ok then
+    return result
+  end
+  return nil
+end
+
 -- Given a domain and optional routing_domain (set to nil if you have
 -- no explicit routing domain), determine the provider_name that would
 -- be set by the shaping layer by speculatively evaluating the egress
@@ -493,6 +515,10 @@ function mod:setup_with_options(options)
             params.provider_name =
               cached_resolve_provider(domain, routing_domain)
           end
+          local pool = resolve_egress_pool_override(domain, routing_domain)
+          if pool then
+            params.egress_pool = pool
+          end
           return kumo.make_queue_config(params)
         end
       end
@@ -195,15 +195,22 @@ pub struct BounceClassifier {
 
 impl BounceClassifier {
     pub fn classify_str(&self, s: &str) -> BounceClass {
-        self.set
-            .matches(s)
-            .into_iter()
-            .next()
-            .and_then(|idx| self.pattern_to_class.get(idx))
-            .cloned()
-            .unwrap_or(BounceClass::PreDefined(
-                PreDefinedBounceClass::Uncategorized,
-            ))
+        self.explain_str(s).0
+    }
+
+    /// Classifies `s`, returning the classification along with the
+    /// rule that produced it, or None if no rule matched
+    pub fn explain_str(&self, s: &str) -> (BounceClass, Option<&str>) {
+        match self.set.matches(s).into_iter().next() {
+            Some(idx) => (
+                self.pattern_to_class[idx].clone(),
+                Some(self.set.patterns()[idx].as_str()),
+            ),
+            None => (
+                BounceClass::PreDefined(PreDefinedBounceClass::Uncategorized),
+                None,
+            ),
+        }
     }
 
     pub fn classify_response(&self, response: &rfc5321::Response) -> BounceClass {
@@ -258,6 +265,14 @@ second_file = ["bbb", "ccc"]
             classifier.classify_str("ccc"),
             BounceClass::UserDefined("second_file".to_string()),
         );
+        assert_eq!(
+            classifier.explain_str("xbbbx"),
+            (BounceClass::UserDefined("bar".to_string()), Some("bbb"))
+        );
+        assert_eq!(
+            classifier.explain_str("ddd"),
+            (PreDefinedBounceClass::Uncategorized.into(), None)
+        );
     }
 
     #[test]
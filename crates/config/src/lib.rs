@@ -1,7 +1,9 @@
 use crate::pool::{pool_get, pool_put};
 pub use crate::pool::{set_gc_on_put, set_max_age, set_max_spare, set_max_use};
 use anyhow::Context;
-use mlua::{FromLua, FromLuaMulti, IntoLuaMulti, Lua, LuaSerdeExt, RegistryKey, Table, Value};
+use mlua::{
+    FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Lua, LuaSerdeExt, RegistryKey, Table, Value,
+};
 use parking_lot::FairMutex as Mutex;
 use prometheus::{CounterVec, HistogramTimer, HistogramVec};
 use serde::Serialize;
@@ -283,6 +285,13 @@ impl LuaConfig {
         }
     }
 
+    /// Provides access to the underlying lua instance, for example,
+    /// to retrieve values that were stored in its registry when
+    /// the policy was loaded
+    pub fn with_lua<R>(&self, func: impl FnOnce(&Lua) -> R) -> R {
+        (func)(&self.inner.as_ref().unwrap().lua)
+    }
+
     pub fn remove_registry_value(&mut self, value: RegistryKey) -> anyhow::Result<()> {
         Ok(self
             .inner
@@ -482,6 +491,24 @@ where
     })
 }
 
+/// Wraps a serializable value so that it can be passed to a lua
+/// callback, or a deserializable value so that it can be returned
+/// from one.
+#[derive(Clone, Debug)]
+pub struct SerdeWrappedValue<T>(pub T);
+
+impl<T: Serialize> IntoLua for SerdeWrappedValue<T> {
+    fn into_lua(self, lua: &Lua) -> mlua::Result<Value> {
+        lua.to_value_with(&self.0, serialize_options())
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> FromLua for SerdeWrappedValue<T> {
+    fn from_lua(value: Value, lua: &Lua) -> mlua::Result<Self> {
+        Ok(Self(from_lua_value(lua, value)?))
+    }
+}
+
 /// CallbackSignature is a bit sugar to aid with statically typing event callback
 /// function invocation.
 ///
@@ -0,0 +1,112 @@
+//! Fetches keys from AWS Secrets Manager and KMS.
+//! Credentials and region are resolved using the standard AWS
+//! provider chain, so that an IAM role associated with the instance,
+//! task or pod can be used rather than long lived credentials.
+use crate::VersionedKey;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_sdk_secretsmanager::error::DisplayErrorContext;
+use data_encoding::BASE64;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+/// The loaded configuration for each region. The configuration
+/// holds the credentials provider, which caches and refreshes
+/// the credentials, so we want to re-use it.
+static SDK_CONFIGS: LazyLock<Mutex<HashMap<Option<String>, SdkConfig>>> =
+    LazyLock::new(Mutex::default);
+
+async fn sdk_config(region: &Option<String>) -> SdkConfig {
+    if let Some(config) = SDK_CONFIGS.lock().unwrap().get(region) {
+        return config.clone();
+    }
+
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = region {
+        loader = loader.region(Region::new(region.clone()));
+    }
+    let config = loader.load().await;
+
+    SDK_CONFIGS
+        .lock()
+        .unwrap()
+        .insert(region.clone(), config.clone());
+    config
+}
+
+pub async fn get_secret_value(
+    secret_id: &str,
+    region: &Option<String>,
+    version_stage: &Option<String>,
+    secret_key: &Option<String>,
+) -> anyhow::Result<VersionedKey> {
+    let client = aws_sdk_secretsmanager::Client::new(&sdk_config(region).await);
+    let mut request = client.get_secret_value().secret_id(secret_id);
+    if let Some(stage) = version_stage {
+        request = request.version_stage(stage);
+    }
+    let output = request.send().await.map_err(|err| {
+        anyhow::anyhow!("GetSecretValue {secret_id}: {}", DisplayErrorContext(err))
+    })?;
+
+    let data = if let Some(string) = output.secret_string() {
+        string.as_bytes().to_vec()
+    } else if let Some(binary) = output.secret_binary() {
+        binary.as_ref().to_vec()
+    } else {
+        anyhow::bail!("secret {secret_id} has no value");
+    };
+
+    let data = match secret_key {
+        Some(key) => {
+            let object: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&data)
+                .map_err(|err| {
+                    anyhow::anyhow!("secret {secret_id} is not a JSON object: {err:#}")
+                })?;
+            match object.get(key) {
+                Some(serde_json::Value::String(value)) => value.as_bytes().to_vec(),
+                Some(_) => anyhow::bail!("field {key} of secret {secret_id} is not a string"),
+                None => anyhow::bail!("secret {secret_id} has no field {key}"),
+            }
+        }
+        None => data,
+    };
+
+    Ok(VersionedKey {
+        data,
+        version: output.version_id().map(|v| v.to_string()),
+    })
+}
+
+pub async fn decrypt(
+    ciphertext: &str,
+    key_id: &Option<String>,
+    region: &Option<String>,
+) -> anyhow::Result<VersionedKey> {
+    let blob = BASE64
+        .decode(ciphertext.trim().as_bytes())
+        .map_err(|err| anyhow::anyhow!("aws_kms_ciphertext is not valid base64: {err:#}"))?;
+
+    let client = aws_sdk_kms::Client::new(&sdk_config(region).await);
+    let mut request = client
+        .decrypt()
+        .ciphertext_blob(aws_sdk_kms::primitives::Blob::new(blob));
+    if let Some(key_id) = key_id {
+        request = request.key_id(key_id);
+    }
+    let output = request
+        .send()
+        .await
+        .map_err(|err| anyhow::anyhow!("KMS Decrypt: {}", DisplayErrorContext(err)))?;
+
+    let data = output
+        .plaintext()
+        .ok_or_else(|| anyhow::anyhow!("KMS Decrypt returned no plaintext"))?
+        .as_ref()
+        .to_vec();
+
+    // The ciphertext is immutable, so there is no version to track
+    Ok(VersionedKey {
+        data,
+        version: None,
+    })
+}
@@ -0,0 +1,62 @@
+//! Fetches keys from GCP Secret Manager.
+//! Credentials are resolved using Application Default Credentials,
+//! so that the service account associated with the instance or
+//! workload can be used rather than a key file.
+use crate::VersionedKey;
+use data_encoding::BASE64;
+use gcp_auth::TokenProvider;
+use serde::Deserialize;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::OnceCell;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+static PROVIDER: OnceCell<Arc<dyn TokenProvider>> = OnceCell::const_new();
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+#[derive(Deserialize)]
+struct AccessSecretVersionResponse {
+    /// The resource name of the version that was accessed,
+    /// which includes the version number, even when the
+    /// latest version was requested
+    name: String,
+    payload: SecretPayload,
+}
+
+#[derive(Deserialize)]
+struct SecretPayload {
+    data: String,
+}
+
+pub async fn access_secret_version(
+    project: &str,
+    secret: &str,
+    version: &Option<String>,
+) -> anyhow::Result<VersionedKey> {
+    let provider = PROVIDER
+        .get_or_try_init(|| async { gcp_auth::provider().await })
+        .await?;
+    let token = provider.token(&[SCOPE]).await?;
+
+    let version = version.as_deref().unwrap_or("latest");
+    let url = format!(
+        "https://secretmanager.googleapis.com/v1/projects/{project}/\
+         secrets/{secret}/versions/{version}:access"
+    );
+    let response = CLIENT.get(&url).bearer_auth(token.as_str()).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("{url}: {status}: {body}");
+    }
+
+    let result: AccessSecretVersionResponse = response.json().await?;
+    let data = BASE64
+        .decode(result.payload.data.as_bytes())
+        .map_err(|err| anyhow::anyhow!("{url}: payload is not valid base64: {err:#}"))?;
+
+    Ok(VersionedKey {
+        data,
+        version: Some(result.name),
+    })
+}
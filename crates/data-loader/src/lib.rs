@@ -3,10 +3,61 @@ use anyhow::{anyhow, Context};
 #[cfg(feature = "impl")]
 use config::{any_err, from_lua_value, get_or_create_sub_module};
 #[cfg(feature = "impl")]
+use lruttl::LruCacheWithTtl;
+#[cfg(feature = "impl")]
 use mlua::Lua;
+#[cfg(feature = "impl")]
+use prometheus::IntCounterVec;
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "impl")]
-use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+use std::collections::HashSet;
+#[cfg(feature = "impl")]
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+#[cfg(feature = "impl")]
+use std::time::Instant;
+
+#[cfg(feature = "impl")]
+mod aws;
+#[cfg(feature = "impl")]
+mod gcp;
+#[cfg(feature = "impl")]
+mod vault;
+
+/// How long the data from a cloud secret store is cached,
+/// if the source doesn't specify a cache_ttl
+#[cfg(feature = "impl")]
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+#[cfg(feature = "impl")]
+static KEY_CACHE: LazyLock<LruCacheWithTtl<KeySource, CachedKey>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("data_loader_key_cache", 1024));
+/// The sources that are currently being refreshed in the background
+#[cfg(feature = "impl")]
+static KEY_REFRESHING: LazyLock<Mutex<HashSet<KeySource>>> = LazyLock::new(Mutex::default);
+/// The most recently seen version of each versioned source,
+/// used to detect rotation
+#[cfg(feature = "impl")]
+static KEY_VERSIONS: LazyLock<LruCacheWithTtl<KeySource, String>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("data_loader_key_versions", 1024));
+#[cfg(feature = "impl")]
+static KEY_ROTATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "keysource_rotations",
+        "how many times a new version of a key was detected, by the kind of keysource",
+        &["source"]
+    )
+    .unwrap()
+});
+#[cfg(feature = "impl")]
+static KEY_REFRESH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "keysource_refresh_failures",
+        "how many background refreshes of a cached key failed, by the kind of keysource",
+        &["source"]
+    )
+    .unwrap()
+});
 
 #[derive(Deserialize, Serialize, Clone, Hash, PartialEq, Eq, Debug)]
 #[serde(untagged)]
@@ -20,7 +71,76 @@ pub enum KeySource {
         vault_token: Option<String>,
         vault_mount: String,
         vault_path: String,
+        /// If set, the key is cached, and refreshed ahead of expiry
+        #[serde(default, with = "duration_serde")]
+        cache_ttl: Option<Duration>,
+    },
+    AwsSecretsManager {
+        aws_secret_id: String,
+        aws_region: Option<String>,
+        /// The staging label of the version to fetch,
+        /// AWSCURRENT if not specified
+        aws_version_stage: Option<String>,
+        /// If set, the secret is a JSON object and the key
+        /// data is the value of this field
+        aws_secret_key: Option<String>,
+        #[serde(default, with = "duration_serde")]
+        cache_ttl: Option<Duration>,
+    },
+    AwsKms {
+        /// The base64 encoded ciphertext to be decrypted
+        aws_kms_ciphertext: String,
+        aws_kms_key_id: Option<String>,
+        aws_region: Option<String>,
+        #[serde(default, with = "duration_serde")]
+        cache_ttl: Option<Duration>,
     },
+    GcpSecretManager {
+        gcp_project: String,
+        gcp_secret: String,
+        /// The version to fetch, latest if not specified
+        gcp_version: Option<String>,
+        #[serde(default, with = "duration_serde")]
+        cache_ttl: Option<Duration>,
+    },
+}
+
+impl KeySource {
+    /// Returns the kind of source, for use as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::File(_) => "file",
+            Self::Data { .. } => "data",
+            Self::Vault { .. } => "vault",
+            Self::AwsSecretsManager { .. } => "aws_secrets_manager",
+            Self::AwsKms { .. } => "aws_kms",
+            Self::GcpSecretManager { .. } => "gcp_secret_manager",
+        }
+    }
+}
+
+/// Key data fetched from a remote store, along with
+/// an identifier for its version, if the store has one
+#[cfg(feature = "impl")]
+struct VersionedKey {
+    data: Vec<u8>,
+    version: Option<String>,
+}
+
+#[cfg(feature = "impl")]
+#[derive(Clone)]
+struct CachedKey {
+    data: Arc<Vec<u8>>,
+    refresh_at: Instant,
+}
+
+/// Returns how long to wait before refreshing something that
+/// expires after ttl: somewhere between 60% and 80% of the ttl,
+/// so that the refreshes of items that were loaded at the same
+/// time, such as at startup, are spread out
+#[cfg(feature = "impl")]
+fn refresh_delay(ttl: Duration) -> Duration {
+    ttl.mul_f64(0.6 + 0.2 * rand::random::<f64>())
 }
 
 #[cfg(feature = "impl")]
@@ -29,11 +149,102 @@ impl KeySource {
         match self {
             Self::File(path) => Ok(tokio::fs::read(path).await?),
             Self::Data { key_data } => Ok(key_data.as_bytes().to_vec()),
+            Self::AwsSecretsManager { cache_ttl, .. }
+            | Self::AwsKms { cache_ttl, .. }
+            | Self::GcpSecretManager { cache_ttl, .. } => {
+                self.get_cached(cache_ttl.unwrap_or(DEFAULT_CACHE_TTL))
+                    .await
+            }
+            Self::Vault {
+                cache_ttl: Some(ttl),
+                ..
+            } => self.get_cached(*ttl).await,
+            Self::Vault {
+                cache_ttl: None, ..
+            } => Ok(self.fetch_versioned().await?.data),
+        }
+    }
+
+    /// Returns the cached data if possible, otherwise fetches it.
+    /// Once the data is due for refresh, it is fetched again in the
+    /// background, while the cached data continues to be returned.
+    async fn get_cached(&self, ttl: Duration) -> anyhow::Result<Vec<u8>> {
+        if let Some(cached) = KEY_CACHE.get(self) {
+            if Instant::now() >= cached.refresh_at {
+                self.refresh_in_background(ttl);
+            }
+            return Ok(cached.data.to_vec());
+        }
+        self.fetch_and_cache(ttl).await
+    }
+
+    fn refresh_in_background(&self, ttl: Duration) {
+        if !KEY_REFRESHING.lock().unwrap().insert(self.clone()) {
+            return;
+        }
+        let source = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = source.fetch_and_cache(ttl).await {
+                // The cached data remains in use until it expires,
+                // after which the next get will fetch it directly
+                KEY_REFRESH_FAILURES
+                    .with_label_values(&[source.kind()])
+                    .inc();
+                tracing::error!("refreshing key: {err:#}");
+            }
+            KEY_REFRESHING.lock().unwrap().remove(&source);
+        });
+    }
+
+    async fn fetch_and_cache(&self, ttl: Duration) -> anyhow::Result<Vec<u8>> {
+        let fetched = self.fetch_versioned().await?;
+        if let Some(version) = fetched.version {
+            self.check_rotation(version);
+        }
+        if !ttl.is_zero() {
+            let now = Instant::now();
+            KEY_CACHE.insert(
+                self.clone(),
+                CachedKey {
+                    data: Arc::new(fetched.data.clone()),
+                    refresh_at: now + refresh_delay(ttl),
+                },
+                now + ttl,
+            );
+        }
+        Ok(fetched.data)
+    }
+
+    async fn fetch_versioned(&self) -> anyhow::Result<VersionedKey> {
+        match self {
+            Self::AwsSecretsManager {
+                aws_secret_id,
+                aws_region,
+                aws_version_stage,
+                aws_secret_key,
+                ..
+            } => {
+                aws::get_secret_value(aws_secret_id, aws_region, aws_version_stage, aws_secret_key)
+                    .await
+            }
+            Self::AwsKms {
+                aws_kms_ciphertext,
+                aws_kms_key_id,
+                aws_region,
+                ..
+            } => aws::decrypt(aws_kms_ciphertext, aws_kms_key_id, aws_region).await,
+            Self::GcpSecretManager {
+                gcp_project,
+                gcp_secret,
+                gcp_version,
+                ..
+            } => gcp::access_secret_version(gcp_project, gcp_secret, gcp_version).await,
             Self::Vault {
                 vault_address,
                 vault_token,
                 vault_mount,
                 vault_path,
+                ..
             } => {
                 let address = match vault_address {
                     Some(a) => a.to_string(),
@@ -51,30 +262,29 @@ impl KeySource {
                         )
                     })?,
                 };
+                vault::read(&address, &token, vault_mount, vault_path).await
+            }
+            Self::File(_) | Self::Data { .. } => {
+                anyhow::bail!("{self:?} is not a remote key source")
+            }
+        }
+        .with_context(|| format!("{self:?}"))
+    }
 
-                let client = VaultClient::new(
-                    VaultClientSettingsBuilder::default()
-                        .address(address)
-                        .token(token)
-                        .build()?,
-                )?;
-
-                #[derive(Deserialize, Debug)]
-                struct Entry {
-                    key: String,
-                }
-
-                let entry: Entry = vaultrs::kv2::read(&client, vault_mount, vault_path)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "kv2::read vault_mount={vault_mount}, vault_path={vault_path} {self:?}"
-                        )
-                    })?;
-
-                Ok(entry.key.into())
+    /// Records the version of the key, and reports when it differs
+    /// from the version that was previously seen
+    fn check_rotation(&self, version: String) {
+        if let Some(previous) = KEY_VERSIONS.get(self) {
+            if previous != version {
+                KEY_ROTATIONS.with_label_values(&[self.kind()]).inc();
+                tracing::info!("{self:?} was rotated from version {previous} to {version}");
             }
         }
+        KEY_VERSIONS.insert(
+            self.clone(),
+            version,
+            Instant::now() + Duration::from_secs(86400),
+        );
     }
 }
 
@@ -252,6 +462,7 @@ mod test {
                 vault_token: Some(KEY.to_string()),
                 vault_mount: "secret".to_string(),
                 vault_path: path.to_string(),
+                cache_ttl: None,
             }
         }
     }
@@ -276,6 +487,48 @@ mod test {
         }
     }
 
+    #[test]
+    fn cloud_sources() {
+        let source: KeySource = serde_json::from_value(serde_json::json!({
+            "aws_secret_id": "dkim/example.com",
+            "aws_region": "us-east-1",
+            "cache_ttl": "1m",
+        }))
+        .unwrap();
+        assert_eq!(
+            source,
+            KeySource::AwsSecretsManager {
+                aws_secret_id: "dkim/example.com".to_string(),
+                aws_region: Some("us-east-1".to_string()),
+                aws_version_stage: None,
+                aws_secret_key: None,
+                cache_ttl: Some(Duration::from_secs(60)),
+            }
+        );
+        assert_eq!(source.kind(), "aws_secrets_manager");
+
+        let source: KeySource = serde_json::from_value(serde_json::json!({
+            "aws_kms_ciphertext": "AQICAHh...",
+        }))
+        .unwrap();
+        assert_eq!(source.kind(), "aws_kms");
+
+        let source: KeySource = serde_json::from_value(serde_json::json!({
+            "gcp_project": "my-project",
+            "gcp_secret": "dkim-example-com",
+        }))
+        .unwrap();
+        assert_eq!(
+            source,
+            KeySource::GcpSecretManager {
+                gcp_project: "my-project".to_string(),
+                gcp_secret: "dkim-example-com".to_string(),
+                gcp_version: None,
+                cache_ttl: None,
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_vault() -> anyhow::Result<()> {
         if which::which("vault").is_err() {
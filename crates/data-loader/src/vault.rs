@@ -0,0 +1,118 @@
+//! Reads keys from HashiCorp Vault, and keeps the tokens that
+//! are used to do so alive by renewing them ahead of their expiry.
+use crate::{refresh_delay, VersionedKey};
+use anyhow::Context;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+
+/// The (address, token) pairs that have a renewal task
+static RENEWING: LazyLock<Mutex<HashSet<(String, String)>>> = LazyLock::new(Mutex::default);
+
+static TOKEN_RENEWALS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "keysource_vault_token_renewals",
+        "how many times a vault token was renewed, by outcome",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+fn make_client(address: &str, token: &str) -> anyhow::Result<VaultClient> {
+    Ok(VaultClient::new(
+        VaultClientSettingsBuilder::default()
+            .address(address)
+            .token(token)
+            .build()?,
+    )?)
+}
+
+pub async fn read(
+    address: &str,
+    token: &str,
+    vault_mount: &str,
+    vault_path: &str,
+) -> anyhow::Result<VersionedKey> {
+    let client = make_client(address, token)?;
+    ensure_token_renewal(address, token);
+
+    #[derive(Deserialize, Debug)]
+    struct Entry {
+        key: String,
+    }
+
+    let entry: Entry = vaultrs::kv2::read(&client, vault_mount, vault_path)
+        .await
+        .with_context(|| format!("kv2::read vault_mount={vault_mount}, vault_path={vault_path}"))?;
+
+    Ok(VersionedKey {
+        data: entry.key.into(),
+        version: None,
+    })
+}
+
+/// Starts a task to renew the token, if there isn't one already
+fn ensure_token_renewal(address: &str, token: &str) {
+    let key = (address.to_string(), token.to_string());
+    if !RENEWING.lock().unwrap().insert(key.clone()) {
+        return;
+    }
+    tokio::spawn(async move {
+        let (address, token) = &key;
+        if let Err(err) = renew_token(address, token).await {
+            tracing::error!("vault token renewal for {address}: {err:#}");
+        }
+        // Allow a subsequent read to try again, for example, if the
+        // token was replaced with one of the same value
+        RENEWING.lock().unwrap().remove(&key);
+    });
+}
+
+/// Renews the token ahead of its expiry, for as long as it remains
+/// renewable.  Returns Ok when the token does not need renewing,
+/// or can no longer be renewed, and Err if it expired because
+/// renewal failed.
+async fn renew_token(address: &str, token: &str) -> anyhow::Result<()> {
+    let client = make_client(address, token)?;
+    let info = vaultrs::token::lookup_self(&client)
+        .await
+        .context("token lookup_self")?;
+    if !info.renewable || info.ttl == 0 {
+        // Root tokens and periodic tokens without a ttl don't expire,
+        // and non-renewable tokens cannot be extended
+        return Ok(());
+    }
+
+    let mut ttl = Duration::from_secs(info.ttl);
+    let mut expires = Instant::now() + ttl;
+    loop {
+        tokio::time::sleep(refresh_delay(ttl)).await;
+
+        match vaultrs::token::renew_self(&client, None).await {
+            Ok(auth) => {
+                TOKEN_RENEWALS.with_label_values(&["ok"]).inc();
+                if !auth.renewable || auth.lease_duration == 0 {
+                    return Ok(());
+                }
+                ttl = Duration::from_secs(auth.lease_duration);
+                expires = Instant::now() + ttl;
+            }
+            Err(err) => {
+                TOKEN_RENEWALS.with_label_values(&["failed"]).inc();
+                let remaining = expires.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    anyhow::bail!("token expired; last renewal error: {err:#}");
+                }
+                tracing::warn!(
+                    "vault token renewal for {address} failed, \
+                     will retry within {remaining:?}: {err:#}"
+                );
+                // Try again within the remaining lifetime of the token
+                ttl = remaining;
+            }
+        }
+    }
+}
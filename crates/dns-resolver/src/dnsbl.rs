@@ -0,0 +1,310 @@
+//! Queries DNS based block lists for an IP address, as described
+//! by <https://datatracker.ietf.org/doc/html/rfc5782>
+use crate::{get_query_timeout, get_resolver, IpDisplay, Resolver};
+use hickory_resolver::proto::rr::RecordType;
+use hickory_resolver::Name;
+use lruttl::LruCacheWithTtl;
+use prometheus::IntCounterVec;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, LazyLock};
+
+static DNSBL_CACHE: LazyLock<LruCacheWithTtl<Name, Arc<Vec<IpAddr>>>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("dns_resolver_dnsbl", 64 * 1024));
+
+static DNSBL_CHECKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "dnsbl_checks",
+        "total number of addresses checked against a DNSBL",
+        &["zone"]
+    )
+    .unwrap()
+});
+static DNSBL_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "dnsbl_hits",
+        "total number of addresses that were listed by a DNSBL",
+        &["zone"]
+    )
+    .unwrap()
+});
+static DNSBL_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "dnsbl_errors",
+        "total number of DNSBL checks that failed",
+        &["zone"]
+    )
+    .unwrap()
+});
+
+/// A DNSBL to be checked
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum DnsblList {
+    /// Just the zone name, with the default weight, and
+    /// considering any return code to be a hit
+    Zone(String),
+    Detailed {
+        zone: String,
+        /// How much a hit adds to the score
+        #[serde(default = "default_weight")]
+        weight: f64,
+        /// If not empty, only these return codes are considered
+        /// to be a hit
+        #[serde(default)]
+        codes: Vec<Ipv4Addr>,
+    },
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+impl DnsblList {
+    pub fn zone(&self) -> &str {
+        match self {
+            Self::Zone(zone) | Self::Detailed { zone, .. } => zone,
+        }
+    }
+
+    fn weight(&self) -> f64 {
+        match self {
+            Self::Zone(_) => default_weight(),
+            Self::Detailed { weight, .. } => *weight,
+        }
+    }
+
+    fn is_hit(&self, code: &Ipv4Addr) -> bool {
+        match self {
+            Self::Zone(_) => true,
+            Self::Detailed { codes, .. } => codes.is_empty() || codes.contains(code),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct DnsblResult {
+    /// The sum of the weights of the lists that listed the address
+    pub score: f64,
+    /// The return codes of the lists that listed the address,
+    /// keyed by zone
+    pub hits: BTreeMap<String, Vec<String>>,
+    /// The lists that could not be checked, keyed by zone
+    pub errors: BTreeMap<String, String>,
+}
+
+impl DnsblResult {
+    pub fn is_listed(&self) -> bool {
+        !self.hits.is_empty()
+    }
+}
+
+/// Produces the name that is queried to check addr against zone
+fn query_name(addr: IpAddr, zone: &str) -> anyhow::Result<Name> {
+    let zone = zone.trim_end_matches('.');
+    let mut name = Name::from_utf8(format!(
+        "{}.{zone}",
+        IpDisplay {
+            ip: addr,
+            reverse: true
+        }
+    ))?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+/// Returns the A records for name, which are the return codes of the list
+async fn lookup_codes(resolver: &dyn Resolver, name: Name) -> anyhow::Result<Arc<Vec<IpAddr>>> {
+    if let Some(codes) = DNSBL_CACHE.get(&name) {
+        return Ok(codes);
+    }
+
+    let answer = match get_query_timeout() {
+        Some(timeout) => {
+            match tokio::time::timeout(timeout, resolver.resolve(name.clone(), RecordType::A)).await
+            {
+                Ok(answer) => answer?,
+                Err(_) => anyhow::bail!("DNS query for {name} timed out after {timeout:?}"),
+            }
+        }
+        None => resolver.resolve(name.clone(), RecordType::A).await?,
+    };
+    if answer.bogus {
+        anyhow::bail!(
+            "DNS query for {name} returned bogus result: {}",
+            answer.why_bogus.as_deref().unwrap_or("")
+        );
+    }
+
+    let codes = Arc::new(answer.as_addr());
+    DNSBL_CACHE.insert(name, codes.clone(), answer.expires);
+    Ok(codes)
+}
+
+/// Checks addr against a single list, returning the return codes
+/// that are considered to be a hit
+async fn check_list(
+    resolver: &dyn Resolver,
+    addr: IpAddr,
+    list: &DnsblList,
+) -> anyhow::Result<Vec<Ipv4Addr>> {
+    let name = query_name(addr, list.zone())?;
+    let codes = lookup_codes(resolver, name).await?;
+
+    let mut hits = vec![];
+    for code in codes.iter() {
+        let IpAddr::V4(code) = code else {
+            continue;
+        };
+        // By convention, 127.255.255.0/24 is used to report errors,
+        // such as the query being refused because it came via a public
+        // resolver, rather than listing the address
+        if matches!(code.octets(), [127, 255, 255, _]) {
+            anyhow::bail!("{} returned error code {code}", list.zone());
+        }
+        if list.is_hit(code) {
+            hits.push(*code);
+        }
+    }
+    Ok(hits)
+}
+
+async fn check_with_resolver(
+    resolver: &dyn Resolver,
+    addr: IpAddr,
+    lists: &[DnsblList],
+) -> DnsblResult {
+    let results = futures::future::join_all(
+        lists
+            .iter()
+            .map(|list| async move { (list, check_list(resolver, addr, list).await) }),
+    )
+    .await;
+
+    let mut result = DnsblResult::default();
+    for (list, outcome) in results {
+        let zone = list.zone().to_string();
+        DNSBL_CHECKS.with_label_values(&[&zone]).inc();
+        match outcome {
+            Ok(codes) if codes.is_empty() => {}
+            Ok(codes) => {
+                DNSBL_HITS.with_label_values(&[&zone]).inc();
+                result.score += list.weight();
+                result
+                    .hits
+                    .insert(zone, codes.iter().map(|c| c.to_string()).collect());
+            }
+            Err(err) => {
+                DNSBL_ERRORS.with_label_values(&[&zone]).inc();
+                result.errors.insert(zone, format!("{err:#}"));
+            }
+        }
+    }
+    result
+}
+
+/// Checks addr against each of the lists concurrently.
+/// Results are cached according to the TTL of the DNS records.
+pub async fn check(addr: IpAddr, lists: &[DnsblList]) -> DnsblResult {
+    let resolver = get_resolver();
+    check_with_resolver(&**resolver, addr, lists).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TestResolver;
+
+    fn resolver() -> TestResolver {
+        TestResolver::default()
+            .with_zone(
+                r#"
+$ORIGIN bl.example.com.
+2.0.0.127 600 A 127.0.0.2
+10.2.0.192 600 A 127.0.0.4
+10.2.0.192 600 A 127.0.0.10
+11.2.0.192 600 A 127.255.255.254
+"#,
+            )
+            .with_zone(
+                r#"
+$ORIGIN other.example.net.
+10.2.0.192 600 A 127.0.0.2
+"#,
+            )
+    }
+
+    #[test]
+    fn query_names() {
+        assert_eq!(
+            query_name("192.0.2.10".parse().unwrap(), "bl.example.com.")
+                .unwrap()
+                .to_string(),
+            "10.2.0.192.bl.example.com."
+        );
+        assert_eq!(
+            query_name("2001:db8::1".parse().unwrap(), "bl.example.com")
+                .unwrap()
+                .to_string(),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.example.com."
+        );
+    }
+
+    #[tokio::test]
+    async fn scoring() {
+        let resolver = resolver();
+        let lists = vec![
+            DnsblList::Detailed {
+                zone: "bl.example.com".to_string(),
+                weight: 2.5,
+                codes: vec![],
+            },
+            DnsblList::Zone("other.example.net".to_string()),
+        ];
+
+        let result = check_with_resolver(&resolver, "192.0.2.10".parse().unwrap(), &lists).await;
+        assert_eq!(result.score, 3.5);
+        assert_eq!(
+            result.hits["bl.example.com"],
+            vec!["127.0.0.4".to_string(), "127.0.0.10".to_string()]
+        );
+        assert!(result.errors.is_empty());
+        let name = query_name("192.0.2.10".parse().unwrap(), "bl.example.com").unwrap();
+        assert!(DNSBL_CACHE.get(&name).is_some());
+
+        let result = check_with_resolver(&resolver, "192.0.2.99".parse().unwrap(), &lists).await;
+        assert_eq!(result, DnsblResult::default());
+        assert!(!result.is_listed());
+    }
+
+    #[tokio::test]
+    async fn return_codes() {
+        let resolver = resolver();
+        let lists = vec![DnsblList::Detailed {
+            zone: "bl.example.com".to_string(),
+            weight: 1.0,
+            codes: vec![Ipv4Addr::new(127, 0, 0, 10)],
+        }];
+        let result = check_with_resolver(&resolver, "192.0.2.10".parse().unwrap(), &lists).await;
+        assert_eq!(
+            result.hits["bl.example.com"],
+            vec!["127.0.0.10".to_string()]
+        );
+
+        let lists = vec![DnsblList::Detailed {
+            zone: "bl.example.com".to_string(),
+            weight: 1.0,
+            codes: vec![Ipv4Addr::new(127, 0, 0, 2)],
+        }];
+        let result = check_with_resolver(&resolver, "192.0.2.10".parse().unwrap(), &lists).await;
+        assert!(!result.is_listed());
+
+        let result = check_with_resolver(&resolver, "192.0.2.11".parse().unwrap(), &lists).await;
+        assert!(!result.is_listed());
+        assert_eq!(
+            result.errors["bl.example.com"],
+            "bl.example.com returned error code 127.255.255.254"
+        );
+    }
+}
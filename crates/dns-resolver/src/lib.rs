@@ -10,13 +10,17 @@ use rand::prelude::SliceRandom;
 use serde::Serialize;
 use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock, Mutex as StdMutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+pub mod dnsbl;
 mod resolver;
 #[cfg(feature = "unbound")]
 pub use resolver::UnboundResolver;
-pub use resolver::{ptr_host, DnsError, HickoryResolver, IpDisplay, Resolver, TestResolver};
+pub use resolver::{
+    ptr_host, Answer, DnsError, HickoryResolver, IpDisplay, Resolver, TestResolver,
+};
 
 // An `ArcSwap` can only hold `Sized` types, so we cannot stuff a `dyn Resolver` directly into it.
 // Instead, the documentation recommends adding a level of indirection, so we wrap the `Resolver`
@@ -117,6 +121,42 @@ pub fn fully_qualify(domain_name: &str) -> ResolveResult<Name> {
     Ok(name)
 }
 
+/// When non-zero, the number of milliseconds to wait for the
+/// resolver to answer each query before giving up
+static QUERY_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets an overall time limit for each query made by the lookup
+/// functions in this module. This is applied in addition to any
+/// timeouts configured for the resolver itself.
+pub fn set_query_timeout(timeout: Option<Duration>) {
+    QUERY_TIMEOUT_MS.store(
+        timeout.map_or(0, |t| t.as_millis().max(1) as u64),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn get_query_timeout() -> Option<Duration> {
+    match QUERY_TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+async fn resolve_with_timeout(name: Name, rrtype: RecordType) -> anyhow::Result<Answer> {
+    let resolver = RESOLVER.load_full();
+    match get_query_timeout() {
+        Some(timeout) => {
+            match tokio::time::timeout(timeout, resolver.resolve(name.clone(), rrtype)).await {
+                Ok(answer) => Ok(answer?),
+                Err(_) => {
+                    anyhow::bail!("DNS query for {name} {rrtype} timed out after {timeout:?}")
+                }
+            }
+        }
+        None => Ok(resolver.resolve(name, rrtype).await?),
+    }
+}
+
 pub fn reconfigure_resolver(resolver: impl Resolver) {
     RESOLVER.store(Arc::new(Box::new(resolver)));
 }
@@ -125,11 +165,18 @@ pub fn get_resolver() -> Arc<Box<dyn Resolver>> {
     RESOLVER.load_full()
 }
 
+/// Queries the NS records of the root zone, bypassing the caches,
+/// in order to verify that the configured resolver is responsive
+pub async fn check_resolver() -> anyhow::Result<()> {
+    get_resolver().resolve(Name::root(), RecordType::NS).await?;
+    Ok(())
+}
+
 /// Resolves TLSA records for a destination name and port according to
 /// <https://datatracker.ietf.org/doc/html/rfc6698#appendix-B.2>
 pub async fn resolve_dane(hostname: &str, port: u16) -> anyhow::Result<Vec<TLSA>> {
     let name = fully_qualify(&format!("_{port}._tcp.{hostname}"))?;
-    let answer = RESOLVER.load().resolve(name, RecordType::TLSA).await?;
+    let answer = resolve_with_timeout(name, RecordType::TLSA).await?;
     tracing::info!("resolve_dane {hostname}:{port} TLSA answer is: {answer:?}");
 
     if answer.bogus {
@@ -432,10 +479,7 @@ struct ByPreference {
 }
 
 async fn lookup_mx_record(domain_name: &Name) -> anyhow::Result<(Vec<ByPreference>, Instant)> {
-    let mx_lookup = RESOLVER
-        .load()
-        .resolve(domain_name.clone(), RecordType::MX)
-        .await?;
+    let mx_lookup = resolve_with_timeout(domain_name.clone(), RecordType::MX).await?;
     let mx_records = mx_lookup.records;
 
     if mx_records.is_empty() {
@@ -540,10 +584,7 @@ pub async fn ipv4_lookup(key: &str) -> anyhow::Result<(Arc<Vec<IpAddr>>, Instant
         return Ok(value);
     }
 
-    let answer = RESOLVER
-        .load()
-        .resolve(key_fq.clone(), RecordType::A)
-        .await?;
+    let answer = resolve_with_timeout(key_fq.clone(), RecordType::A).await?;
     let ips = answer.as_addr();
 
     let ips = Arc::new(ips);
@@ -561,10 +602,7 @@ pub async fn ipv6_lookup(key: &str) -> anyhow::Result<(Arc<Vec<IpAddr>>, Instant
         return Ok(value);
     }
 
-    let answer = RESOLVER
-        .load()
-        .resolve(key_fq.clone(), RecordType::AAAA)
-        .await?;
+    let answer = resolve_with_timeout(key_fq.clone(), RecordType::AAAA).await?;
     let ips = answer.as_addr();
 
     let ips = Arc::new(ips);
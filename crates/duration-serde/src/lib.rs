@@ -93,6 +93,16 @@ impl<'de> Deserialize<'de> for Wrap<Option<Duration>> {
     }
 }
 
+impl<'de> Deserialize<'de> for Wrap<Vec<Duration>> {
+    fn deserialize<D>(d: D) -> Result<Wrap<Vec<Duration>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let durations = Vec::<Wrap<Duration>>::deserialize(d)?;
+        Ok(Wrap(durations.into_iter().map(|Wrap(dur)| dur).collect()))
+    }
+}
+
 impl<'a> Serialize for Wrap<&'a Duration> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -136,6 +146,24 @@ impl Serialize for Wrap<Option<Duration>> {
     }
 }
 
+impl<'a> Serialize for Wrap<&'a Vec<Duration>> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(self.0.iter().map(Wrap))
+    }
+}
+
+impl Serialize for Wrap<Vec<Duration>> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Wrap(&self.0).serialize(serializer)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -180,4 +208,22 @@ mod test {
         let foo = serde_json::from_str::<Foo>(json).unwrap();
         assert_eq!(foo.time, Duration::from_secs(15));
     }
+
+    #[test]
+    fn list() {
+        #[derive(Serialize, Deserialize)]
+        struct Foo {
+            #[serde(with = "super")]
+            times: Vec<Duration>,
+        }
+
+        let json = r#"{"times": ["4 hours", 86400]}"#;
+        let foo = serde_json::from_str::<Foo>(json).unwrap();
+        assert_eq!(
+            foo.times,
+            vec![Duration::from_secs(4 * 3600), Duration::from_secs(86400)]
+        );
+        let reverse = serde_json::to_string(&foo).unwrap();
+        assert_eq!(reverse, r#"{"times":["4h","1day"]}"#);
+    }
 }
@@ -151,6 +151,7 @@ impl DaemonWithMaildir {
         let source = KumoDaemon::spawn(KumoArgs {
             policy_file: "source.lua".to_string(),
             env,
+            args: vec![],
         })
         .await
         .context("KumoDaemon::spawn")?;
@@ -267,6 +268,8 @@ pub struct KumoDaemon {
 pub struct KumoArgs {
     pub policy_file: String,
     pub env: Vec<(String, String)>,
+    /// Additional command line arguments for kumod
+    pub args: Vec<String>,
 }
 
 impl KumoDaemon {
@@ -274,6 +277,7 @@ impl KumoDaemon {
         KumoDaemon::spawn(KumoArgs {
             policy_file: "maildir-sink.lua".to_string(),
             env: vec![],
+            args: vec![],
         })
         .await
     }
@@ -282,6 +286,7 @@ impl KumoDaemon {
         KumoDaemon::spawn(KumoArgs {
             policy_file: policy_file.as_ref().to_string_lossy().to_string(),
             env: vec![],
+            args: vec![],
         })
         .await
     }
@@ -291,6 +296,7 @@ impl KumoDaemon {
         KumoDaemon::spawn(KumoArgs {
             policy_file: "sink.lua".to_string(),
             env: vec![],
+            args: vec![],
         })
         .await
     }
@@ -306,6 +312,7 @@ impl KumoDaemon {
 
         let mut cmd = Command::new(&path);
         cmd.args(["--policy", &args.policy_file, "--user", &user.name])
+            .args(&args.args)
             .env(
                 "KUMOD_LOG",
                 "kumod=trace,kumo_server_common=info,kumo_server_runtime=info,amqprs=trace,warn",
@@ -409,6 +416,14 @@ impl KumoDaemon {
         }
     }
 
+    /// Waits for the daemon to exit of its own accord
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .context("daemon didn't exit")??;
+        Ok(())
+    }
+
     pub fn listener(&self, service: &str) -> SocketAddr {
         match self.listeners.get(service) {
             Some(addr) => *addr,
@@ -616,6 +631,7 @@ impl DaemonWithMaildirAndWebHook {
                     format!("{}s", batch_params.max_batch_latency),
                 ),
             ],
+            args: vec![],
         })
         .await?;
 
@@ -661,6 +677,7 @@ impl DaemonWithTsa {
                     tsa_listener.port().to_string(),
                 ),
             ],
+            args: vec![],
         })
         .await?;
 
@@ -18,6 +18,7 @@ mod suspend_delivery_ready_q;
 mod suspend_delivery_ready_q_and_deliver;
 mod suspend_delivery_scheduled_q;
 mod suspend_delivery_scheduled_q_and_deliver;
+mod takeover;
 mod temp_fail;
 mod tls_opportunistic_fail;
 mod tls_opportunistic_reconnect;
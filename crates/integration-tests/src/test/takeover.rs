@@ -0,0 +1,76 @@
+use crate::kumod::{KumoArgs, KumoDaemon, MailGenParams};
+use rfc5321::{SmtpClient, SmtpClientTimeouts};
+use std::time::Duration;
+
+/// Verify that a new instance can take over the listeners and spool
+/// of a running instance, without connections being refused or told
+/// to wait for spool startup in the meantime
+#[tokio::test]
+async fn takeover() -> anyhow::Result<()> {
+    let handoff_dir = tempfile::tempdir()?;
+    let handoff = handoff_dir
+        .path()
+        .join("handoff.sock")
+        .to_string_lossy()
+        .to_string();
+
+    let mut old = KumoDaemon::spawn(KumoArgs {
+        policy_file: "maildir-sink.lua".to_string(),
+        env: vec![],
+        args: vec!["--handoff-socket".to_string(), handoff.clone()],
+    })
+    .await?;
+    let smtp = old.listener("smtp");
+
+    let mut client = old.smtp_client("localhost").await?;
+    let response = MailGenParams {
+        recip: Some("first@example.com"),
+        ..Default::default()
+    }
+    .send(&mut client)
+    .await?;
+    anyhow::ensure!(response.code == 250);
+    drop(client);
+
+    // The new instance uses the same spool and maildir as the old one
+    let new = tokio::spawn(KumoDaemon::spawn(KumoArgs {
+        policy_file: "maildir-sink.lua".to_string(),
+        env: vec![(
+            "KUMOD_TEST_DIR".to_string(),
+            old.dir.path().to_string_lossy().to_string(),
+        )],
+        args: vec![
+            "--handoff-socket".to_string(),
+            handoff,
+            "--takeover".to_string(),
+        ],
+    }));
+
+    // Having handed over its listeners, the old instance shuts down,
+    // which releases the spool so that the new instance can start
+    old.wait_for_exit(Duration::from_secs(30)).await?;
+
+    // A connection made while the new instance is starting waits
+    // until it is ready to accept mail
+    let mut client = SmtpClient::new(smtp, SmtpClientTimeouts::short_timeouts()).await?;
+    let banner_timeout = client.timeouts().banner_timeout;
+    let banner = client.read_response(None, banner_timeout).await?;
+    anyhow::ensure!(banner.code == 220, "unexpected banner: {banner:#?}");
+    client.ehlo("localhost").await?;
+
+    let mut new = new.await??;
+    assert_eq!(new.listener("smtp"), smtp);
+
+    let response = MailGenParams {
+        recip: Some("second@example.com"),
+        ..Default::default()
+    }
+    .send(&mut client)
+    .await?;
+    anyhow::ensure!(response.code == 250);
+
+    assert!(old.wait_for_maildir_count(2, Duration::from_secs(10)).await);
+
+    new.stop().await?;
+    Ok(())
+}
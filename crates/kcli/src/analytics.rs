@@ -0,0 +1,95 @@
+use clap::{Parser, ValueEnum};
+use kumo_api_types::analytics::{AnalyticsDimension, AnalyticsV1Entry, AnalyticsV1Request};
+use num_format::{Locale, ToFormattedString};
+use reqwest::Url;
+use tabout::{Alignment, Column};
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Window {
+    #[value(name = "5m")]
+    FiveMinutes,
+    #[value(name = "1h")]
+    OneHour,
+    #[value(name = "24h")]
+    OneDay,
+}
+
+#[derive(Debug, Parser)]
+/// Shows the rolling counts of accepted, delivered, deferred, bounced
+/// and complaint messages for each provider, egress pool and tenant.
+///
+/// Analytics must have been enabled via `kumo.configure_analytics`
+/// for this command to work.
+///
+/// The default output shows a table of the counts over the window
+/// selected by --window. The structured output formats include the
+/// counts for all of the windows.
+pub struct AnalyticsCommand {
+    /// Only show the counts for this dimension;
+    /// one of provider, pool or tenant
+    #[arg(long)]
+    dimension: Option<AnalyticsDimension>,
+
+    /// Only show the counts for this provider, pool or tenant name
+    #[arg(long)]
+    name: Option<String>,
+
+    /// The window over which the counts are shown in the table
+    #[arg(long, value_enum, default_value = "1h")]
+    window: Window,
+}
+
+impl AnalyticsCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/analytics/v1")?;
+        let request = AnalyticsV1Request {
+            dimension: self.dimension,
+            name: self.name.clone(),
+        };
+        request.apply_to_url(&mut url);
+
+        let result: Vec<AnalyticsV1Entry> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        crate::output::print_table_or(&result, || {
+            let mut columns = vec![
+                Column {
+                    name: "DIMENSION".to_string(),
+                    alignment: Alignment::Left,
+                },
+                Column {
+                    name: "NAME".to_string(),
+                    alignment: Alignment::Left,
+                },
+            ];
+            for name in ["ACCEPTED", "DELIVERED", "DEFERRED", "BOUNCED", "COMPLAINT"] {
+                columns.push(Column {
+                    name: name.to_string(),
+                    alignment: Alignment::Right,
+                });
+            }
+
+            let mut rows = vec![];
+            for entry in &result {
+                let counts = match self.window {
+                    Window::FiveMinutes => &entry.last_5m,
+                    Window::OneHour => &entry.last_1h,
+                    Window::OneDay => &entry.last_24h,
+                };
+                let mut row = vec![entry.dimension.to_string(), entry.name.clone()];
+                for count in [
+                    counts.accepted,
+                    counts.delivered,
+                    counts.deferred,
+                    counts.bounced,
+                    counts.complaint,
+                ] {
+                    row.push(count.to_formatted_string(&Locale::en));
+                }
+                rows.push(row);
+            }
+            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,53 @@
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use kumo_api_types::{AuditLogV1Entry, AuditLogV1Request};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the most recent administrative actions from the audit log.
+///
+/// Each mutating request made to the admin API is recorded,
+/// along with the identity of the caller and the parameters
+/// of the request.
+pub struct AuditLogCommand {
+    /// Only return entries at or after this time,
+    /// in RFC 3339 format
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only return entries before this time,
+    /// in RFC 3339 format
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+
+    /// Only return entries for this actor
+    #[arg(long)]
+    actor: Option<String>,
+
+    /// Only return entries whose path starts with this prefix
+    #[arg(long)]
+    path: Option<String>,
+
+    /// The maximum number of entries to return
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+impl AuditLogCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/audit/v1")?;
+        let request = AuditLogV1Request {
+            since: self.since,
+            until: self.until,
+            actor: self.actor.clone(),
+            path: self.path.clone(),
+            limit: self.limit,
+        };
+        request.apply_to_url(&mut url);
+
+        let result: Vec<AuditLogV1Entry> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        crate::output::print(&result)
+    }
+}
@@ -1,3 +1,5 @@
+use crate::rebind::name_equals_value;
+use clap::builder::ValueParser;
 use clap::Parser;
 use kumo_api_types::{BounceV1Request, BounceV1Response};
 use reqwest::Url;
@@ -42,6 +44,12 @@ pub struct BounceCommand {
     #[arg(long)]
     tenant: Option<String>,
 
+    /// Only match messages with this metadata value.
+    /// The key must be declared via `kumo.configure_meta_index`.
+    /// Can be used multiple times, in which case all must match.
+    #[arg(long, value_name="KEY=VALUE", value_parser=ValueParser::new(name_equals_value))]
+    meta: Vec<(String, String)>,
+
     /// The reason to log in the delivery logs (each matching message will
     /// bounce with an AdminBounce record) as well as in the list
     /// of bounces.
@@ -68,10 +76,11 @@ impl BounceCommand {
             && self.campaign.is_none()
             && self.tenant.is_none()
             && self.routing_domain.is_none()
+            && self.meta.is_empty()
         {
             if !self.everything {
                 anyhow::bail!(
-                    "No domain, routing_domain, campaign or tenant was specified. \
+                    "No domain, routing_domain, campaign, tenant or meta was specified. \
                      Use --everything if you intend to purge all queues"
                 );
             }
@@ -85,6 +94,7 @@ impl BounceCommand {
                 domain: self.domain.clone(),
                 routing_domain: self.routing_domain.clone(),
                 tenant: self.tenant.clone(),
+                meta: self.meta.iter().cloned().collect(),
                 reason: self.reason.clone(),
                 duration: self.duration.clone(),
                 expires: None,
@@ -96,8 +106,9 @@ impl BounceCommand {
         eprintln!(
             "NOTE: the bounce is running async. Use the bounce-list command to review ongoing status!"
         );
-        println!("{}", result.id);
-
-        Ok(())
+        crate::output::print_or(&result, || {
+            println!("{}", result.id);
+            Ok(())
+        })
     }
 }
@@ -1,3 +1,4 @@
+use crate::output::OutputFormat;
 use clap::Parser;
 use kumo_api_types::BounceV1ListEntry;
 use num_format::{Locale, ToFormattedString};
@@ -26,8 +27,10 @@ impl BounceListCommand {
         .await?;
 
         if self.json {
-            println!("{}", serde_json::to_string_pretty(&result)?);
-        } else {
+            return crate::output::print_as(OutputFormat::Json, &result);
+        }
+
+        crate::output::print_table_or(&result, || {
             let columns = [
                 Column {
                     name: "ID".to_string(),
@@ -51,7 +54,7 @@ impl BounceListCommand {
                 },
             ];
             let mut rows = vec![];
-            for entry in result {
+            for entry in &result {
                 let mut criteria = vec![];
                 if let Some(c) = &entry.campaign {
                     criteria.push(format!("campaign={c}"));
@@ -72,15 +75,14 @@ impl BounceListCommand {
 
                 rows.push(vec![
                     entry.id.to_string(),
-                    entry.reason,
+                    entry.reason.to_string(),
                     humantime::format_duration(entry.duration).to_string(),
                     entry.total_bounced.to_formatted_string(&Locale::en),
                     criteria,
                 ]);
             }
             tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
-        }
-
-        Ok(())
+            Ok(())
+        })
     }
 }
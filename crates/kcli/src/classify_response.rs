@@ -0,0 +1,42 @@
+use clap::Parser;
+use kumo_api_types::{BounceClassifyV1Request, BounceClassifyV1Response};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Show how a response would be classified by the bounce classifier.
+///
+/// The response is classified by the kumod instance at the endpoint,
+/// using the rules that it currently has loaded, including those from
+/// any `override_files` that were configured via
+/// `kumo.configure_bounce_classifier`.
+///
+/// ## Example
+///
+///    kcli classify-response --text "550 5.1.1 mailbox does not exist"
+pub struct ClassifyResponseCommand {
+    /// The response text to classify, as it would appear in a
+    /// single line in the logs, such as "550 5.1.1 mailbox does not exist"
+    #[arg(long)]
+    text: String,
+}
+
+impl ClassifyResponseCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: BounceClassifyV1Response = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/bounce-classify/v1")?,
+            &BounceClassifyV1Request {
+                text: self.text.clone(),
+            },
+        )
+        .await?;
+
+        crate::output::print_or(&result, || {
+            match &result.rule {
+                Some(rule) => println!("{} (matched rule: {rule})", result.classification),
+                None => println!("{} (no rule matched)", result.classification),
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use clap::Parser;
+use kumo_api_types::cluster::{ClusterNodeStatusV1, ClusterStatusV1Response, NodeStatusV1Response};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the merged queue depths, suspensions and overall
+/// totals of a cluster of kumod nodes.
+///
+/// By default, the endpoint is asked to produce the cluster
+/// status using the peers configured via `kumo.configure_cluster_peers`.
+///
+/// If one or more `--peer` options are passed, kcli will instead
+/// query each of the peers, as well as the endpoint, and merge the
+/// results itself.
+pub struct ClusterStatusCommand {
+    /// The base URL of the HTTP listener of a peer, such as
+    /// `http://10.0.0.2:8000`. May be specified multiple times.
+    #[arg(long)]
+    peer: Vec<Url>,
+}
+
+async fn node_status(endpoint: &Url) -> ClusterNodeStatusV1 {
+    let result: anyhow::Result<NodeStatusV1Response> = async {
+        crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/node-status/v1")?,
+            &(),
+        )
+        .await
+    }
+    .await;
+    match result {
+        Ok(status) => ClusterNodeStatusV1 {
+            peer: Some(endpoint.to_string()),
+            status: Some(status),
+            error: None,
+        },
+        Err(err) => ClusterNodeStatusV1 {
+            peer: Some(endpoint.to_string()),
+            status: None,
+            error: Some(format!("{err:#}")),
+        },
+    }
+}
+
+impl ClusterStatusCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: ClusterStatusV1Response = if self.peer.is_empty() {
+            crate::request_with_json_response(
+                reqwest::Method::GET,
+                endpoint.join("/api/admin/cluster-status/v1")?,
+                &(),
+            )
+            .await?
+        } else {
+            let nodes = futures::future::join_all(
+                std::iter::once(endpoint)
+                    .chain(self.peer.iter())
+                    .map(node_status),
+            )
+            .await;
+            ClusterStatusV1Response::merge(nodes)
+        };
+
+        crate::output::print(&result)
+    }
+}
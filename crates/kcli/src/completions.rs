@@ -0,0 +1,27 @@
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Generate a shell completion script for kcli.
+///
+/// The script is printed to stdout.  For example, to enable
+/// completion for bash:
+///
+///    kcli completions bash > /etc/bash_completion.d/kcli
+///
+/// or for zsh, writing to a directory that is in your `fpath`:
+///
+///    kcli completions zsh > ~/.zfunc/_kcli
+pub struct CompletionsCommand {
+    /// The shell for which to generate the script
+    shell: Shell,
+}
+
+impl CompletionsCommand {
+    pub async fn run(&self, _endpoint: &Url) -> anyhow::Result<()> {
+        let mut cmd = crate::Opt::command();
+        clap_complete::generate(self.shell, &mut cmd, "kcli", &mut std::io::stdout());
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use clap::Parser;
+use kumo_api_types::drain::{DrainV1Request, DrainV1Response};
+use reqwest::Url;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Puts the node into maintenance mode, and drains its queues
+/// ahead of a deadline.
+///
+/// While draining, new mail is rejected with a transient error,
+/// both via SMTP and via the HTTP injection API.
+/// Messages whose next delivery attempt is scheduled after the
+/// deadline are made immediately eligible for delivery, unless
+/// their scheduled queue is suspended.
+///
+/// If --handoff-to is specified, the messages that remain in the
+/// scheduled queues when the deadline is reached are rebound to
+/// that routing domain, which is typically a peer node.
+///
+/// Use `kcli drain-status` to monitor the progress of the drain,
+/// and `kcli drain-cancel` to resume accepting mail.
+///
+/// ## Example
+///
+///    kcli drain --deadline 30m --reason "kernel upgrade" --handoff-to "[10.0.0.2]"
+pub struct DrainCommand {
+    /// How long to allow for the queues to drain
+    #[arg(long, value_parser=humantime::parse_duration)]
+    deadline: Duration,
+
+    /// The reason for the drain
+    #[arg(long)]
+    reason: String,
+
+    /// The SMTP status code used to reject new mail while
+    /// draining. Must be a 4xx code.
+    #[arg(long, default_value_t = DrainV1Request::default_code())]
+    code: u16,
+
+    /// The message used to reject new mail while draining
+    #[arg(long, default_value_t = DrainV1Request::default_message())]
+    message: String,
+
+    /// Don't make deferred messages immediately eligible
+    /// for delivery
+    #[arg(long)]
+    no_accelerate: bool,
+
+    /// The routing domain to which the remaining messages
+    /// are rebound when the deadline is reached
+    #[arg(long)]
+    handoff_to: Option<String>,
+}
+
+impl DrainCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: DrainV1Response = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/drain/v1")?,
+            &DrainV1Request {
+                deadline: self.deadline,
+                reason: self.reason.clone(),
+                code: self.code,
+                message: self.message.clone(),
+                accelerate: !self.no_accelerate,
+                handoff_to: self.handoff_to.clone(),
+            },
+        )
+        .await?;
+
+        crate::output::print(&result)
+    }
+}
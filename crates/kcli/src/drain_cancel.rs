@@ -0,0 +1,22 @@
+use clap::Parser;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Cancels a drain started by `kcli drain`, so that the node
+/// resumes accepting mail.
+///
+/// Messages that have already been handed off are not affected.
+pub struct DrainCancelCommand {}
+
+impl DrainCancelCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = crate::request_with_text_response(
+            reqwest::Method::DELETE,
+            endpoint.join("/api/admin/drain/v1")?,
+            &(),
+        )
+        .await?;
+
+        crate::output::print_status(&response)
+    }
+}
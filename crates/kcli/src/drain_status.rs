@@ -0,0 +1,20 @@
+use clap::Parser;
+use kumo_api_types::drain::DrainV1Status;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Reports on the progress of a drain started by `kcli drain`
+pub struct DrainStatusCommand {}
+
+impl DrainStatusCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: DrainV1Status = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/drain/v1")?,
+            &(),
+        )
+        .await?;
+
+        crate::output::print(&result)
+    }
+}
@@ -0,0 +1,87 @@
+use clap::Parser;
+use kumo_api_types::egress_preflight::{EgressPreflightV1Report, EgressPreflightV1Request};
+use reqwest::Url;
+use tabout::{Alignment, Column};
+
+#[derive(Debug, Parser)]
+/// Validates that the source address of each egress source can be
+/// bound, has a PTR record that matches its EHLO name, and that the
+/// PTR record resolves back to the source address.
+///
+/// If no sources or pools are specified, the sources configured via
+/// `kumo.configure_egress_preflight` are validated.
+///
+/// The command exits with a non-zero status if any check failed.
+pub struct EgressPreflightCommand {
+    /// The name of an egress source to validate.
+    /// Can be specified multiple times.
+    #[arg(long)]
+    source: Vec<String>,
+
+    /// The name of an egress pool whose sources should be validated.
+    /// Can be specified multiple times.
+    #[arg(long)]
+    pool: Vec<String>,
+
+    /// Rather than performing the validation, show the report
+    /// from the most recent validation, which may have been
+    /// performed when kumod started
+    #[arg(long, conflicts_with_all=["source", "pool"])]
+    last: bool,
+}
+
+impl EgressPreflightCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let url = endpoint.join("/api/admin/egress-preflight/v1")?;
+        let report: EgressPreflightV1Report = if self.last {
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?
+        } else {
+            crate::request_with_json_response(
+                reqwest::Method::POST,
+                url,
+                &EgressPreflightV1Request {
+                    sources: self.source.clone(),
+                    pools: self.pool.clone(),
+                },
+            )
+            .await?
+        };
+
+        crate::output::print_table_or(&report, || {
+            let columns = ["SOURCE", "ADDRESS", "CHECK", "STATUS", "DETAIL"]
+                .into_iter()
+                .map(|name| Column {
+                    name: name.to_string(),
+                    alignment: Alignment::Left,
+                })
+                .collect::<Vec<_>>();
+
+            let mut rows = vec![];
+            for source in &report.sources {
+                let address = source
+                    .address
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                for check in &source.checks {
+                    rows.push(vec![
+                        source.name.clone(),
+                        address.clone(),
+                        check.check.as_str().to_string(),
+                        format!("{:?}", check.status),
+                        check.detail.clone(),
+                    ]);
+                }
+            }
+            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            for error in &report.errors {
+                eprintln!("{error}");
+            }
+            Ok(())
+        })?;
+
+        if !report.passed() {
+            anyhow::bail!("one or more egress sources failed validation");
+        }
+        Ok(())
+    }
+}
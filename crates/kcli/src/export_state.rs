@@ -0,0 +1,108 @@
+use crate::output::OutputFormat;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use kumo_api_types::tuning::TuningV1Response;
+use kumo_api_types::{BounceV1ListEntry, SuspendReadyQueueV1ListEntry, SuspendV1ListEntry};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The operational state of a node, as written by `export-state`
+/// and read by `import-state`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OperationalStateV1 {
+    /// The endpoint from which the state was exported
+    pub source: String,
+    /// When the state was exported. The remaining durations of
+    /// the entries below are relative to this time.
+    pub exported: DateTime<Utc>,
+    pub suspensions: Vec<SuspendV1ListEntry>,
+    pub ready_queue_suspensions: Vec<SuspendReadyQueueV1ListEntry>,
+    pub bounces: Vec<BounceV1ListEntry>,
+    pub tuning: TuningV1Response,
+}
+
+impl OperationalStateV1 {
+    pub async fn fetch(endpoint: &Url) -> anyhow::Result<Self> {
+        let exported = Utc::now();
+        let suspensions = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/suspend/v1")?,
+            &(),
+        )
+        .await?;
+        let ready_queue_suspensions = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/suspend-ready-q/v1")?,
+            &(),
+        )
+        .await?;
+        let bounces = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/bounce/v1")?,
+            &(),
+        )
+        .await?;
+        let tuning = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/tuning/v1")?,
+            &(),
+        )
+        .await?;
+
+        Ok(Self {
+            source: endpoint.to_string(),
+            exported,
+            suspensions,
+            ready_queue_suspensions,
+            bounces,
+            tuning,
+        })
+    }
+}
+
+#[derive(Debug, Parser)]
+/// Export the operational state of a node to a file.
+///
+/// The administrative suspensions, ready queue suspensions and bounces
+/// that are currently in effect are exported, along with the runtime
+/// adjustments made via `kcli tuning`.  The state is written as JSON,
+/// or as YAML when `--output yaml` is used.  The file can be loaded into
+/// another node, or into the same node after it has been rebuilt,
+/// via `kcli import-state`.
+pub struct ExportStateCommand {
+    /// Write the state to this file, rather than to stdout
+    #[arg(long)]
+    file: Option<PathBuf>,
+}
+
+impl ExportStateCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let state = OperationalStateV1::fetch(endpoint).await?;
+        let data = match crate::output::format() {
+            None | Some(OutputFormat::Json) => serde_json::to_string_pretty(&state)?,
+            Some(OutputFormat::Yaml) => serde_yaml::to_string(&state)?,
+            Some(format) => {
+                anyhow::bail!("export-state cannot produce {format:?} output; use json or yaml")
+            }
+        };
+
+        match &self.file {
+            Some(path) => {
+                std::fs::write(path, data)?;
+                eprintln!(
+                    "Exported {} suspensions, {} ready queue suspensions, \
+                     {} bounces and {} ready queue adjustments to {}",
+                    state.suspensions.len(),
+                    state.ready_queue_suspensions.len(),
+                    state.bounces.len(),
+                    state.tuning.ready_queues.len(),
+                    path.display()
+                );
+            }
+            None => println!("{}", data.trim_end()),
+        }
+
+        Ok(())
+    }
+}
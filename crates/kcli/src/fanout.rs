@@ -0,0 +1,285 @@
+use crate::output::{self, OutputFormat};
+use anyhow::Context;
+use ordermap::OrderMap;
+use serde_json::{json, Value};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+/// The options that are consumed or replaced when kcli is run for each host
+const HOST_OPTIONS: &[&str] = &["--hosts", "--inventory", "--endpoint", "--output"];
+
+/// Reads the hosts from an inventory file, which lists one host per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn read_inventory(path: &Path) -> anyhow::Result<Vec<String>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("reading inventory {}", path.display()))?;
+    let hosts: Vec<String> = data
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect();
+    anyhow::ensure!(!hosts.is_empty(), "{} lists no hosts", path.display());
+    Ok(hosts)
+}
+
+fn host_endpoint(host: &str) -> String {
+    if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{host}")
+    }
+}
+
+/// Returns the arguments that were passed to this invocation of kcli,
+/// except for those in HOST_OPTIONS
+fn forwarded_args() -> Vec<OsString> {
+    let mut args = vec![];
+    let mut iter = std::env::args_os().skip(1);
+    while let Some(arg) = iter.next() {
+        let text = arg.to_string_lossy();
+        if HOST_OPTIONS.contains(&text.as_ref()) {
+            // Skip its value too
+            iter.next();
+            continue;
+        }
+        if HOST_OPTIONS
+            .iter()
+            .any(|option| text.starts_with(&format!("{option}=")))
+        {
+            continue;
+        }
+        args.push(arg);
+    }
+    args
+}
+
+/// Builds the command that runs kcli against `host`. When a structured
+/// output format was selected, the command produces JSON which is then
+/// merged and converted to the selected format.
+fn command_for(host: &str) -> anyhow::Result<Command> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("--endpoint").arg(host_endpoint(host));
+    if output::format().is_some() {
+        command.arg("--output").arg("json");
+    }
+    command
+        .args(forwarded_args())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    Ok(command)
+}
+
+/// Labels a record with the host that produced it
+fn label(host: &str, value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut labelled = serde_json::Map::new();
+            labelled.insert("host".to_string(), json!(host));
+            labelled.extend(map);
+            Value::Object(labelled)
+        }
+        value => json!({"host": host, "value": value}),
+    }
+}
+
+/// Parses the JSON output of a command, which is either a single
+/// document or, for commands that stream their results, a document
+/// per record
+fn parse_output(data: &[u8]) -> anyhow::Result<Value> {
+    let mut values = serde_json::Deserializer::from_slice(data)
+        .into_iter::<Value>()
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.len() == 1 {
+        Ok(values.remove(0))
+    } else {
+        Ok(Value::Array(values))
+    }
+}
+
+/// Runs the command against each of the hosts concurrently.
+/// `streaming` indicates that the command prints its results as they
+/// happen, in which case the output of each host is labelled and shown
+/// as it arrives, rather than when the command has completed.
+pub async fn run(hosts: &[String], streaming: bool) -> anyhow::Result<()> {
+    let failed = if streaming {
+        run_streaming(hosts).await?
+    } else {
+        run_collected(hosts).await?
+    };
+    anyhow::ensure!(
+        failed == 0,
+        "the command failed for {failed} of {} hosts",
+        hosts.len()
+    );
+    Ok(())
+}
+
+/// Runs the command to completion for each host, then prints the
+/// results grouped by host. Returns the number of hosts that failed.
+async fn run_collected(hosts: &[String]) -> anyhow::Result<usize> {
+    let results = futures::future::join_all(hosts.iter().map(|host| async move {
+        let result = match command_for(host) {
+            Ok(mut command) => command.output().await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        (host, result)
+    }))
+    .await;
+
+    let format = output::format();
+    let mut failed = 0;
+    let mut merged: OrderMap<String, Value> = OrderMap::new();
+
+    for (idx, (host, result)) in results.into_iter().enumerate() {
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                failed += 1;
+                eprintln!("[{host}] {err:#}");
+                merged.insert(host.to_string(), json!({"error": format!("{err:#}")}));
+                continue;
+            }
+        };
+        let stderr = String::from_utf8_lossy(&outcome.stderr);
+        for line in stderr.lines() {
+            eprintln!("[{host}] {line}");
+        }
+        if !outcome.status.success() {
+            failed += 1;
+        }
+
+        if format.is_none() {
+            if idx > 0 {
+                println!();
+            }
+            println!("==> {host} <==");
+            print!("{}", String::from_utf8_lossy(&outcome.stdout));
+            continue;
+        }
+
+        let value = if outcome.status.success() {
+            parse_output(&outcome.stdout)
+                .unwrap_or_else(|err| json!({"error": format!("parsing output: {err:#}")}))
+        } else {
+            json!({"error": stderr.trim()})
+        };
+        merged.insert(host.to_string(), value);
+    }
+
+    match format {
+        None => {}
+        Some(format @ (OutputFormat::Json | OutputFormat::Yaml)) => {
+            output::print_as(format, &merged)?;
+        }
+        Some(format) => {
+            // Produce a single table with a host column
+            let mut records = vec![];
+            for (host, value) in merged {
+                match value {
+                    Value::Array(values) => {
+                        records.extend(values.into_iter().map(|value| label(&host, value)))
+                    }
+                    value => records.push(label(&host, value)),
+                }
+            }
+            output::print_as(format, &records)?;
+        }
+    }
+
+    Ok(failed)
+}
+
+enum HostEvent {
+    Stdout(String),
+    Stderr(String),
+    Exited(std::io::Result<ExitStatus>),
+}
+
+/// Runs the command for each host, printing the output of each as it
+/// arrives, labelled with its host. Returns the number of hosts that failed.
+async fn run_streaming(hosts: &[String]) -> anyhow::Result<usize> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    for host in hosts {
+        let mut child = command_for(host)?
+            .spawn()
+            .with_context(|| format!("running kcli for {host}"))?;
+        let stdout = child.stdout.take().expect("stdout to be piped");
+        let stderr = child.stderr.take().expect("stderr to be piped");
+        let host = host.to_string();
+        let tx = tx.clone();
+
+        tokio::spawn(async move {
+            let mut stdout = BufReader::new(stdout).lines();
+            let mut stderr = BufReader::new(stderr).lines();
+            tokio::join!(
+                async {
+                    while let Ok(Some(line)) = stdout.next_line().await {
+                        tx.send((host.clone(), HostEvent::Stdout(line))).ok();
+                    }
+                },
+                async {
+                    while let Ok(Some(line)) = stderr.next_line().await {
+                        tx.send((host.clone(), HostEvent::Stderr(line))).ok();
+                    }
+                }
+            );
+            let status = child.wait().await;
+            tx.send((host, HostEvent::Exited(status))).ok();
+        });
+    }
+    drop(tx);
+
+    let structured = output::format().is_some();
+    let mut failed = 0;
+    while let Some((host, event)) = rx.recv().await {
+        match event {
+            HostEvent::Stdout(line) if structured => match serde_json::from_str(&line) {
+                Ok(record) => output::write_record(&label(&host, record))?,
+                Err(_) => eprintln!("[{host}] {line}"),
+            },
+            HostEvent::Stdout(line) => println!("[{host}] {line}"),
+            HostEvent::Stderr(line) => eprintln!("[{host}] {line}"),
+            HostEvent::Exited(status) => {
+                if !status.map(|status| status.success()).unwrap_or(false) {
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labelling() {
+        assert_eq!(
+            label("mx1", json!({"id": 1})),
+            json!({"host": "mx1", "id": 1})
+        );
+        assert_eq!(
+            label("mx1", json!("OK")),
+            json!({"host": "mx1", "value": "OK"})
+        );
+        assert_eq!(
+            parse_output(b"{\"a\": 1}\n{\"a\": 2}\n").unwrap(),
+            json!([{"a": 1}, {"a": 2}])
+        );
+        assert_eq!(
+            parse_output(b"[\n  {\"a\": 1}\n]\n").unwrap(),
+            json!([{"a": 1}])
+        );
+        assert_eq!(host_endpoint("mx1:8000"), "http://mx1:8000");
+        assert_eq!(host_endpoint("https://mx1:8000"), "https://mx1:8000");
+    }
+}
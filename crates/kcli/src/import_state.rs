@@ -0,0 +1,317 @@
+use crate::export_state::OperationalStateV1;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use kumo_api_types::tuning::{TuningV1Request, TuningV1Response};
+use kumo_api_types::{
+    BounceV1Request, BounceV1Response, SuspendReadyQueueV1Request, SuspendV1Request,
+    SuspendV1Response,
+};
+use reqwest::Url;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Import operational state that was exported by `kcli export-state`.
+///
+/// Each suspension, ready queue suspension and bounce is recreated
+/// with the same expiration time that it had when it was exported;
+/// those that have since expired are skipped, as are those for which
+/// an entry with the same criteria and reason is already present.
+/// Take care when importing bounces: they will bounce any matching
+/// messages that are in the queues of the target node.
+///
+/// The ready queue adjustments, DNS query timeout and memory limits
+/// made via `kcli tuning` are also applied.  Cache capacities are
+/// not imported, as they are typically defined by the policy.
+pub struct ImportStateCommand {
+    /// The file that was written by `kcli export-state`,
+    /// in either JSON or YAML format
+    file: PathBuf,
+
+    /// Show what would be imported, without changing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Do not apply the runtime adjustments
+    #[arg(long)]
+    skip_tuning: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ImportKind {
+    Suspension,
+    ReadyQueueSuspension,
+    Bounce,
+    Tuning,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ImportOutcome {
+    Imported,
+    WouldImport,
+    Expired,
+    AlreadyPresent,
+}
+
+/// What was done with an entry of the exported state
+#[derive(Serialize, Debug)]
+struct ImportAction {
+    kind: ImportKind,
+    /// The criteria or name of a suspension or bounce
+    target: String,
+    outcome: ImportOutcome,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl ImportAction {
+    fn describe(&self) -> String {
+        let subject = match self.kind {
+            ImportKind::Suspension => format!("suspension of {}", self.target),
+            ImportKind::ReadyQueueSuspension => {
+                format!("suspension of ready queue {}", self.target)
+            }
+            ImportKind::Bounce => format!("bounce of {}", self.target),
+            ImportKind::Tuning => self.target.to_string(),
+        };
+        let until = match self.expires {
+            Some(expires) => format!(" until {expires}"),
+            None => String::new(),
+        };
+        match self.outcome {
+            ImportOutcome::Imported => format!("Imported {subject}{until}"),
+            ImportOutcome::WouldImport => format!("Would import {subject}{until}"),
+            ImportOutcome::Expired => format!("Skipped expired {subject}"),
+            ImportOutcome::AlreadyPresent => {
+                format!("Skipped {subject}, which is already present")
+            }
+        }
+    }
+}
+
+fn describe_criteria(
+    campaign: &Option<String>,
+    tenant: &Option<String>,
+    domain: &Option<String>,
+    routing_domain: &Option<String>,
+) -> String {
+    let mut result = vec![];
+    for (label, value) in [
+        ("campaign", campaign),
+        ("tenant", tenant),
+        ("domain", domain),
+        ("routing_domain", routing_domain),
+    ] {
+        if let Some(value) = value {
+            result.push(format!("{label}={value}"));
+        }
+    }
+    if result.is_empty() {
+        "everything".to_string()
+    } else {
+        result.join(" ")
+    }
+}
+
+fn tuning_request(tuning: &TuningV1Response) -> TuningV1Request {
+    TuningV1Request {
+        ready_queues: tuning.ready_queues.clone(),
+        dns_query_timeout: tuning.dns_query_timeout,
+        memory_soft_limit: tuning.memory_soft_limit,
+        low_memory_threshold: Some(tuning.low_memory_threshold),
+        ..Default::default()
+    }
+}
+
+impl ImportStateCommand {
+    /// Computes the expiration time of an entry that had `remaining`
+    /// time left when it was exported, or None if it has expired
+    fn expires(state: &OperationalStateV1, remaining: Duration) -> Option<DateTime<Utc>> {
+        let expires = state.exported + chrono::Duration::from_std(remaining).ok()?;
+        if expires > Utc::now() {
+            Some(expires)
+        } else {
+            None
+        }
+    }
+
+    fn outcome(&self) -> ImportOutcome {
+        if self.dry_run {
+            ImportOutcome::WouldImport
+        } else {
+            ImportOutcome::Imported
+        }
+    }
+
+    /// Prints the action as it happens, unless a structured output
+    /// format was selected, in which case the actions are printed
+    /// together once the import is complete
+    fn report(actions: &mut Vec<ImportAction>, action: ImportAction) {
+        if crate::output::format().is_none() {
+            println!("{}", action.describe());
+        }
+        actions.push(action);
+    }
+
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let data = std::fs::read(&self.file)?;
+        // YAML is a superset of JSON, so this accepts either format
+        let state: OperationalStateV1 = serde_yaml::from_slice(&data)?;
+        let current = OperationalStateV1::fetch(endpoint).await?;
+        let mut actions = vec![];
+
+        for entry in &state.suspensions {
+            let target = describe_criteria(&entry.campaign, &entry.tenant, &entry.domain, &None);
+            let expires = Self::expires(&state, entry.duration);
+            let outcome = if expires.is_none() {
+                ImportOutcome::Expired
+            } else if current.suspensions.iter().any(|existing| {
+                existing.campaign == entry.campaign
+                    && existing.tenant == entry.tenant
+                    && existing.domain == entry.domain
+                    && existing.reason == entry.reason
+            }) {
+                ImportOutcome::AlreadyPresent
+            } else {
+                if !self.dry_run {
+                    let _: SuspendV1Response = crate::request_with_json_response(
+                        reqwest::Method::POST,
+                        endpoint.join("/api/admin/suspend/v1")?,
+                        &SuspendV1Request {
+                            campaign: entry.campaign.clone(),
+                            tenant: entry.tenant.clone(),
+                            domain: entry.domain.clone(),
+                            reason: entry.reason.clone(),
+                            duration: None,
+                            expires,
+                        },
+                    )
+                    .await?;
+                }
+                self.outcome()
+            };
+            Self::report(
+                &mut actions,
+                ImportAction {
+                    kind: ImportKind::Suspension,
+                    target,
+                    outcome,
+                    expires,
+                },
+            );
+        }
+
+        for entry in &state.ready_queue_suspensions {
+            let expires = Self::expires(&state, entry.duration);
+            let outcome = if expires.is_none() {
+                ImportOutcome::Expired
+            } else if current
+                .ready_queue_suspensions
+                .iter()
+                .any(|existing| existing.name == entry.name && existing.reason == entry.reason)
+            {
+                ImportOutcome::AlreadyPresent
+            } else {
+                if !self.dry_run {
+                    let _: SuspendV1Response = crate::request_with_json_response(
+                        reqwest::Method::POST,
+                        endpoint.join("/api/admin/suspend-ready-q/v1")?,
+                        &SuspendReadyQueueV1Request {
+                            name: entry.name.clone(),
+                            reason: entry.reason.clone(),
+                            duration: None,
+                            expires,
+                        },
+                    )
+                    .await?;
+                }
+                self.outcome()
+            };
+            Self::report(
+                &mut actions,
+                ImportAction {
+                    kind: ImportKind::ReadyQueueSuspension,
+                    target: entry.name.to_string(),
+                    outcome,
+                    expires,
+                },
+            );
+        }
+
+        for entry in &state.bounces {
+            let target = describe_criteria(
+                &entry.campaign,
+                &entry.tenant,
+                &entry.domain,
+                &entry.routing_domain,
+            );
+            let expires = Self::expires(&state, entry.duration);
+            let outcome = if expires.is_none() {
+                ImportOutcome::Expired
+            } else if current.bounces.iter().any(|existing| {
+                existing.campaign == entry.campaign
+                    && existing.tenant == entry.tenant
+                    && existing.domain == entry.domain
+                    && existing.routing_domain == entry.routing_domain
+                    && existing.meta == entry.meta
+                    && existing.reason == entry.reason
+            }) {
+                ImportOutcome::AlreadyPresent
+            } else {
+                if !self.dry_run {
+                    let _: BounceV1Response = crate::request_with_json_response(
+                        reqwest::Method::POST,
+                        endpoint.join("/api/admin/bounce/v1")?,
+                        &BounceV1Request {
+                            campaign: entry.campaign.clone(),
+                            tenant: entry.tenant.clone(),
+                            domain: entry.domain.clone(),
+                            routing_domain: entry.routing_domain.clone(),
+                            meta: entry.meta.clone(),
+                            reason: entry.reason.clone(),
+                            duration: None,
+                            suppress_logging: false,
+                            expires,
+                        },
+                    )
+                    .await?;
+                }
+                self.outcome()
+            };
+            Self::report(
+                &mut actions,
+                ImportAction {
+                    kind: ImportKind::Bounce,
+                    target,
+                    outcome,
+                    expires,
+                },
+            );
+        }
+
+        if !self.skip_tuning {
+            let request = tuning_request(&state.tuning);
+            if !self.dry_run {
+                let _: TuningV1Response = crate::request_with_json_response(
+                    reqwest::Method::POST,
+                    endpoint.join("/api/admin/tuning/v1")?,
+                    &request,
+                )
+                .await?;
+            }
+            Self::report(
+                &mut actions,
+                ImportAction {
+                    kind: ImportKind::Tuning,
+                    target: format!("{} ready queue adjustments", request.ready_queues.len()),
+                    outcome: self.outcome(),
+                    expires: None,
+                },
+            );
+        }
+
+        crate::output::print_or(&actions, || Ok(()))
+    }
+}
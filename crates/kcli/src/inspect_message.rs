@@ -23,8 +23,6 @@ impl InspectMessageCommand {
         let result: InspectMessageV1Response =
             crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
 
-        println!("{}", serde_json::to_string_pretty(&result)?);
-
-        Ok(())
+        crate::output::print(&result)
     }
 }
@@ -1,6 +1,7 @@
 use clap::Parser;
 use kumo_api_types::SetDiagnosticFilterRequest;
 use reqwest::Url;
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 /// Changes the diagnostic log filter
@@ -8,6 +9,30 @@ use reqwest::Url;
 /// See <https://docs.kumomta.com/reference/kumo/set_diagnostic_log_filter/>
 /// for more information about the log filter syntax.
 pub struct SetLogFilterCommand {
+    /// Apply the filter only to work relating to delivering
+    /// messages to this destination domain.
+    /// The filter must be a level, or a single target=level pair.
+    #[arg(long, conflicts_with_all=["tenant", "egress_pool"])]
+    domain: Option<String>,
+
+    /// Apply the filter only to work relating to delivering
+    /// messages for this tenant.
+    /// The filter must be a level, or a single target=level pair.
+    #[arg(long, conflicts_with_all=["domain", "egress_pool"])]
+    tenant: Option<String>,
+
+    /// Apply the filter only to work relating to delivering
+    /// messages via this egress pool.
+    /// The filter must be a level, or a single target=level pair.
+    #[arg(long, conflicts_with_all=["domain", "tenant"])]
+    egress_pool: Option<String>,
+
+    /// How long a domain, tenant or egress pool filter remains
+    /// in effect. The default is '10m'. A duration of '0s'
+    /// removes a previously set filter.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    duration: Option<Duration>,
+
     filter: String,
 }
 
@@ -18,16 +43,14 @@ impl SetLogFilterCommand {
             endpoint.join("/api/admin/set_diagnostic_log_filter/v1")?,
             &SetDiagnosticFilterRequest {
                 filter: self.filter.clone(),
+                domain: self.domain.clone(),
+                tenant: self.tenant.clone(),
+                egress_pool: self.egress_pool.clone(),
+                duration: self.duration,
             },
         )
         .await?;
 
-        if !response.is_empty() {
-            println!("{response}");
-        } else {
-            println!("OK");
-        }
-
-        Ok(())
+        crate::output::print_status(&response)
     }
 }
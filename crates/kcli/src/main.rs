@@ -2,25 +2,50 @@ use anyhow::Context;
 use clap::{Parser, ValueEnum};
 use futures::Stream;
 use reqwest::Url;
+use std::path::PathBuf;
 use std::time::Duration;
 
+mod analytics;
+mod audit_log;
 mod bounce;
 mod bounce_cancel;
 mod bounce_list;
+mod classify_response;
+mod cluster_status;
+mod completions;
+mod drain;
+mod drain_cancel;
+mod drain_status;
+mod egress_preflight;
+mod export_state;
+mod fanout;
+mod import_state;
 mod inspect_message;
 mod logfilter;
+mod message_search;
+mod mx_pin;
+mod mx_pin_cancel;
+mod mx_pin_list;
+mod output;
 mod provider_summary;
+mod queue;
 mod queue_summary;
 mod rebind;
+mod smtp_probe;
 mod suspend;
 mod suspend_cancel;
 mod suspend_list;
 mod suspend_ready_q;
 mod suspend_ready_q_cancel;
 mod suspend_ready_q_list;
+mod tail_logs;
 mod top;
+mod trace_message;
 mod trace_smtp_client;
 mod trace_smtp_server;
+mod tuning;
+mod validate_config;
+mod wire_capture;
 
 /// KumoMTA CLI.
 ///
@@ -43,6 +68,26 @@ struct Opt {
     #[arg(long)]
     endpoint: Option<String>,
 
+    /// Run the command against each of these hosts concurrently,
+    /// rather than against a single endpoint, and label the results
+    /// with the host that produced them.
+    /// Each host is either a URL or a host and port, such as
+    /// `mx1.example.com:8000`.
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["endpoint", "inventory"])]
+    hosts: Vec<String>,
+
+    /// Like `--hosts`, but reads the hosts from a file that lists one
+    /// host per line. Blank lines and lines starting with `#` are ignored.
+    #[arg(long, conflicts_with = "endpoint")]
+    inventory: Option<PathBuf>,
+
+    /// Print the results in a structured format, rather than
+    /// the usual output of the command.
+    /// Commands that stream their results, such as tail-logs,
+    /// print a record per event.
+    #[arg(long, global = true, value_enum)]
+    output: Option<output::OutputFormat>,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
@@ -53,10 +98,22 @@ const TIMEOUT: Duration = Duration::from_secs(60);
 enum SubCommand {
     #[command(hide = true)]
     MarkdownHelp,
+    Analytics(analytics::AnalyticsCommand),
+    AuditLog(audit_log::AuditLogCommand),
     Bounce(bounce::BounceCommand),
     BounceList(bounce_list::BounceListCommand),
     BounceCancel(bounce_cancel::BounceCancelCommand),
+    ClassifyResponse(classify_response::ClassifyResponseCommand),
+    ClusterStatus(cluster_status::ClusterStatusCommand),
+    Completions(completions::CompletionsCommand),
+    Drain(drain::DrainCommand),
+    DrainStatus(drain_status::DrainStatusCommand),
+    DrainCancel(drain_cancel::DrainCancelCommand),
+    EgressPreflight(egress_preflight::EgressPreflightCommand),
+    ExportState(export_state::ExportStateCommand),
+    ImportState(import_state::ImportStateCommand),
     Rebind(rebind::RebindCommand),
+    SmtpProbe(smtp_probe::SmtpProbeCommand),
     Suspend(suspend::SuspendCommand),
     SuspendList(suspend_list::SuspendListCommand),
     SuspendCancel(suspend_cancel::SuspendCancelCommand),
@@ -64,15 +121,49 @@ enum SubCommand {
     SuspendReadyQList(suspend_ready_q_list::SuspendReadyQListCommand),
     SuspendReadyQCancel(suspend_ready_q_cancel::SuspendReadyQCancelCommand),
     SetLogFilter(logfilter::SetLogFilterCommand),
+    SetWireCapture(wire_capture::SetWireCaptureCommand),
+    TailLogs(tail_logs::TailLogsCommand),
     InspectMessage(inspect_message::InspectMessageCommand),
+    MessageSearch(message_search::MessageSearchCommand),
+    MxPin(mx_pin::MxPinCommand),
+    MxPinList(mx_pin_list::MxPinListCommand),
+    MxPinCancel(mx_pin_cancel::MxPinCancelCommand),
     ProviderSummary(provider_summary::ProviderSummaryCommand),
+    Queue(queue::QueueCommand),
     QueueSummary(queue_summary::QueueSummaryCommand),
+    TraceMessage(trace_message::TraceMessageCommand),
     TraceSmtpClient(trace_smtp_client::TraceSmtpClientCommand),
     TraceSmtpServer(trace_smtp_server::TraceSmtpServerCommand),
     Top(top::TopCommand),
+    Tuning(tuning::TuningCommand),
+    ValidateConfig(validate_config::ValidateConfigCommand),
 }
 
 impl SubCommand {
+    /// Returns true if the command can be run against multiple
+    /// hosts via `--hosts`
+    fn supports_hosts(&self) -> bool {
+        !matches!(
+            self,
+            Self::MarkdownHelp
+                | Self::Completions(_)
+                | Self::ExportState(_)
+                | Self::SmtpProbe(_)
+                | Self::Top(_)
+                | Self::ValidateConfig(_)
+        )
+    }
+
+    /// Returns true if the command prints its results as they happen,
+    /// rather than when it has completed
+    fn streams(&self) -> bool {
+        match self {
+            Self::TailLogs(_) | Self::TraceSmtpClient(_) | Self::TraceSmtpServer(_) => true,
+            Self::TraceMessage(cmd) => cmd.follows(),
+            _ => false,
+        }
+    }
+
     async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
         match self {
             Self::MarkdownHelp => {
@@ -105,7 +196,13 @@ impl SubCommand {
                         )?;
                     } else {
                         let (sub_command, remainder) = chunk.split_once('`').unwrap();
-                        let filename = format!("docs/reference/kcli/{sub_command}.md");
+                        // Nested sub-commands, such as `queue ls`, are
+                        // placed in a directory named after their parent
+                        let filename =
+                            format!("docs/reference/kcli/{}.md", sub_command.replace(' ', "/"));
+                        if let Some(parent) = std::path::Path::new(&filename).parent() {
+                            std::fs::create_dir_all(parent)?;
+                        }
                         let help = format!("# kcli {sub_command}\n{remainder}");
                         std::fs::write(&filename, &help)?;
                     }
@@ -113,10 +210,22 @@ impl SubCommand {
 
                 Ok(())
             }
+            Self::Analytics(cmd) => cmd.run(endpoint).await,
+            Self::AuditLog(cmd) => cmd.run(endpoint).await,
             Self::Bounce(cmd) => cmd.run(endpoint).await,
             Self::BounceCancel(cmd) => cmd.run(endpoint).await,
             Self::BounceList(cmd) => cmd.run(endpoint).await,
+            Self::ClassifyResponse(cmd) => cmd.run(endpoint).await,
+            Self::ClusterStatus(cmd) => cmd.run(endpoint).await,
+            Self::Completions(cmd) => cmd.run(endpoint).await,
+            Self::Drain(cmd) => cmd.run(endpoint).await,
+            Self::DrainCancel(cmd) => cmd.run(endpoint).await,
+            Self::DrainStatus(cmd) => cmd.run(endpoint).await,
+            Self::EgressPreflight(cmd) => cmd.run(endpoint).await,
+            Self::ExportState(cmd) => cmd.run(endpoint).await,
+            Self::ImportState(cmd) => cmd.run(endpoint).await,
             Self::Rebind(cmd) => cmd.run(endpoint).await,
+            Self::SmtpProbe(cmd) => cmd.run(endpoint).await,
             Self::Suspend(cmd) => cmd.run(endpoint).await,
             Self::SuspendCancel(cmd) => cmd.run(endpoint).await,
             Self::SuspendList(cmd) => cmd.run(endpoint).await,
@@ -124,12 +233,22 @@ impl SubCommand {
             Self::SuspendReadyQCancel(cmd) => cmd.run(endpoint).await,
             Self::SuspendReadyQList(cmd) => cmd.run(endpoint).await,
             Self::SetLogFilter(cmd) => cmd.run(endpoint).await,
+            Self::SetWireCapture(cmd) => cmd.run(endpoint).await,
+            Self::TailLogs(cmd) => cmd.run(endpoint).await,
             Self::InspectMessage(cmd) => cmd.run(endpoint).await,
+            Self::MessageSearch(cmd) => cmd.run(endpoint).await,
+            Self::MxPin(cmd) => cmd.run(endpoint).await,
+            Self::MxPinCancel(cmd) => cmd.run(endpoint).await,
+            Self::MxPinList(cmd) => cmd.run(endpoint).await,
             Self::ProviderSummary(cmd) => cmd.run(endpoint).await,
+            Self::Queue(cmd) => cmd.run(endpoint).await,
             Self::QueueSummary(cmd) => cmd.run(endpoint).await,
+            Self::TraceMessage(cmd) => cmd.run(endpoint).await,
             Self::TraceSmtpClient(cmd) => cmd.run(endpoint).await,
             Self::TraceSmtpServer(cmd) => cmd.run(endpoint).await,
             Self::Top(cmd) => cmd.run(endpoint).await,
+            Self::Tuning(cmd) => cmd.run(endpoint).await,
+            Self::ValidateConfig(cmd) => cmd.run(endpoint).await,
         }
     }
 }
@@ -262,6 +381,22 @@ pub enum ColorMode {
 async fn main() -> anyhow::Result<()> {
     let opts = Opt::parse();
 
+    if let Some(format) = opts.output {
+        output::set_format(format);
+    }
+
+    let hosts = match &opts.inventory {
+        Some(path) => fanout::read_inventory(path)?,
+        None => opts.hosts.clone(),
+    };
+    if !hosts.is_empty() {
+        anyhow::ensure!(
+            opts.cmd.supports_hosts(),
+            "this command cannot be used with --hosts or --inventory"
+        );
+        return fanout::run(&hosts, opts.cmd.streams()).await;
+    }
+
     let endpoint = opts
         .endpoint
         .or_else(|| std::env::var("KUMO_KCLI_ENDPOINT").ok())
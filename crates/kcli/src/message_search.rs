@@ -0,0 +1,67 @@
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use kumo_api_types::{MessageSearchV1Entry, MessageSearchV1Request};
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Searches the message index for matching messages.
+///
+/// Returns the queue, status and event history of each matching
+/// message, most recently received first.
+///
+/// The message index must have been enabled via
+/// `kumo.configure_message_index` for this command to work.
+pub struct MessageSearchCommand {
+    /// Only return the message with this spool id
+    #[arg(long)]
+    id: Option<String>,
+
+    /// Only return messages with this envelope sender
+    #[arg(long)]
+    sender: Option<String>,
+
+    /// Only return messages with this envelope recipient
+    #[arg(long)]
+    recipient: Option<String>,
+
+    /// Only return messages with an indexed header or meta
+    /// value matching this NAME=VALUE pair,
+    /// for example `X-Customer-ID=1234`
+    #[arg(long)]
+    attribute: Option<String>,
+
+    /// Only return messages received at or after this time,
+    /// in RFC 3339 format
+    #[arg(long)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only return messages received before this time,
+    /// in RFC 3339 format
+    #[arg(long)]
+    until: Option<DateTime<Utc>>,
+
+    /// The maximum number of messages to return
+    #[arg(long)]
+    limit: Option<usize>,
+}
+
+impl MessageSearchCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/message-search/v1")?;
+        let request = MessageSearchV1Request {
+            id: self.id.clone(),
+            sender: self.sender.clone(),
+            recipient: self.recipient.clone(),
+            attribute: self.attribute.clone(),
+            since: self.since,
+            until: self.until,
+            limit: self.limit,
+        };
+        request.apply_to_url(&mut url);
+
+        let result: Vec<MessageSearchV1Entry> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        crate::output::print(&result)
+    }
+}
@@ -0,0 +1,55 @@
+use clap::Parser;
+use kumo_api_types::mx_pin::{MxPinV1Request, MxPinV1Response};
+use reqwest::Url;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Pin the delivery to a site to a specific MX host or IP address.
+///
+/// While the pin is active, new connections to the site are made
+/// only to the pinned host, rather than following the usual
+/// connection plan.  This can be used to avoid a misbehaving member
+/// of a provider's pool of MX hosts.  The target must be one of the
+/// addresses that the site resolves to; otherwise the pin is ignored.
+///
+/// Pinning a site replaces any existing pin for that site.
+///
+/// ## Example
+///
+///    kcli mx-pin --site mx.example.com --target mx2.example.com --reason "mx1 is rejecting"
+pub struct MxPinCommand {
+    /// The site name, as shown in the ready queue names
+    #[arg(long)]
+    site: String,
+
+    /// The MX host name or IP address to connect to
+    #[arg(long)]
+    target: String,
+
+    /// The reason for the pin
+    #[arg(long)]
+    reason: String,
+
+    /// How long the pin remains active.
+    /// The default is '1h'.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    duration: Option<Duration>,
+}
+
+impl MxPinCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: MxPinV1Response = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/mx-pin/v1")?,
+            &MxPinV1Request {
+                site: self.site.clone(),
+                target: self.target.clone(),
+                reason: self.reason.clone(),
+                duration: self.duration,
+            },
+        )
+        .await?;
+
+        crate::output::print(&result)
+    }
+}
@@ -0,0 +1,27 @@
+use clap::Parser;
+use kumo_api_types::mx_pin::MxPinV1CancelRequest;
+use reqwest::Url;
+use uuid::Uuid;
+
+#[derive(Debug, Parser)]
+/// Cancels an MX pin.
+///
+/// Cancelling the pin restores the usual connection plan for the site.
+pub struct MxPinCancelCommand {
+    /// The id field of the pin that you wish to cancel
+    #[arg(long, value_parser=Uuid::parse_str)]
+    pub id: Uuid,
+}
+
+impl MxPinCancelCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let response = crate::request_with_text_response(
+            reqwest::Method::DELETE,
+            endpoint.join("/api/admin/mx-pin/v1")?,
+            &MxPinV1CancelRequest { id: self.id },
+        )
+        .await?;
+
+        crate::output::print_status(&response)
+    }
+}
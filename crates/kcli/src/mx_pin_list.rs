@@ -0,0 +1,21 @@
+use clap::Parser;
+use kumo_api_types::mx_pin::MxPinV1ListEntry;
+use reqwest::Url;
+
+#[derive(Debug, Parser)]
+/// Returns the list of sites whose delivery is pinned to a specific
+/// MX host or IP address.
+pub struct MxPinListCommand {}
+
+impl MxPinListCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: Vec<MxPinV1ListEntry> = crate::request_with_json_response(
+            reqwest::Method::GET,
+            endpoint.join("/api/admin/mx-pin/v1")?,
+            &(),
+        )
+        .await?;
+
+        crate::output::print(&result)
+    }
+}
@@ -0,0 +1,252 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use tabout::{Alignment, Column};
+
+/// The structured formats in which the results of a command
+/// can be printed
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Pretty printed JSON, or one JSON object per line for
+    /// commands that stream their results
+    Json,
+    /// YAML, with a document per record for commands that
+    /// stream their results
+    Yaml,
+    /// A table with a column per field, or tab separated values
+    /// for commands that stream their results
+    Table,
+    /// Comma separated values, with a header row
+    Csv,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// The columns used by `write_record`, which are established by
+/// the first record that it writes
+static RECORD_COLUMNS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+pub fn set_format(format: OutputFormat) {
+    FORMAT.set(format).ok();
+}
+
+/// Returns the format selected via `--output`, if any
+pub fn format() -> Option<OutputFormat> {
+    FORMAT.get().copied()
+}
+
+/// Prints informational text to stdout, or to stderr when a structured
+/// output format was selected, so that stdout holds only the results
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if $crate::output::format().is_some() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use note;
+
+/// Prints `value` in the selected format, defaulting to pretty JSON
+pub fn print<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    print_as(format().unwrap_or(OutputFormat::Json), value)
+}
+
+/// Prints `value` in the selected format, or calls `text` to produce
+/// the usual output of the command when no format was selected
+pub fn print_or<T: Serialize>(
+    value: &T,
+    text: impl FnOnce() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match format() {
+        Some(format) => print_as(format, value),
+        None => text(),
+    }
+}
+
+/// Like `print_or`, but for commands that already present their results
+/// as a table: `table` is used both when no format was selected and for
+/// the `table` format
+pub fn print_table_or<T: Serialize>(
+    value: &T,
+    table: impl FnOnce() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match format() {
+        Some(OutputFormat::Table) | None => table(),
+        Some(format) => print_as(format, value),
+    }
+}
+
+/// Prints the plain text response of an API that has no structured
+/// response, which is typically empty on success
+pub fn print_status(response: &str) -> anyhow::Result<()> {
+    let status = if response.is_empty() { "OK" } else { response };
+    print_or(&serde_json::json!({"status": status}), || {
+        println!("{status}");
+        Ok(())
+    })
+}
+
+pub fn print_as<T: Serialize>(format: OutputFormat, value: &T) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+        OutputFormat::Table | OutputFormat::Csv => {
+            // An array produces a row per element; anything else
+            // produces a single row
+            let records = match serde_json::to_value(value)? {
+                Value::Array(records) => records,
+                record => vec![record],
+            };
+            let columns = columns_of(&records);
+            let rows: Vec<Vec<String>> = records
+                .iter()
+                .map(|record| row_of(&columns, record))
+                .collect();
+
+            if format == OutputFormat::Table {
+                let columns: Vec<Column> = columns
+                    .iter()
+                    .map(|name| Column {
+                        name: name.to_uppercase(),
+                        alignment: Alignment::Left,
+                    })
+                    .collect();
+                tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            } else {
+                let mut out = std::io::stdout().lock();
+                write_row(&mut out, format, &columns)?;
+                for row in &rows {
+                    write_row(&mut out, format, row)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a single record produced by a command that streams its
+/// results, in the selected format.
+/// For the `table` and `csv` formats, the columns are determined
+/// by the first record.
+pub fn write_record<T: Serialize>(record: &T) -> anyhow::Result<()> {
+    let mut out = std::io::stdout().lock();
+    match format().unwrap_or(OutputFormat::Json) {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string(record)?)?,
+        OutputFormat::Yaml => write!(out, "---\n{}", serde_yaml::to_string(record)?)?,
+        format => {
+            let record = serde_json::to_value(record)?;
+            let mut columns = RECORD_COLUMNS.lock().unwrap();
+            if columns.is_none() {
+                let header = columns_of(std::slice::from_ref(&record));
+                write_row(&mut out, format, &header)?;
+                columns.replace(header);
+            }
+            let columns = columns.as_ref().expect("columns to be set");
+            write_row(&mut out, format, &row_of(columns, &record))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Returns the union of the fields of the records, in the order
+/// in which they are first seen
+fn columns_of(records: &[Value]) -> Vec<String> {
+    let mut columns: Vec<String> = vec![];
+    for record in records {
+        match record {
+            Value::Object(map) => {
+                for key in map.keys() {
+                    if !columns.contains(key) {
+                        columns.push(key.to_string());
+                    }
+                }
+            }
+            _ => {
+                if !columns.iter().any(|c| c == "value") {
+                    columns.push("value".to_string());
+                }
+            }
+        }
+    }
+    columns
+}
+
+fn row_of(columns: &[String], record: &Value) -> Vec<String> {
+    columns
+        .iter()
+        .map(|column| match record {
+            Value::Object(map) => map.get(column).map(cell).unwrap_or_default(),
+            value if column == "value" => cell(value),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+/// Formats a field as a cell; nested values are represented as JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn write_row(out: &mut impl Write, format: OutputFormat, cells: &[String]) -> anyhow::Result<()> {
+    let line = if format == OutputFormat::Csv {
+        cells
+            .iter()
+            .map(|cell| {
+                if cell.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", cell.replace('"', "\"\""))
+                } else {
+                    cell.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    } else {
+        cells
+            .iter()
+            .map(|cell| cell.replace(['\t', '\n', '\r'], " "))
+            .collect::<Vec<_>>()
+            .join("\t")
+    };
+    writeln!(out, "{line}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tabulation() {
+        let records = vec![
+            json!({"id": 1, "reason": "maintenance, planned"}),
+            json!({"id": 2, "reason": "say \"hi\"", "extra": {"a": true}}),
+        ];
+        let columns = columns_of(&records);
+        assert_eq!(columns.len(), 3);
+
+        let mut out = vec![];
+        write_row(&mut out, OutputFormat::Csv, &columns).unwrap();
+        for record in &records {
+            write_row(&mut out, OutputFormat::Csv, &row_of(&columns, record)).unwrap();
+        }
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("\"maintenance, planned\""));
+        assert!(lines[2].contains("\"say \"\"hi\"\"\""));
+        assert!(lines[2].contains("\"{\"\"a\"\":true}\""));
+
+        assert_eq!(columns_of(&[json!("a"), json!(1)]), vec!["value"]);
+        assert_eq!(row_of(&["value".to_string()], &json!(1)), vec!["1"]);
+    }
+}
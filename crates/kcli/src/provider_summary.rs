@@ -8,6 +8,7 @@ use lexicmp::natural_lexical_cmp;
 use message::message::QueueNameComponents;
 use num_format::{Locale, ToFormattedString};
 use reqwest::Url;
+use serde::Serialize;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -78,15 +79,19 @@ pub struct ProviderSummaryCommand {
     limit: Option<usize>,
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize)]
 struct ProviderMetrics {
+    #[serde(rename = "provider")]
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<String>,
     delivered: usize,
     transfail: usize,
     fail: usize,
-    queue_size: usize,
-    pool: Option<String>,
     connections: usize,
+    queue_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domains: Option<String>,
 }
 
 impl ProviderMetrics {
@@ -266,13 +271,12 @@ impl ProviderSummaryCommand {
                 });
             }
 
+            if let Some(limit) = self.limit {
+                provider_by_pool.truncate(limit);
+            }
+
             let mut rows = vec![];
-            for m in &provider_by_pool {
-                if let Some(limit) = self.limit {
-                    if rows.len() >= limit {
-                        break;
-                    }
-                }
+            for m in &mut provider_by_pool {
                 let mut row = vec![
                     m.name.to_string(),
                     m.pool.as_ref().unwrap().to_string(),
@@ -283,14 +287,18 @@ impl ProviderSummaryCommand {
                     m.queue_size.to_formatted_string(&Locale::en),
                 ];
 
-                if let Some(domains) = resolve_domains(&mut site_to_domains, &m.name) {
-                    row.push(domains);
+                m.domains = resolve_domains(&mut site_to_domains, &m.name);
+                if let Some(domains) = &m.domains {
+                    row.push(domains.to_string());
                 }
 
                 rows.push(row);
             }
 
-            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            crate::output::print_table_or(&provider_by_pool, || {
+                tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+                Ok(())
+            })
         } else {
             let mut provider_metrics: Vec<_> =
                 provider_metrics.into_iter().map(|(_k, v)| v).collect();
@@ -333,13 +341,12 @@ impl ProviderSummaryCommand {
                 });
             }
 
+            if let Some(limit) = self.limit {
+                provider_metrics.truncate(limit);
+            }
+
             let mut rows = vec![];
-            for m in &provider_metrics {
-                if let Some(limit) = self.limit {
-                    if rows.len() >= limit {
-                        break;
-                    }
-                }
+            for m in &mut provider_metrics {
                 let mut row = vec![
                     m.name.to_string(),
                     m.delivered.to_formatted_string(&Locale::en),
@@ -349,17 +356,19 @@ impl ProviderSummaryCommand {
                     m.queue_size.to_formatted_string(&Locale::en),
                 ];
 
-                if let Some(domains) = resolve_domains(&mut site_to_domains, &m.name) {
-                    row.push(domains);
+                m.domains = resolve_domains(&mut site_to_domains, &m.name);
+                if let Some(domains) = &m.domains {
+                    row.push(domains.to_string());
                 }
 
                 rows.push(row);
             }
 
-            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            crate::output::print_table_or(&provider_metrics, || {
+                tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+                Ok(())
+            })
         }
-
-        Ok(())
     }
 }
 
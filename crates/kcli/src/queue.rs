@@ -0,0 +1,256 @@
+use crate::output::OutputFormat;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use kumo_api_types::scheduled_queue::{
+    ScheduledQueueV1Message, ScheduledQueueV1Request, ScheduledQueueV1Summary,
+};
+use kumo_api_types::{InspectMessageV1Request, InspectMessageV1Response};
+use num_format::{Locale, ToFormattedString};
+use reqwest::Url;
+use std::time::Duration;
+use tabout::{Alignment, Column};
+
+#[derive(Debug, Parser)]
+/// Inspect the scheduled queues and the messages that they hold.
+///
+/// Unlike `queue-summary`, which is based on the metrics endpoint,
+/// these commands examine the contents of the scheduled queues, so
+/// they can filter by the age and due time of the individual
+/// messages.  Examining a very large queue is relatively expensive
+/// for the server, so prefer to narrow the results with the
+/// `--domain`, `--tenant` or `--campaign` options.
+pub struct QueueCommand {
+    #[command(subcommand)]
+    cmd: QueueSubCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum QueueSubCommand {
+    Ls(QueueLsCommand),
+    Summary(QueueSummaryCommand),
+    Show(QueueShowCommand),
+}
+
+#[derive(Debug, Args)]
+struct QueueFilter {
+    /// Only include queues for this domain
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// Only include queues for this routing domain
+    #[arg(long)]
+    routing_domain: Option<String>,
+
+    /// Only include queues for this tenant
+    #[arg(long)]
+    tenant: Option<String>,
+
+    /// Only include queues for this campaign
+    #[arg(long)]
+    campaign: Option<String>,
+
+    /// Only include messages that were received at least this
+    /// long ago, such as `1h`
+    #[arg(long, value_parser=humantime::parse_duration)]
+    min_age: Option<Duration>,
+
+    /// Only include messages that were received at most this
+    /// long ago, such as `30m`
+    #[arg(long, value_parser=humantime::parse_duration)]
+    max_age: Option<Duration>,
+
+    /// Only include messages whose next delivery attempt is
+    /// due before this time, in RFC 3339 format
+    #[arg(long)]
+    due_before: Option<DateTime<Utc>>,
+
+    /// Only include messages whose next delivery attempt is
+    /// due at or after this time, in RFC 3339 format
+    #[arg(long)]
+    due_after: Option<DateTime<Utc>>,
+
+    /// The maximum number of entries to return.
+    /// The server default is 100.
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Print the results as JSON rather than as a table
+    #[arg(long)]
+    json: bool,
+}
+
+impl QueueFilter {
+    fn apply_to_url(&self, url: &mut Url) {
+        ScheduledQueueV1Request {
+            domain: self.domain.clone(),
+            routing_domain: self.routing_domain.clone(),
+            tenant: self.tenant.clone(),
+            campaign: self.campaign.clone(),
+            id: None,
+            min_age: self.min_age,
+            max_age: self.max_age,
+            due_before: self.due_before,
+            due_after: self.due_after,
+            limit: self.limit,
+        }
+        .apply_to_url(url);
+    }
+}
+
+#[derive(Debug, Parser)]
+/// List the messages in the matching scheduled queues,
+/// soonest due first
+struct QueueLsCommand {
+    #[command(flatten)]
+    filter: QueueFilter,
+}
+
+#[derive(Debug, Parser)]
+/// Summarize the matching scheduled queues, largest first
+struct QueueSummaryCommand {
+    #[command(flatten)]
+    filter: QueueFilter,
+}
+
+#[derive(Debug, Parser)]
+/// Show the metadata of a message, given its spool id
+struct QueueShowCommand {
+    /// Include the message body in the output
+    #[arg(long)]
+    want_body: bool,
+
+    /// The spool id of the message
+    id: String,
+}
+
+/// Formats the time between now and `when` for a human to read
+fn relative(now: DateTime<Utc>, when: DateTime<Utc>) -> String {
+    let delta = (when - now).num_seconds();
+    let duration = humantime::format_duration(Duration::from_secs(delta.unsigned_abs()));
+    if delta > 0 {
+        format!("in {duration}")
+    } else if delta < 0 {
+        format!("{duration} ago")
+    } else {
+        "now".to_string()
+    }
+}
+
+fn column(name: &str, alignment: Alignment) -> Column {
+    Column {
+        name: name.to_string(),
+        alignment,
+    }
+}
+
+impl QueueLsCommand {
+    async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/scheduled-queue-messages/v1")?;
+        self.filter.apply_to_url(&mut url);
+
+        let result: Vec<ScheduledQueueV1Message> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        if self.filter.json {
+            return crate::output::print_as(OutputFormat::Json, &result);
+        }
+
+        let now = Utc::now();
+        let columns = [
+            column("ID", Alignment::Left),
+            column("QUEUE", Alignment::Left),
+            column("RECEIVED", Alignment::Left),
+            column("DUE", Alignment::Left),
+            column("ATTEMPTS", Alignment::Right),
+        ];
+        let rows: Vec<_> = result
+            .iter()
+            .map(|msg| {
+                vec![
+                    msg.id.to_string(),
+                    msg.queue.to_string(),
+                    relative(now, msg.created),
+                    msg.due
+                        .map(|due| relative(now, due))
+                        .unwrap_or_else(|| "now".to_string()),
+                    msg.num_attempts.to_string(),
+                ]
+            })
+            .collect();
+
+        crate::output::print_table_or(&result, || {
+            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            Ok(())
+        })
+    }
+}
+
+impl QueueSummaryCommand {
+    async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/scheduled-queues/v1")?;
+        self.filter.apply_to_url(&mut url);
+
+        let result: Vec<ScheduledQueueV1Summary> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        if self.filter.json {
+            return crate::output::print_as(OutputFormat::Json, &result);
+        }
+
+        let now = Utc::now();
+        let columns = [
+            column("SCHEDULED QUEUE", Alignment::Left),
+            column("COUNT", Alignment::Right),
+            column("OLDEST", Alignment::Left),
+            column("NEXT DUE", Alignment::Left),
+        ];
+        let rows: Vec<_> = result
+            .iter()
+            .map(|queue| {
+                vec![
+                    queue.name.to_string(),
+                    queue.count.to_formatted_string(&Locale::en),
+                    queue
+                        .oldest
+                        .map(|oldest| relative(now, oldest))
+                        .unwrap_or_default(),
+                    queue
+                        .next_due
+                        .map(|due| relative(now, due))
+                        .unwrap_or_else(|| "now".to_string()),
+                ]
+            })
+            .collect();
+
+        crate::output::print_table_or(&result, || {
+            tabout::tabulate_output(&columns, &rows, &mut std::io::stdout())?;
+            Ok(())
+        })
+    }
+}
+
+impl QueueShowCommand {
+    async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut url = endpoint.join("/api/admin/inspect-message/v1")?;
+        let request = InspectMessageV1Request {
+            id: self.id.clone().try_into()?,
+            want_body: self.want_body,
+        };
+        request.apply_to_url(&mut url);
+
+        let result: InspectMessageV1Response =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        crate::output::print(&result)
+    }
+}
+
+impl QueueCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        match &self.cmd {
+            QueueSubCommand::Ls(cmd) => cmd.run(endpoint).await,
+            QueueSubCommand::Summary(cmd) => cmd.run(endpoint).await,
+            QueueSubCommand::Show(cmd) => cmd.run(endpoint).await,
+        }
+    }
+}
@@ -9,6 +9,7 @@ use lexicmp::natural_lexical_cmp;
 use message::message::QueueNameComponents;
 use num_format::{Locale, ToFormattedString};
 use reqwest::Url;
+use serde_json::json;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use tabout::{Alignment, Column};
@@ -369,6 +370,7 @@ impl QueueSummaryCommand {
         ];
 
         let mut ready_rows = vec![];
+        let mut ready_records = vec![];
         for m in &metrics.ready {
             let mut status = vec![];
 
@@ -384,6 +386,16 @@ impl QueueSummaryCommand {
 
             let status = status.join(", ");
 
+            ready_records.push(json!({
+                "site": m.site_name(),
+                "source": m.source(),
+                "protocol": m.protocol(),
+                "delivered": m.delivered,
+                "transfail": m.transfail,
+                "connections": m.connection_count,
+                "queue_size": m.queue_size,
+                "status": status,
+            }));
             ready_rows.push(vec![
                 m.site_name().to_string(),
                 m.source().unwrap_or("").to_string(),
@@ -396,8 +408,6 @@ impl QueueSummaryCommand {
             ]);
         }
 
-        tabout::tabulate_output(&ready_columns, &ready_rows, &mut std::io::stdout())?;
-
         let sched_columns = [
             Column {
                 name: "SCHEDULED QUEUE".to_string(),
@@ -414,6 +424,7 @@ impl QueueSummaryCommand {
         ];
 
         let mut sched_rows = vec![];
+        let mut sched_records = vec![];
         for m in &metrics.scheduled {
             let components = QueueNameComponents::parse(&m.name);
 
@@ -431,6 +442,11 @@ impl QueueSummaryCommand {
                 String::new()
             };
 
+            sched_records.push(json!({
+                "name": m.name,
+                "queue_size": m.queue_size,
+                "status": status,
+            }));
             sched_rows.push(vec![
                 m.name.to_string(),
                 m.queue_size.to_formatted_string(&Locale::en),
@@ -438,11 +454,16 @@ impl QueueSummaryCommand {
             ]);
         }
 
-        println!();
-
-        tabout::tabulate_output(&sched_columns, &sched_rows, &mut std::io::stdout())?;
-
-        Ok(())
+        let summary = json!({
+            "ready_queues": ready_records,
+            "scheduled_queues": sched_records,
+        });
+        crate::output::print_table_or(&summary, || {
+            tabout::tabulate_output(&ready_columns, &ready_rows, &mut std::io::stdout())?;
+            println!();
+            tabout::tabulate_output(&sched_columns, &sched_rows, &mut std::io::stdout())?;
+            Ok(())
+        })
     }
 }
 
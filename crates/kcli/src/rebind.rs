@@ -13,7 +13,8 @@ use std::collections::HashMap;
 /// options.
 ///
 /// Each matching queue has its messages removed and assessed by
-/// the rebinding logic.
+/// the rebinding logic.  If you use `--meta`, only the messages
+/// whose metadata matches are removed from the queues that hold them.
 ///
 /// If `--trigger-rebind-event` is in use, each message will be
 /// passed to the `rebind_message` event, along with the effective
@@ -89,6 +90,12 @@ pub struct RebindCommand {
     #[arg(long)]
     tenant: Option<String>,
 
+    /// Only match messages with this metadata value.
+    /// The key must be declared via `kumo.configure_meta_index`.
+    /// Can be used multiple times, in which case all must match.
+    #[arg(long, value_name="KEY=VALUE", value_parser=ValueParser::new(name_equals_value))]
+    meta: Vec<(String, String)>,
+
     /// The reason to log in the delivery logs (each matching message will
     /// rebind with an AdminRebind record)
     #[arg(long)]
@@ -149,10 +156,11 @@ impl RebindCommand {
             && self.campaign.is_none()
             && self.tenant.is_none()
             && self.routing_domain.is_none()
+            && self.meta.is_empty()
         {
             if !self.everything {
                 anyhow::bail!(
-                    "No domain, routing_domain, campaign or tenant was specified. \
+                    "No domain, routing_domain, campaign, tenant or meta was specified. \
                      Use --everything if you intend to apply to all queues"
                 );
             }
@@ -166,7 +174,7 @@ impl RebindCommand {
             data.insert(k.to_string(), v.to_string());
         }
 
-        let _result: RebindV1Response = crate::request_with_json_response(
+        let result: RebindV1Response = crate::request_with_json_response(
             reqwest::Method::POST,
             endpoint.join("/api/admin/rebind/v1")?,
             &RebindV1Request {
@@ -174,6 +182,7 @@ impl RebindCommand {
                 domain: self.domain.clone(),
                 routing_domain: self.routing_domain.clone(),
                 tenant: self.tenant.clone(),
+                meta: self.meta.iter().cloned().collect(),
                 reason: self.reason.clone(),
                 suppress_logging: self.suppress_logging,
                 data,
@@ -185,6 +194,6 @@ impl RebindCommand {
 
         eprintln!("NOTE: Rebind always runs asynchronously");
 
-        Ok(())
+        crate::output::print_or(&result, || Ok(()))
     }
 }
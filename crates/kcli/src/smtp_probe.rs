@@ -0,0 +1,326 @@
+use anyhow::Context;
+use clap::Parser;
+use reqwest::Url;
+use rfc5321::{
+    Command, DeferredTracer, ForwardPath, ReversePath, SmtpClient, SmtpClientTimeouts,
+    SmtpClientTraceEvent, SmtpClientTracer, TlsOptions, TlsStatus,
+};
+use serde::Serialize;
+use smtp_test_server::{Direction, FakeSmtpServer, SmtpScript};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Parser)]
+/// Connect to an SMTP server and perform a test transaction, using the
+/// same SMTP client implementation that kumod uses for delivery, and
+/// print the transcript of the session.
+///
+/// Rather than connecting to a real server, `--script` starts a fake
+/// SMTP server whose behavior, such as delayed greetings, unusual
+/// replies, dropping the connection during DATA, or failing the TLS
+/// handshake, is described by a JSON file. This allows the way that
+/// the client handles those cases to be reproduced deterministically.
+/// The server side of each session is printed after the client side.
+///
+/// The script is the JSON representation of the `SmtpScript` type
+/// from the `smtp-test-server` crate. For example,
+/// `{"starttls": "HandshakeFailure"}` fails the TLS handshake, and
+/// `{"rules": [{"command": "RCPT", "reply": {"code": 550,
+/// "enhanced_code": "5.1.1", "text": "no such user"}}]}` rejects
+/// the recipient.
+///
+/// ## Examples
+///
+///    kcli smtp-probe --target mx.example.com:25 --starttls
+///
+///    kcli smtp-probe --script ./drop-during-data.json
+///
+/// The command exits with a non-zero status if any connection failed.
+pub struct SmtpProbeCommand {
+    /// The host and port to connect to, such as `mx.example.com:25`
+    #[arg(long, required_unless_present = "script", conflicts_with = "script")]
+    target: Option<String>,
+
+    /// Start a fake SMTP server that behaves according to the
+    /// script in this JSON file, and probe it
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// The name to use in the EHLO command
+    #[arg(long, default_value = "localhost")]
+    ehlo: String,
+
+    /// Use STARTTLS when the server advertises it
+    #[arg(long)]
+    starttls: bool,
+
+    /// Don't verify the certificate presented by the server
+    #[arg(long)]
+    insecure: bool,
+
+    /// The envelope sender of the test message
+    #[arg(long, default_value = "probe@example.com")]
+    sender: String,
+
+    /// The envelope recipient of the test message
+    #[arg(long, default_value = "probe@example.com")]
+    recipient: String,
+
+    /// A file holding the test message. If not specified,
+    /// a short message is generated.
+    #[arg(long)]
+    data: Option<PathBuf>,
+
+    /// Stop after EHLO (and STARTTLS), without sending a message
+    #[arg(long)]
+    no_send: bool,
+
+    /// How many times to connect and perform the transaction
+    #[arg(long, default_value = "1")]
+    connections: usize,
+
+    /// How long to wait for each response from the server
+    #[arg(long, value_parser=humantime::parse_duration, default_value = "20s")]
+    timeout: Duration,
+}
+
+/// Collects the events of a session as transcript lines
+#[derive(Debug, Default)]
+struct Transcript {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Transcript {
+    fn push(&self, line: String) {
+        self.lines.lock().unwrap().push(line);
+    }
+
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.lines.lock().unwrap())
+    }
+}
+
+impl SmtpClientTracer for Transcript {
+    fn trace_event(&self, event: SmtpClientTraceEvent) {
+        match event {
+            SmtpClientTraceEvent::Closed => self.push("(connection closed)".to_string()),
+            SmtpClientTraceEvent::Read(data) => {
+                for line in String::from_utf8_lossy(&data).lines() {
+                    self.push(format!("< {line}"));
+                }
+            }
+            SmtpClientTraceEvent::Write(text) => {
+                for line in text.lines() {
+                    self.push(format!("> {line}"));
+                }
+            }
+            SmtpClientTraceEvent::WriteData(data) => {
+                self.push(format!("> ({} bytes of message data)", data.len()))
+            }
+            SmtpClientTraceEvent::Diagnostic { level, message } => {
+                self.push(format!("({level}: {message})"))
+            }
+        }
+    }
+
+    fn lazy_trace(&self, deferred: &dyn DeferredTracer) {
+        self.trace_event(deferred.trace());
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ProbeResult {
+    connection: usize,
+    success: bool,
+    outcome: String,
+    /// How long the session took, in seconds
+    elapsed: f64,
+    transcript: Vec<String>,
+    /// The transcript of the fake server, when using --script
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    server_transcript: Vec<String>,
+}
+
+impl SmtpProbeCommand {
+    fn timeouts(&self) -> SmtpClientTimeouts {
+        let t = self.timeout;
+        SmtpClientTimeouts {
+            connect_timeout: t,
+            banner_timeout: t,
+            ehlo_timeout: t,
+            mail_from_timeout: t,
+            rcpt_to_timeout: t,
+            data_timeout: t,
+            data_dot_timeout: t,
+            rset_timeout: t,
+            idle_timeout: t,
+            starttls_timeout: t,
+            auth_timeout: t,
+        }
+    }
+
+    fn message_data(&self) -> anyhow::Result<Vec<u8>> {
+        match &self.data {
+            Some(path) => {
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))
+            }
+            None => Ok(format!(
+                "From: <{sender}>\r\n\
+                 To: <{recipient}>\r\n\
+                 Subject: kcli smtp-probe\r\n\
+                 \r\n\
+                 This is a test message sent by kcli smtp-probe.\r\n",
+                sender = self.sender,
+                recipient = self.recipient
+            )
+            .into_bytes()),
+        }
+    }
+
+    /// Performs a single session, returning a description
+    /// of its outcome
+    async fn probe(
+        &self,
+        target: &str,
+        data: &[u8],
+        transcript: &Arc<Transcript>,
+    ) -> anyhow::Result<String> {
+        let timeouts = self.timeouts();
+        let mut client = tokio::time::timeout(
+            timeouts.connect_timeout,
+            SmtpClient::new(target, timeouts.clone()),
+        )
+        .await
+        .with_context(|| format!("timed out connecting to {target}"))?
+        .with_context(|| format!("connecting to {target}"))?;
+        client.set_tracer(transcript.clone());
+
+        let banner = client.read_response(None, timeouts.banner_timeout).await?;
+        if banner.code != 220 {
+            return Err(anyhow::anyhow!(
+                "greeting was not 220: {}",
+                banner.to_single_line()
+            ));
+        }
+
+        client.ehlo(&self.ehlo).await?;
+
+        if self.starttls && client.has_capability("STARTTLS") {
+            let status = client
+                .starttls(TlsOptions {
+                    insecure: self.insecure,
+                    ..Default::default()
+                })
+                .await?;
+            if let TlsStatus::FailedHandshake(err) = status {
+                anyhow::bail!("TLS handshake failed: {err}");
+            }
+            client.ehlo(&self.ehlo).await?;
+        }
+
+        let outcome = if self.no_send {
+            "EHLO completed".to_string()
+        } else {
+            let sender = ReversePath::try_from(self.sender.as_str())
+                .map_err(|err| anyhow::anyhow!("invalid sender {}: {err}", self.sender))?;
+            let recipient = ForwardPath::try_from(self.recipient.as_str())
+                .map_err(|err| anyhow::anyhow!("invalid recipient {}: {err}", self.recipient))?;
+            let response = client.send_mail(sender, recipient, data).await?;
+            format!("message accepted: {}", response.to_single_line())
+        };
+
+        client.send_command(&Command::Quit).await.ok();
+        Ok(outcome)
+    }
+
+    pub async fn run(&self, _endpoint: &Url) -> anyhow::Result<()> {
+        let server = match &self.script {
+            Some(path) => {
+                let script = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let script: SmtpScript = serde_json::from_str(&script)
+                    .with_context(|| format!("parsing {}", path.display()))?;
+                Some(FakeSmtpServer::start(script).await?)
+            }
+            None => None,
+        };
+        let target = match (&server, &self.target) {
+            (Some(server), _) => server.addr().to_string(),
+            (None, Some(target)) => target.to_string(),
+            (None, None) => anyhow::bail!("one of --target or --script is required"),
+        };
+        let data = self.message_data()?;
+
+        let mut results = vec![];
+        for connection in 1..=self.connections {
+            let transcript = Arc::new(Transcript::default());
+            let start = Instant::now();
+            let result = self.probe(&target, &data, &transcript).await;
+            let elapsed = start.elapsed().as_secs_f64();
+
+            let server_transcript = match &server {
+                Some(server) => {
+                    // Give the server a moment to record the end of the session
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    server
+                        .sessions()
+                        .into_iter()
+                        .find(|session| session.session == connection)
+                        .map(|session| {
+                            session
+                                .transcript
+                                .into_iter()
+                                .map(|entry| match entry.direction {
+                                    Direction::Client => format!("< {}", entry.text),
+                                    Direction::Server => format!("> {}", entry.text),
+                                    Direction::Note => format!("({})", entry.text),
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default()
+                }
+                None => vec![],
+            };
+
+            results.push(ProbeResult {
+                connection,
+                success: result.is_ok(),
+                outcome: match result {
+                    Ok(outcome) => outcome,
+                    Err(err) => format!("{err:#}"),
+                },
+                elapsed,
+                transcript: transcript.take(),
+                server_transcript,
+            });
+        }
+
+        crate::output::print_or(&results, || {
+            for result in &results {
+                println!("Connection {} to {target}:", result.connection);
+                for line in &result.transcript {
+                    println!("  {line}");
+                }
+                if !result.server_transcript.is_empty() {
+                    println!("Server side:");
+                    for line in &result.server_transcript {
+                        println!("  {line}");
+                    }
+                }
+                println!(
+                    "{}: {} ({:.3}s)\n",
+                    if result.success { "OK" } else { "FAILED" },
+                    result.outcome,
+                    result.elapsed
+                );
+            }
+            Ok(())
+        })?;
+
+        if results.iter().any(|result| !result.success) {
+            anyhow::bail!("one or more connections failed");
+        }
+        Ok(())
+    }
+}
@@ -60,8 +60,6 @@ impl SuspendCommand {
         )
         .await?;
 
-        println!("{}", serde_json::to_string_pretty(&result)?);
-
-        Ok(())
+        crate::output::print(&result)
     }
 }
@@ -24,12 +24,6 @@ impl SuspendCancelCommand {
         )
         .await?;
 
-        if !response.is_empty() {
-            println!("{response}");
-        } else {
-            println!("OK");
-        }
-
-        Ok(())
+        crate::output::print_status(&response)
     }
 }
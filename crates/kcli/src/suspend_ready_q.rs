@@ -34,8 +34,6 @@ impl SuspendReadyQCommand {
         )
         .await?;
 
-        println!("{}", serde_json::to_string_pretty(&result)?);
-
-        Ok(())
+        crate::output::print(&result)
     }
 }
@@ -23,12 +23,6 @@ impl SuspendReadyQCancelCommand {
         )
         .await?;
 
-        if !response.is_empty() {
-            println!("{response}");
-        } else {
-            println!("OK");
-        }
-
-        Ok(())
+        crate::output::print_status(&response)
     }
 }
@@ -18,8 +18,6 @@ impl SuspendReadyQListCommand {
         )
         .await?;
 
-        println!("{}", serde_json::to_string_pretty(&result)?);
-
-        Ok(())
+        crate::output::print(&result)
     }
 }
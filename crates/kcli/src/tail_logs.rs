@@ -0,0 +1,83 @@
+use clap::Parser;
+use kumo_api_types::TailLogsV1Request;
+use reqwest::Url;
+use tokio_tungstenite::tungstenite::{connect, Message};
+
+/// Stream log records from kumod as they are generated.
+///
+/// This is a diagnostic tool for the server operator.
+///
+/// Each matching record is printed as a single line of JSON.
+/// The filter expression is evaluated by the server, so that
+/// only the records of interest are sent to kcli.
+///
+/// Take care to use an appropriate `--filter` and/or `--sample-rate`
+/// when using this with a live busy server, as you will be
+/// overwhelmed by the traffic.
+#[derive(Debug, Parser)]
+pub struct TailLogsCommand {
+    /// Only show records that match this filter expression.
+    ///
+    /// Comparisons take the form `FIELD OP VALUE`, where FIELD is a
+    /// dotted path into the JSON log record, such as `type`,
+    /// `response.code`, `meta.customer` or the queue components
+    /// `tenant`, `campaign` and `domain`. OP is one of `==`, `!=`,
+    /// `<`, `<=`, `>`, `>=`, `=~` (regex match) or `!~` (regex does
+    /// not match). Comparisons may be combined using `and`, `or`,
+    /// `not` and parentheses.
+    ///
+    /// Eg: --filter 'type == "TransientFailure" and response.content =~ "(?i)greylist"'
+    #[arg(long)]
+    pub filter: Option<String>,
+
+    /// Only show approximately this fraction of the matching records,
+    /// where 1.0 shows all of them and 0.01 shows roughly one in
+    /// every hundred
+    #[arg(long)]
+    pub sample_rate: Option<f64>,
+
+    /// Pretty print each record, rather than printing it on a single line
+    #[arg(long)]
+    pub pretty: bool,
+}
+
+impl TailLogsCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let mut endpoint = endpoint.join("/api/admin/tail-logs/v1")?;
+        endpoint.set_scheme("ws").expect("ws to be valid scheme");
+
+        let (mut socket, _response) = connect(endpoint.to_string())?;
+
+        socket.send(Message::Text(serde_json::to_string(&TailLogsV1Request {
+            filter: self.filter.clone(),
+            sample_rate: self.sample_rate,
+        })?))?;
+
+        loop {
+            let msg = socket.read()?;
+            match msg {
+                Message::Text(s) => {
+                    if crate::output::format().is_some() {
+                        let record: serde_json::Value = serde_json::from_str(&s)?;
+                        crate::output::write_record(&record)?;
+                    } else if self.pretty {
+                        let record: serde_json::Value = serde_json::from_str(&s)?;
+                        println!("{}", serde_json::to_string_pretty(&record)?);
+                    } else {
+                        println!("{s}");
+                    }
+                }
+                Message::Close(Some(frame)) => {
+                    anyhow::bail!("{}", frame.reason);
+                }
+                Message::Close(None) => {
+                    return Ok(());
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                _ => {
+                    anyhow::bail!("Unexpected {msg:?} response");
+                }
+            }
+        }
+    }
+}
@@ -24,6 +24,11 @@ pub struct TopCommand {
 
 impl TopCommand {
     pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            crate::output::format().is_none(),
+            "top is interactive and does not support --output; \
+             use queue-summary or provider-summary instead"
+        );
         initialize_panic_handler();
         startup()?;
 
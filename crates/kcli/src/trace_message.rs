@@ -0,0 +1,423 @@
+use crate::output::note;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Parser;
+use kumo_api_types::scheduled_queue::{ScheduledQueueV1Message, ScheduledQueueV1Request};
+use kumo_api_types::{
+    InspectMessageV1Request, InspectMessageV1Response, MessageSearchV1Entry, MessageSearchV1Event,
+    MessageSearchV1Request, MessageSearchV1Status, TailLogsV1Request,
+};
+use kumo_log_types::{JsonLogRecord, RecordType};
+use message::message::QueueNameComponents;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tokio_tungstenite::tungstenite::{connect, Message};
+
+#[derive(Debug, Parser)]
+/// Follow a message from reception through to its final disposition.
+///
+/// Combines the event history recorded in the message index with the
+/// current state of the spool and scheduled queues into a single
+/// chronological view of the reception, each delivery attempt and
+/// its response, and the final disposition of the message.
+///
+/// The message index must have been enabled via
+/// `kumo.configure_message_index` for the history to be available.
+/// When it has not been enabled, a message can still be traced by
+/// its `--id`, but only its current state is shown.
+///
+/// With `--follow`, messages that have not yet reached their final
+/// disposition are watched via the log tailing endpoint, and their
+/// events are printed as they occur, until they have all reached
+/// their final disposition.
+pub struct TraceMessageCommand {
+    /// The spool id of the message to trace
+    #[arg(
+        long,
+        required_unless_present = "recipient",
+        conflicts_with = "recipient"
+    )]
+    id: Option<String>,
+
+    /// Trace the messages sent to this envelope recipient
+    #[arg(long)]
+    recipient: Option<String>,
+
+    /// When tracing by recipient, only consider messages received
+    /// at or after this time, in RFC 3339 format
+    #[arg(long, requires = "recipient")]
+    since: Option<DateTime<Utc>>,
+
+    /// When tracing by recipient, only consider messages received
+    /// before this time, in RFC 3339 format
+    #[arg(long, requires = "recipient")]
+    until: Option<DateTime<Utc>>,
+
+    /// When tracing by recipient, the maximum number of messages
+    /// to trace. The default is 10.
+    #[arg(long, requires = "recipient")]
+    limit: Option<usize>,
+
+    /// Keep watching messages that have not yet reached their final
+    /// disposition, printing their events as they occur
+    #[arg(long)]
+    follow: bool,
+}
+
+const DEFAULT_LIMIT: usize = 10;
+
+/// Returns true if an event of this kind concludes the life of a message
+fn is_final(kind: &str) -> bool {
+    matches!(kind, "Delivery" | "Bounce" | "AdminBounce" | "Expiration")
+}
+
+/// Produces a tail-logs filter expression matching any of the ids
+fn filter_for_ids<'a>(ids: impl IntoIterator<Item = &'a String>) -> String {
+    ids.into_iter()
+        .map(|id| {
+            let id = id.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("id == \"{id}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+fn format_time(when: DateTime<Utc>) -> String {
+    when.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// An event in the life of a message, normalized from either the
+/// message index or a log record
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+struct TraceEvent {
+    timestamp: DateTime<Utc>,
+    kind: String,
+    queue: String,
+    site: String,
+    response: String,
+    num_attempts: u16,
+}
+
+impl TraceEvent {
+    fn from_search_event(event: &MessageSearchV1Event) -> Self {
+        Self {
+            timestamp: event.timestamp,
+            kind: event.kind.to_string(),
+            queue: event.queue.to_string(),
+            site: event.site.to_string(),
+            response: event.response.to_string(),
+            num_attempts: event.num_attempts,
+        }
+    }
+
+    fn from_log_record(record: &JsonLogRecord) -> Option<Self> {
+        match record.kind {
+            RecordType::Reception
+            | RecordType::Delivery
+            | RecordType::Bounce
+            | RecordType::TransientFailure
+            | RecordType::Expiration
+            | RecordType::AdminBounce
+            | RecordType::AdminRebind
+            | RecordType::DeferredInjectionRebind => {}
+            RecordType::OOB
+            | RecordType::Feedback
+            | RecordType::Rejection
+            | RecordType::TenantUsage
+            | RecordType::Unsubscribe
+            | RecordType::Any => return None,
+        }
+        Some(Self {
+            timestamp: record.timestamp,
+            kind: format!("{:?}", record.kind),
+            queue: record.queue.to_string(),
+            site: record.site.to_string(),
+            response: record.response.to_single_line(),
+            num_attempts: record.num_attempts,
+        })
+    }
+
+    fn print(&self, prefix: &str) {
+        let mut line = format!(
+            "{prefix}{} {:<16} attempts={} queue={}",
+            format_time(self.timestamp),
+            self.kind,
+            self.num_attempts,
+            self.queue
+        );
+        if !self.site.is_empty() {
+            line.push_str(&format!(" site={}", self.site));
+        }
+        println!("{line}");
+        if !self.response.trim().is_empty() {
+            println!("{prefix}    {}", self.response.trim());
+        }
+    }
+}
+
+/// An event along with the id of its message, as written when
+/// a structured output format was selected
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    id: &'a str,
+    #[serde(flatten)]
+    event: &'a TraceEvent,
+}
+
+/// Tracks what has been shown for a given message
+#[derive(Default)]
+struct TracedMessage {
+    seen: HashSet<TraceEvent>,
+    done: bool,
+}
+
+impl TracedMessage {
+    /// Prints the event unless it has been printed already
+    fn show(&mut self, id: &str, event: TraceEvent, prefix: &str) -> anyhow::Result<()> {
+        if self.done || self.seen.contains(&event) {
+            return Ok(());
+        }
+        if crate::output::format().is_some() {
+            crate::output::write_record(&TraceRecord { id, event: &event })?;
+        } else {
+            event.print(prefix);
+        }
+        if is_final(&event.kind) {
+            note!("{prefix}Final disposition: {}", event.kind);
+            self.done = true;
+        }
+        self.seen.insert(event);
+        Ok(())
+    }
+}
+
+impl TraceMessageCommand {
+    pub fn follows(&self) -> bool {
+        self.follow
+    }
+
+    async fn search(
+        &self,
+        endpoint: &Url,
+        id: Option<&str>,
+    ) -> anyhow::Result<Vec<MessageSearchV1Entry>> {
+        let mut url = endpoint.join("/api/admin/message-search/v1")?;
+        let request = match id {
+            Some(id) => MessageSearchV1Request {
+                id: Some(id.to_string()),
+                ..Default::default()
+            },
+            None => MessageSearchV1Request {
+                recipient: self.recipient.clone(),
+                since: self.since,
+                until: self.until,
+                limit: Some(self.limit.unwrap_or(DEFAULT_LIMIT)),
+                ..Default::default()
+            },
+        };
+        request.apply_to_url(&mut url);
+
+        let mut result: Vec<MessageSearchV1Entry> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+        // The search returns the most recently received first
+        result.reverse();
+        Ok(result)
+    }
+
+    /// Describes where the message is right now, based on the
+    /// spool and the scheduled queues.
+    /// Returns false if the message is no longer in the spool.
+    async fn show_current_state(
+        &self,
+        endpoint: &Url,
+        id: &str,
+        queue: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let mut url = endpoint.join("/api/admin/inspect-message/v1")?;
+        InspectMessageV1Request {
+            id: id.to_string().try_into()?,
+            want_body: false,
+        }
+        .apply_to_url(&mut url);
+        let inspect: anyhow::Result<InspectMessageV1Response> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await;
+        let info = match inspect {
+            Ok(info) => info,
+            Err(err) => {
+                note!("Not present in the spool: {err:#}");
+                return Ok(false);
+            }
+        };
+        if queue.is_none() {
+            note!("Sender: {}", info.message.sender);
+            note!("Recipient: {}", info.message.recipient);
+        }
+
+        let mut request = ScheduledQueueV1Request {
+            id: Some(id.to_string()),
+            ..Default::default()
+        };
+        if let Some(queue) = queue {
+            // Narrow the search to the queue that the message was
+            // last known to be in, to avoid visiting every queue
+            let components = QueueNameComponents::parse(queue);
+            request.domain = Some(components.domain.to_string());
+            request.routing_domain = components.routing_domain.map(|s| s.to_string());
+            request.tenant = components.tenant.map(|s| s.to_string());
+            request.campaign = components.campaign.map(|s| s.to_string());
+        }
+        let mut url = endpoint.join("/api/admin/scheduled-queue-messages/v1")?;
+        request.apply_to_url(&mut url);
+        let scheduled: Vec<ScheduledQueueV1Message> =
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?;
+
+        match scheduled.first() {
+            Some(entry) => match entry.due {
+                Some(due) => note!(
+                    "Scheduled in {}: attempt {} is due at {}",
+                    entry.queue,
+                    entry.num_attempts + 1,
+                    format_time(due)
+                ),
+                None => note!(
+                    "Scheduled in {}: eligible for immediate delivery",
+                    entry.queue
+                ),
+            },
+            None => note!(
+                "In the spool, but not in a scheduled queue: \
+                 it is in a ready queue or is being delivered"
+            ),
+        }
+
+        Ok(true)
+    }
+
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let entries = match self.search(endpoint, self.id.as_deref()).await {
+            Ok(entries) => entries,
+            Err(err) if self.id.is_some() => {
+                eprintln!("Message history is unavailable: {err:#}");
+                vec![]
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut traced: HashMap<String, TracedMessage> = HashMap::new();
+
+        if entries.is_empty() {
+            let Some(id) = &self.id else {
+                anyhow::bail!("no matching messages were found in the message index");
+            };
+            note!("Message {id}");
+            let in_spool = self.show_current_state(endpoint, id, None).await?;
+            traced.insert(
+                id.to_string(),
+                TracedMessage {
+                    done: !in_spool,
+                    ..Default::default()
+                },
+            );
+        }
+
+        for entry in &entries {
+            note!("Message {}", entry.id);
+            note!("Sender: {}", entry.sender);
+            note!("Recipient: {}", entry.recipient);
+            note!("Received: {}", format_time(entry.created));
+
+            let mut message = TracedMessage::default();
+            for event in &entry.events {
+                message.show(&entry.id, TraceEvent::from_search_event(event), "")?;
+            }
+            if entry.status == MessageSearchV1Status::Queued && !message.done {
+                let in_spool = self
+                    .show_current_state(endpoint, &entry.id, Some(&entry.queue))
+                    .await?;
+                if !in_spool {
+                    message.done = true;
+                }
+            }
+            note!();
+            traced.insert(entry.id.to_string(), message);
+        }
+
+        traced.retain(|_, message| !message.done);
+        if !self.follow || traced.is_empty() {
+            return Ok(());
+        }
+
+        let multiple = traced.len() > 1;
+        let prefix_for = |id: &str| {
+            if multiple {
+                format!("{id}: ")
+            } else {
+                String::new()
+            }
+        };
+
+        let mut ws_url = endpoint.join("/api/admin/tail-logs/v1")?;
+        ws_url.set_scheme("ws").expect("ws to be valid scheme");
+        let (mut socket, _response) = connect(ws_url.to_string())?;
+        socket.send(Message::Text(serde_json::to_string(&TailLogsV1Request {
+            filter: Some(filter_for_ids(traced.keys())),
+            sample_rate: None,
+        })?))?;
+        note!("Following; press CTRL-C to stop");
+
+        // Events that occurred between the search above and the
+        // subscription being established were not sent over the
+        // websocket, so look for them in the index now that any
+        // subsequent events are guaranteed to be delivered to us
+        if !entries.is_empty() {
+            for (id, message) in traced.iter_mut() {
+                if let Ok(found) = self.search(endpoint, Some(id)).await {
+                    let prefix = prefix_for(id);
+                    for event in found.iter().flat_map(|entry| entry.events.iter()) {
+                        message.show(id, TraceEvent::from_search_event(event), &prefix)?;
+                    }
+                }
+            }
+        }
+
+        loop {
+            if traced.values().all(|message| message.done) {
+                return Ok(());
+            }
+            let msg = socket.read()?;
+            match msg {
+                Message::Text(s) => {
+                    let record: JsonLogRecord = serde_json::from_str(&s)?;
+                    let prefix = prefix_for(&record.id);
+                    if let (Some(event), Some(message)) = (
+                        TraceEvent::from_log_record(&record),
+                        traced.get_mut(&record.id),
+                    ) {
+                        message.show(&record.id, event, &prefix)?;
+                    }
+                }
+                Message::Close(Some(frame)) => {
+                    anyhow::bail!("{}", frame.reason);
+                }
+                Message::Close(None) => {
+                    return Ok(());
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                _ => {
+                    anyhow::bail!("Unexpected {msg:?} response");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn id_filter() {
+        let ids = vec!["abc".to_string(), "d\"e\\f".to_string()];
+        assert_eq!(filter_for_ids(&ids), r#"id == "abc" or id == "d\"e\\f""#);
+    }
+}
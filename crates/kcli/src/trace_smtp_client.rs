@@ -310,6 +310,17 @@ impl TraceSmtpClientCommand {
                         }
                     };
 
+                    if crate::output::format().is_some() {
+                        crate::output::write_record(&event)?;
+                        if event.payload == TraceSmtpClientV1Payload::Closed {
+                            meta_by_conn.remove(&key);
+                            if self.only_one {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+
                     match event.payload {
                         TraceSmtpClientV1Payload::BeginSession => {
                             println!("[{key}] {delta} === BeginSession {}", event.when);
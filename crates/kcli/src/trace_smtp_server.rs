@@ -149,6 +149,17 @@ impl TraceSmtpServerCommand {
                         }
                     };
 
+                    if crate::output::format().is_some() {
+                        crate::output::write_record(&event)?;
+                        if event.payload == TraceSmtpV1Payload::Closed {
+                            meta_by_conn.remove(&key);
+                            if self.only_one {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+
                     match event.payload {
                         TraceSmtpV1Payload::Connected => {
                             println!("[{key}] {delta} === Connected {}", event.when,);
@@ -0,0 +1,141 @@
+use crate::rebind::name_equals_value;
+use clap::builder::ValueParser;
+use clap::Parser;
+use kumo_api_types::tuning::{ReadyQueueTuningV1, TuningV1Request, TuningV1Response};
+use reqwest::Url;
+use std::time::Duration;
+use throttle::ThrottleSpec;
+
+#[derive(Debug, Parser)]
+/// Adjust throttles, limits and cache capacities at runtime.
+///
+/// The adjustments take effect immediately, without reloading
+/// the policy, and are recorded in the audit log.  They are not
+/// persisted: they are lost when kumod is restarted.
+///
+/// When no adjustments are specified, the adjustments currently
+/// in effect are printed.  Otherwise, the adjustments are applied
+/// and the resulting state is printed.
+///
+/// ## Examples
+///
+/// Limit the number of connections to a site:
+///
+///    kcli tuning --ready-queue '(alt1|alt2|alt3|alt4)?.gmail-smtp-in.l.google.com' --connection-limit 8
+///
+/// Revert to the limits defined by the policy:
+///
+///    kcli tuning --reset-ready-queue '(alt1|alt2|alt3|alt4)?.gmail-smtp-in.l.google.com'
+///
+/// Increase the capacity of the MX cache and shorten DNS timeouts:
+///
+///    kcli tuning --cache-capacity dns_resolver_mx=128000 --dns-query-timeout 5s
+pub struct TuningCommand {
+    /// The ready queue name, or site name, to which the
+    /// `--connection-limit`, `--max-ready`, `--max-deliveries-per-connection`,
+    /// `--max-message-rate` and `--max-connection-rate` options apply.
+    /// Any existing overrides for it are replaced.
+    #[arg(long)]
+    ready_queue: Option<String>,
+
+    /// The maximum number of concurrent connections
+    #[arg(long, requires = "ready_queue")]
+    connection_limit: Option<usize>,
+
+    /// The maximum number of messages held in the ready queue
+    #[arg(long, requires = "ready_queue")]
+    max_ready: Option<usize>,
+
+    /// The maximum number of messages delivered over a connection
+    #[arg(long, requires = "ready_queue")]
+    max_deliveries_per_connection: Option<usize>,
+
+    /// The rate at which messages may be delivered, such as `100/s`
+    #[arg(long, requires = "ready_queue", value_parser=parse_throttle)]
+    max_message_rate: Option<ThrottleSpec>,
+
+    /// The rate at which connections may be established, such as `10/s`
+    #[arg(long, requires = "ready_queue", value_parser=parse_throttle)]
+    max_connection_rate: Option<ThrottleSpec>,
+
+    /// Remove the overrides for a ready queue or site name,
+    /// reverting to the configuration defined by the policy.
+    /// Can be used multiple times.
+    #[arg(long)]
+    reset_ready_queue: Vec<String>,
+
+    /// The time limit for each DNS query, such as `5s`.
+    /// Use `0s` to remove the limit.
+    #[arg(long, value_parser=humantime::parse_duration)]
+    dns_query_timeout: Option<Duration>,
+
+    /// Change the capacity of an lruttl cache.
+    /// Can be used multiple times.
+    #[arg(long, name="NAME=CAPACITY", value_parser=ValueParser::new(name_equals_value))]
+    cache_capacity: Vec<(String, String)>,
+
+    /// Override the soft memory limit, in bytes.
+    /// Use `0` to revert to the limit detected from the environment.
+    #[arg(long)]
+    memory_soft_limit: Option<u64>,
+
+    /// The percentage of the soft memory limit above which
+    /// memory is considered to be low
+    #[arg(long)]
+    low_memory_threshold: Option<u8>,
+}
+
+fn parse_throttle(arg: &str) -> Result<ThrottleSpec, String> {
+    ThrottleSpec::try_from(arg)
+}
+
+impl TuningCommand {
+    fn build_request(&self) -> anyhow::Result<TuningV1Request> {
+        let mut request = TuningV1Request {
+            reset_ready_queues: self.reset_ready_queue.clone(),
+            dns_query_timeout: self.dns_query_timeout,
+            memory_soft_limit: self.memory_soft_limit,
+            low_memory_threshold: self.low_memory_threshold,
+            ..Default::default()
+        };
+
+        if let Some(name) = &self.ready_queue {
+            request.ready_queues.push(ReadyQueueTuningV1 {
+                name: name.to_string(),
+                connection_limit: self.connection_limit,
+                max_ready: self.max_ready,
+                max_deliveries_per_connection: self.max_deliveries_per_connection,
+                max_message_rate: self.max_message_rate.clone(),
+                max_connection_rate: self.max_connection_rate.clone(),
+            });
+        }
+
+        for (name, capacity) in &self.cache_capacity {
+            let capacity: usize = capacity
+                .parse()
+                .map_err(|err| anyhow::anyhow!("invalid capacity {capacity} for {name}: {err}"))?;
+            request.cache_capacities.insert(name.to_string(), capacity);
+        }
+
+        Ok(request)
+    }
+
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let request = self.build_request()?;
+        let url = endpoint.join("/api/admin/tuning/v1")?;
+
+        let result: TuningV1Response = if request.ready_queues.is_empty()
+            && request.reset_ready_queues.is_empty()
+            && request.dns_query_timeout.is_none()
+            && request.cache_capacities.is_empty()
+            && request.memory_soft_limit.is_none()
+            && request.low_memory_threshold.is_none()
+        {
+            crate::request_with_json_response(reqwest::Method::GET, url, &()).await?
+        } else {
+            crate::request_with_json_response(reqwest::Method::POST, url, &request).await?
+        };
+
+        crate::output::print(&result)
+    }
+}
@@ -0,0 +1,153 @@
+use crate::output::OutputFormat;
+use anyhow::Context;
+use clap::Parser;
+use kumo_api_types::config_snapshot::{ConfigChange, ConfigChangeKind, ConfigSnapshotV1};
+use reqwest::Url;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+/// Validate a policy, and optionally preview the effect of
+/// deploying it to a running node.
+///
+/// The policy is loaded by running `kumod --validate`, so kumod
+/// must be installed on the machine running kcli, and kcli should
+/// be run as the same user that kumod runs as, so that the policy
+/// can access the same files.
+///
+/// When `--against` is used, the listener, logging, queue and egress
+/// path configuration that the node is currently using is compared
+/// with the configuration that the local policy would produce for the
+/// same queues and egress paths, and the differences are printed.
+/// Any runtime adjustments made via `kcli tuning` are reported as
+/// differences in the egress path configuration.
+///
+/// ## Example
+///
+///    kcli validate-config --policy ./init.lua --against http://mx1.example.com:8000
+pub struct ValidateConfigCommand {
+    /// The policy file to validate
+    #[arg(long, default_value = "/opt/kumomta/etc/policy/init.lua")]
+    policy: PathBuf,
+
+    /// The kumod executable that is used to load the policy
+    #[arg(long, default_value = "/opt/kumomta/sbin/kumod")]
+    kumod: PathBuf,
+
+    /// The HTTP endpoint of a running node whose configuration
+    /// should be compared with that of the policy,
+    /// such as `http://mx1.example.com:8000`
+    #[arg(long)]
+    against: Option<String>,
+
+    /// Print the differences as JSON rather than as text
+    #[arg(long, requires = "against")]
+    json: bool,
+}
+
+fn print_change(change: &ConfigChange) {
+    let marker = match change.kind {
+        ConfigChangeKind::Added => '+',
+        ConfigChangeKind::Removed => '-',
+        ConfigChangeKind::Changed => '~',
+    };
+    println!("  {marker} {}", change.key);
+    for field in &change.fields {
+        let show = |value: &Option<serde_json::Value>| match value {
+            Some(value) => value.to_string(),
+            None => "(unset)".to_string(),
+        };
+        println!(
+            "      {}: {} -> {}",
+            field.path,
+            show(&field.before),
+            show(&field.after)
+        );
+    }
+}
+
+impl ValidateConfigCommand {
+    async fn fetch_running_snapshot(&self, against: &str) -> anyhow::Result<ConfigSnapshotV1> {
+        let against = if against.contains("://") {
+            against.to_string()
+        } else {
+            format!("http://{against}")
+        };
+        let url = Url::parse(&against)
+            .with_context(|| format!("parsing {against}"))?
+            .join("/api/admin/config-snapshot/v1")?;
+        crate::request_with_json_response(reqwest::Method::GET, url, &()).await
+    }
+
+    pub async fn run(&self, _endpoint: &Url) -> anyhow::Result<()> {
+        let running = match &self.against {
+            Some(against) => Some(
+                self.fetch_running_snapshot(against)
+                    .await
+                    .with_context(|| format!("obtaining the configuration of {against}"))?,
+            ),
+            None => None,
+        };
+
+        let dir = tempfile::tempdir()?;
+        let local_path = dir.path().join("local.json");
+
+        let mut command = tokio::process::Command::new(&self.kumod);
+        command.arg("--validate").arg("--policy").arg(&self.policy);
+        if let Some(running) = &running {
+            let reference_path = dir.path().join("reference.json");
+            std::fs::write(&reference_path, serde_json::to_string(running)?)?;
+            command
+                .arg("--dump-config")
+                .arg(&local_path)
+                .arg("--dump-config-reference")
+                .arg(&reference_path);
+        }
+
+        let status = command
+            .status()
+            .await
+            .with_context(|| format!("running {}", self.kumod.display()))?;
+        anyhow::ensure!(
+            status.success(),
+            "{} failed validation ({status})",
+            self.policy.display()
+        );
+
+        let Some(running) = running else {
+            return crate::output::print_or(
+                &serde_json::json!({"policy": self.policy, "valid": true}),
+                || {
+                    println!("{} is valid", self.policy.display());
+                    Ok(())
+                },
+            );
+        };
+
+        let data = std::fs::read(&local_path)
+            .with_context(|| format!("reading {}", local_path.display()))?;
+        let local: ConfigSnapshotV1 = serde_json::from_slice(&data)?;
+        let changes = running.diff(&local);
+
+        if self.json {
+            return crate::output::print_as(OutputFormat::Json, &changes);
+        }
+
+        crate::output::print_or(&changes, || {
+            if changes.is_empty() {
+                println!("No differences");
+                return Ok(());
+            }
+
+            let mut section = None;
+            for change in &changes {
+                if section != Some(&change.section) {
+                    println!("{}:", change.section);
+                    section.replace(&change.section);
+                }
+                print_change(change);
+            }
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,56 @@
+use clap::Parser;
+use kumo_api_types::wire_capture::{WireCaptureV1Request, WireCaptureV1Response};
+use reqwest::Url;
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+/// Record the outgoing SMTP sessions that deliver to a domain.
+///
+/// The commands sent by kumod and the responses from the destination,
+/// along with the outcome of any STARTTLS handshake, are written to a
+/// file on the kumod host, whose path is printed.  Message content and
+/// authentication credentials are redacted.
+///
+/// A capture stops once its duration has elapsed or the file has
+/// reached its maximum size.  Starting a capture for a domain replaces
+/// any existing capture for that domain.
+///
+/// ## Example
+///
+///    kcli set-wire-capture --domain example.com --duration 10m
+pub struct SetWireCaptureCommand {
+    /// Capture the sessions that deliver messages to this domain
+    #[arg(long)]
+    domain: String,
+
+    /// How long to capture for
+    #[arg(long, default_value = "10m", value_parser=humantime::parse_duration)]
+    duration: Duration,
+
+    /// The maximum size of the capture file, in bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Record the message content sent after the DATA command,
+    /// rather than only its size
+    #[arg(long)]
+    include_data: bool,
+}
+
+impl SetWireCaptureCommand {
+    pub async fn run(&self, endpoint: &Url) -> anyhow::Result<()> {
+        let result: WireCaptureV1Response = crate::request_with_json_response(
+            reqwest::Method::POST,
+            endpoint.join("/api/admin/wire-capture/v1")?,
+            &WireCaptureV1Request {
+                domain: self.domain.clone(),
+                duration: Some(self.duration),
+                max_size: self.max_size,
+                include_data: self.include_data,
+            },
+        )
+        .await?;
+
+        crate::output::print(&result)
+    }
+}
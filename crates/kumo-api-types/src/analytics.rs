@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use std::ops::AddAssign;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+/// The ways in which message outcomes are grouped
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsDimension {
+    /// The provider to which the message was delivered, as identified
+    /// by the provider definitions, or the site name if no provider
+    /// was matched
+    Provider,
+    /// The egress pool via which the message was delivered
+    Pool,
+    /// The tenant of the message
+    Tenant,
+}
+
+impl AnalyticsDimension {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Provider => "provider",
+            Self::Pool => "pool",
+            Self::Tenant => "tenant",
+        }
+    }
+}
+
+impl std::fmt::Display for AnalyticsDimension {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for AnalyticsDimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "provider" => Ok(Self::Provider),
+            "pool" => Ok(Self::Pool),
+            "tenant" => Ok(Self::Tenant),
+            _ => Err(format!(
+                "invalid dimension {s:?}; must be one of provider, pool or tenant"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct AnalyticsV1Request {
+    /// Only return the counts for this dimension
+    #[serde(default)]
+    pub dimension: Option<AnalyticsDimension>,
+    /// Only return the counts for this provider, pool or tenant name
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl AnalyticsV1Request {
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(dimension) = &self.dimension {
+            query.append_pair("dimension", dimension.as_str());
+        }
+        if let Some(name) = &self.name {
+            query.append_pair("name", name);
+        }
+    }
+}
+
+/// The number of messages with each outcome over a window of time
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub struct AnalyticsV1Counts {
+    /// Messages that were received. Since the provider and egress
+    /// pool are not known until delivery, these are only counted
+    /// for the tenant dimension.
+    pub accepted: u64,
+    /// Messages that were delivered
+    pub delivered: u64,
+    /// Delivery attempts that resulted in a transient failure
+    pub deferred: u64,
+    /// Messages that permanently failed
+    pub bounced: u64,
+    /// Feedback reports that were received
+    pub complaint: u64,
+}
+
+impl AddAssign for AnalyticsV1Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.accepted += other.accepted;
+        self.delivered += other.delivered;
+        self.deferred += other.deferred;
+        self.bounced += other.bounced;
+        self.complaint += other.complaint;
+    }
+}
+
+/// The rolling counts for a provider, pool or tenant
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct AnalyticsV1Entry {
+    pub dimension: AnalyticsDimension,
+    /// The name of the provider, pool or tenant
+    #[schema(example = "gmail")]
+    pub name: String,
+    /// The counts for the last 5 minutes
+    pub last_5m: AnalyticsV1Counts,
+    /// The counts for the last hour
+    pub last_1h: AnalyticsV1Counts,
+    /// The counts for the last 24 hours
+    pub last_24h: AnalyticsV1Counts,
+}
@@ -0,0 +1,198 @@
+use crate::{SuspendReadyQueueV1ListEntry, SuspendV1ListEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::{ToResponse, ToSchema};
+use uuid::Uuid;
+
+/// A summary of the message volume handled by a node
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct MetricsSummaryV1 {
+    /// The number of messages across all scheduled queues
+    pub scheduled_count: usize,
+    /// The number of messages across all ready queues
+    pub ready_count: usize,
+    /// The total number of messages received
+    pub received: usize,
+    /// The total number of messages delivered
+    pub delivered: usize,
+    /// The total number of delivery attempts that transiently failed
+    pub transfail: usize,
+    /// The total number of delivery attempts that permanently failed
+    pub fail: usize,
+}
+
+impl MetricsSummaryV1 {
+    pub fn accumulate(&mut self, other: &Self) {
+        self.scheduled_count += other.scheduled_count;
+        self.ready_count += other.ready_count;
+        self.received += other.received;
+        self.delivered += other.delivered;
+        self.transfail += other.transfail;
+        self.fail += other.fail;
+    }
+}
+
+/// The status of an individual node
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, ToResponse)]
+pub struct NodeStatusV1Response {
+    /// The node id of the node
+    pub nodeid: Uuid,
+    /// The hostname of the node
+    pub hostname: String,
+    /// The number of messages in each scheduled queue
+    pub scheduled: BTreeMap<String, usize>,
+    /// The number of messages in each ready queue
+    pub ready: BTreeMap<String, usize>,
+    /// The active scheduled queue suspensions
+    pub suspensions: Vec<SuspendV1ListEntry>,
+    /// The active ready queue suspensions
+    pub ready_q_suspensions: Vec<SuspendReadyQueueV1ListEntry>,
+    /// Overall totals for the node
+    pub totals: MetricsSummaryV1,
+}
+
+/// The outcome of obtaining the status of a node
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ClusterNodeStatusV1 {
+    /// The endpoint that was queried, or null for the node
+    /// that produced the cluster status
+    #[schema(example = "http://10.0.0.2:8000")]
+    pub peer: Option<String>,
+    /// The status of the node, if it could be obtained
+    pub status: Option<NodeStatusV1Response>,
+    /// The reason that the status could not be obtained
+    pub error: Option<String>,
+}
+
+/// A scheduled queue suspension, along with the node to which it applies
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ClusterSuspensionV1 {
+    /// The hostname of the node
+    pub node: String,
+    pub suspension: SuspendV1ListEntry,
+}
+
+/// A ready queue suspension, along with the node to which it applies
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ClusterReadyQueueSuspensionV1 {
+    /// The hostname of the node
+    pub node: String,
+    pub suspension: SuspendReadyQueueV1ListEntry,
+}
+
+/// The merged status of the cluster
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct ClusterStatusV1Response {
+    /// The status of each individual node
+    pub nodes: Vec<ClusterNodeStatusV1>,
+    /// The number of messages in each scheduled queue, summed
+    /// across all of the nodes
+    pub scheduled: BTreeMap<String, usize>,
+    /// The number of messages in each ready queue, summed
+    /// across all of the nodes
+    pub ready: BTreeMap<String, usize>,
+    /// The active scheduled queue suspensions across all of the nodes
+    pub suspensions: Vec<ClusterSuspensionV1>,
+    /// The active ready queue suspensions across all of the nodes
+    pub ready_q_suspensions: Vec<ClusterReadyQueueSuspensionV1>,
+    /// Overall totals, summed across all of the nodes
+    pub totals: MetricsSummaryV1,
+}
+
+impl ClusterStatusV1Response {
+    /// Merges the status of the nodes.  Nodes that share the same
+    /// node id as an earlier node are assumed to be the same node,
+    /// reached via a different endpoint, and are omitted.
+    pub fn merge(nodes: Vec<ClusterNodeStatusV1>) -> Self {
+        let mut result = Self {
+            nodes: vec![],
+            scheduled: BTreeMap::new(),
+            ready: BTreeMap::new(),
+            suspensions: vec![],
+            ready_q_suspensions: vec![],
+            totals: MetricsSummaryV1::default(),
+        };
+        let mut seen = vec![];
+
+        for node in nodes {
+            if let Some(status) = &node.status {
+                if seen.contains(&status.nodeid) {
+                    continue;
+                }
+                seen.push(status.nodeid);
+
+                for (name, count) in &status.scheduled {
+                    *result.scheduled.entry(name.clone()).or_default() += count;
+                }
+                for (name, count) in &status.ready {
+                    *result.ready.entry(name.clone()).or_default() += count;
+                }
+                for suspension in &status.suspensions {
+                    result.suspensions.push(ClusterSuspensionV1 {
+                        node: status.hostname.clone(),
+                        suspension: suspension.clone(),
+                    });
+                }
+                for suspension in &status.ready_q_suspensions {
+                    result
+                        .ready_q_suspensions
+                        .push(ClusterReadyQueueSuspensionV1 {
+                            node: status.hostname.clone(),
+                            suspension: suspension.clone(),
+                        });
+                }
+                result.totals.accumulate(&status.totals);
+            }
+            result.nodes.push(node);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_node(nodeid: Uuid, hostname: &str, scheduled: usize) -> ClusterNodeStatusV1 {
+        ClusterNodeStatusV1 {
+            peer: Some(format!("http://{hostname}:8000")),
+            status: Some(NodeStatusV1Response {
+                nodeid,
+                hostname: hostname.to_string(),
+                scheduled: [("example.com".to_string(), scheduled)]
+                    .into_iter()
+                    .collect(),
+                ready: BTreeMap::new(),
+                suspensions: vec![],
+                ready_q_suspensions: vec![],
+                totals: MetricsSummaryV1 {
+                    scheduled_count: scheduled,
+                    ..Default::default()
+                },
+            }),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let a = Uuid::from_u128(1);
+        let b = Uuid::from_u128(2);
+        let merged = ClusterStatusV1Response::merge(vec![
+            make_node(a, "a", 10),
+            make_node(b, "b", 5),
+            // The same node as "a", reached via a different endpoint
+            make_node(a, "a-alias", 10),
+            ClusterNodeStatusV1 {
+                peer: Some("http://c:8000".to_string()),
+                status: None,
+                error: Some("connection refused".to_string()),
+            },
+        ]);
+
+        assert_eq!(merged.nodes.len(), 3);
+        assert_eq!(merged.scheduled["example.com"], 15);
+        assert_eq!(merged.totals.scheduled_count, 15);
+    }
+}
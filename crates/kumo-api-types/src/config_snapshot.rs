@@ -0,0 +1,299 @@
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use utoipa::ToSchema;
+
+/// The values of fields with these names are replaced by a digest,
+/// so that a change to them can be detected without revealing them
+const SECRET_FIELDS: &[&str] = &["key_data", "vault_token", "password"];
+
+/// The configuration that is in effect on a node, or that would be
+/// produced by a policy, in a form that can be compared
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, ToSchema)]
+pub struct ConfigSnapshotV1 {
+    /// The parameters of each listener, keyed by the kind of
+    /// listener and its address
+    #[schema(example=json!({"esmtp:0.0.0.0:25": {"listen": "0.0.0.0:25"}}))]
+    #[serde(default)]
+    pub listeners: BTreeMap<String, Value>,
+    /// The parameters of each logger, keyed by the function that
+    /// configured it and its name or log directory
+    #[schema(example=json!({"configure_local_logs:/var/log/kumomta": {"log_dir": "/var/log/kumomta"}}))]
+    #[serde(default)]
+    pub logging: BTreeMap<String, Value>,
+    /// The configuration of each scheduled queue, keyed by queue name
+    #[serde(default)]
+    pub queues: BTreeMap<String, Value>,
+    /// The configuration of each egress path
+    #[serde(default)]
+    pub egress_paths: Vec<ConfigSnapshotV1EgressPath>,
+}
+
+/// The configuration of an egress path, along with the parameters
+/// that were passed to `get_egress_path_config` to produce it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ConfigSnapshotV1EgressPath {
+    pub routing_domain: String,
+    pub egress_source: String,
+    pub site_name: String,
+    pub config: Value,
+}
+
+impl ConfigSnapshotV1EgressPath {
+    /// Returns a key that identifies the egress path within a snapshot
+    pub fn key(&self) -> String {
+        format!(
+            "{}->{} for {}",
+            self.egress_source, self.site_name, self.routing_domain
+        )
+    }
+}
+
+/// How an entry differs between two snapshots
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// An entry that differs between two snapshots
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    /// The section of the snapshot, such as `listeners`
+    pub section: String,
+    /// The key of the entry within the section
+    pub key: String,
+    pub kind: ConfigChangeKind,
+    /// For changed entries, the fields whose values differ
+    #[serde(default)]
+    pub fields: Vec<ConfigFieldChange>,
+}
+
+/// A field whose value differs between two snapshots
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigFieldChange {
+    /// The dotted path to the field
+    pub path: String,
+    /// The value in the original snapshot, if any
+    pub before: Option<Value>,
+    /// The value in the new snapshot, if any
+    pub after: Option<Value>,
+}
+
+impl ConfigSnapshotV1 {
+    /// Returns the changes that would be made by replacing this
+    /// snapshot with `target`
+    pub fn diff(&self, target: &Self) -> Vec<ConfigChange> {
+        let mut changes = vec![];
+        diff_section(
+            "listeners",
+            &self.listeners,
+            &target.listeners,
+            &mut changes,
+        );
+        diff_section("logging", &self.logging, &target.logging, &mut changes);
+        diff_section("queues", &self.queues, &target.queues, &mut changes);
+        diff_section(
+            "egress_paths",
+            &egress_path_map(&self.egress_paths),
+            &egress_path_map(&target.egress_paths),
+            &mut changes,
+        );
+        changes
+    }
+}
+
+fn egress_path_map(paths: &[ConfigSnapshotV1EgressPath]) -> BTreeMap<String, Value> {
+    paths
+        .iter()
+        .map(|path| (path.key(), path.config.clone()))
+        .collect()
+}
+
+fn diff_section(
+    section: &str,
+    before: &BTreeMap<String, Value>,
+    after: &BTreeMap<String, Value>,
+    changes: &mut Vec<ConfigChange>,
+) {
+    let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    for key in keys {
+        let (kind, fields) = match (before.get(key), after.get(key)) {
+            (Some(a), Some(b)) => {
+                let mut fields = vec![];
+                diff_value("", a, b, &mut fields);
+                if fields.is_empty() {
+                    continue;
+                }
+                (ConfigChangeKind::Changed, fields)
+            }
+            (Some(_), None) => (ConfigChangeKind::Removed, vec![]),
+            (None, Some(_)) => (ConfigChangeKind::Added, vec![]),
+            (None, None) => unreachable!(),
+        };
+        changes.push(ConfigChange {
+            section: section.to_string(),
+            key: key.to_string(),
+            kind,
+            fields,
+        });
+    }
+}
+
+fn diff_value(path: &str, before: &Value, after: &Value, fields: &mut Vec<ConfigFieldChange>) {
+    match (before, after) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{path}.{key}")
+                };
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff_value(&path, a, b, fields),
+                    (a, b) => fields.push(ConfigFieldChange {
+                        path,
+                        before: a.cloned(),
+                        after: b.cloned(),
+                    }),
+                }
+            }
+        }
+        (a, b) if a != b => fields.push(ConfigFieldChange {
+            path: path.to_string(),
+            before: Some(a.clone()),
+            after: Some(b.clone()),
+        }),
+        _ => {}
+    }
+}
+
+/// Replaces the values of secret fields, such as inline key data,
+/// with a digest of their value
+pub fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(s) if SECRET_FIELDS.contains(&key.as_str()) => {
+                        let digest = HEXLOWER.encode(&Sha256::digest(s.as_bytes()));
+                        *value = Value::String(format!("sha256:{}", &digest[..16]));
+                    }
+                    value => redact_secrets(value),
+                }
+            }
+        }
+        Value::Array(array) => {
+            for value in array.iter_mut() {
+                redact_secrets(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn snapshot_diff() {
+        let running = ConfigSnapshotV1 {
+            listeners: [
+                (
+                    "esmtp:0.0.0.0:25".to_string(),
+                    json!({"listen": "0.0.0.0:25"}),
+                ),
+                (
+                    "http:0.0.0.0:8000".to_string(),
+                    json!({"listen": "0.0.0.0:8000", "trusted_hosts": ["127.0.0.1"]}),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            queues: [(
+                "example.com".to_string(),
+                json!({"max_age": "7d", "retry_interval": "20m"}),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+
+        let mut local = running.clone();
+        local.listeners.remove("esmtp:0.0.0.0:25");
+        local.listeners.insert(
+            "esmtp:0.0.0.0:2525".to_string(),
+            json!({"listen": "0.0.0.0:2525"}),
+        );
+        local.queues.insert(
+            "example.com".to_string(),
+            json!({"max_age": "3d", "retry_interval": "20m", "strategy": "SkipList"}),
+        );
+
+        k9::snapshot!(
+            running.diff(&local),
+            r#"
+[
+    ConfigChange {
+        section: "listeners",
+        key: "esmtp:0.0.0.0:25",
+        kind: Removed,
+        fields: [],
+    },
+    ConfigChange {
+        section: "listeners",
+        key: "esmtp:0.0.0.0:2525",
+        kind: Added,
+        fields: [],
+    },
+    ConfigChange {
+        section: "queues",
+        key: "example.com",
+        kind: Changed,
+        fields: [
+            ConfigFieldChange {
+                path: "max_age",
+                before: Some(
+                    String("7d"),
+                ),
+                after: Some(
+                    String("3d"),
+                ),
+            },
+            ConfigFieldChange {
+                path: "strategy",
+                before: None,
+                after: Some(
+                    String("SkipList"),
+                ),
+            },
+        ],
+    },
+]
+"#
+        );
+
+        assert!(running.diff(&running).is_empty());
+    }
+
+    #[test]
+    fn redaction() {
+        let mut value = json!({
+            "tls_private_key": {"key_data": "secret"},
+            "hosts": [{"password": "hunter2", "name": "foo"}],
+        });
+        redact_secrets(&mut value);
+        assert_eq!(
+            value["tls_private_key"]["key_data"],
+            "sha256:2bb80d537b1da3e3"
+        );
+        assert_eq!(value["hosts"][0]["password"], "sha256:f52fbd32b2b3b86f");
+        assert_eq!(value["hosts"][0]["name"], "foo");
+    }
+}
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{ToResponse, ToSchema};
+
+/// Puts the node into maintenance mode, draining its queues
+/// ahead of a deadline.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DrainV1Request {
+    /// How long to allow for the queues to drain
+    #[serde(with = "duration_serde")]
+    #[schema(value_type=String, example="30m")]
+    pub deadline: Duration,
+
+    /// The reason for the drain
+    #[schema(example = "Kernel upgrade")]
+    pub reason: String,
+
+    /// The SMTP status code used to reject new mail while draining.
+    /// Must be a 4xx code. Defaults to 421.
+    #[serde(default = "DrainV1Request::default_code")]
+    #[schema(example = 421)]
+    pub code: u16,
+
+    /// The message used to reject new mail while draining,
+    /// which should begin with an enhanced status code
+    #[serde(default = "DrainV1Request::default_message")]
+    #[schema(example = "4.3.2 draining for scheduled maintenance. Try later")]
+    pub message: String,
+
+    /// If true (the default), messages whose next delivery attempt
+    /// is scheduled after the deadline are made immediately eligible
+    /// for delivery, unless their scheduled queue is suspended.
+    #[serde(default = "DrainV1Request::default_accelerate")]
+    pub accelerate: bool,
+
+    /// If set, when the deadline is reached, the messages that
+    /// remain in the scheduled queues are rebound to this
+    /// routing_domain, which is typically a peer node, such as
+    /// `[10.0.0.2]`, so that it can deliver them.
+    #[serde(default)]
+    #[schema(example = "[10.0.0.2]")]
+    pub handoff_to: Option<String>,
+}
+
+impl DrainV1Request {
+    pub fn default_code() -> u16 {
+        421
+    }
+
+    pub fn default_message() -> String {
+        "4.3.2 draining for scheduled maintenance. Try later".to_string()
+    }
+
+    fn default_accelerate() -> bool {
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct DrainV1Response {
+    /// When the drain started
+    pub started: DateTime<Utc>,
+    /// When the drain deadline will be reached
+    pub deadline: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema, ToResponse)]
+pub struct DrainV1Status {
+    /// true if the node is in maintenance mode
+    pub draining: bool,
+    /// The reason for the drain
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// When the drain started
+    #[serde(default)]
+    pub started: Option<DateTime<Utc>>,
+    /// When the drain deadline will be, or was, reached
+    #[serde(default)]
+    pub deadline: Option<DateTime<Utc>>,
+    /// The number of messages that were queued when the drain started
+    pub initial_messages: usize,
+    /// The number of messages currently in the scheduled queues
+    pub scheduled_messages: usize,
+    /// The number of messages currently in the ready queues
+    pub ready_messages: usize,
+    /// The number of messages that were made immediately eligible
+    /// for delivery by the drain
+    pub accelerated_messages: usize,
+    /// The number of transactions that were rejected while draining
+    pub rejected: usize,
+    /// The routing_domain to which the remaining messages are
+    /// handed off at the deadline
+    #[serde(default)]
+    pub handoff_to: Option<String>,
+    /// true if the remaining messages have been handed off
+    pub handed_off: bool,
+}
@@ -134,6 +134,34 @@ pub enum ConfigRefreshStrategy {
     Epoch,
 }
 
+/// Limits the rate at which message data is transmitted
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthLimit {
+    /// The sustained rate, in bytes, such as "10,000,000/s"
+    pub rate: ThrottleSpec,
+
+    /// The number of bytes that may be sent in a burst.
+    /// Defaults to the limit of `rate`.
+    #[serde(default)]
+    pub max_burst: Option<u64>,
+}
+
+impl BandwidthLimit {
+    pub fn as_throttle_spec(&self) -> ThrottleSpec {
+        ThrottleSpec {
+            max_burst: self.max_burst,
+            ..self.rate
+        }
+    }
+
+    /// The largest quantity that can be requested from the throttle
+    /// at once
+    pub fn burst(&self) -> u64 {
+        self.max_burst.unwrap_or(self.rate.limit).max(1)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "lua", derive(FromLua))]
 #[serde(deny_unknown_fields)]
@@ -254,6 +282,34 @@ pub struct EgressPathConfig {
     /// If true, rather than ESMTP, use the LMTP protocol
     #[serde(default)]
     pub use_lmtp: bool,
+
+    /// Limits the total number of connections to the site, across
+    /// all sources and all of the nodes that share the redis
+    /// throttle configuration
+    #[serde(default)]
+    pub cluster_connection_limit: Option<usize>,
+
+    /// Limits the total rate of messages sent to the site, across
+    /// all sources and all of the nodes that share the redis
+    /// throttle configuration
+    #[serde(default)]
+    pub cluster_max_message_rate: Option<ThrottleSpec>,
+
+    /// Whether TLS sessions are cached per site, so that
+    /// subsequent connections can resume them
+    #[serde(default = "EgressPathConfig::default_tls_session_resumption")]
+    pub tls_session_resumption: bool,
+
+    /// If the destination responds to a message with one of these
+    /// codes, the connection is closed and delivery of the message is
+    /// immediately tried against the next candidate host, rather than
+    /// scheduling it for a later retry
+    #[serde(default)]
+    pub try_next_host_on_response_codes: Vec<u16>,
+
+    /// Limits the rate at which message data is sent via this path
+    #[serde(default)]
+    pub max_bandwidth: Option<BandwidthLimit>,
 }
 
 #[cfg(feature = "lua")]
@@ -294,6 +350,11 @@ impl Default for EgressPathConfig {
             remember_broken_tls: None,
             opportunistic_tls_reconnect_on_failed_handshake: false,
             use_lmtp: false,
+            cluster_connection_limit: None,
+            cluster_max_message_rate: None,
+            tls_session_resumption: Self::default_tls_session_resumption(),
+            try_next_host_on_response_codes: vec![],
+            max_bandwidth: None,
         }
     }
 }
@@ -307,6 +368,10 @@ impl EgressPathConfig {
         true
     }
 
+    fn default_tls_session_resumption() -> bool {
+        true
+    }
+
     fn default_enable_dane() -> bool {
         false
     }
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use utoipa::{ToResponse, ToSchema};
+
+/// Selects the egress sources to validate
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EgressPreflightV1Request {
+    /// The names of egress sources to validate
+    #[serde(default)]
+    #[schema(example=json!(["ip-1"]))]
+    pub sources: Vec<String>,
+    /// The names of egress pools whose sources should be validated
+    #[serde(default)]
+    #[schema(example=json!(["pool-1"]))]
+    pub pools: Vec<String>,
+}
+
+impl EgressPreflightV1Request {
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty() && self.pools.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EgressPreflightCheck {
+    /// The source address can be bound on this host
+    Bind,
+    /// The source address has a PTR record
+    Ptr,
+    /// One of the PTR records matches the EHLO name
+    PtrMatchesEhlo,
+    /// One of the PTR records resolves back to the source address
+    ForwardConfirmed,
+}
+
+impl EgressPreflightCheck {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bind => "bind",
+            Self::Ptr => "ptr",
+            Self::PtrMatchesEhlo => "ptr_matches_ehlo",
+            Self::ForwardConfirmed => "forward_confirmed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum EgressPreflightStatus {
+    Pass,
+    Fail,
+    /// The check does not apply to this source, or could not
+    /// be performed because an earlier check failed
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct EgressPreflightV1CheckResult {
+    pub check: EgressPreflightCheck,
+    pub status: EgressPreflightStatus,
+    /// Explains the outcome of the check
+    #[schema(example = "192.0.2.1 resolves to mta1.example.com")]
+    pub detail: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct EgressPreflightV1Source {
+    /// The name of the egress source
+    #[schema(example = "ip-1")]
+    pub name: String,
+    /// The address from which connections originate; either the
+    /// source_address, or the address that the proxy is asked to use
+    #[schema(value_type=Option<String>, example="192.0.2.1")]
+    pub address: Option<IpAddr>,
+    /// The EHLO name used by the source
+    #[schema(example = "mta1.example.com")]
+    pub ehlo_domain: String,
+    /// The outcome of each of the checks
+    pub checks: Vec<EgressPreflightV1CheckResult>,
+}
+
+impl EgressPreflightV1Source {
+    /// Returns true if none of the checks failed
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != EgressPreflightStatus::Fail)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema, ToResponse)]
+pub struct EgressPreflightV1Report {
+    /// When the validation was performed
+    pub checked: DateTime<Utc>,
+    /// The results for each of the validated sources
+    pub sources: Vec<EgressPreflightV1Source>,
+    /// Sources or pools that could not be resolved from the policy
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+impl EgressPreflightV1Report {
+    /// Returns true if all of the sources passed
+    pub fn passed(&self) -> bool {
+        self.errors.is_empty() && self.sources.iter().all(|source| source.passed())
+    }
+}
@@ -2,16 +2,28 @@ use chrono::{DateTime, Utc};
 use cidr_map::CidrSet;
 use serde::{Deserialize, Serialize};
 use spool::SpoolId;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 use url::Url;
 use utoipa::{IntoParams, ToResponse, ToSchema};
 use uuid::Uuid;
 
+pub mod analytics;
+pub mod cluster;
+pub mod config_snapshot;
+pub mod drain;
 pub mod egress_path;
+pub mod egress_preflight;
+pub mod mx_pin;
+#[cfg(feature = "lua")]
+pub mod provider;
 pub mod rebind;
+pub mod scheduled_queue;
 pub mod shaping;
+pub mod suppression;
 pub mod tsa;
+pub mod tuning;
+pub mod wire_capture;
 
 /// Describes which messages should be bounced.
 /// The criteria apply to the scheduled queue associated
@@ -36,6 +48,13 @@ pub struct BounceV1Request {
     #[serde(default)]
     pub routing_domain: Option<String>,
 
+    /// Only match messages whose metadata has these values.
+    /// Each key must have been declared via `kumo.configure_meta_index`.
+    /// If omitted, messages match regardless of their metadata.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(example=json!({"x-customer-id": "12345"}))]
+    pub meta: BTreeMap<String, String>,
+
     /// Reason to log in the delivery log. Each matching message will be bounced
     /// with an AdminBounce record unless you suppress logging.
     /// The reason will also be shown in the list of currently active admin
@@ -109,9 +128,62 @@ pub struct BounceV1Response {
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct SetDiagnosticFilterRequest {
-    /// The diagnostic filter spec to use
+    /// The diagnostic filter spec to use.
+    /// When `domain`, `tenant` or `egress_pool` is set, this must be
+    /// either a level such as `debug`, or a single `target=level` pair.
     #[schema(example = "kumod=trace")]
     pub filter: String,
+
+    /// If set, the filter applies only to work relating to
+    /// delivering messages to this destination domain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "example.com")]
+    pub domain: Option<String>,
+
+    /// If set, the filter applies only to work relating to
+    /// delivering messages for this tenant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// If set, the filter applies only to work relating to
+    /// delivering messages via this egress pool.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_pool: Option<String>,
+
+    /// When `domain`, `tenant` or `egress_pool` is set, specifies how
+    /// long the filter remains in effect. Defaults to "10m".
+    /// A duration of zero removes a previously set filter.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(example = "30m")]
+    pub duration: Option<Duration>,
+}
+
+impl SetDiagnosticFilterRequest {
+    /// Returns the span field and value to which the filter
+    /// is scoped, if any
+    pub fn scope(&self) -> Result<Option<(&'static str, &str)>, String> {
+        let mut scopes = [
+            ("domain", &self.domain),
+            ("tenant", &self.tenant),
+            ("egress_pool", &self.egress_pool),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| value.as_deref().map(|value| (field, value)));
+
+        let scope = scopes.next();
+        if scopes.next().is_some() {
+            return Err("only one of domain, tenant or egress_pool may be specified".to_string());
+        }
+        Ok(scope)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration.unwrap_or(Duration::from_secs(600))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
@@ -135,6 +207,9 @@ pub struct BounceV1ListEntry {
     /// The routing_domain field of the original request, if any.
     #[serde(default)]
     pub routing_domain: Option<String>,
+    /// The meta field of the original request, if any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
 
     /// The reason field of the original request
     pub reason: String,
@@ -213,7 +288,7 @@ pub struct SuspendV1CancelRequest {
     pub id: Uuid,
 }
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct SuspendV1ListEntry {
     /// The id of the suspension. This can be used later to cancel
     /// the suspension.
@@ -268,7 +343,7 @@ impl SuspendReadyQueueV1Request {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct SuspendReadyQueueV1ListEntry {
     /// The id for the suspension. Can be used to cancel the suspension.
     pub id: Uuid,
@@ -307,6 +382,12 @@ impl InspectMessageV1Request {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, IntoParams)]
+pub struct UnsubscribeV1Request {
+    /// The encrypted token from the List-Unsubscribe URL
+    pub token: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
 pub struct InspectMessageV1Response {
     /// The spool identifier of the message
@@ -337,6 +418,7 @@ pub struct MessageInformation {
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct TraceSmtpV1Request {
     #[serde(default)]
+    #[schema(value_type=Option<Vec<String>>, example=json!(["10.0.0.0/24"]))]
     pub source_addr: Option<CidrSet>,
 }
 
@@ -429,6 +511,7 @@ pub struct TraceSmtpClientV1Request {
 
     /// The source address to match. If omitted, any will match.
     #[serde(default)]
+    #[schema(value_type=Option<Vec<String>>, example=json!(["10.0.0.0/24"]))]
     pub source_addr: Option<CidrSet>,
 
     /// The mx hostname to match. If omitted, any will match.
@@ -441,6 +524,7 @@ pub struct TraceSmtpClientV1Request {
 
     /// The mx ip address to match. If omitted, any will match.
     #[serde(default)]
+    #[schema(value_type=Option<Vec<String>>, example=json!(["10.0.0.0/24"]))]
     pub mx_addr: Option<CidrSet>,
 }
 
@@ -461,3 +545,388 @@ pub struct QueueState {
 pub struct ReadyQueueStateResponse {
     pub states_by_ready_queue: HashMap<String, HashMap<String, QueueState>>,
 }
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct SpoolInStatusV1Response {
+    /// true if the startup enumeration of the spool has completed
+    pub complete: bool,
+    /// When the enumeration started. Will be null if the spool
+    /// has not yet been started.
+    pub started: Option<DateTime<Utc>>,
+    /// When the enumeration completed.
+    pub finished: Option<DateTime<Utc>>,
+    /// The number of messages that have been spooled in so far
+    pub spooled_in: usize,
+    /// The number of messages that failed to spool in
+    pub failed: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct WebhookBacklogV1ListEntry {
+    /// The name of the webhook logger
+    pub name: String,
+    /// The number of log records that are buffered awaiting delivery
+    pub num_records: usize,
+    /// The total size of the buffered log records
+    pub size_bytes: u64,
+    /// When the oldest of the buffered records was buffered
+    pub oldest: Option<DateTime<Utc>>,
+    /// When delivery of the buffered records will next be attempted
+    pub next_attempt: Option<DateTime<Utc>>,
+    /// The error from the most recent failed delivery attempt
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct WebhookBacklogFlushV1Request {
+    /// The name of the webhook logger whose backlog should be
+    /// flushed. If omitted, all webhook loggers are flushed.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// If true, the buffered records are discarded rather
+    /// than being retried immediately
+    #[serde(default)]
+    pub discard: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct WebhookBacklogFlushV1Response {
+    /// The number of buffered records that were discarded
+    pub num_discarded: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct BounceClassifyV1Request {
+    /// The response text to classify, such as
+    /// `550 5.1.1 mailbox does not exist`
+    #[schema(example = "550 5.1.1 mailbox does not exist")]
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct BounceClassifyV1Response {
+    /// The classification of the response
+    #[schema(example = "InvalidRecipient")]
+    pub classification: String,
+    /// The rule that matched the response. This is None if
+    /// no rule matched, in which case the classification
+    /// is `Uncategorized`.
+    #[serde(default)]
+    pub rule: Option<String>,
+}
+
+/// The operations that an API token is permitted to perform
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiTokenScope {
+    /// Read metrics and other read-only status information
+    MetricsRead,
+    /// Suspend, rebind and inspect queues and the messages in them
+    QueueAdmin,
+    /// Inject messages via the HTTP injection API
+    Inject,
+    /// Create, list and cancel administrative bounces
+    BounceAdmin,
+    /// Unrestricted access, including management of API tokens
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ApiTokenV1ListEntry {
+    /// The name of the token
+    pub name: String,
+    /// The scopes granted to the token
+    pub scopes: Vec<ApiTokenScope>,
+    /// The rate limit that applies to requests made using the token
+    #[schema(example = "100/s")]
+    pub rate_limit: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ApiTokenV1CreateRequest {
+    /// The name of the token. If a token with the same name already
+    /// exists, it is replaced.
+    #[schema(example = "grafana")]
+    pub name: String,
+    /// The secret token value. If omitted, a random token will
+    /// be generated and returned in the response.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The scopes to grant to the token
+    pub scopes: Vec<ApiTokenScope>,
+    /// Limits the rate at which requests can be made using the token
+    #[serde(default)]
+    #[schema(example = "100/s")]
+    pub rate_limit: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct ApiTokenV1CreateResponse {
+    /// The name of the token
+    pub name: String,
+    /// The secret token value, to be passed as `Authorization: Bearer TOKEN`
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ApiTokenV1DeleteRequest {
+    /// The name of the token to revoke
+    pub name: String,
+}
+
+/// Records an administrative action that was performed via the HTTP API
+#[derive(Clone, Serialize, Deserialize, Debug, ToSchema)]
+pub struct AuditLogV1Entry {
+    /// When the action was performed
+    pub timestamp: DateTime<Utc>,
+    /// The identity that performed the action; the authenticated
+    /// username, token or certificate identity, or the peer address
+    /// of a trusted host
+    pub actor: String,
+    /// How the actor was authenticated
+    #[schema(example = "api_token")]
+    pub auth_method: String,
+    /// The address of the client that made the request
+    pub peer_address: Option<String>,
+    /// The HTTP method of the request
+    #[schema(example = "POST")]
+    pub method: String,
+    /// The path of the request
+    #[schema(example = "/api/admin/suspend/v1")]
+    pub path: String,
+    /// The query string of the request
+    pub query: Option<String>,
+    /// The parameters passed in the body of the request, with any
+    /// secrets redacted
+    #[schema(value_type=Object)]
+    pub params: serde_json::Value,
+    /// The HTTP status code of the response
+    pub status: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct AuditLogV1Request {
+    /// Only return entries at or after this time
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    /// Only return entries before this time
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    /// Only return entries for this actor
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// Only return entries whose path starts with this prefix
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The maximum number of entries to return. The most recent
+    /// matching entries are returned. The default is 100.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl AuditLogV1Request {
+    pub fn matches(&self, entry: &AuditLogV1Entry) -> bool {
+        if let Some(since) = &self.since {
+            if entry.timestamp < *since {
+                return false;
+            }
+        }
+        if let Some(until) = &self.until {
+            if entry.timestamp >= *until {
+                return false;
+            }
+        }
+        if let Some(actor) = &self.actor {
+            if entry.actor != *actor {
+                return false;
+            }
+        }
+        if let Some(path) = &self.path {
+            if !entry.path.starts_with(path.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(since) = &self.since {
+            query.append_pair("since", &since.to_rfc3339());
+        }
+        if let Some(until) = &self.until {
+            query.append_pair("until", &until.to_rfc3339());
+        }
+        if let Some(actor) = &self.actor {
+            query.append_pair("actor", actor);
+        }
+        if let Some(path) = &self.path {
+            query.append_pair("path", path);
+        }
+        if let Some(limit) = self.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct MessageSearchV1Request {
+    /// Only return the message with this spool id
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Only return messages with this envelope sender
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Only return messages with this envelope recipient
+    #[serde(default)]
+    pub recipient: Option<String>,
+    /// Only return messages with an indexed header or meta
+    /// value matching this `NAME=VALUE` pair
+    #[serde(default)]
+    pub attribute: Option<String>,
+    /// Only return messages received at or after this time
+    #[serde(default)]
+    pub since: Option<DateTime<Utc>>,
+    /// Only return messages received before this time
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
+    /// The maximum number of messages to return. The most recently
+    /// received matching messages are returned. The default is 100.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl MessageSearchV1Request {
+    /// Splits the attribute filter into its name and value
+    pub fn attribute_name_value(&self) -> anyhow::Result<Option<(&str, &str)>> {
+        match &self.attribute {
+            Some(attribute) => match attribute.split_once('=') {
+                Some((name, value)) => Ok(Some((name.trim(), value.trim()))),
+                None => anyhow::bail!("attribute must be of the form NAME=VALUE"),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(id) = &self.id {
+            query.append_pair("id", id);
+        }
+        if let Some(sender) = &self.sender {
+            query.append_pair("sender", sender);
+        }
+        if let Some(recipient) = &self.recipient {
+            query.append_pair("recipient", recipient);
+        }
+        if let Some(attribute) = &self.attribute {
+            query.append_pair("attribute", attribute);
+        }
+        if let Some(since) = &self.since {
+            query.append_pair("since", &since.to_rfc3339());
+        }
+        if let Some(until) = &self.until {
+            query.append_pair("until", &until.to_rfc3339());
+        }
+        if let Some(limit) = self.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+    }
+}
+
+/// The most recently known status of an indexed message
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum MessageSearchV1Status {
+    /// The message is in a queue, awaiting delivery
+    Queued,
+    /// The message was delivered
+    Delivered,
+    /// The message permanently failed or was administratively bounced
+    Bounced,
+    /// The message expired from the queue
+    Expired,
+}
+
+/// A message that matched the search criteria
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct MessageSearchV1Entry {
+    /// The spool identifier of the message
+    pub id: String,
+    /// The envelope sender
+    pub sender: String,
+    /// The envelope recipient
+    pub recipient: String,
+    /// When the message was received
+    pub created: DateTime<Utc>,
+    /// The queue to which the message most recently belonged
+    pub queue: String,
+    /// The most recently known status of the message
+    pub status: MessageSearchV1Status,
+    /// The number of delivery attempts made so far
+    pub num_attempts: u16,
+    /// The indexed header and meta values that were captured
+    /// when the message was received
+    #[schema(example=json!({"X-Customer-ID": "1234"}))]
+    pub attributes: BTreeMap<String, String>,
+    /// The reception and delivery events for the message,
+    /// oldest first
+    pub events: Vec<MessageSearchV1Event>,
+}
+
+/// A reception or delivery event for an indexed message
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct MessageSearchV1Event {
+    /// When the event occurred
+    pub timestamp: DateTime<Utc>,
+    /// The kind of event, using the same names as the `type`
+    /// field of the log records
+    #[schema(example = "TransientFailure")]
+    pub kind: String,
+    /// The queue to which the message belonged at the time
+    pub queue: String,
+    /// The site name or provider associated with the event
+    pub site: String,
+    /// The response associated with the event
+    #[schema(example = "451 4.4.2 try again later")]
+    pub response: String,
+    /// The number of delivery attempts made as of this event
+    pub num_attempts: u16,
+}
+
+/// The outcome of the readiness checks
+#[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
+pub struct ReadinessV1Response {
+    /// true if all of the checks passed
+    pub ready: bool,
+    /// The outcome of each individual check, keyed by the
+    /// name of the check
+    pub checks: BTreeMap<String, ReadinessCheckV1>,
+}
+
+/// The outcome of an individual readiness check
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ReadinessCheckV1 {
+    /// true if the check passed
+    pub ok: bool,
+    /// Explains the outcome of the check
+    #[schema(example = "spool data is not writable: No space left on device")]
+    pub detail: String,
+}
+
+/// Sent by the client to begin tailing the log records
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+pub struct TailLogsV1Request {
+    /// Only records that match this filter expression are sent.
+    /// If omitted, all records are sent.
+    #[serde(default)]
+    #[schema(example = r#"type == "TransientFailure" and response.content =~ "(?i)greylist""#)]
+    pub filter: Option<String>,
+
+    /// When set, only approximately this fraction of the matching
+    /// records are sent, where 1.0 sends all of them and 0.01 sends
+    /// roughly one in every hundred
+    #[serde(default)]
+    #[schema(example = 0.1)]
+    pub sample_rate: Option<f64>,
+}
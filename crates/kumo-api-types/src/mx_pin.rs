@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{ToResponse, ToSchema};
+use uuid::Uuid;
+
+/// Pins the delivery to a site to a specific MX host or IP address,
+/// for a limited time.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MxPinV1Request {
+    /// The site name, as shown in the ready queue names
+    #[schema(example = "(alt1|alt2|alt3|alt4)?.gmail-smtp-in.l.google.com")]
+    pub site: String,
+
+    /// The MX host name or IP address to which connections should
+    /// be made. It must be one of the addresses that the site
+    /// resolves to.
+    /// Any existing pin for the same site is replaced.
+    #[schema(example = "alt1.gmail-smtp-in.l.google.com")]
+    pub target: String,
+
+    /// The reason for the pin
+    #[schema(example = "gmail-smtp-in.l.google.com is rejecting connections")]
+    pub reason: String,
+
+    /// How long the pin remains active. Defaults to "1h".
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type=Option<String>, example="1h")]
+    pub duration: Option<Duration>,
+}
+
+impl MxPinV1Request {
+    pub fn duration(&self) -> Duration {
+        self.duration.unwrap_or(Duration::from_secs(3600))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct MxPinV1Response {
+    /// The id of the pin. Can be used to cancel the pin.
+    pub id: Uuid,
+    /// When the pin will expire
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct MxPinV1ListEntry {
+    /// The id of the pin. Can be used to cancel the pin.
+    pub id: Uuid,
+    /// The site that is pinned
+    pub site: String,
+    /// The MX host name or IP address that the site is pinned to
+    pub target: String,
+    /// The reason for the pin
+    pub reason: String,
+    /// How long until this pin expires and is automatically removed
+    #[serde(with = "duration_serde")]
+    pub duration: Duration,
+    /// The time at which the pin will expire
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct MxPinV1CancelRequest {
+    /// The id of the pin to cancel
+    pub id: Uuid,
+}
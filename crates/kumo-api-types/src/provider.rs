@@ -0,0 +1,166 @@
+//! Identifies the mailbox provider that hosts a domain, such as
+//! gmail or outlook, from the names of its MX hosts, using a set
+//! of definitions that are loaded from TOML files.
+use crate::shaping::{
+    provider_rules_match_domain, provider_rules_match_domain_suffix, provider_rules_match_hosts,
+    ProviderMatch,
+};
+use anyhow::Context;
+use config::{any_err, from_lua_value, get_or_create_module};
+use dns_resolver::MailExchanger;
+use mlua::{Lua, Value};
+use ordermap::OrderMap;
+use serde::Deserialize;
+use std::sync::{Arc, LazyLock, RwLock};
+
+static DEFINITIONS: LazyLock<RwLock<Arc<ProviderDefinitions>>> = LazyLock::new(RwLock::default);
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderDefinition {
+    #[serde(rename = "match")]
+    pub matches: Vec<ProviderMatch>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderDefinitions {
+    #[serde(default)]
+    provider: OrderMap<String, ProviderDefinition>,
+}
+
+impl ProviderDefinitions {
+    /// Loads and merges the definitions from the files.
+    /// A definition in a later file replaces any definition of
+    /// the same name from an earlier file.
+    pub fn load_files(paths: &[String]) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+        for path in paths {
+            let data = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+            let defs: Self = toml::from_str(&data).with_context(|| format!("parsing {path}"))?;
+            for (name, definition) in defs.provider {
+                anyhow::ensure!(
+                    !definition.matches.is_empty(),
+                    "{path}: provider {name} has no match rules"
+                );
+                result.provider.insert(name, definition);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ProviderDefinition> {
+        self.provider.get(name)
+    }
+
+    /// Returns the name of the first provider whose rules match the
+    /// domain, given the names of its MX hosts
+    pub fn identify_with_hosts(&self, domain: &str, hosts: &[String]) -> Option<&str> {
+        self.provider.iter().find_map(|(name, definition)| {
+            let matched = match provider_rules_match_domain_suffix(&definition.matches, domain) {
+                Some(matched) => matched,
+                None => !hosts.is_empty() && provider_rules_match_hosts(&definition.matches, hosts),
+            };
+            matched.then_some(name.as_str())
+        })
+    }
+
+    /// Returns the name of the first provider whose rules match the
+    /// domain, resolving its MX hosts if required
+    pub async fn identify(&self, domain: &str) -> Option<String> {
+        for (name, definition) in &self.provider {
+            if provider_rules_match_domain(name, &definition.matches, domain).await {
+                return Some(name.to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Returns the definitions configured by
+/// `kumo.configure_provider_definitions`
+pub fn get_provider_definitions() -> Arc<ProviderDefinitions> {
+    DEFINITIONS.read().unwrap().clone()
+}
+
+/// Returns the name of the provider that hosts the domain whose
+/// MX hosts are given by mx, if any
+pub fn identify_provider_for_mx(mx: &MailExchanger) -> Option<String> {
+    get_provider_definitions()
+        .identify_with_hosts(&mx.domain_name, &mx.hosts)
+        .map(|name| name.to_string())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ProviderDefinitionParams {
+    files: Vec<String>,
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let kumo_mod = get_or_create_module(lua, "kumo")?;
+
+    kumo_mod.set(
+        "configure_provider_definitions",
+        lua.create_function(|lua, params: Value| {
+            let params: ProviderDefinitionParams = from_lua_value(lua, params)?;
+            let definitions = ProviderDefinitions::load_files(&params.files).map_err(any_err)?;
+            *DEFINITIONS.write().unwrap() = Arc::new(definitions);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "identify_provider",
+        lua.create_async_function(|_lua, domain: String| async move {
+            Ok(get_provider_definitions().identify(&domain).await)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identify_with_hosts() {
+        let defs: ProviderDefinitions = toml::from_str(
+            r#"
+[provider."gmail"]
+match = [{MXSuffix=".google.com"}, {DomainSuffix="gmail.com"}]
+
+[provider."outlook"]
+match = [{MXSuffix=".olc.protection.outlook.com"}]
+"#,
+        )
+        .unwrap();
+
+        let hosts = |hosts: &[&str]| hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(defs.identify_with_hosts("gmail.com", &[]), Some("gmail"));
+        assert_eq!(
+            defs.identify_with_hosts(
+                "example.com",
+                &hosts(&["aspmx.l.google.com.", "alt1.aspmx.l.google.com."])
+            ),
+            Some("gmail")
+        );
+        assert_eq!(
+            defs.identify_with_hosts(
+                "hotmail.com",
+                &hosts(&["hotmail-com.olc.protection.outlook.com."])
+            ),
+            Some("outlook")
+        );
+        // A vanity domain that blends providers is not identified
+        assert_eq!(
+            defs.identify_with_hosts(
+                "example.com",
+                &hosts(&["aspmx.l.google.com.", "mx.example.com."])
+            ),
+            None
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use utoipa::{ToResponse, ToSchema};
 
 /// Describes which messages should be rebound.
@@ -24,6 +24,13 @@ pub struct RebindV1Request {
     #[serde(default)]
     pub routing_domain: Option<String>,
 
+    /// Only match messages whose metadata has these values.
+    /// Each key must have been declared via `kumo.configure_meta_index`.
+    /// If omitted, messages match regardless of their metadata.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(example=json!({"x-customer-id": "12345"}))]
+    pub meta: BTreeMap<String, String>,
+
     /// Reason to log in the delivery log. Each matching message will log
     /// with an AdminRebind record unless you suppress logging.
     #[schema(example = "Cleaning up a bad send")]
@@ -0,0 +1,189 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::Url;
+use utoipa::{IntoParams, ToSchema};
+
+/// Selects the scheduled queues, and the messages within them,
+/// that should be reported. Criteria that are omitted match
+/// everything.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, IntoParams)]
+pub struct ScheduledQueueV1Request {
+    /// Only include queues for this domain
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Only include queues for this routing domain
+    #[serde(default)]
+    pub routing_domain: Option<String>,
+    /// Only include queues for this tenant
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Only include queues for this campaign
+    #[serde(default)]
+    pub campaign: Option<String>,
+    /// Only include the message with this spool id
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Only include messages that were received at least
+    /// this long ago, such as `1h`
+    #[serde(default, with = "duration_serde")]
+    #[param(value_type=Option<String>)]
+    pub min_age: Option<Duration>,
+    /// Only include messages that were received at most
+    /// this long ago, such as `1h`
+    #[serde(default, with = "duration_serde")]
+    #[param(value_type=Option<String>)]
+    pub max_age: Option<Duration>,
+    /// Only include messages whose next delivery attempt
+    /// is due before this time
+    #[serde(default)]
+    pub due_before: Option<DateTime<Utc>>,
+    /// Only include messages whose next delivery attempt
+    /// is due at or after this time
+    #[serde(default)]
+    pub due_after: Option<DateTime<Utc>>,
+    /// The maximum number of entries to return. The default is 100.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+impl ScheduledQueueV1Request {
+    /// Returns true if a message received at `created`, whose next
+    /// delivery attempt is due at `due`, satisfies the age and due
+    /// time criteria
+    pub fn matches_message(
+        &self,
+        now: DateTime<Utc>,
+        created: DateTime<Utc>,
+        due: Option<DateTime<Utc>>,
+    ) -> bool {
+        let age = (now - created).to_std().unwrap_or_default();
+        if let Some(min_age) = self.min_age {
+            if age < min_age {
+                return false;
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            if age > max_age {
+                return false;
+            }
+        }
+        // A message with no due time is eligible for immediate delivery
+        let due = due.unwrap_or(now);
+        if let Some(due_before) = &self.due_before {
+            if due >= *due_before {
+                return false;
+            }
+        }
+        if let Some(due_after) = &self.due_after {
+            if due < *due_after {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn apply_to_url(&self, url: &mut Url) {
+        let mut query = url.query_pairs_mut();
+        if let Some(domain) = &self.domain {
+            query.append_pair("domain", domain);
+        }
+        if let Some(routing_domain) = &self.routing_domain {
+            query.append_pair("routing_domain", routing_domain);
+        }
+        if let Some(tenant) = &self.tenant {
+            query.append_pair("tenant", tenant);
+        }
+        if let Some(campaign) = &self.campaign {
+            query.append_pair("campaign", campaign);
+        }
+        if let Some(id) = &self.id {
+            query.append_pair("id", id);
+        }
+        if let Some(min_age) = &self.min_age {
+            query.append_pair("min_age", &format!("{}s", min_age.as_secs()));
+        }
+        if let Some(max_age) = &self.max_age {
+            query.append_pair("max_age", &format!("{}s", max_age.as_secs()));
+        }
+        if let Some(due_before) = &self.due_before {
+            query.append_pair("due_before", &due_before.to_rfc3339());
+        }
+        if let Some(due_after) = &self.due_after {
+            query.append_pair("due_after", &due_after.to_rfc3339());
+        }
+        if let Some(limit) = self.limit {
+            query.append_pair("limit", &limit.to_string());
+        }
+    }
+}
+
+/// Summarizes the matching messages in a scheduled queue
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScheduledQueueV1Summary {
+    /// The name of the scheduled queue
+    #[schema(example = "campaign_name:tenant_name@example.com")]
+    pub name: String,
+    /// The domain portion of the queue name
+    pub domain: String,
+    /// The routing domain portion of the queue name, if any
+    pub routing_domain: Option<String>,
+    /// The tenant portion of the queue name, if any
+    pub tenant: Option<String>,
+    /// The campaign portion of the queue name, if any
+    pub campaign: Option<String>,
+    /// The number of matching messages in the queue
+    pub count: usize,
+    /// When the oldest matching message was received
+    pub oldest: Option<DateTime<Utc>>,
+    /// When the soonest delivery attempt of the matching
+    /// messages is due
+    pub next_due: Option<DateTime<Utc>>,
+}
+
+/// A message in a scheduled queue
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct ScheduledQueueV1Message {
+    /// The spool identifier of the message
+    #[schema(example = "d7ef132b5d7711eea8c8000c29c33806")]
+    pub id: String,
+    /// The scheduled queue that holds the message
+    pub queue: String,
+    /// When the message was received
+    pub created: DateTime<Utc>,
+    /// When the next delivery attempt is due. If null, the
+    /// message is eligible for immediate delivery.
+    pub due: Option<DateTime<Utc>>,
+    /// The number of delivery attempts made so far
+    pub num_attempts: u16,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_criteria() {
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+
+        let request = ScheduledQueueV1Request {
+            min_age: Some(Duration::from_secs(3600)),
+            due_before: Some(now + hour),
+            ..Default::default()
+        };
+        assert!(request.matches_message(now, now - hour * 2, None));
+        assert!(request.matches_message(now, now - hour * 2, Some(now + hour / 2)));
+        assert!(!request.matches_message(now, now - hour / 2, None));
+        assert!(!request.matches_message(now, now - hour * 2, Some(now + hour * 2)));
+
+        let request = ScheduledQueueV1Request {
+            max_age: Some(Duration::from_secs(3600)),
+            due_after: Some(now),
+            ..Default::default()
+        };
+        assert!(request.matches_message(now, now - hour / 2, Some(now + hour)));
+        assert!(!request.matches_message(now, now - hour * 2, Some(now + hour)));
+        assert!(!request.matches_message(now, now - hour / 2, Some(now - hour)));
+    }
+}
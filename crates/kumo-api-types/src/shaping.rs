@@ -9,6 +9,7 @@ use config::serialize_options;
 use dns_resolver::{fully_qualify, MailExchanger};
 #[cfg(feature = "lua")]
 use kumo_log_types::JsonLogRecord;
+use kumo_log_types::RecordType;
 #[cfg(feature = "lua")]
 use mlua::prelude::LuaUserData;
 #[cfg(feature = "lua")]
@@ -201,6 +202,14 @@ pub enum Action {
     Bounce,
     BounceTenant,
     BounceCampaign,
+    /// Reduce the `max_message_rate` of the egress path to the
+    /// given percentage of its configured value
+    ReduceRate {
+        percent: u8,
+    },
+    /// Route messages for the destination domain via the named
+    /// egress pool
+    SwitchPool(String),
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Hash, Default)]
@@ -213,12 +222,206 @@ pub enum Trigger {
     Threshold(ThrottleSpec),
 }
 
+/// A rolling bounce rate threshold, which is tracked by the tsa-daemon
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BounceRate {
+    /// The percentage of messages that must have bounced over the
+    /// window for the condition to be satisfied
+    pub percent: f64,
+
+    /// The period over which the bounce rate is computed
+    #[serde(with = "duration_serde")]
+    pub window: Duration,
+
+    /// The minimum number of messages that must have been attempted
+    /// over the window before the condition can be satisfied
+    #[serde(default = "BounceRate::default_min_volume")]
+    pub min_volume: u64,
+
+    /// if true, TransientFailure records are counted as bounces
+    #[serde(default)]
+    pub include_transient: bool,
+}
+
+impl BounceRate {
+    fn default_min_volume() -> u64 {
+        100
+    }
+
+    /// Returns Some(true) if a record of this kind counts as a bounce,
+    /// Some(false) if it counts as a success, or None if the record
+    /// does not contribute to the bounce rate
+    pub fn outcome(&self, kind: RecordType) -> Option<bool> {
+        match kind {
+            RecordType::Delivery => Some(false),
+            RecordType::Bounce => Some(true),
+            RecordType::TransientFailure if self.include_transient => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Hash for BounceRate {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.percent.to_bits().hash(h);
+        self.window.hash(h);
+        self.min_volume.hash(h);
+        self.include_transient.hash(h);
+    }
+}
+
+/// A condition that must be satisfied in order for a rule to trigger.
+/// Conditions can be combined using `All` and `Any`.
+#[derive(Deserialize, Serialize, Debug, Clone, Hash)]
+pub enum Condition {
+    /// Satisfied when the response matches any of the regexes
+    #[serde(deserialize_with = "regex_string_or_array")]
+    Response(Vec<Regex>),
+    /// Satisfied when the response was assigned one of these
+    /// categories by the response category mapping
+    #[serde(deserialize_with = "category_string_or_array")]
+    Category(Vec<String>),
+    /// Satisfied when the bounce rate over the window reaches
+    /// the threshold
+    BounceRate(BounceRate),
+    /// Satisfied when all of the conditions are satisfied
+    All(Vec<Condition>),
+    /// Satisfied when any of the conditions are satisfied
+    Any(Vec<Condition>),
+}
+
+impl Condition {
+    /// Returns true if a record with this kind, response and
+    /// response category can contribute to satisfying the condition.
+    /// Bounce rates need to observe every delivery attempt, so this
+    /// is true for any record that is counted by a BounceRate condition.
+    pub fn observes(&self, kind: RecordType, response: &str, category: Option<&str>) -> bool {
+        match self {
+            Self::Response(regex) => regex_list_matches(regex, response),
+            Self::Category(categories) => category_list_matches(categories, category),
+            Self::BounceRate(rate) => rate.outcome(kind).is_some(),
+            Self::All(conditions) | Self::Any(conditions) => conditions
+                .iter()
+                .any(|condition| condition.observes(kind, response, category)),
+        }
+    }
+
+    /// Evaluates the condition against a response and its category.
+    /// `bounce_rate` is called to evaluate each BounceRate condition.
+    /// It is called for every BounceRate condition, even those that
+    /// cannot change the overall result, so that it can account for
+    /// the current record in each of them.
+    pub fn evaluate<F>(
+        &self,
+        response: &str,
+        category: Option<&str>,
+        bounce_rate: &mut F,
+    ) -> anyhow::Result<bool>
+    where
+        F: FnMut(&BounceRate) -> anyhow::Result<bool>,
+    {
+        Ok(match self {
+            Self::Response(regex) => regex_list_matches(regex, response),
+            Self::Category(categories) => category_list_matches(categories, category),
+            Self::BounceRate(rate) => bounce_rate(rate)?,
+            Self::All(conditions) => {
+                let mut result = true;
+                for condition in conditions {
+                    result &= condition.evaluate(response, category, bounce_rate)?;
+                }
+                result
+            }
+            Self::Any(conditions) => {
+                let mut result = false;
+                for condition in conditions {
+                    result |= condition.evaluate(response, category, bounce_rate)?;
+                }
+                result
+            }
+        })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Response(regex) => {
+                anyhow::ensure!(!regex.is_empty(), "Response requires at least one regex");
+            }
+            Self::Category(categories) => {
+                anyhow::ensure!(
+                    !categories.is_empty(),
+                    "Category requires at least one category"
+                );
+            }
+            Self::BounceRate(rate) => {
+                anyhow::ensure!(
+                    rate.percent > 0.0 && rate.percent <= 100.0,
+                    "BounceRate percent must be greater than 0 and no more than 100"
+                );
+                anyhow::ensure!(
+                    !rate.window.is_zero(),
+                    "BounceRate window must be greater than 0"
+                );
+            }
+            Self::All(conditions) | Self::Any(conditions) => {
+                anyhow::ensure!(
+                    !conditions.is_empty(),
+                    "All and Any require at least one condition"
+                );
+                for condition in conditions {
+                    condition.validate()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Response(regex) => {
+                let regex: Vec<&str> = regex.iter().map(|r| r.as_str()).collect();
+                write!(fmt, "response =~ {}", regex.join(" | "))
+            }
+            Self::Category(categories) => write!(fmt, "category in {}", categories.join(" | ")),
+            Self::BounceRate(rate) => write!(
+                fmt,
+                "bounce rate >= {}% over {:?}",
+                rate.percent, rate.window
+            ),
+            Self::All(conditions) | Self::Any(conditions) => {
+                let joiner = if matches!(self, Self::All(_)) {
+                    " and "
+                } else {
+                    " or "
+                };
+                let conditions: Vec<String> = conditions.iter().map(|c| c.to_string()).collect();
+                write!(fmt, "({})", conditions.join(joiner))
+            }
+        }
+    }
+}
+
+fn regex_list_matches(regex: &[Regex], response: &str) -> bool {
+    regex.iter().any(|r| r.is_match(response).unwrap_or(false))
+}
+
+fn category_list_matches(categories: &[String], category: Option<&str>) -> bool {
+    category.is_some_and(|category| categories.iter().any(|c| c == category))
+}
+
 #[serde_as]
-#[derive(Deserialize, Serialize, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Rule {
-    #[serde(deserialize_with = "regex_string_or_array")]
+    #[serde(default, deserialize_with = "regex_string_or_array")]
     pub regex: Vec<Regex>,
 
+    /// Additional conditions that must be satisfied for the rule
+    /// to trigger. When both regex and condition are specified,
+    /// both must be satisfied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+
     #[serde(deserialize_with = "one_or_many_action")]
     pub action: Vec<Action>,
 
@@ -236,16 +439,115 @@ pub struct Rule {
     /// against those.
     #[serde(default)]
     pub match_internal: bool,
+
+    /// if true, the actions of this rule are not applied. Instead,
+    /// the tsa-daemon logs the decisions that it would have made.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl Hash for Rule {
+    fn hash<H: Hasher>(&self, h: &mut H) {
+        self.regex.hash(h);
+        self.action.hash(h);
+        self.trigger.hash(h);
+        self.duration.hash(h);
+        self.was_rollup.hash(h);
+        self.match_internal.hash(h);
+        // The condition is only included when present, so that the
+        // hash of a rule that doesn't use it is the same as it was
+        // prior to its introduction
+        if let Some(condition) = &self.condition {
+            condition.hash(h);
+        }
+        // Likewise, dry_run is only included when set. This also
+        // means that the history of a rule in dry-run mode is not
+        // carried over when it is switched to enforcement.
+        if self.dry_run {
+            self.dry_run.hash(h);
+        }
+    }
 }
 
 impl Rule {
-    pub fn matches(&self, is_internal: bool, response: &str) -> bool {
+    /// Returns true if the record is relevant to this rule.
+    /// For a rule with a condition, this doesn't mean that the
+    /// rule has triggered; the tsa-daemon evaluates the condition
+    /// via `Rule::evaluate`.
+    pub fn matches(
+        &self,
+        is_internal: bool,
+        kind: RecordType,
+        response: &str,
+        category: Option<&str>,
+    ) -> bool {
         if is_internal && !self.match_internal {
             return false;
         }
-        self.regex
-            .iter()
-            .any(|r| r.is_match(response).unwrap_or(false))
+        match &self.condition {
+            None => self.regex_matches(response),
+            Some(condition) => {
+                self.regex_matches(response) || condition.observes(kind, response, category)
+            }
+        }
+    }
+
+    fn regex_matches(&self, response: &str) -> bool {
+        regex_list_matches(&self.regex, response)
+    }
+
+    /// Evaluates the regex and condition of the rule against the
+    /// response and its category. See `Condition::evaluate` for
+    /// more information about `bounce_rate`.
+    pub fn evaluate<F>(
+        &self,
+        response: &str,
+        category: Option<&str>,
+        mut bounce_rate: F,
+    ) -> anyhow::Result<bool>
+    where
+        F: FnMut(&BounceRate) -> anyhow::Result<bool>,
+    {
+        let regex_matched = self.regex.is_empty() || self.regex_matches(response);
+        let condition_satisfied = match &self.condition {
+            Some(condition) => condition.evaluate(response, category, &mut bounce_rate)?,
+            None => true,
+        };
+        Ok(regex_matched && condition_satisfied)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.regex.is_empty() || self.condition.is_some(),
+            "automation rule must have a regex, a condition, or both"
+        );
+        if let Some(condition) = &self.condition {
+            condition.validate()?;
+        }
+        for action in &self.action {
+            if let Action::ReduceRate { percent } = action {
+                anyhow::ensure!(
+                    *percent > 0 && *percent < 100,
+                    "ReduceRate percent must be between 1 and 99"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the regex and condition of the rule
+    pub fn describe(&self) -> String {
+        let regex = if self.regex.len() == 1 {
+            self.regex[0].to_string()
+        } else {
+            let regex: Vec<String> = self.regex.iter().map(|r| r.to_string()).collect();
+            format!("({})", regex.join(","))
+        };
+        match &self.condition {
+            None => regex,
+            Some(condition) if self.regex.is_empty() => condition.to_string(),
+            Some(condition) => format!("{regex} and {condition}"),
+        }
     }
 
     pub fn clone_and_set_rollup(&self) -> Self {
@@ -345,29 +647,25 @@ impl ShapingInner {
         };
         let domain = recipient.domain.to_string();
 
-        // Track events/outcomes by site.
-        let source = record.egress_source.as_deref().unwrap_or("unspecified");
-        // record.site is poorly named; it is really an identifier for the
-        // egress path. For matching purposes, we want just the site_name
-        // in the form produced by our MX resolution process.
-        // In an earlier incarnation of this logic, we would resolve the
-        // site_name for ourselves based on other data in the record,
-        // but that could lead to over-resolution of some names and
-        // yield surprising results.
-        // What we do here is extract the egress path decoration from
-        // record.site to arrive at something that looks like the
-        // mx site_name.
-        // NOTE: this is coupled with the logic in
-        // ReadyQueueManager::compute_queue_name
-        let site_name = record
-            .site
-            .trim_start_matches(&format!("{source}->"))
-            .trim_end_matches("@smtp_client")
-            .to_string();
+        let site_name = record_site_name(record);
 
         Ok(self.match_rules_impl(record, &domain, &site_name).await)
     }
 
+    /// Returns the egress pool defined for the domain or its site, if any
+    pub fn get_egress_pool(&self, domain: &str, site_name: &str) -> Option<String> {
+        if let Some(pool) = self
+            .by_domain
+            .get(domain)
+            .and_then(|entry| entry.egress_pool.as_ref())
+        {
+            return Some(pool.to_string());
+        }
+        self.by_site
+            .get(site_name)
+            .and_then(|entry| entry.egress_pool.clone())
+    }
+
     pub async fn match_rules_impl(
         &self,
         record: &JsonLogRecord,
@@ -376,6 +674,7 @@ impl ShapingInner {
     ) -> Vec<Rule> {
         let mut result = vec![];
         let response = record.response.to_single_line();
+        let category = record.response_category.as_deref();
         tracing::trace!("Consider rules for {response}");
 
         let is_internal = record.response.content.starts_with("KumoMTA internal: ");
@@ -383,7 +682,7 @@ impl ShapingInner {
         if let Some(default) = self.by_domain.get("default") {
             for rule in &default.automation {
                 tracing::trace!("Consider \"default\" rule {rule:?} for {response}");
-                if rule.matches(is_internal, &response) {
+                if rule.matches(is_internal, record.kind, &response, category) {
                     // For automation under `default`, we always
                     // assume that mx_rollup should be true.
                     // If you somehow have a domain where that isn't
@@ -402,7 +701,7 @@ impl ShapingInner {
                         "Consider provider \"{}\" rule {rule:?} for {response}",
                         prov.provider_name
                     );
-                    if rule.matches(is_internal, &response) {
+                    if rule.matches(is_internal, record.kind, &response, category) {
                         result.push(rule.clone());
                     }
                 }
@@ -413,7 +712,7 @@ impl ShapingInner {
         if let Some(by_site) = self.by_site.get(site_name) {
             for rule in &by_site.automation {
                 tracing::trace!("Consider \"{site_name}\" rule {rule:?} for {response}");
-                if rule.matches(is_internal, &response) {
+                if rule.matches(is_internal, record.kind, &response, category) {
                     result.push(rule.clone_and_set_rollup());
                 }
             }
@@ -423,7 +722,7 @@ impl ShapingInner {
         if let Some(by_domain) = self.by_domain.get(domain) {
             for rule in &by_domain.automation {
                 tracing::trace!("Consider \"{domain}\" rule {rule:?} for {response}");
-                if rule.matches(is_internal, &response) {
+                if rule.matches(is_internal, record.kind, &response, category) {
                     result.push(rule.clone());
                 }
             }
@@ -433,6 +732,29 @@ impl ShapingInner {
     }
 }
 
+/// Returns the site_name of the egress path that produced the record.
+#[cfg(feature = "lua")]
+pub fn record_site_name(record: &JsonLogRecord) -> String {
+    let source = record.egress_source.as_deref().unwrap_or("unspecified");
+    // record.site is poorly named; it is really an identifier for the
+    // egress path. For matching purposes, we want just the site_name
+    // in the form produced by our MX resolution process.
+    // In an earlier incarnation of this logic, we would resolve the
+    // site_name for ourselves based on other data in the record,
+    // but that could lead to over-resolution of some names and
+    // yield surprising results.
+    // What we do here is extract the egress path decoration from
+    // record.site to arrive at something that looks like the
+    // mx site_name.
+    // NOTE: this is coupled with the logic in
+    // ReadyQueueManager::compute_queue_name
+    record
+        .site
+        .trim_start_matches(&format!("{source}->"))
+        .trim_end_matches("@smtp_client")
+        .to_string()
+}
+
 #[cfg(feature = "lua")]
 #[derive(Debug, Default, Clone, mlua::FromLua)]
 pub struct Shaping {
@@ -876,6 +1198,25 @@ impl Shaping {
             .await
     }
 
+    /// Resolves the egress path configuration that is defined by
+    /// the shaping data, without any overrides produced by the tsa-daemon
+    pub async fn get_egress_path_params(
+        &self,
+        domain: &str,
+        egress_source: &str,
+        site_name: &str,
+    ) -> anyhow::Result<EgressPathConfig> {
+        Ok(self
+            .get_egress_path_config(domain, egress_source, site_name)
+            .await
+            .finish()?
+            .params)
+    }
+
+    pub fn get_egress_pool(&self, domain: &str, site_name: &str) -> Option<String> {
+        self.inner.get_egress_pool(domain, site_name)
+    }
+
     pub fn get_errors(&self) -> &[String] {
         &self.inner.errors
     }
@@ -930,6 +1271,13 @@ impl LuaUserData for Shaping {
             },
         );
 
+        methods.add_method(
+            "get_egress_pool",
+            move |_lua, this, (domain, site_name): (String, String)| {
+                Ok(this.get_egress_pool(&domain, &site_name))
+            },
+        );
+
         methods.add_method("get_errors", move |_lua, this, ()| {
             let errors: Vec<String> = this.get_errors().iter().map(|s| s.to_string()).collect();
             Ok(errors)
@@ -1002,6 +1350,11 @@ struct PartialEntry {
 
     #[serde(default)]
     pub sources: OrderMap<String, toml::Table>,
+
+    /// The egress pool to use for the destination, which is
+    /// typically set by a SwitchPool automation action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub egress_pool: Option<String>,
 }
 
 #[cfg(feature = "lua")]
@@ -1077,83 +1430,120 @@ fn test_host_matches() {
     assert!(!host_matches("foo.com", "notfoo.com"));
 }
 
+/// Returns true if each of the MX hosts is matched by one of the
+/// MXSuffix or HostName rules
 #[cfg(feature = "lua")]
-impl ProviderEntry {
-    async fn domain_matches(&self, domain: &str) -> bool {
-        // We'd like to avoid doing DNS if we can do a simple suffix match,
-        // so we bias to looking at those first
-        let mut need_mx = false;
-
-        tracing::trace!(
-            "ProviderEntry::domain_matches({domain}) vs {:?}",
-            self.matches
-        );
+pub(crate) fn provider_rules_match_hosts(rules: &[ProviderMatch], hosts: &[String]) -> bool {
+    tracing::trace!("Consider MXSuffix rules");
+    for host in hosts {
+        let mut matched = false;
 
-        for rule in &self.matches {
+        for rule in rules {
             match rule {
-                ProviderMatch::DomainSuffix(suffix) => {
-                    if suffix_matches(domain, suffix) {
-                        tracing::trace!("{domain} suffix matches {suffix}");
-                        return true;
+                ProviderMatch::MXSuffix(suffix) => {
+                    // For a given MX suffix rule, all hosts must match
+                    // it for it to be valid. This is so that we don't
+                    // falsely lump a vanity domain that blends providers
+                    // together.
+                    tracing::trace!("suffix={suffix} vs host {host}");
+                    if suffix_matches(host, suffix) {
+                        matched = true;
+                        break;
                     }
                 }
-                ProviderMatch::HostName(_) | ProviderMatch::MXSuffix(_) => {
-                    need_mx = true;
+                ProviderMatch::HostName(name) => {
+                    if host_matches(host, name) {
+                        matched = true;
+                        break;
+                    }
                 }
+                ProviderMatch::DomainSuffix(_) => {}
             }
         }
 
-        if !need_mx {
+        if !matched {
+            tracing::trace!("host didn't match any of these rules");
             return false;
         }
+    }
 
-        // Now we can consider DNS
-        match MailExchanger::resolve(&domain).await {
-            Err(err) => {
-                tracing::error!(
-                    "Error resolving MX for {domain}: {err:#}. \
-                    Provider {} match rules will be ignored",
-                    self.provider_name
-                );
-                false
-            }
-            Ok(mx) => {
-                tracing::trace!("Consider MXSuffix rules");
-                for host in &mx.hosts {
-                    let mut matched = false;
-
-                    for rule in &self.matches {
-                        match rule {
-                            ProviderMatch::MXSuffix(suffix) => {
-                                // For a given MX suffix rule, all hosts must match
-                                // it for it to be valid. This is so that we don't
-                                // falsely lump a vanity domain that blends providers
-                                // together.
-                                tracing::trace!("suffix={suffix} vs host {host}");
-                                if suffix_matches(host, suffix) {
-                                    matched = true;
-                                    break;
-                                }
-                            }
-                            ProviderMatch::HostName(name) => {
-                                if host_matches(host, name) {
-                                    matched = true;
-                                    break;
-                                }
-                            }
-                            ProviderMatch::DomainSuffix(_) => {}
-                        }
-                    }
+    true
+}
 
-                    if !matched {
-                        tracing::trace!("host didn't match any of these rules");
-                        return false;
-                    }
+/// Returns true if the domain is matched by one of the DomainSuffix
+/// rules. Otherwise, returns None if the MX hosts of the domain need
+/// to be considered, or false if they do not.
+#[cfg(feature = "lua")]
+pub(crate) fn provider_rules_match_domain_suffix(
+    rules: &[ProviderMatch],
+    domain: &str,
+) -> Option<bool> {
+    let mut need_mx = false;
+
+    for rule in rules {
+        match rule {
+            ProviderMatch::DomainSuffix(suffix) => {
+                if suffix_matches(domain, suffix) {
+                    tracing::trace!("{domain} suffix matches {suffix}");
+                    return Some(true);
                 }
-
-                true
             }
+            ProviderMatch::HostName(_) | ProviderMatch::MXSuffix(_) => {
+                need_mx = true;
+            }
+        }
+    }
+
+    if need_mx {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+/// Returns true if the domain is matched by the rules of the named provider
+#[cfg(feature = "lua")]
+pub(crate) async fn provider_rules_match_domain(
+    provider_name: &str,
+    rules: &[ProviderMatch],
+    domain: &str,
+) -> bool {
+    tracing::trace!("provider {provider_name} rules {rules:?} vs {domain}");
+
+    // We'd like to avoid doing DNS if we can do a simple suffix match,
+    // so we bias to looking at those first
+    if let Some(matched) = provider_rules_match_domain_suffix(rules, domain) {
+        return matched;
+    }
+
+    // Now we can consider DNS
+    match MailExchanger::resolve(&domain).await {
+        Err(err) => {
+            tracing::error!(
+                "Error resolving MX for {domain}: {err:#}. \
+                Provider {provider_name} match rules will be ignored",
+            );
+            false
+        }
+        Ok(mx) => provider_rules_match_hosts(rules, &mx.hosts),
+    }
+}
+
+#[cfg(feature = "lua")]
+impl ProviderEntry {
+    async fn domain_matches(&self, domain: &str) -> bool {
+        if self.matches.is_empty() {
+            // Use the match rules from the provider definitions
+            let definitions = crate::provider::get_provider_definitions();
+            return match definitions.get(&self.provider_name) {
+                Some(definition) => {
+                    provider_rules_match_domain(&self.provider_name, &definition.matches, domain)
+                        .await
+                }
+                None => false,
+            };
         }
+        provider_rules_match_domain(&self.provider_name, &self.matches, domain).await
     }
 
     fn merge_from(&mut self, mut other: Self) {
@@ -1246,6 +1636,11 @@ impl ProviderEntry {
             );
         }
 
+        for rule in &self.automation {
+            rule.validate()
+                .with_context(|| format!("provider '{provider_name}' automation rule {rule:?}"))?;
+        }
+
         Ok(MergedEntry {
             params,
             sources,
@@ -1300,9 +1695,14 @@ impl PartialEntry {
             self.params = other.params;
             self.automation = other.automation;
             self.sources = other.sources;
+            self.egress_pool = other.egress_pool;
         } else {
             toml_table_merge_from(&mut self.params, &other.params);
 
+            if other.egress_pool.is_some() {
+                self.egress_pool = other.egress_pool;
+            }
+
             for (source, tbl) in other.sources {
                 match self.sources.get_mut(&source) {
                     Some(existing) => {
@@ -1338,6 +1738,11 @@ impl PartialEntry {
             );
         }
 
+        for rule in &self.automation {
+            rule.validate()
+                .with_context(|| format!("domain '{domain}' automation rule {rule:?}"))?;
+        }
+
         Ok(MergedEntry {
             params,
             sources,
@@ -1376,6 +1781,16 @@ where
     )
 }
 
+fn category_string_or_array<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    string_or_array(
+        deserializer,
+        "category string or array of category strings for Category",
+    )
+}
+
 fn regex_string_or_array<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
 where
     D: Deserializer<'de>,
@@ -1667,6 +2082,7 @@ match_internal = true
                 timestamp: Default::default(),
                 created: Default::default(),
                 num_attempts: 1,
+                latency: None,
                 bounce_classification: Default::default(),
                 egress_pool: None,
                 egress_source: None,
@@ -1682,6 +2098,10 @@ match_internal = true
                 tls_peer_subject_name: None,
                 provider_name: None,
                 session_id: None,
+                suppressed_count: None,
+                response_category: None,
+                annotations: vec![],
+                tenant_usage: None,
             }
         }
 
@@ -1754,6 +2174,113 @@ match_internal = true
         );
     }
 
+    #[test]
+    fn test_rule_conditions() {
+        let rule: Rule = toml::from_str(
+            r#"
+condition = {Any=[
+    {Response="4\\.7\\.1"},
+    {All=[{Response="blocked"}, {BounceRate={percent=5.0, window="1h"}}]},
+]}
+action = [{ReduceRate={percent=50}}, {SwitchPool="fallback"}]
+duration = "1h"
+"#,
+        )
+        .unwrap();
+        rule.validate().unwrap();
+        k9::assert_equal!(
+            rule.describe(),
+            "(response =~ 4\\.7\\.1 or (response =~ blocked and bounce rate >= 5% over 3600s))"
+        );
+
+        // Deliveries are relevant, as they are counted by the bounce rate
+        assert!(rule.matches(false, RecordType::Delivery, "250 ok", None));
+        assert!(!rule.matches(false, RecordType::TransientFailure, "451 later", None));
+        assert!(!rule.matches(true, RecordType::Bounce, "550 blocked", None));
+
+        let mut evaluated = 0;
+        let mut evaluate = |response: &str, high_bounce_rate: bool| {
+            rule.evaluate(response, None, |rate: &BounceRate| {
+                assert_eq!(rate.min_volume, 100);
+                evaluated += 1;
+                Ok(high_bounce_rate)
+            })
+            .unwrap()
+        };
+        assert!(evaluate("451 4.7.1 try again later", false));
+        assert!(!evaluate("550 blocked", false));
+        assert!(evaluate("550 blocked", true));
+        assert!(!evaluate("250 ok", true));
+        // The bounce rate is evaluated even when it cannot
+        // change the result
+        k9::assert_equal!(evaluated, 4);
+
+        let rule: Rule = toml::from_str(
+            r#"
+action = "Suspend"
+duration = "1h"
+"#,
+        )
+        .unwrap();
+        assert!(rule.validate().is_err());
+
+        let rule: Rule = toml::from_str(
+            r#"
+regex = "blocked"
+condition = {BounceRate={percent=0.0, window="1h"}}
+action = {ReduceRate={percent=100}}
+duration = "1h"
+"#,
+        )
+        .unwrap();
+        assert!(rule.validate().is_err());
+    }
+
+    #[test]
+    fn test_rule_category_condition() {
+        let rule: Rule = toml::from_str(
+            r#"
+condition = {Category=["RateLimited", "ReputationBlock"]}
+action = "Suspend"
+duration = "1h"
+"#,
+        )
+        .unwrap();
+        rule.validate().unwrap();
+        k9::assert_equal!(rule.describe(), "category in RateLimited | ReputationBlock");
+
+        let response = "451 4.7.500 Server busy. (S3150)";
+        assert!(rule.matches(
+            false,
+            RecordType::TransientFailure,
+            response,
+            Some("RateLimited")
+        ));
+        assert!(!rule.matches(false, RecordType::TransientFailure, response, None));
+        assert!(!rule.matches(
+            false,
+            RecordType::TransientFailure,
+            response,
+            Some("MailboxFull")
+        ));
+
+        let never = |_: &BounceRate| -> anyhow::Result<bool> { unreachable!() };
+        assert!(rule
+            .evaluate(response, Some("ReputationBlock"), never)
+            .unwrap());
+        assert!(!rule.evaluate(response, None, never).unwrap());
+
+        let rule: Rule = toml::from_str(
+            r#"
+condition = {Category=[]}
+action = "Suspend"
+duration = "1h"
+"#,
+        )
+        .unwrap();
+        assert!(rule.validate().is_err());
+    }
+
     #[tokio::test]
     async fn test_defaults() {
         let shaping = make_shaping_configs(&[
@@ -1890,6 +2417,11 @@ MergedEntry {
         remember_broken_tls: None,
         opportunistic_tls_reconnect_on_failed_handshake: false,
         use_lmtp: false,
+        cluster_connection_limit: None,
+        cluster_max_message_rate: None,
+        tls_session_resumption: true,
+        try_next_host_on_response_codes: [],
+        max_bandwidth: None,
     },
     sources: {},
     automation: [
@@ -1911,6 +2443,7 @@ MergedEntry {
                     /Server busy\. Please try again later from/,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -1937,6 +2470,7 @@ MergedEntry {
             duration: 5400s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
         Rule {
             regex: [
@@ -1944,6 +2478,7 @@ MergedEntry {
                     KumoMTA internal: failed to connect to any candidate hosts: All failures are related to OpportunisticInsecure STARTTLS. Consider setting enable_tls=Disabled for this site,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -1960,6 +2495,7 @@ MergedEntry {
             duration: 2592000s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
     ],
 }
@@ -2026,6 +2562,11 @@ MergedEntry {
         remember_broken_tls: None,
         opportunistic_tls_reconnect_on_failed_handshake: false,
         use_lmtp: false,
+        cluster_connection_limit: None,
+        cluster_max_message_rate: None,
+        tls_session_resumption: true,
+        try_next_host_on_response_codes: [],
+        max_bandwidth: None,
     },
     sources: {
         "my source name": EgressPathConfig {
@@ -2075,6 +2616,11 @@ MergedEntry {
             remember_broken_tls: None,
             opportunistic_tls_reconnect_on_failed_handshake: false,
             use_lmtp: false,
+            cluster_connection_limit: None,
+            cluster_max_message_rate: None,
+            tls_session_resumption: true,
+            try_next_host_on_response_codes: [],
+            max_bandwidth: None,
         },
     },
     automation: [
@@ -2096,6 +2642,7 @@ MergedEntry {
                     /Server busy\. Please try again later from/,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -2122,6 +2669,7 @@ MergedEntry {
             duration: 5400s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
         Rule {
             regex: [
@@ -2129,6 +2677,7 @@ MergedEntry {
                     KumoMTA internal: failed to connect to any candidate hosts: All failures are related to OpportunisticInsecure STARTTLS. Consider setting enable_tls=Disabled for this site,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -2145,6 +2694,7 @@ MergedEntry {
             duration: 2592000s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
     ],
 }
@@ -2217,6 +2767,11 @@ MergedEntry {
         remember_broken_tls: None,
         opportunistic_tls_reconnect_on_failed_handshake: false,
         use_lmtp: false,
+        cluster_connection_limit: None,
+        cluster_max_message_rate: None,
+        tls_session_resumption: true,
+        try_next_host_on_response_codes: [],
+        max_bandwidth: None,
     },
     sources: {},
     automation: [
@@ -2238,6 +2793,7 @@ MergedEntry {
                     /Server busy\. Please try again later from/,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -2264,6 +2820,7 @@ MergedEntry {
             duration: 5400s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
         Rule {
             regex: [
@@ -2271,6 +2828,7 @@ MergedEntry {
                     KumoMTA internal: failed to connect to any candidate hosts: All failures are related to OpportunisticInsecure STARTTLS. Consider setting enable_tls=Disabled for this site,
                 ),
             ],
+            condition: None,
             action: [
                 SetConfig(
                     EgressPathConfigValue {
@@ -2287,6 +2845,7 @@ MergedEntry {
             duration: 2592000s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
         Rule {
             regex: [
@@ -2294,6 +2853,7 @@ MergedEntry {
                     \[TS04\],
                 ),
             ],
+            condition: None,
             action: [
                 Suspend,
             ],
@@ -2301,6 +2861,7 @@ MergedEntry {
             duration: 7200s,
             was_rollup: false,
             match_internal: false,
+            dry_run: false,
         },
     ],
 }
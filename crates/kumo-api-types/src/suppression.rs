@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+/// An address or domain that is suppressed; messages to
+/// it are not accepted or delivered
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SuppressionV1Entry {
+    /// The suppressed email address, or the suppressed domain,
+    /// which matches all addresses at that domain.
+    /// Always lowercase.
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+    /// Why the recipient is suppressed
+    #[schema(example = "unsubscribed")]
+    pub reason: String,
+    /// When the entry was added
+    pub created: DateTime<Utc>,
+    /// When the entry expires. If omitted, the entry
+    /// remains in effect until it is removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Describes an address or domain to suppress
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionV1AddRequest {
+    /// The email address, or the domain, to suppress.
+    /// Any existing entry for it is replaced.
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+
+    /// Why the recipient is suppressed
+    #[schema(example = "unsubscribed")]
+    pub reason: String,
+
+    /// How long the entry remains in effect. If omitted,
+    /// the entry remains in effect until it is removed.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type=Option<String>, example="30 days")]
+    pub duration: Option<Duration>,
+}
+
+/// Identifies the suppression entry to remove
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionV1RemoveRequest {
+    /// The email address, or the domain, whose entry
+    /// should be removed
+    #[schema(example = "user@example.com")]
+    pub recipient: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams)]
+pub struct SuppressionV1QueryRequest {
+    /// Only return the entries that suppress this email address,
+    /// or this domain. An email address is suppressed by an entry
+    /// for the address itself, or for its domain.
+    #[serde(default)]
+    pub recipient: Option<String>,
+    /// The maximum number of entries to return.
+    /// The default is 100.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
@@ -1,5 +1,5 @@
 use chrono::{DateTime, Utc};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Default)]
 pub struct Suspensions {
@@ -7,7 +7,7 @@ pub struct Suspensions {
     pub sched_q: Vec<SchedQSuspension>,
 }
 
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct ReadyQSuspension {
     pub rule_hash: String,
     pub site_name: String,
@@ -16,7 +16,7 @@ pub struct ReadyQSuspension {
     pub expires: DateTime<Utc>,
 }
 
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct SchedQSuspension {
     pub rule_hash: String,
     pub tenant: String,
@@ -32,7 +32,7 @@ pub enum SuspensionEntry {
     SchedQ(SchedQSuspension),
 }
 
-#[derive(Serialize, Default, Clone)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct SchedQBounce {
     pub rule_hash: String,
     pub domain: String,
@@ -48,3 +48,22 @@ pub enum SubscriptionItem {
     SchedQSuspension(SchedQSuspension),
     SchedQBounce(SchedQBounce),
 }
+
+/// A decision that an automation rule would have made, had it
+/// not been in dry-run mode
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DryRunDecision {
+    pub rule_hash: String,
+    /// Describes the action that would have been taken
+    pub action: String,
+    pub reason: String,
+    /// When the rule first triggered the action
+    pub first_triggered: DateTime<Utc>,
+    /// When the rule most recently triggered the action
+    pub last_triggered: DateTime<Utc>,
+    /// The number of times that the rule triggered the action
+    /// since first_triggered
+    pub count: u64,
+    /// When the decision would have expired
+    pub expires: DateTime<Utc>,
+}
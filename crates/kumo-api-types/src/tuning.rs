@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use throttle::ThrottleSpec;
+use utoipa::{ToResponse, ToSchema};
+
+/// Overrides the egress path configuration of the matching ready
+/// queues. Fields that are omitted are taken from the
+/// `get_egress_path_config` event as usual.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReadyQueueTuningV1 {
+    /// The ready queue name, or the site name, to which the
+    /// overrides apply. When overrides are defined for both the
+    /// site name and the ready queue name, those defined for the
+    /// ready queue name take precedence.
+    #[schema(example = "(alt1|alt2|alt3|alt4)?.gmail-smtp-in.l.google.com")]
+    pub name: String,
+
+    /// The maximum number of concurrent connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 16)]
+    pub connection_limit: Option<usize>,
+
+    /// The maximum number of messages held in the ready queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ready: Option<usize>,
+
+    /// The maximum number of messages delivered over a connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_deliveries_per_connection: Option<usize>,
+
+    /// The rate at which messages may be delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type=Option<String>, example="100/s")]
+    pub max_message_rate: Option<ThrottleSpec>,
+
+    /// The rate at which connections may be established
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type=Option<String>, example="10/s")]
+    pub max_connection_rate: Option<ThrottleSpec>,
+}
+
+/// Describes the runtime adjustments to make.
+/// Fields that are omitted are left unchanged.
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TuningV1Request {
+    /// Ready queue overrides to define. Each replaces any
+    /// existing override with the same name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ready_queues: Vec<ReadyQueueTuningV1>,
+
+    /// The names of the ready queue overrides to remove,
+    /// reverting to the configuration defined by the policy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reset_ready_queues: Vec<String>,
+
+    /// The time limit for each DNS query. A value of zero
+    /// removes the limit.
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type=Option<String>, example="5s")]
+    pub dns_query_timeout: Option<Duration>,
+
+    /// Changes the capacity of the named lruttl caches
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(example=json!({"dns_resolver_mx": 128000}))]
+    pub cache_capacities: BTreeMap<String, usize>,
+
+    /// Overrides the soft memory limit, in bytes. A value of
+    /// zero reverts to the limit detected from the environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_soft_limit: Option<u64>,
+
+    /// The percentage of the soft memory limit above which
+    /// memory is considered to be low
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 80)]
+    pub low_memory_threshold: Option<u8>,
+}
+
+/// The runtime adjustments currently in effect
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct TuningV1Response {
+    /// The ready queue overrides
+    pub ready_queues: Vec<ReadyQueueTuningV1>,
+
+    /// The time limit for each DNS query, if any
+    #[serde(default, with = "duration_serde")]
+    #[schema(value_type=Option<String>, example="5s")]
+    pub dns_query_timeout: Option<Duration>,
+
+    /// The capacity of each of the lruttl caches
+    pub cache_capacities: BTreeMap<String, usize>,
+
+    /// The soft memory limit override, if any
+    pub memory_soft_limit: Option<u64>,
+
+    /// The percentage of the soft memory limit above which
+    /// memory is considered to be low
+    pub low_memory_threshold: u8,
+}
@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::{ToResponse, ToSchema};
+
+/// Describes which outgoing SMTP sessions should be captured,
+/// and for how long.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct WireCaptureV1Request {
+    /// Capture sessions that deliver messages to this domain.
+    /// Any existing capture for the same domain is replaced.
+    #[schema(example = "example.com")]
+    pub domain: String,
+
+    /// How long to capture for. Defaults to "10m".
+    #[serde(
+        default,
+        with = "duration_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type=Option<String>, example="10m")]
+    pub duration: Option<Duration>,
+
+    /// The maximum size of the capture file, in bytes.
+    /// The capture stops once it has been reached.
+    /// Defaults to 10MB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+
+    /// If true, the message content sent after the DATA command
+    /// is recorded. Otherwise, only its size is recorded.
+    #[serde(default)]
+    pub include_data: bool,
+}
+
+impl WireCaptureV1Request {
+    pub fn duration(&self) -> Duration {
+        self.duration.unwrap_or(Duration::from_secs(600))
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(10 * 1024 * 1024)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, ToResponse)]
+pub struct WireCaptureV1Response {
+    /// The path to the capture file on the kumod host.
+    /// Each line in the file is a JSON encoded
+    /// `TraceSmtpClientV1Event`.
+    #[schema(example = "/var/log/kumomta/wire-capture/example.com-20241021T173000.jsonl")]
+    pub path: String,
+    /// When the capture will stop
+    pub expires: DateTime<Utc>,
+}
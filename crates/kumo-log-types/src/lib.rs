@@ -60,6 +60,12 @@ pub enum RecordType {
     /// and into some other queue
     DeferredInjectionRebind,
 
+    /// A periodic report of the resources consumed by a tenant
+    TenantUsage,
+
+    /// A recipient used a one-click List-Unsubscribe link
+    Unsubscribe,
+
     /// Special for matching anything in the logging config
     Any,
 }
@@ -96,6 +102,10 @@ pub struct JsonLogRecord {
     /// Note that this may be approximate after a restart; use the
     /// number of logged events to determine the true number
     pub num_attempts: u16,
+    /// For Delivery, Bounce and Expiration records, the time in
+    /// seconds between the message being received and this disposition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<f64>,
 
     pub bounce_classification: BounceClass,
 
@@ -141,6 +151,65 @@ pub struct JsonLogRecord {
     /// the same connection for either ingress or egress
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session_id: Option<Uuid>,
+
+    /// When sampling of TransientFailure records is enabled, this is
+    /// set on the periodic summary records to the number of records
+    /// that were not logged in that interval
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppressed_count: Option<u64>,
+
+    /// For TransientFailure and Bounce records, the semantic category
+    /// to which the response was mapped by the response category
+    /// rules for the provider, such as `RateLimited`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_category: Option<String>,
+
+    /// The annotations that were recorded against the message
+    /// by policy and by the internal subsystems that handled it,
+    /// oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<Annotation>,
+
+    /// For TenantUsage records, the resources consumed by the tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_usage: Option<Box<TenantUsage>>,
+}
+
+/// A short note recorded against a message, such as to explain
+/// a policy or shaping decision that was made about it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Annotation {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+}
+
+/// The resources consumed by a tenant over the reporting period
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TenantUsage {
+    pub tenant: String,
+    /// The start of the reporting period
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub period_start: DateTime<Utc>,
+    /// The end of the reporting period
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub period_end: DateTime<Utc>,
+    /// The number of messages that were received
+    pub messages_received: u64,
+    /// The total size of the messages that were received, in bytes
+    pub bytes_received: u64,
+    /// The number of messages that were delivered
+    pub messages_delivered: u64,
+    /// The total size of the messages that were delivered, in bytes
+    pub bytes_delivered: u64,
+    /// The number of delivery attempts, including those
+    /// that resulted in a transient or permanent failure
+    pub delivery_attempts: u64,
+    /// The time spent in delivery attempts, in seconds
+    pub connection_seconds: f64,
+    /// The number of messages in the scheduled queues
+    /// at the end of the reporting period
+    pub scheduled_messages: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -155,5 +224,5 @@ pub struct MaybeProxiedSourceAddress {
 #[cfg(all(test, target_pointer_width = "64"))]
 #[test]
 fn sizes() {
-    assert_eq!(std::mem::size_of::<JsonLogRecord>(), 712);
+    assert_eq!(std::mem::size_of::<JsonLogRecord>(), 776);
 }
@@ -206,7 +206,8 @@ impl PerMessageReportEntry {
         let dsn_gateway = extract_single("dsn-gateway", &mut extensions)?;
         let received_from_mta = extract_single("received-from-mta", &mut extensions)?;
 
-        let arrival_date = extract_single("arrival-date", &mut extensions)?;
+        let arrival_date =
+            extract_single_conv::<DateTimeRfc2822, DateTime<Utc>>("arrival-date", &mut extensions)?;
 
         Ok(Self {
             original_envelope_id,
@@ -263,13 +264,19 @@ impl Report {
             }
         }
 
-        anyhow::bail!("delivery-status part missing");
+        // Some other kind of report, such as an ARF feedback report
+        Ok(None)
     }
 
     fn parse_inner(part: &MimePart, original_message: Option<String>) -> anyhow::Result<Self> {
         let body = part.raw_body();
         let body = body.replace("\r\n", "\n");
-        let mut parts = body.trim().split("\n\n");
+        // Tolerate additional blank lines between the sections
+        let mut parts = body
+            .trim()
+            .split("\n\n")
+            .map(|part| part.trim())
+            .filter(|part| !part.is_empty());
 
         let per_message = parts
             .next()
@@ -587,4 +594,43 @@ Some(
 "#
         );
     }
+
+    #[test]
+    fn rfc3464_delayed() {
+        let result = Report::parse(include_bytes!("../data/rfc3464/5.eml"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            result.per_message.original_envelope_id.as_deref(),
+            Some("QQ314159")
+        );
+        assert_eq!(
+            result.per_message.arrival_date,
+            Some("2024-01-02T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(result.per_recipient.len(), 1);
+
+        let recip = &result.per_recipient[0];
+        assert_eq!(recip.action, ReportAction::Delayed);
+        assert_eq!(recip.final_recipient.recipient, "user@example.net");
+        assert_eq!(
+            recip.diagnostic_code.as_ref().unwrap().diagnostic,
+            "421 4.4.1 connection timed out"
+        );
+        assert_eq!(
+            recip.will_retry_until,
+            Some("2024-01-05T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            result.original_message.as_deref(),
+            Some("From: sender@example.com\nTo: user@example.net\nSubject: hello\n\n")
+        );
+    }
+
+    #[test]
+    fn rfc3464_not_delivery_status() {
+        // An ARF report is a multipart/report, but is not a DSN
+        let result = Report::parse(include_bytes!("../data/rfc5965/1.eml")).unwrap();
+        assert!(result.is_none());
+    }
 }
@@ -39,6 +39,16 @@ pub struct ARFReport {
 
     pub original_message: Option<String>,
     pub supplemental_trace: Option<serde_json::Value>,
+
+    /// The Message-ID header of the original message, if it was
+    /// included in the report
+    #[serde(default)]
+    pub original_message_id: Option<String>,
+    /// The recipient of the original message, taken from the
+    /// supplemental trace header if present, otherwise from the
+    /// first Original-Rcpt-To field of the report
+    #[serde(default)]
+    pub original_recipient: Option<String>,
 }
 
 impl ARFReport {
@@ -59,6 +69,7 @@ impl ARFReport {
         }
 
         let mut original_message = None;
+        let mut original_message_id = None;
         let mut supplemental_trace = None;
 
         for part in mail.child_parts() {
@@ -68,6 +79,13 @@ impl ARFReport {
                 if let Ok(HeaderParseResult { headers, .. }) =
                     Header::parse_headers(part.raw_body())
                 {
+                    if let Some(id) = headers
+                        .get_first("Message-ID")
+                        .and_then(|hdr| hdr.as_unstructured().ok())
+                    {
+                        original_message_id.replace(id.trim().to_string());
+                    }
+
                     // Look for x-headers that might be our supplemental trace headers
                     for hdr in headers.iter() {
                         if !(hdr.get_name().starts_with("X-") || hdr.get_name().starts_with("x-")) {
@@ -107,6 +125,7 @@ impl ARFReport {
                 return Ok(Some(Self::parse_inner(
                     part,
                     original_message,
+                    original_message_id,
                     supplemental_trace,
                 )?));
             }
@@ -118,6 +137,7 @@ impl ARFReport {
     fn parse_inner(
         part: &MimePart,
         original_message: Option<String>,
+        original_message_id: Option<String>,
         supplemental_trace: Option<serde_json::Value>,
     ) -> anyhow::Result<Self> {
         let body = part.raw_body();
@@ -137,10 +157,25 @@ impl ARFReport {
         let reporting_mta = extract_single("reporting-mta", &mut extensions)?;
         let source_ip = extract_single("source-ip", &mut extensions)?;
         let authentication_results = extract_multiple("authentication-results", &mut extensions)?;
-        let original_rcpto_to = extract_multiple("original-rcpt-to", &mut extensions)?;
+        let original_rcpto_to: Vec<String> = extract_multiple("original-rcpt-to", &mut extensions)?;
         let reported_domain = extract_multiple("reported-domain", &mut extensions)?;
         let reported_uri = extract_multiple("reported-uri", &mut extensions)?;
 
+        let original_recipient = supplemental_trace
+            .as_ref()
+            .and_then(|trace| trace.get("recipient"))
+            .and_then(|recip| recip.as_str())
+            .map(|recip| recip.to_string())
+            .or_else(|| {
+                original_rcpto_to.first().map(|recip| {
+                    recip
+                        .trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string()
+                })
+            });
+
         Ok(Self {
             feedback_type,
             user_agent,
@@ -158,6 +193,8 @@ impl ARFReport {
             extensions,
             original_message,
             supplemental_trace,
+            original_message_id,
+            original_recipient,
         })
     }
 }
@@ -325,6 +362,10 @@ Spam Spam Spam
 ",
         ),
         supplemental_trace: None,
+        original_message_id: Some(
+            "8787KJKJ3K4J3K4J3K4J3.mail@example.net",
+        ),
+        original_recipient: None,
     },
 )
 "#
@@ -401,6 +442,12 @@ Spam Spam Spam
                 "recipient": String("test@example.com"),
             },
         ),
+        original_message_id: Some(
+            "8787KJKJ3K4J3K4J3K4J3.mail@example.net",
+        ),
+        original_recipient: Some(
+            "test@example.com",
+        ),
     },
 )
 "#
@@ -449,6 +496,10 @@ This is a test mailing
 ",
         ),
         supplemental_trace: None,
+        original_message_id: None,
+        original_recipient: Some(
+            "user@example.com",
+        ),
     },
 )
 "#
@@ -507,6 +558,10 @@ This is a test mailing
 ",
         ),
         supplemental_trace: None,
+        original_message_id: None,
+        original_recipient: Some(
+            "cb4a01a48251d4765f489076aa81e2a4@comcast.net",
+        ),
     },
 )
 "#
@@ -1,5 +1,6 @@
 pub use crate::counter::*;
 use crate::labels::MetricLabel;
+use crate::policy::OVERFLOW_LABEL_VALUE;
 use crate::registry::StreamingCollector;
 use async_stream::stream;
 use futures::stream::BoxStream;
@@ -9,7 +10,7 @@ pub use paste;
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 mod counter;
 pub mod counter_bundle;
@@ -17,10 +18,13 @@ pub mod counter_bundle;
 #[macro_use]
 pub mod labels;
 pub mod parser;
+pub mod policy;
 pub mod registry;
 
 struct CounterRegistryInner<K, V: AtomicCounterEntry> {
     map: RwLock<HashMap<K, V>>,
+    /// Aggregates the label sets that exceeded the cardinality limit
+    overflow: OnceLock<AtomicCounter>,
     name: &'static str,
     help: &'static str,
     is_gauge: bool,
@@ -30,6 +34,42 @@ struct CounterRegistryInner<K, V: AtomicCounterEntry> {
 /// when streaming out the serialized counter values
 const CHUNK_SIZE: usize = 4 * 1024;
 
+fn emit_overflow_text_value(labels: &[&str], target: &mut String, value: &str) {
+    target.push('{');
+    for (i, label) in labels.iter().enumerate() {
+        if i > 0 {
+            target.push_str(", ");
+        }
+        target.push_str(label);
+        target.push_str("=\"");
+        target.push_str(OVERFLOW_LABEL_VALUE);
+        target.push_str("\"");
+    }
+    target.push_str("} ");
+    target.push_str(value);
+}
+
+fn emit_overflow_json_value(labels: &[&str], target: &mut String, value: &str) {
+    if labels.len() == 1 {
+        target.push('"');
+        target.push_str(OVERFLOW_LABEL_VALUE);
+        target.push_str("\":");
+        target.push_str(value);
+    } else {
+        target.push_str("{");
+        for label in labels {
+            target.push_str("\"");
+            target.push_str(label);
+            target.push_str("\":\"");
+            target.push_str(OVERFLOW_LABEL_VALUE);
+            target.push_str("\",");
+        }
+        target.push_str("\"@\":");
+        target.push_str(value);
+        target.push_str("}");
+    }
+}
+
 impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingCollector
     for CounterRegistryInner<K, V>
 {
@@ -41,6 +81,10 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
         total_connection_count{service="smtp_client:source2->loopback.dummy-mx.wezfurlong.org@smtp_client"} 25
         */
 
+        if crate::policy::is_disabled(self.name) {
+            return futures::stream::empty().boxed();
+        }
+
         let mut buffer = String::with_capacity(CHUNK_SIZE);
         buffer.push_str("# HELP ");
         let prefix = prefix.as_deref().unwrap_or("");
@@ -67,6 +111,7 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
             }
             pairs
         };
+        let overflow = self.overflow.get().cloned();
 
         stream! {
             for (key, counter) in counters {
@@ -84,6 +129,12 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
                 }
             }
 
+            if let (Some(buf), Some(counter)) = (buffer.as_mut(), &overflow) {
+                buf.push_str(self.name);
+                emit_overflow_text_value(K::label_names(), buf, &counter.get().to_string());
+                buf.push('\n');
+            }
+
             if let Some(buf) = buffer.take() {
                 if !buf.is_empty(){
                     yield buf;
@@ -95,6 +146,10 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
     }
 
     fn stream_json(&self) -> BoxStream<String> {
+        if crate::policy::is_disabled(self.name) {
+            return futures::stream::empty().boxed();
+        }
+
         let mut target = String::with_capacity(CHUNK_SIZE);
         target.push_str(",\n\"");
         target.push_str(self.name);
@@ -118,9 +173,10 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
             }
             pairs
         };
+        let overflow = self.overflow.get().cloned();
 
         stream! {
-            if counters.is_empty() {
+            if counters.is_empty() && overflow.is_none() {
                 target.push_str("null}");
                 yield target;
                 return;
@@ -155,6 +211,13 @@ impl<K: Clone + MetricLabel + Send + Sync, V: AtomicCounterEntry> StreamingColle
                 }
             }
 
+            if let (Some(target), Some(counter)) = (buffer.as_mut(), &overflow) {
+                if !counters.is_empty() {
+                    target.push_str(",\n");
+                }
+                emit_overflow_json_value(labels, target, &counter.get().to_string());
+            }
+
             let Some(mut target) = buffer.take() else {return;};
             if labels.len() == 1 {
                 target.push_str("}}}");
@@ -222,6 +285,7 @@ impl<K: Clone + Send + Sync + MetricLabel + 'static, V: AtomicCounterEntry + 'st
         let me = Self {
             inner: Arc::new(CounterRegistryInner {
                 map: Default::default(),
+                overflow: OnceLock::new(),
                 name,
                 help,
                 is_gauge,
@@ -252,6 +316,8 @@ where
 
     /// Resolve an already-existing counter for the given key, creating
     /// a new one if it didn't already exist, or was previously pruned.
+    /// If the metric has reached its cardinality limit, the counter
+    /// that aggregates the excess label sets is returned instead.
     pub fn get_or_create<'a, Q: ?Sized>(&self, key: &'a Q) -> AtomicCounter
     where
         K: Borrow<Q> + From<&'a Q>,
@@ -264,6 +330,12 @@ where
             }
         }
 
+        if let Some(limit) = crate::policy::cardinality_limit(self.inner.name) {
+            if map.len() >= limit {
+                return self.inner.overflow.get_or_init(AtomicCounter::new).clone();
+            }
+        }
+
         let mut map = RwLockUpgradableReadGuard::upgrade(map);
 
         // Check again, as we may have lost a race
@@ -279,3 +351,78 @@ where
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::policy::{set_metrics_policy, MetricsPolicy};
+
+    label_key! {
+        pub struct TestKey {
+            pub service: String,
+        }
+    }
+
+    fn make_registry(name: &'static str) -> CounterRegistry<TestKey> {
+        CounterRegistry {
+            inner: Arc::new(CounterRegistryInner {
+                map: Default::default(),
+                overflow: OnceLock::new(),
+                name,
+                help: "help",
+                is_gauge: false,
+            }),
+        }
+    }
+
+    fn collect_text(registry: &CounterRegistry<TestKey>) -> Vec<String> {
+        let text: String =
+            futures::executor::block_on(registry.inner.stream_text(&None).collect::<Vec<_>>())
+                .concat();
+        let mut lines: Vec<String> = text
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn metrics_policy() {
+        set_metrics_policy(MetricsPolicy {
+            disabled_metrics: ["disabled_counter".to_string()].into_iter().collect(),
+            max_label_cardinality_by_metric: [("limited_counter".to_string(), 2)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        });
+
+        let limited = make_registry("limited_counter");
+        for service in ["a", "b", "c", "d", "a"] {
+            let key = BorrowedTestKey { service };
+            limited.get_or_create(&key as &dyn TestKeyTrait).inc();
+        }
+        assert_eq!(
+            collect_text(&limited),
+            vec![
+                "limited_counter{service=\"a\"} 2",
+                "limited_counter{service=\"b\"} 1",
+                "limited_counter{service=\"other\"} 2",
+            ]
+        );
+
+        let json: String =
+            futures::executor::block_on(limited.inner.stream_json().collect::<Vec<_>>()).concat();
+        let value: serde_json::Value = serde_json::from_str(&format!("{{\"x\":1{json}}}")).unwrap();
+        assert_eq!(
+            value["limited_counter"]["value"],
+            serde_json::json!({"service": {"a": 2, "b": 1, "other": 2}})
+        );
+
+        let disabled = make_registry("disabled_counter");
+        let key = BorrowedTestKey { service: "a" };
+        disabled.get_or_create(&key as &dyn TestKeyTrait).inc();
+        assert!(collect_text(&disabled).is_empty());
+    }
+}
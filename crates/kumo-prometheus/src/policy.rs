@@ -0,0 +1,57 @@
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+/// The label value used by the series that aggregates the values
+/// of any label sets that exceeded the cardinality limit of a metric
+pub const OVERFLOW_LABEL_VALUE: &str = "other";
+
+static POLICY: LazyLock<RwLock<MetricsPolicy>> = LazyLock::new(RwLock::default);
+
+/// Controls which metrics are exported, and how many distinct
+/// label sets each of them may have.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsPolicy {
+    /// The names of metric families that should not be exported
+    #[serde(default)]
+    pub disabled_metrics: HashSet<String>,
+
+    /// The maximum number of distinct label sets that each metric
+    /// may have. Once a metric has reached this limit, any new label
+    /// sets are aggregated into a single series whose labels all have
+    /// the value "other".
+    #[serde(default)]
+    pub max_label_cardinality: Option<usize>,
+
+    /// Overrides max_label_cardinality for specific metrics
+    #[serde(default)]
+    pub max_label_cardinality_by_metric: HashMap<String, usize>,
+}
+
+impl MetricsPolicy {
+    fn cardinality_limit(&self, name: &str) -> Option<usize> {
+        self.max_label_cardinality_by_metric
+            .get(name)
+            .copied()
+            .or(self.max_label_cardinality)
+    }
+}
+
+/// Replaces the metrics policy.
+/// Cardinality limits apply to label sets that are created after
+/// this call; existing series are left intact.
+pub fn set_metrics_policy(policy: MetricsPolicy) {
+    *POLICY.write() = policy;
+}
+
+/// Returns true if the named metric family should not be exported
+pub fn is_disabled(name: &str) -> bool {
+    POLICY.read().disabled_metrics.contains(name)
+}
+
+/// Returns the maximum number of distinct label sets for the named metric
+pub fn cardinality_limit(name: &str) -> Option<usize> {
+    POLICY.read().cardinality_limit(name)
+}
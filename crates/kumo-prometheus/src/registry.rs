@@ -61,7 +61,8 @@ impl Registry {
     ///
     /// This will include the MetricFamily's that have been registered with
     /// the prometheus crate and then supplement the output with our own
-    /// set of registered streaming collectors.
+    /// set of registered streaming collectors, omitting any metrics
+    /// that were disabled via the metrics policy.
     ///
     /// The optional prefix parameter is used to "namespace" the returned
     /// metric names.
@@ -70,6 +71,7 @@ impl Registry {
 
         stream! {
             let mut metrics = prometheus::default_registry().gather();
+            metrics.retain(|metric| !crate::policy::is_disabled(metric.get_name()));
             if let Some(prefix) = &prefix {
                 metrics.iter_mut().for_each(|metric| {
                     let name = format!("{prefix}{}", metric.get_name());
@@ -101,7 +103,8 @@ impl Registry {
 
         stream! {
             let mut buf = "{".to_string();
-            let metrics = prometheus::default_registry().gather();
+            let mut metrics = prometheus::default_registry().gather();
+            metrics.retain(|metric| !crate::policy::is_disabled(metric.get_name()));
             metrics_to_partial_json(&metrics, &mut buf);
             yield buf;
 
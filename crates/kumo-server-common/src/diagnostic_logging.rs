@@ -2,7 +2,8 @@ use anyhow::Context;
 use clap::ValueEnum;
 use metrics_prometheus::recorder::Layer as _;
 use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter, Layer};
@@ -26,11 +27,126 @@ static TRACING_FILTER_RELOAD_HANDLE: OnceLock<
     Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>,
 > = OnceLock::new();
 
-pub fn set_diagnostic_log_filter(new_filter: &str) -> anyhow::Result<()> {
+/// The filter most recently set via set_diagnostic_log_filter,
+/// to which the directives of any scoped filters are appended
+static BASE_FILTER: Mutex<String> = Mutex::new(String::new());
+static SCOPED_FILTERS: Mutex<Vec<ScopedFilter>> = Mutex::new(vec![]);
+
+/// The span fields by which diagnostic logging can be scoped.
+/// kumod records these on the spans that it creates while
+/// delivering messages.
+pub const SCOPE_FIELDS: &[&str] = &["domain", "tenant", "egress_pool"];
+
+#[derive(Debug, Clone)]
+struct ScopedFilter {
+    field: String,
+    value: String,
+    directive: String,
+    expires: Instant,
+}
+
+fn apply_filter(base: &str, scoped: &[ScopedFilter]) -> anyhow::Result<()> {
     let func = TRACING_FILTER_RELOAD_HANDLE
         .get()
         .ok_or_else(|| anyhow::anyhow!("unable to retrieve filter reload handle"))?;
-    (func)(new_filter)
+
+    let mut filter = base.to_string();
+    for entry in scoped {
+        if !filter.is_empty() {
+            filter.push(',');
+        }
+        filter.push_str(&entry.directive);
+    }
+    (func)(&filter)
+}
+
+pub fn set_diagnostic_log_filter(new_filter: &str) -> anyhow::Result<()> {
+    let scoped = SCOPED_FILTERS.lock().unwrap();
+    let mut base = BASE_FILTER.lock().unwrap();
+    apply_filter(new_filter, &scoped)?;
+    *base = new_filter.to_string();
+    Ok(())
+}
+
+/// Builds a directive that applies `filter`, which is either a level
+/// or a `target=level` pair, only within spans whose `field` has `value`
+fn scoped_directive(field: &str, value: &str, filter: &str) -> anyhow::Result<String> {
+    anyhow::ensure!(
+        SCOPE_FIELDS.contains(&field),
+        "cannot scope diagnostic logging by {field}; must be one of {SCOPE_FIELDS:?}"
+    );
+    anyhow::ensure!(
+        !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c)),
+        "invalid {field} {value:?}"
+    );
+    anyhow::ensure!(
+        !filter.contains(|c| ",[]{}".contains(c)),
+        "invalid scoped filter {filter:?}; expected a level or target=level"
+    );
+
+    // Field values are matched as regular expressions. The group
+    // prevents values such as `123` or `true` from being parsed as
+    // numbers or booleans, which would not match the recorded string
+    let value = format!("({})", value.replace('.', "\\."));
+    Ok(match filter.split_once('=') {
+        Some((target, level)) => format!("{target}[{{{field}={value}}}]={level}"),
+        None => format!("[{{{field}={value}}}]={filter}"),
+    })
+}
+
+/// Applies `filter` to the diagnostic logging of work relating to
+/// the specified domain, tenant or egress pool, in addition to the
+/// filter set by set_diagnostic_log_filter, for the specified duration.
+/// Replaces any existing scoped filter for the same field and value;
+/// a zero duration removes it.
+pub fn set_scoped_diagnostic_log_filter(
+    field: &str,
+    value: &str,
+    filter: &str,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let directive = scoped_directive(field, value, filter)?;
+
+    let mut scoped = SCOPED_FILTERS.lock().unwrap();
+    let mut updated: Vec<ScopedFilter> = scoped
+        .iter()
+        .filter(|entry| !(entry.field == field && entry.value == value))
+        .cloned()
+        .collect();
+    if !duration.is_zero() {
+        updated.push(ScopedFilter {
+            field: field.to_string(),
+            value: value.to_string(),
+            directive,
+            expires: Instant::now() + duration,
+        });
+    }
+
+    apply_filter(&BASE_FILTER.lock().unwrap(), &updated)?;
+    *scoped = updated;
+
+    if !duration.is_zero() {
+        kumo_server_runtime::rt_spawn("expire scoped log filter", async move {
+            tokio::time::sleep(duration).await;
+            expire_scoped_filters();
+        })?;
+    }
+    Ok(())
+}
+
+fn expire_scoped_filters() {
+    let mut scoped = SCOPED_FILTERS.lock().unwrap();
+    let now = Instant::now();
+    let before = scoped.len();
+    scoped.retain(|entry| entry.expires > now);
+    if scoped.len() != before {
+        if let Err(err) = apply_filter(&BASE_FILTER.lock().unwrap(), &scoped) {
+            tracing::error!("failed to remove expired scoped log filters: {err:#}");
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -68,11 +184,10 @@ impl<'a> LoggingConfig<'a> {
             DiagnosticFormat::Json => layer.json().boxed(),
         };
 
-        let env_filter = EnvFilter::try_new(
-            std::env::var(self.filter_env_var)
-                .as_deref()
-                .unwrap_or(self.default_filter),
-        )?;
+        let filter =
+            std::env::var(self.filter_env_var).unwrap_or_else(|_| self.default_filter.to_string());
+        let env_filter = EnvFilter::try_new(&filter)?;
+        *BASE_FILTER.lock().unwrap() = filter;
         let (env_filter, reload_handle) = tracing_subscriber::reload::Layer::new(env_filter);
         tracing_subscriber::registry()
             .with(layer.with_filter(env_filter))
@@ -94,3 +209,27 @@ impl<'a> LoggingConfig<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scoped_directives() {
+        assert_eq!(
+            scoped_directive("domain", "example.com", "debug").unwrap(),
+            "[{domain=(example\\.com)}]=debug"
+        );
+        assert_eq!(
+            scoped_directive("tenant", "123", "kumod::ready_queue=trace").unwrap(),
+            "kumod::ready_queue[{tenant=(123)}]=trace"
+        );
+        let directive = scoped_directive("egress_pool", "pool-1", "info").unwrap();
+        assert!(EnvFilter::try_new(directive).is_ok());
+
+        assert!(scoped_directive("campaign", "x", "debug").is_err());
+        assert!(scoped_directive("domain", "a,b", "debug").is_err());
+        assert!(scoped_directive("domain", "", "debug").is_err());
+        assert!(scoped_directive("domain", "example.com", "info,kumod=trace").is_err());
+    }
+}
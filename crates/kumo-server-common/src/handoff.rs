@@ -0,0 +1,351 @@
+//! Handing our listening sockets over to a newly started instance,
+//! so that the binary can be upgraded without refusing connections.
+//!
+//! The running instance listens on a unix socket. The new instance
+//! connects to it, sends a takeover request and receives the listening
+//! sockets via SCM_RIGHTS. Once the new instance has acknowledged them,
+//! the old instance stops accepting connections and shuts down, draining
+//! its in-flight sessions. The old instance holds the handoff connection
+//! open until it exits, which is also when its spool is released, so the
+//! new instance uses wait_for_previous_instance to wait for the connection
+//! to close before it opens the spool, and only then starts accepting
+//! connections on the sockets that it received. Connections made in the
+//! meantime wait in the listen queue rather than being refused.
+//!
+//! The handoff socket is only accessible to its owner, and each side
+//! verifies that its peer is running as the same user as itself, so
+//! that other local users cannot take our listeners or shut us down.
+use crate::listeners::{active_listeners, adopt_listener};
+use anyhow::Context;
+use kumo_server_lifecycle::{LifeCycle, ShutdownSubcription};
+use kumo_server_runtime::rt_spawn;
+use nix::sys::socket::{
+    getsockopt, recvmsg, sendmsg, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+};
+use nix::unistd::geteuid;
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
+
+const TAKEOVER_REQUEST: &[u8] = b"takeover\n";
+const TAKEOVER_ACK: &[u8] = b"ok\n";
+/// The maximum number of descriptors that linux allows
+/// to be passed in a single message
+const MAX_FDS: usize = 253;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+static TAKEOVER_DEADLINE: OnceLock<Instant> = OnceLock::new();
+static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+/// Becomes true once the instance that we took over from has exited
+static PREVIOUS_INSTANCE_EXITED: OnceLock<watch::Receiver<bool>> = OnceLock::new();
+/// Our end of the connection to the instance that took over from us.
+/// It is held open until we exit, so that the new instance can tell
+/// when we have released the spool.
+static NEW_INSTANCE: OnceLock<UnixStream> = OnceLock::new();
+
+/// Returns true if our listeners have been handed to a new instance,
+/// in which case we should stop accepting connections from them
+pub fn handed_off() -> bool {
+    HANDED_OFF.load(Ordering::SeqCst)
+}
+
+/// If we are taking over from a previous instance, returns the time
+/// until which we should wait for that instance to release resources,
+/// such as the spool, that it holds until it has shut down.
+pub fn takeover_deadline() -> Option<Instant> {
+    TAKEOVER_DEADLINE.get().copied()
+}
+
+/// If we are taking over from a previous instance, waits until that
+/// instance has exited and so released its spool, or until the
+/// takeover deadline has passed. Returns immediately otherwise.
+pub async fn wait_for_previous_instance() {
+    let (Some(exited), Some(deadline)) = (PREVIOUS_INSTANCE_EXITED.get(), takeover_deadline())
+    else {
+        return;
+    };
+    let mut exited = exited.clone();
+    let _ = tokio::time::timeout_at(deadline.into(), exited.wait_for(|exited| *exited)).await;
+}
+
+/// Takes over the listening sockets of the instance that is
+/// listening on the handoff socket at `path`, and causes it to
+/// shut down. The sockets are made available to our listeners
+/// via crate::listeners::take_inherited_listener.
+pub async fn take_over(path: &Path, timeout: Duration) -> anyhow::Result<()> {
+    TAKEOVER_DEADLINE
+        .set(Instant::now() + timeout)
+        .map_err(|_| anyhow::anyhow!("take_over called more than once"))?;
+
+    let socket_path = path.to_path_buf();
+    let (listeners, stream) = tokio::task::spawn_blocking(move || request_listeners(&socket_path))
+        .await?
+        .with_context(|| format!("requesting listeners via {}", path.display()))?;
+
+    tracing::info!(
+        "took over {} listeners from the previous instance",
+        listeners.len()
+    );
+    for listener in listeners {
+        adopt_listener(listener, "the previous instance")?;
+    }
+
+    // The previous instance closes the connection when it exits
+    stream.set_nonblocking(true)?;
+    let (exited_tx, exited_rx) = watch::channel(false);
+    PREVIOUS_INSTANCE_EXITED
+        .set(exited_rx)
+        .map_err(|_| anyhow::anyhow!("take_over called more than once"))?;
+    rt_spawn(
+        "handoff wait for previous instance".to_string(),
+        wait_for_exit(stream, exited_tx),
+    )?;
+
+    // The previous instance no longer serves the handoff socket,
+    // so remove it to make way for our own
+    std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+    Ok(())
+}
+
+/// Reads from the connection to the previous instance until it is
+/// closed, which happens when that instance exits
+async fn wait_for_exit(stream: UnixStream, exited: watch::Sender<bool>) {
+    let result = async {
+        let mut stream = tokio::net::UnixStream::from_std(stream)?;
+        let mut buf = [0u8; 16];
+        while stream.read(&mut buf).await? > 0 {}
+        Ok::<(), std::io::Error>(())
+    };
+    if let Err(err) = result.await {
+        tracing::error!("waiting for the previous instance to exit: {err:#}");
+    }
+    tracing::info!("the previous instance has exited");
+    exited.send_replace(true);
+}
+
+/// Ensures that the process on the other end of stream is running
+/// as the same user as we are
+fn check_peer_uid(stream: &UnixStream) -> anyhow::Result<()> {
+    let creds = getsockopt(stream, sockopt::PeerCredentials)?;
+    let euid = geteuid();
+    anyhow::ensure!(
+        creds.uid() == euid.as_raw(),
+        "peer uid {} does not match our uid {euid}",
+        creds.uid()
+    );
+    Ok(())
+}
+
+fn request_listeners(path: &Path) -> anyhow::Result<(Vec<TcpListener>, UnixStream)> {
+    let mut stream = UnixStream::connect(path)?;
+    check_peer_uid(&stream)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    stream.write_all(TAKEOVER_REQUEST)?;
+
+    let mut count = [0u8; 16];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let mut fds = vec![];
+    let len = {
+        let mut iov = [IoSliceMut::new(&mut count)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buffer),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                for fd in received {
+                    // SAFETY: the kernel installed these descriptors
+                    // in our process as part of receiving the message,
+                    // and nothing else refers to them
+                    fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+                }
+            }
+        }
+        anyhow::ensure!(
+            !msg.flags.contains(MsgFlags::MSG_CTRUNC),
+            "listener descriptors were truncated"
+        );
+        msg.bytes
+    };
+
+    let expected: usize = std::str::from_utf8(&count[..len])?
+        .trim()
+        .parse()
+        .context("parsing listener count")?;
+    anyhow::ensure!(
+        fds.len() == expected,
+        "expected {expected} listener descriptors but received {}",
+        fds.len()
+    );
+
+    stream.write_all(TAKEOVER_ACK)?;
+
+    Ok((fds.into_iter().map(TcpListener::from).collect(), stream))
+}
+
+/// Starts listening on the handoff socket at `path`, so that
+/// a newly started instance can take over from us.
+pub fn start_server(path: &Path) -> anyhow::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        anyhow::bail!(
+            "another instance is already listening on {}. \
+             Use --takeover to take over from it",
+            path.display()
+        );
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("removing stale {}", path.display()));
+        }
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("listening on {}", path.display()))?;
+    // Connections made before this takes effect are still
+    // refused by the peer credential check in send_listeners
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("setting permissions of {}", path.display()))?;
+    tracing::info!("accepting takeover requests on {}", path.display());
+
+    let path = path.to_path_buf();
+    rt_spawn("handoff listener".to_string(), async move {
+        let mut shutting_down = ShutdownSubcription::get();
+        loop {
+            tokio::select! {
+                _ = shutting_down.shutting_down() => {
+                    return;
+                }
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, _addr)) => {
+                            if handle_takeover(stream, &path).await {
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            tracing::error!("accept on {}: {err:#}", path.display());
+                        }
+                    }
+                }
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Serves a takeover request. Returns true if our listeners were
+/// handed over, in which case we have begun shutting down.
+async fn handle_takeover(stream: tokio::net::UnixStream, path: &Path) -> bool {
+    let result = match stream.into_std() {
+        Ok(stream) => tokio::task::spawn_blocking(move || send_listeners(stream))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result),
+        Err(err) => Err(err.into()),
+    };
+
+    match result {
+        Ok((count, stream)) => {
+            let _ = NEW_INSTANCE.set(stream);
+            tracing::info!(
+                "handed {count} listeners to a new instance via {}; shutting down",
+                path.display()
+            );
+            HANDED_OFF.store(true, Ordering::SeqCst);
+            LifeCycle::request_shutdown().await;
+            true
+        }
+        Err(err) => {
+            tracing::error!("takeover request via {} failed: {err:#}", path.display());
+            false
+        }
+    }
+}
+
+fn send_listeners(mut stream: UnixStream) -> anyhow::Result<(usize, UnixStream)> {
+    check_peer_uid(&stream)?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut request = [0u8; TAKEOVER_REQUEST.len()];
+    stream.read_exact(&mut request)?;
+    anyhow::ensure!(request == TAKEOVER_REQUEST, "invalid takeover request");
+
+    let listeners = active_listeners()?;
+    anyhow::ensure!(
+        listeners.len() <= MAX_FDS,
+        "cannot hand over more than {MAX_FDS} listeners"
+    );
+    let fds: Vec<RawFd> = listeners.iter().map(|fd| fd.as_raw_fd()).collect();
+    let count = format!("{}\n", fds.len());
+    let cmsgs = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(&fds)]
+    };
+    sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(count.as_bytes())],
+        &cmsgs,
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    // Only stop serving once the new instance has confirmed that
+    // it has the sockets, so that a failed takeover leaves us running
+    let mut ack = [0u8; TAKEOVER_ACK.len()];
+    stream.read_exact(&mut ack)?;
+    anyhow::ensure!(ack == TAKEOVER_ACK, "invalid takeover acknowledgement");
+
+    Ok((fds.len(), stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::listeners::register_active_listener;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn hand_over_listeners() {
+        let path = std::env::temp_dir().join(format!("kumo-handoff-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let handoff = UnixListener::bind(&path).unwrap();
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        register_active_listener(&tcp).unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = handoff.accept().unwrap();
+            let (count, stream) = send_listeners(stream).unwrap();
+            drop(stream);
+            count
+        });
+
+        let (listeners, mut stream) = request_listeners(&path).unwrap();
+        assert_eq!(server.join().unwrap(), 1);
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+
+        // We see EOF once the previous instance has let go of the connection
+        let mut buf = [0u8; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
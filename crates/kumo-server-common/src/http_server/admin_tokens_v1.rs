@@ -0,0 +1,91 @@
+use crate::http_server::auth::{
+    define_api_token, list_api_tokens, remove_api_token, AdminRequired,
+};
+use crate::http_server::{AppError, StatusCodeError};
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::{
+    ApiTokenV1CreateRequest, ApiTokenV1CreateResponse, ApiTokenV1DeleteRequest, ApiTokenV1ListEntry,
+};
+use throttle::ThrottleSpec;
+
+/// List the API tokens that are currently defined.
+/// The secret token values are not returned.
+#[utoipa::path(
+    get,
+    tag="tokens",
+    path="/api/admin/tokens/v1",
+    responses(
+        (status = 200, description = "Obtained token list", body=[ApiTokenV1ListEntry]),
+    ),
+)]
+pub async fn list(_: AdminRequired) -> Result<Json<Vec<ApiTokenV1ListEntry>>, AppError> {
+    Ok(Json(list_api_tokens()))
+}
+
+/// Define a new API token, or replace an existing token with the
+/// same name.  The token is held in memory and will not persist
+/// across a restart; define it in the http listener configuration
+/// if it needs to be permanent.
+#[utoipa::path(
+    post,
+    tag="tokens",
+    path="/api/admin/tokens/v1",
+    responses(
+        (status = 200, description = "Token defined", body=ApiTokenV1CreateResponse),
+    ),
+)]
+pub async fn create(
+    _: AdminRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<ApiTokenV1CreateRequest>,
+) -> Result<Json<ApiTokenV1CreateResponse>, AppError> {
+    let rate_limit = request
+        .rate_limit
+        .as_deref()
+        .map(ThrottleSpec::try_from)
+        .transpose()
+        .map_err(|err| StatusCodeError::new(StatusCode::BAD_REQUEST, err))?;
+
+    let token = request.token.unwrap_or_else(|| {
+        format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        )
+    });
+
+    define_api_token(&request.name, &token, request.scopes, rate_limit)
+        .map_err(|err| StatusCodeError::new(StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+
+    Ok(Json(ApiTokenV1CreateResponse {
+        name: request.name,
+        token,
+    }))
+}
+
+/// Revoke an API token
+#[utoipa::path(
+    delete,
+    tag="tokens",
+    path="/api/admin/tokens/v1",
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 404, description = "No such token"),
+    ),
+)]
+pub async fn delete(
+    _: AdminRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<ApiTokenV1DeleteRequest>,
+) -> Result<(), AppError> {
+    if remove_api_token(&request.name) {
+        Ok(())
+    } else {
+        Err(StatusCodeError::new(
+            StatusCode::NOT_FOUND,
+            format!("no token named {}", request.name),
+        )
+        .into())
+    }
+}
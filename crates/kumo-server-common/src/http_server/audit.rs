@@ -0,0 +1,346 @@
+use crate::http_server::auth::{AdminRequired, AuthKind};
+use crate::http_server::AppError;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Json, Query, Request};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use kumo_api_types::{AuditLogV1Entry, AuditLogV1Request};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+/// The largest request body that will be recorded in the audit log.
+/// Administrative requests are small, so anything larger than this
+/// is rejected rather than recorded.
+const MAX_AUDITED_BODY: usize = 1024 * 1024;
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// Fields whose values are replaced when recording the parameters
+const REDACTED_FIELDS: &[&str] = &["token", "password", "secret"];
+
+static AUDIT_LOG: LazyLock<Mutex<AuditLog>> = LazyLock::new(|| {
+    Mutex::new(AuditLog {
+        params: AuditLogParams::default(),
+        file: None,
+        recent: VecDeque::new(),
+        client: None,
+    })
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogParams {
+    /// Append audit records to this file, one JSON object per line
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+
+    /// POST each audit record as JSON to this URL
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+
+    /// Additional headers to send with the webhook requests
+    #[serde(default)]
+    pub webhook_headers: HashMap<String, String>,
+
+    /// How long to wait for the webhook to respond
+    #[serde(
+        default = "AuditLogParams::default_webhook_timeout",
+        with = "duration_serde"
+    )]
+    pub webhook_timeout: Duration,
+
+    /// How many of the most recent records to retain in memory
+    /// for the audit API when no path is configured
+    #[serde(default = "AuditLogParams::default_max_recent")]
+    pub max_recent: usize,
+}
+
+impl Default for AuditLogParams {
+    fn default() -> Self {
+        Self {
+            path: None,
+            webhook_url: None,
+            webhook_headers: HashMap::new(),
+            webhook_timeout: Self::default_webhook_timeout(),
+            max_recent: Self::default_max_recent(),
+        }
+    }
+}
+
+impl AuditLogParams {
+    fn default_webhook_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_max_recent() -> usize {
+        1000
+    }
+}
+
+struct AuditLog {
+    params: AuditLogParams,
+    file: Option<std::fs::File>,
+    recent: VecDeque<AuditLogV1Entry>,
+    client: Option<reqwest::Client>,
+}
+
+/// Configures where audit records are written.
+/// Audit records are always retained in memory, up to the
+/// configured limit, even if this is never called.
+pub fn configure_audit_log(params: AuditLogParams) -> anyhow::Result<()> {
+    let file = match &params.path {
+        Some(path) => Some(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|err| anyhow::anyhow!("opening audit log {path:?}: {err:#}"))?,
+        ),
+        None => None,
+    };
+    let client = match &params.webhook_url {
+        Some(_) => Some(
+            reqwest::Client::builder()
+                .timeout(params.webhook_timeout)
+                .build()?,
+        ),
+        None => None,
+    };
+
+    let mut log = AUDIT_LOG.lock().unwrap();
+    log.params = params;
+    log.file = file;
+    log.client = client;
+    Ok(())
+}
+
+fn record(entry: AuditLogV1Entry) {
+    let mut log = AUDIT_LOG.lock().unwrap();
+
+    if let Some(file) = &mut log.file {
+        let result = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+                file.sync_data()?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            tracing::error!("failed to write audit record {entry:?}: {err:#}");
+        }
+    }
+
+    if let (Some(client), Some(url)) = (&log.client, &log.params.webhook_url) {
+        let request = log
+            .params
+            .webhook_headers
+            .iter()
+            .fold(client.post(url), |request, (k, v)| request.header(k, v))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&entry).unwrap_or_default());
+        tokio::spawn(async move {
+            let result = async {
+                let response = request.send().await?;
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response.text().await.unwrap_or_default();
+                    anyhow::bail!("{status}: {body}");
+                }
+                Ok(())
+            };
+            if let Err(err) = result.await {
+                tracing::error!("failed to send audit record to webhook: {err:#}");
+            }
+        });
+    }
+
+    let max_recent = log.params.max_recent;
+    log.recent.push_back(entry);
+    while log.recent.len() > max_recent {
+        log.recent.pop_front();
+    }
+}
+
+/// Replaces the values of fields that hold secrets
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::String("REDACTED".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                redact(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_audited(method: &Method, path: &str) -> bool {
+    let mutating = *method != Method::GET && *method != Method::HEAD && *method != Method::OPTIONS;
+    mutating && path.starts_with("/api/admin/")
+}
+
+/// Records each mutating request made to the admin API.
+/// This must run after auth_middleware so that the identity
+/// of the actor is known.
+pub async fn audit_middleware(request: Request, next: Next) -> Response {
+    if !is_audited(request.method(), request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let (actor, auth_method) = match request.extensions().get::<AuthKind>() {
+        Some(kind) => (kind.summarize(), kind.method().to_string()),
+        None => ("unknown".to_string(), "none".to_string()),
+    };
+    let peer_address = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ci| ci.0.ip().to_string());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().map(|q| q.to_string());
+
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_AUDITED_BODY).await {
+        Ok(body) => body,
+        Err(err) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("failed to read request body: {err:#}"),
+            )
+                .into_response();
+        }
+    };
+    let mut params = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()))
+    };
+    redact(&mut params);
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    record(AuditLogV1Entry {
+        timestamp: Utc::now(),
+        actor,
+        auth_method,
+        peer_address,
+        method,
+        path,
+        query,
+        params,
+        status: response.status().as_u16(),
+    });
+
+    response
+}
+
+fn query_file(path: PathBuf, request: AuditLogV1Request) -> anyhow::Result<Vec<AuditLogV1Entry>> {
+    let limit = request.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    let file = std::fs::File::open(&path)?;
+    let mut entries = VecDeque::new();
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str::<AuditLogV1Entry>(&line) {
+            Ok(entry) => {
+                if request.matches(&entry) {
+                    entries.push_back(entry);
+                    if entries.len() > limit {
+                        entries.pop_front();
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::error!("invalid audit record in {path:?}: {err:#}: {line}");
+            }
+        }
+    }
+    Ok(entries.into())
+}
+
+/// Returns the most recent administrative actions that match the
+/// query parameters, oldest first.
+#[utoipa::path(
+    get,
+    tag="audit",
+    path="/api/admin/audit/v1",
+    params(AuditLogV1Request),
+    responses(
+        (status = 200, description = "Obtained audit records", body=[AuditLogV1Entry]),
+    ),
+)]
+pub async fn audit_v1(
+    _: AdminRequired,
+    Query(request): Query<AuditLogV1Request>,
+) -> Result<Json<Vec<AuditLogV1Entry>>, AppError> {
+    let path = AUDIT_LOG.lock().unwrap().params.path.clone();
+    match path {
+        Some(path) => Ok(Json(
+            tokio::task::spawn_blocking(move || query_file(path, request)).await??,
+        )),
+        None => {
+            let limit = request.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+            let log = AUDIT_LOG.lock().unwrap();
+            let mut entries: Vec<_> = log
+                .recent
+                .iter()
+                .rev()
+                .filter(|entry| request.matches(entry))
+                .take(limit)
+                .cloned()
+                .collect();
+            entries.reverse();
+            Ok(Json(entries))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redaction() {
+        let mut params = serde_json::json!({
+            "name": "grafana",
+            "token": "super-secret",
+            "nested": [{"password": "hunter2"}],
+            "secret": null,
+        });
+        redact(&mut params);
+        assert_eq!(
+            params,
+            serde_json::json!({
+                "name": "grafana",
+                "token": "REDACTED",
+                "nested": [{"password": "REDACTED"}],
+                "secret": null,
+            })
+        );
+    }
+
+    #[test]
+    fn audited() {
+        assert!(is_audited(&Method::POST, "/api/admin/suspend/v1"));
+        assert!(is_audited(&Method::DELETE, "/api/admin/tokens/v1"));
+        assert!(!is_audited(&Method::GET, "/api/admin/suspend/v1"));
+        assert!(!is_audited(&Method::POST, "/api/inject/v1"));
+    }
+}
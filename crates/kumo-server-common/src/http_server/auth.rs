@@ -1,3 +1,4 @@
+use crate::http_server::client_cert::TlsPeerIdentity;
 use crate::http_server::AppState;
 use axum::async_trait;
 use axum::extract::{FromRequestParts, Request, State};
@@ -5,14 +6,178 @@ use axum::http::StatusCode;
 use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use config::{load_config, CallbackSignature};
+use data_loader::KeySource;
+use kumo_api_types::{ApiTokenScope, ApiTokenV1ListEntry};
 use lruttl::LruCacheWithTtl;
+use openssl::sha::sha256;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{LazyLock, Mutex};
 use std::time::{Duration, Instant};
+use throttle::ThrottleSpec;
 
 static AUTH_CACHE: LazyLock<Mutex<LruCacheWithTtl<AuthKind, Result<bool, String>>>> =
     LazyLock::new(|| Mutex::new(LruCacheWithTtl::new_named("http_server_auth", 128)));
 
+/// The defined API tokens
+static API_TOKENS: LazyLock<Mutex<ApiTokens>> = LazyLock::new(Default::default);
+
+/// Defines an API token as part of the http listener configuration
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiTokenParams {
+    pub name: String,
+    pub token: KeySource,
+    pub scopes: Vec<ApiTokenScope>,
+    #[serde(default)]
+    pub rate_limit: Option<ThrottleSpec>,
+}
+
+impl ApiTokenParams {
+    pub async fn define(&self) -> anyhow::Result<()> {
+        let token = String::from_utf8(self.token.get().await?)
+            .map_err(|_| anyhow::anyhow!("token {} is not UTF-8", self.name))?;
+        define_api_token(
+            &self.name,
+            token.trim(),
+            self.scopes.clone(),
+            self.rate_limit,
+        )
+    }
+}
+
+/// The SHA-256 digest of an API token. Only the digest of each
+/// token is retained, so that the token values are not held in memory
+type TokenHash = [u8; 32];
+
+#[derive(Default)]
+struct ApiTokens {
+    /// Keyed by token name
+    by_name: HashMap<String, ApiToken>,
+    /// Maps the hash of each token to its name
+    by_hash: HashMap<TokenHash, String>,
+}
+
+impl ApiTokens {
+    fn get(&self, name: &str) -> Option<&ApiToken> {
+        self.by_name.get(name)
+    }
+
+    fn remove(&mut self, name: &str) -> Option<ApiToken> {
+        let token = self.by_name.remove(name)?;
+        self.by_hash.remove(&token.hash);
+        Some(token)
+    }
+}
+
+struct ApiToken {
+    hash: TokenHash,
+    scopes: Vec<ApiTokenScope>,
+    rate_limit: Option<ThrottleSpec>,
+}
+
+impl ApiToken {
+    fn has_scope(&self, scope: ApiTokenScope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&ApiTokenScope::Admin)
+    }
+}
+
+/// Defines or replaces the API token with the specified name
+pub fn define_api_token(
+    name: &str,
+    token: &str,
+    scopes: Vec<ApiTokenScope>,
+    rate_limit: Option<ThrottleSpec>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(!name.is_empty(), "token name must not be empty");
+    anyhow::ensure!(
+        token.len() >= 16,
+        "token {name} must be at least 16 characters long"
+    );
+    let hash = sha256(token.as_bytes());
+    let mut tokens = API_TOKENS.lock().unwrap();
+    if let Some(other) = tokens.by_hash.get(&hash) {
+        if other != name {
+            anyhow::bail!("token {name} has the same value as token {other}");
+        }
+    }
+    tokens.remove(name);
+    tokens.by_hash.insert(hash, name.to_string());
+    tokens.by_name.insert(
+        name.to_string(),
+        ApiToken {
+            hash,
+            scopes,
+            rate_limit,
+        },
+    );
+    Ok(())
+}
+
+/// Revokes the API token with the specified name, returning true
+/// if it was defined
+pub fn remove_api_token(name: &str) -> bool {
+    API_TOKENS.lock().unwrap().remove(name).is_some()
+}
+
+pub fn list_api_tokens() -> Vec<ApiTokenV1ListEntry> {
+    let mut entries: Vec<_> = API_TOKENS
+        .lock()
+        .unwrap()
+        .by_name
+        .iter()
+        .map(|(name, token)| ApiTokenV1ListEntry {
+            name: name.to_string(),
+            scopes: token.scopes.clone(),
+            rate_limit: token.rate_limit.and_then(|spec| spec.as_string().ok()),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+/// Returns the name of the API token with the specified value.
+/// The token is looked up by its SHA-256 hash, so the time taken by
+/// the lookup depends only on the hash, which reveals nothing about
+/// how closely the presented value matches a valid token.
+fn lookup_api_token(token: &str) -> Option<String> {
+    let hash = sha256(token.as_bytes());
+    API_TOKENS.lock().unwrap().by_hash.get(&hash).cloned()
+}
+
+/// Applies the rate limit of the named API token, if any,
+/// producing the response to return when the request is
+/// not permitted to proceed
+async fn check_api_token_rate_limit(name: &str) -> Result<(), Response> {
+    let Some(spec) = API_TOKENS
+        .lock()
+        .unwrap()
+        .get(name)
+        .and_then(|token| token.rate_limit)
+    else {
+        return Ok(());
+    };
+    match spec.throttle(format!("kumo-http-api-token-{name}")).await {
+        Ok(result) => match result.retry_after {
+            Some(delay) if result.throttled => Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(
+                    axum::http::header::RETRY_AFTER,
+                    delay.as_secs().max(1).to_string(),
+                )],
+                "Rate limit exceeded",
+            )
+                .into_response()),
+            _ => Ok(()),
+        },
+        Err(err) => {
+            tracing::error!("Error checking rate limit for API token {name}: {err:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "try again later").into_response())
+        }
+    }
+}
+
 /// Represents some authenticated identity.
 /// Use this as an extractor parameter when you need to reference
 /// that identity in the handler.
@@ -26,6 +191,16 @@ pub enum AuthKind {
     Bearer {
         token: String,
     },
+    /// A bearer token that matched one of the defined API tokens
+    ApiToken {
+        name: String,
+    },
+    /// A verified TLS client certificate, and the roles that
+    /// its identity was mapped to via tls_client_roles
+    ClientCertificate {
+        identity: String,
+        scopes: Vec<ApiTokenScope>,
+    },
 }
 
 impl AuthKind {
@@ -52,7 +227,8 @@ impl AuthKind {
     async fn validate_impl(&self) -> anyhow::Result<bool> {
         let mut config = load_config().await?;
         match self {
-            Self::TrustedIp(_) => Ok(true),
+            Self::TrustedIp(_) | Self::ClientCertificate { .. } => Ok(true),
+            Self::ApiToken { name } => Ok(API_TOKENS.lock().unwrap().get(name).is_some()),
             Self::Basic { user, password } => {
                 let sig = CallbackSignature::<(String, Option<String>), bool>::new(
                     "http_server_validate_auth_basic",
@@ -74,6 +250,10 @@ impl AuthKind {
     }
 
     pub async fn validate(&self) -> anyhow::Result<bool> {
+        if let Self::ApiToken { .. } = self {
+            // Not cached, so that revocation takes effect immediately
+            return self.validate_impl().await;
+        }
         match self.lookup_cache() {
             Some(res) => res.map_err(|err| anyhow::anyhow!("{err}")),
             None => {
@@ -95,13 +275,50 @@ impl AuthKind {
             Self::TrustedIp(addr) => addr.to_string(),
             Self::Basic { user, .. } => user.to_string(),
             Self::Bearer { .. } => "Bearer".to_string(),
+            Self::ApiToken { name } => format!("token:{name}"),
+            Self::ClientCertificate { identity, .. } => identity.to_string(),
+        }
+    }
+
+    /// Returns the name of the method by which this identity
+    /// was authenticated
+    pub fn method(&self) -> &'static str {
+        match self {
+            Self::TrustedIp(_) => "trusted_ip",
+            Self::Basic { .. } => "basic",
+            Self::Bearer { .. } => "bearer",
+            Self::ApiToken { .. } => "api_token",
+            Self::ClientCertificate { .. } => "client_certificate",
+        }
+    }
+
+    /// Returns true if this identity is permitted to perform
+    /// operations that require the specified scope
+    pub fn has_scope(&self, scope: ApiTokenScope) -> bool {
+        match self {
+            Self::TrustedIp(_) => true,
+            // Credentials accepted by the http_server_validate_auth_basic
+            // and http_server_validate_auth_bearer events are only
+            // permitted to inject
+            Self::Basic { .. } | Self::Bearer { .. } => scope == ApiTokenScope::Inject,
+            Self::ApiToken { name } => API_TOKENS
+                .lock()
+                .unwrap()
+                .get(name)
+                .is_some_and(|token| token.has_scope(scope)),
+            Self::ClientCertificate { scopes, .. } => {
+                scopes.contains(&scope) || scopes.contains(&ApiTokenScope::Admin)
+            }
         }
     }
 }
 
 fn is_auth_exempt(uri: &axum::http::Uri) -> bool {
     match uri.path() {
-        "/api/check-liveness/v1" => true,
+        "/api/check-liveness/v1" | "/healthz" | "/readyz" => true,
+        // Requested by mailbox providers on behalf of recipients;
+        // the request is authenticated by its encrypted token
+        "/api/unsubscribe/v1" => true,
         _ => false,
     }
 }
@@ -118,16 +335,32 @@ pub async fn auth_middleware(
     // Get authorization header
     match request.headers().get(axum::http::header::AUTHORIZATION) {
         None => {
-            if let Some(remote_addr) = request
+            let client_cert = request
+                .extensions()
+                .get::<TlsPeerIdentity>()
+                .and_then(|peer| state.resolve_client_certificate(peer));
+            let remote_ip = request
                 .extensions()
                 .get::<axum::extract::ConnectInfo<SocketAddr>>()
-                .map(|ci| ci.0)
-            {
-                let ip = remote_addr.ip();
-                if state.is_trusted_host(ip) {
+                .map(|ci| ci.0.ip());
+            let is_trusted = remote_ip.is_some_and(|ip| state.is_trusted_host(ip));
+
+            match (client_cert, remote_ip) {
+                // A certificate that is mapped to roles takes precedence
+                // over the trusted hosts, as does an unmapped certificate
+                // presented from an untrusted host, so that its identity
+                // is still available to the handler
+                (Some((identity, scopes)), _) if !scopes.is_empty() || !is_trusted => {
+                    request
+                        .extensions_mut()
+                        .insert(AuthKind::ClientCertificate { identity, scopes });
+                    return next.run(request).await;
+                }
+                (_, Some(ip)) if is_trusted => {
                     request.extensions_mut().insert(AuthKind::TrustedIp(ip));
                     return next.run(request).await;
                 }
+                _ => {}
             }
 
             (StatusCode::UNAUTHORIZED, "Missing Authorization header").into_response()
@@ -140,20 +373,31 @@ pub async fn auth_middleware(
                     "Malformed or unsupported Authorization header",
                 )
                     .into_response(),
-                Some(kind) => match kind.validate().await {
-                    Ok(true) => {
-                        // Store the authentication inform for later retrieval
-                        request.extensions_mut().insert(kind);
-                        next.run(request).await
-                    }
-                    Ok(false) => {
-                        (StatusCode::UNAUTHORIZED, "Invalid Authorization").into_response()
+                Some(kind) => {
+                    if let AuthKind::Bearer { token } = &kind {
+                        if let Some(name) = lookup_api_token(token) {
+                            if let Err(response) = check_api_token_rate_limit(&name).await {
+                                return response;
+                            }
+                            request.extensions_mut().insert(AuthKind::ApiToken { name });
+                            return next.run(request).await;
+                        }
                     }
-                    Err(err) => {
-                        tracing::error!("Error validating {kind:?}: {err:#}");
-                        (StatusCode::INTERNAL_SERVER_ERROR, "try again later").into_response()
+                    match kind.validate().await {
+                        Ok(true) => {
+                            // Store the authentication inform for later retrieval
+                            request.extensions_mut().insert(kind);
+                            next.run(request).await
+                        }
+                        Ok(false) => {
+                            (StatusCode::UNAUTHORIZED, "Invalid Authorization").into_response()
+                        }
+                        Err(err) => {
+                            tracing::error!("Error validating {kind:?}: {err:#}");
+                            (StatusCode::INTERNAL_SERVER_ERROR, "try again later").into_response()
+                        }
                     }
-                },
+                }
             },
         },
     }
@@ -205,3 +449,194 @@ where
         }
     }
 }
+
+macro_rules! scope_required {
+    ($(#[$doc:meta])* $name:ident, $scope:expr) => {
+        $(#[$doc])*
+        pub struct $name;
+
+        #[async_trait]
+        impl<B> FromRequestParts<B> for $name
+        where
+            B: Send + Sync,
+        {
+            type Rejection = (StatusCode, &'static str);
+
+            async fn from_request_parts(
+                parts: &mut axum::http::request::Parts,
+                _: &B,
+            ) -> Result<Self, Self::Rejection> {
+                let kind = parts
+                    .extensions
+                    .get::<AuthKind>()
+                    .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized"))?;
+
+                if kind.has_scope($scope) {
+                    Ok($name)
+                } else {
+                    Err((StatusCode::FORBIDDEN, "Insufficient scope"))
+                }
+            }
+        }
+    };
+}
+
+scope_required!(
+    /// Use this type as an extractor parameter when the handler must
+    /// only be accessible to identities with the `metrics_read` scope
+    MetricsReadRequired,
+    ApiTokenScope::MetricsRead
+);
+scope_required!(
+    /// Use this type as an extractor parameter when the handler must
+    /// only be accessible to identities with the `queue_admin` scope
+    QueueAdminRequired,
+    ApiTokenScope::QueueAdmin
+);
+scope_required!(
+    /// Use this type as an extractor parameter when the handler must
+    /// only be accessible to identities with the `inject` scope
+    InjectRequired,
+    ApiTokenScope::Inject
+);
+scope_required!(
+    /// Use this type as an extractor parameter when the handler must
+    /// only be accessible to identities with the `bounce_admin` scope
+    BounceAdminRequired,
+    ApiTokenScope::BounceAdmin
+);
+scope_required!(
+    /// Use this type as an extractor parameter when the handler must
+    /// only be accessible to identities with the `admin` scope
+    AdminRequired,
+    ApiTokenScope::Admin
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn api_token_scopes() {
+        define_api_token(
+            "test-metrics",
+            "0123456789abcdef-metrics",
+            vec![ApiTokenScope::MetricsRead],
+            None,
+        )
+        .unwrap();
+        define_api_token(
+            "test-admin",
+            "0123456789abcdef-admin",
+            vec![ApiTokenScope::Admin],
+            None,
+        )
+        .unwrap();
+
+        // Too short
+        assert!(define_api_token("test-short", "short", vec![], None).is_err());
+        // Duplicate value
+        assert!(define_api_token("test-dup", "0123456789abcdef-admin", vec![], None).is_err());
+
+        assert_eq!(
+            lookup_api_token("0123456789abcdef-metrics").as_deref(),
+            Some("test-metrics")
+        );
+        assert_eq!(lookup_api_token("0123456789abcdef-nope"), None);
+
+        // Replacing the value of a token revokes its prior value
+        define_api_token(
+            "test-rotated",
+            "0123456789abcdef-old",
+            vec![ApiTokenScope::Inject],
+            None,
+        )
+        .unwrap();
+        define_api_token(
+            "test-rotated",
+            "0123456789abcdef-new",
+            vec![ApiTokenScope::Inject],
+            None,
+        )
+        .unwrap();
+        assert_eq!(lookup_api_token("0123456789abcdef-old"), None);
+        assert_eq!(
+            lookup_api_token("0123456789abcdef-new").as_deref(),
+            Some("test-rotated")
+        );
+
+        let metrics = AuthKind::ApiToken {
+            name: "test-metrics".to_string(),
+        };
+        assert!(metrics.has_scope(ApiTokenScope::MetricsRead));
+        assert!(!metrics.has_scope(ApiTokenScope::QueueAdmin));
+
+        let admin = AuthKind::ApiToken {
+            name: "test-admin".to_string(),
+        };
+        assert!(admin.has_scope(ApiTokenScope::BounceAdmin));
+
+        let lua_auth = AuthKind::Bearer {
+            token: "whatever".to_string(),
+        };
+        assert!(lua_auth.has_scope(ApiTokenScope::Inject));
+        assert!(!lua_auth.has_scope(ApiTokenScope::MetricsRead));
+
+        let cert = AuthKind::ClientCertificate {
+            identity: "ops.example.com".to_string(),
+            scopes: vec![ApiTokenScope::QueueAdmin],
+        };
+        assert!(cert.has_scope(ApiTokenScope::QueueAdmin));
+        assert!(!cert.has_scope(ApiTokenScope::Inject));
+
+        assert!(remove_api_token("test-admin"));
+        assert!(!admin.has_scope(ApiTokenScope::BounceAdmin));
+        assert!(!remove_api_token("test-admin"));
+    }
+
+    #[test]
+    fn client_certificate_roles() {
+        let key = rcgen::generate_simple_self_signed(vec![
+            "ops.example.com".to_string(),
+            "other.example.com".to_string(),
+        ])
+        .unwrap();
+        let peer = TlsPeerIdentity::from_der(key.cert.der()).unwrap();
+        assert_eq!(
+            peer.subject_alt_names,
+            vec!["ops.example.com", "other.example.com"]
+        );
+
+        let state = AppState {
+            trusted_hosts: Default::default(),
+            tls_client_roles: std::sync::Arc::new(
+                [(
+                    "other.example.com".to_string(),
+                    vec![ApiTokenScope::BounceAdmin],
+                )]
+                .into_iter()
+                .collect(),
+            ),
+        };
+        assert_eq!(
+            state.resolve_client_certificate(&peer),
+            Some((
+                "other.example.com".to_string(),
+                vec![ApiTokenScope::BounceAdmin]
+            ))
+        );
+
+        let unmapped = TlsPeerIdentity {
+            common_name: Some("someone".to_string()),
+            subject_alt_names: vec![],
+        };
+        assert_eq!(
+            state.resolve_client_certificate(&unmapped),
+            Some(("someone".to_string(), vec![]))
+        );
+        assert_eq!(
+            state.resolve_client_certificate(&TlsPeerIdentity::default()),
+            None
+        );
+    }
+}
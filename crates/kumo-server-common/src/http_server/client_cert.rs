@@ -0,0 +1,121 @@
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::RustlsAcceptor;
+use openssl::nid::Nid;
+use openssl::x509::X509;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_layer::Layer;
+
+/// The identities found in the certificate presented by a TLS client.
+/// This is added to the extensions of every request made via a TLS
+/// listener, and is empty if the client did not present a certificate.
+#[derive(Clone, Debug, Default)]
+pub struct TlsPeerIdentity {
+    /// The subject common name
+    pub common_name: Option<String>,
+    /// The DNS name, email and URI subject alternative names
+    pub subject_alt_names: Vec<String>,
+}
+
+impl TlsPeerIdentity {
+    pub fn from_der(der: &[u8]) -> anyhow::Result<Self> {
+        let cert = X509::from_der(der)?;
+        let mut subject_alt_names = vec![];
+
+        if let Some(names) = cert.subject_alt_names() {
+            for name in names.iter() {
+                if let Some(dns) = name.dnsname() {
+                    subject_alt_names.push(dns.to_string());
+                } else if let Some(email) = name.email() {
+                    subject_alt_names.push(email.to_string());
+                } else if let Some(uri) = name.uri() {
+                    subject_alt_names.push(uri.to_string());
+                }
+            }
+        }
+
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .find_map(|entry| entry.data().as_utf8().ok())
+            .map(|cn| cn.to_string());
+
+        Ok(Self {
+            common_name,
+            subject_alt_names,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.common_name.is_none() && self.subject_alt_names.is_empty()
+    }
+
+    /// Returns the subject alternative names, followed by the common name
+    pub fn identities(&self) -> impl Iterator<Item = &str> {
+        self.subject_alt_names
+            .iter()
+            .chain(self.common_name.iter())
+            .map(|s| s.as_str())
+    }
+
+    /// Returns the identity to use when none of the identities are
+    /// explicitly mapped to roles; the common name if present,
+    /// otherwise the first subject alternative name
+    pub fn primary(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or_else(|| self.subject_alt_names.first().map(|s| s.as_str()))
+    }
+}
+
+/// Wraps the rustls acceptor so that the identity of the client
+/// certificate is made available to the request handlers
+#[derive(Clone)]
+pub struct PeerIdentityAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl PeerIdentityAcceptor {
+    pub fn new(inner: RustlsAcceptor) -> Self {
+        Self { inner }
+    }
+}
+
+impl<I, S> Accept<I, S> for PeerIdentityAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, TlsPeerIdentity>;
+    type Future =
+        Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send + 'static>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity = match stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+            {
+                Some(cert) => TlsPeerIdentity::from_der(cert.as_ref()).unwrap_or_else(|err| {
+                    tracing::error!("failed to parse client certificate: {err:#}");
+                    TlsPeerIdentity::default()
+                }),
+                None => TlsPeerIdentity::default(),
+            };
+            let service = Extension(identity).layer(service);
+
+            Ok((stream, service))
+        })
+    }
+}
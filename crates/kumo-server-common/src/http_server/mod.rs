@@ -1,45 +1,73 @@
-use crate::diagnostic_logging::set_diagnostic_log_filter;
+use crate::diagnostic_logging::{set_diagnostic_log_filter, set_scoped_diagnostic_log_filter};
 use anyhow::Context;
 use axum::extract::{DefaultBodyLimit, Json, Query};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Router;
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use axum_streams::{HttpHeaderValue, StreamBodyAsOptions};
 use cidr_map::CidrSet;
 use data_loader::KeySource;
+use kumo_server_lifecycle::ShutdownSubcription;
 use kumo_server_memory::{get_usage_and_limit, tracking_stats, JemallocStats};
 use kumo_server_runtime::spawn;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::sync::Arc;
+use std::time::Duration;
 use tower_http::compression::CompressionLayer;
 use tower_http::trace::TraceLayer;
 use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
-use utoipa::OpenApi;
+use utoipa::{IntoParams, OpenApi};
 use utoipa_rapidoc::RapiDoc;
+use utoipa_swagger_ui::SwaggerUi;
 // Avoid referencing api types as crate::name in the utoipa macros,
 // otherwise it generates namespaced names in the openapi.json, which
 // in turn require annotating each and every struct with the namespace
 // in order for the document to be valid.
 use kumo_api_types::*;
 
+pub mod admin_tokens_v1;
+pub mod audit;
 pub mod auth;
+pub mod client_cert;
 
 use auth::*;
+use client_cert::*;
 
 #[derive(OpenApi)]
 #[openapi(
     info(license(name = "Apache-2.0")),
-    paths(set_diagnostic_log_filter_v1, bump_config_epoch),
-    // Indicate that all paths can accept http basic auth.
-    // the "basic_auth" name corresponds with the scheme
-    // defined by the OptionalAuth addon defined below
+    paths(
+        set_diagnostic_log_filter_v1,
+        bump_config_epoch,
+        memory_stats,
+        report_metrics,
+        report_metrics_json,
+        admin_tokens_v1::list,
+        admin_tokens_v1::create,
+        admin_tokens_v1::delete,
+        audit::audit_v1
+    ),
+    // Indicate that all paths can accept http basic auth or
+    // a bearer token.
+    // the "basic_auth" and "bearer_auth" names correspond with
+    // the schemes defined by the OptionalAuth addon defined below
     security(
-        ("basic_auth" = [""])
+        ("basic_auth" = [""]),
+        ("bearer_auth" = [""])
     ),
-    components(schemas(SetDiagnosticFilterRequest)),
+    components(schemas(
+        SetDiagnosticFilterRequest,
+        ApiTokenScope,
+        ApiTokenV1ListEntry,
+        ApiTokenV1CreateRequest,
+        ApiTokenV1CreateResponse,
+        ApiTokenV1DeleteRequest,
+        AuditLogV1Entry
+    )),
     modifiers(&OptionalAuth),
 )]
 struct ApiDoc;
@@ -57,6 +85,12 @@ impl utoipa::Modify for OptionalAuth {
             "basic_auth",
             SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
         );
+        // Define bearer_auth as http bearer auth, which is used
+        // with API tokens
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
     }
 }
 
@@ -80,8 +114,18 @@ pub struct HttpListenerParams {
     #[serde(default)]
     pub tls_private_key: Option<KeySource>,
 
+    #[serde(default)]
+    pub tls_client_ca_certificate: Option<KeySource>,
+    #[serde(default)]
+    pub tls_require_client_certificate: bool,
+    #[serde(default)]
+    pub tls_client_roles: HashMap<String, Vec<ApiTokenScope>>,
+
     #[serde(default = "CidrSet::default_trusted_hosts")]
     pub trusted_hosts: CidrSet,
+
+    #[serde(default)]
+    pub api_tokens: Vec<ApiTokenParams>,
 }
 
 pub struct RouterAndDocs {
@@ -108,12 +152,29 @@ impl RouterAndDocs {
 #[derive(Clone)]
 pub struct AppState {
     trusted_hosts: Arc<CidrSet>,
+    tls_client_roles: Arc<HashMap<String, Vec<ApiTokenScope>>>,
 }
 
 impl AppState {
     pub fn is_trusted_host(&self, addr: IpAddr) -> bool {
         self.trusted_hosts.contains(addr)
     }
+
+    /// Resolves the identity and roles of a client certificate.
+    /// The first of its identities that is listed in tls_client_roles
+    /// is used, otherwise its primary identity is used with no roles.
+    pub fn resolve_client_certificate(
+        &self,
+        peer: &TlsPeerIdentity,
+    ) -> Option<(String, Vec<ApiTokenScope>)> {
+        for identity in peer.identities() {
+            if let Some(scopes) = self.tls_client_roles.get(identity) {
+                return Some((identity.to_string(), scopes.clone()));
+            }
+        }
+        peer.primary()
+            .map(|identity| (identity.to_string(), vec![]))
+    }
 }
 
 impl HttpListenerParams {
@@ -145,6 +206,13 @@ impl HttpListenerParams {
     ) -> anyhow::Result<()> {
         let api_docs = router_and_docs.make_docs();
 
+        for token in &self.api_tokens {
+            token
+                .define()
+                .await
+                .with_context(|| format!("defining api token {}", token.name))?;
+        }
+
         let compression_layer: CompressionLayer = CompressionLayer::new()
             .deflate(true)
             .gzip(true)
@@ -155,6 +223,7 @@ impl HttpListenerParams {
             .layer(DefaultBodyLimit::max(
                 self.request_body_limit.unwrap_or(2 * 1024 * 1024),
             ))
+            .merge(SwaggerUi::new("/api/docs").url("/api/docs/openapi.json", api_docs.clone()))
             .merge(RapiDoc::with_openapi("/api-docs/openapi.json", api_docs).path("/rapidoc"))
             .route(
                 "/api/admin/set_diagnostic_log_filter/v1",
@@ -162,21 +231,45 @@ impl HttpListenerParams {
             )
             .route("/api/admin/bump-config-epoch", post(bump_config_epoch))
             .route("/api/admin/memory/stats", get(memory_stats))
+            .route(
+                "/api/admin/tokens/v1",
+                get(admin_tokens_v1::list)
+                    .post(admin_tokens_v1::create)
+                    .delete(admin_tokens_v1::delete),
+            )
+            .route("/api/admin/audit/v1", get(audit::audit_v1))
             .route("/metrics", get(report_metrics))
             .route("/metrics.json", get(report_metrics_json))
+            // Record mutating admin requests in the audit log.
+            // This is layered inside the auth middleware below
+            // so that the identity of the caller is known
+            .route_layer(axum::middleware::from_fn(audit::audit_middleware))
             // Require that all requests be authenticated as either coming
-            // from a trusted IP address, or with an authorization header
+            // from a trusted IP address, or with an authorization header.
+            // The individual handlers then check that the identity has
+            // the necessary scope
             .route_layer(axum::middleware::from_fn_with_state(
                 AppState {
                     trusted_hosts: Arc::new(self.trusted_hosts.clone()),
+                    tls_client_roles: Arc::new(self.tls_client_roles.clone()),
                 },
                 auth_middleware,
             ))
             .layer(compression_layer)
             .layer(TraceLayer::new_for_http());
-        let socket = TcpListener::bind(&self.listen)
-            .with_context(|| format!("listen on {}", self.listen))?;
+        let socket = match crate::listeners::take_inherited_listener(&self.listen)? {
+            Some(socket) => socket,
+            None => TcpListener::bind(&self.listen)
+                .with_context(|| format!("listen on {}", self.listen))?,
+        };
         let addr = socket.local_addr()?;
+        crate::listeners::register_active_listener(&socket)?;
+
+        let handle = axum_server::Handle::new();
+        spawn(
+            format!("http {addr:?} handoff"),
+            stop_after_handoff(handle.clone()),
+        )?;
 
         let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
 
@@ -187,7 +280,9 @@ impl HttpListenerParams {
         if self.use_tls {
             let config = self.tls_config().await?;
             tracing::info!("https listener on {addr:?}");
-            let server = axum_server::from_tcp_rustls(socket, config);
+            let server = axum_server::from_tcp(socket)
+                .handle(handle)
+                .acceptor(PeerIdentityAcceptor::new(RustlsAcceptor::new(config)));
             let serve = async move { server.serve(make_service).await };
 
             if let Some(runtime) = runtime {
@@ -197,7 +292,7 @@ impl HttpListenerParams {
             }
         } else {
             tracing::info!("http listener on {addr:?}");
-            let server = axum_server::from_tcp(socket);
+            let server = axum_server::from_tcp(socket).handle(handle);
             let serve = async move { server.serve(make_service).await };
             if let Some(runtime) = runtime {
                 runtime.spawn(serve);
@@ -209,27 +304,80 @@ impl HttpListenerParams {
     }
 
     async fn tls_config(&self) -> anyhow::Result<RustlsConfig> {
-        let config = crate::tls_helpers::make_server_config(
+        let config = crate::tls_helpers::make_server_config_with_client_auth(
             &self.hostname,
             &self.tls_private_key,
             &self.tls_certificate,
+            &self.tls_client_ca_certificate,
+            self.tls_require_client_certificate,
         )
         .await?;
         Ok(RustlsConfig::from_config(config))
     }
 }
 
+/// Once our listeners have been handed to a new instance, stops
+/// accepting connections, leaving them for the new instance to accept
+async fn stop_after_handoff(handle: axum_server::Handle) {
+    ShutdownSubcription::get().shutting_down().await;
+    if crate::handoff::handed_off() {
+        handle.graceful_shutdown(None);
+    }
+}
+
 #[derive(Debug)]
 pub struct AppError(pub anyhow::Error);
 
+/// An error that should be reported to the client using a specific
+/// HTTP status code, rather than the default internal server error.
+/// Can be returned via `AppError` by converting it into an `anyhow::Error`.
+#[derive(Debug)]
+pub struct StatusCodeError {
+    pub status: StatusCode,
+    pub message: String,
+    /// If set, the client is advised via the Retry-After header
+    /// to wait this long before trying again
+    pub retry_after: Option<Duration>,
+}
+
+impl StatusCodeError {
+    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after.replace(retry_after);
+        self
+    }
+}
+
+impl std::fmt::Display for StatusCodeError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusCodeError {}
+
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error: {:#}", self.0),
-        )
-            .into_response()
+        let (status, retry_after) = match self.0.downcast_ref::<StatusCodeError>() {
+            Some(err) => (err.status, err.retry_after),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        };
+        let mut response = (status, format!("Error: {:#}", self.0)).into_response();
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                retry_after.as_secs().max(1).into(),
+            );
+        }
+        response
     }
 }
 
@@ -255,7 +403,7 @@ where
         (status=200, description = "bump successful")
     ),
 )]
-async fn bump_config_epoch(_: TrustedIpRequired) -> Result<(), AppError> {
+async fn bump_config_epoch(_: AdminRequired) -> Result<(), AppError> {
     config::epoch::bump_current_epoch();
     Ok(())
 }
@@ -271,7 +419,7 @@ async fn bump_config_epoch(_: TrustedIpRequired) -> Result<(), AppError> {
         (status=200, description = "stats were returned")
     ),
 )]
-async fn memory_stats(_: TrustedIpRequired) -> String {
+async fn memory_stats(_: MetricsReadRequired) -> String {
     use kumo_server_memory::NumBytes;
     use std::fmt::Write;
     let mut result = String::new();
@@ -323,14 +471,26 @@ async fn memory_stats(_: TrustedIpRequired) -> String {
     result
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct PrometheusMetricsParams {
+    /// A prefix to add to the name of each metric, such as `kumomta_`
     #[serde(default)]
     prefix: Option<String>,
 }
 
+/// Returns the metrics in the Prometheus text exposition format.
+#[utoipa::path(
+    get,
+    tag="metrics",
+    path="/metrics",
+    params(PrometheusMetricsParams),
+    responses(
+        (status = 200, description = "metrics were returned", content_type = "text/plain")
+    ),
+)]
 async fn report_metrics(
-    _: TrustedIpRequired,
+    _: MetricsReadRequired,
     Query(params): Query<PrometheusMetricsParams>,
 ) -> impl IntoResponse {
     StreamBodyAsOptions::new()
@@ -340,7 +500,16 @@ async fn report_metrics(
         ))
 }
 
-async fn report_metrics_json(_: TrustedIpRequired) -> impl IntoResponse {
+/// Returns the metrics as a json object, keyed by metric name.
+#[utoipa::path(
+    get,
+    tag="metrics",
+    path="/metrics.json",
+    responses(
+        (status = 200, description = "metrics were returned", content_type = "application/json")
+    ),
+)]
+async fn report_metrics_json(_: MetricsReadRequired) -> impl IntoResponse {
     StreamBodyAsOptions::new()
         .content_type(HttpHeaderValue::from_static(
             "application/json; charset=utf-8",
@@ -351,19 +520,32 @@ async fn report_metrics_json(_: TrustedIpRequired) -> impl IntoResponse {
 /// Changes the diagnostic log filter dynamically.
 /// See <https://docs.kumomta.com/reference/kumo/set_diagnostic_log_filter/>
 /// for more information on diagnostic log filters.
+/// When a domain, tenant or egress pool is specified, the filter
+/// applies only to the related work, and expires after the
+/// specified duration.
 #[utoipa::path(
     post,
     tag="logging",
     path="/api/admin/set_diagnostic_log_filter/v1",
     responses(
-        (status = 200, description = "Diagnostic level set successfully")
+        (status = 200, description = "Diagnostic level set successfully"),
+        (status = 400, description = "The request is invalid")
     ),
 )]
 async fn set_diagnostic_log_filter_v1(
-    _: TrustedIpRequired,
+    _: AdminRequired,
     // Note: Json<> must be last in the param list
     Json(request): Json<SetDiagnosticFilterRequest>,
 ) -> Result<(), AppError> {
-    set_diagnostic_log_filter(&request.filter)?;
+    match request
+        .scope()
+        .map_err(|err| StatusCodeError::new(StatusCode::BAD_REQUEST, err))?
+    {
+        Some((field, value)) => {
+            set_scoped_diagnostic_log_filter(field, value, &request.filter, request.duration())
+                .map_err(|err| StatusCodeError::new(StatusCode::BAD_REQUEST, format!("{err:#}")))?
+        }
+        None => set_diagnostic_log_filter(&request.filter)?,
+    }
     Ok(())
 }
@@ -10,10 +10,15 @@ use tokio::task::LocalSet;
 pub mod config_handle;
 pub mod diagnostic_logging;
 pub mod disk_space;
+pub mod handoff;
 pub mod http_server;
+pub mod listeners;
 pub mod nodeid;
 pub mod panic;
+pub mod reputation;
+pub mod schedule;
 pub mod start;
+pub mod systemd;
 pub mod tls_helpers;
 
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
@@ -27,16 +32,22 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         mod_amqp::register,
         mod_filesystem::register,
         mod_http::register,
+        mod_jsonschema::register,
         mod_regex::register,
         mod_serde::register,
         mod_sqlite::register,
         mod_string::register,
         mod_dns_resolver::register,
         mod_kafka::register,
+        mod_ldap::register,
+        mod_memcached::register,
         mod_memoize::register,
         mod_uuid::register,
+        kumo_api_types::provider::register,
         kumo_api_types::shaping::register,
         regex_set_map::register,
+        reputation::register,
+        schedule::register,
     ] {
         func(lua)?;
     }
@@ -162,6 +173,23 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_audit_log",
+        lua.create_function(move |lua, params: Value| {
+            let params: http_server::audit::AuditLogParams = from_lua_value(lua, params)?;
+            http_server::audit::configure_audit_log(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_metrics",
+        lua.create_function(move |lua, params: Value| {
+            let policy: kumo_prometheus::policy::MetricsPolicy = from_lua_value(lua, params)?;
+            kumo_prometheus::policy::set_metrics_policy(policy);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_diagnostic_log_filter",
         lua.create_function(move |_, filter: String| {
@@ -0,0 +1,85 @@
+//! Keeps track of listening sockets that are passed between processes:
+//! those that we inherited, either from systemd or from a previous
+//! instance via crate::handoff, which listeners use in place of binding
+//! a new socket, and those that we are actively using, which are handed
+//! on to our successor.
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::{LazyLock, Mutex};
+
+/// The inherited sockets, keyed by their local address.
+/// Sockets are removed as they are claimed by listeners.
+static INHERITED: LazyLock<Mutex<HashMap<SocketAddr, TcpListener>>> =
+    LazyLock::new(|| Mutex::new(crate::systemd::listen_fds().into_iter().collect()));
+
+/// Duplicates of the sockets that our listeners are using
+static ACTIVE: Mutex<Vec<OwnedFd>> = Mutex::new(vec![]);
+
+/// Makes a listening socket that was passed to us by some other
+/// process available to take_inherited_listener
+pub(crate) fn adopt_listener(listener: TcpListener, source: &str) -> anyhow::Result<()> {
+    let addr = listener
+        .local_addr()
+        .context("socket is not a TCP listener")?;
+    tracing::info!("adopting listener on {addr:?} passed by {source}");
+    INHERITED.lock().unwrap().insert(addr, listener);
+    Ok(())
+}
+
+/// If we inherited a socket that is listening on the address
+/// specified by `listen`, returns it, so that it can be used in place
+/// of binding a new socket.
+pub fn take_inherited_listener(listen: &str) -> anyhow::Result<Option<TcpListener>> {
+    let mut inherited = INHERITED.lock().unwrap();
+    if inherited.is_empty() {
+        return Ok(None);
+    }
+
+    let addrs = listen
+        .to_socket_addrs()
+        .with_context(|| format!("resolving listen address {listen}"))?;
+    for addr in addrs {
+        if let Some(listener) = inherited.remove(&addr) {
+            listener
+                .set_nonblocking(true)
+                .with_context(|| format!("set_nonblocking on inherited listener {addr:?}"))?;
+            return Ok(Some(listener));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Logs any inherited sockets that were not claimed
+/// by any of the configured listeners
+pub fn warn_about_unclaimed_listeners() {
+    for addr in INHERITED.lock().unwrap().keys() {
+        tracing::warn!(
+            "inherited a socket listening on {addr:?}, \
+             but no listener is configured for that address"
+        );
+    }
+}
+
+/// Records a socket that a listener is accepting connections from,
+/// so that it can be handed to a successor process
+pub fn register_active_listener(listener: &impl AsFd) -> anyhow::Result<()> {
+    let fd = listener
+        .as_fd()
+        .try_clone_to_owned()
+        .context("duplicating listener fd")?;
+    ACTIVE.lock().unwrap().push(fd);
+    Ok(())
+}
+
+/// Returns duplicates of the sockets that our listeners are using
+pub(crate) fn active_listeners() -> anyhow::Result<Vec<OwnedFd>> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|fd| fd.try_clone().context("duplicating listener fd"))
+        .collect()
+}
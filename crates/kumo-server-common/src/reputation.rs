@@ -0,0 +1,653 @@
+//! This module periodically fetches operator-configured reputation
+//! feeds, which list IP addresses, networks and domains along with
+//! a score, and makes them available to policy via
+//! `kumo.reputation.lookup`.
+//!
+//! The feed content is held in memory, or in redis so that it can
+//! be shared by the instances that use it.
+use arc_swap::ArcSwap;
+use config::{any_err, from_lua_value, get_or_create_sub_module, load_config, CallbackSignature};
+use kumo_server_runtime::rt_spawn;
+use lruttl::LruCacheWithTtl;
+use mlua::{Lua, LuaSerdeExt};
+use mod_redis::{cmd, RedisConnKey, RedisConnection, RedisValue};
+use prometheus::{IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+static FEEDS: LazyLock<Mutex<BTreeMap<String, Arc<Feed>>>> = LazyLock::new(Default::default);
+
+static FETCH_FAILED_SIG: LazyLock<CallbackSignature<(String, String, u64), ()>> =
+    LazyLock::new(|| CallbackSignature::new_with_multiple("reputation_feed_fetch_failed"));
+
+static FEED_ENTRIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "reputation_feed_entries",
+        "number of entries loaded from the most recent successful fetch of a reputation feed",
+        &["feed"]
+    )
+    .unwrap()
+});
+static FEED_LAST_SUCCESS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "reputation_feed_last_success",
+        "unix timestamp of the most recent successful fetch of a reputation feed",
+        &["feed"]
+    )
+    .unwrap()
+});
+static FEED_STALE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "reputation_feed_stale",
+        "1 if the data for a reputation feed is older than its max_age, 0 otherwise",
+        &["feed"]
+    )
+    .unwrap()
+});
+static FEED_FETCH_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "reputation_feed_fetch_failures",
+        "total number of failed attempts to fetch a reputation feed",
+        &["feed"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeedFormat {
+    /// One entry per line, with comma separated columns.
+    /// Blank lines and lines starting with `#` are ignored.
+    #[default]
+    Csv,
+    /// An array of objects
+    Json,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReputationFeedParams {
+    /// The name of the feed, which is used to label its metrics
+    /// and its results from kumo.reputation.lookup
+    pub name: String,
+
+    /// The URL from which the feed is fetched
+    pub url: String,
+
+    #[serde(default)]
+    pub format: FeedFormat,
+
+    /// Additional headers to send with the request,
+    /// such as an authorization token
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// How often the feed is fetched
+    #[serde(
+        default = "ReputationFeedParams::default_refresh_interval",
+        with = "duration_serde"
+    )]
+    pub refresh_interval: Duration,
+
+    /// The timeout for fetching the feed
+    #[serde(
+        default = "ReputationFeedParams::default_timeout",
+        with = "duration_serde"
+    )]
+    pub timeout: Duration,
+
+    /// The data is considered to be stale when it has not been
+    /// successfully fetched for this long.  The default is
+    /// three times the refresh_interval.
+    #[serde(default, with = "duration_serde")]
+    pub max_age: Option<Duration>,
+
+    /// For Csv feeds, the first line holds the column names
+    /// and is ignored
+    #[serde(default)]
+    pub has_header: bool,
+
+    /// For Csv feeds, the index of the column holding the
+    /// IP address, network or domain
+    #[serde(default)]
+    pub key_column: usize,
+
+    /// For Csv feeds, the index of the column holding the score
+    #[serde(default)]
+    pub score_column: Option<usize>,
+
+    /// For Csv feeds, the index of the column holding the reason
+    #[serde(default)]
+    pub reason_column: Option<usize>,
+
+    /// For Json feeds, the field holding the IP address,
+    /// network or domain
+    #[serde(default = "ReputationFeedParams::default_key_field")]
+    pub key_field: String,
+
+    /// For Json feeds, the field holding the score
+    #[serde(default)]
+    pub score_field: Option<String>,
+
+    /// For Json feeds, the field holding the reason
+    #[serde(default)]
+    pub reason_field: Option<String>,
+
+    /// The score of entries that do not specify one
+    #[serde(default = "ReputationFeedParams::default_score")]
+    pub default_score: f64,
+
+    /// If set, the feed content is held in this redis instance,
+    /// so that it is shared with the other instances that use it
+    #[serde(default)]
+    pub redis: Option<RedisConnKey>,
+
+    /// The prefix for the redis keys that hold the feed content
+    #[serde(default = "ReputationFeedParams::default_redis_key_prefix")]
+    pub redis_key_prefix: String,
+
+    /// How long the results of lookups in redis are cached
+    #[serde(
+        default = "ReputationFeedParams::default_lookup_cache_ttl",
+        with = "duration_serde"
+    )]
+    pub lookup_cache_ttl: Duration,
+
+    /// The number of lookup results cached when using redis
+    #[serde(default = "ReputationFeedParams::default_lookup_cache_capacity")]
+    pub lookup_cache_capacity: usize,
+}
+
+impl ReputationFeedParams {
+    fn default_refresh_interval() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_key_field() -> String {
+        "key".to_string()
+    }
+
+    fn default_score() -> f64 {
+        1.0
+    }
+
+    fn default_redis_key_prefix() -> String {
+        "kumo-reputation".to_string()
+    }
+
+    fn default_lookup_cache_ttl() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_lookup_cache_capacity() -> usize {
+        100_000
+    }
+
+    fn max_age(&self) -> Duration {
+        self.max_age.unwrap_or(self.refresh_interval * 3)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ReputationEntry {
+    /// The entry in the feed that matched, which may be a
+    /// network or parent domain of the key being looked up
+    pub key: String,
+    pub score: f64,
+    pub reason: Option<String>,
+}
+
+struct Feed {
+    params: ReputationFeedParams,
+    table: ArcSwap<HashMap<String, ReputationEntry>>,
+    redis: Option<RedisConnection>,
+    lookup_cache: LruCacheWithTtl<String, Option<ReputationEntry>>,
+}
+
+/// Returns the canonical form of the network of the specified
+/// length that contains addr, such as `10.0.0.0/8`.  A full
+/// length network is represented by the address alone.
+fn network_key(addr: IpAddr, len: u8) -> String {
+    match addr {
+        IpAddr::V4(v4) if len >= 32 => v4.to_string(),
+        IpAddr::V6(v6) if len >= 128 => v6.to_string(),
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            let net = std::net::Ipv4Addr::from(u32::from(v4) & mask);
+            format!("{net}/{len}")
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            let net = std::net::Ipv6Addr::from(u128::from(v6) & mask);
+            format!("{net}/{len}")
+        }
+    }
+}
+
+/// Returns the canonical form of a key from a feed
+fn normalize_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    if let Ok(ip) = key.parse::<IpAddr>() {
+        return Some(ip.to_string());
+    }
+    if let Some((addr, len)) = key.split_once('/') {
+        let addr: IpAddr = addr.parse().ok()?;
+        let len: u8 = len.parse().ok()?;
+        return Some(network_key(addr, len));
+    }
+    Some(key.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Returns the keys that could match the subject of a lookup,
+/// most specific first
+fn candidate_keys(subject: &str) -> Vec<String> {
+    let subject = subject.trim();
+    if let Ok(ip) = subject.parse::<IpAddr>() {
+        let max_len = if ip.is_ipv4() { 32 } else { 128 };
+        return (0..=max_len)
+            .rev()
+            .map(|len| network_key(ip, len))
+            .collect();
+    }
+    let domain = subject.trim_end_matches('.').to_ascii_lowercase();
+    let mut candidates = vec![];
+    let mut remaining = domain.as_str();
+    loop {
+        candidates.push(remaining.to_string());
+        match remaining.split_once('.') {
+            Some((_, parent)) if !parent.is_empty() => remaining = parent,
+            _ => break,
+        }
+    }
+    candidates
+}
+
+fn value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+impl ReputationFeedParams {
+    fn make_entry(
+        &self,
+        key: &str,
+        score: Option<&str>,
+        reason: Option<String>,
+    ) -> anyhow::Result<Option<(String, ReputationEntry)>> {
+        let Some(key) = normalize_key(key) else {
+            return Ok(None);
+        };
+        let score = match score.map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            Some(score) => score
+                .parse()
+                .map_err(|err| anyhow::anyhow!("invalid score {score} for {key}: {err}"))?,
+            None => self.default_score,
+        };
+        let reason = reason.filter(|r| !r.is_empty());
+        Ok(Some((key.clone(), ReputationEntry { key, score, reason })))
+    }
+
+    /// Parses the content of the feed
+    fn parse(&self, data: &str) -> anyhow::Result<HashMap<String, ReputationEntry>> {
+        let mut table = HashMap::new();
+        match self.format {
+            FeedFormat::Csv => {
+                let lines = data
+                    .lines()
+                    .enumerate()
+                    .skip(if self.has_header { 1 } else { 0 });
+                for (idx, line) in lines {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let columns: Vec<&str> = line
+                        .split(',')
+                        .map(|c| c.trim().trim_matches('"'))
+                        .collect();
+                    let column = |n: Option<usize>| n.and_then(|n| columns.get(n).copied());
+                    let Some(key) = column(Some(self.key_column)) else {
+                        anyhow::bail!("line {}: missing key column", idx + 1);
+                    };
+                    let reason = column(self.reason_column).map(|r| r.to_string());
+                    if let Some((key, entry)) =
+                        self.make_entry(key, column(self.score_column), reason)?
+                    {
+                        table.insert(key, entry);
+                    }
+                }
+            }
+            FeedFormat::Json => {
+                let items: Vec<serde_json::Map<String, Value>> = serde_json::from_str(data)?;
+                for item in items {
+                    let field = |name: Option<&String>| {
+                        name.and_then(|name| item.get(name))
+                            .and_then(value_to_string)
+                    };
+                    let Some(key) = field(Some(&self.key_field)) else {
+                        anyhow::bail!("entry {item:?} has no {} field", self.key_field);
+                    };
+                    let score = field(self.score_field.as_ref());
+                    let reason = field(self.reason_field.as_ref());
+                    if let Some((key, entry)) = self.make_entry(&key, score.as_deref(), reason)? {
+                        table.insert(key, entry);
+                    }
+                }
+            }
+        }
+        Ok(table)
+    }
+}
+
+impl Feed {
+    fn redis_key(&self) -> String {
+        format!("{}:{}", self.params.redis_key_prefix, self.params.name)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<HashMap<String, ReputationEntry>> {
+        let client = reqwest::Client::builder()
+            .timeout(self.params.timeout)
+            .build()?;
+        let request = self
+            .params
+            .headers
+            .iter()
+            .fold(client.get(&self.params.url), |request, (k, v)| {
+                request.header(k, v)
+            });
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            anyhow::bail!("{status}: {body}");
+        }
+        self.params.parse(&body)
+    }
+
+    /// Fetches the feed and replaces its content,
+    /// returning the number of entries
+    async fn refresh(&self) -> anyhow::Result<usize> {
+        let table = self.fetch().await?;
+        let count = table.len();
+
+        match &self.redis {
+            Some(redis) => {
+                // Populate a temporary key and then rename it, so that
+                // lookups never observe a partially updated feed
+                let key = self.redis_key();
+                let temp_key = format!("{key}:loading");
+                redis.query(cmd("DEL").arg(&temp_key).clone()).await?;
+                let entries: Vec<(String, String)> = table
+                    .into_iter()
+                    .map(|(k, entry)| Ok((k, serde_json::to_string(&entry)?)))
+                    .collect::<anyhow::Result<_>>()?;
+                for chunk in entries.chunks(1000) {
+                    let mut hset = cmd("HSET");
+                    hset.arg(&temp_key);
+                    for (k, v) in chunk {
+                        hset.arg(k).arg(v);
+                    }
+                    redis.query(hset).await?;
+                }
+                if count > 0 {
+                    redis
+                        .query(cmd("RENAME").arg(&temp_key).arg(&key).clone())
+                        .await?;
+                } else {
+                    redis.query(cmd("DEL").arg(&key).clone()).await?;
+                }
+                self.lookup_cache.clear();
+            }
+            None => {
+                self.table.store(Arc::new(table));
+            }
+        }
+
+        Ok(count)
+    }
+
+    async fn lookup(&self, subject: &str) -> anyhow::Result<Option<ReputationEntry>> {
+        let candidates = candidate_keys(subject);
+
+        let Some(redis) = &self.redis else {
+            let table = self.table.load();
+            return Ok(candidates.iter().find_map(|k| table.get(k).cloned()));
+        };
+
+        if let Some(result) = self.lookup_cache.get(subject) {
+            return Ok(result);
+        }
+        let mut hmget = cmd("HMGET");
+        hmget.arg(self.redis_key());
+        for k in &candidates {
+            hmget.arg(k);
+        }
+        let mut result = None;
+        if let RedisValue::Array(values) = redis.query(hmget).await? {
+            for value in values {
+                if let RedisValue::BulkString(data) = value {
+                    result.replace(serde_json::from_slice(&data)?);
+                    break;
+                }
+            }
+        }
+        self.lookup_cache.insert(
+            subject.to_string(),
+            result.clone(),
+            Instant::now() + self.params.lookup_cache_ttl,
+        );
+        Ok(result)
+    }
+}
+
+async fn run_feed(feed: Arc<Feed>) {
+    let name = feed.params.name.clone();
+    let mut last_success: Option<Instant> = None;
+    let mut failures = 0;
+
+    loop {
+        match feed.refresh().await {
+            Ok(count) => {
+                tracing::debug!("reputation feed {name}: loaded {count} entries");
+                failures = 0;
+                last_success.replace(Instant::now());
+                FEED_ENTRIES.with_label_values(&[&name]).set(count as i64);
+                FEED_LAST_SUCCESS
+                    .with_label_values(&[&name])
+                    .set(chrono::Utc::now().timestamp());
+            }
+            Err(err) => {
+                failures += 1;
+                let error = format!("{err:#}");
+                tracing::error!("reputation feed {name}: fetch failed: {error}");
+                FEED_FETCH_FAILURES.with_label_values(&[&name]).inc();
+                if let Err(err) = trigger_fetch_failed(&name, error, failures).await {
+                    tracing::error!("reputation_feed_fetch_failed: {err:#}");
+                }
+            }
+        }
+
+        let stale = last_success
+            .map(|t| t.elapsed() > feed.params.max_age())
+            .unwrap_or(true);
+        FEED_STALE.with_label_values(&[&name]).set(stale as i64);
+
+        tokio::time::sleep(feed.params.refresh_interval).await;
+    }
+}
+
+async fn trigger_fetch_failed(name: &str, error: String, failures: u64) -> anyhow::Result<()> {
+    let mut config = load_config().await?;
+    config
+        .async_call_callback(&FETCH_FAILED_SIG, (name.to_string(), error, failures))
+        .await?;
+    config.put();
+    Ok(())
+}
+
+/// Defines a feed and starts fetching it in the background
+pub fn define_feed(params: ReputationFeedParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    let mut feeds = FEEDS.lock().unwrap();
+    anyhow::ensure!(
+        !feeds.contains_key(&params.name),
+        "reputation feed {} has already been defined",
+        params.name
+    );
+
+    let redis = params.redis.as_ref().map(|key| key.open()).transpose()?;
+    let feed = Arc::new(Feed {
+        lookup_cache: LruCacheWithTtl::new_named(
+            format!("reputation_feed_{}", params.name),
+            params.lookup_cache_capacity,
+        ),
+        params,
+        table: ArcSwap::default(),
+        redis,
+    });
+    feeds.insert(feed.params.name.clone(), feed.clone());
+
+    rt_spawn(
+        format!("reputation feed {}", feed.params.name),
+        run_feed(feed),
+    )?;
+    Ok(())
+}
+
+/// Looks up an IP address or domain in each of the feeds,
+/// returning the matching entry from each feed that has one
+pub async fn lookup(subject: &str) -> anyhow::Result<BTreeMap<String, ReputationEntry>> {
+    let feeds: Vec<Arc<Feed>> = FEEDS.lock().unwrap().values().cloned().collect();
+    let mut results = BTreeMap::new();
+    for feed in feeds {
+        if let Some(entry) = feed.lookup(subject).await? {
+            results.insert(feed.params.name.clone(), entry);
+        }
+    }
+    Ok(results)
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "reputation")?;
+
+    module.set(
+        "define_feed",
+        lua.create_function(|lua, params: mlua::Value| {
+            let params: ReputationFeedParams = from_lua_value(lua, params)?;
+            define_feed(params).map_err(any_err)
+        })?,
+    )?;
+
+    module.set(
+        "lookup",
+        lua.create_async_function(|lua, subject: String| async move {
+            let results = lookup(&subject).await.map_err(any_err)?;
+            lua.to_value(&results)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        assert_eq!(normalize_key("10.1.2.3/8").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(normalize_key("10.1.2.3/32").as_deref(), Some("10.1.2.3"));
+        assert_eq!(
+            normalize_key("2001:db8::1/32").as_deref(),
+            Some("2001:db8::/32")
+        );
+        assert_eq!(
+            normalize_key(" Example.COM. ").as_deref(),
+            Some("example.com")
+        );
+
+        let candidates = candidate_keys("10.1.2.3");
+        assert_eq!(candidates.len(), 33);
+        assert_eq!(candidates[0], "10.1.2.3");
+        assert_eq!(candidates[8], "10.1.2.0/24");
+        assert_eq!(candidates[32], "0.0.0.0/0");
+
+        assert_eq!(
+            candidate_keys("mail.Example.com"),
+            vec!["mail.example.com", "example.com", "com"]
+        );
+    }
+
+    #[test]
+    fn parse_csv() {
+        let params: ReputationFeedParams = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "url": "http://example.com/feed.csv",
+            "has_header": true,
+            "score_column": 1,
+            "reason_column": 2,
+        }))
+        .unwrap();
+        let table = params
+            .parse(
+                "key,score,reason\n\
+                 # a comment\n\
+                 192.0.2.0/24, 7.5, \"botnet\"\n\
+                 \n\
+                 spammy.example,,\n",
+            )
+            .unwrap();
+        assert_eq!(
+            table.get("192.0.2.0/24"),
+            Some(&ReputationEntry {
+                key: "192.0.2.0/24".to_string(),
+                score: 7.5,
+                reason: Some("botnet".to_string()),
+            })
+        );
+        assert_eq!(
+            table.get("spammy.example"),
+            Some(&ReputationEntry {
+                key: "spammy.example".to_string(),
+                score: 1.0,
+                reason: None,
+            })
+        );
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn parse_json() {
+        let params: ReputationFeedParams = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "url": "http://example.com/feed.json",
+            "format": "Json",
+            "key_field": "ip",
+            "score_field": "risk",
+        }))
+        .unwrap();
+        let table = params
+            .parse(r#"[{"ip": "2001:db8::1", "risk": 3}, {"ip": "192.0.2.1"}]"#)
+            .unwrap();
+        assert_eq!(table["2001:db8::1"].score, 3.0);
+        assert_eq!(table["192.0.2.1"].score, 1.0);
+
+        assert!(params.parse(r#"[{"address": "192.0.2.1"}]"#).is_err());
+    }
+}
@@ -0,0 +1,505 @@
+//! Implements `kumo.schedule`, which triggers an event according
+//! to a cron style schedule so that periodic housekeeping can be
+//! performed from policy, without relying on an external cron job.
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, TimeZone, Timelike, Utc};
+use config::{any_err, from_lua_value, get_or_create_module, load_config, CallbackSignature};
+use kumo_server_runtime::rt_spawn;
+use mlua::{Lua, Value};
+use prometheus::{HistogramVec, IntCounterVec, IntGaugeVec};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+static TASK_NAMES: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+static RUNS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "scheduled_task_runs",
+        "total number of times that a scheduled task has been run",
+        &["task"]
+    )
+    .unwrap()
+});
+static FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "scheduled_task_failures",
+        "total number of runs of a scheduled task that raised an error",
+        &["task"]
+    )
+    .unwrap()
+});
+static SKIPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "scheduled_task_skipped",
+        "total number of runs of a scheduled task that were skipped \
+         because the previous run was still in progress",
+        &["task"]
+    )
+    .unwrap()
+});
+static LAST_RUN: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "scheduled_task_last_run",
+        "unix timestamp of the most recent start of a scheduled task",
+        &["task"]
+    )
+    .unwrap()
+});
+static LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "scheduled_task_latency",
+        "how long a scheduled task took to run",
+        &["task"]
+    )
+    .unwrap()
+});
+
+/// A set of permitted values for one of the fields of a cron
+/// expression, represented as a bitmask
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    bits: u64,
+    /// true if the field was `*`, which matters for the
+    /// interaction between day-of-month and day-of-week
+    any: bool,
+}
+
+impl Field {
+    fn parse(spec: &str, min: u32, max: u32, names: &[&str]) -> anyhow::Result<Self> {
+        let value = |s: &str| -> anyhow::Result<u32> {
+            if let Some(idx) = names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+                return Ok(min + idx as u32);
+            }
+            let n: u32 = s.parse().with_context(|| format!("invalid value {s}"))?;
+            anyhow::ensure!(
+                n >= min && n <= max,
+                "{n} is outside of the range {min}-{max}"
+            );
+            Ok(n)
+        };
+
+        let mut bits = 0u64;
+        for item in spec.split(',') {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => {
+                    let step: u32 = step
+                        .parse()
+                        .with_context(|| format!("invalid step {step}"))?;
+                    anyhow::ensure!(step > 0, "step must be greater than 0");
+                    (range, step)
+                }
+                None => (item, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (value(start)?, value(end)?)
+            } else {
+                let start = value(range)?;
+                // `5/15` means every 15 starting from 5
+                (start, if item.contains('/') { max } else { start })
+            };
+            anyhow::ensure!(start <= end, "invalid range {range}");
+            for n in (start..=end).step_by(step as usize) {
+                bits |= 1 << n;
+            }
+        }
+
+        Ok(Self {
+            bits,
+            any: spec.starts_with('*'),
+        })
+    }
+
+    fn contains(&self, n: u32) -> bool {
+        self.bits & (1 << n) != 0
+    }
+}
+
+/// A standard 5 field cron expression: minute, hour, day of month,
+/// month and day of week, evaluated in UTC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minute: Field,
+    hour: Field,
+    day_of_month: Field,
+    month: Field,
+    day_of_week: Field,
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let expanded = match s.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            s => s,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        anyhow::ensure!(
+            fields.len() == 5,
+            "cron expression {s} must have 5 fields: minute hour day-of-month month day-of-week"
+        );
+
+        const MONTHS: &[&str] = &[
+            "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+        ];
+        const DAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+        let field = |idx: usize, min: u32, max: u32, names: &[&str]| {
+            Field::parse(fields[idx], min, max, names)
+                .with_context(|| format!("cron expression {s}: field {}", fields[idx]))
+        };
+
+        let mut day_of_week = field(4, 0, 7, DAYS)?;
+        // Both 0 and 7 mean Sunday
+        if day_of_week.contains(7) {
+            day_of_week.bits |= 1;
+        }
+
+        Ok(Self {
+            minute: field(0, 0, 59, &[])?,
+            hour: field(1, 0, 23, &[])?,
+            day_of_month: field(2, 1, 31, &[])?,
+            month: field(3, 1, 12, MONTHS)?,
+            day_of_week,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for CronSchedule {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|err| serde::de::Error::custom(format!("{err:#}")))
+    }
+}
+
+impl CronSchedule {
+    fn matches_day(&self, t: DateTime<Utc>) -> bool {
+        let dom = self.day_of_month.contains(t.day());
+        let dow = self
+            .day_of_week
+            .contains(t.weekday().num_days_from_sunday());
+        // As with Vixie cron, when either field starts with `*` the
+        // day must match both of them, so that `*/2` is honored, but
+        // when both fields are restricted a day that matches either
+        // of them is permitted
+        if self.day_of_month.any || self.day_of_week.any {
+            dom && dow
+        } else {
+            dom || dow
+        }
+    }
+
+    /// Returns the first time strictly after `t` that matches
+    /// the schedule
+    pub fn next_after(&self, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut t = t.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+        // Every schedule that can match at all will do so within
+        // a handful of years, even for Feb 29th
+        let limit = t + ChronoDuration::days(366 * 8);
+
+        while t < limit {
+            if !self.month.contains(t.month()) {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+                continue;
+            }
+            if !self.matches_day(t) {
+                t = t.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc();
+                continue;
+            }
+            if !self.hour.contains(t.hour()) {
+                t = t.with_minute(0)? + ChronoDuration::hours(1);
+                continue;
+            }
+            if !self.minute.contains(t.minute()) {
+                t += ChronoDuration::minutes(1);
+                continue;
+            }
+            return Some(t);
+        }
+        None
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ScheduleParams {
+    /// Identifies the task in metrics and in its state file
+    name: String,
+    /// The cron expression
+    schedule: CronSchedule,
+    /// The event that is triggered on each run
+    event_name: String,
+    /// Passed to the event handler
+    #[serde(default)]
+    args: serde_json::Value,
+    /// Each run is delayed by a random duration up to this long,
+    /// to avoid a thundering herd across a cluster
+    #[serde(default, with = "duration_serde")]
+    jitter: Option<Duration>,
+    /// Allow a run to start while the previous run is in progress
+    #[serde(default)]
+    allow_overlap: bool,
+    /// Where to record the time of the most recent run
+    #[serde(default)]
+    state_dir: Option<PathBuf>,
+    /// If the state_dir shows that a run was missed, for example,
+    /// because kumod was stopped at the time, run immediately
+    #[serde(default)]
+    catch_up: bool,
+}
+
+impl ScheduleParams {
+    fn state_file(&self) -> Option<PathBuf> {
+        self.state_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.last_run", self.name)))
+    }
+
+    fn load_last_run(&self) -> Option<DateTime<Utc>> {
+        let path = self.state_file()?;
+        let data = std::fs::read_to_string(&path).ok()?;
+        match DateTime::parse_from_rfc3339(data.trim()) {
+            Ok(t) => Some(t.with_timezone(&Utc)),
+            Err(err) => {
+                tracing::error!(
+                    "scheduled task {}: ignoring invalid {}: {err:#}",
+                    self.name,
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    fn save_last_run(&self, t: DateTime<Utc>) -> anyhow::Result<()> {
+        let Some(path) = self.state_file() else {
+            return Ok(());
+        };
+        // Write then rename, so that a crash cannot leave a truncated file
+        let temp = path.with_extension("last_run.tmp");
+        std::fs::write(&temp, t.to_rfc3339())
+            .with_context(|| format!("writing {}", temp.display()))?;
+        std::fs::rename(&temp, &path).with_context(|| format!("renaming to {}", path.display()))
+    }
+
+    async fn run(&self) -> anyhow::Result<()> {
+        let mut config = load_config().await?;
+        let sig = CallbackSignature::<Value, ()>::new(self.event_name.to_string());
+        config
+            .convert_args_and_call_callback(&sig, &self.args)
+            .await?;
+        config.put();
+        Ok(())
+    }
+}
+
+async fn run_schedule(params: ScheduleParams) {
+    let params = Arc::new(params);
+    let running = Arc::new(AtomicBool::new(false));
+    let name = params.name.clone();
+
+    let mut catch_up = params.catch_up
+        && params
+            .load_last_run()
+            .and_then(|t| params.schedule.next_after(t))
+            .map(|due| due <= Utc::now())
+            .unwrap_or(false);
+
+    loop {
+        let now = Utc::now();
+        let due = if catch_up {
+            catch_up = false;
+            now
+        } else {
+            let Some(due) = params.schedule.next_after(now) else {
+                tracing::error!("scheduled task {name}: the schedule never matches; stopping");
+                return;
+            };
+            due
+        };
+
+        let mut delay = (due - now).to_std().unwrap_or_default();
+        if let Some(jitter) = params.jitter {
+            delay += jitter.mul_f64(rand::random::<f64>());
+        }
+        tokio::time::sleep(delay).await;
+
+        if running.swap(true, Ordering::SeqCst) && !params.allow_overlap {
+            tracing::warn!("scheduled task {name}: previous run is still in progress; skipping");
+            SKIPPED.with_label_values(&[&name]).inc();
+            continue;
+        }
+
+        let started = Utc::now();
+        LAST_RUN
+            .with_label_values(&[&name])
+            .set(started.timestamp());
+        if let Err(err) = params.save_last_run(started) {
+            tracing::error!("scheduled task {name}: {err:#}");
+        }
+
+        let params = params.clone();
+        let running = running.clone();
+        let spawn_result = rt_spawn(format!("scheduled task {name}"), async move {
+            let name = &params.name;
+            let start = Instant::now();
+            RUNS.with_label_values(&[name]).inc();
+            if let Err(err) = params.run().await {
+                FAILURES.with_label_values(&[name]).inc();
+                tracing::error!(
+                    "scheduled task {name}: error while dispatching {}: {err:#}",
+                    params.event_name
+                );
+            }
+            LATENCY
+                .with_label_values(&[name])
+                .observe(start.elapsed().as_secs_f64());
+            running.store(false, Ordering::SeqCst);
+        });
+        if let Err(err) = spawn_result {
+            tracing::error!("scheduled task {name}: failed to spawn: {err:#}");
+            running.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let kumo_mod = get_or_create_module(lua, "kumo")?;
+
+    kumo_mod.set(
+        "schedule",
+        lua.create_function(|lua, params: Value| {
+            let params: ScheduleParams = from_lua_value(lua, params)?;
+
+            if config::is_validating() {
+                return Ok(());
+            }
+
+            if !TASK_NAMES.lock().unwrap().insert(params.name.clone()) {
+                return Err(mlua::Error::external(format!(
+                    "scheduled task {} has already been defined",
+                    params.name
+                )));
+            }
+
+            if let Some(dir) = &params.state_dir {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("creating {}", dir.display()))
+                    .map_err(any_err)?;
+            }
+
+            let name = params.name.clone();
+            rt_spawn(format!("schedule {name}"), run_schedule(params))?;
+            Ok(())
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn next(schedule: &str, t: &str) -> String {
+        let schedule: CronSchedule = schedule.parse().unwrap();
+        let t = DateTime::parse_from_rfc3339(t).unwrap().with_timezone(&Utc);
+        schedule
+            .next_after(t)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn next_after() {
+        assert_eq!(
+            next("*/5 * * * *", "2024-01-10T12:03:27Z"),
+            "2024-01-10T12:05:00+00:00"
+        );
+        assert_eq!(
+            next("*/5 * * * *", "2024-01-10T12:05:00Z"),
+            "2024-01-10T12:10:00+00:00"
+        );
+        assert_eq!(
+            next("30 2 * * *", "2024-01-10T12:00:00Z"),
+            "2024-01-11T02:30:00+00:00"
+        );
+        assert_eq!(
+            next("0 9-17/4 * * mon-fri", "2024-01-12T18:00:00Z"),
+            "2024-01-15T09:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 29 feb *", "2024-03-01T00:00:00Z"),
+            "2028-02-29T00:00:00+00:00"
+        );
+        assert_eq!(
+            next("@monthly", "2024-12-15T00:00:00Z"),
+            "2025-01-01T00:00:00+00:00"
+        );
+        // Sunday may be written as 7
+        assert_eq!(
+            next("0 0 * * 7", "2024-01-10T00:00:00Z"),
+            "2024-01-14T00:00:00+00:00"
+        );
+        // Both day fields restricted: either one matches
+        assert_eq!(
+            next("0 0 20 * 5", "2024-01-10T00:00:00Z"),
+            "2024-01-12T00:00:00+00:00"
+        );
+        assert_eq!(next("0 0 31 feb *", "2024-01-10T00:00:00Z"), "");
+    }
+
+    #[test]
+    fn stepped_days() {
+        // Odd days of the month
+        assert_eq!(
+            next("0 0 */2 * *", "2024-01-11T12:00:00Z"),
+            "2024-01-13T00:00:00+00:00"
+        );
+        // Sunday, Tuesday, Thursday and Saturday
+        assert_eq!(
+            next("0 0 * * */2", "2024-01-11T12:00:00Z"),
+            "2024-01-13T00:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 * * */2", "2024-01-13T12:00:00Z"),
+            "2024-01-14T00:00:00+00:00"
+        );
+        // A stepped field starts with `*`, so both fields must match:
+        // a Monday that is an odd day of the month
+        assert_eq!(
+            next("0 0 */2 * mon", "2024-01-16T00:00:00Z"),
+            "2024-01-29T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for s in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "0 0 * * funday",
+        ] {
+            assert!(s.parse::<CronSchedule>().is_err(), "{s}");
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::diagnostic_logging::LoggingConfig;
 use anyhow::Context;
 use config::RegisterFunc;
-use kumo_server_lifecycle::LifeCycle;
+use kumo_server_lifecycle::{LifeCycle, ShutdownSubcription};
 use kumo_server_runtime::rt_spawn;
 use std::future::Future;
 use std::path::Path;
@@ -56,9 +56,23 @@ impl<'a> StartConfig<'a> {
             // test harness. Do not change or remove it without
             // making appropriate adjustments over there!
             tracing::info!("initialization complete");
+            if error.is_none() {
+                crate::listeners::warn_about_unclaimed_listeners();
+                crate::systemd::notify_ready();
+                rt_spawn(
+                    "systemd-reload-notifier".to_string(),
+                    crate::systemd::report_config_reloads(),
+                )
+                .ok();
+            }
             error
         })?;
 
+        rt_spawn("systemd-stop-notifier".to_string(), async move {
+            ShutdownSubcription::get().shutting_down().await;
+            crate::systemd::notify_stopping();
+        })?;
+
         life_cycle.wait_for_shutdown().await;
 
         // after waiting for those to idle out, shut down logging
@@ -0,0 +1,76 @@
+//! Integration with systemd: adopting listening sockets that were
+//! passed to us via socket activation, and reporting our state to the
+//! service manager via sd_notify.
+//! When we are not running under systemd, these are no-ops.
+use sd_notify::NotifyState;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, IntoRawFd};
+
+/// Returns the TCP listening sockets that were passed to us by systemd,
+/// along with their local addresses. These are made available to
+/// listeners via crate::listeners::take_inherited_listener.
+pub(crate) fn listen_fds() -> Vec<(SocketAddr, TcpListener)> {
+    let mut listeners = vec![];
+
+    let fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(err) => {
+            tracing::error!("failed to determine sockets passed by systemd: {err:#}");
+            return listeners;
+        }
+    };
+
+    for fd in fds {
+        // SAFETY: systemd passes ownership of these descriptors to us,
+        // and listen_fds only returns each of them once
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(addr) => {
+                tracing::info!("adopting listener on {addr:?} passed by systemd");
+                listeners.push((addr, listener));
+            }
+            Err(err) => {
+                // Not a TCP socket; leave it open, as we don't
+                // know what it is for
+                tracing::warn!("ignoring non-TCP socket fd {fd} passed by systemd: {err:#}");
+                let _ = listener.into_raw_fd();
+            }
+        }
+    }
+
+    listeners
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        tracing::error!("failed to notify systemd of {state:?}: {err:#}");
+    }
+}
+
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Reports that the configuration is being reloaded. Since the
+/// configuration is loaded lazily as it is needed, there is nothing
+/// to wait for, so we immediately report being ready again.
+pub fn notify_reloaded() {
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(err) => tracing::error!("failed to get monotonic time: {err:#}"),
+    }
+    notify_ready();
+}
+
+/// Reports a reload to systemd each time that the configuration
+/// epoch changes
+pub async fn report_config_reloads() {
+    let mut epoch = config::epoch::subscribe();
+    while epoch.changed().await.is_ok() {
+        notify_reloaded();
+    }
+}
@@ -2,13 +2,29 @@ use anyhow::Context;
 use data_loader::KeySource;
 use rustls::pki_types::pem::PemObject;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use rustls::ServerConfig;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
 use std::sync::Arc;
 
 pub async fn make_server_config(
     hostname: &str,
     tls_private_key: &Option<KeySource>,
     tls_certificate: &Option<KeySource>,
+) -> anyhow::Result<Arc<ServerConfig>> {
+    make_server_config_with_client_auth(hostname, tls_private_key, tls_certificate, &None, false)
+        .await
+}
+
+/// Like make_server_config, but when client_ca is specified, clients are
+/// asked to present a certificate that is signed by one of the certificate
+/// authorities that it contains.  If require_client_certificate is true,
+/// the handshake will fail for clients that do not present such a certificate.
+pub async fn make_server_config_with_client_auth(
+    hostname: &str,
+    tls_private_key: &Option<KeySource>,
+    tls_certificate: &Option<KeySource>,
+    client_ca: &Option<KeySource>,
+    require_client_certificate: bool,
 ) -> anyhow::Result<Arc<ServerConfig>> {
     let mut certificates = vec![];
     let private_key = match tls_private_key {
@@ -28,9 +44,38 @@ pub async fn make_server_config(
             .with_context(|| format!("loading certificates from {cert_file:?}"))?;
     }
 
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certificates, private_key)?;
+    let builder = ServerConfig::builder();
+    let config = match client_ca {
+        Some(ca_file) => {
+            let data = ca_file.get().await?;
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(&data) {
+                let cert =
+                    cert.with_context(|| format!("loading certificates from {ca_file:?}"))?;
+                roots
+                    .add(cert)
+                    .with_context(|| format!("adding CA certificate from {ca_file:?}"))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = if require_client_certificate {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(certificates, private_key)?
+        }
+        None => {
+            anyhow::ensure!(
+                !require_client_certificate,
+                "a CA certificate must be specified in order to require client certificates"
+            );
+            builder
+                .with_no_client_auth()
+                .with_single_cert(certificates, private_key)?
+        }
+    };
 
     Ok(Arc::new(config))
 }
@@ -14,7 +14,7 @@ use cgroups_rs::memory::MemController;
 use cgroups_rs::{Hierarchy, MaxValue};
 use nix::sys::resource::{rlim_t, RLIM_INFINITY};
 use nix::unistd::{sysconf, SysconfVar};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::{LazyLock, Mutex};
 use std::time::Duration;
 use tikv_jemallocator::Jemalloc;
@@ -65,6 +65,13 @@ static LOW_MEM: AtomicBool = AtomicBool::new(false);
 // have to deal with this small window on startup.
 static HEAD_ROOM: AtomicUsize = AtomicUsize::new(u32::MAX as usize);
 
+/// When non-zero, replaces the soft limit that was detected
+/// from the environment
+static SOFT_LIMIT_OVERRIDE: AtomicU64 = AtomicU64::new(0);
+/// The percentage of the soft limit above which low_memory()
+/// returns true
+static LOW_MEMORY_PERCENT: AtomicU8 = AtomicU8::new(80);
+
 /// Represents the current memory usage of this process
 #[derive(Debug, Clone, Copy)]
 pub struct MemoryUsage {
@@ -143,6 +150,14 @@ impl MemoryLimits {
     pub fn is_unlimited(&self) -> bool {
         self.soft_limit.is_none() && self.hard_limit.is_none()
     }
+
+    /// Applies the soft limit set via set_soft_limit_override, if any
+    pub fn with_soft_limit_override(self) -> Self {
+        Self {
+            soft_limit: get_soft_limit_override().or(self.soft_limit),
+            hard_limit: self.hard_limit,
+        }
+    }
 }
 
 fn rlim_to_opt(rlim: rlim_t) -> Option<u64> {
@@ -337,7 +352,9 @@ fn memory_thread() {
     loop {
         MEM_COUNTED.set(crate::tracking::counted_usage() as f64);
 
-        match get_usage_and_limit() {
+        let usage_and_limit =
+            get_usage_and_limit().map(|(usage, limit)| (usage, limit.with_soft_limit_override()));
+        match usage_and_limit {
             Ok((
                 MemoryUsage { bytes: usage },
                 MemoryLimits {
@@ -352,7 +369,7 @@ fn memory_thread() {
                 MEM_USAGE.set(usage as f64);
                 MEM_LIMIT.set(limit as f64);
 
-                let low_thresh = limit * 8 / 10;
+                let low_thresh = limit / 100 * LOW_MEMORY_PERCENT.load(Ordering::Relaxed) as u64;
                 LOW_MEM.store(usage > low_thresh, Ordering::SeqCst);
 
                 if !is_ok && was_ok {
@@ -407,11 +424,42 @@ pub fn get_headroom() -> usize {
     HEAD_ROOM.load(Ordering::SeqCst)
 }
 
-/// Returns true when we are within 10% if the soft limit
+/// Returns true when usage exceeds the low memory threshold,
+/// which is 80% of the soft limit by default
 pub fn low_memory() -> bool {
     LOW_MEM.load(Ordering::SeqCst)
 }
 
+/// Overrides the soft limit that was detected from the environment.
+/// Passing None reverts to the detected limit.
+/// The change takes effect the next time that the memory usage
+/// is sampled, which happens every few seconds.
+pub fn set_soft_limit_override(limit: Option<u64>) {
+    SOFT_LIMIT_OVERRIDE.store(limit.unwrap_or(0), Ordering::SeqCst);
+}
+
+pub fn get_soft_limit_override() -> Option<u64> {
+    match SOFT_LIMIT_OVERRIDE.load(Ordering::SeqCst) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// Sets the percentage of the soft limit above which
+/// low_memory() will return true. The default is 80.
+pub fn set_low_memory_threshold(percent: u8) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (1..=100).contains(&percent),
+        "low memory threshold must be between 1 and 100 percent"
+    );
+    LOW_MEMORY_PERCENT.store(percent, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn get_low_memory_threshold() -> u8 {
+    LOW_MEMORY_PERCENT.load(Ordering::Relaxed)
+}
+
 /// Returns a receiver that will notify when memory status
 /// changes from OK -> !OK or vice versa.
 pub fn subscribe_to_memory_status_changes() -> Option<Receiver<()>> {
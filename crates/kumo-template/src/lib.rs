@@ -1,5 +1,6 @@
+pub use minijinja::value::Rest;
 use minijinja::Environment;
-pub use minijinja::{context, Error, Template, Value};
+pub use minijinja::{context, Error, ErrorKind, Template, Value};
 use minijinja_contrib::add_to_environment;
 use self_cell::self_cell;
 
@@ -31,6 +32,17 @@ impl TemplateEngine {
         self.env.get_template(name)
     }
 
+    /// Define a filter that can be used by all templates,
+    /// such as `{{ price | currency("USD") }}`.  The filter is
+    /// passed the value being filtered, followed by any arguments.
+    pub fn add_filter<N, F>(&mut self, name: N, filter: F)
+    where
+        N: Into<String>,
+        F: Fn(Value, Rest<Value>) -> Result<Value, Error> + Send + Sync + 'static,
+    {
+        self.env.add_filter(name.into(), filter)
+    }
+
     /// Define a global value that can be reference by all templates
     pub fn add_global<N, V>(&mut self, name: N, value: V)
     where
@@ -46,6 +58,23 @@ impl TemplateEngine {
     {
         self.env.render_named_str(name, source, context)
     }
+
+    /// Check that an expression, such as `meta.customer_id`, can be compiled
+    pub fn check_expression(&self, expr: &str) -> Result<(), Error> {
+        self.env
+            .compile_expression_owned(expr.to_string())
+            .map(|_| ())
+    }
+
+    /// Compile and evaluate an expression against the provided context
+    pub fn eval_expression<CTX>(&self, expr: &str, context: CTX) -> Result<Value, Error>
+    where
+        CTX: serde::Serialize,
+    {
+        self.env
+            .compile_expression_owned(expr.to_string())?
+            .eval(context)
+    }
 }
 
 pub type TemplateList<'a> = Vec<Template<'a, 'a>>;
@@ -0,0 +1,269 @@
+//! This module tracks the age at which the messages in a scheduled
+//! queue reach their final disposition, and evaluates it against the
+//! age SLO declared by the `age_slo` queue config option, such as
+//! "95% of messages are delivered within 15 minutes".
+//!
+//! The age distribution and SLO compliance are exported as metrics
+//! labelled by queue, and the `queue_age_slo_breached` event is
+//! triggered when a queue starts to breach its SLO.
+use crate::queue::QueueManager;
+use config::{load_config, CallbackSignature, SerdeWrappedValue};
+use kumo_log_types::RecordType;
+use message::Message;
+use parking_lot::Mutex;
+use prometheus::{GaugeVec, HistogramVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// The number of buckets into which the window is divided
+const NUM_BUCKETS: u32 = 30;
+
+static WINDOWS: LazyLock<Mutex<HashMap<String, SloWindow>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+static AGE_SLO_BREACHED_SIG: LazyLock<
+    CallbackSignature<(String, SerdeWrappedValue<AgeSloStatus>), ()>,
+> = LazyLock::new(|| CallbackSignature::new_with_multiple("queue_age_slo_breached"));
+
+static DELIVERY_AGE: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "queue_delivery_age",
+        "age in seconds of messages at their final disposition, for \
+         scheduled queues that have an age_slo",
+        &["queue"],
+        vec![
+            1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 43200.0,
+            86400.0, 172800.0, 345600.0
+        ]
+    )
+    .unwrap()
+});
+static SLO_COMPLIANCE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    prometheus::register_gauge_vec!(
+        "queue_age_slo_compliance",
+        "percentage of messages in the age_slo window that reached \
+         their final disposition within the max_age of the queue",
+        &["queue"]
+    )
+    .unwrap()
+});
+static SLO_BREACHED: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "queue_age_slo_breached",
+        "1 if the queue is currently breaching its age_slo, 0 otherwise",
+        &["queue"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgeSlo {
+    /// The percentage of messages that must be delivered
+    /// within max_age
+    pub percentile: f64,
+
+    /// The maximum age of a message, measured from its reception
+    /// to its delivery
+    #[serde(with = "duration_serde")]
+    pub max_age: Duration,
+
+    /// The period of time over which compliance is evaluated
+    #[serde(default = "AgeSlo::default_window", with = "duration_serde")]
+    pub window: Duration,
+
+    /// The minimum number of messages that must be present in the
+    /// window before the SLO is evaluated
+    #[serde(default = "AgeSlo::default_min_messages")]
+    pub min_messages: u64,
+}
+
+impl AgeSlo {
+    fn default_window() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_min_messages() -> u64 {
+        10
+    }
+}
+
+/// Passed to the queue_age_slo_breached event
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AgeSloStatus {
+    pub percentile: f64,
+    #[serde(with = "duration_serde")]
+    pub max_age: Duration,
+    #[serde(with = "duration_serde")]
+    pub window: Duration,
+    /// The percentage of messages in the window that were
+    /// delivered within max_age
+    pub compliance: f64,
+    /// The number of messages in the window
+    pub messages: u64,
+    /// The number of those messages that were delivered within max_age
+    pub within_max_age: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    start: Instant,
+    within: u64,
+    total: u64,
+}
+
+#[derive(Debug, Default)]
+struct SloWindow {
+    buckets: VecDeque<Bucket>,
+    breached: bool,
+}
+
+impl SloWindow {
+    /// Records an outcome, and returns the status of the SLO
+    /// when it has enough messages to be evaluated
+    fn add(&mut self, slo: &AgeSlo, within: bool, now: Instant) -> Option<AgeSloStatus> {
+        let bucket_width = (slo.window / NUM_BUCKETS).max(Duration::from_secs(1));
+        while let Some(front) = self.buckets.front() {
+            if now.duration_since(front.start) >= slo.window {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+        match self.buckets.back_mut() {
+            Some(bucket) if now.duration_since(bucket.start) < bucket_width => {
+                bucket.total += 1;
+                if within {
+                    bucket.within += 1;
+                }
+            }
+            _ => self.buckets.push_back(Bucket {
+                start: now,
+                within: within as u64,
+                total: 1,
+            }),
+        }
+
+        let messages: u64 = self.buckets.iter().map(|b| b.total).sum();
+        if messages < slo.min_messages.max(1) {
+            return None;
+        }
+        let within_max_age: u64 = self.buckets.iter().map(|b| b.within).sum();
+        Some(AgeSloStatus {
+            percentile: slo.percentile,
+            max_age: slo.max_age,
+            window: slo.window,
+            compliance: 100.0 * within_max_age as f64 / messages as f64,
+            messages,
+            within_max_age,
+        })
+    }
+}
+
+/// Called by the logging layer to record the final disposition
+/// of a message, whose age is `age` seconds
+pub async fn record(kind: RecordType, msg: &Message, age: f64) {
+    let within = match kind {
+        RecordType::Delivery => None,
+        // An expired message was, by definition, not delivered in time
+        RecordType::Expiration => Some(false),
+        // Permanent failures say nothing about how quickly
+        // we are able to deliver
+        _ => return,
+    };
+
+    let Ok(queue_name) = msg.get_queue_name() else {
+        return;
+    };
+    let Some(queue) = QueueManager::get_opt(&queue_name) else {
+        return;
+    };
+    let Some(slo) = queue.get_config().borrow().age_slo.clone() else {
+        return;
+    };
+
+    DELIVERY_AGE.with_label_values(&[&queue_name]).observe(age);
+    let within = within.unwrap_or(age <= slo.max_age.as_secs_f64());
+
+    let newly_breached = {
+        let mut windows = WINDOWS.lock();
+        let window = windows.entry(queue_name.clone()).or_default();
+        let Some(status) = window.add(&slo, within, Instant::now()) else {
+            return;
+        };
+        SLO_COMPLIANCE
+            .with_label_values(&[&queue_name])
+            .set(status.compliance);
+
+        let breached = status.compliance < slo.percentile;
+        SLO_BREACHED
+            .with_label_values(&[&queue_name])
+            .set(breached as i64);
+        let newly_breached = breached && !window.breached;
+        window.breached = breached;
+        newly_breached.then_some(status)
+    };
+
+    if let Some(status) = newly_breached {
+        tracing::warn!(
+            "queue {queue_name} is breaching its age SLO: {:.1}% of {} messages \
+             were delivered within {:?}, below the target of {}%",
+            status.compliance,
+            status.messages,
+            status.max_age,
+            status.percentile
+        );
+        if let Err(err) = trigger_breached_event(queue_name, status).await {
+            tracing::error!("queue_age_slo_breached: {err:#}");
+        }
+    }
+}
+
+async fn trigger_breached_event(queue_name: String, status: AgeSloStatus) -> anyhow::Result<()> {
+    let mut config = load_config().await?;
+    config
+        .async_call_callback(
+            &AGE_SLO_BREACHED_SIG,
+            (queue_name, SerdeWrappedValue(status)),
+        )
+        .await?;
+    config.put();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window() {
+        let slo = AgeSlo {
+            percentile: 90.0,
+            max_age: Duration::from_secs(900),
+            window: Duration::from_secs(300),
+            min_messages: 4,
+        };
+        let mut window = SloWindow::default();
+        let start = Instant::now();
+
+        assert_eq!(window.add(&slo, true, start), None);
+        assert_eq!(window.add(&slo, true, start), None);
+        assert_eq!(window.add(&slo, false, start), None);
+        let status = window
+            .add(&slo, true, start + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(status.messages, 4);
+        assert_eq!(status.within_max_age, 3);
+        assert_eq!(status.compliance, 75.0);
+        assert_eq!(window.buckets.len(), 2);
+
+        // The first bucket ages out of the window
+        let status = window
+            .add(&slo, true, start + Duration::from_secs(300))
+            .unwrap_or_else(|| panic!("{window:?}"));
+        assert_eq!(status.messages, 2);
+        assert_eq!(status.compliance, 100.0);
+    }
+}
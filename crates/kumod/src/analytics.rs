@@ -0,0 +1,549 @@
+//! The purpose of this module is to maintain rolling counts of the
+//! outcomes of messages, grouped by provider, egress pool and tenant,
+//! so that basic deliverability reporting doesn't require an external
+//! log processing pipeline.
+//!
+//! Counts are kept in one minute buckets for 24 hours, and the buckets
+//! are periodically written to a local sqlite database so that they
+//! survive a restart.
+
+use crate::smtp_server::RelayDisposition;
+use anyhow::Context;
+use chrono::Utc;
+use kumo_api_types::analytics::{
+    AnalyticsDimension, AnalyticsV1Counts, AnalyticsV1Entry, AnalyticsV1Request,
+};
+use kumo_log_types::RecordType;
+use kumo_server_lifecycle::ShutdownSubcription;
+use message::message::QueueNameComponents;
+use message::Message;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use sqlite::{Connection, ConnectionThreadSafe, State};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static ANALYTICS: OnceLock<Analytics> = OnceLock::new();
+
+const BUCKET_SECONDS: i64 = 60;
+const RETENTION_SECONDS: i64 = 86400;
+
+/// The name under which counts are recorded once a dimension
+/// has reached its limit on the number of distinct names
+const OTHER_NAME: &str = "(other)";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyticsParams {
+    /// The path to the sqlite database that holds the counts
+    #[serde(default = "AnalyticsParams::default_path")]
+    pub path: PathBuf,
+
+    /// How often the counts are written to the database
+    #[serde(
+        default = "AnalyticsParams::default_flush_interval",
+        with = "duration_serde"
+    )]
+    pub flush_interval: Duration,
+
+    /// The maximum number of distinct names that are tracked
+    /// for each dimension
+    #[serde(default = "AnalyticsParams::default_max_names")]
+    pub max_names: usize,
+}
+
+impl AnalyticsParams {
+    fn default_path() -> PathBuf {
+        "/var/spool/kumomta/analytics.db".into()
+    }
+
+    fn default_flush_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_max_names() -> usize {
+        1000
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Accepted,
+    Delivered,
+    Deferred,
+    Bounced,
+    Complaint,
+}
+
+impl Outcome {
+    fn counts(self) -> AnalyticsV1Counts {
+        let mut counts = AnalyticsV1Counts::default();
+        match self {
+            Self::Accepted => counts.accepted = 1,
+            Self::Delivered => counts.delivered = 1,
+            Self::Deferred => counts.deferred = 1,
+            Self::Bounced => counts.bounced = 1,
+            Self::Complaint => counts.complaint = 1,
+        }
+        counts
+    }
+}
+
+type SeriesKey = (AnalyticsDimension, String);
+type BucketKey = (AnalyticsDimension, String, i64);
+
+struct Rollup {
+    /// The counts for each dimension and name, keyed by the
+    /// unix timestamp of the start of the minute
+    series: HashMap<SeriesKey, BTreeMap<i64, AnalyticsV1Counts>>,
+    /// The buckets that have changed since they were last
+    /// written to the database
+    dirty: HashSet<BucketKey>,
+    max_names: usize,
+}
+
+impl Rollup {
+    fn new(max_names: usize) -> Self {
+        Self {
+            series: HashMap::new(),
+            dirty: HashSet::new(),
+            max_names,
+        }
+    }
+
+    fn add(
+        &mut self,
+        dimension: AnalyticsDimension,
+        name: &str,
+        counts: AnalyticsV1Counts,
+        now: i64,
+    ) {
+        let minute = now - now.rem_euclid(BUCKET_SECONDS);
+        let mut key = (dimension, name.to_string());
+        if !self.series.contains_key(&key)
+            && self.series.keys().filter(|(d, _)| *d == dimension).count() >= self.max_names
+        {
+            key.1 = OTHER_NAME.to_string();
+        }
+        *self
+            .series
+            .entry(key.clone())
+            .or_default()
+            .entry(minute)
+            .or_default() += counts;
+        self.dirty.insert((key.0, key.1, minute));
+    }
+
+    /// Removes the buckets that are older than the retention period
+    fn prune(&mut self, now: i64) {
+        let cutoff = now - RETENTION_SECONDS;
+        self.series.retain(|_, buckets| {
+            buckets.retain(|minute, _| *minute >= cutoff);
+            !buckets.is_empty()
+        });
+        self.dirty.retain(|(_, _, minute)| *minute >= cutoff);
+    }
+
+    /// Returns the current counts of the buckets that have changed
+    /// since this was last called
+    fn take_dirty(&mut self) -> Vec<(BucketKey, AnalyticsV1Counts)> {
+        self.dirty
+            .drain()
+            .filter_map(|(dimension, name, minute)| {
+                let counts = *self.series.get(&(dimension, name.clone()))?.get(&minute)?;
+                Some(((dimension, name, minute), counts))
+            })
+            .collect()
+    }
+
+    fn summarize(&self, request: &AnalyticsV1Request, now: i64) -> Vec<AnalyticsV1Entry> {
+        let mut entries: Vec<AnalyticsV1Entry> = self
+            .series
+            .iter()
+            .filter(|((dimension, name), _)| {
+                request.dimension.map_or(true, |d| d == *dimension)
+                    && request.name.as_deref().map_or(true, |n| n == name)
+            })
+            .map(|((dimension, name), buckets)| {
+                let window = |seconds: i64| {
+                    let mut total = AnalyticsV1Counts::default();
+                    for (_, counts) in buckets.range(now - seconds..) {
+                        total += *counts;
+                    }
+                    total
+                };
+                AnalyticsV1Entry {
+                    dimension: *dimension,
+                    name: name.clone(),
+                    last_5m: window(300),
+                    last_1h: window(3600),
+                    last_24h: window(RETENTION_SECONDS),
+                }
+            })
+            .filter(|entry| entry.last_24h != AnalyticsV1Counts::default())
+            .collect();
+        entries.sort_by(|a, b| (a.dimension, &a.name).cmp(&(b.dimension, &b.name)));
+        entries
+    }
+}
+
+struct Analytics {
+    rollup: Mutex<Rollup>,
+    db: Mutex<ConnectionThreadSafe>,
+}
+
+/// Opens the database, loads the counts from the last 24 hours
+/// and starts periodically writing the counts to the database.
+/// This can only be called once.
+pub fn configure_analytics(params: AnalyticsParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    if ANALYTICS.get().is_some() {
+        anyhow::bail!("configure_analytics has already been called");
+    }
+
+    let db = open_analytics_db(&params)?;
+    let mut rollup = Rollup::new(params.max_names);
+    load(&db, &mut rollup, Utc::now().timestamp())
+        .with_context(|| format!("loading analytics from {:?}", params.path))?;
+
+    ANALYTICS
+        .set(Analytics {
+            rollup: Mutex::new(rollup),
+            db: Mutex::new(db),
+        })
+        .map_err(|_| anyhow::anyhow!("configure_analytics has already been called"))?;
+
+    kumo_server_runtime::rt_spawn("analytics flusher", flusher(params.flush_interval))?;
+    Ok(())
+}
+
+fn open_analytics_db(params: &AnalyticsParams) -> anyhow::Result<ConnectionThreadSafe> {
+    let path = &params.path;
+    let db = Connection::open_thread_safe(path)
+        .with_context(|| format!("opening analytics database {path:?}"))?;
+
+    let query = r#"
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS analytics (
+    dimension TEXT NOT NULL,
+    name TEXT NOT NULL,
+    minute INTEGER NOT NULL,
+    accepted INTEGER NOT NULL,
+    delivered INTEGER NOT NULL,
+    deferred INTEGER NOT NULL,
+    bounced INTEGER NOT NULL,
+    complaint INTEGER NOT NULL,
+    PRIMARY KEY (dimension, name, minute)
+);
+    "#;
+
+    db.execute(query)
+        .with_context(|| format!("setting up analytics database {path:?}"))?;
+
+    Ok(db)
+}
+
+fn load(db: &ConnectionThreadSafe, rollup: &mut Rollup, now: i64) -> anyhow::Result<()> {
+    let mut stmt = db.prepare("SELECT * FROM analytics WHERE minute >= $cutoff")?;
+    stmt.bind(("$cutoff", now - RETENTION_SECONDS))?;
+    while stmt.next()? == State::Row {
+        let dimension: AnalyticsDimension = stmt
+            .read::<String, _>("dimension")?
+            .parse()
+            .map_err(|err: String| anyhow::anyhow!(err))?;
+        let name = stmt.read::<String, _>("name")?;
+        let minute = stmt.read::<i64, _>("minute")?;
+        let counts = AnalyticsV1Counts {
+            accepted: stmt.read::<i64, _>("accepted")? as u64,
+            delivered: stmt.read::<i64, _>("delivered")? as u64,
+            deferred: stmt.read::<i64, _>("deferred")? as u64,
+            bounced: stmt.read::<i64, _>("bounced")? as u64,
+            complaint: stmt.read::<i64, _>("complaint")? as u64,
+        };
+        rollup
+            .series
+            .entry((dimension, name))
+            .or_default()
+            .insert(minute, counts);
+    }
+    Ok(())
+}
+
+fn store(
+    db: &ConnectionThreadSafe,
+    buckets: &[(BucketKey, AnalyticsV1Counts)],
+    now: i64,
+) -> anyhow::Result<()> {
+    db.execute("BEGIN")?;
+    let result = (|| -> anyhow::Result<()> {
+        for ((dimension, name, minute), counts) in buckets {
+            let mut stmt = db.prepare(
+                "INSERT INTO analytics
+                    (dimension, name, minute, accepted, delivered, deferred, bounced, complaint)
+                    values ($dimension, $name, $minute, $accepted, $delivered,
+                        $deferred, $bounced, $complaint)
+                    on conflict (dimension, name, minute)
+                    do update set accepted=$accepted, delivered=$delivered,
+                        deferred=$deferred, bounced=$bounced, complaint=$complaint",
+            )?;
+            stmt.bind(("$dimension", dimension.as_str()))?;
+            stmt.bind(("$name", name.as_str()))?;
+            stmt.bind(("$minute", *minute))?;
+            stmt.bind(("$accepted", counts.accepted as i64))?;
+            stmt.bind(("$delivered", counts.delivered as i64))?;
+            stmt.bind(("$deferred", counts.deferred as i64))?;
+            stmt.bind(("$bounced", counts.bounced as i64))?;
+            stmt.bind(("$complaint", counts.complaint as i64))?;
+            stmt.next()?;
+        }
+
+        let mut stmt = db.prepare("DELETE FROM analytics WHERE minute < $cutoff")?;
+        stmt.bind(("$cutoff", now - RETENTION_SECONDS))?;
+        stmt.next()?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => {
+            db.execute("COMMIT")?;
+            Ok(())
+        }
+        Err(err) => {
+            db.execute("ROLLBACK").ok();
+            Err(err)
+        }
+    }
+}
+
+/// Writes the changed counts to the database, if analytics
+/// have been configured
+pub fn flush() -> anyhow::Result<()> {
+    let Some(analytics) = ANALYTICS.get() else {
+        return Ok(());
+    };
+
+    let db = analytics.db.lock();
+    let now = Utc::now().timestamp();
+    let buckets = {
+        let mut rollup = analytics.rollup.lock();
+        rollup.prune(now);
+        rollup.take_dirty()
+    };
+    if buckets.is_empty() {
+        return Ok(());
+    }
+
+    if let Err(err) = store(&db, &buckets, now) {
+        // Try again on the next flush
+        let mut rollup = analytics.rollup.lock();
+        rollup.dirty.extend(buckets.into_iter().map(|(key, _)| key));
+        return Err(err);
+    }
+    Ok(())
+}
+
+async fn flusher(interval: Duration) {
+    let mut shutdown = ShutdownSubcription::get();
+    loop {
+        tokio::select! {
+            _ = shutdown.shutting_down() => {
+                // main flushes once more after the server has stopped
+                break;
+            },
+            _ = tokio::time::sleep(interval) => {}
+        };
+
+        match tokio::task::spawn_blocking(flush).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("Error flushing analytics: {err:#}"),
+            Err(err) => tracing::error!("Error flushing analytics: {err:#}"),
+        }
+    }
+}
+
+/// Called by the logging layer to count the outcome of a message,
+/// if analytics have been configured
+pub async fn record(
+    kind: RecordType,
+    msg: &Message,
+    provider: &str,
+    egress_pool: Option<&str>,
+    relay_disposition: Option<RelayDisposition>,
+) {
+    let Some(analytics) = ANALYTICS.get() else {
+        return;
+    };
+
+    let mut outcome = match kind {
+        RecordType::Reception => Outcome::Accepted,
+        RecordType::Delivery => Outcome::Delivered,
+        RecordType::TransientFailure => Outcome::Deferred,
+        RecordType::Bounce => Outcome::Bounced,
+        _ => return,
+    };
+
+    msg.load_meta_if_needed().await.ok();
+
+    let mut tenant = msg.get_queue_name().ok().and_then(|queue| {
+        QueueNameComponents::parse(&queue)
+            .tenant
+            .map(|t| t.to_string())
+    });
+
+    match relay_disposition {
+        Some(disposition) if kind == RecordType::Reception && disposition.log_arf => {
+            if let Ok(Some(report)) = msg.parse_rfc5965() {
+                // The report is attributed to the tenant of the original
+                // message, which is only known if it was included in the
+                // supplemental trace header
+                outcome = Outcome::Complaint;
+                tenant = report
+                    .supplemental_trace
+                    .as_ref()
+                    .and_then(|trace| trace.get("tenant"))
+                    .and_then(|tenant| tenant.as_str())
+                    .map(|tenant| tenant.to_string());
+            } else if !disposition.relay {
+                return;
+            }
+        }
+        // Messages that are accepted but not relayed, such as
+        // out-of-band bounce reports, are not counted
+        Some(disposition) if kind == RecordType::Reception && !disposition.relay => return,
+        _ => {}
+    }
+
+    let now = Utc::now().timestamp();
+    let counts = outcome.counts();
+    let mut rollup = analytics.rollup.lock();
+    if !matches!(outcome, Outcome::Accepted | Outcome::Complaint) {
+        rollup.add(AnalyticsDimension::Provider, provider, counts, now);
+        if let Some(pool) = egress_pool {
+            rollup.add(AnalyticsDimension::Pool, pool, counts, now);
+        }
+    }
+    if let Some(tenant) = &tenant {
+        rollup.add(AnalyticsDimension::Tenant, tenant, counts, now);
+    }
+}
+
+/// Returns the rolling counts that match the request
+pub fn query(request: &AnalyticsV1Request) -> anyhow::Result<Vec<AnalyticsV1Entry>> {
+    let analytics = ANALYTICS
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("analytics have not been configured"))?;
+    Ok(analytics
+        .rollup
+        .lock()
+        .summarize(request, Utc::now().timestamp()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn windows() {
+        let mut rollup = Rollup::new(10);
+        let delivered = Outcome::Delivered.counts();
+        rollup.add(AnalyticsDimension::Provider, "gmail", delivered, NOW);
+        rollup.add(AnalyticsDimension::Provider, "gmail", delivered, NOW - 600);
+        rollup.add(AnalyticsDimension::Provider, "gmail", delivered, NOW - 7200);
+        rollup.add(
+            AnalyticsDimension::Tenant,
+            "acme",
+            Outcome::Bounced.counts(),
+            NOW - 60,
+        );
+
+        let entries = rollup.summarize(&AnalyticsV1Request::default(), NOW);
+        assert_eq!(entries.len(), 2);
+        let gmail = &entries[0];
+        assert_eq!(gmail.name, "gmail");
+        assert_eq!(gmail.last_5m.delivered, 1);
+        assert_eq!(gmail.last_1h.delivered, 2);
+        assert_eq!(gmail.last_24h.delivered, 3);
+        assert_eq!(entries[1].last_5m.bounced, 1);
+
+        let request = AnalyticsV1Request {
+            dimension: Some(AnalyticsDimension::Tenant),
+            name: None,
+        };
+        assert_eq!(rollup.summarize(&request, NOW).len(), 1);
+
+        // Once the buckets are older than a day, they are pruned
+        rollup.prune(NOW + RETENTION_SECONDS + 60);
+        assert!(rollup.series.is_empty());
+        assert!(rollup.dirty.is_empty());
+    }
+
+    #[test]
+    fn name_limit() {
+        let mut rollup = Rollup::new(2);
+        let counts = Outcome::Deferred.counts();
+        for name in ["a", "b", "c", "d", "a"] {
+            rollup.add(AnalyticsDimension::Pool, name, counts, NOW);
+        }
+        let names: Vec<_> = rollup
+            .summarize(&AnalyticsV1Request::default(), NOW)
+            .into_iter()
+            .map(|entry| (entry.name, entry.last_5m.deferred))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("(other)".to_string(), 2),
+                ("a".to_string(), 2),
+                ("b".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn persistence() {
+        let params = AnalyticsParams {
+            path: ":memory:".into(),
+            flush_interval: AnalyticsParams::default_flush_interval(),
+            max_names: 10,
+        };
+        let db = open_analytics_db(&params).unwrap();
+
+        let mut rollup = Rollup::new(10);
+        rollup.add(
+            AnalyticsDimension::Provider,
+            "yahoo",
+            Outcome::Delivered.counts(),
+            NOW,
+        );
+        rollup.add(
+            AnalyticsDimension::Provider,
+            "yahoo",
+            Outcome::Deferred.counts(),
+            NOW - 2 * RETENTION_SECONDS,
+        );
+        store(&db, &rollup.take_dirty(), NOW).unwrap();
+        assert!(rollup.take_dirty().is_empty());
+
+        // Counts are replaced rather than accumulated when
+        // a bucket is written again
+        rollup.add(
+            AnalyticsDimension::Provider,
+            "yahoo",
+            Outcome::Delivered.counts(),
+            NOW,
+        );
+        store(&db, &rollup.take_dirty(), NOW).unwrap();
+
+        let mut loaded = Rollup::new(10);
+        load(&db, &mut loaded, NOW).unwrap();
+        let entries = loaded.summarize(&AnalyticsV1Request::default(), NOW);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].last_24h.delivered, 2);
+        assert_eq!(entries[0].last_24h.deferred, 0);
+    }
+}
@@ -0,0 +1,111 @@
+//! This module paces the transmission of message data according to
+//! the `max_bandwidth` of the egress path and the `tenant_max_bandwidth`
+//! of the queue, so that a small number of large messages cannot
+//! saturate a constrained uplink.
+use crate::queue::QueueManager;
+use kumo_api_types::egress_path::BandwidthLimit;
+use message::message::QueueNameComponents;
+use message::Message;
+use prometheus::IntCounterVec;
+use rfc5321::DataPacer;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+
+static BANDWIDTH_THROTTLED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "bandwidth_throttled",
+        "total number of times that sending message data was paused \
+         due to a bandwidth limit, by the kind of limit",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+#[derive(Debug)]
+struct Throttle {
+    kind: &'static str,
+    key: String,
+    limit: BandwidthLimit,
+}
+
+#[derive(Debug)]
+pub struct BandwidthPacer {
+    throttles: Vec<Throttle>,
+}
+
+impl BandwidthPacer {
+    /// Returns the pacer that applies to sending msg via the named
+    /// ready queue, or None if no bandwidth limits apply
+    pub fn for_message(
+        ready_queue_name: &str,
+        path_limit: Option<BandwidthLimit>,
+        msg: &Message,
+    ) -> Option<Arc<Self>> {
+        let mut throttles = vec![];
+        if let Some(limit) = path_limit {
+            throttles.push(Throttle {
+                kind: "egress_path",
+                key: format!("kumomta.max_bandwidth.{ready_queue_name}"),
+                limit,
+            });
+        }
+
+        if let Ok(queue_name) = msg.get_queue_name() {
+            let components = QueueNameComponents::parse(&queue_name);
+            if let Some(tenant) = components.tenant {
+                let limit = QueueManager::get_opt(&queue_name)
+                    .and_then(|queue| queue.get_config().borrow().tenant_max_bandwidth);
+                if let Some(limit) = limit {
+                    throttles.push(Throttle {
+                        kind: "tenant",
+                        key: format!("kumomta.tenant_max_bandwidth.{tenant}"),
+                        limit,
+                    });
+                }
+            }
+        }
+
+        if throttles.is_empty() {
+            None
+        } else {
+            Some(Arc::new(Self { throttles }))
+        }
+    }
+
+    async fn acquire(&self, len: usize) {
+        for throttle in &self.throttles {
+            let spec = throttle.limit.as_throttle_spec();
+            let burst = throttle.limit.burst();
+            let mut remaining = len as u64;
+            while remaining > 0 {
+                // A request larger than the burst can never succeed,
+                // so break it up
+                let quantity = remaining.min(burst);
+                match spec.throttle_quantity(&throttle.key, quantity).await {
+                    Ok(result) => match result.retry_after {
+                        Some(delay) => {
+                            BANDWIDTH_THROTTLED
+                                .with_label_values(&[throttle.kind])
+                                .inc();
+                            tokio::time::sleep(delay).await;
+                        }
+                        None => remaining -= quantity,
+                    },
+                    Err(err) => {
+                        // Don't hold up delivery if the throttle
+                        // is unavailable
+                        tracing::error!("bandwidth throttle {}: {err:#}", throttle.key);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DataPacer for BandwidthPacer {
+    fn pace(&self, len: usize) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.acquire(len))
+    }
+}
@@ -0,0 +1,336 @@
+//! This module implements Bounce Address Tag Validation (BATV),
+//! using the prvs scheme described in
+//! <https://datatracker.ietf.org/doc/html/draft-levine-smtp-batv-01>.
+//!
+//! The envelope sender of outbound messages is tagged with a
+//! signature and expiry day, in the form `prvs=KDDDSSSSSS=user@domain`.
+//! Legitimate bounces are sent to that tagged address, so bounces to
+//! addresses in the configured domains that lack a valid tag must be
+//! forged, and can be rejected at reception.
+use data_loader::KeySource;
+use message::EnvelopeAddress;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static BATV: OnceLock<Batv> = OnceLock::new();
+
+static BATV_BOUNCES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "batv_bounce_recipients",
+        "total number of bounce recipients checked for a BATV tag, by outcome",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BatvKey {
+    /// The key number, 0-9, which is encoded into the tag
+    pub id: u8,
+    pub key: KeySource,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BatvParams {
+    /// The signing keys. The first is used to tag senders,
+    /// and all of them are used to validate tags.
+    pub keys: Vec<BatvKey>,
+
+    /// The sender domains whose addresses are tagged, and for which
+    /// bounces are validated
+    pub domains: Vec<String>,
+
+    /// How long a tagged address remains valid
+    #[serde(default = "BatvParams::default_lifetime", with = "duration_serde")]
+    pub lifetime: Duration,
+}
+
+impl BatvParams {
+    fn default_lifetime() -> Duration {
+        Duration::from_secs(7 * 86400)
+    }
+}
+
+struct Batv {
+    keys: Vec<(u8, Vec<u8>)>,
+    domains: HashSet<String>,
+    lifetime_days: u32,
+}
+
+/// Configures BATV. This can only be called once.
+pub async fn configure_batv(params: BatvParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    anyhow::ensure!(!params.keys.is_empty(), "at least one key is required");
+    anyhow::ensure!(
+        !params.domains.is_empty(),
+        "at least one domain is required"
+    );
+    let lifetime_days = params.lifetime.as_secs().div_ceil(86400) as u32;
+    anyhow::ensure!(
+        (1..1000).contains(&lifetime_days),
+        "lifetime must be between 1 and 999 days"
+    );
+
+    let mut keys = vec![];
+    for key in &params.keys {
+        anyhow::ensure!(key.id < 10, "key id {} must be in the range 0-9", key.id);
+        anyhow::ensure!(
+            keys.iter().all(|(id, _)| *id != key.id),
+            "key id {} is used more than once",
+            key.id
+        );
+        keys.push((key.id, key.key.get().await?));
+    }
+
+    let batv = Batv {
+        keys,
+        domains: params
+            .domains
+            .iter()
+            .map(|domain| domain.to_ascii_lowercase())
+            .collect(),
+        lifetime_days,
+    };
+
+    BATV.set(batv)
+        .map_err(|_| anyhow::anyhow!("configure_batv has already been called"))
+}
+
+fn is_tagged(addr: &str) -> bool {
+    addr.get(0..5)
+        .map(|prefix| prefix.eq_ignore_ascii_case("prvs="))
+        .unwrap_or(false)
+}
+
+fn today() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() / 86400) as u32
+}
+
+impl Batv {
+    fn applies_to(&self, addr: &EnvelopeAddress) -> bool {
+        self.domains.contains(&addr.domain().to_ascii_lowercase())
+    }
+
+    /// Returns the first 3 bytes of the signature, as hex
+    fn signature(key: &[u8], id: u8, day: u32, addr: &str) -> anyhow::Result<String> {
+        let pkey = PKey::hmac(key)?;
+        let mut signer = Signer::new(MessageDigest::sha1(), &pkey)?;
+        signer.update(format!("{id}{day:03}{addr}").as_bytes())?;
+        let hmac = signer.sign_to_vec()?;
+        Ok(data_encoding::HEXLOWER.encode(&hmac[0..3]))
+    }
+
+    fn tag(&self, addr: &str, today: u32) -> anyhow::Result<String> {
+        let (id, key) = &self.keys[0];
+        let day = (today + self.lifetime_days) % 1000;
+        let signature = Self::signature(key, *id, day, addr)?;
+        Ok(format!("prvs={id}{day:03}{signature}={addr}"))
+    }
+
+    /// Returns Ok(None) if addr is not tagged, or the untagged
+    /// address if it has a valid tag
+    fn untag(&self, addr: &str, today: u32) -> Result<Option<String>, &'static str> {
+        if !is_tagged(addr) {
+            return Ok(None);
+        }
+        let (tag, original) = addr[5..]
+            .split_once('=')
+            .ok_or("malformed bounce address tag")?;
+        if tag.len() != 10 || !tag.is_ascii() {
+            return Err("malformed bounce address tag");
+        }
+
+        let id: u8 = tag[0..1]
+            .parse()
+            .map_err(|_| "malformed bounce address tag")?;
+        let day: u32 = tag[1..4]
+            .parse()
+            .map_err(|_| "malformed bounce address tag")?;
+
+        // The day is the expiry day, modulo 1000, so it must be
+        // between today and today + lifetime
+        let remaining = (day + 1000 - today % 1000) % 1000;
+        if remaining > self.lifetime_days {
+            return Err("bounce address tag has expired");
+        }
+
+        let (_, key) = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .ok_or("bounce address tag has an unknown key")?;
+        let expected =
+            Self::signature(key, id, day, original).map_err(|_| "failed to compute signature")?;
+        if !expected.eq_ignore_ascii_case(&tag[4..]) {
+            return Err("bounce address tag is invalid");
+        }
+
+        Ok(Some(original.to_string()))
+    }
+}
+
+/// Tags the sender of an outbound message, if BATV is configured
+/// and the sender is in one of the configured domains
+pub fn tag_sender(sender: EnvelopeAddress) -> anyhow::Result<EnvelopeAddress> {
+    let Some(batv) = BATV.get() else {
+        return Ok(sender);
+    };
+    let addr = sender.to_string();
+    if addr.is_empty() || !batv.applies_to(&sender) {
+        return Ok(sender);
+    }
+    // Don't tag an address twice, for example, when relaying
+    // a message that was already tagged
+    if is_tagged(&addr) {
+        return Ok(sender);
+    }
+    EnvelopeAddress::parse(&batv.tag(&addr, today())?)
+}
+
+/// Validates the tag of a recipient at reception.
+/// Returns Ok(Some(untagged)) if the recipient has a valid tag, which
+/// should be used in place of the recipient.
+/// Returns Ok(None) if the recipient is untagged, and doesn't need one.
+/// Returns Err if the message is a bounce to one of the configured
+/// domains that lacks a valid tag.
+pub fn check_recipient(
+    sender: &EnvelopeAddress,
+    recipient: &EnvelopeAddress,
+) -> Result<Option<EnvelopeAddress>, &'static str> {
+    let Some(batv) = BATV.get() else {
+        return Ok(None);
+    };
+    if !batv.applies_to(recipient) {
+        return Ok(None);
+    }
+    let untagged = batv
+        .untag(&recipient.to_string(), today())
+        .and_then(|untagged| {
+            untagged
+                .map(|untagged| {
+                    EnvelopeAddress::parse(&untagged).map_err(|_| "malformed bounce address tag")
+                })
+                .transpose()
+        });
+
+    if !sender.to_string().is_empty() {
+        // Not a bounce. Strip a valid tag, but otherwise let policy
+        // decide what to do, for example, with a reply to an
+        // address whose tag has expired
+        return Ok(untagged.ok().flatten());
+    }
+
+    let result = match untagged {
+        Ok(None) => Err("bounce address is not tagged"),
+        result => result,
+    };
+    BATV_BOUNCES
+        .with_label_values(&[match &result {
+            Ok(_) => "valid",
+            Err(_) => "rejected",
+        }])
+        .inc();
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batv() -> Batv {
+        Batv {
+            keys: vec![(1, b"new key".to_vec()), (0, b"old key".to_vec())],
+            domains: ["example.com".to_string()].into_iter().collect(),
+            lifetime_days: 7,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let batv = batv();
+        let today = 20000;
+        let tagged = batv.tag("user@example.com", today).unwrap();
+        assert!(tagged.starts_with("prvs=1007"), "{tagged}");
+        assert!(tagged.ends_with("=user@example.com"), "{tagged}");
+
+        for day in today..=today + 7 {
+            assert_eq!(
+                batv.untag(&tagged, day),
+                Ok(Some("user@example.com".to_string()))
+            );
+        }
+        assert_eq!(
+            batv.untag(&tagged, today + 8),
+            Err("bounce address tag has expired")
+        );
+        assert_eq!(
+            batv.untag(
+                &tagged
+                    .to_ascii_uppercase()
+                    .replace("USER@EXAMPLE.COM", "user@example.com"),
+                today
+            ),
+            Ok(Some("user@example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let batv = batv();
+        let old = Batv {
+            keys: vec![(0, b"old key".to_vec())],
+            ..batv()
+        };
+        let tagged = old.tag("user@example.com", 999).unwrap();
+        // Wraps around the 1000 day cycle
+        assert!(tagged.starts_with("prvs=0006"), "{tagged}");
+        assert_eq!(
+            batv.untag(&tagged, 1001),
+            Ok(Some("user@example.com".to_string()))
+        );
+
+        let unknown = Batv {
+            keys: vec![(5, b"old key".to_vec())],
+            ..batv()
+        };
+        let tagged = unknown.tag("user@example.com", 999).unwrap();
+        assert_eq!(
+            batv.untag(&tagged, 999),
+            Err("bounce address tag has an unknown key")
+        );
+    }
+
+    #[test]
+    fn forged() {
+        let batv = batv();
+        assert_eq!(batv.untag("user@example.com", 20000), Ok(None));
+        assert_eq!(
+            batv.untag("prvs=1007abcdef=user@example.com", 20000),
+            Err("bounce address tag is invalid")
+        );
+        assert_eq!(
+            batv.untag("prvs=10xyz=user@example.com", 20000),
+            Err("malformed bounce address tag")
+        );
+
+        // Changing the address invalidates the tag
+        let tagged = batv.tag("user@example.com", 20000).unwrap();
+        assert_eq!(
+            batv.untag(&tagged.replace("user@", "other@"), 20000),
+            Err("bounce address tag is invalid")
+        );
+    }
+}
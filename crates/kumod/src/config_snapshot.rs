@@ -0,0 +1,158 @@
+use crate::queue::{Queue, QueueManager};
+use crate::ready_queue::{ReadyQueueManager, GET_EGRESS_PATH_CONFIG_SIG};
+use config::load_config;
+use kumo_api_types::config_snapshot::{
+    redact_secrets, ConfigSnapshotV1, ConfigSnapshotV1EgressPath,
+};
+use kumo_api_types::egress_path::EgressPathConfig;
+use mlua::{Lua, LuaSerdeExt, Value};
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+/// The parameters that were passed to the functions that configure
+/// listeners and logging, which are not otherwise retained in a
+/// form that can be compared
+static RECORDED: LazyLock<Mutex<Recorded>> = LazyLock::new(Mutex::default);
+
+#[derive(Default)]
+struct Recorded {
+    listeners: BTreeMap<String, serde_json::Value>,
+    logging: BTreeMap<String, serde_json::Value>,
+}
+
+/// Converts a configuration value into the redacted form used
+/// by snapshots
+pub fn snapshot_value<T: Serialize>(value: &T) -> serde_json::Value {
+    let mut value =
+        serde_json::to_value(value).unwrap_or_else(|err| json!({"error": format!("{err:#}")}));
+    redact_secrets(&mut value);
+    value
+}
+
+fn lua_params_to_json(lua: &Lua, params: &Value) -> serde_json::Value {
+    // Functions, such as those used for callbacks in some
+    // parameters, cannot be represented and are omitted
+    let options = mlua::DeserializeOptions::new().deny_unsupported_types(false);
+    let mut value = lua
+        .from_value_with(params.clone(), options)
+        .unwrap_or_else(|err| json!({"error": format!("{err:#}")}));
+    redact_secrets(&mut value);
+    value
+}
+
+/// Inserts the value, disambiguating the key if it is already present
+fn insert_unique(
+    map: &mut BTreeMap<String, serde_json::Value>,
+    key: String,
+    value: serde_json::Value,
+) {
+    let mut unique = key.clone();
+    let mut n = 2;
+    while map.contains_key(&unique) {
+        unique = format!("{key}#{n}");
+        n += 1;
+    }
+    map.insert(unique, value);
+}
+
+/// Records the parameters of a listener, keyed by its kind and address
+pub fn record_listener(kind: &str, lua: &Lua, params: &Value) {
+    let value = lua_params_to_json(lua, params);
+    let listen = value
+        .get("listen")
+        .and_then(|listen| listen.as_str())
+        .unwrap_or_default()
+        .to_string();
+    insert_unique(
+        &mut RECORDED.lock().listeners,
+        format!("{kind}:{listen}"),
+        value,
+    );
+}
+
+/// Records the parameters of a logger, keyed by the name of the function
+/// that configured it, and its name or log directory
+pub fn record_logging(func: &str, lua: &Lua, params: &Value) {
+    let value = lua_params_to_json(lua, params);
+    let key = match ["name", "log_dir"]
+        .iter()
+        .find_map(|field| value.get(*field).and_then(|v| v.as_str()))
+    {
+        Some(id) => format!("{func}:{id}"),
+        None => func.to_string(),
+    };
+    insert_unique(&mut RECORDED.lock().logging, key, value);
+}
+
+fn recorded_snapshot() -> ConfigSnapshotV1 {
+    let recorded = RECORDED.lock();
+    ConfigSnapshotV1 {
+        listeners: recorded.listeners.clone(),
+        logging: recorded.logging.clone(),
+        ..Default::default()
+    }
+}
+
+/// Returns the configuration that is currently in effect
+pub fn running_snapshot() -> ConfigSnapshotV1 {
+    let mut snapshot = recorded_snapshot();
+
+    for name in QueueManager::all_queue_names() {
+        if let Some(queue) = QueueManager::get_opt(&name) {
+            let value = snapshot_value(&**queue.get_config().borrow());
+            snapshot.queues.insert(name, value);
+        }
+    }
+
+    snapshot.egress_paths = ReadyQueueManager::all_queues()
+        .iter()
+        .map(|queue| queue.egress_path_snapshot())
+        .collect();
+    snapshot.egress_paths.sort_by_key(|path| path.key());
+    snapshot.egress_paths.dedup_by_key(|path| path.key());
+
+    snapshot
+}
+
+/// Evaluates the policy to produce the configuration of the queues and
+/// egress paths that are listed in `reference`, which is typically a
+/// snapshot that was obtained from a running node.
+pub async fn resolve_snapshot(reference: &ConfigSnapshotV1) -> anyhow::Result<ConfigSnapshotV1> {
+    let mut snapshot = recorded_snapshot();
+    let mut config = load_config().await?;
+
+    for name in reference.queues.keys() {
+        let value = match Queue::call_get_queue_config(name, &mut config).await {
+            Ok(queue_config) => snapshot_value(&queue_config),
+            Err(err) => json!({"error": format!("{err:#}")}),
+        };
+        snapshot.queues.insert(name.to_string(), value);
+    }
+
+    for path in &reference.egress_paths {
+        let result: anyhow::Result<EgressPathConfig> = config
+            .async_call_callback(
+                &GET_EGRESS_PATH_CONFIG_SIG,
+                (
+                    path.routing_domain.clone(),
+                    path.egress_source.clone(),
+                    path.site_name.clone(),
+                ),
+            )
+            .await;
+        snapshot.egress_paths.push(ConfigSnapshotV1EgressPath {
+            routing_domain: path.routing_domain.clone(),
+            egress_source: path.egress_source.clone(),
+            site_name: path.site_name.clone(),
+            config: match result {
+                Ok(path_config) => snapshot_value(&path_config),
+                Err(err) => json!({"error": format!("{err:#}")}),
+            },
+        });
+    }
+
+    Ok(snapshot)
+}
@@ -0,0 +1,737 @@
+//! Delivery Status Notification support, as described by RFC 3461
+//! and RFC 3464.
+//!
+//! The NOTIFY, ORCPT, RET and ENVID ESMTP parameters that are received
+//! by the ESMTP listener are recorded in the metadata of the message,
+//! so that they can be passed on to the next hop when it also supports
+//! DSN, and so that we can decide whether to generate a notification
+//! when delivery of the message fails or is delayed.
+use crate::logging::disposition::{RecordType, ResolvedAddress};
+use crate::queue::QueueManager;
+use chrono::{DateTime, Utc};
+use config::{any_err, from_lua_value, get_or_create_module};
+use message::{EnvelopeAddress, Message};
+use mlua::{Lua, Value};
+use rfc5321::{EsmtpParameter, Response};
+use serde::Deserialize;
+use spool::SpoolId;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+static PARAMS: OnceLock<DsnGenerationParams> = OnceLock::new();
+
+pub const META_RET: &str = "dsn_ret";
+pub const META_ENVID: &str = "dsn_envid";
+pub const META_NOTIFY: &str = "dsn_notify";
+pub const META_ORCPT: &str = "dsn_orcpt";
+/// Tracks how many of the delay thresholds have already been
+/// notified for a message
+const META_DELAY_NOTIFIED: &str = "dsn_delay_notified";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DsnRet {
+    Full,
+    Hdrs,
+}
+
+impl DsnRet {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Full => "FULL",
+            Self::Hdrs => "HDRS",
+        }
+    }
+}
+
+impl FromStr for DsnRet {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.eq_ignore_ascii_case("FULL") {
+            Ok(Self::Full)
+        } else if s.eq_ignore_ascii_case("HDRS") {
+            Ok(Self::Hdrs)
+        } else {
+            anyhow::bail!("invalid RET value {s}")
+        }
+    }
+}
+
+/// The conditions under which a notification has been requested.
+/// NOTIFY=NEVER is represented by all of the fields being false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DsnNotify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl DsnNotify {
+    /// The conditions that apply when no NOTIFY parameter
+    /// was specified, as suggested by RFC 3461 section 4.1
+    pub fn rfc_default() -> Self {
+        Self {
+            success: false,
+            failure: true,
+            delay: true,
+        }
+    }
+}
+
+impl FromStr for DsnNotify {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s.eq_ignore_ascii_case("NEVER") {
+            return Ok(Self::default());
+        }
+        let mut result = Self::default();
+        for word in s.split(',') {
+            let flag = if word.eq_ignore_ascii_case("SUCCESS") {
+                &mut result.success
+            } else if word.eq_ignore_ascii_case("FAILURE") {
+                &mut result.failure
+            } else if word.eq_ignore_ascii_case("DELAY") {
+                &mut result.delay
+            } else {
+                anyhow::bail!("invalid NOTIFY value {s}");
+            };
+            *flag = true;
+        }
+        Ok(result)
+    }
+}
+
+impl TryFrom<String> for DsnNotify {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for DsnNotify {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut words = vec![];
+        if self.success {
+            words.push("SUCCESS");
+        }
+        if self.failure {
+            words.push("FAILURE");
+        }
+        if self.delay {
+            words.push("DELAY");
+        }
+        if words.is_empty() {
+            write!(fmt, "NEVER")
+        } else {
+            write!(fmt, "{}", words.join(","))
+        }
+    }
+}
+
+/// Decodes the xtext encoding defined by RFC 3461 section 4
+pub fn xtext_decode(text: &str) -> anyhow::Result<String> {
+    let mut result = vec![];
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'+' {
+            let hex = [
+                bytes.next().unwrap_or_default(),
+                bytes.next().unwrap_or_default(),
+            ];
+            let hex = std::str::from_utf8(&hex)?;
+            anyhow::ensure!(
+                hex.bytes()
+                    .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase()),
+                "invalid xtext hexchar in {text}"
+            );
+            result.push(u8::from_str_radix(hex, 16)?);
+        } else {
+            anyhow::ensure!(
+                (b'!'..=b'~').contains(&b) && b != b'=',
+                "invalid xtext character in {text}"
+            );
+            result.push(b);
+        }
+    }
+    Ok(String::from_utf8(result)?)
+}
+
+/// Applies the xtext encoding defined by RFC 3461 section 4
+pub fn xtext_encode(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for b in text.bytes() {
+        if (b'!'..=b'~').contains(&b) && b != b'+' && b != b'=' {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("+{b:02X}"));
+        }
+    }
+    result
+}
+
+fn parameter_value<'a>(param: &'a EsmtpParameter) -> anyhow::Result<&'a str> {
+    param
+        .value
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("{} requires a value", param.name))
+}
+
+/// The DSN parameters of the MAIL FROM command
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DsnMailParams {
+    pub ret: Option<DsnRet>,
+    pub envid: Option<String>,
+}
+
+impl DsnMailParams {
+    pub fn parse(parameters: &[EsmtpParameter]) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+        for param in parameters {
+            if param.name.eq_ignore_ascii_case("RET") {
+                anyhow::ensure!(result.ret.is_none(), "duplicate RET parameter");
+                result.ret.replace(parameter_value(param)?.parse()?);
+            } else if param.name.eq_ignore_ascii_case("ENVID") {
+                anyhow::ensure!(result.envid.is_none(), "duplicate ENVID parameter");
+                result.envid.replace(xtext_decode(parameter_value(param)?)?);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn apply_to_message(&self, msg: &Message) -> anyhow::Result<()> {
+        if let Some(ret) = self.ret {
+            msg.set_meta(META_RET, ret.as_str())?;
+        }
+        if let Some(envid) = &self.envid {
+            msg.set_meta(META_ENVID, envid.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// The DSN parameters of a RCPT TO command
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DsnRcptParams {
+    pub notify: Option<DsnNotify>,
+    /// The decoded ORCPT parameter, such as `rfc822;user@example.com`
+    pub orcpt: Option<String>,
+}
+
+impl DsnRcptParams {
+    pub fn parse(parameters: &[EsmtpParameter]) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+        for param in parameters {
+            if param.name.eq_ignore_ascii_case("NOTIFY") {
+                anyhow::ensure!(result.notify.is_none(), "duplicate NOTIFY parameter");
+                result.notify.replace(parameter_value(param)?.parse()?);
+            } else if param.name.eq_ignore_ascii_case("ORCPT") {
+                anyhow::ensure!(result.orcpt.is_none(), "duplicate ORCPT parameter");
+                let value = parameter_value(param)?;
+                let (addr_type, addr) = value
+                    .split_once(';')
+                    .ok_or_else(|| anyhow::anyhow!("invalid ORCPT value {value}"))?;
+                result
+                    .orcpt
+                    .replace(format!("{addr_type};{}", xtext_decode(addr)?));
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn apply_to_message(&self, msg: &Message) -> anyhow::Result<()> {
+        if let Some(notify) = self.notify {
+            msg.set_meta(META_NOTIFY, notify.to_string())?;
+        }
+        if let Some(orcpt) = &self.orcpt {
+            msg.set_meta(META_ORCPT, orcpt.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+fn meta_string(msg: &Message, key: &str) -> Option<String> {
+    msg.get_meta_string(key).ok().flatten()
+}
+
+/// Returns the parameters to pass with MAIL FROM when relaying msg
+/// to a host that supports the DSN extension
+pub fn mail_parameters(msg: &Message) -> Vec<EsmtpParameter> {
+    let mut parameters = vec![];
+    if let Some(ret) = meta_string(msg, META_RET) {
+        parameters.push(EsmtpParameter {
+            name: "RET".to_string(),
+            value: Some(ret),
+        });
+    }
+    if let Some(envid) = meta_string(msg, META_ENVID) {
+        parameters.push(EsmtpParameter {
+            name: "ENVID".to_string(),
+            value: Some(xtext_encode(&envid)),
+        });
+    }
+    parameters
+}
+
+/// Returns the parameters to pass with RCPT TO when relaying msg
+/// to a host that supports the DSN extension
+pub fn rcpt_parameters(msg: &Message) -> Vec<EsmtpParameter> {
+    let mut parameters = vec![];
+    if let Some(notify) = meta_string(msg, META_NOTIFY) {
+        parameters.push(EsmtpParameter {
+            name: "NOTIFY".to_string(),
+            value: Some(notify),
+        });
+    }
+    if let Some(orcpt) = meta_string(msg, META_ORCPT) {
+        if let Some((addr_type, addr)) = orcpt.split_once(';') {
+            parameters.push(EsmtpParameter {
+                name: "ORCPT".to_string(),
+                value: Some(format!("{addr_type};{}", xtext_encode(addr))),
+            });
+        }
+    }
+    parameters
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DsnGenerationParams {
+    /// The name that is reported in the Reporting-MTA field
+    pub reporting_mta: String,
+
+    /// The From header of the notifications. Defaults to
+    /// `MAILER-DAEMON@<reporting_mta>`
+    #[serde(default)]
+    pub from: Option<String>,
+
+    /// When a message has been in the queue for longer than each
+    /// of these durations, a delay notification is generated
+    #[serde(default, with = "duration_serde")]
+    pub delay_thresholds: Vec<Duration>,
+
+    /// The conditions that apply to messages that were received
+    /// without a NOTIFY parameter, including those that were
+    /// injected via HTTP. When not set, notifications are only
+    /// generated for messages that requested them.
+    #[serde(default)]
+    pub default_notify: Option<DsnNotify>,
+}
+
+impl DsnGenerationParams {
+    fn from_header(&self) -> String {
+        match &self.from {
+            Some(from) => from.to_string(),
+            None => format!("MAILER-DAEMON@{}", self.reporting_mta),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DsnAction {
+    Failed,
+    Delayed,
+}
+
+impl DsnAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Failed => "failed",
+            Self::Delayed => "delayed",
+        }
+    }
+}
+
+struct ReportInfo<'a> {
+    action: DsnAction,
+    recipient: String,
+    envid: Option<String>,
+    orcpt: Option<String>,
+    ret: Option<DsnRet>,
+    arrival_date: DateTime<Utc>,
+    response: &'a Response,
+    remote_mta: Option<&'a str>,
+    now: DateTime<Utc>,
+}
+
+/// Returns the header portion of a message
+fn message_headers(data: &[u8]) -> &[u8] {
+    match memchr::memmem::find(data, b"\r\n\r\n") {
+        Some(idx) => &data[..idx + 2],
+        None => data,
+    }
+}
+
+fn build_report(
+    params: &DsnGenerationParams,
+    info: &ReportInfo,
+    original_sender: &str,
+    original: &[u8],
+) -> Vec<u8> {
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let reporting_mta = &params.reporting_mta;
+    let recipient = &info.recipient;
+    let diagnostic = info.response.to_single_line();
+
+    let (subject, explanation) = match info.action {
+        DsnAction::Failed => (
+            "Delivery Status Notification (Failure)",
+            format!("Your message to <{recipient}> could not be delivered."),
+        ),
+        DsnAction::Delayed => (
+            "Delivery Status Notification (Delay)",
+            format!(
+                "Your message to <{recipient}> has not yet been delivered.\r\n\
+                 Delivery will continue to be attempted; you do not need\r\n\
+                 to resend it."
+            ),
+        ),
+    };
+
+    let mut report = format!(
+        "From: {from}\r\n\
+         To: <{original_sender}>\r\n\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}@{reporting_mta}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=us-ascii\r\n\
+         \r\n\
+         This is the mail system at {reporting_mta}.\r\n\
+         \r\n\
+         {explanation}\r\n\
+         \r\n\
+         The last response was:\r\n\
+         \r\n    {diagnostic}\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: message/delivery-status\r\n\
+         \r\n\
+         Reporting-MTA: dns; {reporting_mta}\r\n",
+        from = params.from_header(),
+        date = info.now.to_rfc2822(),
+        id = SpoolId::new(),
+    );
+
+    if let Some(envid) = &info.envid {
+        report.push_str(&format!("Original-Envelope-Id: {envid}\r\n"));
+    }
+    report.push_str(&format!(
+        "Arrival-Date: {}\r\n\r\n",
+        info.arrival_date.to_rfc2822()
+    ));
+
+    if let Some(orcpt) = &info.orcpt {
+        report.push_str(&format!("Original-Recipient: {orcpt}\r\n"));
+    }
+    let status = match &info.response.enhanced_code {
+        Some(enh) => format!("{}.{}.{}", enh.class, enh.subject, enh.detail),
+        None => match info.action {
+            DsnAction::Failed => "5.0.0".to_string(),
+            DsnAction::Delayed => "4.0.0".to_string(),
+        },
+    };
+    report.push_str(&format!(
+        "Final-Recipient: rfc822; {recipient}\r\n\
+         Action: {action}\r\n\
+         Status: {status}\r\n",
+        action = info.action.as_str()
+    ));
+    if let Some(remote_mta) = info.remote_mta {
+        report.push_str(&format!("Remote-MTA: dns; {remote_mta}\r\n"));
+    }
+    report.push_str(&format!(
+        "Diagnostic-Code: smtp; {diagnostic}\r\n\
+         Last-Attempt-Date: {}\r\n\
+         \r\n",
+        info.now.to_rfc2822()
+    ));
+
+    let mut data = report.into_bytes();
+    if info.ret == Some(DsnRet::Full) {
+        data.extend_from_slice(
+            format!("--{boundary}\r\nContent-Type: message/rfc822\r\n\r\n").as_bytes(),
+        );
+        data.extend_from_slice(original);
+    } else {
+        data.extend_from_slice(
+            format!("--{boundary}\r\nContent-Type: text/rfc822-headers\r\n\r\n").as_bytes(),
+        );
+        data.extend_from_slice(message_headers(original));
+    }
+    if !data.ends_with(b"\r\n") {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    data
+}
+
+/// Determines whether a notification should be generated for the
+/// disposition of msg and, if so, which action it reports
+fn action_for_disposition(
+    params: &DsnGenerationParams,
+    kind: RecordType,
+    msg: &Message,
+    notify: DsnNotify,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<DsnAction>> {
+    match kind {
+        RecordType::Bounce | RecordType::Expiration if notify.failure => {
+            Ok(Some(DsnAction::Failed))
+        }
+        RecordType::TransientFailure if notify.delay && !params.delay_thresholds.is_empty() => {
+            let age = msg.age(now).to_std().unwrap_or_default();
+            let crossed = params
+                .delay_thresholds
+                .iter()
+                .filter(|threshold| age >= **threshold)
+                .count() as u64;
+            let notified = msg.get_meta(META_DELAY_NOTIFIED)?.as_u64().unwrap_or(0);
+            if crossed > notified {
+                msg.set_meta(META_DELAY_NOTIFIED, crossed)?;
+                Ok(Some(DsnAction::Delayed))
+            } else {
+                Ok(None)
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn generate_dsn(
+    params: &DsnGenerationParams,
+    kind: RecordType,
+    msg: &Message,
+    response: &Response,
+    peer_address: Option<&ResolvedAddress>,
+) -> anyhow::Result<()> {
+    msg.load_meta_if_needed().await?;
+
+    let sender = msg.sender()?;
+    if sender == EnvelopeAddress::null_sender() {
+        // Never notify about a notification
+        return Ok(());
+    }
+
+    let notify = match meta_string(msg, META_NOTIFY) {
+        Some(notify) => notify.parse()?,
+        None => match params.default_notify {
+            Some(notify) => notify,
+            None => return Ok(()),
+        },
+    };
+
+    let now = Utc::now();
+    let Some(action) = action_for_disposition(params, kind, msg, notify, now)? else {
+        return Ok(());
+    };
+
+    msg.load_data_if_needed().await?;
+    let info = ReportInfo {
+        action,
+        recipient: msg.recipient()?.to_string(),
+        envid: meta_string(msg, META_ENVID),
+        orcpt: meta_string(msg, META_ORCPT),
+        ret: meta_string(msg, META_RET).and_then(|ret| ret.parse().ok()),
+        arrival_date: msg.id().created(),
+        response,
+        remote_mta: peer_address.map(|addr| addr.name.as_str()),
+        now,
+    };
+    let data = build_report(params, &info, &sender.to_string(), &msg.get_data());
+
+    let report = Message::new_dirty(
+        SpoolId::new(),
+        EnvelopeAddress::null_sender(),
+        sender,
+        serde_json::json!({"reception_protocol": "DSN"}),
+        Arc::new(data.into_boxed_slice()),
+    )?;
+    let queue_name = report.get_queue_name()?;
+    report.save().await?;
+    QueueManager::insert_or_unwind(&queue_name, report, false).await
+}
+
+/// Generates a delivery status notification for the disposition of
+/// msg, if DSN generation has been enabled via
+/// `kumo.configure_dsn_generation` and the disposition warrants one.
+/// Errors are logged rather than propagated.
+pub async fn generate_for_disposition(
+    kind: RecordType,
+    msg: &Message,
+    response: &Response,
+    peer_address: Option<&ResolvedAddress>,
+) {
+    let Some(params) = PARAMS.get() else {
+        return;
+    };
+    if !matches!(
+        kind,
+        RecordType::Bounce | RecordType::Expiration | RecordType::TransientFailure
+    ) {
+        return;
+    }
+    if let Err(err) = generate_dsn(params, kind, msg, response, peer_address).await {
+        tracing::error!(
+            "failed to generate delivery status notification for {}: {err:#}",
+            msg.id()
+        );
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let kumo_mod = get_or_create_module(lua, "kumo")?;
+
+    kumo_mod.set(
+        "configure_dsn_generation",
+        lua.create_function(|lua, params: Value| {
+            let params: DsnGenerationParams = from_lua_value(lua, params)?;
+            if config::is_validating() {
+                return Ok(());
+            }
+            PARAMS
+                .set(params)
+                .map_err(|_| anyhow::anyhow!("dsn generation already configured"))
+                .map_err(any_err)
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kumo_log_types::rfc3464::{Report, ReportAction};
+    use rfc5321::EnhancedStatusCode;
+
+    fn param(name: &str, value: &str) -> EsmtpParameter {
+        EsmtpParameter {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn xtext() {
+        assert_eq!(
+            xtext_encode("user+tag@example.com"),
+            "user+2Btag@example.com"
+        );
+        assert_eq!(xtext_encode("a=b c"), "a+3Db+20c");
+        assert_eq!(
+            xtext_decode("user+2Btag@example.com").unwrap(),
+            "user+tag@example.com"
+        );
+        assert!(xtext_decode("user+2b").is_err());
+        assert!(xtext_decode("user+2").is_err());
+        assert!(xtext_decode("a=b").is_err());
+    }
+
+    #[test]
+    fn parse_parameters() {
+        let mail =
+            DsnMailParams::parse(&[param("ret", "hdrs"), param("ENVID", "QQ314159+2B1")]).unwrap();
+        assert_eq!(
+            mail,
+            DsnMailParams {
+                ret: Some(DsnRet::Hdrs),
+                envid: Some("QQ314159+1".to_string()),
+            }
+        );
+        assert!(DsnMailParams::parse(&[param("RET", "BOGUS")]).is_err());
+        assert!(DsnMailParams::parse(&[param("RET", "FULL"), param("RET", "HDRS")]).is_err());
+
+        let rcpt = DsnRcptParams::parse(&[
+            param("NOTIFY", "FAILURE,DELAY"),
+            param("ORCPT", "rfc822;user+2Btag@example.com"),
+        ])
+        .unwrap();
+        assert_eq!(
+            rcpt,
+            DsnRcptParams {
+                notify: Some(DsnNotify::rfc_default()),
+                orcpt: Some("rfc822;user+tag@example.com".to_string()),
+            }
+        );
+        assert_eq!(
+            DsnRcptParams::parse(&[param("NOTIFY", "NEVER")])
+                .unwrap()
+                .notify
+                .unwrap()
+                .to_string(),
+            "NEVER"
+        );
+        assert!(DsnRcptParams::parse(&[param("NOTIFY", "NEVER,FAILURE")]).is_err());
+        assert!(DsnRcptParams::parse(&[param("ORCPT", "user@example.com")]).is_err());
+
+        // Parameters for other extensions are ignored
+        assert_eq!(
+            DsnMailParams::parse(&[param("SIZE", "1000")]).unwrap(),
+            DsnMailParams::default()
+        );
+    }
+
+    #[test]
+    fn report_round_trip() {
+        let params = DsnGenerationParams {
+            reporting_mta: "mta.example.com".to_string(),
+            from: None,
+            delay_thresholds: vec![],
+            default_notify: None,
+        };
+        let response = Response {
+            code: 550,
+            enhanced_code: Some(EnhancedStatusCode {
+                class: 5,
+                subject: 1,
+                detail: 1,
+            }),
+            content: "no such user".to_string(),
+            command: None,
+        };
+        let info = ReportInfo {
+            action: DsnAction::Failed,
+            recipient: "user@example.net".to_string(),
+            envid: Some("QQ314159".to_string()),
+            orcpt: Some("rfc822;alias@example.net".to_string()),
+            ret: Some(DsnRet::Hdrs),
+            arrival_date: "2024-01-01T00:00:00Z".parse().unwrap(),
+            response: &response,
+            remote_mta: Some("mx.example.net"),
+            now: "2024-01-01T00:05:00Z".parse().unwrap(),
+        };
+        let original = b"Subject: hello\r\nFrom: sender@example.com\r\n\r\nThe body\r\n";
+        let data = build_report(&params, &info, "sender@example.com", original);
+
+        let report = Report::parse(&data).unwrap().unwrap();
+        assert_eq!(report.per_message.reporting_mta.name, "mta.example.com");
+        assert_eq!(
+            report.per_message.original_envelope_id.as_deref(),
+            Some("QQ314159")
+        );
+        assert_eq!(report.per_message.arrival_date, Some(info.arrival_date));
+        assert_eq!(report.per_recipient.len(), 1);
+        let recip = &report.per_recipient[0];
+        assert_eq!(recip.action, ReportAction::Failed);
+        assert_eq!(recip.final_recipient.recipient, "user@example.net");
+        assert_eq!(
+            recip.original_recipient.as_ref().unwrap().recipient,
+            "alias@example.net"
+        );
+        assert_eq!(
+            recip.diagnostic_code.as_ref().unwrap().diagnostic,
+            "550 5.1.1 no such user"
+        );
+        assert_eq!(recip.last_attempt_date, Some(info.now));
+        assert_eq!(
+            report.original_message.as_deref(),
+            Some("Subject: hello\nFrom: sender@example.com\n")
+        );
+    }
+}
@@ -0,0 +1,446 @@
+//! Validates that the egress sources are usable before mail is sent
+//! from them: that the source address can be bound on this host,
+//! that it has a PTR record, that the PTR record matches the EHLO
+//! name, and that the PTR record resolves back to the address.
+//! Receivers commonly penalize sources that fail any of these,
+//! so it is better to find out before the traffic is sent.
+
+use crate::egress_source::{EgressPool, EgressSource};
+use chrono::Utc;
+use dns_resolver::Resolver;
+use kumo_api_types::egress_preflight::{
+    EgressPreflightCheck, EgressPreflightStatus, EgressPreflightV1CheckResult,
+    EgressPreflightV1Report, EgressPreflightV1Request, EgressPreflightV1Source,
+};
+use parking_lot::Mutex;
+use prometheus::IntGaugeVec;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, OnceLock};
+use tokio::net::TcpSocket;
+
+static PARAMS: OnceLock<EgressPreflightParams> = OnceLock::new();
+static LAST_REPORT: LazyLock<Mutex<Option<EgressPreflightV1Report>>> =
+    LazyLock::new(Mutex::default);
+
+static PREFLIGHT_OK: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "egress_source_preflight_ok",
+        "whether a preflight check passed (1) or failed (0) for an egress source",
+        &["source", "check"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct EgressPreflightParams {
+    /// The names of the egress sources to validate
+    #[serde(default)]
+    pub sources: Vec<String>,
+
+    /// The names of the egress pools whose sources should be validated
+    #[serde(default)]
+    pub pools: Vec<String>,
+}
+
+pub fn configure_egress_preflight(params: EgressPreflightParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    PARAMS
+        .set(params)
+        .map_err(|_| anyhow::anyhow!("configure_egress_preflight has already been called"))
+}
+
+/// Returns the most recently produced report, if any
+pub fn last_report() -> Option<EgressPreflightV1Report> {
+    LAST_REPORT.lock().clone()
+}
+
+/// Spawns a validation of the configured sources, if any were configured
+pub fn run_at_startup() -> anyhow::Result<()> {
+    let Some(params) = PARAMS.get() else {
+        return Ok(());
+    };
+    let request = EgressPreflightV1Request {
+        sources: params.sources.clone(),
+        pools: params.pools.clone(),
+    };
+    kumo_server_runtime::rt_spawn("egress preflight", async move {
+        if let Err(err) = run(request).await {
+            tracing::error!("egress preflight: {err:#}");
+        }
+    })?;
+    Ok(())
+}
+
+/// Validates the requested sources, or the configured sources if
+/// the request is empty, and records the results in the metrics
+pub async fn run(request: EgressPreflightV1Request) -> anyhow::Result<EgressPreflightV1Report> {
+    let request = if request.is_empty() {
+        let Some(params) = PARAMS.get() else {
+            anyhow::bail!(
+                "no sources or pools were specified, and \
+                 kumo.configure_egress_preflight has not been called"
+            );
+        };
+        EgressPreflightV1Request {
+            sources: params.sources.clone(),
+            pools: params.pools.clone(),
+        }
+    } else {
+        request
+    };
+
+    let mut config = config::load_config().await?;
+    let mut errors = vec![];
+    let mut names = BTreeSet::new();
+    names.extend(request.sources.iter().cloned());
+    for pool_name in &request.pools {
+        match EgressPool::resolve(Some(pool_name), &mut config).await {
+            Ok(pool) => names.extend(pool.entries.into_iter().map(|entry| entry.name)),
+            Err(err) => errors.push(format!("pool {pool_name}: {err:#}")),
+        }
+    }
+
+    let resolver = dns_resolver::get_resolver();
+    let mut sources = vec![];
+    for name in names {
+        let source = match EgressSource::resolve(&name, &mut config).await {
+            Ok(source) => source,
+            Err(err) => {
+                errors.push(format!("source {name}: {err:#}"));
+                continue;
+            }
+        };
+        let result = check_source(&**resolver, &source).await;
+        for check in &result.checks {
+            let value = match check.status {
+                EgressPreflightStatus::Pass => 1,
+                EgressPreflightStatus::Fail => 0,
+                EgressPreflightStatus::Skipped => continue,
+            };
+            PREFLIGHT_OK
+                .with_label_values(&[&result.name, check.check.as_str()])
+                .set(value);
+            if check.status == EgressPreflightStatus::Fail {
+                tracing::warn!(
+                    "egress preflight: source {} failed {} check: {}",
+                    result.name,
+                    check.check.as_str(),
+                    check.detail
+                );
+            }
+        }
+        sources.push(result);
+    }
+
+    for error in &errors {
+        tracing::error!("egress preflight: {error}");
+    }
+
+    let report = EgressPreflightV1Report {
+        checked: Utc::now(),
+        sources,
+        errors,
+    };
+    LAST_REPORT.lock().replace(report.clone());
+    Ok(report)
+}
+
+async fn check_source(resolver: &dyn Resolver, source: &EgressSource) -> EgressPreflightV1Source {
+    // This matches the default used by the smtp dispatcher; an
+    // ehlo_domain in the egress path config can override it for
+    // specific destinations, which is not considered here
+    let ehlo_domain = match &source.ehlo_domain {
+        Some(name) => name.to_string(),
+        None => gethostname::gethostname()
+            .to_str()
+            .unwrap_or("[127.0.0.1]")
+            .to_string(),
+    };
+
+    // When a proxy is used, the connection originates from the
+    // address that the proxy binds, which is not local to us
+    let (address, bind_locally) = match (
+        source.ha_proxy_source_address,
+        source.socks5_proxy_source_address,
+    ) {
+        (Some(addr), _) | (None, Some(addr)) => (Some(addr), false),
+        (None, None) => (source.source_address, true),
+    };
+
+    let checks = match address {
+        Some(address) => check_address(resolver, address, &ehlo_domain, bind_locally).await,
+        None => [
+            EgressPreflightCheck::Bind,
+            EgressPreflightCheck::Ptr,
+            EgressPreflightCheck::PtrMatchesEhlo,
+            EgressPreflightCheck::ForwardConfirmed,
+        ]
+        .into_iter()
+        .map(|check| {
+            skipped(
+                check,
+                "no source address is configured; the host default is used",
+            )
+        })
+        .collect(),
+    };
+
+    EgressPreflightV1Source {
+        name: source.name.clone(),
+        address,
+        ehlo_domain,
+        checks,
+    }
+}
+
+fn result(
+    check: EgressPreflightCheck,
+    status: EgressPreflightStatus,
+    detail: impl Into<String>,
+) -> EgressPreflightV1CheckResult {
+    EgressPreflightV1CheckResult {
+        check,
+        status,
+        detail: detail.into(),
+    }
+}
+
+fn skipped(check: EgressPreflightCheck, detail: &str) -> EgressPreflightV1CheckResult {
+    result(check, EgressPreflightStatus::Skipped, detail)
+}
+
+/// Normalizes a DNS name for comparison
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+async fn check_address(
+    resolver: &dyn Resolver,
+    address: IpAddr,
+    ehlo_domain: &str,
+    bind_locally: bool,
+) -> Vec<EgressPreflightV1CheckResult> {
+    let mut checks = vec![];
+
+    checks.push(if bind_locally {
+        match check_bind(address) {
+            Ok(()) => result(
+                EgressPreflightCheck::Bind,
+                EgressPreflightStatus::Pass,
+                format!("{address} can be bound"),
+            ),
+            Err(err) => result(
+                EgressPreflightCheck::Bind,
+                EgressPreflightStatus::Fail,
+                format!("{address} cannot be bound: {err:#}"),
+            ),
+        }
+    } else {
+        skipped(
+            EgressPreflightCheck::Bind,
+            "the address is bound by the proxy server",
+        )
+    });
+
+    let ptr_names: Vec<String> = match resolver.resolve_ptr(address).await {
+        Ok(names) if names.is_empty() => {
+            checks.push(result(
+                EgressPreflightCheck::Ptr,
+                EgressPreflightStatus::Fail,
+                format!("{address} has no PTR record"),
+            ));
+            vec![]
+        }
+        Ok(names) => names
+            .iter()
+            .map(|name| normalize_name(&name.to_utf8()))
+            .collect(),
+        Err(err) => {
+            checks.push(result(
+                EgressPreflightCheck::Ptr,
+                EgressPreflightStatus::Fail,
+                format!("resolving PTR for {address}: {err:#}"),
+            ));
+            vec![]
+        }
+    };
+
+    if ptr_names.is_empty() {
+        checks.push(skipped(
+            EgressPreflightCheck::PtrMatchesEhlo,
+            "there is no PTR record",
+        ));
+        checks.push(skipped(
+            EgressPreflightCheck::ForwardConfirmed,
+            "there is no PTR record",
+        ));
+        return checks;
+    }
+
+    let ptr_list = ptr_names.join(", ");
+    checks.push(result(
+        EgressPreflightCheck::Ptr,
+        EgressPreflightStatus::Pass,
+        format!("{address} resolves to {ptr_list}"),
+    ));
+
+    let ehlo = normalize_name(ehlo_domain);
+    checks.push(if ptr_names.contains(&ehlo) {
+        result(
+            EgressPreflightCheck::PtrMatchesEhlo,
+            EgressPreflightStatus::Pass,
+            format!("PTR matches EHLO name {ehlo_domain}"),
+        )
+    } else {
+        result(
+            EgressPreflightCheck::PtrMatchesEhlo,
+            EgressPreflightStatus::Fail,
+            format!("PTR {ptr_list} does not match EHLO name {ehlo_domain}"),
+        )
+    });
+
+    let mut confirmed = None;
+    let mut problems = vec![];
+    for name in &ptr_names {
+        match resolver.resolve_ip(name).await {
+            Ok(addrs) if addrs.contains(&address) => {
+                confirmed.replace(name);
+                break;
+            }
+            Ok(addrs) if addrs.is_empty() => problems.push(format!("{name} has no addresses")),
+            Ok(addrs) => problems.push(format!(
+                "{name} resolves to {}",
+                addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            Err(err) => problems.push(format!("resolving {name}: {err:#}")),
+        }
+    }
+    checks.push(match confirmed {
+        Some(name) => result(
+            EgressPreflightCheck::ForwardConfirmed,
+            EgressPreflightStatus::Pass,
+            format!("{name} resolves to {address}"),
+        ),
+        None => result(
+            EgressPreflightCheck::ForwardConfirmed,
+            EgressPreflightStatus::Fail,
+            format!("no PTR name resolves to {address}: {}", problems.join("; ")),
+        ),
+    });
+
+    checks
+}
+
+fn check_bind(address: IpAddr) -> std::io::Result<()> {
+    let socket = match address {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(address, 0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dns_resolver::TestResolver;
+    use EgressPreflightStatus::*;
+
+    fn statuses(checks: &[EgressPreflightV1CheckResult]) -> Vec<EgressPreflightStatus> {
+        checks.iter().map(|check| check.status).collect()
+    }
+
+    fn resolver() -> TestResolver {
+        TestResolver::default()
+            .with_zone(
+                r#"
+$ORIGIN 2.0.192.in-addr.arpa.
+10 600 PTR mta1.example.com.
+11 600 PTR mta2.example.com.
+"#,
+            )
+            .with_zone(
+                r#"
+$ORIGIN example.com.
+mta1 600 A 192.0.2.10
+mta2 600 A 192.0.2.99
+"#,
+            )
+    }
+
+    #[tokio::test]
+    async fn all_pass() {
+        let checks = check_address(
+            &resolver(),
+            "192.0.2.10".parse().unwrap(),
+            "MTA1.example.com",
+            false,
+        )
+        .await;
+        assert_eq!(statuses(&checks), vec![Skipped, Pass, Pass, Pass]);
+    }
+
+    #[tokio::test]
+    async fn mismatched_ehlo() {
+        let checks = check_address(
+            &resolver(),
+            "192.0.2.10".parse().unwrap(),
+            "mta2.example.com",
+            false,
+        )
+        .await;
+        assert_eq!(statuses(&checks), vec![Skipped, Pass, Fail, Pass]);
+    }
+
+    #[tokio::test]
+    async fn not_forward_confirmed() {
+        let checks = check_address(
+            &resolver(),
+            "192.0.2.11".parse().unwrap(),
+            "mta2.example.com",
+            false,
+        )
+        .await;
+        assert_eq!(statuses(&checks), vec![Skipped, Pass, Pass, Fail]);
+        assert_eq!(
+            checks[3].detail,
+            "no PTR name resolves to 192.0.2.11: mta2.example.com resolves to 192.0.2.99"
+        );
+    }
+
+    #[tokio::test]
+    async fn no_ptr() {
+        let checks = check_address(
+            &resolver(),
+            "192.0.2.12".parse().unwrap(),
+            "mta1.example.com",
+            false,
+        )
+        .await;
+        assert_eq!(statuses(&checks), vec![Skipped, Fail, Skipped, Skipped]);
+    }
+
+    #[tokio::test]
+    async fn bind() {
+        let checks =
+            check_address(&resolver(), "127.0.0.1".parse().unwrap(), "localhost", true).await;
+        assert_eq!(checks[0].status, Pass);
+
+        let checks = check_address(
+            &resolver(),
+            "192.0.2.10".parse().unwrap(),
+            "mta1.example.com",
+            true,
+        )
+        .await;
+        assert_eq!(checks[0].status, Fail);
+    }
+}
@@ -1,6 +1,8 @@
 use crate::http_server::admin_suspend_ready_q_v1::AdminSuspendReadyQEntry;
+use crate::proxy_health::{self, ProxyHealthParams, ProxyKind, ProxyServerEntry, SelectedProxy};
 use crate::queue::QueueConfig;
 use crate::ready_queue::{ReadyQueueManager, ReadyQueueName};
+use crate::warmup::WarmupSchedule;
 use anyhow::Context;
 use config::{CallbackSignature, LuaConfig};
 use data_loader::KeySource;
@@ -10,7 +12,7 @@ use kumo_server_common::config_handle::ConfigHandle;
 use lruttl::LruCacheWithTtl;
 use mlua::prelude::LuaUserData;
 use parking_lot::FairMutex as Mutex;
-use prometheus::IntCounter;
+use prometheus::{IntCounter, IntCounterVec};
 use serde::{Deserialize, Serialize};
 use socksv5::v5::{
     SocksV5AuthMethod, SocksV5Command, SocksV5Host, SocksV5RequestStatus, SocksV5Response,
@@ -63,8 +65,93 @@ pub struct EgressSource {
     pub socks5_proxy_username: Option<String>,
     pub socks5_proxy_password: Option<KeySource>,
 
+    /// Additional haproxy servers that may be used, each with a
+    /// relative weight. Connections are spread across the healthy
+    /// servers, including ha_proxy_server if it is set.
+    #[serde(default)]
+    pub ha_proxy_servers: Vec<ProxyServerEntry>,
+
+    /// Additional SOCKS5 servers that may be used, each with a
+    /// relative weight. Connections are spread across the healthy
+    /// servers, including socks5_proxy_server if it is set.
+    #[serde(default)]
+    pub socks5_proxy_servers: Vec<ProxyServerEntry>,
+
+    /// Controls how proxy servers that are failing are taken
+    /// out of rotation, and how they are probed for recovery
+    #[serde(default)]
+    pub proxy_health: ProxyHealthParams,
+
     #[serde(default = "default_ttl", with = "duration_serde")]
     pub ttl: Duration,
+
+    /// When set, the source is warming up, and the volume that
+    /// it sends is limited according to this schedule
+    #[serde(default)]
+    pub warmup: Option<WarmupSchedule>,
+
+    /// Bind to a local port in this range, rather than one from
+    /// the kernel's ephemeral port range
+    #[serde(default)]
+    pub local_port_range: Option<PortRange>,
+
+    /// Bind the socket to this network interface (SO_BINDTODEVICE)
+    #[serde(default)]
+    pub bind_device: Option<String>,
+
+    /// Set this firewall mark on the socket (SO_MARK), for use
+    /// with policy routing
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl PortRange {
+    /// Binds socket to ip and a port from this range, starting at a
+    /// random port and trying each in turn until one is available
+    fn bind(&self, socket: &TcpSocket, ip: IpAddr) -> std::io::Result<()> {
+        let span = u32::from(self.max - self.min) + 1;
+        let start = rand::random::<u32>() % span;
+        for i in 0..span {
+            let port = self.min + ((start + i) % span) as u16;
+            match socket.bind(SocketAddr::new(ip, port)) {
+                Ok(()) => return Ok(()),
+                Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!(
+                "all ports in local_port_range {}-{} are in use",
+                self.min, self.max
+            ),
+        ))
+    }
+}
+
+static PORT_EXHAUSTION: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "egress_source_port_exhaustion",
+        "how many times a connection could not be made because no local \
+         port was available, by source address",
+        &["source_address"]
+    )
+    .unwrap()
+});
+
+/// Returns true if err indicates that no local port was available
+fn is_port_exhaustion(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable
+    )
 }
 
 impl LuaUserData for EgressSource {}
@@ -87,7 +174,14 @@ impl EgressSource {
                 socks5_proxy_source_address: None,
                 socks5_proxy_username: None,
                 socks5_proxy_password: None,
+                ha_proxy_servers: vec![],
+                socks5_proxy_servers: vec![],
+                proxy_health: ProxyHealthParams::default(),
                 source_address: None,
+                warmup: None,
+                local_port_range: None,
+                bind_device: None,
+                fwmark: None,
             }
         } else {
             let sig = CallbackSignature::<String, EgressSource>::new("get_egress_source");
@@ -97,6 +191,14 @@ impl EgressSource {
                 .with_context(|| format!("get_egress_source '{name}'"))?
         };
 
+        if let Some(range) = &source.local_port_range {
+            anyhow::ensure!(
+                range.min > 0 && range.min <= range.max,
+                "source:{name}: local_port_range min must be non-zero \
+                 and no larger than max"
+            );
+        }
+
         SOURCES.lock().insert(
             name.to_string(),
             source.clone(),
@@ -106,12 +208,47 @@ impl EgressSource {
         Ok(source)
     }
 
-    fn resolve_proxy_protocol(&self, address: SocketAddr) -> anyhow::Result<ProxyProto> {
+    /// Returns the kind of proxy used by this source, along with the
+    /// candidate proxy servers, or None if it doesn't use a proxy
+    fn proxy_servers(&self) -> Option<(ProxyKind, Vec<ProxyServerEntry>)> {
+        fn merge(
+            single: Option<SocketAddr>,
+            multiple: &[ProxyServerEntry],
+        ) -> Vec<ProxyServerEntry> {
+            single
+                .map(|server| ProxyServerEntry { server, weight: 1 })
+                .into_iter()
+                .chain(multiple.iter().cloned())
+                .collect()
+        }
+
+        if self.ha_proxy_source_address.is_some() {
+            let servers = merge(self.ha_proxy_server, &self.ha_proxy_servers);
+            if !servers.is_empty() {
+                return Some((ProxyKind::HaProxy, servers));
+            }
+        }
+
+        if self.socks5_proxy_source_address.is_some() {
+            let servers = merge(self.socks5_proxy_server, &self.socks5_proxy_servers);
+            if !servers.is_empty() {
+                return Some((ProxyKind::Socks5, servers));
+            }
+        }
+
+        None
+    }
+
+    fn resolve_proxy_protocol(
+        &self,
+        address: SocketAddr,
+        proxy: Option<(ProxyKind, SocketAddr)>,
+    ) -> anyhow::Result<ProxyProto> {
         use ppp::v2::{Addresses, IPv4, IPv6};
         let source_name = &self.name;
 
-        match (self.ha_proxy_server, self.ha_proxy_source_address) {
-            (Some(server), Some(source)) => match (source, address) {
+        match (proxy, self.ha_proxy_source_address) {
+            (Some((ProxyKind::HaProxy, server)), Some(source)) => match (source, address) {
                 (IpAddr::V4(src_ip), SocketAddr::V4(dest_ip)) => {
                     return Ok(ProxyProto::HA {
                         server,
@@ -145,8 +282,8 @@ impl EgressSource {
             _ => {}
         };
 
-        match (self.socks5_proxy_server, self.socks5_proxy_source_address) {
-            (Some(server), Some(source)) => match (source, address) {
+        match (proxy, self.socks5_proxy_source_address) {
+            (Some((ProxyKind::Socks5, server)), Some(source)) => match (source, address) {
                 (IpAddr::V6(_), SocketAddr::V6(_)) | (IpAddr::V4(_), SocketAddr::V4(_)) => {
                     return Ok(ProxyProto::Socks5 {
                         server,
@@ -175,10 +312,58 @@ impl EgressSource {
         &self,
         address: SocketAddr,
         timeout_duration: Duration,
+    ) -> anyhow::Result<(TcpStream, MaybeProxiedSourceAddress)> {
+        let Some((kind, servers)) = self.proxy_servers() else {
+            let proxy_proto = self.resolve_proxy_protocol(address, None)?;
+            return self
+                .connect_via(address, proxy_proto, None, timeout_duration)
+                .await;
+        };
+
+        // Try each of the healthy proxies in turn, so that a proxy
+        // that is down doesn't cause the connection attempt to fail
+        let candidates = proxy_health::select_servers(kind, &servers, &self.proxy_health);
+        let mut errors = vec![];
+        for proxy in candidates {
+            let proxy_proto = self.resolve_proxy_protocol(address, Some((kind, proxy.server)))?;
+            match self
+                .connect_via(address, proxy_proto, Some(&proxy), timeout_duration)
+                .await
+            {
+                Ok(result) => return Ok(result),
+                Err(err) => {
+                    if proxy.is_failed() {
+                        // Couldn't reach the proxy; try the next one
+                        errors.push(format!("{err:#}"));
+                        continue;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            anyhow::bail!(
+                "source:{} has no proxy servers with a non-zero weight",
+                self.name
+            );
+        }
+        anyhow::bail!(
+            "failed to connect via any proxy server for source:{}: {}",
+            self.name,
+            errors.join(", ")
+        );
+    }
+
+    async fn connect_via(
+        &self,
+        address: SocketAddr,
+        proxy_proto: ProxyProto<'_>,
+        proxy: Option<&SelectedProxy>,
+        timeout_duration: Duration,
     ) -> anyhow::Result<(TcpStream, MaybeProxiedSourceAddress)> {
         let source_name = &self.name;
 
-        let proxy_proto = self.resolve_proxy_protocol(address)?;
         let transport_address = proxy_proto.transport_address(address);
 
         let transport_context = format!("{transport_address:?} {proxy_proto:?}");
@@ -195,8 +380,42 @@ impl EgressSource {
         // No need for Nagle with SMTP request/response
         socket.set_nodelay(true)?;
 
-        if let Some(source) = self.source_address {
-            if let Err(err) = socket.bind(SocketAddr::new(source, 0)) {
+        if let Some(device) = &self.bind_device {
+            socket
+                .bind_device(Some(device.as_bytes()))
+                .with_context(|| format!("bind_device {device} for source:{source_name} failed"))?;
+        }
+
+        if let Some(mark) = self.fwmark {
+            nix::sys::socket::setsockopt(&socket, nix::sys::socket::sockopt::Mark, &mark)
+                .with_context(|| format!("set fwmark {mark} for source:{source_name} failed"))?;
+        }
+
+        let bind_address = match (self.source_address, &self.local_port_range) {
+            (Some(source), _) => Some(source),
+            // Binding to a port requires an address, so use the
+            // unspecified address of the appropriate family
+            (None, Some(_)) => Some(match transport_address {
+                SocketAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+            }),
+            (None, None) => None,
+        };
+        let port_metric_label = bind_address
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unspecified".to_string());
+
+        if let Some(source) = bind_address {
+            let result = match &self.local_port_range {
+                Some(range) => range.bind(&socket, source),
+                None => socket.bind(SocketAddr::new(source, 0)),
+            };
+            if let Err(err) = result {
+                if is_port_exhaustion(&err) {
+                    PORT_EXHAUSTION
+                        .with_label_values(&[&port_metric_label])
+                        .inc();
+                }
                 let error = format!(
                     "bind {source:?} for source:{source_name} failed: {err:#} \
                     while attempting to connect to {connect_context}"
@@ -221,18 +440,33 @@ impl EgressSource {
             {
                 Err(_) => {
                     inc_failed_proxy_connection_attempts(is_proxy);
+                    if let Some(proxy) = proxy {
+                        proxy.record_failure();
+                    }
                     anyhow::bail!(
                         "timeout after {timeout_duration:?} \
                          while connecting to {transport_context}"
                     );
                 }
                 Ok(Err(err)) => {
+                    if is_port_exhaustion(&err) {
+                        PORT_EXHAUSTION
+                            .with_label_values(&[&port_metric_label])
+                            .inc();
+                    }
                     inc_failed_proxy_connection_attempts(is_proxy);
+                    if let Some(proxy) = proxy {
+                        proxy.record_failure();
+                    }
                     anyhow::bail!("failed to connect to {transport_context}: {err:#}");
                 }
                 Ok(Ok(stream)) => stream,
             };
 
+        if let Some(proxy) = proxy {
+            proxy.record_success();
+        }
+
         let source_address = tokio::time::timeout_at(
             deadline.into(),
             proxy_proto.perform_handshake(&mut stream, &source_name),
@@ -560,6 +794,31 @@ mod test {
     use super::*;
     use std::collections::HashMap;
 
+    #[tokio::test]
+    async fn port_range() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let range = PortRange {
+            min: port,
+            max: port,
+        };
+
+        let first = TcpSocket::new_v4().unwrap();
+        range.bind(&first, localhost).unwrap();
+        assert_eq!(first.local_addr().unwrap().port(), port);
+
+        let second = TcpSocket::new_v4().unwrap();
+        let err = range.bind(&second, localhost).unwrap_err();
+        assert!(is_port_exhaustion(&err), "{err:#}");
+
+        drop(first);
+        range.bind(&second, localhost).unwrap();
+    }
+
     #[test]
     fn round_robin() {
         let pool = EgressPool {
@@ -0,0 +1,247 @@
+//! Processing of ARF (RFC 5965) feedback reports, which are received
+//! either via an ESMTP listener domain that has `log_arf` enabled,
+//! or by watching a maildir into which the reports are delivered.
+use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
+use crate::smtp_server::RelayDisposition;
+use anyhow::Context;
+use config::{
+    any_err, from_lua_value, get_or_create_module, load_config, CallbackSignature,
+    SerdeWrappedValue,
+};
+use kumo_log_types::rfc5965::ARFReport;
+use kumo_server_lifecycle::ShutdownSubcription;
+use kumo_server_runtime::spawn;
+use message::{EnvelopeAddress, Message};
+use mlua::{Lua, Value};
+use rfc5321::Response;
+use serde::Deserialize;
+use spool::SpoolId;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+pub static FEEDBACK_REPORT_SIG: LazyLock<
+    CallbackSignature<(Message, SerdeWrappedValue<ARFReport>), ()>,
+> = LazyLock::new(|| CallbackSignature::new_with_multiple("feedback_report_received"));
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FeedbackMaildirParams {
+    /// The maildir into which the reports are delivered.
+    /// Reports are read from its `new` subdirectory.
+    pub path: PathBuf,
+
+    /// How often to check for new reports
+    #[serde(
+        default = "FeedbackMaildirParams::default_poll_interval",
+        with = "duration_serde"
+    )]
+    pub poll_interval: Duration,
+
+    /// Remove each report once it has been processed, rather than
+    /// moving it into the `cur` subdirectory
+    #[serde(default)]
+    pub delete_processed: bool,
+}
+
+impl FeedbackMaildirParams {
+    fn default_poll_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+/// Calls the `feedback_report_received` event for a report that
+/// was parsed from msg. Errors are logged rather than propagated,
+/// as the report has already been accepted.
+pub async fn dispatch_feedback_report(msg: &Message, report: ARFReport) {
+    let result = async {
+        let mut config = load_config().await?;
+        config
+            .async_call_callback(
+                &FEEDBACK_REPORT_SIG,
+                (msg.clone(), SerdeWrappedValue(report)),
+            )
+            .await
+    };
+    if let Err(err) = result.await {
+        tracing::error!(
+            "Error in feedback_report_received event for {}: {err:#}",
+            msg.id()
+        );
+    }
+}
+
+/// Returns the envelope address corresponding to the single
+/// address in the named header, if any
+fn header_address(msg: &Message, header_name: &str) -> Option<EnvelopeAddress> {
+    let list = msg.get_address_header(header_name).ok()??;
+    let addr = list.single_address_string().ok()?;
+    EnvelopeAddress::parse(addr).ok()
+}
+
+/// Processes a single message that was read from the maildir.
+/// Returns false if it is not an ARF report.
+async fn process_report_file(data: &[u8]) -> anyhow::Result<bool> {
+    let data = mailparsing::normalize_crlf(data);
+    let msg = Message::new_dirty(
+        SpoolId::new(),
+        EnvelopeAddress::null_sender(),
+        EnvelopeAddress::null_sender(),
+        serde_json::json!({}),
+        Arc::new(data.into_boxed_slice()),
+    )?;
+
+    // There is no envelope for a message in a maildir, so use
+    // the addresses from its headers instead
+    if let Some(sender) = header_address(&msg, "From") {
+        msg.set_sender(sender)?;
+    }
+    if let Some(recipient) = header_address(&msg, "To") {
+        msg.set_recipient(recipient)?;
+    }
+    msg.set_meta("reception_protocol", "Maildir")?;
+    msg.set_meta("queue", "null")?;
+
+    let Some(report) = msg.parse_rfc5965()? else {
+        return Ok(false);
+    };
+
+    log_disposition(LogDisposition {
+        kind: RecordType::Reception,
+        msg: msg.clone(),
+        site: "",
+        peer_address: None,
+        response: Response {
+            code: 250,
+            enhanced_code: None,
+            command: None,
+            content: "".to_string(),
+        },
+        egress_pool: None,
+        egress_source: None,
+        relay_disposition: Some(RelayDisposition {
+            relay: false,
+            log_arf: true,
+            log_oob: false,
+        }),
+        delivery_protocol: None,
+        tls_info: None,
+        source_address: None,
+        provider: None,
+        session_id: None,
+    })
+    .await;
+
+    dispatch_feedback_report(&msg, report).await;
+    Ok(true)
+}
+
+/// Processes each of the messages in the `new` subdirectory of the
+/// maildir, then moves them into `cur`, or removes them, so that
+/// they are not processed again.
+async fn process_maildir(params: &FeedbackMaildirParams) -> anyhow::Result<()> {
+    let new_dir = params.path.join("new");
+    let cur_dir = params.path.join("cur");
+
+    let mut entries = tokio::fs::read_dir(&new_dir)
+        .await
+        .with_context(|| format!("reading {}", new_dir.display()))?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+
+        let data = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?;
+        match process_report_file(&data).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!("{} is not an ARF feedback report", path.display());
+            }
+            Err(err) => {
+                tracing::error!("failed to process {}: {err:#}", path.display());
+            }
+        }
+
+        if params.delete_processed {
+            tokio::fs::remove_file(&path)
+                .await
+                .with_context(|| format!("removing {}", path.display()))?;
+        } else {
+            tokio::fs::rename(&path, cur_dir.join(seen_name(&path)))
+                .await
+                .with_context(|| format!("moving {} to {}", path.display(), cur_dir.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the name under which a message from `new` is stored in
+/// `cur`, which has the maildir Seen flag
+fn seen_name(path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if name.contains(":2,") {
+        name
+    } else {
+        format!("{name}:2,S")
+    }
+}
+
+async fn watch_maildir(params: FeedbackMaildirParams) {
+    let mut shutdown = ShutdownSubcription::get();
+    loop {
+        if let Err(err) = process_maildir(&params).await {
+            tracing::error!("feedback maildir {}: {err:#}", params.path.display());
+        }
+        tokio::select! {
+            _ = shutdown.shutting_down() => return,
+            _ = tokio::time::sleep(params.poll_interval) => {}
+        };
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    FEEDBACK_REPORT_SIG.register();
+
+    let kumo_mod = get_or_create_module(lua, "kumo")?;
+    kumo_mod.set(
+        "start_feedback_maildir",
+        lua.create_function(|lua, params: Value| {
+            let params: FeedbackMaildirParams = from_lua_value(lua, params)?;
+            if config::is_validating() {
+                return Ok(());
+            }
+            spawn(
+                format!("feedback maildir {}", params.path.display()),
+                watch_maildir(params),
+            )
+            .map_err(any_err)?;
+            Ok(())
+        })?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maildir_seen_name() {
+        assert_eq!(
+            seen_name(Path::new("/maildir/new/1700000000.M1P2.host")),
+            "1700000000.M1P2.host:2,S"
+        );
+        assert_eq!(
+            seen_name(Path::new("/maildir/new/1700000000.M1P2.host:2,")),
+            "1700000000.M1P2.host:2,"
+        );
+    }
+}
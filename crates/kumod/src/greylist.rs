@@ -0,0 +1,505 @@
+//! The purpose of this module is to provide greylisting for inbound
+//! listeners: the first attempt to deliver a message for a given
+//! (client network, sender, recipient) tuple is temporarily rejected,
+//! and accepted once the client retries after a delay.
+//! Legitimate MTAs retry, while many sources of spam do not.
+//!
+//! Client networks that repeatedly pass greylisting are automatically
+//! whitelisted, so that well-behaved sources are only delayed until
+//! they have established themselves.
+//!
+//! The state is held in memory, or in redis so that it can be shared
+//! by all of the instances that receive mail for the same domains.
+
+use chrono::Utc;
+use message::EnvelopeAddress;
+use mod_redis::{cmd, FromRedisValue, RedisConnKey, RedisConnection};
+use prometheus::IntCounterVec;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+static GREYLIST: OnceLock<Greylist> = OnceLock::new();
+
+static GREYLIST_DECISIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "greylist_decisions_count",
+        "total number of greylisting decisions, by the reason for the decision",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GreylistParams {
+    /// How long a client must wait before a retry is accepted
+    #[serde(
+        default = "GreylistParams::default_initial_delay",
+        with = "duration_serde"
+    )]
+    pub initial_delay: Duration,
+
+    /// How long after the first attempt a retry will be accepted.
+    /// Retries after this time are treated as a new first attempt.
+    #[serde(
+        default = "GreylistParams::default_retry_window",
+        with = "duration_serde"
+    )]
+    pub retry_window: Duration,
+
+    /// How long a tuple that has passed greylisting is remembered,
+    /// and how long a client network remains whitelisted, since it
+    /// was last seen
+    #[serde(default = "GreylistParams::default_pass_ttl", with = "duration_serde")]
+    pub pass_ttl: Duration,
+
+    /// The number of tuples that must pass greylisting from a client
+    /// network before it is whitelisted. 0 disables whitelisting.
+    #[serde(default = "GreylistParams::default_auto_whitelist_after")]
+    pub auto_whitelist_after: u64,
+
+    /// The prefix length of the IPv4 client network
+    #[serde(default = "GreylistParams::default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+
+    /// The prefix length of the IPv6 client network
+    #[serde(default = "GreylistParams::default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+
+    /// The number of tuples and networks held in memory when
+    /// redis is not used
+    #[serde(default = "GreylistParams::default_capacity")]
+    pub capacity: usize,
+
+    /// If set, the state is held in this redis instance, so that
+    /// it is shared with the other instances that use it
+    #[serde(default)]
+    pub redis: Option<RedisConnKey>,
+
+    /// The prefix for the redis keys that hold the state
+    #[serde(default = "GreylistParams::default_redis_key_prefix")]
+    pub redis_key_prefix: String,
+}
+
+impl GreylistParams {
+    fn default_initial_delay() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    fn default_retry_window() -> Duration {
+        Duration::from_secs(4 * 3600)
+    }
+
+    fn default_pass_ttl() -> Duration {
+        Duration::from_secs(36 * 86400)
+    }
+
+    fn default_auto_whitelist_after() -> u64 {
+        5
+    }
+
+    fn default_ipv4_prefix() -> u8 {
+        24
+    }
+
+    fn default_ipv6_prefix() -> u8 {
+        64
+    }
+
+    fn default_capacity() -> usize {
+        100_000
+    }
+
+    fn default_redis_key_prefix() -> String {
+        "kumo-greylist".to_string()
+    }
+}
+
+/// What we know about a (client network, sender, recipient) tuple
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct TupleState {
+    /// The unix timestamp of the first attempt
+    first_seen: i64,
+    /// A retry was accepted, so further attempts are accepted
+    passed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GreylistDecision {
+    /// The client network has been whitelisted
+    Whitelisted,
+    /// The tuple passed greylisting previously
+    Known,
+    /// The client retried after the initial delay
+    Retried,
+    /// The attempt is deferred; the client should retry after
+    /// the specified number of seconds
+    Deferred { retry_after: u64 },
+}
+
+impl GreylistDecision {
+    pub fn accepted(&self) -> bool {
+        !matches!(self, Self::Deferred { .. })
+    }
+
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::Whitelisted => "whitelisted",
+            Self::Known => "known",
+            Self::Retried => "retried",
+            Self::Deferred { .. } => "deferred",
+        }
+    }
+}
+
+/// Decides the outcome of an attempt for a tuple, returning the
+/// new state that should be stored for it, along with its ttl
+fn decide(
+    params: &GreylistParams,
+    state: Option<TupleState>,
+    now: i64,
+) -> (GreylistDecision, TupleState, Duration) {
+    let delay = params.initial_delay.as_secs() as i64;
+    let window = params.retry_window.as_secs() as i64;
+
+    match state {
+        Some(state) if state.passed => (GreylistDecision::Known, state, params.pass_ttl),
+        Some(state) if now < state.first_seen + delay => (
+            GreylistDecision::Deferred {
+                retry_after: (state.first_seen + delay - now) as u64,
+            },
+            state,
+            params.retry_window,
+        ),
+        Some(state) if now <= state.first_seen + window => (
+            GreylistDecision::Retried,
+            TupleState {
+                first_seen: state.first_seen,
+                passed: true,
+            },
+            params.pass_ttl,
+        ),
+        _ => (
+            GreylistDecision::Deferred {
+                retry_after: delay as u64,
+            },
+            TupleState {
+                first_seen: now,
+                passed: false,
+            },
+            params.retry_window,
+        ),
+    }
+}
+
+/// Returns the client network that contains addr, as a string
+fn client_network(params: &GreylistParams, addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => {
+            let prefix = params.ipv4_prefix.min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            let net = std::net::Ipv4Addr::from(u32::from(v4) & mask);
+            format!("{net}/{prefix}")
+        }
+        IpAddr::V6(v6) => {
+            let prefix = params.ipv6_prefix.min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            let net = std::net::Ipv6Addr::from(u128::from(v6) & mask);
+            format!("{net}/{prefix}")
+        }
+    }
+}
+
+enum Store {
+    Memory {
+        tuples: lruttl::LruCacheWithTtl<String, TupleState>,
+        networks: lruttl::LruCacheWithTtl<String, u64>,
+    },
+    Redis(RedisConnection),
+}
+
+struct Greylist {
+    params: GreylistParams,
+    store: Store,
+}
+
+/// Enables greylisting for the listeners that opt into it.
+/// This can only be called once.
+pub fn configure_greylisting(params: GreylistParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    if GREYLIST.get().is_some() {
+        anyhow::bail!("configure_greylisting has already been called");
+    }
+    anyhow::ensure!(
+        params.retry_window > params.initial_delay,
+        "retry_window must be longer than initial_delay"
+    );
+
+    let store = match &params.redis {
+        Some(key) => Store::Redis(key.open()?),
+        None => Store::Memory {
+            tuples: lruttl::LruCacheWithTtl::new_named("greylist_tuples", params.capacity),
+            networks: lruttl::LruCacheWithTtl::new_named("greylist_networks", params.capacity),
+        },
+    };
+
+    GREYLIST
+        .set(Greylist { params, store })
+        .map_err(|_| anyhow::anyhow!("configure_greylisting has already been called"))
+}
+
+impl Greylist {
+    fn tuple_key(&self, network: &str, sender: &str, recipient: &str) -> String {
+        format!(
+            "{}:tuple:{network}:{sender}:{recipient}",
+            self.params.redis_key_prefix
+        )
+    }
+
+    fn network_key(&self, network: &str) -> String {
+        format!("{}:network:{network}", self.params.redis_key_prefix)
+    }
+
+    async fn get_network_count(&self, network: &str) -> anyhow::Result<u64> {
+        match &self.store {
+            Store::Memory { networks, .. } => Ok(networks.get(network).unwrap_or(0)),
+            Store::Redis(redis) => {
+                let mut get = cmd("GET");
+                get.arg(self.network_key(network));
+                let count: Option<u64> =
+                    FromRedisValue::from_redis_value(&redis.query(get).await?)?;
+                Ok(count.unwrap_or(0))
+            }
+        }
+    }
+
+    /// Records that a tuple from the network passed greylisting
+    async fn increment_network(&self, network: &str) -> anyhow::Result<()> {
+        let ttl = self.params.pass_ttl;
+        match &self.store {
+            Store::Memory { networks, .. } => {
+                let count = networks.get(network).unwrap_or(0) + 1;
+                networks.insert(network.to_string(), count, Instant::now() + ttl);
+            }
+            Store::Redis(redis) => {
+                let key = self.network_key(network);
+                let mut incr = cmd("INCR");
+                incr.arg(&key);
+                redis.query(incr).await?;
+                let mut expire = cmd("PEXPIRE");
+                expire.arg(&key).arg(ttl.as_millis() as u64);
+                redis.query(expire).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_tuple(&self, key: &str) -> anyhow::Result<Option<TupleState>> {
+        match &self.store {
+            Store::Memory { tuples, .. } => Ok(tuples.get(key)),
+            Store::Redis(redis) => {
+                let mut get = cmd("GET");
+                get.arg(key);
+                let value: Option<String> =
+                    FromRedisValue::from_redis_value(&redis.query(get).await?)?;
+                Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+            }
+        }
+    }
+
+    async fn set_tuple(&self, key: String, state: TupleState, ttl: Duration) -> anyhow::Result<()> {
+        match &self.store {
+            Store::Memory { tuples, .. } => {
+                tuples.insert(key, state, Instant::now() + ttl);
+            }
+            Store::Redis(redis) => {
+                let mut set = cmd("SET");
+                set.arg(key)
+                    .arg(serde_json::to_string(&state)?)
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64);
+                redis.query(set).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check(
+        &self,
+        peer: IpAddr,
+        sender: &str,
+        recipient: &str,
+    ) -> anyhow::Result<GreylistDecision> {
+        let network = client_network(&self.params, peer);
+
+        let whitelist_after = self.params.auto_whitelist_after;
+        if whitelist_after > 0 && self.get_network_count(&network).await? >= whitelist_after {
+            return Ok(GreylistDecision::Whitelisted);
+        }
+
+        let key = self.tuple_key(
+            &network,
+            &sender.to_ascii_lowercase(),
+            &recipient.to_ascii_lowercase(),
+        );
+        let state = self.get_tuple(&key).await?;
+        let (decision, state, ttl) = decide(&self.params, state, Utc::now().timestamp());
+        self.set_tuple(key, state, ttl).await?;
+
+        if decision == GreylistDecision::Retried {
+            self.increment_network(&network).await?;
+        }
+
+        Ok(decision)
+    }
+}
+
+/// Called by listeners that have greylisting enabled, when a recipient
+/// is received. Returns None if greylisting has not been configured.
+/// Errors accessing the state are logged, and the recipient accepted,
+/// so that a problem with redis doesn't prevent mail from being received.
+pub async fn check(
+    peer: IpAddr,
+    sender: &EnvelopeAddress,
+    recipient: &EnvelopeAddress,
+) -> Option<GreylistDecision> {
+    let greylist = GREYLIST.get()?;
+    match greylist
+        .check(peer, &sender.to_string(), &recipient.to_string())
+        .await
+    {
+        Ok(decision) => {
+            GREYLIST_DECISIONS
+                .with_label_values(&[decision.reason()])
+                .inc();
+            Some(decision)
+        }
+        Err(err) => {
+            tracing::error!("greylisting {peer} {sender:?} {recipient:?}: {err:#}");
+            GREYLIST_DECISIONS.with_label_values(&["error"]).inc();
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params() -> GreylistParams {
+        GreylistParams {
+            initial_delay: Duration::from_secs(300),
+            retry_window: Duration::from_secs(3600),
+            pass_ttl: Duration::from_secs(86400),
+            auto_whitelist_after: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            capacity: 100,
+            redis: None,
+            redis_key_prefix: GreylistParams::default_redis_key_prefix(),
+        }
+    }
+
+    #[test]
+    fn decisions() {
+        let params = params();
+        let now = 1_700_000_000;
+
+        let (decision, state, ttl) = decide(&params, None, now);
+        assert_eq!(decision, GreylistDecision::Deferred { retry_after: 300 });
+        assert_eq!(state.first_seen, now);
+        assert_eq!(ttl, params.retry_window);
+
+        // Retrying too soon doesn't reset the delay
+        let (decision, state, _) = decide(&params, Some(state), now + 100);
+        assert_eq!(decision, GreylistDecision::Deferred { retry_after: 200 });
+        assert_eq!(state.first_seen, now);
+
+        let (decision, state, ttl) = decide(&params, Some(state), now + 300);
+        assert_eq!(decision, GreylistDecision::Retried);
+        assert!(state.passed);
+        assert_eq!(ttl, params.pass_ttl);
+
+        let (decision, _, _) = decide(&params, Some(state), now + 86000);
+        assert_eq!(decision, GreylistDecision::Known);
+
+        // A retry after the window has closed starts over
+        let stale = TupleState {
+            first_seen: now,
+            passed: false,
+        };
+        let (decision, state, _) = decide(&params, Some(stale), now + 3601);
+        assert_eq!(decision, GreylistDecision::Deferred { retry_after: 300 });
+        assert_eq!(state.first_seen, now + 3601);
+    }
+
+    #[test]
+    fn networks() {
+        let params = params();
+        assert_eq!(
+            client_network(&params, "192.0.2.77".parse().unwrap()),
+            "192.0.2.0/24"
+        );
+        assert_eq!(
+            client_network(&params, "2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+    }
+
+    #[tokio::test]
+    async fn whitelisting() {
+        let params = params();
+        let greylist = Greylist {
+            store: Store::Memory {
+                tuples: lruttl::LruCacheWithTtl::new_named("test_greylist_tuples", 100),
+                networks: lruttl::LruCacheWithTtl::new_named("test_greylist_networks", 100),
+            },
+            params,
+        };
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for recipient in ["a@example.com", "b@example.com"] {
+            let decision = greylist
+                .check(peer, "sender@example.com", recipient)
+                .await
+                .unwrap();
+            assert!(!decision.accepted());
+
+            // Pretend that the initial delay has elapsed
+            let key = greylist.tuple_key("192.0.2.0/24", "sender@example.com", recipient);
+            let state = greylist.get_tuple(&key).await.unwrap().unwrap();
+            greylist
+                .set_tuple(
+                    key,
+                    TupleState {
+                        first_seen: state.first_seen - 300,
+                        passed: false,
+                    },
+                    Duration::from_secs(3600),
+                )
+                .await
+                .unwrap();
+
+            let decision = greylist
+                .check(peer, "sender@example.com", recipient)
+                .await
+                .unwrap();
+            assert_eq!(decision, GreylistDecision::Retried);
+        }
+
+        // Another host in the same network is now whitelisted
+        let decision = greylist
+            .check(
+                "192.0.2.200".parse().unwrap(),
+                "other@example.com",
+                "c@example.com",
+            )
+            .await
+            .unwrap();
+        assert_eq!(decision, GreylistDecision::Whitelisted);
+    }
+}
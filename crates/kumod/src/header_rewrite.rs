@@ -0,0 +1,375 @@
+//! This module implements declarative header rewriting rules,
+//! configured via `kumo.configure_header_rewrite`.
+//!
+//! Each rule adds, removes or replaces headers, optionally conditioned
+//! on the listener, tenant or destination domain of the message, and
+//! is applied either at reception or when the message is dispatched
+//! via SMTP. Rules applied at reception modify the stored message,
+//! and are applied before the reception events so that any DKIM
+//! signature added by those events covers the result; rules applied
+//! at dispatch affect only the copy that is sent, so that they are not
+//! applied more than once when the delivery is retried.
+use kumo_api_types::shaping::Regex;
+use mailparsing::{Header, HeaderParseResult};
+use message::queue_name::QueueNameComponents;
+use message::Message;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use std::sync::{LazyLock, OnceLock};
+
+static HEADER_REWRITE: OnceLock<HeaderRewriteParams> = OnceLock::new();
+
+static REWRITTEN: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "header_rewrite_messages",
+        "total number of messages whose headers were modified by header rewrite rules, by stage",
+        &["stage"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RewriteStage {
+    /// When the message is received, before the reception event
+    #[default]
+    Reception,
+    /// When the message is sent via SMTP
+    Dispatch,
+}
+
+impl RewriteStage {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Reception => "reception",
+            Self::Dispatch => "dispatch",
+        }
+    }
+}
+
+/// Restricts the messages to which a rule applies. An empty list
+/// matches any value; otherwise the value must be in the list.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteCondition {
+    /// The address of the listener that received the message,
+    /// such as `0.0.0.0:25`; the `received_via` meta value
+    #[serde(default)]
+    pub listener: Vec<String>,
+    /// The `reception_protocol` meta value, such as `ESMTP` or `HTTP`
+    #[serde(default)]
+    pub reception_protocol: Vec<String>,
+    /// The tenant of the message
+    #[serde(default)]
+    pub tenant: Vec<String>,
+    /// The domain of the recipient
+    #[serde(default)]
+    pub domain: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub enum RewriteAction {
+    /// Adds a header, after the existing headers unless
+    /// `prepend` is true
+    Add {
+        header: String,
+        value: String,
+        #[serde(default)]
+        prepend: bool,
+    },
+    /// Removes all headers with the specified name. If `matching`
+    /// is set, only those whose value matches it are removed.
+    Remove {
+        header: String,
+        #[serde(default)]
+        matching: Option<Regex>,
+    },
+    /// Replaces the portions of the value of each header with the
+    /// specified name that match `pattern`. `replacement` may refer
+    /// to capture groups as `$1` or `${name}`.
+    Replace {
+        header: String,
+        pattern: Regex,
+        replacement: String,
+    },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewriteRule {
+    #[serde(default)]
+    pub stage: RewriteStage,
+    #[serde(default)]
+    pub when: RewriteCondition,
+    pub action: RewriteAction,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewriteParams {
+    /// The rules, which are applied in order
+    pub rules: Vec<HeaderRewriteRule>,
+}
+
+/// The properties of a message that rules can be conditioned on
+#[derive(Debug, Default)]
+struct RewriteContext {
+    listener: Option<String>,
+    reception_protocol: Option<String>,
+    tenant: Option<String>,
+    domain: String,
+}
+
+impl RewriteContext {
+    fn from_message(msg: &Message) -> anyhow::Result<Self> {
+        let queue_name = msg.get_queue_name()?;
+        let components = QueueNameComponents::parse(&queue_name);
+        Ok(Self {
+            listener: msg.get_meta_string("received_via")?,
+            reception_protocol: msg.get_meta_string("reception_protocol")?,
+            tenant: components.tenant.map(|t| t.to_string()),
+            domain: components.domain.to_string(),
+        })
+    }
+}
+
+fn matches(list: &[String], value: Option<&str>) -> bool {
+    list.is_empty()
+        || value
+            .map(|value| list.iter().any(|item| item.eq_ignore_ascii_case(value)))
+            .unwrap_or(false)
+}
+
+impl RewriteCondition {
+    fn matches(&self, ctx: &RewriteContext) -> bool {
+        matches(&self.listener, ctx.listener.as_deref())
+            && matches(&self.reception_protocol, ctx.reception_protocol.as_deref())
+            && matches(&self.tenant, ctx.tenant.as_deref())
+            && matches(&self.domain, Some(&ctx.domain))
+    }
+}
+
+impl RewriteAction {
+    /// Applies the action, returning true if the headers were changed
+    fn apply(&self, headers: &mut Vec<Header>) -> anyhow::Result<bool> {
+        match self {
+            Self::Add {
+                header,
+                value,
+                prepend,
+            } => {
+                let hdr = Header::new_unstructured(header.to_string(), value.to_string());
+                if *prepend {
+                    headers.insert(0, hdr);
+                } else {
+                    headers.push(hdr);
+                }
+                Ok(true)
+            }
+            Self::Remove { header, matching } => {
+                let before = headers.len();
+                let mut error = None;
+                headers.retain(|hdr| {
+                    if !hdr.get_name().eq_ignore_ascii_case(header) {
+                        return true;
+                    }
+                    let Some(matching) = matching else {
+                        return false;
+                    };
+                    match hdr
+                        .as_unstructured()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|value| Ok(matching.is_match(&value)?))
+                    {
+                        Ok(is_match) => !is_match,
+                        Err(err) => {
+                            error.get_or_insert(err);
+                            true
+                        }
+                    }
+                });
+                if let Some(err) = error {
+                    return Err(err);
+                }
+                Ok(headers.len() != before)
+            }
+            Self::Replace {
+                header,
+                pattern,
+                replacement,
+            } => {
+                let mut changed = false;
+                for hdr in headers.iter_mut() {
+                    if !hdr.get_name().eq_ignore_ascii_case(header) {
+                        continue;
+                    }
+                    let value = hdr.as_unstructured()?;
+                    let new_value = pattern.try_replacen(&value, 0, replacement.as_str())?;
+                    if new_value != value {
+                        *hdr = Header::new_unstructured(
+                            hdr.get_name().to_string(),
+                            new_value.to_string(),
+                        );
+                        changed = true;
+                    }
+                }
+                Ok(changed)
+            }
+        }
+    }
+}
+
+impl HeaderRewriteParams {
+    /// Applies the rules for the specified stage to the message data,
+    /// returning the new data if any rule changed it
+    fn rewrite(
+        &self,
+        stage: RewriteStage,
+        ctx: &RewriteContext,
+        data: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.stage == stage && rule.when.matches(ctx))
+            .peekable();
+        if rules.peek().is_none() {
+            return Ok(None);
+        }
+
+        let HeaderParseResult {
+            mut headers,
+            body_offset,
+            ..
+        } = Header::parse_headers(data)?;
+
+        let mut changed = false;
+        for rule in rules {
+            changed |= rule.action.apply(&mut headers)?;
+        }
+        if !changed {
+            return Ok(None);
+        }
+
+        let mut new_data = Vec::with_capacity(data.len());
+        for hdr in headers.iter() {
+            hdr.write_header(&mut new_data)?;
+        }
+        new_data.extend_from_slice(b"\r\n");
+        new_data.extend_from_slice(&data[body_offset..]);
+        Ok(Some(new_data))
+    }
+}
+
+/// Configures the header rewrite rules. This can only be called once.
+pub fn configure_header_rewrite(params: HeaderRewriteParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    HEADER_REWRITE
+        .set(params)
+        .map_err(|_| anyhow::anyhow!("configure_header_rewrite has already been called"))
+}
+
+/// Applies the rules for the specified stage to the message, returning
+/// the rewritten message data if any rule changed it. The message
+/// itself is not modified.
+pub fn rewrite(stage: RewriteStage, msg: &Message) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(params) = HEADER_REWRITE.get() else {
+        return Ok(None);
+    };
+    if !params.rules.iter().any(|rule| rule.stage == stage) {
+        return Ok(None);
+    }
+    let ctx = RewriteContext::from_message(msg)?;
+    let data = msg.get_data();
+    let result = params.rewrite(stage, &ctx, &data)?;
+    if result.is_some() {
+        REWRITTEN.with_label_values(&[stage.label()]).inc();
+    }
+    Ok(result)
+}
+
+/// Applies the reception rules, updating the message
+pub fn apply_at_reception(msg: &Message) -> anyhow::Result<()> {
+    if let Some(data) = rewrite(RewriteStage::Reception, msg)? {
+        msg.assign_data(data);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MESSAGE: &str = "From: <user@example.com>\r\n\
+        X-Internal-Id: 1234\r\n\
+        Subject: [EXTERNAL] hello\r\n\
+        X-Mailer: Internal Mailer 5.1\r\n\
+        \r\n\
+        body\r\n";
+
+    fn params(rules: serde_json::Value) -> HeaderRewriteParams {
+        serde_json::from_value(serde_json::json!({ "rules": rules })).unwrap()
+    }
+
+    fn rewrite(params: &HeaderRewriteParams, stage: RewriteStage, ctx: &RewriteContext) -> String {
+        params
+            .rewrite(stage, ctx, MESSAGE.as_bytes())
+            .unwrap()
+            .map(|data| String::from_utf8(data).unwrap())
+            .unwrap_or_else(|| MESSAGE.to_string())
+    }
+
+    #[test]
+    fn actions() {
+        let params = params(serde_json::json!([
+            {"action": {"Remove": {"header": "x-internal-id"}}},
+            {"action": {"Remove": {"header": "X-Mailer", "matching": "^Internal"}}},
+            {"action": {"Replace": {
+                "header": "Subject",
+                "pattern": "^\\[(\\w+)\\] (.*)$",
+                "replacement": "$2 ($1)",
+            }}},
+            {"action": {"Add": {"header": "X-Policy", "value": "rewritten", "prepend": true}}},
+            {"stage": "Dispatch", "action": {"Add": {"header": "X-Dispatched", "value": "yes"}}},
+        ]));
+
+        let ctx = RewriteContext {
+            domain: "example.net".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewrite(&params, RewriteStage::Reception, &ctx),
+            "X-Policy: rewritten\r\n\
+             From: <user@example.com>\r\n\
+             Subject: hello (EXTERNAL)\r\n\
+             \r\n\
+             body\r\n"
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let params = params(serde_json::json!([
+            {
+                "when": {"tenant": ["mytenant"], "domain": ["example.net"]},
+                "action": {"Add": {"header": "X-Tenant", "value": "mytenant"}},
+            },
+        ]));
+
+        let ctx = RewriteContext {
+            tenant: Some("mytenant".to_string()),
+            domain: "Example.Net".to_string(),
+            ..Default::default()
+        };
+        assert!(rewrite(&params, RewriteStage::Reception, &ctx).contains("X-Tenant: mytenant\r\n"));
+
+        let ctx = RewriteContext {
+            tenant: None,
+            domain: "example.net".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(rewrite(&params, RewriteStage::Reception, &ctx), MESSAGE);
+    }
+}
@@ -0,0 +1,23 @@
+use axum::extract::{Json, Query};
+use kumo_api_types::analytics::{AnalyticsV1Entry, AnalyticsV1Request};
+use kumo_server_common::http_server::auth::MetricsReadRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Retrieve the rolling counts of accepted, delivered, deferred, bounced
+/// and complaint messages for each provider, egress pool and tenant,
+/// over the last 5 minutes, hour and 24 hours.
+#[utoipa::path(
+    get,
+    tag="metrics",
+    path="/api/admin/analytics/v1",
+    params(AnalyticsV1Request),
+    responses(
+        (status = 200, description = "Obtained the counts", body=[AnalyticsV1Entry]),
+    ),
+)]
+pub async fn get_analytics(
+    _: MetricsReadRequired,
+    Query(request): Query<AnalyticsV1Request>,
+) -> Result<Json<Vec<AnalyticsV1Entry>>, AppError> {
+    Ok(Json(crate::analytics::query(&request)?))
+}
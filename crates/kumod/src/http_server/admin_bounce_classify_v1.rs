@@ -0,0 +1,32 @@
+use crate::logging::classify::explain_classification;
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::{BounceClassifyV1Request, BounceClassifyV1Response};
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+
+/// Classify a response using the rules that are currently loaded by
+/// the bounce classifier, to check how it would be classified in the logs.
+#[utoipa::path(
+    post,
+    tag="inspect",
+    path="/api/admin/bounce-classify/v1",
+    responses(
+        (status = 200, description = "Classified the response", body=BounceClassifyV1Response),
+    ),
+)]
+pub async fn classify(
+    _: QueueAdminRequired,
+    Json(request): Json<BounceClassifyV1Request>,
+) -> Result<Json<BounceClassifyV1Response>, AppError> {
+    let (classification, rule) = explain_classification(&request.text).ok_or_else(|| {
+        StatusCodeError::new(
+            StatusCode::NOT_FOUND,
+            "the bounce classifier has not been configured",
+        )
+    })?;
+    Ok(Json(BounceClassifyV1Response {
+        classification: classification.into(),
+        rule,
+    }))
+}
@@ -3,16 +3,17 @@ use crate::queue::QueueManager;
 use axum::extract::Json;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use config::get_or_create_sub_module;
+use config::{any_err, get_or_create_sub_module};
 use kumo_api_types::{BounceV1CancelRequest, BounceV1ListEntry, BounceV1Request, BounceV1Response};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
-use kumo_server_common::http_server::AppError;
+use kumo_server_common::http_server::auth::BounceAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
 use kumo_server_runtime::rt_spawn;
 use message::message::QueueNameComponents;
 use message::Message;
 use mlua::{Lua, LuaSerdeExt};
 use parking_lot::FairMutex as Mutex;
-use std::collections::HashMap;
+use spool::SpoolId;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, LazyLock};
 use std::time::Instant;
 use uuid::Uuid;
@@ -26,6 +27,7 @@ pub struct AdminBounceEntry {
     pub tenant: Option<String>,
     pub domain: Option<String>,
     pub routing_domain: Option<String>,
+    pub meta: BTreeMap<String, String>,
     pub reason: String,
     pub suppress_logging: bool,
     pub expires: Instant,
@@ -72,6 +74,7 @@ impl AdminBounceEntry {
                         tenant: entry.tenant,
                         domain: entry.domain,
                         routing_domain: entry.routing_domain,
+                        meta: entry.meta,
                         reason: entry.reason,
                         bounced,
                         total_bounced,
@@ -99,7 +102,8 @@ impl AdminBounceEntry {
                 && !(ent.campaign == entry.campaign
                     && ent.tenant == entry.tenant
                     && ent.domain == entry.domain
-                    && ent.routing_domain == entry.routing_domain)
+                    && ent.routing_domain == entry.routing_domain
+                    && ent.meta == entry.meta)
         });
 
         entries.push(entry);
@@ -138,19 +142,47 @@ impl AdminBounceEntry {
         entries
     }
 
-    pub fn get_for_queue_name(queue_name: &str) -> Option<Self> {
+    fn get_matching_queue_name(queue_name: &str) -> Vec<Self> {
         let components = QueueNameComponents::parse(queue_name);
-        let mut entries = Self::get_matching(
+        Self::get_matching(
             components.campaign,
             components.tenant,
             Some(components.domain),
             components.routing_domain,
-        );
+        )
+    }
+
+    /// Returns an entry that applies to every message in the named queue.
+    /// Entries that match by meta are not considered, as they apply
+    /// only to some of the messages in the queue.
+    pub fn get_for_queue_name(queue_name: &str) -> Option<Self> {
+        let mut entries = Self::get_matching_queue_name(queue_name);
+        entries.retain(|ent| ent.meta.is_empty());
+        entries.pop()
+    }
+
+    /// Returns an entry that applies to the message with the specified
+    /// id, which is held by the named queue.
+    pub fn get_for_message(queue_name: &str, id: &SpoolId) -> Option<Self> {
+        let mut entries = Self::get_matching_queue_name(queue_name);
+        entries.retain(|ent| ent.matches_meta(id));
         entries.pop()
     }
 
+    /// Returns true if the message with the specified id satisfies
+    /// the meta criteria of this entry
+    pub fn matches_meta(&self, id: &SpoolId) -> bool {
+        self.meta.is_empty() || crate::meta_index::matches(id, &self.meta)
+    }
+
     pub async fn list_matching_queues(&self) -> Vec<String> {
-        let mut names = QueueManager::all_queue_names();
+        let mut names = if self.meta.is_empty() {
+            QueueManager::all_queue_names()
+        } else {
+            crate::meta_index::queues_matching(&self.meta)
+                .into_iter()
+                .collect()
+        };
         names.retain(|queue_name| {
             let components = QueueNameComponents::parse(queue_name);
             self.matches(
@@ -221,10 +253,18 @@ impl AdminBounceEntry {
     ),
 )]
 pub async fn bounce_v1(
-    _: TrustedIpRequired,
+    _: BounceAdminRequired,
     // Note: Json<> must be last in the param list
     Json(request): Json<BounceV1Request>,
 ) -> Result<Json<BounceV1Response>, AppError> {
+    if let Err(err) = crate::meta_index::check_criteria(&request.meta) {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{err:#}"),
+        ))
+        .into());
+    }
+
     let duration = request.duration();
 
     let id = Uuid::new_v4();
@@ -234,6 +274,7 @@ pub async fn bounce_v1(
         tenant: request.tenant,
         domain: request.domain,
         routing_domain: request.routing_domain,
+        meta: request.meta,
         reason: request.reason,
         suppress_logging: request.suppress_logging,
         expires: Instant::now() + duration,
@@ -272,7 +313,7 @@ pub async fn bounce_v1(
     ),
 )]
 pub async fn bounce_v1_list(
-    _: TrustedIpRequired,
+    _: BounceAdminRequired,
 ) -> Result<Json<Vec<BounceV1ListEntry>>, AppError> {
     Ok(Json(AdminBounceEntry::get_all_v1()))
 }
@@ -288,7 +329,7 @@ pub async fn bounce_v1_list(
     ),
 )]
 pub async fn bounce_v1_delete(
-    _: TrustedIpRequired,
+    _: BounceAdminRequired,
     Json(request): Json<BounceV1CancelRequest>,
 ) -> Response {
     let removed = AdminBounceEntry::remove_by_id(&request.id);
@@ -318,6 +359,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "bounce",
         lua.create_function(move |lua, request: mlua::Value| {
             let request: BounceV1Request = lua.from_value(request)?;
+            crate::meta_index::check_criteria(&request.meta).map_err(any_err)?;
 
             let duration = request.duration();
             let id = Uuid::new_v4();
@@ -329,6 +371,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
                 reason: request.reason,
                 expires: Instant::now() + duration,
                 routing_domain: request.routing_domain,
+                meta: request.meta,
                 suppress_logging: false,
                 bounced: Arc::new(Mutex::new(HashMap::new())),
             };
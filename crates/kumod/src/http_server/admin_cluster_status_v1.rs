@@ -0,0 +1,181 @@
+use crate::http_server::admin_suspend_ready_q_v1::AdminSuspendReadyQEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
+use crate::smtp_server::EsmtpListenerParams;
+use arc_swap::ArcSwap;
+use axum::extract::Json;
+use futures::StreamExt;
+use kumo_api_types::cluster::{
+    ClusterNodeStatusV1, ClusterStatusV1Response, MetricsSummaryV1, NodeStatusV1Response,
+};
+use kumo_server_common::http_server::auth::MetricsReadRequired;
+use kumo_server_common::http_server::AppError;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+static PEERS: LazyLock<ArcSwap<ClusterPeersParams>> = LazyLock::new(ArcSwap::default);
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterPeersParams {
+    /// The base URLs of the HTTP listeners of the other nodes
+    /// in the cluster, such as `http://10.0.0.2:8000`
+    #[serde(default)]
+    pub peers: Vec<String>,
+
+    /// Additional headers to send with each request to the peers,
+    /// such as an Authorization header
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// How long to wait for each peer to respond
+    #[serde(default, with = "duration_serde")]
+    pub timeout: Option<Duration>,
+}
+
+impl ClusterPeersParams {
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+}
+
+pub fn configure_cluster_peers(params: ClusterPeersParams) {
+    PEERS.store(Arc::new(params));
+}
+
+/// Computes the status of this node from its metrics and suspensions
+pub async fn local_node_status() -> anyhow::Result<NodeStatusV1Response> {
+    let mut scheduled = BTreeMap::new();
+    let mut ready = BTreeMap::new();
+    let mut totals = MetricsSummaryV1::default();
+
+    let mut parser = kumo_prometheus::parser::Parser::new();
+    let mut stream = kumo_prometheus::registry::Registry::stream_text(None);
+    let mut accumulate = |m: kumo_prometheus::parser::Metric| {
+        let value = m.value() as usize;
+        let service = m.labels().get("service").map(|s| s.as_str());
+        // Services named "protocol:queue" are per-ready-queue, while
+        // plain protocol names hold the totals for that protocol
+        let ready_queue = service.and_then(|s| s.split_once(':').map(|(_, q)| q));
+        match (m.name().as_str(), ready_queue) {
+            ("scheduled_count", _) => {
+                if let Some(queue) = m.labels().get("queue") {
+                    *scheduled.entry(queue.to_string()).or_default() += value;
+                    totals.scheduled_count += value;
+                }
+            }
+            ("ready_count", Some(queue)) => {
+                *ready.entry(queue.to_string()).or_default() += value;
+                totals.ready_count += value;
+            }
+            ("total_messages_received", None) if service.is_some() => {
+                totals.received += value;
+            }
+            ("total_messages_delivered", None) if service.is_some() => {
+                totals.delivered += value;
+            }
+            ("total_messages_transfail", None) if service.is_some() => {
+                totals.transfail += value;
+            }
+            ("total_messages_fail", None) if service.is_some() => {
+                totals.fail += value;
+            }
+            _ => {}
+        }
+    };
+    while let Some(chunk) = stream.next().await {
+        parser.push_bytes(chunk, false, &mut accumulate)?;
+    }
+    parser.push_bytes("", true, &mut accumulate)?;
+
+    Ok(NodeStatusV1Response {
+        nodeid: kumo_server_common::nodeid::NodeId::get_uuid(),
+        hostname: EsmtpListenerParams::default_hostname(),
+        scheduled,
+        ready,
+        suspensions: AdminSuspendEntry::get_all_v1(),
+        ready_q_suspensions: AdminSuspendReadyQEntry::get_all_v1(),
+        totals,
+    })
+}
+
+async fn peer_node_status(
+    client: &reqwest::Client,
+    params: &ClusterPeersParams,
+    peer: &str,
+) -> anyhow::Result<NodeStatusV1Response> {
+    let url = format!("{}/api/admin/node-status/v1", peer.trim_end_matches('/'));
+    let request = params
+        .headers
+        .iter()
+        .fold(client.get(&url), |request, (k, v)| request.header(k, v));
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        anyhow::bail!("{url}: {status}: {}", String::from_utf8_lossy(&body));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Retrieve the queue depths, suspensions and overall totals for
+/// this node.
+#[utoipa::path(
+    get,
+    tag="cluster",
+    path="/api/admin/node-status/v1",
+    responses(
+        (status = 200, description = "Obtained node status", body=NodeStatusV1Response),
+    ),
+)]
+pub async fn node_status(_: MetricsReadRequired) -> Result<Json<NodeStatusV1Response>, AppError> {
+    Ok(Json(local_node_status().await?))
+}
+
+/// Retrieve the status of this node and each of its configured peers,
+/// merging their queue depths, suspensions and overall totals.
+#[utoipa::path(
+    get,
+    tag="cluster",
+    path="/api/admin/cluster-status/v1",
+    responses(
+        (status = 200, description = "Obtained cluster status", body=ClusterStatusV1Response),
+    ),
+)]
+pub async fn cluster_status(
+    _: MetricsReadRequired,
+) -> Result<Json<ClusterStatusV1Response>, AppError> {
+    let params = PEERS.load_full();
+    let timeout = params
+        .timeout
+        .unwrap_or(ClusterPeersParams::DEFAULT_TIMEOUT);
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+
+    let mut nodes = vec![ClusterNodeStatusV1 {
+        peer: None,
+        status: Some(local_node_status().await?),
+        error: None,
+    }];
+
+    let peers = futures::future::join_all(params.peers.iter().map(|peer| {
+        let client = &client;
+        let params = &params;
+        async move {
+            match peer_node_status(client, params, peer).await {
+                Ok(status) => ClusterNodeStatusV1 {
+                    peer: Some(peer.to_string()),
+                    status: Some(status),
+                    error: None,
+                },
+                Err(err) => ClusterNodeStatusV1 {
+                    peer: Some(peer.to_string()),
+                    status: None,
+                    error: Some(format!("{err:#}")),
+                },
+            }
+        }
+    }))
+    .await;
+    nodes.extend(peers);
+
+    Ok(Json(ClusterStatusV1Response::merge(nodes)))
+}
@@ -0,0 +1,18 @@
+use axum::extract::Json;
+use kumo_api_types::config_snapshot::ConfigSnapshotV1;
+use kumo_server_common::http_server::auth::AdminRequired;
+
+/// Returns the listener, logging, queue and egress path configuration
+/// that is currently in effect, for comparison with that produced by
+/// another policy.
+#[utoipa::path(
+    get,
+    tag="config",
+    path="/api/admin/config-snapshot/v1",
+    responses(
+        (status = 200, description = "Obtained the configuration snapshot", body=ConfigSnapshotV1),
+    ),
+)]
+pub async fn config_snapshot(_: AdminRequired) -> Json<ConfigSnapshotV1> {
+    Json(crate::config_snapshot::running_snapshot())
+}
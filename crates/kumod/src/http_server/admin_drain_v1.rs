@@ -0,0 +1,237 @@
+use crate::http_server::admin_rebind_v1::AdminRebindEntry;
+use crate::http_server::admin_suspend_v1::AdminSuspendEntry;
+use crate::queue::QueueManager;
+use crate::ready_queue::ReadyQueueManager;
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use kumo_api_types::drain::{DrainV1Request, DrainV1Response, DrainV1Status};
+use kumo_api_types::rebind::RebindV1Request;
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+use kumo_server_runtime::rt_spawn;
+use parking_lot::FairMutex as Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use uuid::Uuid;
+
+static DRAIN: LazyLock<Mutex<Option<DrainState>>> = LazyLock::new(|| Mutex::new(None));
+/// Allows check_reject to avoid the lock when we are not draining
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+struct DrainState {
+    id: Uuid,
+    request: DrainV1Request,
+    started: DateTime<Utc>,
+    deadline: DateTime<Utc>,
+    initial_messages: usize,
+    accelerated_messages: usize,
+    rejected: usize,
+    handed_off: bool,
+}
+
+/// Returns (scheduled, ready) message counts
+fn queued_messages() -> (usize, usize) {
+    let ready = ReadyQueueManager::all_queues()
+        .iter()
+        .map(|q| q.ready_count())
+        .sum();
+    (QueueManager::scheduled_count_total(), ready)
+}
+
+/// If the node is draining, returns the status code and message
+/// with which new mail must be rejected
+pub fn check_reject() -> Option<(u16, String)> {
+    if !DRAINING.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut drain = DRAIN.lock();
+    let state = drain.as_mut()?;
+    state.rejected += 1;
+    Some((state.request.code, state.request.message.clone()))
+}
+
+/// Applies `func` to the state, provided that the drain with the
+/// specified id has not been cancelled or replaced
+fn update_state(id: Uuid, func: impl FnOnce(&mut DrainState)) -> bool {
+    let mut drain = DRAIN.lock();
+    match drain.as_mut() {
+        Some(state) if state.id == id => {
+            func(state);
+            true
+        }
+        _ => false,
+    }
+}
+
+async fn run_drain(id: Uuid, request: DrainV1Request, deadline: DateTime<Utc>) {
+    if request.accelerate {
+        let mut accelerated = 0;
+        for name in QueueManager::all_queue_names() {
+            // Suspended queues were suspended for a reason;
+            // it isn't safe to hurry their messages along
+            if AdminSuspendEntry::get_for_queue_name(&name).is_some() {
+                continue;
+            }
+            if let Some(q) = QueueManager::get_opt(&name) {
+                accelerated += q.expedite_due_after(deadline).await;
+            }
+        }
+        tracing::info!("drain: made {accelerated} messages immediately eligible for delivery");
+        if !update_state(id, |state| state.accelerated_messages = accelerated) {
+            return;
+        }
+    }
+
+    let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(remaining).await;
+
+    let Some(handoff_to) = &request.handoff_to else {
+        return;
+    };
+    // Check that we weren't cancelled while we were sleeping
+    if !update_state(id, |_| {}) {
+        return;
+    }
+
+    tracing::info!("drain: deadline reached, handing off remaining messages to {handoff_to}");
+    let entry = Arc::new(AdminRebindEntry {
+        request: RebindV1Request {
+            campaign: None,
+            tenant: None,
+            domain: None,
+            routing_domain: None,
+            meta: Default::default(),
+            reason: format!("drain handoff to {handoff_to}: {}", request.reason),
+            suppress_logging: false,
+            data: HashMap::from([("routing_domain".to_string(), handoff_to.clone())]),
+            trigger_rebind_event: false,
+            always_flush: true,
+        },
+    });
+    for name in QueueManager::all_queue_names() {
+        if let Some(q) = QueueManager::get_opt(&name) {
+            q.rebind_all(&entry).await;
+        }
+    }
+    update_state(id, |state| state.handed_off = true);
+}
+
+/// Put the node into maintenance mode: new mail is rejected with
+/// a transient error, while the queued messages are delivered
+/// ahead of the deadline.  Replaces any drain that is already
+/// in progress.
+#[utoipa::path(
+    post,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "Drain started", body=DrainV1Response),
+    ),
+)]
+pub async fn drain_v1(
+    _: QueueAdminRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<DrainV1Request>,
+) -> Result<Json<DrainV1Response>, AppError> {
+    if !(400..500).contains(&request.code) {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            format!("code {} must be a 4xx code", request.code),
+        ))
+        .into());
+    }
+
+    let started = Utc::now();
+    let deadline = started + chrono::Duration::from_std(request.deadline)?;
+    let (scheduled, ready) = queued_messages();
+    let id = Uuid::new_v4();
+
+    tracing::info!(
+        "drain: starting drain with deadline {deadline}: {}",
+        request.reason
+    );
+    DRAIN.lock().replace(DrainState {
+        id,
+        request: request.clone(),
+        started,
+        deadline,
+        initial_messages: scheduled + ready,
+        accelerated_messages: 0,
+        rejected: 0,
+        handed_off: false,
+    });
+    DRAINING.store(true, Ordering::Relaxed);
+
+    // Move into a lua-capable thread so that logging related
+    // lua events can be triggered by the handoff rebind.
+    rt_spawn("drain_v1".to_string(), async move {
+        run_drain(id, request, deadline).await;
+    })?;
+
+    Ok(Json(DrainV1Response { started, deadline }))
+}
+
+/// Report on the progress of the drain
+#[utoipa::path(
+    get,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "Drain status", body=DrainV1Status),
+    ),
+)]
+pub async fn drain_v1_status(_: QueueAdminRequired) -> Result<Json<DrainV1Status>, AppError> {
+    let (scheduled_messages, ready_messages) = queued_messages();
+    let drain = DRAIN.lock();
+    let status = match drain.as_ref() {
+        Some(state) => DrainV1Status {
+            draining: true,
+            reason: Some(state.request.reason.clone()),
+            started: Some(state.started),
+            deadline: Some(state.deadline),
+            initial_messages: state.initial_messages,
+            scheduled_messages,
+            ready_messages,
+            accelerated_messages: state.accelerated_messages,
+            rejected: state.rejected,
+            handoff_to: state.request.handoff_to.clone(),
+            handed_off: state.handed_off,
+        },
+        None => DrainV1Status {
+            scheduled_messages,
+            ready_messages,
+            ..Default::default()
+        },
+    };
+    Ok(Json(status))
+}
+
+/// Cancel the drain, and resume accepting mail
+#[utoipa::path(
+    delete,
+    tag="drain",
+    path="/api/admin/drain/v1",
+    responses(
+        (status = 200, description = "Cancelled the drain"),
+        (status = 404, description = "The node is not draining"),
+    ),
+)]
+pub async fn drain_v1_cancel(_: QueueAdminRequired) -> Response {
+    let mut drain = DRAIN.lock();
+    DRAINING.store(false, Ordering::Relaxed);
+    match drain.take() {
+        Some(state) => {
+            tracing::info!("drain: cancelled drain: {}", state.request.reason);
+            (StatusCode::OK, "cancelled the drain".to_string())
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            "the node is not draining".to_string(),
+        ),
+    }
+    .into_response()
+}
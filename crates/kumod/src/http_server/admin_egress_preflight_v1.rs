@@ -0,0 +1,46 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::egress_preflight::{EgressPreflightV1Report, EgressPreflightV1Request};
+use kumo_server_common::http_server::auth::{AdminRequired, MetricsReadRequired};
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+
+/// Retrieve the report produced by the most recent egress source
+/// preflight validation.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/egress-preflight/v1",
+    responses(
+        (status = 200, description = "Obtained the report", body=EgressPreflightV1Report),
+        (status = 404, description = "No validation has been performed"),
+    ),
+)]
+pub async fn get_report(_: MetricsReadRequired) -> Result<Json<EgressPreflightV1Report>, AppError> {
+    let report = crate::egress_preflight::last_report().ok_or_else(|| {
+        StatusCodeError::new(
+            StatusCode::NOT_FOUND,
+            "no egress preflight validation has been performed",
+        )
+    })?;
+    Ok(Json(report))
+}
+
+/// Validate that the source address of each of the requested egress
+/// sources can be bound, has a PTR record that matches the EHLO name,
+/// and that the PTR record resolves back to the source address.
+/// If no sources or pools are specified, the sources configured
+/// via `kumo.configure_egress_preflight` are validated.
+#[utoipa::path(
+    post,
+    tag="inspect",
+    path="/api/admin/egress-preflight/v1",
+    responses(
+        (status = 200, description = "Performed the validation", body=EgressPreflightV1Report),
+    ),
+)]
+pub async fn run_preflight(
+    _: AdminRequired,
+    Json(request): Json<EgressPreflightV1Request>,
+) -> Result<Json<EgressPreflightV1Report>, AppError> {
+    Ok(Json(crate::egress_preflight::run(request).await?))
+}
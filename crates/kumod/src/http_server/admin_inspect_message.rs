@@ -1,6 +1,6 @@
 use axum::extract::{Json, Query};
 use kumo_api_types::{InspectMessageV1Request, InspectMessageV1Response, MessageInformation};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::QueueAdminRequired;
 use kumo_server_common::http_server::AppError;
 use message::Message;
 
@@ -15,7 +15,7 @@ use message::Message;
     ),
 )]
 pub async fn inspect_v1(
-    _: TrustedIpRequired,
+    _: QueueAdminRequired,
     Query(request): Query<InspectMessageV1Request>,
 ) -> Result<Json<InspectMessageV1Response>, AppError> {
     let msg = Message::new_with_id(request.id.into()).await?;
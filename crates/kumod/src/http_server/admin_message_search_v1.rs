@@ -0,0 +1,23 @@
+use axum::extract::{Json, Query};
+use kumo_api_types::{MessageSearchV1Entry, MessageSearchV1Request};
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Search the message index by envelope, indexed header or meta values,
+/// and reception time, returning the current status and event history
+/// of each matching message, most recently received first.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/message-search/v1",
+    params(MessageSearchV1Request),
+    responses(
+        (status = 200, description = "Obtained matching messages", body=[MessageSearchV1Entry]),
+    ),
+)]
+pub async fn search(
+    _: QueueAdminRequired,
+    Query(request): Query<MessageSearchV1Request>,
+) -> Result<Json<Vec<MessageSearchV1Entry>>, AppError> {
+    Ok(Json(crate::message_index::search(request).await?))
+}
@@ -0,0 +1,208 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::mx_pin::{
+    MxPinV1CancelRequest, MxPinV1ListEntry, MxPinV1Request, MxPinV1Response,
+};
+use kumo_log_types::ResolvedAddress;
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+use parking_lot::FairMutex as Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::Instant;
+use uuid::Uuid;
+
+static PINS: LazyLock<Mutex<HashMap<String, AdminMxPinEntry>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Debug)]
+pub struct AdminMxPinEntry {
+    pub id: Uuid,
+    pub site: String,
+    pub target: String,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+fn normalize_host(name: &str) -> &str {
+    name.trim_end_matches('.')
+}
+
+impl AdminMxPinEntry {
+    /// Returns true if addr is the pinned host
+    pub fn matches(&self, addr: &ResolvedAddress) -> bool {
+        if let Ok(ip) = self.target.parse::<IpAddr>() {
+            return addr.addr.ip() == Some(ip);
+        }
+        normalize_host(&addr.name).eq_ignore_ascii_case(normalize_host(&self.target))
+    }
+
+    pub fn get_for_site(site: &str) -> Option<Self> {
+        let mut pins = PINS.lock();
+        let now = Instant::now();
+        match pins.get(site) {
+            Some(entry) if entry.expires > now => Some(entry.clone()),
+            Some(_) => {
+                pins.remove(site);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn get_all_v1() -> Vec<MxPinV1ListEntry> {
+        let mut pins = PINS.lock();
+        let now = Instant::now();
+        pins.retain(|_, entry| entry.expires > now);
+        pins.values()
+            .map(|entry| {
+                let duration = entry.expires.saturating_duration_since(now);
+                MxPinV1ListEntry {
+                    id: entry.id,
+                    site: entry.site.clone(),
+                    target: entry.target.clone(),
+                    reason: entry.reason.clone(),
+                    duration,
+                    expires: chrono::Utc::now() + duration,
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces any existing pin for the same site
+    fn add(entry: Self) {
+        tracing::info!(
+            "pinning site {} to {} for {:?}: {}",
+            entry.site,
+            entry.target,
+            entry.expires.saturating_duration_since(Instant::now()),
+            entry.reason
+        );
+        PINS.lock().insert(entry.site.clone(), entry);
+    }
+
+    fn remove_by_id(id: &Uuid) -> bool {
+        let mut pins = PINS.lock();
+        let site = pins
+            .values()
+            .find(|entry| entry.id == *id)
+            .map(|entry| entry.site.clone());
+        match site {
+            Some(site) => {
+                pins.remove(&site);
+                tracing::info!("removed the pin for site {site}");
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Pin the delivery to a site to a specific MX host or IP address
+#[utoipa::path(
+    post,
+    tag="mx-pin",
+    path="/api/admin/mx-pin/v1",
+    responses(
+        (status = 200, description = "Pinned", body=MxPinV1Response),
+    ),
+)]
+pub async fn pin(
+    _: QueueAdminRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<MxPinV1Request>,
+) -> Result<Json<MxPinV1Response>, AppError> {
+    if request.site.is_empty() || request.target.is_empty() {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            "site and target must not be empty".to_string(),
+        ))
+        .into());
+    }
+
+    let duration = request.duration();
+    let entry = AdminMxPinEntry {
+        id: Uuid::new_v4(),
+        site: request.site,
+        target: request.target,
+        reason: request.reason,
+        expires: Instant::now() + duration,
+    };
+    let id = entry.id;
+    AdminMxPinEntry::add(entry);
+
+    Ok(Json(MxPinV1Response {
+        id,
+        expires: chrono::Utc::now() + duration,
+    }))
+}
+
+/// List the active MX pins
+#[utoipa::path(
+    get,
+    tag="mx-pin",
+    path="/api/admin/mx-pin/v1",
+    responses(
+        (status = 200, description = "Pinned", body=MxPinV1ListEntry),
+    ),
+)]
+pub async fn list(_: QueueAdminRequired) -> Result<Json<Vec<MxPinV1ListEntry>>, AppError> {
+    Ok(Json(AdminMxPinEntry::get_all_v1()))
+}
+
+/// Remove an MX pin
+#[utoipa::path(
+    delete,
+    tag="mx-pin",
+    path="/api/admin/mx-pin/v1",
+    responses(
+        (status = 200, description = "Removed the pin"),
+        (status = 404, description = "Pin either expired or was never valid"),
+    ),
+)]
+pub async fn delete(_: QueueAdminRequired, Json(request): Json<MxPinV1CancelRequest>) -> Response {
+    if AdminMxPinEntry::remove_by_id(&request.id) {
+        (StatusCode::OK, format!("removed {}", request.id))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("mx-pin entry {} not found", request.id),
+        )
+    }
+    .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let entry = AdminMxPinEntry {
+            id: Uuid::new_v4(),
+            site: "mx.example.com".to_string(),
+            target: "MX1.example.com".to_string(),
+            reason: "testing".to_string(),
+            expires: Instant::now(),
+        };
+        let mx1 = ResolvedAddress {
+            name: "mx1.example.com.".to_string(),
+            addr: "10.0.0.1".parse::<IpAddr>().unwrap().into(),
+        };
+        let mx2 = ResolvedAddress {
+            name: "mx2.example.com.".to_string(),
+            addr: "10.0.0.2".parse::<IpAddr>().unwrap().into(),
+        };
+        assert!(entry.matches(&mx1));
+        assert!(!entry.matches(&mx2));
+
+        let entry = AdminMxPinEntry {
+            target: "10.0.0.2".to_string(),
+            ..entry
+        };
+        assert!(!entry.matches(&mx1));
+        assert!(entry.matches(&mx2));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::ready_queue::ReadyQueueManager;
 use axum::extract::{Json, Query};
 use kumo_api_types::{QueueState, ReadyQueueStateRequest, ReadyQueueStateResponse};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::MetricsReadRequired;
 use kumo_server_common::http_server::AppError;
 use std::collections::HashMap;
 
@@ -18,7 +18,7 @@ use std::collections::HashMap;
     ),
 )]
 pub async fn readyq_states(
-    _: TrustedIpRequired,
+    _: MetricsReadRequired,
     Query(request): Query<ReadyQueueStateRequest>,
 ) -> Result<Json<ReadyQueueStateResponse>, AppError> {
     let mut states_by_ready_queue = HashMap::new();
@@ -70,6 +70,17 @@ pub async fn readyq_states(
                 },
             );
         }
+        if let Some(s) = &states.mx_pinned {
+            add_state(
+                &mut states_by_ready_queue,
+                queue.name(),
+                "mx_pinned",
+                QueueState {
+                    context: s.context.clone(),
+                    since: s.since,
+                },
+            );
+        }
     }
 
     Ok(Json(ReadyQueueStateResponse {
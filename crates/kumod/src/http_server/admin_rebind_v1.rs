@@ -1,10 +1,12 @@
 use crate::queue::QueueManager;
 use axum::extract::Json;
+use axum::http::StatusCode;
 use kumo_api_types::rebind::{RebindV1Request, RebindV1Response};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
-use kumo_server_common::http_server::AppError;
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
 use kumo_server_runtime::rt_spawn;
 use message::message::QueueNameComponents;
+use spool::SpoolId;
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -49,8 +51,20 @@ impl AdminRebindEntry {
         true
     }
 
+    /// Returns true if the message with the specified id satisfies
+    /// the meta criteria of this entry
+    pub fn matches_meta(&self, id: &SpoolId) -> bool {
+        self.request.meta.is_empty() || crate::meta_index::matches(id, &self.request.meta)
+    }
+
     pub async fn list_matching_queues(&self) -> Vec<String> {
-        let mut names = QueueManager::all_queue_names();
+        let mut names = if self.request.meta.is_empty() {
+            QueueManager::all_queue_names()
+        } else {
+            crate::meta_index::queues_matching(&self.request.meta)
+                .into_iter()
+                .collect()
+        };
         names.retain(|queue_name| {
             let components = QueueNameComponents::parse(queue_name);
             self.matches(
@@ -79,10 +93,18 @@ impl AdminRebindEntry {
     ),
 )]
 pub async fn rebind_v1(
-    _: TrustedIpRequired,
+    _: QueueAdminRequired,
     // Note: Json<> must be last in the param list
     Json(request): Json<RebindV1Request>,
 ) -> Result<Json<RebindV1Response>, AppError> {
+    if let Err(err) = crate::meta_index::check_criteria(&request.meta) {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{err:#}"),
+        ))
+        .into());
+    }
+
     let entry = Arc::new(AdminRebindEntry { request });
 
     let queue_names = entry.list_matching_queues().await;
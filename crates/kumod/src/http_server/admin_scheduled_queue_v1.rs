@@ -0,0 +1,160 @@
+use crate::queue::{Queue, QueueManager};
+use axum::extract::{Json, Query};
+use chrono::Utc;
+use kumo_api_types::scheduled_queue::{
+    ScheduledQueueV1Message, ScheduledQueueV1Request, ScheduledQueueV1Summary,
+};
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::AppError;
+use message::message::QueueNameComponents;
+use message::Message;
+use std::sync::Arc;
+
+const DEFAULT_LIMIT: usize = 100;
+
+fn component_matches(criteria: &Option<String>, value: Option<&str>) -> bool {
+    match criteria {
+        Some(criteria) => value.is_some_and(|value| value.eq_ignore_ascii_case(criteria)),
+        None => true,
+    }
+}
+
+fn queue_matches(request: &ScheduledQueueV1Request, components: &QueueNameComponents) -> bool {
+    component_matches(&request.domain, Some(components.domain))
+        && component_matches(&request.routing_domain, components.routing_domain)
+        && component_matches(&request.tenant, components.tenant)
+        && component_matches(&request.campaign, components.campaign)
+}
+
+/// Resolves the scheduled queues whose names satisfy the request
+fn matching_queues(request: &ScheduledQueueV1Request) -> Vec<(String, Arc<Queue>)> {
+    let mut names = QueueManager::all_queue_names();
+    names.retain(|name| queue_matches(request, &QueueNameComponents::parse(name)));
+    names.sort();
+    names
+        .into_iter()
+        .filter_map(|name| QueueManager::get_opt(&name).map(|queue| (name, queue)))
+        .collect()
+}
+
+/// Returns the messages of the queue that satisfy the request
+fn matching_messages(request: &ScheduledQueueV1Request, queue: &Queue) -> Vec<Message> {
+    let now = Utc::now();
+    let mut messages = queue.snapshot_messages();
+    messages.retain(|msg| {
+        request
+            .id
+            .as_ref()
+            .map_or(true, |id| msg.id().to_string() == *id)
+            && request.matches_message(now, msg.id().created(), msg.get_due())
+    });
+    messages
+}
+
+/// List the scheduled queues that match the criteria, along with the
+/// number of matching messages in each, largest first.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/scheduled-queues/v1",
+    params(ScheduledQueueV1Request),
+    responses(
+        (status = 200, description = "Obtained matching queues", body=[ScheduledQueueV1Summary]),
+    ),
+)]
+pub async fn list_queues(
+    _: QueueAdminRequired,
+    Query(request): Query<ScheduledQueueV1Request>,
+) -> Result<Json<Vec<ScheduledQueueV1Summary>>, AppError> {
+    let mut summaries = vec![];
+    for (name, queue) in matching_queues(&request) {
+        let messages = matching_messages(&request, &queue);
+        if messages.is_empty() {
+            continue;
+        }
+        let components = QueueNameComponents::parse(&name);
+        summaries.push(ScheduledQueueV1Summary {
+            domain: components.domain.to_string(),
+            routing_domain: components.routing_domain.map(|s| s.to_string()),
+            tenant: components.tenant.map(|s| s.to_string()),
+            campaign: components.campaign.map(|s| s.to_string()),
+            count: messages.len(),
+            oldest: messages.iter().map(|msg| msg.id().created()).min(),
+            next_due: messages.iter().filter_map(|msg| msg.get_due()).min(),
+            name,
+        });
+    }
+
+    summaries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    summaries.truncate(request.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok(Json(summaries))
+}
+
+/// List the messages in the scheduled queues that match the criteria,
+/// soonest due first.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/scheduled-queue-messages/v1",
+    params(ScheduledQueueV1Request),
+    responses(
+        (status = 200, description = "Obtained matching messages", body=[ScheduledQueueV1Message]),
+    ),
+)]
+pub async fn list_messages(
+    _: QueueAdminRequired,
+    Query(request): Query<ScheduledQueueV1Request>,
+) -> Result<Json<Vec<ScheduledQueueV1Message>>, AppError> {
+    let mut entries = vec![];
+    for (name, queue) in matching_queues(&request) {
+        for msg in matching_messages(&request, &queue) {
+            entries.push(ScheduledQueueV1Message {
+                id: msg.id().to_string(),
+                queue: name.clone(),
+                created: msg.id().created(),
+                due: msg.get_due(),
+                num_attempts: msg.get_num_attempts(),
+            });
+        }
+    }
+
+    // Messages with no due time are eligible for immediate delivery
+    // and so sort ahead of the others
+    entries.sort_by(|a, b| a.due.cmp(&b.due).then_with(|| a.id.cmp(&b.id)));
+    entries.truncate(request.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok(Json(entries))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue_criteria() {
+        let components = QueueNameComponents::parse("camp:tenant@example.com");
+        let request = ScheduledQueueV1Request {
+            domain: Some("Example.com".to_string()),
+            tenant: Some("tenant".to_string()),
+            ..Default::default()
+        };
+        assert!(queue_matches(&request, &components));
+
+        let request = ScheduledQueueV1Request {
+            campaign: Some("other".to_string()),
+            ..Default::default()
+        };
+        assert!(!queue_matches(&request, &components));
+
+        let request = ScheduledQueueV1Request {
+            routing_domain: Some("routed.example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(!queue_matches(&request, &components));
+        assert!(queue_matches(
+            &request,
+            &QueueNameComponents::parse("example.com!routed.example.com")
+        ));
+    }
+}
@@ -0,0 +1,28 @@
+use crate::spool::SPOOL_IN_PROGRESS;
+use axum::extract::Json;
+use kumo_api_types::SpoolInStatusV1Response;
+use kumo_server_common::http_server::auth::MetricsReadRequired;
+use kumo_server_common::http_server::AppError;
+
+/// Retrieve information about the progress of the spool enumeration
+/// that happens in the background when kumod starts up.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/spoolin-status/v1",
+    responses(
+        (status = 200, description = "Obtained status information", body=SpoolInStatusV1Response),
+    ),
+)]
+pub async fn spoolin_status(
+    _: MetricsReadRequired,
+) -> Result<Json<SpoolInStatusV1Response>, AppError> {
+    let finished = SPOOL_IN_PROGRESS.finished();
+    Ok(Json(SpoolInStatusV1Response {
+        complete: finished.is_some(),
+        started: SPOOL_IN_PROGRESS.started(),
+        finished,
+        spooled_in: SPOOL_IN_PROGRESS.spooled_in(),
+        failed: SPOOL_IN_PROGRESS.failed(),
+    }))
+}
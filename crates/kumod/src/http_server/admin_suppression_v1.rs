@@ -0,0 +1,119 @@
+use axum::extract::{Json, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use config::{any_err, get_or_create_sub_module};
+use kumo_api_types::suppression::{
+    SuppressionV1AddRequest, SuppressionV1Entry, SuppressionV1QueryRequest,
+    SuppressionV1RemoveRequest,
+};
+use kumo_server_common::http_server::auth::BounceAdminRequired;
+use kumo_server_common::http_server::AppError;
+use mlua::{Lua, LuaSerdeExt};
+
+/// Adds an address or domain to the suppression list, replacing
+/// any existing entry for it.
+#[utoipa::path(
+    post,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Added to the suppression list", body=SuppressionV1Entry)
+    ),
+)]
+pub async fn add(
+    _: BounceAdminRequired,
+    // Note: Json<> must be last in the param list
+    Json(request): Json<SuppressionV1AddRequest>,
+) -> Result<Json<SuppressionV1Entry>, AppError> {
+    Ok(Json(crate::suppression::add(request).await?))
+}
+
+/// Lists the entries of the suppression list, or those that
+/// suppress a specific address or domain.
+#[utoipa::path(
+    get,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    params(SuppressionV1QueryRequest),
+    responses(
+        (status = 200, description = "Obtained matching entries", body=[SuppressionV1Entry]),
+    ),
+)]
+pub async fn query(
+    _: BounceAdminRequired,
+    Query(request): Query<SuppressionV1QueryRequest>,
+) -> Result<Json<Vec<SuppressionV1Entry>>, AppError> {
+    Ok(Json(crate::suppression::query(request).await?))
+}
+
+/// Removes an address or domain from the suppression list.
+#[utoipa::path(
+    delete,
+    tag="suppression",
+    path="/api/admin/suppression/v1",
+    responses(
+        (status = 200, description = "Removed the entry"),
+        (status = 404, description = "There is no entry for the requested recipient"),
+    ),
+)]
+pub async fn remove(
+    _: BounceAdminRequired,
+    Json(request): Json<SuppressionV1RemoveRequest>,
+) -> Result<Response, AppError> {
+    let removed = crate::suppression::remove(&request.recipient).await?;
+    Ok(if removed {
+        (StatusCode::OK, format!("removed {}", request.recipient))
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            format!("suppression entry {} not found", request.recipient),
+        )
+    }
+    .into_response())
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let module = get_or_create_sub_module(lua, "api.admin.suppression")?;
+
+    module.set(
+        "add",
+        lua.create_async_function(|lua, request: mlua::Value| async move {
+            let request: SuppressionV1AddRequest = lua.from_value(request)?;
+            let entry = crate::suppression::add(request).await.map_err(any_err)?;
+            lua.to_value(&entry)
+        })?,
+    )?;
+
+    module.set(
+        "remove",
+        lua.create_async_function(|_lua, recipient: String| async move {
+            crate::suppression::remove(&recipient)
+                .await
+                .map_err(any_err)
+        })?,
+    )?;
+
+    module.set(
+        "lookup",
+        lua.create_async_function(|lua, recipient: String| async move {
+            let entry = crate::suppression::lookup(&recipient)
+                .await
+                .map_err(any_err)?;
+            lua.to_value(&entry)
+        })?,
+    )?;
+
+    module.set(
+        "list",
+        lua.create_async_function(|lua, request: Option<mlua::Value>| async move {
+            let request: SuppressionV1QueryRequest = match request {
+                Some(request) => lua.from_value(request)?,
+                None => Default::default(),
+            };
+            let entries = crate::suppression::query(request).await.map_err(any_err)?;
+            lua.to_value(&entries)
+        })?,
+    )?;
+
+    Ok(())
+}
@@ -6,7 +6,7 @@ use kumo_api_types::{
     SuspendReadyQueueV1ListEntry, SuspendReadyQueueV1Request, SuspendV1CancelRequest,
     SuspendV1Response,
 };
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::QueueAdminRequired;
 use kumo_server_common::http_server::AppError;
 use mlua::{Lua, LuaSerdeExt, Value};
 use parking_lot::FairMutex as Mutex;
@@ -187,7 +187,7 @@ impl AdminSuspendReadyQEntry {
     ),
 )]
 pub async fn suspend(
-    _: TrustedIpRequired,
+    _: QueueAdminRequired,
     // Note: Json<> must be last in the param list
     Json(request): Json<SuspendReadyQueueV1Request>,
 ) -> Result<Json<SuspendV1Response>, AppError> {
@@ -214,7 +214,7 @@ pub async fn suspend(
     ),
 )]
 pub async fn list(
-    _: TrustedIpRequired,
+    _: QueueAdminRequired,
 ) -> Result<Json<Vec<SuspendReadyQueueV1ListEntry>>, AppError> {
     Ok(Json(AdminSuspendReadyQEntry::get_all_v1()))
 }
@@ -229,7 +229,10 @@ pub async fn list(
         (status = 404, description = "Suspension either expired or was never valid"),
     ),
 )]
-pub async fn delete(_: TrustedIpRequired, Json(request): Json<SuspendV1CancelRequest>) -> Response {
+pub async fn delete(
+    _: QueueAdminRequired,
+    Json(request): Json<SuspendV1CancelRequest>,
+) -> Response {
     let removed = AdminSuspendReadyQEntry::remove_by_id(&request.id);
     if removed {
         (StatusCode::OK, format!("removed {}", request.id))
@@ -0,0 +1,94 @@
+use crate::logging::tail::LogFilter;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::response::IntoResponse;
+use kumo_api_types::TailLogsV1Request;
+use kumo_server_common::http_server::auth::AdminRequired;
+use tokio::sync::broadcast::error::RecvError;
+
+async fn process_websocket_inner(socket: &mut WebSocket) -> anyhow::Result<()> {
+    let mut rx = crate::logging::tail::subscribe();
+
+    let request: TailLogsV1Request = match socket
+        .recv()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("websocket closed"))??
+    {
+        Message::Text(json) => serde_json::from_str(&json)?,
+        message => anyhow::bail!("unexpected {message:?}"),
+    };
+
+    let filter = match &request.filter {
+        Some(filter) => Some(LogFilter::parse(filter)?),
+        None => None,
+    };
+    if let Some(rate) = request.sample_rate {
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&rate),
+            "sample_rate must be between 0.0 and 1.0"
+        );
+    }
+
+    loop {
+        let record = match rx.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(n)) => {
+                tracing::debug!("tail-logs client lagged and missed {n} records");
+                continue;
+            }
+            Err(err @ RecvError::Closed) => return Err(err.into()),
+        };
+        if filter.as_ref().is_some_and(|f| !f.matches(&record)) {
+            continue;
+        }
+        if request
+            .sample_rate
+            .is_some_and(|rate| rand::random::<f64>() >= rate)
+        {
+            continue;
+        }
+
+        let json = serde_json::to_string(&*record)?;
+        socket.send(Message::Text(json)).await?;
+    }
+}
+
+async fn process_websocket(mut socket: WebSocket) {
+    if let Err(err) = process_websocket_inner(&mut socket).await {
+        tracing::debug!("error in tail-logs websocket: {err:#}");
+        // Let the client know why we are closing the connection.
+        // The reason is limited to 123 bytes by the protocol.
+        let mut reason = format!("{err:#}");
+        if reason.len() > 123 {
+            let mut end = 120;
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            reason.truncate(end);
+            reason.push_str("...");
+        }
+        socket
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: reason.into(),
+            })))
+            .await
+            .ok();
+    }
+}
+
+/// Tail the log records produced by kumod, as they are generated.
+/// The connection is upgraded to a WebSocket, over which the client
+/// sends a `TailLogsV1Request` holding an optional filter expression and
+/// sample rate, and then receives a stream of JSON log records that match
+/// the filter, each sent as a separate text message.
+#[utoipa::path(
+    get,
+    tag="logging",
+    path="/api/admin/tail-logs/v1",
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol")
+    ),
+)]
+pub async fn tail(_: AdminRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(|socket| process_websocket(socket))
+}
@@ -2,12 +2,12 @@ use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use chrono::{DateTime, Utc};
 use kumo_api_types::{TraceSmtpClientV1Event, TraceSmtpClientV1Payload, TraceSmtpClientV1Request};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::AdminRequired;
 use parking_lot::Mutex;
 use rfc5321::DeferredTracer;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::LazyLock;
-use tokio::sync::broadcast::{channel, Sender};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
 use tracing::Level;
 
 static MGR: LazyLock<SmtpClientTraceManager> = LazyLock::new(SmtpClientTraceManager::new);
@@ -31,6 +31,11 @@ impl SmtpClientTraceManager {
             mgr.tx.send((f)()).ok();
         }
     }
+
+    /// Subscribe to the stream of trace events
+    pub fn subscribe() -> Receiver<SmtpClientTraceEvent> {
+        MGR.tx.subscribe()
+    }
 }
 
 pub struct SmtpClientTracerImpl {
@@ -103,6 +108,7 @@ fn port_payload(event: rfc5321::SmtpClientTraceEvent) -> SmtpClientTraceEventPay
         TE::Closed => SmtpClientTraceEventPayload::Closed,
         TE::Read(data) => SmtpClientTraceEventPayload::Read(data),
         TE::Write(data) => SmtpClientTraceEventPayload::Write(data),
+        TE::WriteData(data) => SmtpClientTraceEventPayload::WriteData(data),
         TE::Diagnostic { level, message } => {
             SmtpClientTraceEventPayload::Diagnostic { level, message }
         }
@@ -141,7 +147,7 @@ pub struct SmtpClientTraceEvent {
 }
 
 impl SmtpClientTraceEvent {
-    fn to_v1(self) -> TraceSmtpClientV1Event {
+    pub fn to_v1(self) -> TraceSmtpClientV1Event {
         TraceSmtpClientV1Event {
             conn_meta: self.conn_meta,
             payload: self.payload.to_v1(),
@@ -157,6 +163,8 @@ pub enum SmtpClientTraceEventPayload {
     Closed,
     Read(Vec<u8>),
     Write(String),
+    /// Message content written after the DATA command
+    WriteData(String),
     Diagnostic {
         level: tracing::Level,
         message: String,
@@ -174,7 +182,7 @@ impl SmtpClientTraceEventPayload {
             Self::Read(data) => {
                 TraceSmtpClientV1Payload::Read(String::from_utf8_lossy(&data).to_string())
             }
-            Self::Write(s) => TraceSmtpClientV1Payload::Write(s),
+            Self::Write(s) | Self::WriteData(s) => TraceSmtpClientV1Payload::Write(s),
             Self::Diagnostic { level, message } => TraceSmtpClientV1Payload::Diagnostic {
                 level: level.to_string(),
                 message: message.to_string(),
@@ -267,6 +275,19 @@ async fn process_websocket(socket: WebSocket) {
     }
 }
 
-pub async fn trace(_: TrustedIpRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
+/// Trace the outgoing SMTP sessions made by kumod.
+/// The connection is upgraded to a WebSocket, over which the client
+/// sends a `TraceSmtpClientV1Request` holding the filter criteria,
+/// and then receives a stream of `TraceSmtpClientV1Event` objects,
+/// each sent as a separate text message.
+#[utoipa::path(
+    get,
+    tag="trace",
+    path="/api/admin/trace-smtp-client/v1",
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol")
+    ),
+)]
+pub async fn trace(_: AdminRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
     ws.on_upgrade(|socket| process_websocket(socket))
 }
@@ -2,7 +2,7 @@ use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::response::IntoResponse;
 use chrono::{DateTime, Utc};
 use kumo_api_types::{TraceSmtpV1Event, TraceSmtpV1Payload, TraceSmtpV1Request};
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::AdminRequired;
 use spool::SpoolId;
 use std::net::IpAddr;
 use std::sync::LazyLock;
@@ -164,6 +164,19 @@ async fn process_websocket(socket: WebSocket) {
     }
 }
 
-pub async fn trace(_: TrustedIpRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
+/// Trace the incoming SMTP sessions made to kumod.
+/// The connection is upgraded to a WebSocket, over which the client
+/// sends a `TraceSmtpV1Request` holding the filter criteria,
+/// and then receives a stream of `TraceSmtpV1Event` objects,
+/// each sent as a separate text message.
+#[utoipa::path(
+    get,
+    tag="trace",
+    path="/api/admin/trace-smtp-server/v1",
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol")
+    ),
+)]
+pub async fn trace(_: AdminRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
     ws.on_upgrade(|socket| process_websocket(socket))
 }
@@ -0,0 +1,194 @@
+use crate::ready_queue::{site_name_of, ReadyQueueManager};
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::egress_path::EgressPathConfig;
+use kumo_api_types::tuning::{ReadyQueueTuningV1, TuningV1Request, TuningV1Response};
+use kumo_server_common::http_server::auth::AdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static READY_QUEUE_TUNING: LazyLock<Mutex<BTreeMap<String, ReadyQueueTuningV1>>> =
+    LazyLock::new(Mutex::default);
+
+/// Applies any runtime overrides that match the ready queue
+/// to its egress path configuration
+pub fn apply_ready_queue_tuning(
+    ready_queue_name: &str,
+    site_name: &str,
+    path_config: &mut EgressPathConfig,
+) {
+    let tuning = READY_QUEUE_TUNING.lock();
+    if tuning.is_empty() {
+        return;
+    }
+    // Apply the site overrides first, so that those for
+    // the specific ready queue take precedence
+    for name in [site_name, ready_queue_name] {
+        let Some(entry) = tuning.get(name) else {
+            continue;
+        };
+        if let Some(limit) = entry.connection_limit {
+            path_config.connection_limit = limit;
+        }
+        if let Some(max_ready) = entry.max_ready {
+            path_config.max_ready = max_ready;
+        }
+        if let Some(max_deliveries) = entry.max_deliveries_per_connection {
+            path_config.max_deliveries_per_connection = max_deliveries;
+        }
+        if let Some(rate) = &entry.max_message_rate {
+            path_config.max_message_rate.replace(rate.clone());
+        }
+        if let Some(rate) = &entry.max_connection_rate {
+            path_config.max_connection_rate.replace(rate.clone());
+        }
+    }
+}
+
+fn bad_request(message: String) -> AppError {
+    anyhow::Error::new(StatusCodeError::new(StatusCode::BAD_REQUEST, message)).into()
+}
+
+fn cache_capacities() -> BTreeMap<String, usize> {
+    let mut capacities = BTreeMap::new();
+    for (name, capacity) in lruttl::get_cache_capacities() {
+        let entry = capacities.entry(name).or_default();
+        *entry = capacity.max(*entry);
+    }
+    capacities
+}
+
+fn current_tuning() -> TuningV1Response {
+    TuningV1Response {
+        ready_queues: READY_QUEUE_TUNING.lock().values().cloned().collect(),
+        dns_query_timeout: dns_resolver::get_query_timeout(),
+        cache_capacities: cache_capacities(),
+        memory_soft_limit: kumo_server_memory::get_soft_limit_override(),
+        low_memory_threshold: kumo_server_memory::get_low_memory_threshold(),
+    }
+}
+
+/// Retrieve the runtime adjustments that are currently in effect.
+#[utoipa::path(
+    get,
+    tag="tuning",
+    path="/api/admin/tuning/v1",
+    responses(
+        (status = 200, description = "Obtained the current tuning", body=TuningV1Response),
+    ),
+)]
+pub async fn get_tuning(_: AdminRequired) -> Json<TuningV1Response> {
+    Json(current_tuning())
+}
+
+/// Adjust ready queue limits and throttles, DNS timeouts, cache
+/// capacities and memory thresholds without reloading the policy.
+/// Adjustments are not persisted, and are lost when kumod restarts.
+#[utoipa::path(
+    post,
+    tag="tuning",
+    path="/api/admin/tuning/v1",
+    responses(
+        (status = 200, description = "Applied the adjustments", body=TuningV1Response),
+    ),
+)]
+pub async fn set_tuning(
+    _: AdminRequired,
+    Json(request): Json<TuningV1Request>,
+) -> Result<Json<TuningV1Response>, AppError> {
+    // Validate everything before changing anything, so that
+    // a bad request has no partial effect
+    if let Some(percent) = request.low_memory_threshold {
+        if !(1..=100).contains(&percent) {
+            return Err(bad_request(
+                "low_memory_threshold must be between 1 and 100 percent".to_string(),
+            ));
+        }
+    }
+    let known_caches = cache_capacities();
+    for (name, capacity) in &request.cache_capacities {
+        if !known_caches.contains_key(name) {
+            return Err(bad_request(format!("there is no cache named {name}")));
+        }
+        if *capacity == 0 {
+            return Err(bad_request(format!(
+                "the capacity of cache {name} must be non-zero"
+            )));
+        }
+    }
+    for entry in &request.ready_queues {
+        if entry.connection_limit == Some(0) || entry.max_ready == Some(0) {
+            return Err(bad_request(format!(
+                "connection_limit and max_ready for {} must be non-zero",
+                entry.name
+            )));
+        }
+    }
+
+    let mut changed_queues = BTreeSet::new();
+    {
+        let mut tuning = READY_QUEUE_TUNING.lock();
+        for name in request.reset_ready_queues {
+            if tuning.remove(&name).is_some() {
+                changed_queues.insert(name);
+            }
+        }
+        for entry in request.ready_queues {
+            changed_queues.insert(entry.name.clone());
+            tuning.insert(entry.name.clone(), entry);
+        }
+    }
+
+    if let Some(timeout) = request.dns_query_timeout {
+        dns_resolver::set_query_timeout(if timeout == Duration::ZERO {
+            None
+        } else {
+            Some(timeout)
+        });
+    }
+    for (name, capacity) in &request.cache_capacities {
+        lruttl::set_cache_capacity(name, *capacity);
+    }
+    if let Some(limit) = request.memory_soft_limit {
+        kumo_server_memory::set_soft_limit_override(if limit == 0 { None } else { Some(limit) });
+    }
+    if let Some(percent) = request.low_memory_threshold {
+        kumo_server_memory::set_low_memory_threshold(percent)?;
+    }
+
+    // Apply the ready queue changes now, rather than waiting
+    // for the next scheduled configuration refresh
+    if !changed_queues.is_empty() {
+        for queue in ReadyQueueManager::all_queues() {
+            let name = queue.name();
+            let matched = changed_queues.contains(name)
+                || site_name_of(name).is_some_and(|site| changed_queues.contains(site));
+            if matched {
+                queue.refresh_config_now().await;
+            }
+        }
+    }
+
+    Ok(Json(current_tuning()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn site_name() {
+        assert_eq!(
+            site_name_of("unspecified->(alt1|alt2)?.mx.example.com@smtp_client"),
+            Some("(alt1|alt2)?.mx.example.com")
+        );
+        assert_eq!(
+            site_name_of("ip-1->mx_list:10.0.0.1,10.0.0.2@smtp_client"),
+            Some("mx_list:10.0.0.1,10.0.0.2")
+        );
+        assert_eq!(site_name_of("bogus"), None);
+    }
+}
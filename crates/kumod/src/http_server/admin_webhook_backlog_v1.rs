@@ -0,0 +1,73 @@
+use crate::logging::webhook::WebhookBacklog;
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::{
+    WebhookBacklogFlushV1Request, WebhookBacklogFlushV1Response, WebhookBacklogV1ListEntry,
+};
+use kumo_server_common::http_server::auth::QueueAdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+
+/// List the log records that are buffered on disk because they
+/// could not be delivered to their webhook.
+#[utoipa::path(
+    get,
+    tag="inspect",
+    path="/api/admin/webhook-backlog/v1",
+    responses(
+        (status = 200, description = "Obtained backlog information", body=[WebhookBacklogV1ListEntry]),
+    ),
+)]
+pub async fn list(_: QueueAdminRequired) -> Result<Json<Vec<WebhookBacklogV1ListEntry>>, AppError> {
+    Ok(Json(
+        WebhookBacklog::get_all()
+            .into_iter()
+            .map(|backlog| {
+                let status = backlog.status();
+                WebhookBacklogV1ListEntry {
+                    name: status.name,
+                    num_records: status.num_records,
+                    size_bytes: status.size_bytes,
+                    oldest: status.oldest,
+                    next_attempt: status.next_attempt,
+                    last_error: status.last_error,
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// Retry delivery of the buffered webhook log records immediately,
+/// or discard them.
+#[utoipa::path(
+    post,
+    tag="inspect",
+    path="/api/admin/webhook-backlog/v1",
+    responses(
+        (status = 200, description = "Backlog flushed", body=WebhookBacklogFlushV1Response),
+    ),
+)]
+pub async fn flush(
+    _: QueueAdminRequired,
+    Json(request): Json<WebhookBacklogFlushV1Request>,
+) -> Result<Json<WebhookBacklogFlushV1Response>, AppError> {
+    let backlogs = match &request.name {
+        Some(name) => vec![WebhookBacklog::get_named(name).ok_or_else(|| {
+            StatusCodeError::new(
+                StatusCode::NOT_FOUND,
+                format!("no webhook logger named {name}"),
+            )
+        })?],
+        None => WebhookBacklog::get_all(),
+    };
+
+    let mut num_discarded = 0;
+    for backlog in backlogs {
+        if request.discard {
+            num_discarded += backlog.discard();
+        } else {
+            backlog.retry_now();
+        }
+    }
+
+    Ok(Json(WebhookBacklogFlushV1Response { num_discarded }))
+}
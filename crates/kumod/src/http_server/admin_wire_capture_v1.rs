@@ -0,0 +1,30 @@
+use axum::extract::Json;
+use axum::http::StatusCode;
+use kumo_api_types::wire_capture::{WireCaptureV1Request, WireCaptureV1Response};
+use kumo_server_common::http_server::auth::AdminRequired;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+
+/// Record the outgoing SMTP sessions that deliver to a domain into a
+/// file on the kumod host, for a limited time. Message content and
+/// authentication credentials are redacted.
+#[utoipa::path(
+    post,
+    tag="trace",
+    path="/api/admin/wire-capture/v1",
+    responses(
+        (status = 200, description = "The capture has started", body=WireCaptureV1Response),
+    ),
+)]
+pub async fn set_wire_capture(
+    _: AdminRequired,
+    Json(request): Json<WireCaptureV1Request>,
+) -> Result<Json<WireCaptureV1Response>, AppError> {
+    if let Err(err) = crate::wire_capture::validate_request(&request) {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{err:#}"),
+        ))
+        .into());
+    }
+    Ok(Json(crate::wire_capture::start_capture(request).await?))
+}
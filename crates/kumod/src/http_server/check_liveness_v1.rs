@@ -23,6 +23,8 @@ pub async fn check_liveness_v1() -> Response {
                 (503, "waiting for spool startup")
             } else if kumo_server_common::disk_space::is_over_limit() {
                 (503, "storage is too full")
+            } else if spool::quota::is_over_soft_quota() {
+                (503, "spool quota exceeded")
             } else {
                 (200, "OK")
             }
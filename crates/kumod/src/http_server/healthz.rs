@@ -0,0 +1,128 @@
+use crate::spool::SpoolManager;
+use axum::extract::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use kumo_api_types::{ReadinessCheckV1, ReadinessV1Response};
+use kumo_server_lifecycle::Activity;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long to wait for any individual check to complete
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness probe. Responds with 200 for as long as the process
+/// is able to service HTTP requests.
+#[utoipa::path(
+    get,
+    tag="liveness",
+    path="/healthz",
+    responses(
+        (status = 200, description = "the process is alive"),
+    ),
+)]
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+async fn run_check<F>(checks: &mut BTreeMap<String, ReadinessCheckV1>, name: &str, check: F)
+where
+    F: Future<Output = anyhow::Result<String>>,
+{
+    let (ok, detail) = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(detail)) => (true, detail),
+        Ok(Err(err)) => (false, format!("{err:#}")),
+        Err(_) => (false, format!("timed out after {CHECK_TIMEOUT:?}")),
+    };
+    checks.insert(name.to_string(), ReadinessCheckV1 { ok, detail });
+}
+
+/// Readiness probe. Verifies that the spool is writable, that the DNS
+/// resolver is responsive, that redis is reachable (if throttles are
+/// configured to use it) and that the policy can be loaded, and
+/// reports the outcome of each check.
+#[utoipa::path(
+    get,
+    tag="liveness",
+    path="/readyz",
+    responses(
+        (status = 200, description = "all checks passed", body=ReadinessV1Response),
+        (status = 503, description = "one or more checks failed", body=ReadinessV1Response),
+    ),
+)]
+pub async fn readyz() -> Response {
+    let mut checks = BTreeMap::new();
+
+    let Some(_activity) = Activity::get_opt("check readiness".to_string()) else {
+        checks.insert(
+            "lifecycle".to_string(),
+            ReadinessCheckV1 {
+                ok: false,
+                detail: "shutting down".to_string(),
+            },
+        );
+        return respond(checks);
+    };
+
+    run_check(&mut checks, "spool", async {
+        let manager = SpoolManager::get();
+        if !manager.spool_started() {
+            anyhow::bail!("waiting for spool startup");
+        }
+        if spool::quota::is_over_soft_quota() {
+            anyhow::bail!("spool quota exceeded");
+        }
+        manager.check_writable().await?;
+        Ok("writable".to_string())
+    })
+    .await;
+
+    run_check(&mut checks, "storage", async {
+        if kumo_server_common::disk_space::is_over_limit() {
+            anyhow::bail!("storage is too full");
+        }
+        Ok("OK".to_string())
+    })
+    .await;
+
+    run_check(&mut checks, "memory", async {
+        if kumo_server_memory::get_headroom() == 0 {
+            anyhow::bail!("load shedding");
+        }
+        Ok("OK".to_string())
+    })
+    .await;
+
+    run_check(&mut checks, "dns", async {
+        dns_resolver::check_resolver().await?;
+        Ok("resolver is responsive".to_string())
+    })
+    .await;
+
+    run_check(&mut checks, "redis", async {
+        if throttle::ping_redis().await? {
+            Ok("connected".to_string())
+        } else {
+            Ok("not configured".to_string())
+        }
+    })
+    .await;
+
+    run_check(&mut checks, "policy", async {
+        config::load_config().await?;
+        Ok("loaded".to_string())
+    })
+    .await;
+
+    respond(checks)
+}
+
+fn respond(checks: BTreeMap<String, ReadinessCheckV1>) -> Response {
+    let ready = checks.values().all(|check| check.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessV1Response { ready, checks })).into_response()
+}
@@ -4,31 +4,40 @@ use crate::queue::{DeliveryProto, QueueConfig, QueueManager};
 use crate::ready_queue::{Dispatcher, QueueDispatcher};
 use crate::smtp_server::{EsmtpListenerParams, TraceHeaders};
 use crate::spool::SpoolManager;
+use crate::suppression::SuppressionStage;
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use axum::extract::Json;
+use axum::http::StatusCode;
 use axum_client_ip::InsecureClientIp;
 use config::{any_err, get_or_create_sub_module, load_config, CallbackSignature, LuaConfig};
+use kumo_api_types::ApiTokenScope;
 use kumo_chrono_helper::Utc;
 use kumo_log_types::ResolvedAddress;
 use kumo_prometheus::AtomicCounter;
 use kumo_server_common::http_server::auth::AuthKind;
-use kumo_server_common::http_server::AppError;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
 use kumo_server_runtime::{Runtime, RUNTIME};
-use kumo_template::{CompiledTemplates, TemplateEngine, TemplateList};
+use kumo_template::{
+    CompiledTemplates, Error as TemplateError, ErrorKind as TemplateErrorKind, Rest,
+    TemplateEngine, TemplateList, Value as TemplateValue,
+};
+use lruttl::LruCacheWithTtl;
 use mailparsing::{AddrSpec, Address, EncodeHeaderValue, Mailbox, MessageBuilder, MimePart};
 use message::{EnvelopeAddress, Message};
 use mlua::{Lua, LuaSerdeExt};
+use openssl::sha::Sha256;
 use rfc5321::Response;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use spool::SpoolId;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use throttle::ThrottleSpec;
 use utoipa::{ToResponse, ToSchema};
 
@@ -42,6 +51,9 @@ static HTTPINJECT: LazyLock<Runtime> =
 
 static HTTPINJECT_THREADS: AtomicUsize = AtomicUsize::new(0);
 static LIMIT: LazyLock<ArcSwap<Option<ThrottleSpec>>> = LazyLock::new(ArcSwap::default);
+/// How long clients are advised to wait before retrying an injection
+/// that was refused because the spool is over its quota
+const SPOOL_QUOTA_RETRY_AFTER: Duration = Duration::from_secs(60);
 
 pub fn set_httpinject_recipient_rate_limit(spec: Option<ThrottleSpec>) {
     let spec = Arc::new(spec);
@@ -126,6 +138,18 @@ pub struct InjectV1Request {
     /// The content of the message
     pub content: Content,
 
+    /// When using templating, this is a map of template name to
+    /// template source that can be referenced by the content using
+    /// `{% include %}`, `{% import %}` or `{% extends %}`, allowing
+    /// common fragments to be shared between the parts of the message.
+    /// Names ending with `.html` will have html escaping applied
+    /// to their substitutions.
+    #[serde(default)]
+    #[schema(example=json!({
+        "footer.html": "<p>Sent to {{ email }}</p>",
+    }))]
+    pub templates: HashMap<String, String>,
+
     /// When using templating, this is the map of placeholder
     /// name to replacement value that should be used by
     /// the templating engine.  This map applies to all
@@ -155,6 +179,13 @@ pub struct InjectV1Request {
     /// Controls which trace headers will be added to the message.
     #[serde(default)]
     pub trace_headers: HttpTraceHeaders,
+
+    /// When set to true, the response will include a
+    /// `recipient_results` list holding the outcome for each
+    /// recipient, in the same order as the `recipients` list.
+    /// This cannot be combined with `deferred_generation`.
+    #[serde(default)]
+    pub return_recipient_results: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, ToResponse, ToSchema)]
@@ -169,6 +200,33 @@ pub struct InjectV1Response {
 
     /// The list of error messages
     pub errors: Vec<String>,
+
+    /// The outcome for each recipient, in the same order as the
+    /// `recipients` list of the request.  Only present when
+    /// `return_recipient_results` was set in the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient_results: Option<Vec<InjectV1RecipientResult>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct InjectV1RecipientResult {
+    /// The position of the recipient in the `recipients` list
+    pub index: usize,
+
+    /// The email address of the recipient
+    #[schema(example = "john.smith@mailbox-example.com")]
+    pub email: String,
+
+    /// The spool id of the generated message, if it was
+    /// successfully queued
+    #[serde(default)]
+    #[schema(example = "d7ef132b5d7711eea8c8000c29c33806")]
+    pub id: Option<String>,
+
+    /// The reason that the message could not be generated or
+    /// queued for this recipient
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 /// The message content.
@@ -230,6 +288,7 @@ pub struct Attachment {
     /// The content of the payload.
     /// This is interpreted as UTF-8 text unless the
     /// `base64` field is set to `true`.
+    #[serde(default)]
     data: String,
     /// The MIME `Content-Type` header that should be
     /// set for this attachment.
@@ -245,11 +304,95 @@ pub struct Attachment {
     /// If true, the `data` field must be encoded as base64
     #[serde(default)]
     base64: bool,
+    /// The path to a file whose content is used in place of `data`.
+    /// The file is read as each message is generated, rather than
+    /// being held in memory.  This is only permitted when injecting
+    /// via `kumo.api.inject.inject_v1`.
+    #[serde(default)]
+    path: Option<String>,
+}
+
+/// The source of the content of an attachment
+enum AttachmentSource<'a> {
+    Data(Cow<'a, [u8]>),
+    Path(&'a str),
+}
+
+/// An attachment, prepared for inclusion in each generated message
+struct CompiledAttachment<'a> {
+    content_type: &'a str,
+    opts: mailparsing::AttachmentOptions,
+    source: AttachmentSource<'a>,
+}
+
+/// The compiled content templates, keyed by a hash of their sources,
+/// so that repeated requests for the same campaign content can skip
+/// the cost of compiling them again
+static TEMPLATE_CACHE: LazyLock<LruCacheWithTtl<String, Arc<CompiledTemplates>>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("inject_v1_templates", 1024));
+const TEMPLATE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// The name of the lua registry table that holds the filters
+/// registered via kumo.api.inject.register_template_filter
+const TEMPLATE_FILTERS: &str = "kumomta-inject-template-filters";
+
+/// A template filter that is implemented by a lua function
+#[derive(Clone)]
+struct TemplateFilter {
+    name: String,
+    lua: Lua,
+    func: mlua::Function,
+}
+
+impl TemplateFilter {
+    fn call(
+        &self,
+        value: TemplateValue,
+        args: Rest<TemplateValue>,
+    ) -> anyhow::Result<TemplateValue> {
+        let mut params = vec![self.lua.to_value(&value)?];
+        for arg in args.iter() {
+            params.push(self.lua.to_value(arg)?);
+        }
+        let result: mlua::Value = self.func.call(mlua::MultiValue::from_vec(params))?;
+        let result: Value = self.lua.from_value(result)?;
+        Ok(TemplateValue::from_serialize(&result))
+    }
+
+    fn add_to(self, env: &mut TemplateEngine) {
+        env.add_filter(self.name.clone(), move |value, args| {
+            self.call(value, args).map_err(|err| {
+                TemplateError::new(
+                    TemplateErrorKind::InvalidOperation,
+                    format!("template filter {}: {err:#}", self.name),
+                )
+            })
+        });
+    }
+}
+
+/// Returns the filters that were registered by the policy
+/// of the provided lua instance
+fn template_filters(lua: &Lua) -> anyhow::Result<Vec<TemplateFilter>> {
+    let Some(table) = lua.named_registry_value::<Option<mlua::Table>>(TEMPLATE_FILTERS)? else {
+        return Ok(vec![]);
+    };
+    let mut filters = vec![];
+    for pair in table.pairs::<String, mlua::Function>() {
+        let (name, func) = pair?;
+        filters.push(TemplateFilter {
+            name,
+            lua: lua.clone(),
+            func,
+        });
+    }
+    filters.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(filters)
 }
 
 struct Compiled<'a> {
-    env_and_templates: CompiledTemplates,
-    attached: Vec<MimePart<'a>>,
+    env_and_templates: Arc<CompiledTemplates>,
+    attached: Vec<CompiledAttachment<'a>>,
 }
 
 impl<'a> Compiled<'a> {
@@ -323,11 +466,23 @@ impl<'a> Compiled<'a> {
                     ));
                 }
 
-                for part in &self.attached {
-                    builder.attach_part(part.clone());
+                // The attachments are streamed into the output as it is
+                // written, so that they are not also held in memory as
+                // encoded parts
+                for a in &self.attached {
+                    match &a.source {
+                        AttachmentSource::Data(data) => {
+                            builder.attach_reader(a.content_type, &data[..], Some(&a.opts))
+                        }
+                        AttachmentSource::Path(path) => {
+                            builder.attach_file(a.content_type, *path, Some(&a.opts))
+                        }
+                    }
                 }
 
-                Ok(builder.build()?.to_message_string())
+                let mut generated = vec![];
+                builder.write_message(&mut generated)?;
+                Ok(String::from_utf8_lossy(&generated).into_owned())
             }
         }
     }
@@ -372,15 +527,14 @@ impl InjectV1Request {
         }
     }
 
-    fn compile(&self) -> anyhow::Result<Compiled> {
-        let mut env = TemplateEngine::new();
+    /// Returns the names and sources of the templates that make up
+    /// the content, in the order in which expand_for_recip uses them
+    fn content_templates(&self) -> anyhow::Result<Vec<(String, &str)>> {
         let mut id = 0;
-
-        // Pass 1: create the templates
+        let mut templates = vec![];
         match &self.content {
             Content::Rfc822(text) => {
-                let name = id.to_string();
-                env.add_template(name, text)?;
+                templates.push((id.to_string(), text.as_str()));
             }
             Content::Builder {
                 text_body: None,
@@ -394,69 +548,61 @@ impl InjectV1Request {
                 ..
             } => {
                 if let Some(tb) = text_body {
-                    let name = id.to_string();
+                    templates.push((id.to_string(), tb.as_str()));
                     id += 1;
-                    env.add_template(name, tb)?;
                 }
                 if let Some(hb) = html_body {
                     // The filename extension is needed to enable auto-escaping
-                    let name = format!("{id}.html");
+                    templates.push((format!("{id}.html"), hb.as_str()));
                     id += 1;
-                    env.add_template(name, hb)?;
                 }
                 for value in headers.values() {
-                    let name = id.to_string();
+                    templates.push((id.to_string(), value.as_str()));
                     id += 1;
-                    env.add_template(name, value)?;
                 }
             }
         }
+        Ok(templates)
+    }
 
-        // Pass 2: retrieve the references
-
-        fn get_templates<'b>(
-            env: &'b TemplateEngine,
-            content: &Content,
-        ) -> anyhow::Result<TemplateList<'b>> {
-            let mut id = 0;
-            let mut templates = vec![];
-            match content {
-                Content::Rfc822(_) => {
-                    let name = id.to_string();
-                    templates.push(env.get_template(&name)?);
-                }
-                Content::Builder {
-                    text_body,
-                    html_body,
-                    headers,
-                    ..
-                } => {
-                    if text_body.is_some() {
-                        let name = id.to_string();
-                        id += 1;
-                        templates.push(env.get_template(&name)?);
-                    }
-                    if html_body.is_some() {
-                        // The filename extension is needed to enable auto-escaping
-                        let name = format!("{id}.html");
-                        id += 1;
-                        templates.push(env.get_template(&name)?);
-                    }
-                    for _ in headers {
-                        let name = id.to_string();
-                        id += 1;
-                        templates.push(env.get_template(&name)?);
-                    }
-                }
-            };
-            Ok(templates)
+    /// Computes the TEMPLATE_CACHE key for the templates
+    fn template_cache_key(&self, sources: &[(String, &str)], filters: &[TemplateFilter]) -> String {
+        let mut hasher = Sha256::new();
+        let mut add = |s: &str| {
+            hasher.update(&(s.len() as u64).to_le_bytes());
+            hasher.update(s.as_bytes());
+        };
+        // The filters are defined by the policy, so a change
+        // to the configuration must invalidate the cache
+        add(&format!("{:?}", config::epoch::get_current_epoch()));
+        for filter in filters {
+            add(&filter.name);
+        }
+        for (name, source) in sources {
+            add(name);
+            add(source);
+        }
+        let partials: BTreeMap<_, _> = self.templates.iter().collect();
+        for (name, source) in partials {
+            add(name);
+            add(source);
         }
+        data_encoding::HEXLOWER.encode(&hasher.finish())
+    }
 
-        let attached = self.attachment_data()?;
+    fn compile(&self, filters: &[TemplateFilter]) -> anyhow::Result<Compiled> {
+        let sources = self.content_templates()?;
+        let key = self.template_cache_key(&sources, filters);
+
+        let env_and_templates = match TEMPLATE_CACHE.get(&key) {
+            Some(compiled) => compiled,
+            None => {
+                let compiled = Arc::new(self.compile_templates(sources, filters)?);
+                TEMPLATE_CACHE.insert(key, compiled, Instant::now() + TEMPLATE_CACHE_TTL)
+            }
+        };
 
-        let env_and_templates = CompiledTemplates::try_new(env, |env: &TemplateEngine| {
-            get_templates(env, &self.content)
-        })?;
+        let attached = self.attachment_data()?;
 
         Ok(Compiled {
             env_and_templates,
@@ -464,7 +610,43 @@ impl InjectV1Request {
         })
     }
 
-    fn attachment_data(&self) -> anyhow::Result<Vec<MimePart>> {
+    fn compile_templates(
+        &self,
+        sources: Vec<(String, &str)>,
+        filters: &[TemplateFilter],
+    ) -> anyhow::Result<CompiledTemplates> {
+        let mut env = TemplateEngine::new();
+
+        // Pass 1: create the templates
+        for (name, source) in &sources {
+            env.add_template(name, *source)?;
+        }
+        for (name, source) in &self.templates {
+            anyhow::ensure!(
+                !sources.iter().any(|(n, _)| n == name),
+                "template name {name} is reserved for the content of the message"
+            );
+            env.add_template(name, source)?;
+        }
+        for filter in filters {
+            filter.clone().add_to(&mut env);
+        }
+
+        // Pass 2: retrieve the references
+        let names: Vec<String> = sources.into_iter().map(|(name, _)| name).collect();
+        CompiledTemplates::try_new(
+            env,
+            |env: &TemplateEngine| -> anyhow::Result<TemplateList> {
+                let mut templates = vec![];
+                for name in &names {
+                    templates.push(env.get_template(name)?);
+                }
+                Ok(templates)
+            },
+        )
+    }
+
+    fn attachment_data(&self) -> anyhow::Result<Vec<CompiledAttachment>> {
         match &self.content {
             Content::Rfc822(_) => Ok(vec![]),
             Content::Builder { attachments, .. } => {
@@ -476,25 +658,40 @@ impl InjectV1Request {
                         content_id: a.content_id.clone(),
                     };
 
-                    let decoded_data;
-
-                    let part = MimePart::new_binary(
-                        &a.content_type,
-                        if a.base64 {
-                            decoded_data = data_encoding::BASE64.decode(a.data.as_bytes())?;
-                            &decoded_data
-                        } else {
-                            a.data.as_bytes()
-                        },
-                        Some(&opts),
-                    );
+                    let source = match &a.path {
+                        Some(path) => {
+                            anyhow::ensure!(
+                                a.data.is_empty(),
+                                "attachment cannot have both data and path"
+                            );
+                            std::fs::metadata(path)
+                                .with_context(|| format!("attachment path {path}"))?;
+                            AttachmentSource::Path(path)
+                        }
+                        None if a.base64 => AttachmentSource::Data(Cow::Owned(
+                            data_encoding::BASE64.decode(a.data.as_bytes())?,
+                        )),
+                        None => AttachmentSource::Data(Cow::Borrowed(a.data.as_bytes())),
+                    };
 
-                    attached.push(part);
+                    attached.push(CompiledAttachment {
+                        content_type: &a.content_type,
+                        opts,
+                        source,
+                    });
                 }
                 Ok(attached)
             }
         }
     }
+
+    /// Returns true if any of the attachments reference a file
+    fn has_attachment_path(&self) -> bool {
+        match &self.content {
+            Content::Rfc822(_) => false,
+            Content::Builder { attachments, .. } => attachments.iter().any(|a| a.path.is_some()),
+        }
+    }
 }
 
 async fn process_recipient<'a>(
@@ -505,11 +702,17 @@ async fn process_recipient<'a>(
     request: &'a InjectV1Request,
     compiled: &Compiled<'a>,
     auth: &AuthKind,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<SpoolId> {
     MSGS_RECVD.inc();
     let recip_addr = EnvelopeAddress::parse(&recip.email)
         .with_context(|| format!("recipient email {}", recip.email))?;
 
+    if let Some(entry) =
+        crate::suppression::check_recipient(SuppressionStage::Reception, &recip_addr).await
+    {
+        anyhow::bail!("recipient is suppressed: {}", entry.reason);
+    }
+
     let generated = compiled.expand_for_recip(recip, &request.substitutions, &request.content)?;
 
     // build into a Message
@@ -550,6 +753,10 @@ async fn process_recipient<'a>(
     message.set_meta("reception_protocol", "HTTP")?;
     message.set_meta("received_from", peer_address.to_string())?;
 
+    // Applied before http_message_generated, so that the rewritten
+    // headers are covered by any DKIM signature that it adds
+    crate::header_rewrite::apply_at_reception(&message)?;
+
     // call callback to assign to queue
     let sig = CallbackSignature::<message::Message, ()>::new("http_message_generated");
     config.async_call_callback(&sig, message.clone()).await?;
@@ -591,7 +798,7 @@ async fn process_recipient<'a>(
             .await?;
     }
 
-    Ok(())
+    Ok(id)
 }
 
 async fn queue_deferred(
@@ -649,6 +856,7 @@ async fn queue_deferred(
         fail_count: 0,
         failed_recipients: vec![],
         errors: vec![],
+        recipient_results: None,
     })
 }
 
@@ -660,19 +868,29 @@ async fn inject_v1_impl(
 ) -> Result<Json<InjectV1Response>, AppError> {
     request.normalize()?;
 
+    if request.deferred_generation && request.return_recipient_results {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            "return_recipient_results cannot be used together with deferred_generation",
+        ))
+        .into());
+    }
+
     if request.deferred_generation {
         return Ok(Json(
             queue_deferred(auth, sender, peer_address, request).await?,
         ));
     }
 
-    let compiled = request.compile()?;
+    let mut config = load_config().await?;
+    let filters = config.with_lua(template_filters)?;
+    let compiled = request.compile(&filters)?;
     let mut success_count = 0;
     let mut fail_count = 0;
     let mut errors = vec![];
     let mut failed_recipients = vec![];
-    let mut config = load_config().await?;
-    for recip in &request.recipients {
+    let mut recipient_results = vec![];
+    for (index, recip) in request.recipients.iter().enumerate() {
         match process_recipient(
             &mut config,
             &sender,
@@ -684,13 +902,29 @@ async fn inject_v1_impl(
         )
         .await
         {
-            Ok(()) => {
+            Ok(id) => {
                 success_count += 1;
+                if request.return_recipient_results {
+                    recipient_results.push(InjectV1RecipientResult {
+                        index,
+                        email: recip.email.to_string(),
+                        id: Some(id.to_string()),
+                        error: None,
+                    });
+                }
             }
             Err(err) => {
                 fail_count += 1;
                 failed_recipients.push(recip.email.to_string());
                 errors.push(format!("{}: {err:#}", recip.email));
+                if request.return_recipient_results {
+                    recipient_results.push(InjectV1RecipientResult {
+                        index,
+                        email: recip.email.to_string(),
+                        id: None,
+                        error: Some(format!("{err:#}")),
+                    });
+                }
             }
         }
     }
@@ -699,6 +933,9 @@ async fn inject_v1_impl(
         fail_count,
         failed_recipients,
         errors,
+        recipient_results: request
+            .return_recipient_results
+            .then_some(recipient_results),
     }))
 }
 
@@ -729,6 +966,21 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    module.set(
+        "register_template_filter",
+        lua.create_function(|lua, (name, func): (String, mlua::Function)| {
+            let filters = match lua.named_registry_value::<Option<mlua::Table>>(TEMPLATE_FILTERS)? {
+                Some(filters) => filters,
+                None => {
+                    let filters = lua.create_table()?;
+                    lua.set_named_registry_value(TEMPLATE_FILTERS, filters.clone())?;
+                    filters
+                }
+            };
+            filters.set(name, func)
+        })?,
+    )?;
+
     Ok(())
 }
 
@@ -748,6 +1000,22 @@ pub async fn inject_v1(
     // Note: Json<> must be last in the param list
     Json(request): Json<InjectV1Request>,
 ) -> Result<Json<InjectV1Response>, AppError> {
+    if !auth.has_scope(ApiTokenScope::Inject) {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::FORBIDDEN,
+            "Insufficient scope",
+        ))
+        .into());
+    }
+    if request.has_attachment_path() {
+        // Reading files from the local filesystem is reserved
+        // for the trusted policy
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            "attachment path is not permitted via HTTP",
+        ))
+        .into());
+    }
     if kumo_server_memory::get_headroom() == 0 {
         // Using too much memory
         return Err(anyhow::anyhow!("load shedding").into());
@@ -755,6 +1023,23 @@ pub async fn inject_v1(
     if kumo_server_common::disk_space::is_over_limit() {
         return Err(anyhow::anyhow!("disk is too full").into());
     }
+    if spool::quota::is_over_soft_quota() {
+        return Err(anyhow::Error::new(
+            StatusCodeError::new(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "spool quota exceeded. Try later",
+            )
+            .with_retry_after(SPOOL_QUOTA_RETRY_AFTER),
+        )
+        .into());
+    }
+    if let Some((_code, message)) = crate::http_server::admin_drain_v1::check_reject() {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            message,
+        ))
+        .into());
+    }
 
     let limit = LIMIT.load();
     if let Some(limit) = limit.as_ref() {
@@ -912,6 +1197,7 @@ impl QueueDispatcher for HttpInjectionGeneratorDispatcher {
 #[cfg(test)]
 mod test {
     use super::*;
+    use mailparsing::DecodedBody;
 
     #[tokio::test]
     async fn test_generate_basic() {
@@ -930,12 +1216,14 @@ This is a test message to {{ name }}, with some 👻🍉💩 emoji!
             }],
             substitutions: HashMap::new(),
             content: Content::Rfc822(input.to_string()),
+            templates: HashMap::new(),
             deferred_spool: true,
             deferred_generation: false,
             trace_headers: Default::default(),
+            return_recipient_results: false,
         };
 
-        let compiled = request.compile().unwrap();
+        let compiled = request.compile(&[]).unwrap();
         let generated = compiled
             .expand_for_recip(
                 &request.recipients[0],
@@ -978,12 +1266,14 @@ This is a test message to {{ name }}, with some 👻🍉💩 emoji!
             }],
             substitutions: HashMap::new(),
             content: Content::Rfc822(input.to_string()),
+            templates: HashMap::new(),
             deferred_spool: true,
             deferred_generation: false,
             trace_headers: Default::default(),
+            return_recipient_results: false,
         };
 
-        let compiled = request.compile().unwrap();
+        let compiled = request.compile(&[]).unwrap();
         let generated = compiled
             .expand_for_recip(
                 &request.recipients[0],
@@ -1009,6 +1299,69 @@ This is a test message to James Smythe, with some =F0=9F=91=BB=F0=9F=8D=89=\r
         );
     }
 
+    #[tokio::test]
+    async fn test_generate_partials_and_filters() {
+        let input = r#"From: Me <me@example.com>
+Subject: items
+To: "{{ name }}" <{{ email }}>
+
+{% for item in items %}{{ item | shout }}
+{% endfor %}{% include "footer" %}
+"#;
+        let request = InjectV1Request {
+            envelope_sender: "noreply@example.com".to_string(),
+            recipients: vec![Recipient {
+                email: "user@example.com".to_string(),
+                name: Some("James Smythe".to_string()),
+                substitutions: [("items".to_string(), serde_json::json!(["one", "two"]))]
+                    .into_iter()
+                    .collect(),
+            }],
+            substitutions: HashMap::new(),
+            content: Content::Rfc822(input.to_string()),
+            templates: [("footer".to_string(), "Bye {{ name }}".to_string())]
+                .into_iter()
+                .collect(),
+            deferred_spool: true,
+            deferred_generation: false,
+            trace_headers: Default::default(),
+            return_recipient_results: false,
+        };
+
+        let lua = Lua::new();
+        let filters = vec![TemplateFilter {
+            name: "shout".to_string(),
+            func: lua
+                .load("function(s) return string.upper(s) end")
+                .eval()
+                .unwrap(),
+            lua,
+        }];
+
+        let compiled = request.compile(&filters).unwrap();
+        let generated = compiled
+            .expand_for_recip(
+                &request.recipients[0],
+                &request.substitutions,
+                &request.content,
+            )
+            .unwrap();
+        assert!(
+            generated.contains("\r\n\r\nONE\r\nTWO\r\nBye James Smythe"),
+            "{generated}"
+        );
+
+        let mut request = request;
+        request
+            .templates
+            .insert("0".to_string(), "clash".to_string());
+        let err = request.compile(&filters).err().unwrap();
+        assert_eq!(
+            format!("{err:#}"),
+            "template name 0 is reserved for the content of the message"
+        );
+    }
+
     #[tokio::test]
     async fn test_generate_builder() {
         let mut request = InjectV1Request {
@@ -1019,6 +1372,7 @@ This is a test message to James Smythe, with some =F0=9F=91=BB=F0=9F=8D=89=\r
                 substitutions: HashMap::new(),
             }],
             substitutions: HashMap::new(),
+            templates: HashMap::new(),
             content: Content::Builder {
                 text_body: Some("I am the plain text, {{ name }}. 😀".to_string()),
                 html_body: Some(
@@ -1031,6 +1385,7 @@ This is a test message to James Smythe, with some =F0=9F=91=BB=F0=9F=8D=89=\r
                     content_type: "image/gif".to_string(),
                     content_id: Some("my-image".to_string()),
                     file_name: None,
+                    path: None,
                 }],
                 subject: Some("hello {{ name }} 👫".to_string()),
                 from: None,
@@ -1040,10 +1395,11 @@ This is a test message to James Smythe, with some =F0=9F=91=BB=F0=9F=8D=89=\r
             deferred_spool: true,
             deferred_generation: false,
             trace_headers: Default::default(),
+            return_recipient_results: false,
         };
 
         request.normalize().unwrap();
-        let compiled = request.compile().unwrap();
+        let compiled = request.compile(&[]).unwrap();
         let generated = compiled
             .expand_for_recip(
                 &request.recipients[0],
@@ -1092,6 +1448,67 @@ Some(
         k9::assert_equal!(structure.attachments.len(), 1);
     }
 
+    #[tokio::test]
+    async fn test_generate_builder_attachment_path() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, &data).unwrap();
+
+        let mut request = InjectV1Request {
+            envelope_sender: "noreply@example.com".to_string(),
+            recipients: vec![Recipient {
+                email: "user@example.com".to_string(),
+                name: None,
+                substitutions: HashMap::new(),
+            }],
+            substitutions: HashMap::new(),
+            templates: HashMap::new(),
+            content: Content::Builder {
+                text_body: Some("See attached".to_string()),
+                html_body: None,
+                attachments: vec![Attachment {
+                    data: String::new(),
+                    base64: false,
+                    content_type: "application/octet-stream".to_string(),
+                    content_id: None,
+                    file_name: Some("data.bin".to_string()),
+                    path: Some(file.path().to_str().unwrap().to_string()),
+                }],
+                subject: None,
+                from: None,
+                reply_to: None,
+                headers: Default::default(),
+            },
+            deferred_spool: true,
+            deferred_generation: false,
+            trace_headers: Default::default(),
+            return_recipient_results: false,
+        };
+        assert!(request.has_attachment_path());
+
+        request.normalize().unwrap();
+        let compiled = request.compile(&[]).unwrap();
+        let generated = compiled
+            .expand_for_recip(
+                &request.recipients[0],
+                &request.substitutions,
+                &request.content,
+            )
+            .unwrap();
+
+        let parsed = MimePart::parse(generated.as_str()).unwrap();
+        let structure = parsed.simplified_structure().unwrap();
+        k9::assert_equal!(structure.attachments.len(), 1);
+        match structure.attachments[0].body().unwrap() {
+            DecodedBody::Binary(body) => assert!(body == data),
+            DecodedBody::Text(_) => panic!("expected a binary attachment"),
+        }
+
+        // A missing file is reported when the request is compiled
+        drop(file);
+        assert!(request.compile(&[]).is_err());
+    }
+
     #[tokio::test]
     async fn test_to_from_builder() {
         let mut request = InjectV1Request {
@@ -1102,6 +1519,7 @@ Some(
                 substitutions: HashMap::new(),
             }],
             substitutions: HashMap::new(),
+            templates: HashMap::new(),
             content: Content::Builder {
                 text_body: Some("I am the plain text, {{ name }}. 😀".to_string()),
                 html_body: Some(
@@ -1120,10 +1538,11 @@ Some(
             deferred_spool: true,
             deferred_generation: false,
             trace_headers: Default::default(),
+            return_recipient_results: false,
         };
 
         request.normalize().unwrap();
-        let compiled = request.compile().unwrap();
+        let compiled = request.compile(&[]).unwrap();
         let generated = compiled
             .expand_for_recip(
                 &request.recipients[0],
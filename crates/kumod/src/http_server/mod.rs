@@ -2,41 +2,99 @@ use crate::smtp_server::TraceHeaders;
 use axum::routing::{delete, get, post};
 use axum::Router;
 use inject_v1::*;
+use kumo_api_types::analytics::*;
+use kumo_api_types::cluster::*;
+use kumo_api_types::config_snapshot::*;
+use kumo_api_types::drain::*;
+use kumo_api_types::egress_preflight::*;
+use kumo_api_types::mx_pin::*;
 use kumo_api_types::rebind::*;
+use kumo_api_types::scheduled_queue::*;
+use kumo_api_types::suppression::*;
+use kumo_api_types::tuning::*;
+use kumo_api_types::wire_capture::*;
 use kumo_api_types::*;
 use kumo_server_common::http_server::RouterAndDocs;
 use spool::SpoolId;
 use utoipa::OpenApi;
 
+pub mod admin_analytics_v1;
+pub mod admin_bounce_classify_v1;
 pub mod admin_bounce_v1;
+pub mod admin_cluster_status_v1;
+pub mod admin_config_snapshot_v1;
+pub mod admin_drain_v1;
+pub mod admin_egress_preflight_v1;
 pub mod admin_inspect_message;
+pub mod admin_message_search_v1;
+pub mod admin_mx_pin_v1;
 pub mod admin_ready_queue_states;
 pub mod admin_rebind_v1;
+pub mod admin_scheduled_queue_v1;
+pub mod admin_spoolin_status_v1;
+pub mod admin_suppression_v1;
 pub mod admin_suspend_ready_q_v1;
 pub mod admin_suspend_v1;
+pub mod admin_tail_logs_v1;
 pub mod admin_trace_smtp_client_v1;
 pub mod admin_trace_smtp_server_v1;
+pub mod admin_tuning_v1;
+pub mod admin_webhook_backlog_v1;
+pub mod admin_wire_capture_v1;
 pub mod check_liveness_v1;
+pub mod healthz;
 pub mod inject_v1;
+pub mod unsubscribe_v1;
 
 #[derive(OpenApi)]
 #[openapi(
     info(title = "kumod",),
     paths(
         inject_v1::inject_v1,
+        admin_analytics_v1::get_analytics,
+        admin_bounce_classify_v1::classify,
         admin_bounce_v1::bounce_v1,
         admin_bounce_v1::bounce_v1_list,
         admin_bounce_v1::bounce_v1_delete,
+        admin_cluster_status_v1::cluster_status,
+        admin_cluster_status_v1::node_status,
+        admin_config_snapshot_v1::config_snapshot,
+        admin_drain_v1::drain_v1,
+        admin_drain_v1::drain_v1_status,
+        admin_drain_v1::drain_v1_cancel,
+        admin_egress_preflight_v1::get_report,
+        admin_egress_preflight_v1::run_preflight,
         admin_inspect_message::inspect_v1,
+        admin_message_search_v1::search,
+        admin_mx_pin_v1::pin,
+        admin_mx_pin_v1::list,
+        admin_mx_pin_v1::delete,
         admin_ready_queue_states::readyq_states,
         admin_rebind_v1::rebind_v1,
+        admin_scheduled_queue_v1::list_queues,
+        admin_scheduled_queue_v1::list_messages,
+        admin_spoolin_status_v1::spoolin_status,
         admin_suspend_ready_q_v1::suspend,
         admin_suspend_ready_q_v1::list,
         admin_suspend_ready_q_v1::delete,
+        admin_suppression_v1::add,
+        admin_suppression_v1::query,
+        admin_suppression_v1::remove,
         admin_suspend_v1::suspend,
         admin_suspend_v1::list,
         admin_suspend_v1::delete,
+        admin_tail_logs_v1::tail,
+        admin_trace_smtp_client_v1::trace,
+        admin_trace_smtp_server_v1::trace,
+        admin_tuning_v1::get_tuning,
+        admin_tuning_v1::set_tuning,
+        admin_webhook_backlog_v1::list,
+        admin_webhook_backlog_v1::flush,
+        admin_wire_capture_v1::set_wire_capture,
         check_liveness_v1::check_liveness_v1,
+        healthz::healthz,
+        healthz::readyz,
+        unsubscribe_v1::unsubscribe_v1,
     ),
     components(
         schemas(
@@ -47,31 +105,96 @@ pub mod inject_v1;
             Attachment,
             InjectV1Request,
             InjectV1Response,
+            InjectV1RecipientResult,
             SpoolId,
+            BounceClassifyV1Request,
+            BounceClassifyV1Response,
             BounceV1Request,
             BounceV1Response,
             BounceV1ListEntry,
             BounceV1CancelRequest,
+            ClusterNodeStatusV1,
+            ClusterReadyQueueSuspensionV1,
+            ClusterStatusV1Response,
+            ClusterSuspensionV1,
+            ConfigSnapshotV1,
+            ConfigSnapshotV1EgressPath,
+            DrainV1Request,
+            DrainV1Response,
+            DrainV1Status,
             InspectMessageV1Response,
             MessageInformation,
+            MessageSearchV1Entry,
+            MessageSearchV1Event,
+            MessageSearchV1Status,
+            MetricsSummaryV1,
+            MxPinV1CancelRequest,
+            MxPinV1ListEntry,
+            MxPinV1Request,
+            MxPinV1Response,
+            NodeStatusV1Response,
             ReadyQueueStateRequest,
             ReadyQueueStateResponse,
             QueueState,
+            ReadinessCheckV1,
+            ReadinessV1Response,
             RebindV1Request,
             RebindV1Response,
+            ScheduledQueueV1Message,
+            ScheduledQueueV1Summary,
+            SpoolInStatusV1Response,
+            SuppressionV1AddRequest,
+            SuppressionV1Entry,
+            SuppressionV1RemoveRequest,
             SuspendReadyQueueV1Request,
             SuspendV1Response,
             SuspendReadyQueueV1ListEntry,
             SuspendV1CancelRequest,
             SuspendV1ListEntry,
             SuspendV1Request,
+            TailLogsV1Request,
             TraceHeaders,
+            TraceSmtpV1Request,
+            TraceSmtpV1Event,
+            TraceSmtpV1Payload,
+            TraceSmtpClientV1Request,
+            TraceSmtpClientV1Event,
+            TraceSmtpClientV1Payload,
+            ReadyQueueTuningV1,
+            TuningV1Request,
+            TuningV1Response,
+            WebhookBacklogV1ListEntry,
+            WebhookBacklogFlushV1Request,
+            WebhookBacklogFlushV1Response,
+            WireCaptureV1Request,
+            WireCaptureV1Response,
+            AnalyticsDimension,
+            AnalyticsV1Counts,
+            AnalyticsV1Entry,
+            EgressPreflightCheck,
+            EgressPreflightStatus,
+            EgressPreflightV1CheckResult,
+            EgressPreflightV1Report,
+            EgressPreflightV1Request,
+            EgressPreflightV1Source,
         ),
         responses(
             InjectV1Response,
+            BounceClassifyV1Response,
             BounceV1Response,
+            ClusterStatusV1Response,
+            DrainV1Response,
+            DrainV1Status,
             InspectMessageV1Response,
-            ReadyQueueStateResponse
+            MxPinV1Response,
+            NodeStatusV1Response,
+            ReadinessV1Response,
+            ReadyQueueStateResponse,
+            SpoolInStatusV1Response,
+            TuningV1Response,
+            WebhookBacklogFlushV1Response,
+            WireCaptureV1Response,
+            EgressPreflightV1Report
         ),
     )
 )]
@@ -84,18 +207,79 @@ pub fn make_router() -> RouterAndDocs {
                 "/api/check-liveness/v1",
                 get(check_liveness_v1::check_liveness_v1),
             )
+            .route("/healthz", get(healthz::healthz))
+            .route("/readyz", get(healthz::readyz))
             .route("/api/inject/v1", post(inject_v1::inject_v1))
+            .route("/api/unsubscribe/v1", post(unsubscribe_v1::unsubscribe_v1))
+            .route(
+                "/api/admin/analytics/v1",
+                get(admin_analytics_v1::get_analytics),
+            )
+            .route(
+                "/api/admin/bounce-classify/v1",
+                post(admin_bounce_classify_v1::classify),
+            )
             .route("/api/admin/bounce/v1", post(admin_bounce_v1::bounce_v1))
             .route("/api/admin/bounce/v1", get(admin_bounce_v1::bounce_v1_list))
             .route(
                 "/api/admin/bounce/v1",
                 delete(admin_bounce_v1::bounce_v1_delete),
             )
+            .route(
+                "/api/admin/cluster-status/v1",
+                get(admin_cluster_status_v1::cluster_status),
+            )
+            .route(
+                "/api/admin/node-status/v1",
+                get(admin_cluster_status_v1::node_status),
+            )
+            .route(
+                "/api/admin/egress-preflight/v1",
+                get(admin_egress_preflight_v1::get_report),
+            )
+            .route(
+                "/api/admin/egress-preflight/v1",
+                post(admin_egress_preflight_v1::run_preflight),
+            )
+            .route(
+                "/api/admin/config-snapshot/v1",
+                get(admin_config_snapshot_v1::config_snapshot),
+            )
+            .route("/api/admin/drain/v1", post(admin_drain_v1::drain_v1))
+            .route("/api/admin/drain/v1", get(admin_drain_v1::drain_v1_status))
+            .route(
+                "/api/admin/drain/v1",
+                delete(admin_drain_v1::drain_v1_cancel),
+            )
+            .route("/api/admin/mx-pin/v1", post(admin_mx_pin_v1::pin))
+            .route("/api/admin/mx-pin/v1", get(admin_mx_pin_v1::list))
+            .route("/api/admin/mx-pin/v1", delete(admin_mx_pin_v1::delete))
             .route(
                 "/api/admin/ready-q-states/v1",
                 get(admin_ready_queue_states::readyq_states),
             )
             .route("/api/admin/rebind/v1", post(admin_rebind_v1::rebind_v1))
+            .route(
+                "/api/admin/scheduled-queues/v1",
+                get(admin_scheduled_queue_v1::list_queues),
+            )
+            .route(
+                "/api/admin/scheduled-queue-messages/v1",
+                get(admin_scheduled_queue_v1::list_messages),
+            )
+            .route(
+                "/api/admin/spoolin-status/v1",
+                get(admin_spoolin_status_v1::spoolin_status),
+            )
+            .route("/api/admin/suppression/v1", post(admin_suppression_v1::add))
+            .route(
+                "/api/admin/suppression/v1",
+                get(admin_suppression_v1::query),
+            )
+            .route(
+                "/api/admin/suppression/v1",
+                delete(admin_suppression_v1::remove),
+            )
             .route("/api/admin/suspend/v1", post(admin_suspend_v1::suspend))
             .route("/api/admin/suspend/v1", get(admin_suspend_v1::list))
             .route("/api/admin/suspend/v1", delete(admin_suspend_v1::delete))
@@ -115,6 +299,13 @@ pub fn make_router() -> RouterAndDocs {
                 "/api/admin/inspect-message/v1",
                 get(admin_inspect_message::inspect_v1),
             )
+            .route(
+                "/api/admin/message-search/v1",
+                get(admin_message_search_v1::search),
+            )
+            .route("/api/admin/tail-logs/v1", get(admin_tail_logs_v1::tail))
+            .route("/api/admin/tuning/v1", get(admin_tuning_v1::get_tuning))
+            .route("/api/admin/tuning/v1", post(admin_tuning_v1::set_tuning))
             .route(
                 "/api/admin/trace-smtp-client/v1",
                 get(admin_trace_smtp_client_v1::trace),
@@ -122,6 +313,18 @@ pub fn make_router() -> RouterAndDocs {
             .route(
                 "/api/admin/trace-smtp-server/v1",
                 get(admin_trace_smtp_server_v1::trace),
+            )
+            .route(
+                "/api/admin/webhook-backlog/v1",
+                get(admin_webhook_backlog_v1::list),
+            )
+            .route(
+                "/api/admin/webhook-backlog/v1",
+                post(admin_webhook_backlog_v1::flush),
+            )
+            .route(
+                "/api/admin/wire-capture/v1",
+                post(admin_wire_capture_v1::set_wire_capture),
             ),
         docs: ApiDoc::openapi(),
     }
@@ -0,0 +1,43 @@
+use axum::extract::Query;
+use axum::http::StatusCode;
+use kumo_api_types::UnsubscribeV1Request;
+use kumo_server_common::http_server::{AppError, StatusCodeError};
+
+/// Receives a one-click unsubscribe request, as described by RFC 8058,
+/// from a URL added by `kumo.add_list_unsubscribe_headers`.
+/// The request is authenticated by its encrypted token, rather than by
+/// the usual HTTP authentication.
+#[utoipa::path(
+    post,
+    tag="unsubscribe",
+    path="/api/unsubscribe/v1",
+    params(UnsubscribeV1Request),
+    responses(
+        (status = 200, description = "The recipient was unsubscribed"),
+        (status = 400, description = "The token is invalid or has expired"),
+    ),
+)]
+pub async fn unsubscribe_v1(
+    Query(request): Query<UnsubscribeV1Request>,
+    body: String,
+) -> Result<&'static str, AppError> {
+    // RFC 8058 requires the body to contain the List-Unsubscribe-Post
+    // value; it may be sent as either a urlencoded or a multipart form
+    if !body.contains("List-Unsubscribe=One-Click") {
+        return Err(anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            "expected a List-Unsubscribe=One-Click request body",
+        ))
+        .into());
+    }
+
+    let unsub = crate::list_unsubscribe::validate_token(&request.token).map_err(|err| {
+        anyhow::Error::new(StatusCodeError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{err:#}"),
+        ))
+    })?;
+    crate::list_unsubscribe::unsubscribe(unsub).await?;
+
+    Ok("Unsubscribed")
+}
@@ -0,0 +1,364 @@
+//! This module implements RFC 8058 one-click unsubscribe.
+//!
+//! At reception, policy adds List-Unsubscribe and List-Unsubscribe-Post
+//! headers whose URL includes an encrypted token that identifies the
+//! message and recipient.  When the mailbox provider POSTs to that
+//! URL, the token is validated, an Unsubscribe record is logged and
+//! the list_unsubscribe event is triggered, so that policy can
+//! record the unsubscription.
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use config::{load_config, CallbackSignature, SerdeWrappedValue};
+use data_encoding::BASE64URL_NOPAD;
+use data_loader::KeySource;
+use kumo_log_types::{JsonLogRecord, RecordType};
+use kumo_template::{context, TemplateEngine};
+use message::queue_name::QueueNameComponents;
+use message::Message;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use prometheus::IntCounterVec;
+use rfc5321::Response;
+use serde::{Deserialize, Serialize};
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+
+static LIST_UNSUBSCRIBE: OnceLock<ListUnsubscribe> = OnceLock::new();
+
+static LIST_UNSUBSCRIBE_SIG: LazyLock<CallbackSignature<SerdeWrappedValue<Unsubscription>, ()>> =
+    LazyLock::new(|| CallbackSignature::new("list_unsubscribe"));
+
+static UNSUBSCRIBE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "list_unsubscribe_requests",
+        "total number of one-click unsubscribe requests, by outcome",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListUnsubscribeParams {
+    /// The secret from which the token encryption key is derived
+    pub key: KeySource,
+
+    /// The template for the one-click URL
+    pub url: String,
+
+    /// The optional template for the mailto address
+    #[serde(default)]
+    pub mailto: Option<String>,
+
+    /// How long a token remains valid
+    #[serde(
+        default = "ListUnsubscribeParams::default_lifetime",
+        with = "duration_serde"
+    )]
+    pub lifetime: Duration,
+}
+
+impl ListUnsubscribeParams {
+    fn default_lifetime() -> Duration {
+        Duration::from_secs(90 * 86400)
+    }
+}
+
+/// Overrides for the templates, passed by policy
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ListUnsubscribeOverrides {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub mailto: Option<String>,
+}
+
+struct ListUnsubscribe {
+    /// The AES-256 key used to seal the tokens
+    key: [u8; 32],
+    params: ListUnsubscribeParams,
+}
+
+/// The length of the AES-GCM nonce and tag that surround the token
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// The information sealed into the token.  The token is encrypted
+/// and authenticated, so none of this is visible to anyone that sees
+/// the URL, other than its overall length.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Unsubscription {
+    /// The id of the message
+    pub id: String,
+    pub sender: String,
+    pub recipient: String,
+    /// The queue to which the message was assigned at reception,
+    /// which encodes its campaign and tenant
+    pub queue: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub expires: DateTime<Utc>,
+}
+
+pub async fn configure_list_unsubscribe(params: ListUnsubscribeParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+
+    // Check that the templates are valid now, rather than when
+    // the first message is received
+    let engine = TemplateEngine::new();
+    let sample = context! { token => "token", recipient => "user@example.com" };
+    engine.render("url", &params.url, &sample)?;
+    if let Some(mailto) = &params.mailto {
+        engine.render("mailto", mailto, &sample)?;
+    }
+
+    let secret = params.key.get().await?;
+    LIST_UNSUBSCRIBE
+        .set(ListUnsubscribe::new(&secret, params)?)
+        .map_err(|_| anyhow::anyhow!("configure_list_unsubscribe has already been called"))
+}
+
+impl ListUnsubscribe {
+    /// Derives the token encryption key from the configured secret,
+    /// so that a secret of any length and form can be used
+    fn new(secret: &[u8], params: ListUnsubscribeParams) -> anyhow::Result<Self> {
+        let pkey = PKey::hmac(secret)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+        signer.update(b"kumomta list-unsubscribe token key")?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&signer.sign_to_vec()?);
+        Ok(Self { key, params })
+    }
+
+    fn make_token(&self, unsub: &Unsubscription) -> anyhow::Result<String> {
+        let payload = serde_json::to_vec(unsub)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            &payload,
+            &mut tag,
+        )?;
+
+        let mut token = nonce.to_vec();
+        token.extend_from_slice(&ciphertext);
+        token.extend_from_slice(&tag);
+        Ok(BASE64URL_NOPAD.encode(&token))
+    }
+
+    fn validate_token(&self, token: &str, now: DateTime<Utc>) -> anyhow::Result<Unsubscription> {
+        let token = BASE64URL_NOPAD.decode(token.as_bytes())?;
+        anyhow::ensure!(token.len() > NONCE_LEN + TAG_LEN, "malformed token");
+        let (nonce, rest) = token.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        let payload = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| anyhow::anyhow!("invalid token"))?;
+
+        let unsub: Unsubscription = serde_json::from_slice(&payload)?;
+        anyhow::ensure!(unsub.expires > now, "token has expired");
+        Ok(unsub)
+    }
+
+    fn add_headers(
+        &self,
+        msg: &Message,
+        overrides: ListUnsubscribeOverrides,
+    ) -> anyhow::Result<()> {
+        let unsub = Unsubscription {
+            id: msg.id().to_string(),
+            sender: msg.sender()?.to_string(),
+            recipient: msg.recipient()?.to_string(),
+            queue: msg.get_queue_name()?,
+            expires: Utc::now() + ChronoDuration::from_std(self.params.lifetime)?,
+        };
+        let token = self.make_token(&unsub)?;
+
+        let components = QueueNameComponents::parse(&unsub.queue);
+        let ctx = context! {
+            token,
+            id => unsub.id,
+            sender => unsub.sender,
+            recipient => unsub.recipient,
+            campaign => components.campaign,
+            tenant => components.tenant,
+            domain => components.domain,
+        };
+
+        let engine = TemplateEngine::new();
+        let url = engine.render(
+            "url",
+            overrides.url.as_deref().unwrap_or(&self.params.url),
+            &ctx,
+        )?;
+        let mut value = format!("<{url}>");
+        if let Some(mailto) = overrides.mailto.as_ref().or(self.params.mailto.as_ref()) {
+            let mailto = engine.render("mailto", mailto, &ctx)?;
+            value.push_str(&format!(", <mailto:{mailto}>"));
+        }
+
+        // Replace any headers that were present in the injected
+        // message, as they cannot be one-click compliant
+        msg.remove_all_named_headers("List-Unsubscribe")?;
+        msg.remove_all_named_headers("List-Unsubscribe-Post")?;
+        msg.prepend_header(Some("List-Unsubscribe-Post"), "List-Unsubscribe=One-Click");
+        msg.prepend_header(Some("List-Unsubscribe"), &value);
+        Ok(())
+    }
+}
+
+/// Adds the List-Unsubscribe and List-Unsubscribe-Post headers to msg
+pub fn add_headers(msg: &Message, overrides: ListUnsubscribeOverrides) -> anyhow::Result<()> {
+    LIST_UNSUBSCRIBE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("kumo.configure_list_unsubscribe has not been called"))?
+        .add_headers(msg, overrides)
+}
+
+fn make_record(unsub: &Unsubscription, now: DateTime<Utc>) -> JsonLogRecord {
+    JsonLogRecord {
+        kind: RecordType::Unsubscribe,
+        id: unsub.id.clone(),
+        sender: unsub.sender.clone(),
+        recipient: unsub.recipient.clone(),
+        queue: unsub.queue.clone(),
+        site: String::new(),
+        size: 0,
+        response: Response {
+            code: 0,
+            enhanced_code: None,
+            content: String::new(),
+            command: None,
+        },
+        peer_address: None,
+        timestamp: now,
+        created: now,
+        num_attempts: 0,
+        latency: None,
+        bounce_classification: Default::default(),
+        egress_pool: None,
+        egress_source: None,
+        source_address: None,
+        feedback_report: None,
+        meta: Default::default(),
+        headers: Default::default(),
+        delivery_protocol: None,
+        reception_protocol: Some("HTTP".to_string()),
+        nodeid: kumo_server_common::nodeid::NodeId::get_uuid(),
+        tls_cipher: None,
+        tls_protocol_version: None,
+        tls_peer_subject_name: None,
+        provider_name: None,
+        session_id: None,
+        suppressed_count: None,
+        response_category: None,
+        annotations: vec![],
+        tenant_usage: None,
+    }
+}
+
+/// Validates the token from a one-click unsubscribe request
+pub fn validate_token(token: &str) -> anyhow::Result<Unsubscription> {
+    let list_unsubscribe = LIST_UNSUBSCRIBE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("kumo.configure_list_unsubscribe has not been called"))?;
+
+    let result = list_unsubscribe.validate_token(token, Utc::now());
+    UNSUBSCRIBE_REQUESTS
+        .with_label_values(&[if result.is_ok() { "valid" } else { "invalid" }])
+        .inc();
+    result
+}
+
+/// Logs an Unsubscribe record and triggers the list_unsubscribe event
+pub async fn unsubscribe(unsub: Unsubscription) -> anyhow::Result<()> {
+    crate::logging::Logger::log_to_all(make_record(&unsub, Utc::now())).await;
+
+    let mut config = load_config().await?;
+    config
+        .async_call_callback(&LIST_UNSUBSCRIBE_SIG, SerdeWrappedValue(unsub))
+        .await?;
+    config.put();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn list_unsubscribe(secret: &str) -> ListUnsubscribe {
+        ListUnsubscribe::new(
+            secret.as_bytes(),
+            ListUnsubscribeParams {
+                key: KeySource::Data {
+                    key_data: secret.to_string(),
+                },
+                url: "https://example.com/api/unsubscribe/v1?token={{ token }}".to_string(),
+                mailto: None,
+                lifetime: ListUnsubscribeParams::default_lifetime(),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let list_unsubscribe = list_unsubscribe("secret");
+        let now = Utc::now();
+        let unsub = Unsubscription {
+            id: "d7ef132b5d7711eea8c8000c29c33806".to_string(),
+            sender: "sender@example.com".to_string(),
+            recipient: "user@example.net".to_string(),
+            queue: "campaign:tenant@example.net".to_string(),
+            expires: now + ChronoDuration::days(1),
+        };
+        let token = list_unsubscribe.make_token(&unsub).unwrap();
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)));
+
+        // The token must not reveal its contents
+        let raw = BASE64URL_NOPAD.decode(token.as_bytes()).unwrap();
+        let raw = String::from_utf8_lossy(&raw);
+        assert!(!raw.contains("user@example.net"));
+        assert!(!raw.contains("campaign"));
+        assert_ne!(token, list_unsubscribe.make_token(&unsub).unwrap());
+
+        let decoded = list_unsubscribe.validate_token(&token, now).unwrap();
+        assert_eq!(decoded.recipient, unsub.recipient);
+        assert_eq!(decoded.queue, unsub.queue);
+
+        let err = list_unsubscribe
+            .validate_token(&token, now + ChronoDuration::days(2))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "token has expired");
+
+        let other = list_unsubscribe("other secret");
+        let err = other.validate_token(&token, now).unwrap_err();
+        assert_eq!(err.to_string(), "invalid token");
+
+        let mut tampered = BASE64URL_NOPAD.decode(token.as_bytes()).unwrap();
+        tampered[NONCE_LEN] ^= 1;
+        let err = list_unsubscribe
+            .validate_token(&BASE64URL_NOPAD.encode(&tampered), now)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid token");
+
+        assert!(other.validate_token("garbage", now).is_err());
+    }
+}
@@ -4,12 +4,13 @@ use bounce_classify::{
 };
 use config::epoch::{get_current_epoch, ConfigEpoch};
 use kumo_log_types::{JsonLogRecord, RecordType};
-use lru_cache::LruCache;
+use lruttl::LruCacheWithTtl;
 use parking_lot::Mutex;
-use prometheus::Histogram;
-use rfc5321::Response;
+use prometheus::{Histogram, IntCounter};
+use regex::{Captures, Regex};
 use serde::Deserialize;
 use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 
 static CLASSIFY_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
@@ -19,13 +20,42 @@ static CLASSIFY_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
     )
     .unwrap()
 });
+static CLASSIFY_CACHE_HIT: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "bounce_classify_cache_hit",
+        "how many times a bounce classification was satisfied by the cache"
+    )
+    .unwrap()
+});
+static CLASSIFY_CACHE_MISS: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "bounce_classify_cache_miss",
+        "how many times a bounce classification required evaluating the rules"
+    )
+    .unwrap()
+});
 static CLASSIFY: OnceLock<ClassifierWrapper> = OnceLock::new();
 
+/// Matches the portions of a response that tend to differ between
+/// otherwise identical responses: timestamps, and identifiers such
+/// as queue ids and host names that contain digits
+static VARIABLE_TOKENS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:\.\d+)?Z?|\d{1,2}:\d{2}:\d{2}(?:\.\d+)?|[0-9A-Za-z]{8,}",
+    )
+    .unwrap()
+});
+
 #[derive(Deserialize, Clone, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct ClassifierParams {
     pub files: Vec<String>,
 
+    /// Additional rules files whose rules are consulted before those
+    /// from `files`, and which therefore take precedence over them
+    #[serde(default)]
+    pub override_files: Vec<String>,
+
     #[serde(default = "ClassifierParams::default_back_pressure")]
     pub back_pressure: usize,
 
@@ -37,6 +67,13 @@ pub struct ClassifierParams {
 
     #[serde(default = "ClassifierParams::default_cache_size")]
     pub uncategorized_cache_size: usize,
+
+    /// How long a cached classification remains valid
+    #[serde(
+        default = "ClassifierParams::default_cache_ttl",
+        with = "duration_serde"
+    )]
+    pub cache_ttl: Duration,
 }
 
 impl ClassifierParams {
@@ -54,9 +91,15 @@ impl ClassifierParams {
         1024
     }
 
+    fn default_cache_ttl() -> Duration {
+        Duration::from_secs(3600)
+    }
+
     fn load(&self) -> anyhow::Result<BounceClassifier> {
         let mut builder = BounceClassifierBuilder::new();
-        for file_name in &self.files {
+        // The first matching rule wins, so the overrides must be
+        // merged ahead of the regular files
+        for file_name in self.override_files.iter().chain(self.files.iter()) {
             if file_name.ends_with(".json") {
                 builder
                     .merge_json_file(file_name)
@@ -85,7 +128,7 @@ impl ClassifierParams {
 }
 
 struct ClassifyRequest {
-    response: Response,
+    text: String,
     tx: oneshot::Sender<BounceClass>,
     epoch: ConfigEpoch,
 }
@@ -95,21 +138,25 @@ struct ClassifyRequest {
 /// could be a large set of responses that do not have a categorization
 /// and we don't want those to thrash and out-compete the actual
 /// classifications and render the whole class completely ineffective.
+///
+/// The caches are keyed by the normalized response text; see
+/// `normalize_response`.
 struct State {
-    cache: LruCache<Response, BounceClass>,
-    uncat_cache: LruCache<Response, BounceClass>,
+    cache: LruCacheWithTtl<String, BounceClass>,
+    uncat_cache: LruCacheWithTtl<String, BounceClass>,
+    cache_ttl: Duration,
     classifier: Arc<BounceClassifier>,
     classifier_epoch: ConfigEpoch,
 }
 
 impl State {
-    fn insert(&mut self, response: Response, result: BounceClass) {
+    fn insert(&self, text: String, result: BounceClass) {
         let cache = match &result {
-            BounceClass::PreDefined(PreDefinedBounceClass::Uncategorized) => &mut self.uncat_cache,
-            _ => &mut self.cache,
+            BounceClass::PreDefined(PreDefinedBounceClass::Uncategorized) => &self.uncat_cache,
+            _ => &self.cache,
         };
 
-        cache.insert(response.clone(), result.clone());
+        cache.insert(text, result, Instant::now() + self.cache_ttl);
     }
 
     /// clear the caches and return a copy of the classifier.
@@ -156,8 +203,12 @@ impl ClassifierWrapper {
 
         let epoch = get_current_epoch();
         let state = Arc::new(Mutex::new(State {
-            cache: LruCache::new(params.cache_size),
-            uncat_cache: LruCache::new(params.uncategorized_cache_size),
+            cache: LruCacheWithTtl::new_named("bounce_classify", params.cache_size),
+            uncat_cache: LruCacheWithTtl::new_named(
+                "bounce_classify_uncategorized",
+                params.uncategorized_cache_size,
+            ),
+            cache_ttl: params.cache_ttl,
             classifier: classifier.clone(),
             classifier_epoch: epoch,
         }));
@@ -175,12 +226,7 @@ impl ClassifierWrapper {
                 .spawn(move || {
                     let mut my_epoch = epoch;
                     let mut classifier = classifier;
-                    while let Ok(ClassifyRequest {
-                        response,
-                        tx,
-                        epoch,
-                    }) = rx.recv()
-                    {
+                    while let Ok(ClassifyRequest { text, tx, epoch }) = rx.recv() {
                         tracing::trace!("classify request with {epoch:?}");
                         if epoch != my_epoch {
                             if let Some(c) = state.lock().get_updated_classifier(epoch) {
@@ -190,11 +236,11 @@ impl ClassifierWrapper {
                             }
                         }
 
-                        let result = classifier.classify_response(&response);
+                        let result = classifier.classify_str(&text);
                         if epoch == my_epoch {
                             // Only cache if the epochs match up, as a cheap defensive
                             // measure to avoid poisoning the cache with a stale result
-                            state.lock().insert(response.clone(), result.clone());
+                            state.lock().insert(text, result.clone());
                         }
                         if tx.send(result).is_err() {
                             break;
@@ -239,23 +285,23 @@ impl ClassifierWrapper {
         Ok(Self { tx, state })
     }
 
-    fn check_cache(&self, response: &Response) -> Option<BounceClass> {
-        let mut state = self.state.lock();
-        if let Some(result) = state.cache.get_mut(response) {
-            return Some(result.clone());
+    fn check_cache(&self, text: &str) -> Option<BounceClass> {
+        let state = self.state.lock();
+        if let Some(result) = state.cache.get(text) {
+            return Some(result);
         }
-        if let Some(result) = state.uncat_cache.get_mut(response) {
-            return Some(result.clone());
+        if let Some(result) = state.uncat_cache.get(text) {
+            return Some(result);
         }
 
         None
     }
 
-    async fn classify(&self, response: Response) -> anyhow::Result<BounceClass> {
+    async fn classify(&self, text: String) -> anyhow::Result<BounceClass> {
         let (tx, rx) = oneshot::channel();
         self.tx
             .send_async(ClassifyRequest {
-                response,
+                text,
                 tx,
                 epoch: get_current_epoch(),
             })
@@ -264,6 +310,33 @@ impl ClassifierWrapper {
     }
 }
 
+/// Returns the single line form of a response with the timestamps
+/// and identifiers, which tend to be unique to each response, replaced
+/// by `#`, so that near-identical responses can share a cache entry.
+/// Classification is performed on the normalized text, so that the
+/// cached result is correct for every response that shares it.
+pub fn normalize_response(line: &str) -> String {
+    VARIABLE_TOKENS
+        .replace_all(line, |caps: &Captures| {
+            let token = &caps[0];
+            if token.bytes().all(|b| b.is_ascii_alphabetic()) {
+                token.to_string()
+            } else {
+                "#".to_string()
+            }
+        })
+        .into_owned()
+}
+
+/// Classifies `text` using the most recently loaded rules, returning the
+/// classification and the rule that matched, if any.
+/// Returns None if no classifier has been configured.
+pub fn explain_classification(text: &str) -> Option<(BounceClass, Option<String>)> {
+    let classifier = CLASSIFY.get()?.state.lock().classifier.clone();
+    let (class, rule) = classifier.explain_str(&normalize_response(text));
+    Some((class, rule.map(|rule| rule.to_string())))
+}
+
 pub async fn apply_classification(record: &mut JsonLogRecord) {
     // No sense classifying receptions or deliveries as bounces, as they are not bounces!
     if matches!(record.kind, RecordType::Reception | RecordType::Delivery) {
@@ -277,15 +350,59 @@ pub async fn apply_classification(record: &mut JsonLogRecord) {
 
     let _timer = CLASSIFY_LATENCY.start_timer();
 
+    let text = normalize_response(&record.response.to_single_line());
+
     // Check the caches before we commit any serious resources to
     // classifying this response
-    if let Some(result) = classifier.check_cache(&record.response) {
-        record.bounce_classification = result;
-        return;
-    }
+    let result = match classifier.check_cache(&text) {
+        Some(result) => {
+            CLASSIFY_CACHE_HIT.inc();
+            result
+        }
+        None => {
+            CLASSIFY_CACHE_MISS.inc();
+            // pass to the classifier thread pool
+            match classifier.classify(text).await {
+                Ok(result) => result,
+                Err(_) => return,
+            }
+        }
+    };
+
+    let label: String = result.clone().into();
+    crate::metrics_helper::bounce_classification_for_provider(
+        &label,
+        record.provider_name.as_deref().unwrap_or(&record.site),
+    )
+    .inc();
+    record.bounce_classification = result;
+}
 
-    // clone data and pass to the classifier thread pool
-    if let Ok(result) = classifier.classify(record.response.clone()).await {
-        record.bounce_classification = result;
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            normalize_response(
+                "421 4.7.28 Gmail has detected an unusual rate of unsolicited mail. \
+                 x12si3456789qkd.123 - gsmtp"
+            ),
+            "421 4.7.28 Gmail has detected an unusual rate of unsolicited mail. \
+             #.123 - gsmtp"
+        );
+        assert_eq!(
+            normalize_response(
+                "451 4.7.500 Server busy. Please try again later from [10.0.0.1]. (S3150) \
+                 [BN8NAM11FT012.eop-nam11.prod.protection.outlook.com 2024-03-08T17:51:42.481Z]"
+            ),
+            "451 4.7.500 Server busy. Please try again later from [10.0.0.1]. (S3150) \
+             [#.eop-nam11.prod.protection.outlook.com #]"
+        );
+        assert_eq!(
+            normalize_response("452 4.2.2 mailbox full, queued as 20240308175142"),
+            "452 4.2.2 mailbox full, queued as #"
+        );
     }
 }
@@ -8,6 +8,8 @@ use kumo_log_types::MaybeProxiedSourceAddress;
 pub use kumo_log_types::*;
 use message::Message;
 use rfc5321::{EnhancedStatusCode, Response, TlsInformation};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use uuid::Uuid;
 
@@ -44,8 +46,45 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         session_id,
     } = args;
 
+    let now = Utc::now();
+    let latency = match kind {
+        RecordType::Delivery | RecordType::Bounce | RecordType::Expiration => {
+            let latency = (now - msg.id().created())
+                .to_std()
+                .unwrap_or_default()
+                .as_secs_f64();
+            // The site name is unbounded, so it is not used as a
+            // fallback for the provider label
+            crate::metrics_helper::observe_final_disposition(
+                kind,
+                provider.unwrap_or("unknown"),
+                egress_pool.unwrap_or(""),
+                latency,
+                msg.get_num_attempts(),
+            );
+            Some(latency)
+        }
+        _ => None,
+    };
+
+    crate::message_index::record(kind, &msg, site, &response).await;
+    crate::analytics::record(
+        kind,
+        &msg,
+        provider.unwrap_or(site),
+        egress_pool,
+        relay_disposition,
+    )
+    .await;
+    crate::tenant_usage::record(kind, &msg).await;
+    if let Some(latency) = latency {
+        crate::age_slo::record(kind, &msg, latency).await;
+    }
+    crate::dsn::generate_for_disposition(kind, &msg, &response, peer_address).await;
+
     let loggers = Logger::get_loggers();
-    if loggers.is_empty() {
+    let tailing = crate::logging::tail::is_active();
+    if loggers.is_empty() && !tailing {
         return;
     }
 
@@ -64,9 +103,81 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
         }
     }
 
-    let now = Utc::now();
     let nodeid = kumo_server_common::nodeid::NodeId::get_uuid();
 
+    let response_category = match kind {
+        RecordType::TransientFailure | RecordType::Bounce => {
+            crate::logging::response_category::categorize_response(
+                provider,
+                &response.to_single_line(),
+            )
+        }
+        _ => None,
+    };
+
+    // The annotations explain how the message was handled,
+    // so they are included in the records of its final disposition
+    let annotations = match kind {
+        RecordType::Delivery
+        | RecordType::Bounce
+        | RecordType::Expiration
+        | RecordType::AdminBounce => msg.get_annotations().unwrap_or_default(),
+        _ => vec![],
+    };
+
+    let mut tls_cipher = None;
+    let mut tls_protocol_version = None;
+    let mut tls_peer_subject_name = None;
+    if let Some(info) = tls_info {
+        tls_cipher.replace(info.cipher.clone());
+        tls_protocol_version.replace(info.protocol_version.clone());
+        tls_peer_subject_name.replace(info.subject_name.clone());
+    }
+
+    let make_record =
+        |headers: HashMap<String, Value>, meta: HashMap<String, Value>| JsonLogRecord {
+            kind,
+            id: msg.id().to_string(),
+            size: msg.get_data_len() as u64,
+            sender: msg
+                .sender()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|err| format!("{err:#}")),
+            recipient: msg
+                .recipient()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|err| format!("{err:#}")),
+            queue: msg
+                .get_queue_name()
+                .unwrap_or_else(|err| format!("{err:#}")),
+            site: site.to_string(),
+            peer_address: peer_address.cloned(),
+            response: response.clone(),
+            timestamp: now,
+            created: msg.id().created(),
+            num_attempts: msg.get_num_attempts(),
+            latency,
+            egress_pool: egress_pool.map(|s| s.to_string()),
+            egress_source: egress_source.map(|s| s.to_string()),
+            bounce_classification: BounceClass::default(),
+            feedback_report: feedback_report.clone(),
+            headers,
+            meta,
+            delivery_protocol: delivery_protocol.map(|s| s.to_string()),
+            reception_protocol: reception_protocol.clone(),
+            nodeid,
+            tls_cipher: tls_cipher.clone(),
+            tls_protocol_version: tls_protocol_version.clone(),
+            tls_peer_subject_name: tls_peer_subject_name.clone(),
+            source_address: source_address.clone(),
+            provider_name: provider.map(|s| s.to_string()),
+            session_id,
+            suppressed_count: None,
+            response_category: response_category.clone(),
+            annotations: annotations.clone(),
+            tenant_usage: None,
+        };
+
     for logger in loggers.iter() {
         if !logger.record_is_enabled(kind) {
             continue;
@@ -116,52 +227,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
 
         let (headers, meta) = logger.extract_fields(&msg).await;
 
-        let mut tls_cipher = None;
-        let mut tls_protocol_version = None;
-        let mut tls_peer_subject_name = None;
-        if let Some(info) = tls_info {
-            tls_cipher.replace(info.cipher.clone());
-            tls_protocol_version.replace(info.protocol_version.clone());
-            tls_peer_subject_name.replace(info.subject_name.clone());
-        }
-
-        let record = JsonLogRecord {
-            kind,
-            id: msg.id().to_string(),
-            size: msg.get_data().len() as u64,
-            sender: msg
-                .sender()
-                .map(|addr| addr.to_string())
-                .unwrap_or_else(|err| format!("{err:#}")),
-            recipient: msg
-                .recipient()
-                .map(|addr| addr.to_string())
-                .unwrap_or_else(|err| format!("{err:#}")),
-            queue: msg
-                .get_queue_name()
-                .unwrap_or_else(|err| format!("{err:#}")),
-            site: site.to_string(),
-            peer_address: peer_address.cloned(),
-            response: response.clone(),
-            timestamp: now,
-            created: msg.id().created(),
-            num_attempts: msg.get_num_attempts(),
-            egress_pool: egress_pool.map(|s| s.to_string()),
-            egress_source: egress_source.map(|s| s.to_string()),
-            bounce_classification: BounceClass::default(),
-            feedback_report: feedback_report.clone(),
-            headers: headers.clone(),
-            meta: meta.clone(),
-            delivery_protocol: delivery_protocol.map(|s| s.to_string()),
-            reception_protocol: reception_protocol.clone(),
-            nodeid,
-            tls_cipher,
-            tls_protocol_version,
-            tls_peer_subject_name,
-            source_address: source_address.clone(),
-            provider_name: provider.map(|s| s.to_string()),
-            session_id,
-        };
+        let record = make_record(headers.clone(), meta.clone());
         if let Err(err) = logger.log(record).await {
             tracing::error!("failed to log: {err:#}");
         }
@@ -233,6 +299,7 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                             timestamp: recip.last_attempt_date.unwrap_or_else(|| Utc::now()),
                             created: msg.id().created(),
                             num_attempts: 0,
+                            latency: None,
                             egress_pool: None,
                             egress_source: None,
                             bounce_classification: BounceClass::default(),
@@ -248,6 +315,10 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
                             source_address: None,
                             provider_name: provider.map(|s| s.to_string()),
                             session_id,
+                            suppressed_count: None,
+                            response_category: None,
+                            annotations: vec![],
+                            tenant_usage: None,
                         };
 
                         if let Err(err) = logger.log(record).await {
@@ -258,4 +329,15 @@ pub async fn log_disposition(args: LogDisposition<'_>) {
             }
         }
     }
+
+    if tailing {
+        // Clients that are tailing the logs see all of the metadata,
+        // as they may filter on any of it
+        let meta = msg
+            .get_meta_obj()
+            .ok()
+            .and_then(|meta| serde_json::from_value(meta).ok())
+            .unwrap_or_default();
+        crate::logging::tail::submit(make_record(HashMap::new(), meta)).await;
+    }
 }
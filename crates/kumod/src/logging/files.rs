@@ -1,16 +1,26 @@
+use crate::logging::retention::{LogRetentionParams, RotatedSegment};
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
 use crate::logging::{LogCommand, LogRecordParams};
 use anyhow::Context;
 use chrono::Utc;
-use flume::Receiver;
+use data_loader::KeySource;
+use flume::{Receiver, Sender};
 pub use kumo_log_types::*;
 use kumo_server_common::disk_space::MinFree;
 use kumo_template::{Template, TemplateEngine};
+use openssl::cms::{CMSOptions, CmsContentInfo};
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::X509;
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use zstd::stream::write::Encoder;
 
 #[derive(Deserialize, Clone, Debug)]
@@ -53,10 +63,33 @@ pub struct LogFileParams {
     #[serde(default)]
     pub filter_event: Option<String>,
 
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
+
     #[serde(default)]
     pub min_free_space: MinFree,
     #[serde(default)]
     pub min_free_inodes: MinFree,
+
+    /// The name of a meta field whose value identifies the tenant.
+    /// When set, records are written into a subdirectory of the
+    /// log_dir named after the tenant.
+    #[serde(default)]
+    pub tenant_meta: Option<String>,
+
+    /// Maps tenant names to the X.509 certificate(s) with which
+    /// the log segments for that tenant will be encrypted
+    #[serde(default)]
+    pub tenant_encryption_certificates: HashMap<String, KeySource>,
+
+    /// Controls how long completed segments are kept
+    #[serde(default)]
+    pub retention: Option<LogRetentionParams>,
 }
 
 impl LogFileParams {
@@ -69,12 +102,95 @@ impl LogFileParams {
     pub fn default_compression_level() -> i32 {
         0 // use the zstd default
     }
+
+    pub async fn load_tenant_certificates(&self) -> anyhow::Result<HashMap<String, Vec<X509>>> {
+        if let Some(name) = &self.tenant_meta {
+            anyhow::ensure!(
+                self.meta.contains(name),
+                "tenant_meta field `{name}` must also be listed in meta"
+            );
+        } else if !self.tenant_encryption_certificates.is_empty() {
+            anyhow::bail!("tenant_encryption_certificates requires tenant_meta to be set");
+        }
+
+        // The certificates are keyed by the directory name, as that is
+        // what identifies the tenant of a segment. Since the mapping from
+        // tenant name to directory name is lossy, we must ensure that no
+        // tenant can be confused with another.
+        let mut dirs: HashMap<String, &str> = HashMap::new();
+        for tenant in self.tenant_encryption_certificates.keys() {
+            let dir = tenant_dir_name(tenant).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "tenant_encryption_certificates: tenant {tenant:?} \
+                     cannot be used as a log directory name"
+                )
+            })?;
+            if let Some(other) = dirs.insert(dir.clone(), tenant) {
+                anyhow::bail!(
+                    "tenant_encryption_certificates: tenants {other:?} and {tenant:?} \
+                     would share the log directory {dir:?}"
+                );
+            }
+        }
+
+        let mut result = HashMap::new();
+        for (dir, tenant) in dirs {
+            let source = &self.tenant_encryption_certificates[tenant];
+            let data = source
+                .get()
+                .await
+                .with_context(|| format!("loading encryption certificate for tenant {tenant}"))?;
+            let certs = X509::stack_from_pem(&data)
+                .with_context(|| format!("parsing encryption certificate for tenant {tenant}"))?;
+            anyhow::ensure!(
+                !certs.is_empty(),
+                "no certificates found in encryption certificate for tenant {tenant}"
+            );
+            result.insert(dir, certs);
+        }
+        Ok(result)
+    }
+}
+
+/// The name of the hidden file in each tenant directory that
+/// records the name of the tenant to which it belongs
+const TENANT_MARKER: &str = ".tenant";
+
+/// Produces a tenant name from the value of the tenant_meta field
+fn tenant_name(tenant: &Value) -> Option<String> {
+    match tenant {
+        Value::String(s) => Some(s.to_string()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Produces a directory name from a tenant name, or None if the
+/// tenant cannot be safely represented as a directory name
+fn tenant_dir_name(tenant: &str) -> Option<String> {
+    let name: String = tenant
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        None
+    } else {
+        Some(name)
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub(crate) struct FileNameKey {
     log_dir: PathBuf,
     suffix: Option<String>,
+    /// The directory name of the tenant
+    tenant: Option<String>,
 }
 
 pub(crate) struct OpenedFile {
@@ -82,17 +198,82 @@ pub(crate) struct OpenedFile {
     name: PathBuf,
     written: u64,
     expires: Option<Instant>,
+    encrypt_with: Option<Vec<X509>>,
+    log_dir: PathBuf,
+    tenant: Option<String>,
+    rotated: Option<Sender<RotatedSegment>>,
 }
 
 impl Drop for OpenedFile {
     fn drop(&mut self) {
         self.file.do_finish().ok();
-        mark_path_as_done(&self.name).ok();
+        let path = match &self.encrypt_with {
+            Some(certs) => encrypt_or_discard_segment(&self.name, certs),
+            None => mark_path_as_done(&self.name)
+                .ok()
+                .map(|_| self.name.clone()),
+        };
         tracing::debug!("Flushed {:?}", self.name);
+
+        if let (Some(path), Some(rotated)) = (path, &self.rotated) {
+            rotated
+                .send(RotatedSegment {
+                    path,
+                    log_dir: self.log_dir.clone(),
+                    tenant: self.tenant.clone(),
+                    encrypted: self.encrypt_with.is_some(),
+                })
+                .ok();
+        }
     }
 }
 
-fn mark_path_as_done(path: &PathBuf) -> std::io::Result<()> {
+/// Replaces a completed log segment with a CMS (PKCS#7)
+/// enveloped copy of its content, named with a `.p7m` suffix,
+/// returning the path to the encrypted copy
+fn encrypt_segment(path: &Path, certs: &[X509]) -> anyhow::Result<PathBuf> {
+    let data = std::fs::read(path).with_context(|| format!("reading {path:?}"))?;
+    let mut stack = Stack::new()?;
+    for cert in certs {
+        stack.push(cert.clone())?;
+    }
+    let cms = CmsContentInfo::encrypt(&stack, &data, Cipher::aes_256_cbc(), CMSOptions::BINARY)?;
+
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(".p7m");
+    let encrypted = PathBuf::from(encrypted);
+    let result = std::fs::write(&encrypted, cms.to_der()?)
+        .with_context(|| format!("writing {encrypted:?}"))
+        .and_then(|_| Ok(mark_path_as_done(&encrypted)?));
+    if let Err(err) = result {
+        // Don't leave a partially written copy behind
+        std::fs::remove_file(&encrypted).ok();
+        return Err(err);
+    }
+    std::fs::remove_file(path).with_context(|| format!("removing {path:?}"))?;
+    Ok(encrypted)
+}
+
+/// Encrypts a completed log segment, returning the path to the
+/// encrypted copy. If it cannot be encrypted, the plain text segment
+/// is removed rather than being left where it may be handed over
+/// to the tenant or read by someone else.
+fn encrypt_or_discard_segment(path: &Path, certs: &[X509]) -> Option<PathBuf> {
+    match encrypt_segment(path, certs) {
+        Ok(path) => Some(path),
+        Err(err) => {
+            tracing::error!(
+                "Failed to encrypt {path:?}, discarding the unencrypted segment: {err:#}"
+            );
+            if let Err(err) = std::fs::remove_file(path) {
+                tracing::error!("Failed to remove unencrypted segment {path:?}: {err:#}");
+            }
+            None
+        }
+    }
+}
+
+fn mark_path_as_done(path: &Path) -> std::io::Result<()> {
     let meta = path.metadata()?;
     // Remove the `w` bit to signal to the tailer that this
     // file will not be written to any more and that it is
@@ -102,7 +283,10 @@ fn mark_path_as_done(path: &PathBuf) -> std::io::Result<()> {
     std::fs::set_permissions(&path, perms)
 }
 
-fn mark_existing_logs_as_done_in_dir(dir: &PathBuf) -> anyhow::Result<()> {
+fn mark_existing_logs_as_done_in_dir(
+    dir: &PathBuf,
+    tenant_certificates: Option<&HashMap<String, Vec<X509>>>,
+) -> anyhow::Result<()> {
     for entry in std::fs::read_dir(dir).with_context(|| format!("reading dir {dir:?}"))? {
         if let Ok(entry) = entry {
             match entry.file_name().to_str() {
@@ -110,10 +294,21 @@ fn mark_existing_logs_as_done_in_dir(dir: &PathBuf) -> anyhow::Result<()> {
                     continue;
                 }
                 None => continue,
-                Some(_name) => {
+                Some(name) => {
                     if let Ok(file_type) = entry.file_type() {
                         if file_type.is_file() {
                             mark_path_as_done(&entry.path()).ok();
+                        } else if file_type.is_dir() {
+                            // A per-tenant directory
+                            let Some(tenant_certificates) = tenant_certificates else {
+                                continue;
+                            };
+                            if let Err(err) = finish_existing_tenant_logs(
+                                &entry.path(),
+                                tenant_certificates.get(name),
+                            ) {
+                                tracing::error!("Error: {err:#}");
+                            }
                         }
                     }
                 }
@@ -123,11 +318,64 @@ fn mark_existing_logs_as_done_in_dir(dir: &PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+fn finish_existing_tenant_logs(dir: &Path, certs: Option<&Vec<X509>>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading dir {dir:?}"))? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_hidden = entry
+            .file_name()
+            .to_str()
+            .map_or(true, |n| n.starts_with('.'));
+        if is_hidden || !entry.file_type()?.is_file() {
+            continue;
+        }
+        match certs {
+            Some(certs) if path.extension().map_or(true, |ext| ext != "p7m") => {
+                encrypt_or_discard_segment(&path, certs);
+            }
+            _ => {
+                mark_path_as_done(&path).ok();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ensures that a tenant directory belongs to the specified tenant,
+/// recording it in the directory if it has not yet been claimed.
+/// This prevents tenants whose names map to the same directory name
+/// from being mixed together across restarts.
+fn claim_tenant_dir(dir: &Path, tenant: &str) -> anyhow::Result<()> {
+    let marker = dir.join(TENANT_MARKER);
+    match std::fs::read_to_string(&marker) {
+        Ok(owner) => {
+            anyhow::ensure!(
+                owner == tenant,
+                "tenant {tenant:?} would share the log directory {dir:?} \
+                 with tenant {owner:?}; record discarded"
+            );
+            Ok(())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => std::fs::write(&marker, tenant)
+            .with_context(|| format!("writing tenant marker {marker:?}")),
+        Err(err) => Err(err).with_context(|| format!("reading tenant marker {marker:?}")),
+    }
+}
+
 pub struct LogThreadState {
     pub params: LogFileParams,
     pub receiver: Receiver<LogCommand>,
     pub template_engine: TemplateEngine,
     pub file_map: HashMap<FileNameKey, OpenedFile>,
+    /// Keyed by tenant directory name
+    pub tenant_certificates: HashMap<String, Vec<X509>>,
+    /// Maps tenant directory names to the name of the tenant to
+    /// which they belong, so that records for distinct tenants
+    /// whose names map to the same directory are not mixed together
+    pub tenant_dirs: HashMap<String, String>,
+    /// Receives each completed segment
+    pub rotated: Option<Sender<RotatedSegment>>,
+    pub segment_manager: Option<JoinHandle<()>>,
 }
 
 impl LogThreadState {
@@ -135,6 +383,12 @@ impl LogThreadState {
         tracing::debug!("LogFileParams: {:#?}", self.params);
 
         self.mark_existing_logs_as_done();
+        // The tenants that have certificates always own their directory
+        for tenant in self.params.tenant_encryption_certificates.keys() {
+            if let Some(dir) = tenant_dir_name(tenant) {
+                self.tenant_dirs.insert(dir, tenant.to_string());
+            }
+        }
         let mut expire_counter = 0u16;
 
         loop {
@@ -188,15 +442,29 @@ impl LogThreadState {
 
         tracing::debug!("Clearing any buffered files prior to completion");
         self.file_map.clear();
+
+        // Allow the segment manager to finish processing the
+        // segments that were just completed
+        self.rotated.take();
+        if let Some(manager) = self.segment_manager.take() {
+            manager.await.ok();
+        }
     }
 
     fn mark_existing_logs_as_done(&self) {
-        if let Err(err) = mark_existing_logs_as_done_in_dir(&self.params.log_dir) {
+        let tenant_certificates = self
+            .params
+            .tenant_meta
+            .as_ref()
+            .map(|_| &self.tenant_certificates);
+        if let Err(err) =
+            mark_existing_logs_as_done_in_dir(&self.params.log_dir, tenant_certificates)
+        {
             tracing::error!("Error: {err:#}");
         }
         for params in self.params.per_record.values() {
             if let Some(log_dir) = &params.log_dir {
-                if let Err(err) = mark_existing_logs_as_done_in_dir(log_dir) {
+                if let Err(err) = mark_existing_logs_as_done_in_dir(log_dir, tenant_certificates) {
                     tracing::error!("Error: {err:#}");
                 }
             }
@@ -248,8 +516,38 @@ impl LogThreadState {
         None
     }
 
-    fn do_record(&mut self, record: JsonLogRecord) -> anyhow::Result<()> {
+    /// Resolves the directory name for a tenant, ensuring that it
+    /// isn't already in use by a different tenant
+    fn resolve_tenant_dir(&mut self, tenant: &str) -> anyhow::Result<Option<String>> {
+        let Some(dir) = tenant_dir_name(tenant) else {
+            return Ok(None);
+        };
+        match self.tenant_dirs.get(&dir) {
+            Some(owner) if owner == tenant => {}
+            Some(owner) => anyhow::bail!(
+                "tenant {tenant:?} would share the log directory {dir:?} \
+                 with tenant {owner:?}; record discarded"
+            ),
+            None => {
+                self.tenant_dirs.insert(dir.clone(), tenant.to_string());
+            }
+        }
+        Ok(Some(dir))
+    }
+
+    fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
         tracing::trace!("do_record {record:?}");
+        let tenant_name = self
+            .params
+            .tenant_meta
+            .as_ref()
+            .and_then(|name| record.meta.get(name))
+            .and_then(tenant_name);
+        let tenant = match &tenant_name {
+            Some(name) => self.resolve_tenant_dir(name)?,
+            None => None,
+        };
         let file_key = if let Some(per_rec) = self.per_record(record.kind) {
             FileNameKey {
                 log_dir: per_rec
@@ -258,12 +556,14 @@ impl LogThreadState {
                     .unwrap_or(&self.params.log_dir)
                     .to_path_buf(),
                 suffix: per_rec.suffix.clone(),
+                tenant,
             }
         } else {
             // Just use the global settings
             FileNameKey {
                 log_dir: self.params.log_dir.clone(),
                 suffix: None,
+                tenant,
             }
         };
 
@@ -275,7 +575,16 @@ impl LogThreadState {
                 base_name.push_str(suffix);
             }
 
-            let name = file_key.log_dir.join(base_name);
+            let name = match (&file_key.tenant, &tenant_name) {
+                (Some(tenant_dir), Some(tenant)) => {
+                    let dir = file_key.log_dir.join(tenant_dir);
+                    std::fs::create_dir_all(&dir)
+                        .with_context(|| format!("creating tenant log directory {dir:?}"))?;
+                    claim_tenant_dir(&dir, tenant)?;
+                    dir.join(base_name)
+                }
+                _ => file_key.log_dir.join(base_name),
+            };
 
             let f = std::fs::OpenOptions::new()
                 .append(true)
@@ -292,6 +601,14 @@ impl LogThreadState {
                     .params
                     .max_segment_duration
                     .map(|duration| Instant::now() + duration),
+                encrypt_with: file_key
+                    .tenant
+                    .as_ref()
+                    .and_then(|tenant| self.tenant_certificates.get(tenant))
+                    .cloned(),
+                log_dir: file_key.log_dir.clone(),
+                tenant: file_key.tenant.clone(),
+                rotated: self.rotated.clone(),
             };
 
             if let Some(per_rec) = self.per_record(record.kind) {
@@ -315,14 +632,14 @@ impl LogThreadState {
         if let Some(file) = self.file_map.get_mut(&file_key) {
             let mut record_text = Vec::new();
             self.template_engine
-                .add_global("log_record", kumo_template::Value::from_serialize(&record));
+                .add_global("log_record", kumo_template::Value::from_serialize(&logged));
 
             if let Some(template) =
                 Self::resolve_template(&self.params, &self.template_engine, record.kind)
             {
-                template.render_to_write(&record, &mut record_text)?;
+                template.render_to_write(&logged, &mut record_text)?;
             } else {
-                serde_json::to_writer(&mut record_text, &record).context("serializing record")?;
+                serde_json::to_writer(&mut record_text, &logged).context("serializing record")?;
             }
             if record_text.last() != Some(&b'\n') {
                 record_text.push(b'\n');
@@ -346,3 +663,113 @@ impl LogThreadState {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
+    use serde_json::json;
+
+    #[test]
+    fn tenant_names() {
+        assert_eq!(tenant_name(&json!("acme")), Some("acme".to_string()));
+        assert_eq!(tenant_name(&json!(42)), Some("42".to_string()));
+        assert_eq!(tenant_name(&Value::Null), None);
+
+        assert_eq!(tenant_dir_name("acme"), Some("acme".to_string()));
+        // Leading dots and path separators are not permitted
+        assert_eq!(tenant_dir_name("../../etc"), None);
+        assert_eq!(tenant_dir_name("a/b c"), Some("a_b_c".to_string()));
+        assert_eq!(tenant_dir_name(".."), None);
+        assert_eq!(tenant_dir_name(""), None);
+    }
+
+    #[test]
+    fn tenant_dir_claims() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        claim_tenant_dir(dir.path(), "a b")?;
+        claim_tenant_dir(dir.path(), "a b")?;
+        // A distinct tenant with the same directory name is rejected
+        assert!(claim_tenant_dir(dir.path(), "a_b").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_certificate_names() -> anyhow::Result<()> {
+        fn params(tenants: &[&str]) -> LogFileParams {
+            let certs: serde_json::Map<String, Value> = tenants
+                .iter()
+                .map(|tenant| (tenant.to_string(), json!({"key_data": "unused"})))
+                .collect();
+            serde_json::from_value(json!({
+                "log_dir": "/tmp/unused",
+                "meta": ["tenant"],
+                "tenant_meta": "tenant",
+                "tenant_encryption_certificates": certs,
+            }))
+            .unwrap()
+        }
+
+        let err = params(&[".."])
+            .load_tenant_certificates()
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("cannot be used as a log directory name"));
+
+        let err = params(&["a b", "a_b"])
+            .load_tenant_certificates()
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("would share the log directory"));
+        Ok(())
+    }
+
+    fn make_cert() -> (PKey<Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "tenant").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, builder.build())
+    }
+
+    #[test]
+    fn encryption() -> anyhow::Result<()> {
+        let (key, cert) = make_cert();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("20240101-000000.000000000");
+        std::fs::write(&path, b"hello")?;
+
+        let encrypted = encrypt_segment(&path, &[cert.clone()])?;
+        assert!(!path.exists());
+        assert_eq!(encrypted, dir.path().join("20240101-000000.000000000.p7m"));
+        let cms = CmsContentInfo::from_der(&std::fs::read(&encrypted)?)?;
+        assert_eq!(cms.decrypt(&key, &cert)?, b"hello");
+        assert!(encrypted.metadata()?.permissions().readonly());
+
+        // If it cannot be encrypted, the plain text is not left behind
+        let path = dir.path().join("20240101-000001.000000000");
+        std::fs::write(&path, b"hello")?;
+        // Occupy the destination so that writing it fails
+        std::fs::create_dir(dir.path().join("20240101-000001.000000000.p7m"))?;
+        assert!(encrypt_or_discard_segment(&path, &[cert.clone()]).is_none());
+        assert!(!path.exists());
+
+        Ok(())
+    }
+}
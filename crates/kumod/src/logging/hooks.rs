@@ -1,4 +1,6 @@
 use crate::logging::files::LogFileParams;
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
 use crate::logging::{LogCommand, LogRecordParams, LOGGING_RUNTIME};
 use crate::queue::QueueManager;
 use anyhow::Context;
@@ -51,6 +53,14 @@ pub struct LogHookParams {
 
     #[serde(default)]
     pub deferred_spool: bool,
+
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
 }
 
 pub struct LogHookState {
@@ -101,7 +111,8 @@ impl LogHookState {
         }
     }
 
-    async fn do_record(&mut self, record: JsonLogRecord) -> anyhow::Result<()> {
+    async fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
         tracing::trace!("do_record {record:?}");
 
         if record.reception_protocol.as_deref() == Some("LogRecord") {
@@ -135,20 +146,20 @@ impl LogHookState {
 
         let mut record_text = Vec::new();
         self.template_engine
-            .add_global("log_record", kumo_template::Value::from_serialize(&record));
+            .add_global("log_record", kumo_template::Value::from_serialize(&logged));
 
         if let Some(template) =
             Self::resolve_template(&self.params, &self.template_engine, record.kind)
         {
-            template.render_to_write(&record, &mut record_text)?;
+            template.render_to_write(&logged, &mut record_text)?;
         } else {
-            serde_json::to_writer(&mut record_text, &record).context("serializing record")?;
+            serde_json::to_writer(&mut record_text, &logged).context("serializing record")?;
         }
         if record_text.last() != Some(&b'\n') {
             record_text.push(b'\n');
         }
 
-        let record_json = serde_json::to_value(&record)?;
+        let record_json = serde_json::to_value(&logged)?;
 
         let id = SpoolId::new();
         let msg = Message::new_dirty(
@@ -0,0 +1,458 @@
+use crate::logging::files::LogFileParams;
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
+use crate::logging::{default_true, LogCommand, LOGGING_RUNTIME};
+use anyhow::Context;
+use flume::Receiver;
+pub use kumo_log_types::*;
+use kumo_template::{Template, TemplateEngine};
+use message::message::QueueNameComponents;
+use prometheus::{IntCounter, IntCounterVec};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::util::Timeout;
+use rdkafka::ClientConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::sync::{Semaphore, TryAcquireError};
+
+static KAFKA_SENT_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_kafka_sent_count",
+        "how many log records were successfully published to kafka",
+        &["logger"]
+    )
+    .unwrap()
+});
+static KAFKA_FAILED_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_kafka_failed_count",
+        "how many log records could not be published to kafka",
+        &["logger"]
+    )
+    .unwrap()
+});
+static KAFKA_BACKLOG_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_kafka_backlog_count",
+        "how many times publishing a log record to kafka hit the back_pressure",
+        &["logger"]
+    )
+    .unwrap()
+});
+
+/// Which field of the log record to use as the kafka message key.
+/// Kafka assigns records with the same key to the same partition.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KafkaPartitionKey {
+    /// Don't set a key; the producer will distribute records
+    /// across the partitions of the topic
+    None,
+    #[default]
+    Queue,
+    Tenant,
+    Campaign,
+    Domain,
+    Recipient,
+}
+
+impl KafkaPartitionKey {
+    fn key_for(&self, queue: &str, recipient: &str) -> Option<String> {
+        let components = QueueNameComponents::parse(queue);
+        match self {
+            Self::None => None,
+            Self::Queue => Some(queue.to_string()),
+            Self::Tenant => components.tenant.map(|s| s.to_string()),
+            Self::Campaign => components.campaign.map(|s| s.to_string()),
+            Self::Domain => Some(components.domain.to_string()),
+            Self::Recipient => Some(recipient.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl KafkaCompression {
+    fn as_config_value(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Snappy => "snappy",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// How many brokers must acknowledge a record before
+/// it is considered to have been published
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaAcks {
+    /// Don't wait for any acknowledgement
+    None,
+    /// Wait for the partition leader to acknowledge
+    Leader,
+    /// Wait for all in-sync replicas to acknowledge
+    #[default]
+    All,
+}
+
+impl KafkaAcks {
+    fn as_config_value(&self) -> &'static str {
+        match self {
+            Self::None => "0",
+            Self::Leader => "1",
+            Self::All => "all",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaRecordParams {
+    /// Publish to this topic instead of the default topic
+    #[serde(default)]
+    pub topic: Option<String>,
+
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Instead of publishing the json object, format it with this
+    /// minijinja template
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogKafkaParams {
+    /// The unique name to identify this instance of the kafka logger
+    pub name: String,
+
+    /// librdkafka producer configuration, such as `bootstrap.servers`
+    pub producer_config: HashMap<String, String>,
+
+    /// The topic to which records are published, unless overridden
+    /// via per_record
+    pub topic: String,
+
+    #[serde(default)]
+    pub partition_by: KafkaPartitionKey,
+
+    #[serde(default)]
+    pub compression: KafkaCompression,
+
+    #[serde(default)]
+    pub acks: KafkaAcks,
+
+    /// Enable the idempotent producer, which guarantees that
+    /// records are published exactly once and in order.
+    /// Requires acks = "all".
+    #[serde(default = "default_true")]
+    pub enable_idempotence: bool,
+
+    /// Maximum number of records to batch together in a single request
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+
+    /// How long to wait for additional records to accumulate
+    /// before sending a batch
+    #[serde(default, with = "duration_serde")]
+    pub linger: Option<Duration>,
+
+    /// How long to keep retrying to publish an individual record
+    /// before giving up on it
+    #[serde(
+        default = "LogKafkaParams::default_send_timeout",
+        with = "duration_serde"
+    )]
+    pub send_timeout: Duration,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture in the log
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture in the log
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, KafkaRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be published
+    #[serde(default)]
+    pub filter_event: Option<String>,
+
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
+}
+
+impl LogKafkaParams {
+    fn default_send_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub fn build_producer(&self) -> anyhow::Result<FutureProducer> {
+        anyhow::ensure!(
+            !self.enable_idempotence || self.acks == KafkaAcks::All,
+            "enable_idempotence requires acks = \"all\""
+        );
+
+        let mut builder = ClientConfig::new();
+        for (k, v) in &self.producer_config {
+            builder.set(k, v);
+        }
+        builder.set("compression.type", self.compression.as_config_value());
+        builder.set("acks", self.acks.as_config_value());
+        builder.set("enable.idempotence", self.enable_idempotence.to_string());
+        builder.set(
+            "message.timeout.ms",
+            self.send_timeout.as_millis().to_string(),
+        );
+        if let Some(batch_size) = self.batch_size {
+            builder.set("batch.num.messages", batch_size.to_string());
+        }
+        if let Some(linger) = self.linger {
+            builder.set("linger.ms", linger.as_millis().to_string());
+        }
+
+        builder.create().context("creating kafka producer")
+    }
+
+    fn topic_for(&self, kind: RecordType) -> &str {
+        self.per_record
+            .get(&kind)
+            .or_else(|| self.per_record.get(&RecordType::Any))
+            .and_then(|pr| pr.topic.as_deref())
+            .unwrap_or(&self.topic)
+    }
+}
+
+pub struct LogKafkaState {
+    params: LogKafkaParams,
+    receiver: Receiver<LogCommand>,
+    template_engine: TemplateEngine,
+    producer: Arc<FutureProducer>,
+    sema: Arc<Semaphore>,
+    sent: IntCounter,
+    failed: IntCounter,
+}
+
+impl LogKafkaState {
+    pub fn new(
+        params: LogKafkaParams,
+        receiver: Receiver<LogCommand>,
+        template_engine: TemplateEngine,
+        producer: FutureProducer,
+    ) -> anyhow::Result<Self> {
+        let sema = Arc::new(Semaphore::new(params.back_pressure));
+        let sent = KAFKA_SENT_COUNT.get_metric_with_label_values(&[&params.name])?;
+        let failed = KAFKA_FAILED_COUNT.get_metric_with_label_values(&[&params.name])?;
+
+        Ok(Self {
+            params,
+            receiver,
+            template_engine,
+            producer: Arc::new(producer),
+            sema,
+            sent,
+            failed,
+        })
+    }
+
+    pub async fn logger_thread(&mut self) {
+        tracing::debug!("LogKafkaParams: {:#?}", self.params);
+
+        loop {
+            let cmd = match self.receiver.recv_async().await {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    break;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    tracing::debug!("LogCommand::Terminate received. Stopping publishing logs");
+                    break;
+                }
+                LogCommand::Record(record) => {
+                    if let Err(err) = self.do_record(record).await {
+                        tracing::error!("failed to log: {err:#}");
+                    };
+                }
+            }
+        }
+
+        // Wait for the outstanding sends to complete
+        if let Ok(permits) = u32::try_from(self.params.back_pressure) {
+            self.sema.acquire_many(permits).await.ok();
+        }
+        let producer = self.producer.clone();
+        let timeout = self.params.send_timeout;
+        let result =
+            tokio::task::spawn_blocking(move || producer.flush(Timeout::After(timeout))).await;
+        tracing::debug!("flushed kafka producer {}: {result:?}", self.params.name);
+    }
+
+    async fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
+        tracing::trace!("do_record {record:?}");
+
+        // Bound the number of in-flight sends, for the same reasons
+        // as are outlined in LogHookState::do_record
+        let permit = match self.sema.clone().try_acquire_owned() {
+            Ok(p) => p,
+            Err(TryAcquireError::NoPermits) => {
+                KAFKA_BACKLOG_COUNT
+                    .get_metric_with_label_values(&[&self.params.name])?
+                    .inc();
+                self.sema.clone().acquire_owned().await?
+            }
+            Err(TryAcquireError::Closed) => {
+                anyhow::bail!("back_pressure semaphore is closed!?");
+            }
+        };
+
+        let mut payload = Vec::new();
+        self.template_engine
+            .add_global("log_record", kumo_template::Value::from_serialize(&logged));
+
+        if let Some(template) =
+            Self::resolve_template(&self.params, &self.template_engine, record.kind)
+        {
+            template.render_to_write(&logged, &mut payload)?;
+        } else {
+            serde_json::to_writer(&mut payload, &logged).context("serializing record")?;
+        }
+
+        let topic = self.params.topic_for(record.kind).to_string();
+        let key = self
+            .params
+            .partition_by
+            .key_for(&record.queue, &record.recipient);
+        let producer = self.producer.clone();
+        let timeout = self.params.send_timeout;
+        let sent = self.sent.clone();
+        let failed = self.failed.clone();
+        let id = record.id;
+
+        LOGGING_RUNTIME.spawn("log-kafka".to_string(), async move {
+            let result = producer
+                .send(
+                    FutureRecord {
+                        topic: &topic,
+                        partition: None,
+                        payload: Some(&payload),
+                        key: key.as_ref(),
+                        headers: None,
+                        timestamp: None,
+                    },
+                    Timeout::After(timeout),
+                )
+                .await;
+            match result {
+                Ok(_) => sent.inc(),
+                Err((err, _)) => {
+                    failed.inc();
+                    tracing::error!("failed to publish log record for {id} to {topic}: {err:#}");
+                }
+            }
+            drop(permit);
+        })?;
+
+        Ok(())
+    }
+
+    fn resolve_template<'a>(
+        params: &LogKafkaParams,
+        template_engine: &'a TemplateEngine,
+        kind: RecordType,
+    ) -> Option<Template<'a, 'a>> {
+        if let Some(pr) = params.per_record.get(&kind) {
+            if pr.template.is_some() {
+                let label = format!("{kind:?}");
+                return template_engine.get_template(&label).ok();
+            }
+            return None;
+        }
+        if let Some(pr) = params.per_record.get(&RecordType::Any) {
+            if pr.template.is_some() {
+                return template_engine.get_template("Any").ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partition_key() {
+        let queue = "campaign:tenant@example.com";
+        let recipient = "user@example.com";
+
+        assert_eq!(KafkaPartitionKey::None.key_for(queue, recipient), None);
+        assert_eq!(
+            KafkaPartitionKey::Queue
+                .key_for(queue, recipient)
+                .as_deref(),
+            Some(queue)
+        );
+        assert_eq!(
+            KafkaPartitionKey::Tenant
+                .key_for(queue, recipient)
+                .as_deref(),
+            Some("tenant")
+        );
+        assert_eq!(
+            KafkaPartitionKey::Campaign
+                .key_for(queue, recipient)
+                .as_deref(),
+            Some("campaign")
+        );
+        assert_eq!(
+            KafkaPartitionKey::Domain
+                .key_for(queue, recipient)
+                .as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            KafkaPartitionKey::Recipient
+                .key_for(queue, recipient)
+                .as_deref(),
+            Some(recipient)
+        );
+
+        // No tenant in the queue name
+        assert_eq!(
+            KafkaPartitionKey::Tenant.key_for("example.com", recipient),
+            None
+        );
+    }
+}
@@ -1,9 +1,17 @@
 use crate::logging::classify::{apply_classification, ClassifierParams};
 use crate::logging::files::{LogFileParams, LogThreadState};
 use crate::logging::hooks::{LogHookParams, LogHookState};
+use crate::logging::kafka::{LogKafkaParams, LogKafkaState};
+use crate::logging::nats::{LogNatsParams, LogNatsState};
+use crate::logging::otlp::{LogOtlpParams, LogOtlpState};
+use crate::logging::retention::SegmentManager;
+use crate::logging::sampling::TransientFailureSampler;
+use crate::logging::syslog::{LogSyslogParams, LogSyslogState};
+use crate::logging::transform::{LoggedRecord, RecordTransform};
+use crate::logging::webhook::{LogWebhookParams, LogWebhookState};
 use anyhow::Context;
 use config::{any_err, from_lua_value, get_or_create_module};
-use flume::{bounded, Sender, TrySendError};
+use flume::{bounded, unbounded, Sender, TrySendError};
 pub use kumo_log_types::*;
 use kumo_server_common::disk_space::MonitoredPath;
 use kumo_server_runtime::Runtime;
@@ -17,7 +25,7 @@ use serde_json::Value;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, LazyLock};
+use std::sync::{Arc, LazyLock, Weak};
 use tokio::sync::Mutex as TokioMutex;
 use tokio::task::JoinHandle;
 
@@ -25,7 +33,17 @@ pub(crate) mod classify;
 pub(crate) mod disposition;
 pub(crate) mod files;
 pub(crate) mod hooks;
+pub(crate) mod kafka;
+pub(crate) mod nats;
+pub(crate) mod otlp;
 pub(crate) mod rejection;
+pub(crate) mod response_category;
+pub(crate) mod retention;
+pub(crate) mod sampling;
+pub(crate) mod syslog;
+pub(crate) mod tail;
+pub(crate) mod transform;
+pub(crate) mod webhook;
 
 static SUBMIT_FULL: LazyLock<CounterVec> = LazyLock::new(|| {
     prometheus::register_counter_vec!(
@@ -82,7 +100,7 @@ fn default_true() -> bool {
 
 #[derive(Debug)]
 pub(crate) enum LogCommand {
-    Record(JsonLogRecord),
+    Record(LoggedRecord),
     Terminate,
 }
 
@@ -93,8 +111,9 @@ pub struct Logger {
     headers: Vec<String>,
     enabled: HashMap<RecordType, bool>,
     filter_event: Option<String>,
+    transform: Option<RecordTransform>,
+    sampler: Option<TransientFailureSampler>,
     hook_name: Option<String>,
-    #[allow(unused)]
     name: String,
     submit_latency: Histogram,
 }
@@ -138,6 +157,8 @@ impl Logger {
 
         let headers = params.headers.clone();
         let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
         let hook_name = params.name.to_string();
         let name = format!("hook-{hook_name}");
         let (sender, receiver) = bounded(params.back_pressure);
@@ -158,12 +179,306 @@ impl Logger {
             headers,
             enabled,
             filter_event: None,
+            transform,
+            sampler,
             hook_name: Some(hook_name),
             name,
             submit_latency,
         };
 
-        loggers.push(Arc::new(logger));
+        loggers.push(Self::start_sampling(logger));
+        Ok(())
+    }
+
+    pub async fn init_kafka(params: LogKafkaParams) -> anyhow::Result<()> {
+        let name = format!("kafka-{}", params.name);
+        if LOGGER.lock().iter().any(|existing| existing.name == name) {
+            anyhow::bail!(
+                "A kafka logger with name `{}` has already been registered",
+                params.name
+            );
+        }
+
+        let mut template_engine = TemplateEngine::new();
+
+        for (kind, per_rec) in &params.per_record {
+            if let Some(template_source) = &per_rec.template {
+                template_engine
+                    .add_template(format!("{kind:?}"), template_source.clone())
+                    .with_context(|| {
+                        format!(
+                            "compiling template:\n{template_source}\nfor log record type {kind:?}"
+                        )
+                    })?;
+            }
+        }
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let producer = params.build_producer()?;
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
+        let filter_event = params.filter_event.clone();
+        let (sender, receiver) = bounded(params.back_pressure);
+
+        let mut state = LogKafkaState::new(params, receiver, template_engine, producer)?;
+
+        let thread = LOGGING_RUNTIME.spawn("log kafka".to_string(), async move {
+            tracing::debug!("calling state.logger_thread()");
+            state.logger_thread().await
+        })?;
+
+        let submit_latency = SUBMIT_LATENCY.get_metric_with_label_values(&[&name])?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            transform,
+            sampler,
+            hook_name: None,
+            name,
+            submit_latency,
+        };
+
+        LOGGER.lock().push(Self::start_sampling(logger));
+        Ok(())
+    }
+
+    pub async fn init_nats(params: LogNatsParams) -> anyhow::Result<()> {
+        let name = format!("nats-{}", params.name);
+        if LOGGER.lock().iter().any(|existing| existing.name == name) {
+            anyhow::bail!(
+                "A nats logger with name `{}` has already been registered",
+                params.name
+            );
+        }
+
+        let mut template_engine = TemplateEngine::new();
+        params.compile_subjects(&mut template_engine)?;
+
+        for (kind, per_rec) in &params.per_record {
+            if let Some(template_source) = &per_rec.template {
+                template_engine
+                    .add_template(format!("{kind:?}"), template_source.clone())
+                    .with_context(|| {
+                        format!(
+                            "compiling template:\n{template_source}\nfor log record type {kind:?}"
+                        )
+                    })?;
+            }
+        }
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let client = params.connect().await?;
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
+        let filter_event = params.filter_event.clone();
+        let (sender, receiver) = bounded(params.back_pressure);
+
+        let mut state = LogNatsState::new(params, receiver, template_engine, client)?;
+
+        let thread = LOGGING_RUNTIME.spawn("log nats".to_string(), async move {
+            tracing::debug!("calling state.logger_thread()");
+            state.logger_thread().await
+        })?;
+
+        let submit_latency = SUBMIT_LATENCY.get_metric_with_label_values(&[&name])?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            transform,
+            sampler,
+            hook_name: None,
+            name,
+            submit_latency,
+        };
+
+        LOGGER.lock().push(Self::start_sampling(logger));
+        Ok(())
+    }
+
+    pub async fn init_otlp(params: LogOtlpParams) -> anyhow::Result<()> {
+        let name = format!("otlp-{}", params.endpoint);
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let provider = params.build_provider()?;
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let filter_event = params.filter_event.clone();
+        let (sender, receiver) = bounded(params.back_pressure);
+
+        let mut state = LogOtlpState::new(params, receiver, provider);
+
+        let thread = LOGGING_RUNTIME.spawn("log otlp".to_string(), async move {
+            tracing::debug!("calling state.logger_thread()");
+            state.logger_thread().await
+        })?;
+
+        let submit_latency = SUBMIT_LATENCY.get_metric_with_label_values(&[&name])?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            transform: None,
+            sampler: None,
+            hook_name: None,
+            name,
+            submit_latency,
+        };
+
+        LOGGER.lock().push(Self::start_sampling(logger));
+        Ok(())
+    }
+
+    pub async fn init_syslog(params: LogSyslogParams) -> anyhow::Result<()> {
+        let name = format!("syslog-{}", params.name);
+        if LOGGER.lock().iter().any(|existing| existing.name == name) {
+            anyhow::bail!(
+                "A syslog logger with name `{}` has already been registered",
+                params.name
+            );
+        }
+
+        let mut template_engine = TemplateEngine::new();
+
+        for (kind, per_rec) in &params.per_record {
+            if let Some(template_source) = &per_rec.template {
+                template_engine
+                    .add_template(format!("{kind:?}"), template_source.clone())
+                    .with_context(|| {
+                        format!(
+                            "compiling template:\n{template_source}\nfor log record type {kind:?}"
+                        )
+                    })?;
+            }
+        }
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
+        let filter_event = params.filter_event.clone();
+        let (sender, receiver) = bounded(params.back_pressure);
+
+        let mut state = LogSyslogState::new(params, receiver, template_engine)?;
+
+        let thread = LOGGING_RUNTIME.spawn("log syslog".to_string(), async move {
+            tracing::debug!("calling state.logger_thread()");
+            state.logger_thread().await
+        })?;
+
+        let submit_latency = SUBMIT_LATENCY.get_metric_with_label_values(&[&name])?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            transform,
+            sampler,
+            hook_name: None,
+            name,
+            submit_latency,
+        };
+
+        LOGGER.lock().push(Self::start_sampling(logger));
+        Ok(())
+    }
+
+    pub async fn init_webhook(params: LogWebhookParams) -> anyhow::Result<()> {
+        let name = format!("webhook-{}", params.name);
+        if LOGGER.lock().iter().any(|existing| existing.name == name) {
+            anyhow::bail!(
+                "A webhook logger with name `{}` has already been registered",
+                params.name
+            );
+        }
+
+        let mut template_engine = TemplateEngine::new();
+
+        for (kind, per_rec) in &params.per_record {
+            if let Some(template_source) = &per_rec.template {
+                template_engine
+                    .add_template(format!("{kind:?}"), template_source.clone())
+                    .with_context(|| {
+                        format!(
+                            "compiling template:\n{template_source}\nfor log record type {kind:?}"
+                        )
+                    })?;
+            }
+        }
+
+        let mut enabled = HashMap::new();
+        for (kind, cfg) in &params.per_record {
+            enabled.insert(*kind, cfg.enable);
+        }
+
+        let headers = params.headers.clone();
+        let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
+        let filter_event = params.filter_event.clone();
+        let (sender, receiver) = bounded(params.back_pressure);
+
+        let mut state = LogWebhookState::new(params, receiver, template_engine)?;
+
+        let thread = LOGGING_RUNTIME.spawn("log webhook".to_string(), async move {
+            tracing::debug!("calling state.logger_thread()");
+            state.logger_thread().await
+        })?;
+
+        let submit_latency = SUBMIT_LATENCY.get_metric_with_label_values(&[&name])?;
+
+        let logger = Self {
+            sender,
+            thread: TokioMutex::new(Some(thread)),
+            meta,
+            headers,
+            enabled,
+            filter_event,
+            transform,
+            sampler,
+            hook_name: None,
+            name,
+            submit_latency,
+        };
+
+        LOGGER.lock().push(Self::start_sampling(logger));
         Ok(())
     }
 
@@ -185,6 +500,8 @@ impl Logger {
         std::fs::create_dir_all(&params.log_dir)
             .with_context(|| format!("creating log directory {}", params.log_dir.display()))?;
 
+        let tenant_certificates = params.load_tenant_certificates().await?;
+
         let mut enabled = HashMap::new();
         for (kind, cfg) in &params.per_record {
             enabled.insert(*kind, cfg.enable);
@@ -192,6 +509,8 @@ impl Logger {
 
         let headers = params.headers.clone();
         let meta = params.meta.clone();
+        let transform = RecordTransform::new(params.transform.clone())?;
+        let sampler = TransientFailureSampler::new(params.sample_transient_failures.clone())?;
         let (sender, receiver) = bounded(params.back_pressure);
         let filter_event = params.filter_event.clone();
         let name = format!("dir-{}", params.log_dir.display());
@@ -204,6 +523,25 @@ impl Logger {
         }
         .register();
 
+        let mut log_dirs = vec![params.log_dir.clone()];
+        for per_rec in params.per_record.values() {
+            if let Some(log_dir) = &per_rec.log_dir {
+                if !log_dirs.contains(log_dir) {
+                    log_dirs.push(log_dir.clone());
+                }
+            }
+        }
+        let (rotated, rotated_rx) = unbounded();
+        let segment_manager = LOGGING_RUNTIME.spawn(
+            "log segment manager".to_string(),
+            SegmentManager {
+                receiver: rotated_rx,
+                retention: params.retention.clone(),
+                log_dirs,
+            }
+            .run(),
+        )?;
+
         let thread = LOGGING_RUNTIME.spawn("log file".to_string(), async move {
             tracing::debug!("calling state.logger_thread()");
             let mut state = LogThreadState {
@@ -211,6 +549,10 @@ impl Logger {
                 receiver,
                 template_engine,
                 file_map: HashMap::new(),
+                tenant_certificates,
+                tenant_dirs: HashMap::new(),
+                rotated: Some(rotated),
+                segment_manager: Some(segment_manager),
             };
             state.logger_thread().await
         })?;
@@ -224,15 +566,30 @@ impl Logger {
             headers,
             enabled,
             filter_event,
+            transform,
+            sampler,
             hook_name: None,
             name,
             submit_latency,
         };
 
-        LOGGER.lock().push(Arc::new(logger));
+        LOGGER.lock().push(Self::start_sampling(logger));
         Ok(())
     }
 
+    /// Logs a record that is not associated with a message, such as
+    /// a TenantUsage report, to each logger that has it enabled
+    pub async fn log_to_all(record: JsonLogRecord) {
+        for logger in Self::get_loggers() {
+            if !logger.record_is_enabled(record.kind) {
+                continue;
+            }
+            if let Err(err) = logger.log(record.clone()).await {
+                tracing::error!("failed to log: {err:#}");
+            }
+        }
+    }
+
     pub fn record_is_enabled(&self, kind: RecordType) -> bool {
         if let Some(enabled) = self.enabled.get(&kind) {
             return *enabled;
@@ -243,10 +600,71 @@ impl Logger {
         true
     }
 
+    /// Wraps up a newly created logger, and if it is sampling
+    /// TransientFailure records, spawns a task to periodically
+    /// log the summaries of the records that it suppressed
+    fn start_sampling(logger: Self) -> Arc<Self> {
+        let logger = Arc::new(logger);
+        if let Some(sampler) = &logger.sampler {
+            let interval = sampler.interval();
+            let weak = Arc::downgrade(&logger);
+            if let Err(err) = LOGGING_RUNTIME.spawn(
+                format!("log sampler {}", logger.name),
+                Self::sampling_task(weak, interval),
+            ) {
+                tracing::error!("failed to spawn log sampler for {}: {err:#}", logger.name);
+            }
+        }
+        logger
+    }
+
+    async fn sampling_task(logger: Weak<Self>, interval: std::time::Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(logger) = logger.upgrade() else {
+                return;
+            };
+            if let Err(err) = logger.flush_samples().await {
+                tracing::debug!("log sampler for {} stopping: {err:#}", logger.name);
+                return;
+            }
+        }
+    }
+
+    /// Logs the summaries of the TransientFailure records that
+    /// were suppressed by sampling since the last flush
+    async fn flush_samples(&self) -> anyhow::Result<()> {
+        if let Some(sampler) = &self.sampler {
+            for record in sampler.take_summaries() {
+                self.submit(record).await?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn log(&self, mut record: JsonLogRecord) -> anyhow::Result<()> {
         let _timer = self.submit_latency.start_timer();
         apply_classification(&mut record).await;
-        match self.sender.try_send(LogCommand::Record(record)) {
+        if let Some(sampler) = &self.sampler {
+            if !sampler.should_log(&record) {
+                return Ok(());
+            }
+        }
+        self.submit(record).await
+    }
+
+    async fn submit(&self, record: JsonLogRecord) -> anyhow::Result<()> {
+        let transformed = match &self.transform {
+            Some(transform) => match transform.apply(&record).await? {
+                Some(value) => Some(value),
+                None => return Ok(()),
+            },
+            None => None,
+        };
+        match self.sender.try_send(LogCommand::Record(LoggedRecord {
+            record,
+            transformed,
+        })) {
             Ok(()) => Ok(()),
             Err(TrySendError::Full(record)) => {
                 SUBMIT_FULL
@@ -261,8 +679,12 @@ impl Logger {
     }
 
     pub async fn signal_shutdown() {
+        crate::tenant_usage::report().await;
         let loggers = Self::get_loggers();
         for logger in loggers.iter() {
+            if let Err(err) = logger.flush_samples().await {
+                tracing::error!("failed to log sampling summaries: {err:#}");
+            }
             tracing::debug!("Terminating a logger");
             logger.sender.send_async(LogCommand::Terminate).await.ok();
             tracing::debug!("Joining that logger");
@@ -361,9 +783,12 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    response_category::register(lua)?;
+
     kumo_mod.set(
         "configure_local_logs",
         lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_local_logs", &lua, &params);
             let params: LogFileParams = from_lua_value(&lua, params)?;
             Logger::init(params).await.map_err(any_err)
         })?,
@@ -372,10 +797,56 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     kumo_mod.set(
         "configure_log_hook",
         lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_log_hook", &lua, &params);
             let params: LogHookParams = from_lua_value(&lua, params)?;
             Logger::init_hook(params).await.map_err(any_err)
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_kafka_logs",
+        lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_kafka_logs", &lua, &params);
+            let params: LogKafkaParams = from_lua_value(&lua, params)?;
+            Logger::init_kafka(params).await.map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_nats_logs",
+        lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_nats_logs", &lua, &params);
+            let params: LogNatsParams = from_lua_value(&lua, params)?;
+            Logger::init_nats(params).await.map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_otlp_traces",
+        lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_otlp_traces", &lua, &params);
+            let params: LogOtlpParams = from_lua_value(&lua, params)?;
+            Logger::init_otlp(params).await.map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_syslog_logs",
+        lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_syslog_logs", &lua, &params);
+            let params: LogSyslogParams = from_lua_value(&lua, params)?;
+            Logger::init_syslog(params).await.map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_webhook_logs",
+        lua.create_async_function(|lua, params: LuaValue| async move {
+            crate::config_snapshot::record_logging("configure_webhook_logs", &lua, &params);
+            let params: LogWebhookParams = from_lua_value(&lua, params)?;
+            Logger::init_webhook(params).await.map_err(any_err)
+        })?,
+    )?;
+
     Ok(())
 }
@@ -0,0 +1,537 @@
+use crate::logging::files::LogFileParams;
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
+use crate::logging::{default_true, LogCommand, LOGGING_RUNTIME};
+use anyhow::Context;
+use async_nats::jetstream::context::Publish;
+use async_nats::{Client, ConnectOptions, Event};
+use bytes::Bytes;
+use data_loader::KeySource;
+use flume::Receiver;
+pub use kumo_log_types::*;
+use kumo_template::{Template, TemplateEngine};
+use message::message::QueueNameComponents;
+use prometheus::{IntCounter, IntCounterVec};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, TryAcquireError};
+use uuid::Uuid;
+
+/// The upper bound on the delay between attempts to publish a record
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+static NATS_SENT_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_nats_sent_count",
+        "how many log records were successfully published to nats",
+        &["logger"]
+    )
+    .unwrap()
+});
+static NATS_FAILED_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_nats_failed_count",
+        "how many log records could not be published to nats",
+        &["logger"]
+    )
+    .unwrap()
+});
+static NATS_RETRY_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_nats_retry_count",
+        "how many times publishing a log record to nats was retried",
+        &["logger"]
+    )
+    .unwrap()
+});
+static NATS_BACKLOG_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_nats_backlog_count",
+        "how many times publishing a log record to nats hit the back_pressure",
+        &["logger"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NatsRecordParams {
+    /// Publish to this subject instead of the default subject
+    #[serde(default)]
+    pub subject: Option<String>,
+
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Instead of publishing the json object, format it with this
+    /// minijinja template
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogNatsParams {
+    /// The unique name to identify this instance of the nats logger
+    pub name: String,
+
+    /// The nats servers to connect to, such as `nats://localhost:4222`
+    pub servers: Vec<String>,
+
+    /// A minijinja template that produces the subject to which
+    /// records are published, unless overridden via per_record
+    pub subject: String,
+
+    /// Publish via JetStream and wait for the stream to acknowledge
+    /// each record. When false, records are published using core
+    /// nats, which provides no delivery guarantee.
+    #[serde(default = "default_true")]
+    pub jetstream: bool,
+
+    /// The JetStream domain to publish to
+    #[serde(default)]
+    pub jetstream_domain: Option<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password: Option<KeySource>,
+
+    #[serde(default)]
+    pub token: Option<KeySource>,
+
+    /// The content of a nats `.creds` file holding the user JWT
+    /// and nkey seed
+    #[serde(default)]
+    pub credentials: Option<KeySource>,
+
+    #[serde(default)]
+    pub require_tls: bool,
+
+    /// Additional CA certificates, in PEM format, to trust when
+    /// verifying the server
+    #[serde(default)]
+    pub root_certificates: Vec<PathBuf>,
+
+    /// How long to wait for JetStream to acknowledge a record
+    #[serde(
+        default = "LogNatsParams::default_ack_timeout",
+        with = "duration_serde"
+    )]
+    pub ack_timeout: Duration,
+
+    /// How long to keep retrying to publish an individual record
+    /// before giving up on it
+    #[serde(
+        default = "LogNatsParams::default_send_timeout",
+        with = "duration_serde"
+    )]
+    pub send_timeout: Duration,
+
+    /// The initial delay before retrying a failed publish. The
+    /// delay doubles with each subsequent attempt.
+    #[serde(
+        default = "LogNatsParams::default_retry_interval",
+        with = "duration_serde"
+    )]
+    pub retry_interval: Duration,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture in the log
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture in the log
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, NatsRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be published
+    #[serde(default)]
+    pub filter_event: Option<String>,
+
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
+}
+
+impl LogNatsParams {
+    fn default_ack_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_send_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    /// Compiles the subject templates into the template engine,
+    /// alongside any per_record payload templates
+    pub fn compile_subjects(&self, template_engine: &mut TemplateEngine) -> anyhow::Result<()> {
+        template_engine
+            .add_template("subject", self.subject.clone())
+            .with_context(|| format!("compiling subject template {}", self.subject))?;
+        for (kind, per_rec) in &self.per_record {
+            if let Some(subject) = &per_rec.subject {
+                template_engine
+                    .add_template(format!("subject:{kind:?}"), subject.clone())
+                    .with_context(|| {
+                        format!("compiling subject template {subject} for log record type {kind:?}")
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects to the servers. The connection is established in
+    /// the background, and is transparently re-established if it
+    /// is lost, so this doesn't fail if the servers are unavailable.
+    pub async fn connect(&self) -> anyhow::Result<Client> {
+        let mut options = ConnectOptions::new()
+            .name(format!("kumod-{}", self.name))
+            .retry_on_initial_connect()
+            .max_reconnects(None)
+            .require_tls(self.require_tls);
+
+        if let Some(username) = &self.username {
+            let password = match &self.password {
+                Some(password) => String::from_utf8(password.get().await?)
+                    .context("nats password is not UTF-8")?,
+                None => String::new(),
+            };
+            options = options.user_and_password(username.clone(), password);
+        }
+        if let Some(token) = &self.token {
+            let token = String::from_utf8(token.get().await?).context("nats token is not UTF-8")?;
+            options = options.token(token.trim().to_string());
+        }
+        if let Some(credentials) = &self.credentials {
+            let credentials = String::from_utf8(credentials.get().await?)
+                .context("nats credentials are not UTF-8")?;
+            options = options
+                .credentials(&credentials)
+                .context("parsing nats credentials")?;
+        }
+        for path in &self.root_certificates {
+            options = options.add_root_certificates(path.clone());
+        }
+
+        let name = self.name.clone();
+        options = options.event_callback(move |event| {
+            let name = name.clone();
+            async move {
+                match event {
+                    Event::Connected => tracing::info!("nats logger {name}: {event}"),
+                    _ => tracing::warn!("nats logger {name}: {event}"),
+                }
+            }
+        });
+
+        options
+            .connect(&self.servers)
+            .await
+            .with_context(|| format!("connecting to nats servers {:?}", self.servers))
+    }
+}
+
+/// The context used to render the subject of a record
+fn subject_context(record: &JsonLogRecord) -> anyhow::Result<serde_json::Value> {
+    let mut context = serde_json::to_value(record)?;
+    if let Some(obj) = context.as_object_mut() {
+        let components = QueueNameComponents::parse(&record.queue);
+        obj.insert("tenant".to_string(), components.tenant.into());
+        obj.insert("campaign".to_string(), components.campaign.into());
+        obj.insert("domain".to_string(), components.domain.into());
+    }
+    Ok(context)
+}
+
+#[derive(Clone)]
+enum Publisher {
+    Core(Client),
+    JetStream(async_nats::jetstream::Context),
+}
+
+impl Publisher {
+    async fn publish(&self, subject: &str, payload: &Bytes, msg_id: &str) -> anyhow::Result<()> {
+        match self {
+            Self::Core(client) => {
+                client.publish(subject.to_string(), payload.clone()).await?;
+            }
+            Self::JetStream(context) => {
+                // The message id allows the stream to discard duplicates
+                // if an ack was lost and we publish the record again
+                let ack = context
+                    .send_publish(
+                        subject.to_string(),
+                        Publish::build().payload(payload.clone()).message_id(msg_id),
+                    )
+                    .await?;
+                ack.await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct LogNatsState {
+    params: LogNatsParams,
+    receiver: Receiver<LogCommand>,
+    template_engine: TemplateEngine,
+    client: Client,
+    publisher: Publisher,
+    sema: Arc<Semaphore>,
+    sent: IntCounter,
+    failed: IntCounter,
+    retried: IntCounter,
+}
+
+impl LogNatsState {
+    pub fn new(
+        params: LogNatsParams,
+        receiver: Receiver<LogCommand>,
+        template_engine: TemplateEngine,
+        client: Client,
+    ) -> anyhow::Result<Self> {
+        let publisher = if params.jetstream {
+            let mut context = match &params.jetstream_domain {
+                Some(domain) => async_nats::jetstream::with_domain(client.clone(), domain),
+                None => async_nats::jetstream::new(client.clone()),
+            };
+            context.set_timeout(params.ack_timeout);
+            Publisher::JetStream(context)
+        } else {
+            Publisher::Core(client.clone())
+        };
+        let sema = Arc::new(Semaphore::new(params.back_pressure));
+        let sent = NATS_SENT_COUNT.get_metric_with_label_values(&[&params.name])?;
+        let failed = NATS_FAILED_COUNT.get_metric_with_label_values(&[&params.name])?;
+        let retried = NATS_RETRY_COUNT.get_metric_with_label_values(&[&params.name])?;
+
+        Ok(Self {
+            params,
+            receiver,
+            template_engine,
+            client,
+            publisher,
+            sema,
+            sent,
+            failed,
+            retried,
+        })
+    }
+
+    pub async fn logger_thread(&mut self) {
+        tracing::debug!("LogNatsParams: {:#?}", self.params);
+
+        loop {
+            let cmd = match self.receiver.recv_async().await {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    break;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    tracing::debug!("LogCommand::Terminate received. Stopping publishing logs");
+                    break;
+                }
+                LogCommand::Record(record) => {
+                    if let Err(err) = self.do_record(record).await {
+                        tracing::error!("failed to log: {err:#}");
+                    };
+                }
+            }
+        }
+
+        // Wait for the outstanding publishes to complete
+        if let Ok(permits) = u32::try_from(self.params.back_pressure) {
+            self.sema.acquire_many(permits).await.ok();
+        }
+        let result = tokio::time::timeout(self.params.send_timeout, self.client.flush()).await;
+        tracing::debug!("flushed nats client {}: {result:?}", self.params.name);
+    }
+
+    async fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
+        tracing::trace!("do_record {record:?}");
+
+        // Bound the number of in-flight publishes, for the same reasons
+        // as are outlined in LogHookState::do_record
+        let permit = match self.sema.clone().try_acquire_owned() {
+            Ok(p) => p,
+            Err(TryAcquireError::NoPermits) => {
+                NATS_BACKLOG_COUNT
+                    .get_metric_with_label_values(&[&self.params.name])?
+                    .inc();
+                self.sema.clone().acquire_owned().await?
+            }
+            Err(TryAcquireError::Closed) => {
+                anyhow::bail!("back_pressure semaphore is closed!?");
+            }
+        };
+
+        let mut payload = Vec::new();
+        self.template_engine
+            .add_global("log_record", kumo_template::Value::from_serialize(&logged));
+
+        if let Some(template) =
+            Self::resolve_template(&self.params, &self.template_engine, record.kind)
+        {
+            template.render_to_write(&logged, &mut payload)?;
+        } else {
+            serde_json::to_writer(&mut payload, &logged).context("serializing record")?;
+        }
+
+        let subject = Self::resolve_subject(&self.params, &self.template_engine, record.kind)?
+            .render(subject_context(record)?)
+            .context("rendering subject")?;
+        anyhow::ensure!(
+            !subject.is_empty() && !subject.contains(char::is_whitespace),
+            "subject template produced invalid subject {subject:?}"
+        );
+
+        let payload = Bytes::from(payload);
+        let msg_id = Uuid::new_v4().to_string();
+        let publisher = self.publisher.clone();
+        let send_timeout = self.params.send_timeout;
+        let mut delay = self.params.retry_interval;
+        let sent = self.sent.clone();
+        let failed = self.failed.clone();
+        let retried = self.retried.clone();
+        let id = record.id.clone();
+
+        LOGGING_RUNTIME.spawn("log-nats".to_string(), async move {
+            let deadline = Instant::now() + send_timeout;
+            loop {
+                match publisher.publish(&subject, &payload, &msg_id).await {
+                    Ok(()) => {
+                        sent.inc();
+                        break;
+                    }
+                    Err(err) if Instant::now() + delay < deadline => {
+                        retried.inc();
+                        tracing::debug!(
+                            "failed to publish log record for {id} to {subject}, \
+                             will retry in {delay:?}: {err:#}"
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(MAX_RETRY_INTERVAL);
+                    }
+                    Err(err) => {
+                        failed.inc();
+                        tracing::error!(
+                            "failed to publish log record for {id} to {subject}: {err:#}"
+                        );
+                        break;
+                    }
+                }
+            }
+            drop(permit);
+        })?;
+
+        Ok(())
+    }
+
+    fn resolve_subject<'a>(
+        params: &LogNatsParams,
+        template_engine: &'a TemplateEngine,
+        kind: RecordType,
+    ) -> anyhow::Result<Template<'a, 'a>> {
+        for candidate in [kind, RecordType::Any] {
+            if let Some(NatsRecordParams {
+                subject: Some(_), ..
+            }) = params.per_record.get(&candidate)
+            {
+                return Ok(template_engine.get_template(&format!("subject:{candidate:?}"))?);
+            }
+        }
+        Ok(template_engine.get_template("subject")?)
+    }
+
+    fn resolve_template<'a>(
+        params: &LogNatsParams,
+        template_engine: &'a TemplateEngine,
+        kind: RecordType,
+    ) -> Option<Template<'a, 'a>> {
+        if let Some(pr) = params.per_record.get(&kind) {
+            if pr.template.is_some() {
+                let label = format!("{kind:?}");
+                return template_engine.get_template(&label).ok();
+            }
+            return None;
+        }
+        if let Some(pr) = params.per_record.get(&RecordType::Any) {
+            if pr.template.is_some() {
+                return template_engine.get_template("Any").ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subjects() {
+        let params: LogNatsParams = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "servers": ["nats://localhost:4222"],
+            "subject": "kumo.{{ type }}.{{ tenant or 'none' }}",
+            "per_record": {
+                "Bounce": {"subject": "bounces.{{ campaign }}"},
+                "Delivery": {"template": "{{ id }}"},
+            },
+        }))
+        .unwrap();
+        let mut engine = TemplateEngine::new();
+        params.compile_subjects(&mut engine).unwrap();
+
+        let render = |kind, queue: &str| {
+            let context = serde_json::json!({
+                "type": kind,
+                "tenant": QueueNameComponents::parse(queue).tenant,
+                "campaign": QueueNameComponents::parse(queue).campaign,
+            });
+            LogNatsState::resolve_subject(&params, &engine, kind)
+                .unwrap()
+                .render(context)
+                .unwrap()
+        };
+
+        assert_eq!(
+            render(RecordType::Delivery, "camp:tenant@example.com"),
+            "kumo.Delivery.tenant"
+        );
+        assert_eq!(
+            render(RecordType::TransientFailure, "example.com"),
+            "kumo.TransientFailure.none"
+        );
+        assert_eq!(
+            render(RecordType::Bounce, "camp:tenant@example.com"),
+            "bounces.camp"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use crate::logging::files::LogFileParams;
+use crate::logging::{default_true, LogCommand};
+use anyhow::Context;
+use flume::Receiver;
+pub use kumo_log_types::*;
+use message::message::QueueNameComponents;
+use opentelemetry::trace::{
+    Span, SpanBuilder, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+    TraceState, Tracer as _, TracerProvider as _,
+};
+use opentelemetry::{Context as OtelContext, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::{Sampler, Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use serde::Deserialize;
+use serde_json::Value;
+use spool::SpoolId;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    #[default]
+    Grpc,
+    Http,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpRecordParams {
+    #[serde(default = "default_true")]
+    pub enable: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogOtlpParams {
+    /// The OTLP collector endpoint, such as `http://localhost:4317`
+    pub endpoint: String,
+
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+
+    /// Reported as the `service.name` resource attribute
+    #[serde(default = "LogOtlpParams::default_service_name")]
+    pub service_name: String,
+
+    /// The fraction of messages for which traces are exported.
+    /// The decision is made based on the message id, so either
+    /// all or none of the spans for a given message are exported.
+    #[serde(default = "LogOtlpParams::default_sample_ratio")]
+    pub sample_ratio: f64,
+
+    /// How long to wait for the collector to accept a batch of spans
+    #[serde(default, with = "duration_serde")]
+    pub timeout: Option<Duration>,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture as span attributes
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture as span attributes
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, OtlpRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be exported
+    #[serde(default)]
+    pub filter_event: Option<String>,
+}
+
+impl LogOtlpParams {
+    fn default_service_name() -> String {
+        "kumod".to_string()
+    }
+
+    fn default_sample_ratio() -> f64 {
+        1.0
+    }
+
+    pub fn build_provider(&self) -> anyhow::Result<TracerProvider> {
+        let exporter = match self.protocol {
+            OtlpProtocol::Grpc => {
+                let mut builder = SpanExporter::builder()
+                    .with_tonic()
+                    .with_endpoint(&self.endpoint);
+                if let Some(timeout) = self.timeout {
+                    builder = builder.with_timeout(timeout);
+                }
+                builder.build()
+            }
+            OtlpProtocol::Http => {
+                let mut builder = SpanExporter::builder()
+                    .with_http()
+                    .with_endpoint(&self.endpoint);
+                if let Some(timeout) = self.timeout {
+                    builder = builder.with_timeout(timeout);
+                }
+                builder.build()
+            }
+        }
+        .context("creating OTLP span exporter")?;
+
+        let nodeid = kumo_server_common::nodeid::NodeId::get_uuid();
+
+        Ok(TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_sampler(Sampler::TraceIdRatioBased(self.sample_ratio))
+            .with_resource(Resource::new([
+                KeyValue::new("service.name", self.service_name.clone()),
+                KeyValue::new("service.instance.id", nodeid.to_string()),
+            ]))
+            .build())
+    }
+}
+
+/// Each message is represented by its own trace, whose id is the
+/// message id. The trace has a root span covering the lifetime of
+/// the message, from creation through to its final disposition,
+/// with a child span for each logged event.
+/// Deriving the ids from the message id means that we don't need
+/// to keep any state to correlate the spans for a given message.
+fn ids_for_message(id: &str) -> Option<(TraceId, SpanId)> {
+    let id = SpoolId::from_str(id)?;
+    let bytes = id.as_bytes();
+    let mut span_id = [0u8; 8];
+    span_id.copy_from_slice(&bytes[0..8]);
+    Some((TraceId::from_bytes(*bytes), SpanId::from_bytes(span_id)))
+}
+
+/// Returns true if the record represents the final disposition of
+/// a message, and should therefore close out its root span
+fn is_final(kind: RecordType) -> bool {
+    matches!(
+        kind,
+        RecordType::Delivery
+            | RecordType::Bounce
+            | RecordType::Expiration
+            | RecordType::AdminBounce
+            | RecordType::Rejection
+    )
+}
+
+fn status_for(record: &JsonLogRecord) -> Status {
+    match record.kind {
+        RecordType::Delivery | RecordType::Reception => Status::Ok,
+        RecordType::Bounce
+        | RecordType::TransientFailure
+        | RecordType::Expiration
+        | RecordType::AdminBounce
+        | RecordType::Rejection => Status::error(record.response.to_single_line()),
+        _ => Status::Unset,
+    }
+}
+
+fn json_to_attribute(key: String, value: &Value) -> KeyValue {
+    match value {
+        Value::String(s) => KeyValue::new(key, s.to_string()),
+        Value::Bool(b) => KeyValue::new(key, *b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => KeyValue::new(key, i),
+            None => KeyValue::new(key, n.as_f64().unwrap_or_default()),
+        },
+        other => KeyValue::new(key, other.to_string()),
+    }
+}
+
+fn attributes_for(record: &JsonLogRecord) -> Vec<KeyValue> {
+    let components = QueueNameComponents::parse(&record.queue);
+
+    let mut attrs = vec![
+        KeyValue::new("kumo.message_id", record.id.to_string()),
+        KeyValue::new("kumo.record_type", format!("{:?}", record.kind)),
+        KeyValue::new("kumo.sender", record.sender.to_string()),
+        KeyValue::new("kumo.recipient", record.recipient.to_string()),
+        KeyValue::new("kumo.queue", record.queue.to_string()),
+        KeyValue::new("kumo.domain", components.domain.to_string()),
+        KeyValue::new("kumo.size", record.size as i64),
+        KeyValue::new("kumo.num_attempts", record.num_attempts as i64),
+        KeyValue::new("kumo.response.code", record.response.code as i64),
+        KeyValue::new("kumo.response", record.response.to_single_line()),
+    ];
+
+    let mut opt = |key: &'static str, value: Option<&str>| {
+        if let Some(value) = value {
+            attrs.push(KeyValue::new(key, value.to_string()));
+        }
+    };
+    opt("kumo.tenant", components.tenant);
+    opt("kumo.campaign", components.campaign);
+    opt(
+        "kumo.site",
+        if record.site.is_empty() {
+            None
+        } else {
+            Some(record.site.as_str())
+        },
+    );
+    opt("kumo.egress_pool", record.egress_pool.as_deref());
+    opt("kumo.egress_source", record.egress_source.as_deref());
+    opt("kumo.provider", record.provider_name.as_deref());
+    opt(
+        "kumo.delivery_protocol",
+        record.delivery_protocol.as_deref(),
+    );
+    opt(
+        "kumo.reception_protocol",
+        record.reception_protocol.as_deref(),
+    );
+
+    if let Some(peer) = &record.peer_address {
+        attrs.push(KeyValue::new("kumo.peer_address", peer.to_string()));
+    }
+    if let Some(session_id) = &record.session_id {
+        attrs.push(KeyValue::new("kumo.session_id", session_id.to_string()));
+    }
+
+    for (name, value) in &record.meta {
+        attrs.push(json_to_attribute(format!("kumo.meta.{name}"), value));
+    }
+    for (name, value) in &record.headers {
+        attrs.push(json_to_attribute(format!("kumo.header.{name}"), value));
+    }
+
+    attrs
+}
+
+pub struct LogOtlpState {
+    params: LogOtlpParams,
+    receiver: Receiver<LogCommand>,
+    provider: TracerProvider,
+    tracer: Tracer,
+}
+
+impl LogOtlpState {
+    pub fn new(
+        params: LogOtlpParams,
+        receiver: Receiver<LogCommand>,
+        provider: TracerProvider,
+    ) -> Self {
+        let tracer = provider.tracer("kumod");
+        Self {
+            params,
+            receiver,
+            provider,
+            tracer,
+        }
+    }
+
+    pub async fn logger_thread(&mut self) {
+        tracing::debug!("LogOtlpParams: {:#?}", self.params);
+
+        loop {
+            let cmd = match self.receiver.recv_async().await {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    break;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    tracing::debug!("LogCommand::Terminate received. Stopping exporting spans");
+                    break;
+                }
+                LogCommand::Record(logged) => {
+                    self.do_record(logged.record);
+                }
+            }
+        }
+
+        // Shutting down flushes any pending spans, and may block
+        let provider = self.provider.clone();
+        let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        tracing::debug!("shutdown OTLP tracer provider: {result:?}");
+    }
+
+    fn do_record(&mut self, record: JsonLogRecord) {
+        tracing::trace!("do_record {record:?}");
+
+        let Some((trace_id, root_span_id)) = ids_for_message(&record.id) else {
+            tracing::trace!("cannot derive trace id from {}", record.id);
+            return;
+        };
+
+        let attributes = attributes_for(&record);
+        let timestamp = SystemTime::from(record.timestamp);
+
+        // Reception covers the time taken to receive and accept
+        // the message; the other events are points in time
+        let start = if record.kind == RecordType::Reception {
+            SystemTime::from(record.created)
+        } else {
+            timestamp
+        };
+
+        let parent = OtelContext::new().with_remote_span_context(SpanContext::new(
+            trace_id,
+            root_span_id,
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+        let mut span = self.tracer.build_with_context(
+            SpanBuilder::from_name(format!("{:?}", record.kind))
+                .with_kind(SpanKind::Internal)
+                .with_start_time(start)
+                .with_attributes(attributes.clone()),
+            &parent,
+        );
+        span.set_status(status_for(&record));
+        span.end_with_timestamp(timestamp);
+
+        if is_final(record.kind) {
+            let mut root = self.tracer.build_with_context(
+                SpanBuilder::from_name("message")
+                    .with_kind(SpanKind::Internal)
+                    .with_trace_id(trace_id)
+                    .with_span_id(root_span_id)
+                    .with_start_time(SystemTime::from(record.created))
+                    .with_attributes(attributes),
+                &OtelContext::new(),
+            );
+            root.set_status(status_for(&record));
+            root.end_with_timestamp(timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_ids() {
+        let id = SpoolId::new();
+        let (trace_id, span_id) = ids_for_message(&id.to_string()).unwrap();
+        assert_eq!(trace_id.to_bytes(), *id.as_bytes());
+        assert_eq!(span_id.to_bytes(), id.as_bytes()[0..8]);
+
+        // The ids are stable
+        assert_eq!(ids_for_message(&id.to_string()), Some((trace_id, span_id)));
+
+        assert_eq!(ids_for_message("not an id"), None);
+    }
+}
@@ -45,6 +45,7 @@ pub async fn log_rejection(args: LogRejection) {
             timestamp: now,
             created: now,
             num_attempts: 0,
+            latency: None,
             egress_pool: None,
             egress_source: None,
             bounce_classification: BounceClass::default(),
@@ -60,6 +61,10 @@ pub async fn log_rejection(args: LogRejection) {
             source_address: None,
             provider_name: None,
             session_id: args.session_id,
+            suppressed_count: None,
+            response_category: None,
+            annotations: vec![],
+            tenant_usage: None,
         };
         if let Err(err) = logger.log(record).await {
             tracing::error!("failed to log: {err:#}");
@@ -0,0 +1,250 @@
+//! Maps provider-specific transient failure and bounce responses,
+//! such as the S3150 deferrals issued by Microsoft or the 4.7.28
+//! rate limiting responses issued by Gmail, to semantic categories,
+//! using rules that are loaded from TOML files so that they can be
+//! updated independently of kumod.
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use config::{any_err, from_lua_value, get_or_create_module};
+use mlua::{Lua, Value};
+use regex::RegexSet;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, OnceLock};
+
+static CATEGORIES: LazyLock<ArcSwap<ResponseCategories>> = LazyLock::new(ArcSwap::default);
+static CONFIGURED: OnceLock<()> = OnceLock::new();
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseCategoryParams {
+    pub files: Vec<String>,
+}
+
+impl ResponseCategoryParams {
+    pub fn register(&self) -> anyhow::Result<()> {
+        let categories = ResponseCategories::load_files(&self.files)?;
+        if config::is_validating() {
+            return Ok(());
+        }
+
+        CONFIGURED
+            .set(())
+            .map_err(|_| anyhow::anyhow!("response categories already configured"))?;
+        CATEGORIES.store(Arc::new(categories));
+
+        // Pick up changes to the files when the configuration changes
+        let params = self.clone();
+        tokio::spawn(async move {
+            let mut subscriber = config::epoch::subscribe();
+            while let Ok(()) = subscriber.changed().await {
+                match ResponseCategories::load_files(&params.files) {
+                    Ok(categories) => CATEGORIES.store(Arc::new(categories)),
+                    Err(err) => tracing::error!("Error reloading response categories: {err:#}"),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CategoryRule {
+    /// The category that is assigned to matching responses
+    category: String,
+    /// When set, the rule only applies to responses from
+    /// this provider
+    #[serde(default)]
+    provider: Option<String>,
+    /// Regexes that are matched against the single line
+    /// form of the response
+    regex: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct CategoryFile {
+    #[serde(default)]
+    rule: Vec<CategoryRule>,
+}
+
+struct RuleSet {
+    set: RegexSet,
+    categories: Vec<String>,
+}
+
+impl RuleSet {
+    fn new(rules: Vec<(String, String)>) -> anyhow::Result<Self> {
+        let set = RegexSet::new(rules.iter().map(|(regex, _)| regex))?;
+        let categories = rules.into_iter().map(|(_, category)| category).collect();
+        Ok(Self { set, categories })
+    }
+
+    /// Returns the category of the first rule that matches
+    fn categorize(&self, response: &str) -> Option<&str> {
+        let idx = self.set.matches(response).into_iter().next()?;
+        Some(&self.categories[idx])
+    }
+}
+
+#[derive(Default)]
+pub struct ResponseCategories {
+    by_provider: HashMap<String, RuleSet>,
+    default: Option<RuleSet>,
+}
+
+impl ResponseCategories {
+    /// Loads and merges the rules from the files.
+    /// Rules are considered in the order in which they appear,
+    /// so rules in earlier files take precedence over those
+    /// in later files.
+    pub fn load_files(paths: &[String]) -> anyhow::Result<Self> {
+        let mut files = vec![];
+        for path in paths {
+            let data = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+            let file: CategoryFile =
+                toml::from_str(&data).with_context(|| format!("parsing {path}"))?;
+            files.push((path.as_str(), file));
+        }
+        Self::from_files(files)
+    }
+
+    fn from_files(files: Vec<(&str, CategoryFile)>) -> anyhow::Result<Self> {
+        let mut default = vec![];
+        let mut by_provider: HashMap<String, Vec<(String, String)>> = HashMap::new();
+
+        for (path, file) in files {
+            for rule in file.rule {
+                anyhow::ensure!(
+                    !rule.regex.is_empty(),
+                    "{path}: rule for category {} has no regex",
+                    rule.category
+                );
+                let rules = match rule.provider {
+                    Some(provider) => by_provider.entry(provider).or_default(),
+                    None => &mut default,
+                };
+                for regex in rule.regex {
+                    rules.push((regex, rule.category.clone()));
+                }
+            }
+        }
+
+        let mut result = Self::default();
+        for (provider, rules) in by_provider {
+            let set = RuleSet::new(rules)
+                .with_context(|| format!("compiling rules for provider {provider}"))?;
+            result.by_provider.insert(provider, set);
+        }
+        if !default.is_empty() {
+            result
+                .default
+                .replace(RuleSet::new(default).context("compiling rules without a provider")?);
+        }
+        Ok(result)
+    }
+
+    /// Returns the category of the response. The rules for the provider
+    /// are consulted first, followed by the rules that apply to
+    /// all providers.
+    pub fn categorize(&self, provider: Option<&str>, response: &str) -> Option<&str> {
+        provider
+            .and_then(|provider| self.by_provider.get(provider))
+            .and_then(|rules| rules.categorize(response))
+            .or_else(|| {
+                self.default
+                    .as_ref()
+                    .and_then(|rules| rules.categorize(response))
+            })
+    }
+}
+
+/// Returns the category of the response, using the rules configured
+/// by `kumo.configure_response_categories`, if any
+pub fn categorize_response(provider: Option<&str>, response: &str) -> Option<String> {
+    CATEGORIES
+        .load()
+        .categorize(provider, response)
+        .map(|category| category.to_string())
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let kumo_mod = get_or_create_module(lua, "kumo")?;
+
+    kumo_mod.set(
+        "configure_response_categories",
+        lua.create_function(|lua, params: Value| {
+            let params: ResponseCategoryParams = from_lua_value(lua, params)?;
+            params.register().map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "categorize_response",
+        lua.create_function(|_lua, (response, provider): (String, Option<String>)| {
+            Ok(categorize_response(provider.as_deref(), &response))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categorize() {
+        let file: CategoryFile = toml::from_str(
+            r#"
+[[rule]]
+category = "RateLimited"
+provider = "office365"
+regex = ["\\(S3150\\)", "\\(S775\\)"]
+
+[[rule]]
+category = "ReputationBlock"
+provider = "office365"
+regex = ["\\(S3140\\)", "4\\.7\\.500 "]
+
+[[rule]]
+category = "RateLimited"
+provider = "google"
+regex = ["4\\.7\\.28 "]
+
+[[rule]]
+category = "MailboxFull"
+regex = ["4\\.2\\.2 "]
+"#,
+        )
+        .unwrap();
+        let categories = ResponseCategories::from_files(vec![("test.toml", file)]).unwrap();
+
+        let s3150 = "451 4.7.500 Server busy. Please try again later from [10.0.0.1]. (S3150)";
+        assert_eq!(
+            categories.categorize(Some("office365"), s3150),
+            Some("RateLimited")
+        );
+        // Rules for other providers are not considered
+        assert_eq!(categories.categorize(Some("google"), s3150), None);
+        assert_eq!(categories.categorize(None, s3150), None);
+        assert_eq!(
+            categories.categorize(
+                Some("google"),
+                "421 4.7.28 Gmail has detected an unusual rate of unsolicited mail"
+            ),
+            Some("RateLimited")
+        );
+        // Rules without a provider apply to all providers
+        assert_eq!(
+            categories.categorize(Some("google"), "452 4.2.2 The recipient's inbox is full"),
+            Some("MailboxFull")
+        );
+        assert_eq!(
+            categories.categorize(None, "452 4.2.2 The recipient's inbox is full"),
+            Some("MailboxFull")
+        );
+    }
+}
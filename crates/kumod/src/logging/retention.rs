@@ -0,0 +1,251 @@
+use config::{load_config, CallbackSignature, SerdeWrappedValue};
+use flume::Receiver;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{Duration, SystemTime};
+
+pub static LOG_SEGMENT_ROTATED_SIG: LazyLock<
+    CallbackSignature<(String, SerdeWrappedValue<RotatedSegment>), ()>,
+> = LazyLock::new(|| CallbackSignature::new_with_multiple("log_segment_rotated"));
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogRetentionParams {
+    /// Completed segments that were last modified longer ago
+    /// than this are deleted
+    #[serde(default, with = "duration_serde")]
+    pub max_age: Option<Duration>,
+
+    /// When the total size of the completed segments in a directory
+    /// exceeds this many bytes, the oldest segments are deleted
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// How often to apply the policy
+    #[serde(
+        default = "LogRetentionParams::default_check_interval",
+        with = "duration_serde"
+    )]
+    pub check_interval: Duration,
+}
+
+impl LogRetentionParams {
+    fn default_check_interval() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+/// Describes a log segment that has been completed and will not
+/// be written to any more
+#[derive(Serialize, Clone, Debug)]
+pub struct RotatedSegment {
+    /// The path to the completed segment
+    pub path: PathBuf,
+    /// The log directory to which the segment belongs
+    pub log_dir: PathBuf,
+    /// The tenant, when tenant_meta is in use
+    pub tenant: Option<String>,
+    /// Whether the segment was encrypted
+    pub encrypted: bool,
+}
+
+/// Runs alongside a local file logger, calling the `log_segment_rotated`
+/// event for each segment that it completes, and periodically applying
+/// its retention policy.
+pub struct SegmentManager {
+    pub receiver: Receiver<RotatedSegment>,
+    pub retention: Option<LogRetentionParams>,
+    pub log_dirs: Vec<PathBuf>,
+}
+
+impl SegmentManager {
+    pub async fn run(self) {
+        let check_interval = self
+            .retention
+            .as_ref()
+            .map(|r| r.check_interval)
+            .unwrap_or(Duration::MAX);
+        let mut next_check = tokio::time::Instant::now();
+
+        loop {
+            tokio::select! {
+                segment = self.receiver.recv_async() => {
+                    match segment {
+                        Ok(segment) => Self::segment_rotated(segment).await,
+                        // The logger has terminated and all of its
+                        // segments have been completed
+                        Err(_) => break,
+                    }
+                }
+                _ = tokio::time::sleep_until(next_check), if self.retention.is_some() => {
+                    self.apply_retention().await;
+                    next_check = tokio::time::Instant::now() + check_interval;
+                }
+            }
+        }
+    }
+
+    async fn segment_rotated(segment: RotatedSegment) {
+        let path = segment.path.display().to_string();
+        let result = async {
+            let mut config = load_config().await?;
+            config
+                .async_call_callback(
+                    &LOG_SEGMENT_ROTATED_SIG,
+                    (path.clone(), SerdeWrappedValue(segment)),
+                )
+                .await
+        };
+        if let Err(err) = result.await {
+            tracing::error!("Error in log_segment_rotated event for {path}: {err:#}");
+        }
+    }
+
+    async fn apply_retention(&self) {
+        let Some(retention) = self.retention.clone() else {
+            return;
+        };
+        let log_dirs = self.log_dirs.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let now = SystemTime::now();
+            for dir in &log_dirs {
+                if let Err(err) = apply_retention_to_dir(dir, &retention, now, true) {
+                    tracing::error!("applying log retention policy to {dir:?}: {err:#}");
+                }
+            }
+        })
+        .await;
+        if let Err(err) = result {
+            tracing::error!("applying log retention policy: {err:#}");
+        }
+    }
+}
+
+/// Deletes the completed segments in dir that fall outside the
+/// retention policy, returning the paths that were deleted.
+/// When include_tenants is true, each subdirectory is treated as
+/// holding the segments for a tenant, and the policy is applied
+/// to it separately.
+pub fn apply_retention_to_dir(
+    dir: &Path,
+    retention: &LogRetentionParams,
+    now: SystemTime,
+    include_tenants: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut segments = vec![];
+    let mut deleted = vec![];
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_hidden = entry
+            .file_name()
+            .to_str()
+            .map_or(true, |n| n.starts_with('.'));
+        if is_hidden {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if include_tenants {
+                deleted.append(&mut apply_retention_to_dir(
+                    &entry.path(),
+                    retention,
+                    now,
+                    false,
+                )?);
+            }
+            continue;
+        }
+        let meta = entry.metadata()?;
+        // Segments that are still writable are in use
+        if !file_type.is_file() || !meta.permissions().readonly() {
+            continue;
+        }
+        segments.push((meta.modified()?, meta.len(), entry.path()));
+    }
+
+    // Oldest first
+    segments.sort();
+
+    let mut total_size: u64 = segments.iter().map(|(_, size, _)| size).sum();
+    for (modified, size, path) in segments {
+        let expired = retention.max_age.map_or(false, |max_age| {
+            now.duration_since(modified).unwrap_or_default() > max_age
+        });
+        let too_big = retention
+            .max_size
+            .map_or(false, |max_size| total_size > max_size);
+        if !expired && !too_big {
+            // Everything that follows is newer
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                tracing::info!("Removed log segment {path:?} per retention policy");
+                total_size -= size;
+                deleted.push(path);
+            }
+            Err(err) => {
+                tracing::error!("Failed to remove log segment {path:?}: {err:#}");
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_segment(dir: &Path, name: &str, size: usize, age: Duration, done: bool) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![b'x'; size]).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+        if done {
+            let mut perms = file.metadata().unwrap().permissions();
+            perms.set_readonly(true);
+            std::fs::set_permissions(&path, perms).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn retention() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let day = Duration::from_secs(86400);
+
+        let old = make_segment(dir.path(), "20240101-000000", 10, 3 * day, true);
+        let middle = make_segment(dir.path(), "20240102-000000", 10, 2 * day, true);
+        let new = make_segment(dir.path(), "20240103-000000", 10, day / 2, true);
+        // Still being written, so is never removed
+        let current = make_segment(dir.path(), "20240104-000000", 10, 4 * day, false);
+        let tenant_dir = dir.path().join("acme");
+        std::fs::create_dir(&tenant_dir)?;
+        let tenant = make_segment(&tenant_dir, "20240101-000000", 10, 3 * day, true);
+
+        let by_size = LogRetentionParams {
+            max_age: None,
+            max_size: Some(25),
+            check_interval: Duration::from_secs(60),
+        };
+        let deleted = apply_retention_to_dir(dir.path(), &by_size, SystemTime::now(), true)?;
+        assert_eq!(deleted, vec![old.clone()]);
+
+        let by_age = LogRetentionParams {
+            max_age: Some(day),
+            max_size: None,
+            check_interval: Duration::from_secs(60),
+        };
+        let mut deleted = apply_retention_to_dir(dir.path(), &by_age, SystemTime::now(), true)?;
+        deleted.sort();
+        assert_eq!(deleted, vec![middle.clone(), tenant.clone()]);
+
+        assert!(new.exists());
+        assert!(current.exists());
+
+        Ok(())
+    }
+}
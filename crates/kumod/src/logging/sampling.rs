@@ -0,0 +1,208 @@
+pub use kumo_log_types::*;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TransientFailureSamplingParams {
+    /// The fraction of TransientFailure records to log, in the
+    /// range 0.0 to 1.0
+    pub rate: f64,
+
+    /// The number of records for each (site, response code)
+    /// that are always logged in each interval before sampling
+    /// takes effect
+    #[serde(default = "TransientFailureSamplingParams::default_always_log")]
+    pub always_log: u64,
+
+    /// How often to emit the records that summarize the
+    /// records that were not logged
+    #[serde(
+        default = "TransientFailureSamplingParams::default_interval",
+        with = "duration_serde"
+    )]
+    pub interval: Duration,
+}
+
+impl TransientFailureSamplingParams {
+    fn default_always_log() -> u64 {
+        1
+    }
+
+    fn default_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+#[derive(Default)]
+struct SampleState {
+    seen: u64,
+    suppressed: u64,
+    last_suppressed: Option<JsonLogRecord>,
+}
+
+/// Reduces the volume of TransientFailure records, which can be
+/// very large and very repetitive when a destination is deferring
+/// everything, while keeping track of how many were left out.
+pub struct TransientFailureSampler {
+    params: TransientFailureSamplingParams,
+    state: Mutex<HashMap<(String, u16), SampleState>>,
+}
+
+impl TransientFailureSampler {
+    pub fn new(params: Option<TransientFailureSamplingParams>) -> anyhow::Result<Option<Self>> {
+        let Some(params) = params else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&params.rate),
+            "sample_transient_failures.rate must be in the range 0.0 to 1.0"
+        );
+        anyhow::ensure!(
+            !params.interval.is_zero(),
+            "sample_transient_failures.interval must be greater than zero"
+        );
+        Ok(Some(Self {
+            params,
+            state: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.params.interval
+    }
+
+    /// Returns true if the record should be logged
+    pub fn should_log(&self, record: &JsonLogRecord) -> bool {
+        if record.kind != RecordType::TransientFailure {
+            return true;
+        }
+        let mut state = self.state.lock();
+        let entry = state
+            .entry((record.site.clone(), record.response.code))
+            .or_default();
+        entry.seen += 1;
+        if entry.seen <= self.params.always_log || rand::random::<f64>() < self.params.rate {
+            return true;
+        }
+        entry.suppressed += 1;
+        entry.last_suppressed.replace(record.clone());
+        false
+    }
+
+    /// Returns a record summarizing the suppressed records for each
+    /// (site, response code) in the interval that just ended, and
+    /// starts a new interval.
+    /// The summary is the most recently suppressed record, with
+    /// suppressed_count set to the number of records it represents.
+    pub fn take_summaries(&self) -> Vec<JsonLogRecord> {
+        let state = std::mem::take(&mut *self.state.lock());
+        state
+            .into_values()
+            .filter_map(|entry| {
+                let mut record = entry.last_suppressed?;
+                record.suppressed_count.replace(entry.suppressed);
+                Some(record)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rfc5321::Response;
+    use uuid::Uuid;
+
+    fn make_record(kind: RecordType, code: u16, site: &str) -> JsonLogRecord {
+        JsonLogRecord {
+            kind,
+            id: String::new(),
+            sender: String::new(),
+            recipient: String::new(),
+            queue: String::new(),
+            site: site.to_string(),
+            size: 0,
+            response: Response {
+                code,
+                command: None,
+                enhanced_code: None,
+                content: String::new(),
+            },
+            peer_address: None,
+            timestamp: Default::default(),
+            created: Default::default(),
+            num_attempts: 1,
+            latency: None,
+            bounce_classification: Default::default(),
+            egress_pool: None,
+            egress_source: None,
+            source_address: None,
+            feedback_report: None,
+            meta: Default::default(),
+            headers: Default::default(),
+            delivery_protocol: None,
+            reception_protocol: None,
+            nodeid: Uuid::default(),
+            tls_cipher: None,
+            tls_protocol_version: None,
+            tls_peer_subject_name: None,
+            provider_name: None,
+            session_id: None,
+            suppressed_count: None,
+            response_category: None,
+            annotations: vec![],
+            tenant_usage: None,
+        }
+    }
+
+    #[test]
+    fn sampling() {
+        let sampler = TransientFailureSampler::new(Some(TransientFailureSamplingParams {
+            rate: 0.0,
+            always_log: 2,
+            interval: Duration::from_secs(60),
+        }))
+        .unwrap()
+        .unwrap();
+
+        let logged = (0..5)
+            .filter(|_| sampler.should_log(&make_record(RecordType::TransientFailure, 421, "a")))
+            .count();
+        assert_eq!(logged, 2);
+
+        // Different response codes and sites are sampled separately
+        assert!(sampler.should_log(&make_record(RecordType::TransientFailure, 451, "a")));
+        assert!(sampler.should_log(&make_record(RecordType::TransientFailure, 421, "b")));
+
+        // Other record types are not sampled
+        for _ in 0..5 {
+            assert!(sampler.should_log(&make_record(RecordType::Delivery, 250, "a")));
+        }
+
+        let summaries = sampler.take_summaries();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].site, "a");
+        assert_eq!(summaries[0].response.code, 421);
+        assert_eq!(summaries[0].suppressed_count, Some(3));
+
+        // A new interval starts afresh
+        assert!(sampler.take_summaries().is_empty());
+        assert!(sampler.should_log(&make_record(RecordType::TransientFailure, 421, "a")));
+    }
+
+    #[test]
+    fn validation() {
+        assert!(
+            TransientFailureSampler::new(Some(TransientFailureSamplingParams {
+                rate: 1.5,
+                always_log: 1,
+                interval: Duration::from_secs(60),
+            }))
+            .is_err()
+        );
+        assert!(TransientFailureSampler::new(None).unwrap().is_none());
+    }
+}
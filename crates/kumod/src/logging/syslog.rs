@@ -0,0 +1,551 @@
+use crate::logging::files::LogFileParams;
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
+use crate::logging::{default_true, LogCommand};
+use crate::smtp_server::EsmtpListenerParams;
+use anyhow::Context;
+use chrono::SecondsFormat;
+use flume::Receiver;
+pub use kumo_log_types::*;
+use kumo_template::{Template, TemplateEngine};
+use prometheus::{IntCounter, IntCounterVec};
+use rfc5321::TlsOptions;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+static SYSLOG_SENT_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_syslog_sent_count",
+        "how many log records were sent to syslog",
+        &["logger"]
+    )
+    .unwrap()
+});
+static SYSLOG_FAILED_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_syslog_failed_count",
+        "how many log records could not be sent to syslog",
+        &["logger"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyslogTransport {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+    /// A unix domain datagram socket, such as /dev/log
+    Unix,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Kern,
+    User,
+    #[default]
+    Mail,
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    fn code(&self) -> u8 {
+        match self {
+            Self::Kern => 0,
+            Self::User => 1,
+            Self::Mail => 2,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Syslog => 5,
+            Self::Lpr => 6,
+            Self::News => 7,
+            Self::Uucp => 8,
+            Self::Cron => 9,
+            Self::Authpriv => 10,
+            Self::Ftp => 11,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogSeverity {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl SyslogSeverity {
+    fn default_for(kind: RecordType) -> Self {
+        match kind {
+            RecordType::Bounce | RecordType::Expiration | RecordType::AdminBounce => Self::Warning,
+            RecordType::TransientFailure | RecordType::Rejection => Self::Notice,
+            _ => Self::Info,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogRecordParams {
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Instead of sending the json object, format it with this
+    /// minijinja template
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// Overrides the default severity for this record type
+    #[serde(default)]
+    pub severity: Option<SyslogSeverity>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogSyslogParams {
+    /// The unique name to identify this instance of the syslog logger
+    pub name: String,
+
+    #[serde(default)]
+    pub transport: SyslogTransport,
+
+    /// `host:port` for the network transports, or the path to
+    /// the socket for the Unix transport
+    pub address: String,
+
+    /// The name to verify in the server certificate when using
+    /// the Tls transport. Defaults to the host portion of address.
+    #[serde(default)]
+    pub tls_hostname: Option<String>,
+
+    /// Don't verify the server certificate when using the Tls transport
+    #[serde(default)]
+    pub tls_insecure: bool,
+
+    #[serde(default)]
+    pub facility: SyslogFacility,
+
+    #[serde(default = "LogSyslogParams::default_app_name")]
+    pub app_name: String,
+
+    #[serde(default = "EsmtpListenerParams::default_hostname")]
+    pub hostname: String,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture in the log
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture in the log
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, SyslogRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be sent
+    #[serde(default)]
+    pub filter_event: Option<String>,
+
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
+}
+
+impl LogSyslogParams {
+    fn default_app_name() -> String {
+        "kumod".to_string()
+    }
+
+    fn per_record(&self, kind: RecordType) -> Option<&SyslogRecordParams> {
+        self.per_record
+            .get(&kind)
+            .or_else(|| self.per_record.get(&RecordType::Any))
+    }
+
+    fn severity_for(&self, kind: RecordType) -> SyslogSeverity {
+        self.per_record(kind)
+            .and_then(|pr| pr.severity)
+            .unwrap_or_else(|| SyslogSeverity::default_for(kind))
+    }
+
+    fn tls_server_name(&self) -> anyhow::Result<ServerName<'static>> {
+        let host = match &self.tls_hostname {
+            Some(name) => name.to_string(),
+            None => match self.address.rsplit_once(':') {
+                Some((host, _port)) => host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                None => self.address.to_string(),
+            },
+        };
+        match IpAddr::from_str(&host) {
+            Ok(ip) => Ok(ServerName::IpAddress(ip.into())),
+            Err(_) => ServerName::try_from(host.clone())
+                .with_context(|| format!("invalid tls_hostname {host}")),
+        }
+    }
+}
+
+/// Restrict a header field to the characters and length
+/// permitted by RFC 5424, which uses `-` to represent
+/// an empty value
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+/// Format an RFC 5424 syslog message
+fn format_message(
+    facility: SyslogFacility,
+    severity: SyslogSeverity,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    hostname: &str,
+    app_name: &str,
+    kind: RecordType,
+    msg: &[u8],
+) -> Vec<u8> {
+    let pri = (facility.code() as u32 * 8) + severity as u32;
+    let mut result = format!(
+        "<{pri}>1 {timestamp} {hostname} {app_name} {procid} {msgid} - ",
+        timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+        hostname = header_field(hostname, 255),
+        app_name = header_field(app_name, 48),
+        procid = std::process::id(),
+        msgid = header_field(&format!("{kind:?}"), 32),
+    )
+    .into_bytes();
+    result.extend_from_slice(msg);
+    result
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixDatagram),
+}
+
+impl Connection {
+    async fn connect(params: &LogSyslogParams) -> anyhow::Result<Self> {
+        let address = &params.address;
+        match params.transport {
+            SyslogTransport::Udp => {
+                let socket = UdpSocket::bind(if address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })
+                .await?;
+                socket
+                    .connect(address)
+                    .await
+                    .with_context(|| format!("connecting to {address}"))?;
+                Ok(Self::Udp(socket))
+            }
+            SyslogTransport::Tcp => {
+                let stream = TcpStream::connect(address)
+                    .await
+                    .with_context(|| format!("connecting to {address}"))?;
+                Ok(Self::Tcp(stream))
+            }
+            SyslogTransport::Tls => {
+                let stream = TcpStream::connect(address)
+                    .await
+                    .with_context(|| format!("connecting to {address}"))?;
+                let connector = TlsOptions {
+                    insecure: params.tls_insecure,
+                    ..Default::default()
+                }
+                .build_tls_connector();
+                let stream = connector
+                    .connect(params.tls_server_name()?, stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {address}"))?;
+                Ok(Self::Tls(Box::new(stream)))
+            }
+            SyslogTransport::Unix => {
+                let socket = UnixDatagram::unbound()?;
+                socket
+                    .connect(address)
+                    .with_context(|| format!("connecting to {address}"))?;
+                Ok(Self::Unix(socket))
+            }
+        }
+    }
+
+    async fn send(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Udp(socket) => {
+                socket.send(msg).await?;
+            }
+            Self::Unix(socket) => {
+                socket.send(msg).await?;
+            }
+            // Stream transports use the octet-counting framing
+            // described by RFC 6587 and RFC 5425
+            Self::Tcp(stream) => {
+                stream
+                    .write_all(format!("{} ", msg.len()).as_bytes())
+                    .await?;
+                stream.write_all(msg).await?;
+            }
+            Self::Tls(stream) => {
+                stream
+                    .write_all(format!("{} ", msg.len()).as_bytes())
+                    .await?;
+                stream.write_all(msg).await?;
+                stream.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct LogSyslogState {
+    params: LogSyslogParams,
+    receiver: Receiver<LogCommand>,
+    template_engine: TemplateEngine,
+    connection: Option<Connection>,
+    sent: IntCounter,
+    failed: IntCounter,
+}
+
+impl LogSyslogState {
+    pub fn new(
+        params: LogSyslogParams,
+        receiver: Receiver<LogCommand>,
+        template_engine: TemplateEngine,
+    ) -> anyhow::Result<Self> {
+        let sent = SYSLOG_SENT_COUNT.get_metric_with_label_values(&[&params.name])?;
+        let failed = SYSLOG_FAILED_COUNT.get_metric_with_label_values(&[&params.name])?;
+        Ok(Self {
+            params,
+            receiver,
+            template_engine,
+            connection: None,
+            sent,
+            failed,
+        })
+    }
+
+    pub async fn logger_thread(&mut self) {
+        tracing::debug!("LogSyslogParams: {:#?}", self.params);
+
+        loop {
+            let cmd = match self.receiver.recv_async().await {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    return;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    tracing::debug!("LogCommand::Terminate received. Stopping sending logs");
+                    break;
+                }
+                LogCommand::Record(record) => {
+                    if let Err(err) = self.do_record(record).await {
+                        self.failed.inc();
+                        tracing::error!("failed to log: {err:#}");
+                    };
+                }
+            }
+        }
+    }
+
+    async fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
+        tracing::trace!("do_record {record:?}");
+
+        let mut record_text = Vec::new();
+        self.template_engine
+            .add_global("log_record", kumo_template::Value::from_serialize(&logged));
+
+        if let Some(template) =
+            Self::resolve_template(&self.params, &self.template_engine, record.kind)
+        {
+            template.render_to_write(&logged, &mut record_text)?;
+        } else {
+            serde_json::to_writer(&mut record_text, &logged).context("serializing record")?;
+        }
+        // Trailing newlines are not meaningful in a syslog message
+        while record_text.last() == Some(&b'\n') {
+            record_text.pop();
+        }
+
+        let msg = format_message(
+            self.params.facility,
+            self.params.severity_for(record.kind),
+            record.timestamp,
+            &self.params.hostname,
+            &self.params.app_name,
+            record.kind,
+            &record_text,
+        );
+
+        // If the send fails on an existing connection, it may be
+        // because the peer closed it; try once more with a fresh one
+        let mut retried = false;
+        loop {
+            let mut conn = match self.connection.take() {
+                Some(conn) => conn,
+                None => Connection::connect(&self.params).await?,
+            };
+            match conn.send(&msg).await {
+                Ok(()) => {
+                    self.connection.replace(conn);
+                    self.sent.inc();
+                    return Ok(());
+                }
+                Err(err) if retried => return Err(err),
+                Err(err) => {
+                    tracing::debug!("syslog send failed: {err:#}, will reconnect and retry");
+                    retried = true;
+                }
+            }
+        }
+    }
+
+    fn resolve_template<'a>(
+        params: &LogSyslogParams,
+        template_engine: &'a TemplateEngine,
+        kind: RecordType,
+    ) -> Option<Template<'a, 'a>> {
+        if let Some(pr) = params.per_record.get(&kind) {
+            if pr.template.is_some() {
+                let label = format!("{kind:?}");
+                return template_engine.get_template(&label).ok();
+            }
+            return None;
+        }
+        if let Some(pr) = params.per_record.get(&RecordType::Any) {
+            if pr.template.is_some() {
+                return template_engine.get_template("Any").ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn format() {
+        let timestamp = chrono::Utc
+            .with_ymd_and_hms(2024, 10, 1, 12, 30, 45)
+            .unwrap();
+        let msg = format_message(
+            SyslogFacility::Mail,
+            SyslogSeverity::Info,
+            timestamp,
+            "mx1.example.com",
+            "kumo d",
+            RecordType::Delivery,
+            b"{\"hello\":\"world\"}",
+        );
+        assert_eq!(
+            String::from_utf8(msg).unwrap(),
+            format!(
+                "<22>1 2024-10-01T12:30:45.000000Z mx1.example.com kumod {} Delivery - \
+                 {{\"hello\":\"world\"}}",
+                std::process::id()
+            )
+        );
+
+        assert_eq!(header_field("", 10), "-");
+        assert_eq!(header_field("abcdef", 3), "abc");
+    }
+
+    #[tokio::test]
+    async fn udp() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let params = LogSyslogParams {
+            name: "test".to_string(),
+            transport: SyslogTransport::Udp,
+            address: server.local_addr()?.to_string(),
+            tls_hostname: None,
+            tls_insecure: false,
+            facility: SyslogFacility::Local0,
+            app_name: "kumod".to_string(),
+            hostname: "localhost".to_string(),
+            back_pressure: 10,
+            meta: vec![],
+            headers: vec![],
+            per_record: HashMap::new(),
+            filter_event: None,
+            transform: None,
+            sample_transient_failures: None,
+        };
+
+        let mut conn = Connection::connect(&params).await?;
+        conn.send(b"hello").await?;
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).await?;
+        assert_eq!(&buf[..len], b"hello");
+        Ok(())
+    }
+}
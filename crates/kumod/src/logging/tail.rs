@@ -0,0 +1,393 @@
+use crate::logging::classify::apply_classification;
+use kumo_log_types::JsonLogRecord;
+use message::queue_name::QueueNameComponents;
+use regex::Regex;
+use serde_json::Value;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+static NULL: Value = Value::Null;
+
+static TAIL: LazyLock<Sender<Arc<Value>>> = LazyLock::new(|| channel(1024).0);
+
+/// Returns true if there are any clients tailing the logs
+pub fn is_active() -> bool {
+    TAIL.receiver_count() > 0
+}
+
+/// Subscribe to the stream of log records.
+/// Each record is the JSON representation of a JsonLogRecord.
+pub fn subscribe() -> Receiver<Arc<Value>> {
+    TAIL.subscribe()
+}
+
+/// Submit a record to any clients that are tailing the logs
+pub async fn submit(mut record: JsonLogRecord) {
+    if !is_active() {
+        return;
+    }
+    apply_classification(&mut record).await;
+    match serde_json::to_value(&record) {
+        Ok(value) => {
+            TAIL.send(Arc::new(value)).ok();
+        }
+        Err(err) => {
+            tracing::error!("failed to serialize log record for tail: {err:#}");
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Literal(Value),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        field: String,
+        op: Op,
+        operand: Operand,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+/// Consumes the next character if it is the expected one
+fn next_is(chars: &mut Peekable<CharIndices>, expect: char) -> bool {
+    chars.next_if(|&(_, c)| c == expect).is_some()
+}
+
+fn tokenize(text: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((pos, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '=' if next_is(&mut chars, '=') => Token::Op(Op::Eq),
+            '=' if next_is(&mut chars, '~') => Token::Op(Op::Match),
+            '!' if next_is(&mut chars, '=') => Token::Op(Op::Ne),
+            '!' if next_is(&mut chars, '~') => Token::Op(Op::NotMatch),
+            '!' => Token::Not,
+            '<' if next_is(&mut chars, '=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if next_is(&mut chars, '=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '&' if next_is(&mut chars, '&') => Token::And,
+            '|' if next_is(&mut chars, '|') => Token::Or,
+            '"' | '\'' => {
+                let quote = c;
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => anyhow::bail!("unterminated string starting at {pos}"),
+                        },
+                        Some((_, c)) if c == quote => break,
+                        Some((_, c)) => s.push(c),
+                        None => anyhow::bail!("unterminated string starting at {pos}"),
+                    }
+                }
+                Token::Str(s)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = c.to_string();
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    s.push(c);
+                }
+                Token::Num(
+                    s.parse()
+                        .map_err(|_| anyhow::anyhow!("invalid number '{s}' at {pos}"))?,
+                )
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
+                {
+                    s.push(c);
+                }
+                match s.to_ascii_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Ident(s),
+                }
+            }
+            c => anyhow::bail!("unexpected '{c}' at {pos}"),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> anyhow::Result<Expr> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.tokens.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    other => anyhow::bail!("expected ')' but found {other:?}"),
+                }
+            }
+            Some(Token::Ident(field)) => {
+                let op = match self.tokens.next() {
+                    Some(Token::Op(op)) => op,
+                    other => {
+                        anyhow::bail!("expected a comparison after '{field}' but found {other:?}")
+                    }
+                };
+                let value = match self.tokens.next() {
+                    Some(Token::Str(s)) => Value::String(s),
+                    Some(Token::Num(n)) => n.into(),
+                    Some(Token::Ident(s)) => match s.as_str() {
+                        "true" => Value::Bool(true),
+                        "false" => Value::Bool(false),
+                        "null" => Value::Null,
+                        _ => anyhow::bail!(
+                            "expected a value after '{field}' but found '{s}'; \
+                             quote string values"
+                        ),
+                    },
+                    other => anyhow::bail!("expected a value after '{field}' but found {other:?}"),
+                };
+                let operand = match op {
+                    Op::Match | Op::NotMatch => match value {
+                        Value::String(s) => Operand::Regex(Regex::new(&s)?),
+                        _ => anyhow::bail!("the regex for '{field}' must be a string"),
+                    },
+                    _ => Operand::Literal(value),
+                };
+                Ok(Expr::Compare { field, op, operand })
+            }
+            other => anyhow::bail!("expected a comparison but found {other:?}"),
+        }
+    }
+}
+
+/// Resolves a dotted field path against a log record.
+/// `tenant`, `campaign` and `domain` are derived from the queue
+/// name when they are not otherwise present in the record.
+fn lookup<'a>(record: &'a Value, field: &str) -> Option<std::borrow::Cow<'a, Value>> {
+    let mut value = record;
+    let mut found = true;
+    for component in field.split('.') {
+        match value.get(component) {
+            Some(v) => value = v,
+            None => {
+                found = false;
+                break;
+            }
+        }
+    }
+    if found {
+        return Some(std::borrow::Cow::Borrowed(value));
+    }
+
+    let queue = record.get("queue")?.as_str()?;
+    let components = QueueNameComponents::parse(queue);
+    let derived = match field {
+        "tenant" => components.tenant,
+        "campaign" => components.campaign,
+        "domain" => Some(components.domain),
+        _ => None,
+    }?;
+    Some(std::borrow::Cow::Owned(Value::String(derived.to_string())))
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        value => value.to_string(),
+    }
+}
+
+impl Expr {
+    fn matches(&self, record: &Value) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(record) && b.matches(record),
+            Self::Or(a, b) => a.matches(record) || b.matches(record),
+            Self::Not(a) => !a.matches(record),
+            Self::Compare { field, op, operand } => {
+                let value = lookup(record, field);
+                let value = value.as_deref().unwrap_or(&NULL);
+                match operand {
+                    Operand::Regex(re) => {
+                        let is_match = !value.is_null() && re.is_match(&as_text(value));
+                        is_match == (*op == Op::Match)
+                    }
+                    Operand::Literal(literal) => {
+                        let equal = || match (as_number(value), literal) {
+                            (Some(a), Value::Number(b)) => b.as_f64() == Some(a),
+                            _ => value == literal,
+                        };
+                        let ordered = |f: fn(f64, f64) -> bool| match (
+                            as_number(value),
+                            as_number(literal),
+                        ) {
+                            (Some(a), Some(b)) => f(a, b),
+                            _ => false,
+                        };
+                        match op {
+                            Op::Eq => equal(),
+                            Op::Ne => !equal(),
+                            Op::Lt => ordered(|a, b| a < b),
+                            Op::Le => ordered(|a, b| a <= b),
+                            Op::Gt => ordered(|a, b| a > b),
+                            Op::Ge => ordered(|a, b| a >= b),
+                            Op::Match | Op::NotMatch => unreachable!(),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A filter expression that is evaluated against each log record
+/// before it is sent to a client that is tailing the logs.
+///
+/// Comparisons take the form `FIELD OP VALUE`, where FIELD is a dotted
+/// path into the JSON log record, such as `response.code` or `meta.tenant`,
+/// OP is one of `==`, `!=`, `<`, `<=`, `>`, `>=`, `=~` (regex match)
+/// or `!~` (regex does not match) and VALUE is a quoted string, a number,
+/// `true`, `false` or `null`.  Comparisons may be combined using `and`,
+/// `or`, `not` and parentheses.
+#[derive(Debug)]
+pub struct LogFilter {
+    expr: Expr,
+}
+
+impl LogFilter {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?.into_iter().peekable(),
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.tokens.next() {
+            anyhow::bail!("unexpected {token:?} after the end of the expression");
+        }
+        Ok(Self { expr })
+    }
+
+    pub fn matches(&self, record: &Value) -> bool {
+        self.expr.matches(record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn record() -> Value {
+        json!({
+            "type": "TransientFailure",
+            "queue": "newsletter:acme@example.com",
+            "site": "unspecified->mx.example.com@smtp_client",
+            "num_attempts": 3,
+            "response": {
+                "code": 451,
+                "content": "4.7.1 Greylisted, try again later",
+            },
+            "meta": {"customer": "1234"},
+        })
+    }
+
+    #[test]
+    fn filter() {
+        let record = record();
+        let check = |text: &str| LogFilter::parse(text).unwrap().matches(&record);
+
+        assert!(check(r#"type == "TransientFailure""#));
+        assert!(!check(r#"type != "TransientFailure""#));
+        assert!(check("response.code == 451"));
+        assert!(check("response.code >= 400 and response.code < 500"));
+        assert!(check(r#"response.content =~ "(?i)greylist""#));
+        assert!(check(r#"response.content !~ "blocked""#));
+        assert!(check(r#"tenant == "acme" && campaign == 'newsletter'"#));
+        assert!(check(r#"domain == "example.com""#));
+        assert!(check(r#"meta.customer == 1234"#));
+        assert!(check("num_attempts > 2"));
+        assert!(check("meta.missing == null"));
+        assert!(!check(r#"meta.missing =~ ".""#));
+        assert!(check(
+            r#"not (type == "Delivery" or type == "Bounce") and site =~ "example""#
+        ));
+        assert!(!check(r#"tenant == "other" || !(num_attempts > 2)"#));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(LogFilter::parse("").is_err());
+        assert!(LogFilter::parse("type").is_err());
+        assert!(LogFilter::parse("type == Delivery").is_err());
+        assert!(LogFilter::parse(r#"type == "Delivery" )"#).is_err());
+        assert!(LogFilter::parse(r#"response.content =~ "(""#).is_err());
+        assert!(LogFilter::parse(r#"type == "unterminated"#).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Context;
+use config::{load_config, CallbackSignature, SerdeWrappedValue};
+pub use kumo_log_types::*;
+use kumo_template::TemplateEngine;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// A log record as submitted to a logger, along with the result
+/// of applying that logger's transform to it, if it has one.
+/// Serializes as the transformed record when present.
+#[derive(Debug)]
+pub struct LoggedRecord {
+    pub record: JsonLogRecord,
+    pub transformed: Option<Value>,
+}
+
+impl Serialize for LoggedRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.transformed {
+            Some(value) => value.serialize(serializer),
+            None => self.record.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RecordTransformParams {
+    /// Fields to add to the record. The values are expressions
+    /// that are evaluated against the original record, such as
+    /// `meta.customer_id`
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+
+    /// Fields to rename, mapping the existing name to the new name
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+
+    /// Fields to remove from the record
+    #[serde(default)]
+    pub remove: Vec<String>,
+
+    /// The name of an event that will be called with the
+    /// record after the other transformations have been applied.
+    /// It returns the record to be logged, or nil to skip it.
+    #[serde(default)]
+    pub event: Option<String>,
+}
+
+impl RecordTransformParams {
+    fn is_empty(&self) -> bool {
+        self.add.is_empty()
+            && self.rename.is_empty()
+            && self.remove.is_empty()
+            && self.event.is_none()
+    }
+}
+
+/// Field names may use `.` to refer to nested fields, such as `meta.email`
+fn take_field(value: &mut Value, path: &str) -> Option<Value> {
+    match path.split_once('.') {
+        Some((head, tail)) => take_field(value.get_mut(head)?, tail),
+        None => value.as_object_mut()?.remove(path),
+    }
+}
+
+fn set_field(value: &mut Value, path: &str, field_value: Value) {
+    let Some(obj) = value.as_object_mut() else {
+        return;
+    };
+    match path.split_once('.') {
+        Some((head, tail)) => {
+            let child = obj
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            set_field(child, tail, field_value);
+        }
+        None => {
+            obj.insert(path.to_string(), field_value);
+        }
+    }
+}
+
+pub struct RecordTransform {
+    params: RecordTransformParams,
+    engine: TemplateEngine,
+}
+
+impl std::fmt::Debug for RecordTransform {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("RecordTransform")
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl RecordTransform {
+    pub fn new(params: Option<RecordTransformParams>) -> anyhow::Result<Option<Self>> {
+        let Some(params) = params.filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let engine = TemplateEngine::new();
+        for (name, expr) in &params.add {
+            engine
+                .check_expression(expr)
+                .with_context(|| format!("compiling expression `{expr}` for field {name}"))?;
+        }
+        Ok(Some(Self { params, engine }))
+    }
+
+    /// Applies the transform, returning None if the
+    /// record should not be logged
+    pub async fn apply(&self, record: &JsonLogRecord) -> anyhow::Result<Option<Value>> {
+        let mut value = serde_json::to_value(record)?;
+
+        let mut added = vec![];
+        for (name, expr) in &self.params.add {
+            let result = self
+                .engine
+                .eval_expression(expr, &value)
+                .with_context(|| format!("evaluating expression `{expr}` for field {name}"))?;
+            if !result.is_undefined() {
+                added.push((name, serde_json::to_value(&result)?));
+            }
+        }
+        for (name, field_value) in added {
+            set_field(&mut value, name, field_value);
+        }
+
+        for (from, to) in &self.params.rename {
+            if let Some(field_value) = take_field(&mut value, from) {
+                set_field(&mut value, to, field_value);
+            }
+        }
+
+        for name in &self.params.remove {
+            take_field(&mut value, name);
+        }
+
+        if let Some(name) = &self.params.event {
+            let sig = CallbackSignature::<
+                SerdeWrappedValue<Value>,
+                Option<SerdeWrappedValue<Value>>,
+            >::new(name.clone());
+            let mut lua_config = load_config().await?;
+            let result = lua_config
+                .async_call_callback_non_default(&sig, SerdeWrappedValue(value))
+                .await
+                .with_context(|| format!("calling {name} event for log record transform"))?;
+            return Ok(result.map(|v| v.0));
+        }
+
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fields() {
+        let mut value = json!({
+            "recipient": "user@example.com",
+            "meta": {"email": "user@example.com", "customer": 42},
+        });
+
+        assert_eq!(
+            take_field(&mut value, "meta.email"),
+            Some(json!("user@example.com"))
+        );
+        assert_eq!(take_field(&mut value, "meta.nothing"), None);
+        assert_eq!(take_field(&mut value, "nothing.email"), None);
+
+        let recipient = take_field(&mut value, "recipient").unwrap();
+        set_field(&mut value, "rcpt", recipient);
+        set_field(&mut value, "extra.customer_id", json!(42));
+
+        assert_eq!(
+            value,
+            json!({
+                "rcpt": "user@example.com",
+                "meta": {"customer": 42},
+                "extra": {"customer_id": 42},
+            })
+        );
+    }
+}
@@ -0,0 +1,738 @@
+use crate::logging::files::LogFileParams;
+use crate::logging::sampling::TransientFailureSamplingParams;
+use crate::logging::transform::{LoggedRecord, RecordTransformParams};
+use crate::logging::{default_true, LogCommand};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use flume::Receiver;
+pub use kumo_log_types::*;
+use kumo_template::{Template, TemplateEngine};
+use parking_lot::Mutex;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// The maximum number of buffered records to deliver before
+/// giving the logger a chance to accept newly submitted records
+const DRAIN_BATCH_SIZE: usize = 100;
+
+static WEBHOOK_BACKLOGS: LazyLock<Mutex<HashMap<String, Arc<WebhookBacklog>>>> =
+    LazyLock::new(Mutex::default);
+
+static WEBHOOK_SENT_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_webhook_sent_count",
+        "how many log records were delivered to a webhook",
+        &["logger"]
+    )
+    .unwrap()
+});
+static WEBHOOK_FAILED_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_webhook_failed_count",
+        "how many attempts to deliver a log record to a webhook failed",
+        &["logger"]
+    )
+    .unwrap()
+});
+static WEBHOOK_DROPPED_COUNT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "log_webhook_dropped_count",
+        "how many buffered log records were discarded due to buffer size or age limits",
+        &["logger"]
+    )
+    .unwrap()
+});
+static WEBHOOK_BACKLOG_COUNT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "log_webhook_backlog_count",
+        "how many log records are buffered on disk awaiting delivery to a webhook",
+        &["logger"]
+    )
+    .unwrap()
+});
+static WEBHOOK_BACKLOG_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "log_webhook_backlog_bytes",
+        "size of the log records buffered on disk awaiting delivery to a webhook",
+        &["logger"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookRecordParams {
+    #[serde(default = "default_true")]
+    pub enable: bool,
+
+    /// Instead of sending the json object, format it with this
+    /// minijinja template
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogWebhookParams {
+    /// The unique name to identify this instance of the webhook logger
+    pub name: String,
+
+    /// The URL to which each record is POSTed
+    pub url: String,
+
+    /// Additional HTTP headers to send with each request,
+    /// such as `Authorization`
+    #[serde(default)]
+    pub http_headers: HashMap<String, String>,
+
+    #[serde(default = "LogWebhookParams::default_content_type")]
+    pub content_type: String,
+
+    /// How long to wait for the webhook to respond
+    #[serde(default = "LogWebhookParams::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+
+    /// Where to buffer records that could not be delivered
+    pub buffer_dir: PathBuf,
+
+    /// The maximum total size of the buffered records. When
+    /// exceeded, the oldest records are discarded to make room.
+    #[serde(default = "LogWebhookParams::default_max_buffer_size")]
+    pub max_buffer_size: u64,
+
+    /// Buffered records older than this are discarded
+    #[serde(
+        default = "LogWebhookParams::default_max_buffer_age",
+        with = "duration_serde"
+    )]
+    pub max_buffer_age: Duration,
+
+    /// How long to wait before retrying after the first failure
+    #[serde(
+        default = "LogWebhookParams::default_retry_interval",
+        with = "duration_serde"
+    )]
+    pub retry_interval: Duration,
+
+    /// The retry interval doubles after each consecutive failure,
+    /// up to this limit
+    #[serde(
+        default = "LogWebhookParams::default_max_retry_interval",
+        with = "duration_serde"
+    )]
+    pub max_retry_interval: Duration,
+
+    /// Maximum number of outstanding items to be logged before
+    /// the submission will block; helps to avoid runaway issues
+    /// spiralling out of control.
+    #[serde(default = "LogFileParams::default_back_pressure")]
+    pub back_pressure: usize,
+
+    /// List of meta fields to capture in the log
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// List of message headers to capture in the log
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub per_record: HashMap<RecordType, WebhookRecordParams>,
+
+    /// The name of an event which can be used to filter
+    /// out log records which should not be sent
+    #[serde(default)]
+    pub filter_event: Option<String>,
+
+    /// Adjusts the fields of each record before it is logged
+    #[serde(default)]
+    pub transform: Option<RecordTransformParams>,
+
+    /// Samples TransientFailure records to reduce their volume
+    #[serde(default)]
+    pub sample_transient_failures: Option<TransientFailureSamplingParams>,
+}
+
+impl LogWebhookParams {
+    fn default_content_type() -> String {
+        "application/json".to_string()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_max_buffer_size() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    fn default_max_buffer_age() -> Duration {
+        Duration::from_secs(86400)
+    }
+
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_max_retry_interval() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+struct BacklogEntry {
+    /// Nanoseconds since the unix epoch at which the record was
+    /// buffered; also used to name the file that holds it
+    seq: u64,
+    size: u64,
+}
+
+impl BacklogEntry {
+    fn buffered_at(&self) -> DateTime<Utc> {
+        DateTime::from(UNIX_EPOCH + Duration::from_nanos(self.seq))
+    }
+}
+
+#[derive(Default)]
+struct BacklogInner {
+    entries: VecDeque<BacklogEntry>,
+    total_size: u64,
+    last_seq: u64,
+    retry_delay: Option<Duration>,
+    next_attempt: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+/// A bounded, on-disk FIFO buffer of log records that could
+/// not be delivered to a webhook. Each record is held in its
+/// own file so that the buffer survives a restart.
+pub struct WebhookBacklog {
+    name: String,
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    inner: Mutex<BacklogInner>,
+    flush: Notify,
+    count_gauge: IntGauge,
+    bytes_gauge: IntGauge,
+    dropped: IntCounter,
+}
+
+pub struct WebhookBacklogStatus {
+    pub name: String,
+    pub num_records: usize,
+    pub size_bytes: u64,
+    pub oldest: Option<DateTime<Utc>>,
+    pub next_attempt: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl WebhookBacklog {
+    fn open(params: &LogWebhookParams) -> anyhow::Result<Self> {
+        let dir = params.buffer_dir.clone();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("creating webhook buffer_dir {}", dir.display()))?;
+
+        let mut inner = BacklogInner::default();
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("reading webhook buffer_dir {}", dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let Some(seq) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".rec"))
+                .and_then(|seq| seq.parse::<u64>().ok())
+            else {
+                if path.extension().is_some_and(|ext| ext == "tmp") {
+                    // Left over from a partial write; the record
+                    // was never accepted into the buffer
+                    std::fs::remove_file(&path).ok();
+                }
+                continue;
+            };
+            let size = entry.metadata()?.len();
+            inner.entries.push_back(BacklogEntry { seq, size });
+            inner.total_size += size;
+        }
+        inner
+            .entries
+            .make_contiguous()
+            .sort_by_key(|entry| entry.seq);
+        inner.last_seq = inner.entries.back().map(|entry| entry.seq).unwrap_or(0);
+        if !inner.entries.is_empty() {
+            // Try to deliver the records left over from a previous run
+            inner.next_attempt.replace(Utc::now());
+        }
+
+        let backlog = Self {
+            name: params.name.clone(),
+            dir,
+            max_size: params.max_buffer_size,
+            max_age: params.max_buffer_age,
+            inner: Mutex::new(inner),
+            flush: Notify::new(),
+            count_gauge: WEBHOOK_BACKLOG_COUNT.get_metric_with_label_values(&[&params.name])?,
+            bytes_gauge: WEBHOOK_BACKLOG_BYTES.get_metric_with_label_values(&[&params.name])?,
+            dropped: WEBHOOK_DROPPED_COUNT.get_metric_with_label_values(&[&params.name])?,
+        };
+        backlog.update_gauges(&backlog.inner.lock());
+        Ok(backlog)
+    }
+
+    fn path_for(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:020}.rec"))
+    }
+
+    fn update_gauges(&self, inner: &BacklogInner) {
+        self.count_gauge.set(inner.entries.len() as i64);
+        self.bytes_gauge.set(inner.total_size as i64);
+    }
+
+    fn pop_front(&self, inner: &mut BacklogInner) {
+        if let Some(entry) = inner.entries.pop_front() {
+            inner.total_size -= entry.size;
+            if let Err(err) = std::fs::remove_file(self.path_for(entry.seq)) {
+                tracing::error!(
+                    "webhook {}: failed to remove buffered record {}: {err:#}",
+                    self.name,
+                    entry.seq
+                );
+            }
+        }
+    }
+
+    /// Discard records that have exceeded the max_age
+    fn expire(&self, inner: &mut BacklogInner) {
+        let now = Utc::now();
+        while let Some(entry) = inner.entries.front() {
+            let age = (now - entry.buffered_at()).to_std().unwrap_or_default();
+            if age <= self.max_age {
+                break;
+            }
+            self.pop_front(inner);
+            self.dropped.inc();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.lock().entries.is_empty()
+    }
+
+    /// Append a record to the buffer, discarding the oldest
+    /// records if necessary to stay within the size limit
+    fn push(&self, body: &[u8]) -> anyhow::Result<()> {
+        let size = body.len() as u64;
+        let mut inner = self.inner.lock();
+        self.expire(&mut inner);
+
+        if size > self.max_size {
+            self.dropped.inc();
+            anyhow::bail!(
+                "record of {size} bytes is larger than max_buffer_size {}",
+                self.max_size
+            );
+        }
+        while !inner.entries.is_empty() && inner.total_size + size > self.max_size {
+            self.pop_front(&mut inner);
+            self.dropped.inc();
+        }
+
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64;
+        let seq = now.max(inner.last_seq + 1);
+
+        let path = self.path_for(seq);
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, body)
+            .and_then(|_| std::fs::rename(&temp_path, &path))
+            .with_context(|| format!("writing buffered record {}", path.display()))?;
+
+        inner.last_seq = seq;
+        inner.entries.push_back(BacklogEntry { seq, size });
+        inner.total_size += size;
+        self.update_gauges(&inner);
+        Ok(())
+    }
+
+    /// Returns the oldest record in the buffer, if any
+    fn front(&self) -> Option<(u64, anyhow::Result<Vec<u8>>)> {
+        let mut inner = self.inner.lock();
+        self.expire(&mut inner);
+        self.update_gauges(&inner);
+        let seq = inner.entries.front()?.seq;
+        let path = self.path_for(seq);
+        Some((
+            seq,
+            std::fs::read(&path).with_context(|| format!("reading {}", path.display())),
+        ))
+    }
+
+    /// Remove the record that was returned by front
+    fn remove(&self, seq: u64) {
+        let mut inner = self.inner.lock();
+        // The buffer may have been discarded by an administrator
+        // while the record was being delivered
+        if inner.entries.front().map(|entry| entry.seq) == Some(seq) {
+            self.pop_front(&mut inner);
+            self.update_gauges(&inner);
+        }
+    }
+
+    fn record_failure(&self, err: &anyhow::Error, params: &LogWebhookParams) {
+        let mut inner = self.inner.lock();
+        let delay = match inner.retry_delay {
+            Some(delay) => (delay * 2).min(params.max_retry_interval),
+            None => params.retry_interval,
+        };
+        inner.retry_delay.replace(delay);
+        inner
+            .next_attempt
+            .replace(Utc::now() + chrono::Duration::from_std(delay).unwrap_or_default());
+        inner.last_error.replace(format!("{err:#}"));
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock();
+        inner.retry_delay.take();
+        inner.last_error.take();
+        inner.next_attempt = if inner.entries.is_empty() {
+            None
+        } else {
+            Some(Utc::now())
+        };
+    }
+
+    /// Returns the time at which delivery of the buffered
+    /// records should next be attempted, if there are any
+    fn next_attempt(&self) -> Option<tokio::time::Instant> {
+        let inner = self.inner.lock();
+        if inner.entries.is_empty() {
+            return None;
+        }
+        let delay = inner
+            .next_attempt
+            .map(|when| (when - Utc::now()).to_std().unwrap_or_default())
+            .unwrap_or_default();
+        Some(tokio::time::Instant::now() + delay)
+    }
+
+    pub fn status(&self) -> WebhookBacklogStatus {
+        let inner = self.inner.lock();
+        WebhookBacklogStatus {
+            name: self.name.clone(),
+            num_records: inner.entries.len(),
+            size_bytes: inner.total_size,
+            oldest: inner.entries.front().map(|entry| entry.buffered_at()),
+            next_attempt: if inner.entries.is_empty() {
+                None
+            } else {
+                inner.next_attempt
+            },
+            last_error: inner.last_error.clone(),
+        }
+    }
+
+    /// Cause delivery of the buffered records to be attempted
+    /// immediately, rather than waiting for the retry interval
+    pub fn retry_now(&self) {
+        self.inner.lock().next_attempt.replace(Utc::now());
+        self.flush.notify_one();
+    }
+
+    /// Delete all of the buffered records, returning the number
+    /// of records that were discarded
+    pub fn discard(&self) -> usize {
+        let mut inner = self.inner.lock();
+        let count = inner.entries.len();
+        while !inner.entries.is_empty() {
+            self.pop_front(&mut inner);
+        }
+        inner.retry_delay.take();
+        inner.next_attempt.take();
+        self.update_gauges(&inner);
+        count
+    }
+
+    pub fn get_named(name: &str) -> Option<Arc<Self>> {
+        WEBHOOK_BACKLOGS.lock().get(name).cloned()
+    }
+
+    pub fn get_all() -> Vec<Arc<Self>> {
+        let mut result: Vec<_> = WEBHOOK_BACKLOGS.lock().values().cloned().collect();
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        result
+    }
+}
+
+pub struct LogWebhookState {
+    params: LogWebhookParams,
+    receiver: Receiver<LogCommand>,
+    template_engine: TemplateEngine,
+    client: Client,
+    backlog: Arc<WebhookBacklog>,
+    sent: IntCounter,
+    failed: IntCounter,
+}
+
+impl LogWebhookState {
+    pub fn new(
+        params: LogWebhookParams,
+        receiver: Receiver<LogCommand>,
+        template_engine: TemplateEngine,
+    ) -> anyhow::Result<Self> {
+        let client = Client::builder()
+            .timeout(params.timeout)
+            .build()
+            .context("building http client")?;
+        let backlog = Arc::new(WebhookBacklog::open(&params)?);
+        WEBHOOK_BACKLOGS
+            .lock()
+            .insert(params.name.clone(), backlog.clone());
+
+        let sent = WEBHOOK_SENT_COUNT.get_metric_with_label_values(&[&params.name])?;
+        let failed = WEBHOOK_FAILED_COUNT.get_metric_with_label_values(&[&params.name])?;
+
+        Ok(Self {
+            params,
+            receiver,
+            template_engine,
+            client,
+            backlog,
+            sent,
+            failed,
+        })
+    }
+
+    pub async fn logger_thread(&mut self) {
+        tracing::debug!("LogWebhookParams: {:#?}", self.params);
+
+        loop {
+            let cmd = match self.backlog.next_attempt() {
+                None => self.receiver.recv_async().await,
+                Some(when) => {
+                    tokio::select! {
+                        cmd = self.receiver.recv_async() => cmd,
+                        _ = tokio::time::sleep_until(when) => {
+                            self.drain_backlog().await;
+                            continue;
+                        }
+                        _ = self.backlog.flush.notified() => {
+                            self.drain_backlog().await;
+                            continue;
+                        }
+                    }
+                }
+            };
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                other => {
+                    tracing::debug!("logging channel closed {other:?}");
+                    return;
+                }
+            };
+            match cmd {
+                LogCommand::Terminate => {
+                    // Anything still in the buffer will be delivered
+                    // when we next start up
+                    tracing::debug!("LogCommand::Terminate received. Stopping sending logs");
+                    break;
+                }
+                LogCommand::Record(record) => {
+                    if let Err(err) = self.do_record(record).await {
+                        tracing::error!("failed to log: {err:#}");
+                    };
+                }
+            }
+        }
+    }
+
+    async fn do_record(&mut self, logged: LoggedRecord) -> anyhow::Result<()> {
+        let record = &logged.record;
+        tracing::trace!("do_record {record:?}");
+
+        let mut body = Vec::new();
+        self.template_engine
+            .add_global("log_record", kumo_template::Value::from_serialize(&logged));
+
+        if let Some(template) =
+            Self::resolve_template(&self.params, &self.template_engine, record.kind)
+        {
+            template.render_to_write(&logged, &mut body)?;
+        } else {
+            serde_json::to_writer(&mut body, &logged).context("serializing record")?;
+        }
+
+        // If there is a backlog, this record has to wait its turn
+        // so that records are delivered in order
+        if !self.backlog.is_empty() {
+            return self.backlog.push(&body);
+        }
+
+        match self.send(&body).await {
+            Ok(()) => {
+                self.sent.inc();
+                Ok(())
+            }
+            Err(err) => {
+                self.failed.inc();
+                tracing::debug!(
+                    "webhook {}: delivery failed: {err:#}, buffering record",
+                    self.params.name
+                );
+                self.backlog.record_failure(&err, &self.params);
+                self.backlog.push(&body)
+            }
+        }
+    }
+
+    /// Attempt to deliver a batch of the buffered records
+    async fn drain_backlog(&mut self) {
+        for _ in 0..DRAIN_BATCH_SIZE {
+            let Some((seq, body)) = self.backlog.front() else {
+                self.backlog.record_success();
+                return;
+            };
+            let body = match body {
+                Ok(body) => body,
+                Err(err) => {
+                    tracing::error!(
+                        "webhook {}: discarding unreadable buffered record: {err:#}",
+                        self.params.name
+                    );
+                    self.backlog.remove(seq);
+                    continue;
+                }
+            };
+            match self.send(&body).await {
+                Ok(()) => {
+                    self.sent.inc();
+                    self.backlog.remove(seq);
+                    self.backlog.record_success();
+                }
+                Err(err) => {
+                    self.failed.inc();
+                    tracing::debug!(
+                        "webhook {}: delivery of buffered record failed: {err:#}",
+                        self.params.name
+                    );
+                    self.backlog.record_failure(&err, &self.params);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, body: &[u8]) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.params.url)
+            .header(CONTENT_TYPE, &self.params.content_type)
+            .body(body.to_vec());
+        for (name, value) in &self.params.http_headers {
+            request = request.header(name, value);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            anyhow::bail!("{status}: {text}");
+        }
+        Ok(())
+    }
+
+    fn resolve_template<'a>(
+        params: &LogWebhookParams,
+        template_engine: &'a TemplateEngine,
+        kind: RecordType,
+    ) -> Option<Template<'a, 'a>> {
+        if let Some(pr) = params.per_record.get(&kind) {
+            if pr.template.is_some() {
+                let label = format!("{kind:?}");
+                return template_engine.get_template(&label).ok();
+            }
+            return None;
+        }
+        if let Some(pr) = params.per_record.get(&RecordType::Any) {
+            if pr.template.is_some() {
+                return template_engine.get_template("Any").ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_params(dir: &std::path::Path, max_buffer_size: u64) -> LogWebhookParams {
+        LogWebhookParams {
+            name: "test".to_string(),
+            url: "http://localhost/".to_string(),
+            http_headers: HashMap::new(),
+            content_type: LogWebhookParams::default_content_type(),
+            timeout: LogWebhookParams::default_timeout(),
+            buffer_dir: dir.to_path_buf(),
+            max_buffer_size,
+            max_buffer_age: LogWebhookParams::default_max_buffer_age(),
+            retry_interval: LogWebhookParams::default_retry_interval(),
+            max_retry_interval: LogWebhookParams::default_max_retry_interval(),
+            back_pressure: 10,
+            meta: vec![],
+            headers: vec![],
+            per_record: HashMap::new(),
+            filter_event: None,
+            transform: None,
+            sample_transient_failures: None,
+        }
+    }
+
+    #[test]
+    fn backlog() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let params = make_params(dir.path(), 12);
+
+        let backlog = WebhookBacklog::open(&params)?;
+        assert!(backlog.is_empty());
+        backlog.push(b"one")?;
+        backlog.push(b"two")?;
+        backlog.push(b"three")?;
+        assert_eq!(backlog.status().num_records, 3);
+        assert_eq!(backlog.status().size_bytes, 11);
+
+        // Exceeding the size limit evicts the oldest record
+        backlog.push(b"four")?;
+        assert_eq!(backlog.status().num_records, 3);
+        let (seq, body) = backlog.front().unwrap();
+        assert_eq!(body?, b"two");
+
+        // Records larger than the buffer are rejected
+        assert!(backlog.push(b"this is too big").is_err());
+
+        // The buffer persists across a restart
+        drop(backlog);
+        let backlog = WebhookBacklog::open(&params)?;
+        assert_eq!(backlog.status().num_records, 3);
+        let (seq2, body) = backlog.front().unwrap();
+        assert_eq!(seq2, seq);
+        assert_eq!(body?, b"two");
+
+        backlog.remove(seq);
+        let (_, body) = backlog.front().unwrap();
+        assert_eq!(body?, b"three");
+
+        assert_eq!(backlog.discard(), 2);
+        assert!(backlog.is_empty());
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+
+        Ok(())
+    }
+}
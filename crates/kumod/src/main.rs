@@ -9,6 +9,7 @@ use nix::sys::resource::{getrlimit, setrlimit, Resource};
 use nix::unistd::{Uid, User};
 use std::path::PathBuf;
 use std::sync::LazyLock;
+use std::time::Duration;
 
 pub static PRE_INIT_SIG: LazyLock<CallbackSignature<(), ()>> =
     LazyLock::new(|| CallbackSignature::new_with_multiple("pre_init"));
@@ -16,19 +17,39 @@ pub static VALIDATE_SIG: LazyLock<CallbackSignature<(), ()>> =
     LazyLock::new(|| CallbackSignature::new_with_multiple("validate_config"));
 
 mod accounting;
+mod age_slo;
+mod analytics;
+mod bandwidth;
+mod batv;
+mod config_snapshot;
 mod delivery_metrics;
+mod dsn;
+mod egress_preflight;
 mod egress_source;
+mod feedback;
+mod greylist;
+mod header_rewrite;
 mod http_server;
+mod list_unsubscribe;
 mod logging;
 mod lua_deliver;
+mod message_index;
+mod meta_index;
 mod metrics_helper;
 mod mod_kumo;
+mod proxy_health;
 mod queue;
 mod ready_queue;
+mod recipient_verify;
+mod send_window;
 mod smtp_dispatcher;
 mod smtp_server;
 mod spf;
 mod spool;
+mod suppression;
+mod tenant_usage;
+mod warmup;
+mod wire_capture;
 
 /// KumoMTA Daemon.
 ///
@@ -46,6 +67,19 @@ struct Opt {
     #[arg(long)]
     validate: bool,
 
+    /// When used together with --validate, write a JSON snapshot
+    /// of the listener, logging, queue and egress path configuration
+    /// produced by the policy to this file.
+    #[arg(long, requires("validate"))]
+    dump_config: Option<PathBuf>,
+
+    /// When used together with --dump-config, a snapshot, such as
+    /// one obtained from the /api/admin/config-snapshot/v1 endpoint
+    /// of a running node, that lists the queues and egress paths
+    /// whose configuration should be included in the output.
+    #[arg(long, requires("dump_config"))]
+    dump_config_reference: Option<PathBuf>,
+
     /// Rather than spawning kumod in service mode, execute
     /// the policy script as a standalone script and then exit.
     /// Use kumo.on('main') to define the entrypoint for the script
@@ -66,6 +100,18 @@ struct Opt {
     #[arg(long, default_value = "full")]
     diag_format: DiagnosticFormat,
 
+    /// Instead of running the daemon, load the policy to discover
+    /// the spool definitions, then check the consistency of the
+    /// spool and report on any problematic entries, then stop.
+    /// Listeners are not started.
+    #[arg(long)]
+    fsck_spool: bool,
+
+    /// When used together with --fsck-spool, problematic spool
+    /// entries will be moved out of the spool and into this directory.
+    #[arg(long, requires("fsck_spool"))]
+    fsck_quarantine_dir: Option<PathBuf>,
+
     /// Instead of running the daemon, output the openapi spec json
     /// to stdout.
     #[arg(long)]
@@ -79,6 +125,24 @@ struct Opt {
     #[arg(long)]
     user: Option<String>,
 
+    /// Listen on this unix socket for a takeover request from a newly
+    /// started instance of kumod, so that kumod can be upgraded without
+    /// refusing connections. See --takeover.
+    #[arg(long, conflicts_with_all(["validate", "script", "fsck_spool"]))]
+    handoff_socket: Option<PathBuf>,
+
+    /// Take over the listening sockets of the instance of kumod that is
+    /// listening on --handoff-socket, causing it to stop accepting
+    /// connections and shut down, then open the spool once that
+    /// instance has released it.
+    #[arg(long, requires("handoff_socket"))]
+    takeover: bool,
+
+    /// When used together with --takeover, how long to wait for the
+    /// previous instance to finish shutting down and release the spool.
+    #[arg(long, requires("takeover"), default_value = "5m", value_parser=humantime::parse_duration)]
+    takeover_timeout: Duration,
+
     /// Deprecated: List of arguments to pass to the `main` event when
     /// running in --script mode. Can be used multiple times.
     ///
@@ -237,6 +301,12 @@ async fn perform_init(opts: Opt) -> anyhow::Result<()> {
         return Ok(());
     }
 
+    if let (Some(path), true) = (&opts.handoff_socket, opts.takeover) {
+        kumo_server_common::handoff::take_over(path, opts.takeover_timeout)
+            .await
+            .context("take over from the previous instance")?;
+    }
+
     config
         .async_call_callback(&PRE_INIT_SIG, ())
         .await
@@ -248,7 +318,28 @@ async fn perform_init(opts: Opt) -> anyhow::Result<()> {
         .await
         .context("call init callback")?;
 
-    if opts.validate {
+    if opts.fsck_spool {
+        let report = crate::spool::SpoolManager::get()
+            .fsck(opts.fsck_quarantine_dir.clone())
+            .await
+            .context("fsck spool")?;
+
+        for issue in &report.issues {
+            tracing::error!("{issue}");
+        }
+        tracing::info!(
+            "fsck: checked {} meta and {} data entries, found {} issues, quarantined {}",
+            report.num_meta,
+            report.num_data,
+            report.issues.len(),
+            report.num_quarantined
+        );
+
+        LifeCycle::request_shutdown().await;
+        if !report.is_clean() && opts.fsck_quarantine_dir.is_none() {
+            anyhow::bail!("Spool has {} issues", report.issues.len());
+        }
+    } else if opts.validate {
         config
             .async_call_callback(&VALIDATE_SIG, ())
             .await
@@ -258,6 +349,21 @@ async fn perform_init(opts: Opt) -> anyhow::Result<()> {
             anyhow::bail!("Validation failed");
         }
 
+        if let Some(path) = &opts.dump_config {
+            let reference = match &opts.dump_config_reference {
+                Some(reference) => {
+                    let data = std::fs::read(reference)
+                        .with_context(|| format!("reading {reference:?}"))?;
+                    serde_json::from_slice(&data)
+                        .with_context(|| format!("parsing {reference:?}"))?
+                }
+                None => Default::default(),
+            };
+            let snapshot = crate::config_snapshot::resolve_snapshot(&reference).await?;
+            std::fs::write(path, serde_json::to_string_pretty(&snapshot)?)
+                .with_context(|| format!("writing {path:?}"))?;
+        }
+
         LifeCycle::request_shutdown().await;
     } else {
         config::epoch::start_monitor();
@@ -266,6 +372,10 @@ async fn perform_init(opts: Opt) -> anyhow::Result<()> {
             .start_spool(start_time)
             .await
             .context("start_spool")?;
+        crate::egress_preflight::run_at_startup().context("egress preflight")?;
+        if let Some(path) = &opts.handoff_socket {
+            kumo_server_common::handoff::start_server(path).context("start handoff listener")?;
+        }
     }
 
     Ok(())
@@ -273,7 +383,11 @@ async fn perform_init(opts: Opt) -> anyhow::Result<()> {
 
 async fn run(opts: Opt) -> anyhow::Result<()> {
     kumo_server_runtime::assign_main_runtime(tokio::runtime::Handle::current());
-    config::VALIDATE_ONLY.store(opts.validate, std::sync::atomic::Ordering::Relaxed);
+    config::VALIDATE_ONLY.store(
+        opts.validate || opts.fsck_spool,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    crate::spool::set_fsck_mode(opts.fsck_spool);
 
     let res = StartConfig {
         logging: LoggingConfig {
@@ -282,6 +396,8 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
             filter_env_var: "KUMOD_LOG",
             default_filter: if opts.validate || opts.script {
                 ""
+            } else if opts.fsck_spool {
+                "kumod=info,spool=info"
             } else {
                 "kumod=info,config=info,kumo_server_common=info,kumo_server_runtime=info,lruttl=info,spool=info"
             },
@@ -291,6 +407,8 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
             crate::mod_kumo::register,
             crate::spool::register,
             crate::logging::register,
+            crate::feedback::register,
+            crate::dsn::register,
             message::dkim::register,
             crate::spf::register,
         ],
@@ -311,6 +429,10 @@ async fn run(opts: Opt) -> anyhow::Result<()> {
         tracing::error!("error flushing ACCT: {err:#}");
     }
 
+    if let Err(err) = crate::analytics::flush() {
+        tracing::error!("error flushing analytics: {err:#}");
+    }
+
     if let Err(err) = crate::spool::SpoolManager::shutdown().await {
         tracing::error!("error shutting down spool: {err:#}");
     }
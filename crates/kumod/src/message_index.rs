@@ -0,0 +1,587 @@
+//! The purpose of this module is to maintain an optional index of
+//! the messages that pass through this instance, so that the admin
+//! API can locate a message by its envelope or by selected header
+//! and meta values, and report on its current status and history.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use kumo_api_types::{
+    MessageSearchV1Entry, MessageSearchV1Event, MessageSearchV1Request, MessageSearchV1Status,
+};
+use kumo_log_types::RecordType;
+use message::Message;
+use prometheus::IntCounter;
+use rfc5321::Response;
+use serde::Deserialize;
+use sqlite::{Connection, ConnectionThreadSafe, State};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+
+static INDEX: OnceLock<MessageIndex> = OnceLock::new();
+
+static EVENTS_DROPPED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "message_index_events_dropped_count",
+        "total number of message index events that were dropped because the index writer was too far behind"
+    )
+    .unwrap()
+});
+
+/// The maximum number of events applied in a single transaction
+const MAX_BATCH: usize = 1024;
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MessageIndexParams {
+    /// The path to the sqlite database that holds the index
+    #[serde(default = "MessageIndexParams::default_path")]
+    pub path: PathBuf,
+
+    /// The names of the headers whose values should be indexed
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    /// The names of the meta values that should be indexed
+    #[serde(default)]
+    pub meta: Vec<String>,
+
+    /// Messages that were received longer ago than this are
+    /// removed from the index
+    #[serde(
+        default = "MessageIndexParams::default_retention",
+        with = "duration_serde"
+    )]
+    pub retention: Duration,
+
+    /// The maximum number of events that can be waiting to be
+    /// written to the index. Events beyond this are dropped.
+    #[serde(default = "MessageIndexParams::default_back_pressure")]
+    pub back_pressure: usize,
+}
+
+impl MessageIndexParams {
+    fn default_path() -> PathBuf {
+        "/var/spool/kumomta/message-index.db".into()
+    }
+
+    fn default_retention() -> Duration {
+        Duration::from_secs(7 * 86400)
+    }
+
+    fn default_back_pressure() -> usize {
+        128_000
+    }
+}
+
+struct IndexEvent {
+    kind: RecordType,
+    id: String,
+    timestamp: i64,
+    sender: String,
+    recipient: String,
+    created: i64,
+    queue: String,
+    site: String,
+    response: String,
+    num_attempts: u16,
+    attributes: Vec<(String, String)>,
+}
+
+struct MessageIndex {
+    params: MessageIndexParams,
+    sender: flume::Sender<IndexEvent>,
+    db: Arc<ConnectionThreadSafe>,
+}
+
+/// Opens the index and starts the thread that maintains it.
+/// This can only be called once.
+pub fn configure_message_index(params: MessageIndexParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    if INDEX.get().is_some() {
+        anyhow::bail!("configure_message_index has already been called");
+    }
+
+    let writer_db = open_index_db(&params)?;
+    let db = Arc::new(open_index_db(&params)?);
+    let (sender, receiver) = flume::bounded(params.back_pressure);
+
+    let retention = params.retention;
+    std::thread::Builder::new()
+        .name("message-index".to_string())
+        .spawn(move || writer(writer_db, receiver, retention))?;
+
+    INDEX
+        .set(MessageIndex { params, sender, db })
+        .map_err(|_| anyhow::anyhow!("configure_message_index has already been called"))
+}
+
+fn open_index_db(params: &MessageIndexParams) -> anyhow::Result<ConnectionThreadSafe> {
+    let path = &params.path;
+    let db = Connection::open_thread_safe(path)
+        .with_context(|| format!("opening message index database {path:?}"))?;
+
+    let query = r#"
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS messages (
+    id TEXT NOT NULL PRIMARY KEY,
+    sender TEXT NOT NULL COLLATE NOCASE,
+    recipient TEXT NOT NULL COLLATE NOCASE,
+    created INTEGER NOT NULL,
+    queue TEXT NOT NULL,
+    status TEXT NOT NULL,
+    num_attempts INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_sender ON messages (sender);
+CREATE INDEX IF NOT EXISTS messages_recipient ON messages (recipient);
+CREATE INDEX IF NOT EXISTS messages_created ON messages (created);
+CREATE TABLE IF NOT EXISTS attributes (
+    id TEXT NOT NULL,
+    name TEXT NOT NULL COLLATE NOCASE,
+    value TEXT NOT NULL,
+    PRIMARY KEY (id, name)
+);
+CREATE INDEX IF NOT EXISTS attributes_name_value ON attributes (name, value);
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    queue TEXT NOT NULL,
+    site TEXT NOT NULL,
+    response TEXT NOT NULL,
+    num_attempts INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_id ON events (id);
+    "#;
+
+    db.execute(query)
+        .with_context(|| format!("setting up message index database {path:?}"))?;
+
+    Ok(db)
+}
+
+/// Returns the status implied by an event, or None if the event
+/// should not be indexed
+fn status_for_kind(kind: RecordType) -> Option<MessageSearchV1Status> {
+    match kind {
+        RecordType::Reception
+        | RecordType::TransientFailure
+        | RecordType::AdminRebind
+        | RecordType::DeferredInjectionRebind => Some(MessageSearchV1Status::Queued),
+        RecordType::Delivery => Some(MessageSearchV1Status::Delivered),
+        RecordType::Bounce | RecordType::AdminBounce => Some(MessageSearchV1Status::Bounced),
+        RecordType::Expiration => Some(MessageSearchV1Status::Expired),
+        RecordType::OOB
+        | RecordType::Feedback
+        | RecordType::Rejection
+        | RecordType::TenantUsage
+        | RecordType::Unsubscribe
+        | RecordType::Any => None,
+    }
+}
+
+fn status_to_str(status: MessageSearchV1Status) -> &'static str {
+    match status {
+        MessageSearchV1Status::Queued => "Queued",
+        MessageSearchV1Status::Delivered => "Delivered",
+        MessageSearchV1Status::Bounced => "Bounced",
+        MessageSearchV1Status::Expired => "Expired",
+    }
+}
+
+fn status_from_str(status: &str) -> anyhow::Result<MessageSearchV1Status> {
+    Ok(match status {
+        "Queued" => MessageSearchV1Status::Queued,
+        "Delivered" => MessageSearchV1Status::Delivered,
+        "Bounced" => MessageSearchV1Status::Bounced,
+        "Expired" => MessageSearchV1Status::Expired,
+        _ => anyhow::bail!("invalid status {status} in message index"),
+    })
+}
+
+/// Called by the logging layer to record an event in the index,
+/// if the index has been configured
+pub async fn record(kind: RecordType, msg: &Message, site: &str, response: &Response) {
+    let Some(index) = INDEX.get() else {
+        return;
+    };
+    if status_for_kind(kind).is_none() {
+        return;
+    }
+
+    msg.load_meta_if_needed().await.ok();
+
+    let mut attributes = vec![];
+    if kind == RecordType::Reception {
+        for name in &index.params.headers {
+            if let Ok(Some(value)) = msg.get_first_named_header_value(name) {
+                attributes.push((name.to_string(), value));
+            }
+        }
+        for name in &index.params.meta {
+            match msg.get_meta(name.as_str()) {
+                Ok(serde_json::Value::Null) | Err(_) => {}
+                Ok(serde_json::Value::String(value)) => {
+                    attributes.push((name.to_string(), value));
+                }
+                Ok(value) => {
+                    attributes.push((name.to_string(), value.to_string()));
+                }
+            }
+        }
+    }
+
+    let event = IndexEvent {
+        kind,
+        id: msg.id().to_string(),
+        timestamp: Utc::now().timestamp(),
+        sender: msg
+            .sender()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        recipient: msg
+            .recipient()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        created: msg.id().created().timestamp(),
+        queue: msg.get_queue_name().unwrap_or_default(),
+        site: site.to_string(),
+        response: response.to_single_line(),
+        num_attempts: msg.get_num_attempts(),
+        attributes,
+    };
+
+    match index.sender.try_send(event) {
+        Ok(()) => {}
+        Err(flume::TrySendError::Full(_)) => {
+            // Don't hold up reception or delivery waiting for the index
+            EVENTS_DROPPED.inc();
+        }
+        Err(flume::TrySendError::Disconnected(_)) => {
+            tracing::error!("message index writer is not running");
+        }
+    }
+}
+
+fn writer(db: ConnectionThreadSafe, receiver: flume::Receiver<IndexEvent>, retention: Duration) {
+    let prune_interval = Duration::from_secs(3600);
+    let mut last_prune = Instant::now();
+
+    while let Ok(event) = receiver.recv() {
+        let mut batch = vec![event];
+        while batch.len() < MAX_BATCH {
+            match receiver.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(err) = apply_batch(&db, &batch) {
+            tracing::error!(
+                "failed to record {} events in message index: {err:#}",
+                batch.len()
+            );
+        }
+
+        if last_prune.elapsed() >= prune_interval {
+            last_prune = Instant::now();
+            if let Err(err) = prune(&db, retention) {
+                tracing::error!("failed to prune message index: {err:#}");
+            }
+        }
+    }
+}
+
+fn apply_batch(db: &ConnectionThreadSafe, batch: &[IndexEvent]) -> anyhow::Result<()> {
+    db.execute("BEGIN")?;
+    let result = batch.iter().try_for_each(|event| apply_event(db, event));
+    match result {
+        Ok(()) => {
+            db.execute("COMMIT")?;
+            Ok(())
+        }
+        Err(err) => {
+            db.execute("ROLLBACK").ok();
+            Err(err)
+        }
+    }
+}
+
+fn apply_event(db: &ConnectionThreadSafe, event: &IndexEvent) -> anyhow::Result<()> {
+    let status = match status_for_kind(event.kind) {
+        Some(status) => status_to_str(status),
+        None => return Ok(()),
+    };
+
+    let mut stmt = if event.kind == RecordType::Reception {
+        db.prepare(
+            "INSERT INTO messages
+                (id, sender, recipient, created, queue, status, num_attempts)
+                values ($id, $sender, $recipient, $created, $queue, $status, $num_attempts)
+                on conflict (id)
+                do update set queue=$queue, status=$status, num_attempts=$num_attempts",
+        )?
+    } else {
+        db.prepare(
+            "UPDATE messages SET queue=$queue, status=$status, num_attempts=$num_attempts
+                WHERE id=$id",
+        )?
+    };
+    if event.kind == RecordType::Reception {
+        stmt.bind(("$sender", event.sender.as_str()))?;
+        stmt.bind(("$recipient", event.recipient.as_str()))?;
+        stmt.bind(("$created", event.created))?;
+    }
+    stmt.bind(("$id", event.id.as_str()))?;
+    stmt.bind(("$queue", event.queue.as_str()))?;
+    stmt.bind(("$status", status))?;
+    stmt.bind(("$num_attempts", event.num_attempts as i64))?;
+    stmt.next()?;
+
+    for (name, value) in &event.attributes {
+        let mut stmt = db.prepare(
+            "INSERT OR REPLACE INTO attributes (id, name, value) values ($id, $name, $value)",
+        )?;
+        stmt.bind(("$id", event.id.as_str()))?;
+        stmt.bind(("$name", name.as_str()))?;
+        stmt.bind(("$value", value.as_str()))?;
+        stmt.next()?;
+    }
+
+    let mut stmt = db.prepare(
+        "INSERT INTO events
+            (id, timestamp, kind, queue, site, response, num_attempts)
+            values ($id, $timestamp, $kind, $queue, $site, $response, $num_attempts)",
+    )?;
+    stmt.bind(("$id", event.id.as_str()))?;
+    stmt.bind(("$timestamp", event.timestamp))?;
+    stmt.bind(("$kind", format!("{:?}", event.kind).as_str()))?;
+    stmt.bind(("$queue", event.queue.as_str()))?;
+    stmt.bind(("$site", event.site.as_str()))?;
+    stmt.bind(("$response", event.response.as_str()))?;
+    stmt.bind(("$num_attempts", event.num_attempts as i64))?;
+    stmt.next()?;
+
+    Ok(())
+}
+
+/// Removes messages that were received longer ago than the
+/// retention period, along with their attributes and events
+fn prune(db: &ConnectionThreadSafe, retention: Duration) -> anyhow::Result<()> {
+    let cutoff = Utc::now().timestamp() - retention.as_secs() as i64;
+    for query in [
+        "DELETE FROM attributes WHERE id IN (SELECT id FROM messages WHERE created < $cutoff)",
+        "DELETE FROM events WHERE id IN (SELECT id FROM messages WHERE created < $cutoff)",
+        "DELETE FROM messages WHERE created < $cutoff",
+    ] {
+        let mut stmt = db.prepare(query)?;
+        stmt.bind(("$cutoff", cutoff))?;
+        stmt.next()?;
+    }
+    Ok(())
+}
+
+fn timestamp_to_datetime(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default()
+}
+
+fn search_db(
+    db: &ConnectionThreadSafe,
+    request: &MessageSearchV1Request,
+) -> anyhow::Result<Vec<MessageSearchV1Entry>> {
+    let attribute = request.attribute_name_value()?;
+
+    let mut query = "SELECT id, sender, recipient, created, queue, status, num_attempts
+        FROM messages WHERE 1=1"
+        .to_string();
+    if request.id.is_some() {
+        query.push_str(" AND id=$id");
+    }
+    if request.sender.is_some() {
+        query.push_str(" AND sender=$sender");
+    }
+    if request.recipient.is_some() {
+        query.push_str(" AND recipient=$recipient");
+    }
+    if request.since.is_some() {
+        query.push_str(" AND created >= $since");
+    }
+    if request.until.is_some() {
+        query.push_str(" AND created < $until");
+    }
+    if attribute.is_some() {
+        query.push_str(" AND id IN (SELECT id FROM attributes WHERE name=$name AND value=$value)");
+    }
+    query.push_str(" ORDER BY created DESC LIMIT $limit");
+
+    let mut stmt = db.prepare(&query)?;
+    if let Some(id) = &request.id {
+        stmt.bind(("$id", id.as_str()))?;
+    }
+    if let Some(sender) = &request.sender {
+        stmt.bind(("$sender", sender.as_str()))?;
+    }
+    if let Some(recipient) = &request.recipient {
+        stmt.bind(("$recipient", recipient.as_str()))?;
+    }
+    if let Some(since) = &request.since {
+        stmt.bind(("$since", since.timestamp()))?;
+    }
+    if let Some(until) = &request.until {
+        stmt.bind(("$until", until.timestamp()))?;
+    }
+    if let Some((name, value)) = attribute {
+        stmt.bind(("$name", name))?;
+        stmt.bind(("$value", value))?;
+    }
+    let limit = request.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    stmt.bind(("$limit", limit as i64))?;
+
+    let mut entries = vec![];
+    while stmt.next()? == State::Row {
+        entries.push(MessageSearchV1Entry {
+            id: stmt.read::<String, _>("id")?,
+            sender: stmt.read::<String, _>("sender")?,
+            recipient: stmt.read::<String, _>("recipient")?,
+            created: timestamp_to_datetime(stmt.read::<i64, _>("created")?),
+            queue: stmt.read::<String, _>("queue")?,
+            status: status_from_str(&stmt.read::<String, _>("status")?)?,
+            num_attempts: stmt.read::<i64, _>("num_attempts")? as u16,
+            attributes: BTreeMap::new(),
+            events: vec![],
+        });
+    }
+
+    for entry in &mut entries {
+        let mut stmt = db.prepare("SELECT name, value FROM attributes WHERE id=$id")?;
+        stmt.bind(("$id", entry.id.as_str()))?;
+        while stmt.next()? == State::Row {
+            entry.attributes.insert(
+                stmt.read::<String, _>("name")?,
+                stmt.read::<String, _>("value")?,
+            );
+        }
+
+        let mut stmt = db.prepare(
+            "SELECT timestamp, kind, queue, site, response, num_attempts
+                FROM events WHERE id=$id ORDER BY timestamp, rowid",
+        )?;
+        stmt.bind(("$id", entry.id.as_str()))?;
+        while stmt.next()? == State::Row {
+            entry.events.push(MessageSearchV1Event {
+                timestamp: timestamp_to_datetime(stmt.read::<i64, _>("timestamp")?),
+                kind: stmt.read::<String, _>("kind")?,
+                queue: stmt.read::<String, _>("queue")?,
+                site: stmt.read::<String, _>("site")?,
+                response: stmt.read::<String, _>("response")?,
+                num_attempts: stmt.read::<i64, _>("num_attempts")? as u16,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Returns the indexed messages that match the request,
+/// most recently received first
+pub async fn search(request: MessageSearchV1Request) -> anyhow::Result<Vec<MessageSearchV1Entry>> {
+    let index = INDEX
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("the message index has not been configured"))?;
+    let db = index.db.clone();
+    tokio::task::spawn_blocking(move || search_db(&db, &request)).await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_event(kind: RecordType, id: &str, timestamp: i64, response: &str) -> IndexEvent {
+        IndexEvent {
+            kind,
+            id: id.to_string(),
+            timestamp,
+            sender: "sender@example.com".to_string(),
+            recipient: format!("{id}@example.com"),
+            created: 1000,
+            queue: "example.com".to_string(),
+            site: "mx.example.com".to_string(),
+            response: response.to_string(),
+            num_attempts: if kind == RecordType::Reception { 0 } else { 1 },
+            attributes: if kind == RecordType::Reception {
+                vec![("X-Customer-ID".to_string(), format!("{id}-customer"))]
+            } else {
+                vec![]
+            },
+        }
+    }
+
+    #[test]
+    fn index_and_search() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let params = MessageIndexParams {
+            path: dir.path().join("index.db"),
+            headers: vec!["X-Customer-ID".to_string()],
+            meta: vec![],
+            retention: MessageIndexParams::default_retention(),
+            back_pressure: MessageIndexParams::default_back_pressure(),
+        };
+        let db = open_index_db(&params)?;
+
+        apply_batch(
+            &db,
+            &[
+                make_event(RecordType::Reception, "bob", 1000, "250 ok"),
+                make_event(RecordType::Reception, "alice", 1000, "250 ok"),
+                make_event(RecordType::TransientFailure, "bob", 1010, "451 try later"),
+                make_event(RecordType::Delivery, "bob", 1020, "250 delivered"),
+            ],
+        )?;
+
+        let results = search_db(
+            &db,
+            &MessageSearchV1Request {
+                attribute: Some("x-customer-id=bob-customer".to_string()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(results.len(), 1);
+        let bob = &results[0];
+        assert_eq!(bob.recipient, "bob@example.com");
+        assert_eq!(bob.status, MessageSearchV1Status::Delivered);
+        assert_eq!(bob.attributes["X-Customer-ID"], "bob-customer");
+        assert_eq!(
+            bob.events
+                .iter()
+                .map(|e| e.kind.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Reception", "TransientFailure", "Delivery"]
+        );
+
+        let results = search_db(
+            &db,
+            &MessageSearchV1Request {
+                recipient: Some("ALICE@example.com".to_string()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].status, MessageSearchV1Status::Queued);
+
+        let results = search_db(
+            &db,
+            &MessageSearchV1Request {
+                since: Some(timestamp_to_datetime(2000)),
+                ..Default::default()
+            },
+        )?;
+        assert!(results.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! The purpose of this module is to maintain an optional in-memory
+//! index of the values of selected meta keys of the messages that are
+//! currently in the spool, so that admin bounce and rebind requests
+//! can select messages by their metadata without having to load the
+//! metadata of every queued message.
+//!
+//! Messages are added to the index as they are inserted into a
+//! scheduled queue, either upon reception or when they are loaded
+//! from the spool, and are removed from it when they are removed
+//! from the spool.
+
+use message::Message;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use spool::SpoolId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::OnceLock;
+
+static INDEX: OnceLock<Mutex<MetaIndex>> = OnceLock::new();
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MetaIndexParams {
+    /// The names of the meta values that should be indexed
+    pub keys: Vec<String>,
+}
+
+#[derive(Default)]
+struct IndexEntry {
+    /// The indexed key/value pairs of the message
+    values: Vec<(String, String)>,
+    /// The scheduled queue that most recently held the message
+    queue: Option<String>,
+}
+
+#[derive(Default)]
+struct MetaIndex {
+    keys: Vec<String>,
+    /// key -> value -> ids of the messages with that value
+    by_value: HashMap<String, HashMap<String, HashSet<SpoolId>>>,
+    by_id: HashMap<SpoolId, IndexEntry>,
+}
+
+impl MetaIndex {
+    fn record(&mut self, msg: &Message) {
+        let id = *msg.id();
+        if self.by_id.contains_key(&id) || !msg.is_meta_loaded() {
+            return;
+        }
+
+        let mut entry = IndexEntry::default();
+        for key in &self.keys {
+            let value = match msg.get_meta(key.as_str()) {
+                Ok(serde_json::Value::String(s)) => s,
+                Ok(serde_json::Value::Number(n)) => n.to_string(),
+                Ok(serde_json::Value::Bool(b)) => b.to_string(),
+                _ => continue,
+            };
+            self.by_value
+                .entry(key.clone())
+                .or_default()
+                .entry(value.clone())
+                .or_default()
+                .insert(id);
+            entry.values.push((key.clone(), value));
+        }
+        // Messages without any indexed values can never match,
+        // so there is no need to track them
+        if !entry.values.is_empty() {
+            self.by_id.insert(id, entry);
+        }
+    }
+
+    fn forget(&mut self, id: &SpoolId) {
+        let Some(entry) = self.by_id.remove(id) else {
+            return;
+        };
+        for (key, value) in entry.values {
+            if let Some(values) = self.by_value.get_mut(&key) {
+                if let Some(ids) = values.get_mut(&value) {
+                    ids.remove(id);
+                    if ids.is_empty() {
+                        values.remove(&value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn matches(&self, id: &SpoolId, criteria: &BTreeMap<String, String>) -> bool {
+        match self.by_id.get(id) {
+            Some(entry) => criteria
+                .iter()
+                .all(|(key, value)| entry.values.iter().any(|(k, v)| k == key && v == value)),
+            None => false,
+        }
+    }
+
+    fn queues_matching(&self, criteria: &BTreeMap<String, String>) -> HashSet<String> {
+        // Start from the smallest set of candidates; every candidate
+        // must be in the set for each of the criteria
+        let smallest = criteria
+            .iter()
+            .map(|(key, value)| self.by_value.get(key).and_then(|values| values.get(value)))
+            .min_by_key(|ids| ids.map(|ids| ids.len()).unwrap_or(0));
+
+        let Some(Some(ids)) = smallest else {
+            return HashSet::new();
+        };
+
+        ids.iter()
+            .filter(|id| self.matches(id, criteria))
+            .filter_map(|id| self.by_id.get(id).and_then(|entry| entry.queue.clone()))
+            .collect()
+    }
+}
+
+/// Enables the index for the specified meta keys.
+/// This can only be called once.
+pub fn configure_meta_index(params: MetaIndexParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    INDEX
+        .set(Mutex::new(MetaIndex {
+            keys: params.keys,
+            ..Default::default()
+        }))
+        .map_err(|_| anyhow::anyhow!("configure_meta_index has already been called"))
+}
+
+/// Adds msg to the index, if it is not already present.
+/// The metadata of msg must be loaded for it to be indexed.
+pub fn record(msg: &Message) {
+    if let Some(index) = INDEX.get() {
+        index.lock().record(msg);
+    }
+}
+
+/// Notes that the message is held by the named scheduled queue
+pub fn set_queue(id: &SpoolId, queue_name: &str) {
+    if let Some(index) = INDEX.get() {
+        if let Some(entry) = index.lock().by_id.get_mut(id) {
+            if entry.queue.as_deref() != Some(queue_name) {
+                entry.queue.replace(queue_name.to_string());
+            }
+        }
+    }
+}
+
+/// Removes the message from the index
+pub fn forget(id: &SpoolId) {
+    if let Some(index) = INDEX.get() {
+        index.lock().forget(id);
+    }
+}
+
+/// Verifies that criteria only references indexed keys
+pub fn check_criteria(criteria: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    if criteria.is_empty() {
+        return Ok(());
+    }
+    let Some(index) = INDEX.get() else {
+        anyhow::bail!("matching by meta requires kumo.configure_meta_index");
+    };
+    let index = index.lock();
+    for key in criteria.keys() {
+        if !index.keys.contains(key) {
+            anyhow::bail!("meta key `{key}` is not indexed by kumo.configure_meta_index");
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the indexed values of the message with the
+/// specified id satisfy all of the criteria
+pub fn matches(id: &SpoolId, criteria: &BTreeMap<String, String>) -> bool {
+    match INDEX.get() {
+        Some(index) => index.lock().matches(id, criteria),
+        None => false,
+    }
+}
+
+/// Returns the names of the scheduled queues that hold messages
+/// that satisfy all of the criteria
+pub fn queues_matching(criteria: &BTreeMap<String, String>) -> HashSet<String> {
+    match INDEX.get() {
+        Some(index) => index.lock().queues_matching(criteria),
+        None => HashSet::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use message::EnvelopeAddress;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn make_message(customer: &str) -> Message {
+        Message::new_dirty(
+            SpoolId::new(),
+            EnvelopeAddress::parse("sender@example.com").unwrap(),
+            EnvelopeAddress::parse("recip@example.com").unwrap(),
+            json!({"x-customer-id": customer, "other": "value"}),
+            Arc::new(
+                "Subject: hello\r\n\r\nHello\r\n"
+                    .as_bytes()
+                    .to_vec()
+                    .into_boxed_slice(),
+            ),
+        )
+        .unwrap()
+    }
+
+    fn criteria(customer: &str) -> BTreeMap<String, String> {
+        [("x-customer-id".to_string(), customer.to_string())]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn index() {
+        let mut index = MetaIndex {
+            keys: vec!["x-customer-id".to_string()],
+            ..Default::default()
+        };
+
+        let a = make_message("a");
+        let b = make_message("b");
+        let b2 = make_message("b");
+        index.record(&a);
+        index.record(&b);
+        index.record(&b2);
+
+        let mut queue = |msg: &Message, name: &str| {
+            index
+                .by_id
+                .get_mut(msg.id())
+                .unwrap()
+                .queue
+                .replace(name.to_string());
+        };
+        queue(&a, "one.com");
+        queue(&b, "one.com");
+        queue(&b2, "two.com");
+
+        assert!(index.matches(a.id(), &criteria("a")));
+        assert!(!index.matches(a.id(), &criteria("b")));
+        assert!(index.matches(b.id(), &criteria("b")));
+
+        let mut queues: Vec<String> = index.queues_matching(&criteria("b")).into_iter().collect();
+        queues.sort();
+        assert_eq!(queues, vec!["one.com".to_string(), "two.com".to_string()]);
+        assert!(index.queues_matching(&criteria("c")).is_empty());
+
+        // A key that is not indexed never matches
+        let mut other = criteria("a");
+        other.insert("other".to_string(), "value".to_string());
+        assert!(!index.matches(a.id(), &other));
+
+        index.forget(b.id());
+        assert!(!index.matches(b.id(), &criteria("b")));
+        let queues: Vec<String> = index.queues_matching(&criteria("b")).into_iter().collect();
+        assert_eq!(queues, vec!["two.com".to_string()]);
+
+        index.forget(b2.id());
+        assert!(index.by_value["x-customer-id"].get("b").is_none());
+    }
+}
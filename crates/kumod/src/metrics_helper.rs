@@ -1,3 +1,4 @@
+use kumo_log_types::RecordType;
 use kumo_prometheus::{label_key, AtomicCounter, CounterRegistry, PruningCounterRegistry};
 use prometheus::{Histogram, HistogramVec, IntCounter};
 use std::sync::LazyLock;
@@ -25,6 +26,12 @@ label_key! {
         pub pool: String,
     }
 }
+label_key! {
+    pub struct ClassificationAndProviderKey {
+        pub classification: String,
+        pub provider: String,
+    }
+}
 
 pub static CONN_GAUGE: LazyLock<PruningCounterRegistry<ServiceKey>> = LazyLock::new(|| {
     PruningCounterRegistry::register_gauge("connection_count", "number of active connections")
@@ -151,6 +158,14 @@ pub static TOTAL_MSGS_RECVD: LazyLock<CounterRegistry<ServiceKey>> = LazyLock::n
         "total number of messages ever received",
     )
 });
+pub static TOTAL_BOUNCE_CLASSIFICATION_BY_PROVIDER: LazyLock<
+    CounterRegistry<ClassificationAndProviderKey>,
+> = LazyLock::new(|| {
+    CounterRegistry::register(
+        "total_bounce_classification_by_provider",
+        "total number of responses that were assigned each bounce classification",
+    )
+});
 pub static READY_FULL_COUNTER: LazyLock<PruningCounterRegistry<ServiceKey>> = LazyLock::new(|| {
     PruningCounterRegistry::register(
         "ready_full",
@@ -165,6 +180,27 @@ pub static DELIVER_MESSAGE_LATENCY_ROLLUP: LazyLock<HistogramVec> = LazyLock::ne
     )
     .unwrap()
 });
+pub static FINAL_DISPOSITION_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "final_disposition_latency",
+        "time in seconds from reception to the final disposition of a message",
+        &["disposition", "provider", "pool"],
+        vec![
+            1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 43200.0,
+            86400.0, 172800.0, 345600.0
+        ]
+    )
+    .unwrap()
+});
+pub static FINAL_DISPOSITION_ATTEMPTS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "final_disposition_attempts",
+        "number of delivery attempts made before the final disposition of a message",
+        &["disposition", "provider", "pool"],
+        vec![1.0, 2.0, 3.0, 4.0, 5.0, 10.0, 20.0, 50.0]
+    )
+    .unwrap()
+});
 pub static TOTAL_READYQ_RUNS: LazyLock<IntCounter> = LazyLock::new(|| {
     prometheus::register_int_counter!(
         "total_readyq_runs",
@@ -179,6 +215,25 @@ pub fn deliver_message_rollup_for_service(service: &str) -> Histogram {
         .unwrap()
 }
 
+/// Records the latency and number of attempts for a message that
+/// reached its final disposition (Delivery, Bounce or Expiration)
+pub fn observe_final_disposition(
+    kind: RecordType,
+    provider: &str,
+    pool: &str,
+    latency: f64,
+    num_attempts: u16,
+) {
+    let disposition = format!("{kind:?}");
+    let labels = [disposition.as_str(), provider, pool];
+    if let Ok(hist) = FINAL_DISPOSITION_LATENCY.get_metric_with_label_values(&labels) {
+        hist.observe(latency);
+    }
+    if let Ok(hist) = FINAL_DISPOSITION_ATTEMPTS.get_metric_with_label_values(&labels) {
+        hist.observe(num_attempts as f64);
+    }
+}
+
 pub fn connection_denied_for_service(service: &str) -> AtomicCounter {
     let service = BorrowedServiceKey { service };
     CONN_DENIED.get_or_create(&service as &dyn ServiceKeyTrait)
@@ -223,3 +278,12 @@ pub fn total_msgs_fail_for_service(service: &str) -> AtomicCounter {
     let service = BorrowedServiceKey { service };
     TOTAL_MSGS_FAIL.get_or_create(&service as &dyn ServiceKeyTrait)
 }
+
+pub fn bounce_classification_for_provider(classification: &str, provider: &str) -> AtomicCounter {
+    let key = BorrowedClassificationAndProviderKey {
+        classification,
+        provider,
+    };
+    TOTAL_BOUNCE_CLASSIFICATION_BY_PROVIDER
+        .get_or_create(&key as &dyn ClassificationAndProviderKeyTrait)
+}
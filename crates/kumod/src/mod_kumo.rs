@@ -22,14 +22,19 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     crate::PRE_INIT_SIG.register();
     crate::VALIDATE_SIG.register();
     crate::queue::REQUEUE_MESSAGE_SIG.register();
+    crate::spool::SPOOL_QUOTA_STATE_CHANGED_SIG.register();
+    crate::logging::retention::LOG_SEGMENT_ROTATED_SIG.register();
+    crate::warmup::WARMUP_COMPLETE_SIG.register();
     crate::http_server::admin_suspend_ready_q_v1::register(lua)?;
     crate::http_server::admin_suspend_v1::register(lua)?;
     crate::http_server::admin_bounce_v1::register(lua)?;
+    crate::http_server::admin_suppression_v1::register(lua)?;
     crate::http_server::inject_v1::register(lua)?;
 
     kumo_mod.set(
         "start_http_listener",
         lua.create_async_function(|lua, params: Value| async move {
+            crate::config_snapshot::record_listener("http", &lua, &params);
             let params: HttpListenerParams = from_lua_value(&lua, params)?;
             if !config::is_validating() {
                 params
@@ -44,6 +49,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     kumo_mod.set(
         "start_esmtp_listener",
         lua.create_async_function(|lua, params: Value| async move {
+            crate::config_snapshot::record_listener("esmtp", &lua, &params);
             let params: EsmtpListenerParams = from_lua_value(&lua, params)?;
             if !config::is_validating() {
                 params.run().await.map_err(any_err)?;
@@ -96,6 +102,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_wire_capture_directory",
+        lua.create_function(move |_, path: String| {
+            crate::wire_capture::set_capture_directory(path.into());
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_spoolin_threads",
         lua.create_function(move |_, limit: usize| {
@@ -104,6 +118,18 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "set_spoolin_rate_limit",
+        lua.create_function(move |_, spec: Option<String>| {
+            let spec = match spec {
+                Some(s) => Some(ThrottleSpec::try_from(s).map_err(any_err)?),
+                None => None,
+            };
+            crate::spool::set_spoolin_rate_limit(spec);
+            Ok(())
+        })?,
+    )?;
+
     kumo_mod.set(
         "set_logging_threads",
         lua.create_function(move |_, limit: usize| {
@@ -196,6 +222,131 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    kumo_mod.set(
+        "configure_cluster_peers",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::http_server::admin_cluster_status_v1::ClusterPeersParams =
+                from_lua_value(lua, params)?;
+            crate::http_server::admin_cluster_status_v1::configure_cluster_peers(params);
+            Ok(())
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_analytics",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::analytics::AnalyticsParams = from_lua_value(lua, params)?;
+            crate::analytics::configure_analytics(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_egress_preflight",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::egress_preflight::EgressPreflightParams =
+                from_lua_value(lua, params)?;
+            crate::egress_preflight::configure_egress_preflight(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_tenant_usage",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::tenant_usage::TenantUsageParams = from_lua_value(lua, params)?;
+            crate::tenant_usage::configure_tenant_usage(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_greylisting",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::greylist::GreylistParams = from_lua_value(lua, params)?;
+            crate::greylist::configure_greylisting(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_recipient_verification",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::recipient_verify::RecipientVerifyParams =
+                from_lua_value(lua, params)?;
+            crate::recipient_verify::configure_recipient_verification(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "verify_recipient",
+        lua.create_async_function(|lua, recipient: String| async move {
+            let recipient = EnvelopeAddress::parse(&recipient).map_err(any_err)?;
+            let result = crate::recipient_verify::verify_recipient(&recipient)
+                .await
+                .map_err(any_err)?;
+            lua.to_value(&result)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_batv",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: crate::batv::BatvParams = from_lua_value(&lua, params)?;
+            crate::batv::configure_batv(params).await.map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_list_unsubscribe",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: crate::list_unsubscribe::ListUnsubscribeParams =
+                from_lua_value(&lua, params)?;
+            crate::list_unsubscribe::configure_list_unsubscribe(params)
+                .await
+                .map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_header_rewrite",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::header_rewrite::HeaderRewriteParams = from_lua_value(lua, params)?;
+            crate::header_rewrite::configure_header_rewrite(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "add_list_unsubscribe_headers",
+        lua.create_function(|lua, (msg, overrides): (Message, Option<Value>)| {
+            let overrides: crate::list_unsubscribe::ListUnsubscribeOverrides = match overrides {
+                Some(overrides) => from_lua_value(lua, overrides)?,
+                None => Default::default(),
+            };
+            crate::list_unsubscribe::add_headers(&msg, overrides).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_message_index",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::message_index::MessageIndexParams = from_lua_value(lua, params)?;
+            crate::message_index::configure_message_index(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_meta_index",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::meta_index::MetaIndexParams = from_lua_value(lua, params)?;
+            crate::meta_index::configure_meta_index(params).map_err(any_err)
+        })?,
+    )?;
+
+    kumo_mod.set(
+        "configure_suppression",
+        lua.create_function(|lua, params: Value| {
+            let params: crate::suppression::SuppressionParams = from_lua_value(lua, params)?;
+            crate::suppression::configure_suppression(params).map_err(any_err)
+        })?,
+    )?;
+
     kumo_mod.set(
         "make_throttle",
         lua.create_function(move |_lua, (name, spec): (String, String)| {
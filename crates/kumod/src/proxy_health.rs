@@ -0,0 +1,414 @@
+//! Tracks the health of the proxy servers used by egress sources,
+//! so that connections can be routed around proxies that are down.
+//! A proxy is taken out of rotation for a period of time when a
+//! connection to it fails, and may be restored early by an active
+//! health check probe.
+use parking_lot::Mutex;
+use prometheus::{IntCounterVec, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Once};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+static PROXIES: LazyLock<Mutex<HashMap<(ProxyKind, SocketAddr), Arc<ProxyState>>>> =
+    LazyLock::new(Mutex::default);
+static START_PROBER: Once = Once::new();
+
+static CONNECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "proxy_server_connections",
+        "total number of successful connections made to a proxy server",
+        &["proxy"]
+    )
+    .unwrap()
+});
+static FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "proxy_server_connection_failures",
+        "total number of failed connection attempts and health check \
+         probes for a proxy server",
+        &["proxy"]
+    )
+    .unwrap()
+});
+static HEALTHY: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "proxy_server_healthy",
+        "1 if a proxy server is currently in rotation, 0 if it has \
+         been removed because of connection failures",
+        &["proxy"]
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyKind {
+    HaProxy,
+    Socks5,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyServerEntry {
+    /// The host:port of the proxy server
+    pub server: SocketAddr,
+
+    /// The relative likelihood of this server being selected,
+    /// in the same way as the weight of an egress pool entry.
+    /// A weight of 0 prevents this server from being used.
+    #[serde(default = "ProxyServerEntry::default_weight")]
+    pub weight: u32,
+}
+
+impl ProxyServerEntry {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyHealthParams {
+    /// How long to keep a proxy out of rotation after a
+    /// connection to it has failed
+    #[serde(
+        default = "ProxyHealthParams::default_failure_quarantine",
+        with = "duration_serde"
+    )]
+    pub failure_quarantine: Duration,
+
+    /// If set, how often to actively probe each proxy server,
+    /// allowing proxies that have recovered to be restored to
+    /// rotation before failure_quarantine has elapsed
+    #[serde(default, with = "duration_serde")]
+    pub probe_interval: Option<Duration>,
+
+    /// The time limit for each probe
+    #[serde(
+        default = "ProxyHealthParams::default_probe_timeout",
+        with = "duration_serde"
+    )]
+    pub probe_timeout: Duration,
+}
+
+impl Default for ProxyHealthParams {
+    fn default() -> Self {
+        Self {
+            failure_quarantine: Self::default_failure_quarantine(),
+            probe_interval: None,
+            probe_timeout: Self::default_probe_timeout(),
+        }
+    }
+}
+
+impl ProxyHealthParams {
+    fn default_failure_quarantine() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn default_probe_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+#[derive(Debug)]
+struct ProxyState {
+    kind: ProxyKind,
+    server: SocketAddr,
+    label: String,
+    inner: Mutex<ProxyStateInner>,
+}
+
+#[derive(Debug)]
+struct ProxyStateInner {
+    unhealthy_until: Option<Instant>,
+    params: ProxyHealthParams,
+    next_probe: Option<Instant>,
+}
+
+impl ProxyState {
+    fn is_healthy(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.unhealthy_until {
+            Some(until) if until > now => false,
+            Some(_) => {
+                inner.unhealthy_until.take();
+                HEALTHY.with_label_values(&[&self.label]).set(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_failure(&self) {
+        FAILURES.with_label_values(&[&self.label]).inc();
+        HEALTHY.with_label_values(&[&self.label]).set(0);
+        let mut inner = self.inner.lock();
+        inner.unhealthy_until = Some(Instant::now() + inner.params.failure_quarantine);
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock();
+        if inner.unhealthy_until.take().is_some() {
+            HEALTHY.with_label_values(&[&self.label]).set(1);
+        }
+    }
+
+    /// Sends a minimal request to the proxy, to verify that it
+    /// is accepting connections and responding to its protocol
+    async fn probe(&self) -> anyhow::Result<()> {
+        let mut stream = TcpStream::connect(self.server).await?;
+        match self.kind {
+            // There is no way to probe the haproxy protocol without
+            // making a connection on to some destination, so just
+            // verify that it is accepting connections
+            ProxyKind::HaProxy => {}
+            ProxyKind::Socks5 => {
+                // Offer both NOAUTH and username/password
+                // and expect the server to pick one of them
+                stream.write_all(&[5, 2, 0, 2]).await?;
+                let mut response = [0u8; 2];
+                stream.read_exact(&mut response).await?;
+                anyhow::ensure!(
+                    response[0] == 5 && (response[1] == 0 || response[1] == 2),
+                    "unexpected SOCKS5 method selection response {response:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_state(kind: ProxyKind, server: SocketAddr, params: &ProxyHealthParams) -> Arc<ProxyState> {
+    let mut proxies = PROXIES.lock();
+    let state = proxies
+        .entry((kind, server))
+        .or_insert_with(|| {
+            let label = server.to_string();
+            HEALTHY.with_label_values(&[&label]).set(1);
+            Arc::new(ProxyState {
+                kind,
+                server,
+                label,
+                inner: Mutex::new(ProxyStateInner {
+                    unhealthy_until: None,
+                    params: params.clone(),
+                    next_probe: None,
+                }),
+            })
+        })
+        .clone();
+    drop(proxies);
+
+    // The most recently resolved configuration wins
+    let mut inner = state.inner.lock();
+    if inner.params != *params {
+        inner.params = params.clone();
+        inner.next_probe = None;
+    }
+    let probing = inner.params.probe_interval.is_some();
+    drop(inner);
+
+    if probing {
+        START_PROBER.call_once(|| {
+            kumo_server_runtime::get_main_runtime().spawn(run_prober());
+        });
+    }
+
+    state
+}
+
+/// Periodically probes the proxies that have a probe_interval
+async fn run_prober() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let now = Instant::now();
+        let due: Vec<(Arc<ProxyState>, Duration)> = PROXIES
+            .lock()
+            .values()
+            .filter_map(|state| {
+                let mut inner = state.inner.lock();
+                let interval = inner.params.probe_interval?;
+                if inner.next_probe.map(|next| next > now).unwrap_or(false) {
+                    return None;
+                }
+                inner.next_probe = Some(now + interval);
+                Some((state.clone(), inner.params.probe_timeout))
+            })
+            .collect();
+
+        for (state, timeout) in due {
+            tokio::spawn(async move {
+                match tokio::time::timeout(timeout, state.probe()).await {
+                    Ok(Ok(())) => state.record_success(),
+                    Ok(Err(err)) => {
+                        tracing::debug!(
+                            "health check probe of proxy {} failed: {err:#}",
+                            state.label
+                        );
+                        state.record_failure();
+                    }
+                    Err(_) => {
+                        tracing::debug!(
+                            "health check probe of proxy {} timed out after {timeout:?}",
+                            state.label
+                        );
+                        state.record_failure();
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// A proxy server that has been selected for a connection attempt
+#[derive(Debug)]
+pub struct SelectedProxy {
+    pub server: SocketAddr,
+    state: Arc<ProxyState>,
+    /// Whether this attempt to connect to the proxy failed
+    failed: AtomicBool,
+}
+
+impl SelectedProxy {
+    pub fn record_success(&self) {
+        CONNECTIONS.with_label_values(&[&self.state.label]).inc();
+        self.state.record_success();
+    }
+
+    pub fn record_failure(&self) {
+        self.failed.store(true, Ordering::Relaxed);
+        self.state.record_failure();
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the order in which to try the configured servers.
+/// The healthy servers are shuffled according to their weights, so
+/// that the first is selected in proportion to its weight.
+/// If none of the servers are healthy, all of them are returned
+/// instead, as it is better to try than to fail without trying.
+pub fn select_servers(
+    kind: ProxyKind,
+    servers: &[ProxyServerEntry],
+    params: &ProxyHealthParams,
+) -> Vec<SelectedProxy> {
+    let now = Instant::now();
+    let mut healthy = vec![];
+    let mut all = vec![];
+
+    for entry in servers {
+        if entry.weight == 0 {
+            continue;
+        }
+        let state = get_state(kind, entry.server, params);
+        if state.is_healthy(now) {
+            healthy.push((entry.weight, entry.server, state.clone()));
+        }
+        all.push((entry.weight, entry.server, state));
+    }
+
+    let candidates = if healthy.is_empty() { all } else { healthy };
+    weighted_shuffle(candidates, rand::random::<f64>)
+        .into_iter()
+        .map(|(server, state)| SelectedProxy {
+            server,
+            state,
+            failed: AtomicBool::new(false),
+        })
+        .collect()
+}
+
+/// Orders the candidates such that each position is filled by picking
+/// from the remaining candidates with a probability proportional to
+/// their weight
+fn weighted_shuffle<T>(
+    mut candidates: Vec<(u32, SocketAddr, T)>,
+    mut random: impl FnMut() -> f64,
+) -> Vec<(SocketAddr, T)> {
+    let mut result = Vec::with_capacity(candidates.len());
+    while !candidates.is_empty() {
+        let total: u64 = candidates.iter().map(|(weight, ..)| *weight as u64).sum();
+        let mut target = (random() * total as f64) as u64;
+        let mut idx = candidates.len() - 1;
+        for (i, (weight, ..)) in candidates.iter().enumerate() {
+            if target < *weight as u64 {
+                idx = i;
+                break;
+            }
+            target -= *weight as u64;
+        }
+        let (_weight, server, item) = candidates.remove(idx);
+        result.push((server, item));
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn weighted() {
+        let a: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:5000".parse().unwrap();
+        let candidates = || vec![(1, a, ()), (2, b, ()), (1, c, ())];
+        let order = |random: f64| -> Vec<SocketAddr> {
+            weighted_shuffle(candidates(), || random)
+                .into_iter()
+                .map(|(server, _)| server)
+                .collect()
+        };
+
+        // The first pick spans a total weight of 4, of which
+        // b occupies [1, 3)
+        assert_eq!(order(0.0), vec![a, b, c]);
+        assert_eq!(order(0.3), vec![b, a, c]);
+        assert_eq!(order(0.6), vec![b, c, a]);
+        assert_eq!(order(0.9), vec![c, b, a]);
+    }
+
+    #[test]
+    fn failover() {
+        let params = ProxyHealthParams::default();
+        let up: SocketAddr = "10.1.0.1:5000".parse().unwrap();
+        let down: SocketAddr = "10.1.0.2:5000".parse().unwrap();
+        let servers = vec![
+            ProxyServerEntry {
+                server: up,
+                weight: 1,
+            },
+            ProxyServerEntry {
+                server: down,
+                weight: 1,
+            },
+        ];
+
+        let selected = select_servers(ProxyKind::Socks5, &servers, &params);
+        assert_eq!(selected.len(), 2);
+        for proxy in &selected {
+            if proxy.server == down {
+                proxy.record_failure();
+            }
+        }
+
+        // The failed proxy is no longer selected
+        let selected = select_servers(ProxyKind::Socks5, &servers, &params);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].server, up);
+
+        // If everything is down, we try everything
+        selected[0].record_failure();
+        let selected = select_servers(ProxyKind::Socks5, &servers, &params);
+        assert_eq!(selected.len(), 2);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::age_slo::AgeSlo;
 use crate::egress_source::{EgressPool, EgressPoolRoundRobin, RoundRobinResult};
 use crate::http_server::admin_bounce_v1::AdminBounceEntry;
 use crate::http_server::admin_rebind_v1::AdminRebindEntry;
@@ -10,6 +11,7 @@ use crate::metrics_helper::{
     QUEUED_COUNT_GAUGE_BY_PROVIDER, QUEUED_COUNT_GAUGE_BY_PROVIDER_AND_POOL,
 };
 use crate::ready_queue::ReadyQueueManager;
+use crate::send_window::SendWindow;
 use crate::smtp_dispatcher::SmtpProtocol;
 use crate::smtp_server::{make_deferred_queue_config, RejectError, DEFERRED_QUEUE_NAME};
 use crate::spool::SpoolManager;
@@ -19,7 +21,7 @@ use config::epoch::{get_current_epoch, ConfigEpoch};
 use config::{load_config, CallbackSignature, LuaConfig};
 use crossbeam_skiplist::SkipSet;
 use humantime::format_duration;
-use kumo_api_types::egress_path::ConfigRefreshStrategy;
+use kumo_api_types::egress_path::{BandwidthLimit, ConfigRefreshStrategy};
 use kumo_prometheus::{counter_bundle, label_key, AtomicCounter, PruningCounterRegistry};
 use kumo_server_common::config_handle::ConfigHandle;
 use kumo_server_lifecycle::{is_shutting_down, Activity, ShutdownSubcription};
@@ -32,6 +34,7 @@ use parking_lot::FairMutex as StdMutex;
 use prometheus::{Histogram, IntCounter, IntGauge};
 use rfc5321::{EnhancedStatusCode, Response};
 use serde::{Deserialize, Serialize};
+use spool::SpoolId;
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock, Once, OnceLock};
@@ -169,6 +172,13 @@ static DELAY_DUE_TO_THROTTLE_INSERT_READY_COUNTER: LazyLock<PruningCounterRegist
             "number of times a message was delayed due throttle_insert_ready_queue event",
         )
     });
+static DELAY_DUE_TO_SEND_WINDOW_COUNTER: LazyLock<PruningCounterRegistry<QueueKey>> =
+    LazyLock::new(|| {
+        PruningCounterRegistry::register(
+            "delayed_due_to_send_window",
+            "number of times a message was delayed due to the send_window of its queue",
+        )
+    });
 static RESOLVE_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
     prometheus::register_histogram!(
         "queue_resolve_latency",
@@ -209,6 +219,7 @@ struct ScheduledMetrics {
     delay_due_to_message_rate_throttle: OnceLock<AtomicCounter>,
     delay_due_to_throttle_insert_ready: OnceLock<AtomicCounter>,
     delay_due_to_ready_queue_full: OnceLock<AtomicCounter>,
+    delay_due_to_send_window: OnceLock<AtomicCounter>,
 }
 
 impl ScheduledMetrics {
@@ -268,6 +279,7 @@ impl ScheduledMetrics {
             delay_due_to_message_rate_throttle: OnceLock::new(),
             delay_due_to_throttle_insert_ready: OnceLock::new(),
             delay_due_to_ready_queue_full: OnceLock::new(),
+            delay_due_to_send_window: OnceLock::new(),
         }
     }
 
@@ -296,6 +308,14 @@ impl ScheduledMetrics {
             DELAY_DUE_TO_READY_QUEUE_FULL_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
         })
     }
+    pub fn delay_due_to_send_window(&self) -> &AtomicCounter {
+        self.delay_due_to_send_window.get_or_init(|| {
+            let key = BorrowedQueueKey {
+                queue: self.name.as_str(),
+            };
+            DELAY_DUE_TO_SEND_WINDOW_COUNTER.get_or_create(&key as &dyn QueueKeyTrait)
+        })
+    }
 
     pub fn inc(&self) {
         TOTAL_DELAY_GAUGE.inc();
@@ -443,6 +463,22 @@ pub struct QueueConfig {
     /// routing_domain for this queue, will be used instead.
     #[serde(default)]
     pub provider_name: Option<String>,
+
+    /// Limits the rate at which message data is sent for the
+    /// tenant of this queue, across all of its queues and paths
+    #[serde(default)]
+    pub tenant_max_bandwidth: Option<BandwidthLimit>,
+
+    /// Declares the expected delivery age of messages in this queue,
+    /// such as "95% delivered within 15 minutes", so that breaches
+    /// can be reported via metrics and the queue_age_slo_breached event
+    #[serde(default)]
+    pub age_slo: Option<AgeSlo>,
+
+    /// Restricts the days of the week and times of day at which
+    /// messages in this queue can be delivered
+    #[serde(default)]
+    pub send_window: Option<SendWindow>,
 }
 
 impl LuaUserData for QueueConfig {}
@@ -462,6 +498,9 @@ impl Default for QueueConfig {
             timerwheel_tick_interval: None,
             refresh_strategy: ConfigRefreshStrategy::default(),
             provider_name: None,
+            tenant_max_bandwidth: None,
+            age_slo: None,
+            send_window: None,
         }
     }
 }
@@ -854,6 +893,15 @@ impl QueueStructure {
         }
     }
 
+    /// Returns the contained messages without removing them
+    fn snapshot(&self) -> Vec<Message> {
+        match self {
+            Self::TimerWheel(q) => q.lock().snapshot(),
+            Self::SkipList(q) => q.iter().map(|entry| (*entry).0.clone()).collect(),
+            Self::SingletonTimerWheel(q) => q.lock().iter().cloned().collect(),
+        }
+    }
+
     fn insert(&self, msg: Message) -> QueueInsertResult {
         match self {
             Self::TimerWheel(q) => match q.lock().insert(msg) {
@@ -1219,6 +1267,7 @@ impl Queue {
     /// Insert into the timeq, and updates the counters.
     fn timeq_insert(&self, msg: Message) -> Result<(), Message> {
         tracing::trace!("timeq_insert {} due={:?}", self.name, msg.get_due());
+        crate::meta_index::set_queue(msg.id(), &self.name);
         match self.queue.insert(msg) {
             QueueInsertResult::Inserted { should_notify } => {
                 self.metrics().inc();
@@ -1243,6 +1292,31 @@ impl Queue {
         msgs
     }
 
+    /// Removes the messages that satisfy `predicate` from the timeq,
+    /// putting the others back, and returns the removed messages.
+    async fn drain_timeq_matching(&self, predicate: impl Fn(&Message) -> bool) -> Vec<Message> {
+        let mut matched = vec![];
+        for msg in self.drain_timeq() {
+            if predicate(&msg) {
+                matched.push(msg);
+                continue;
+            }
+            let result = match self.insert_delayed(msg.clone()).await {
+                Ok(InsertResult::Delayed) => Ok(()),
+                Ok(InsertResult::Ready(msg)) => self.insert_ready(msg).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                tracing::error!(
+                    "failed to put {} back into {} after partial drain: {err:#}",
+                    msg.id(),
+                    self.name
+                );
+            }
+        }
+        matched
+    }
+
     async fn do_rebind(&self, msg: Message, rebind: &Arc<AdminRebindEntry>) {
         async fn try_apply(msg: &Message, rebind: &Arc<AdminRebindEntry>) -> anyhow::Result<()> {
             if !msg.is_meta_loaded() {
@@ -1314,6 +1388,14 @@ impl Queue {
             msg.set_due(None).await.ok();
         }
 
+        if queue.name != self.name {
+            msg.add_annotation(format!(
+                "admin rebind from {} to {queue_name}: {}",
+                self.name, rebind.request.reason
+            ))
+            .ok();
+        }
+
         // If we changed queues, log an AdminRebind operation so that it is possible
         // to trace through the logs and understand what happened.
         if queue.name != self.name && !rebind.request.suppress_logging {
@@ -1356,7 +1438,12 @@ impl Queue {
 
     #[instrument(skip(self))]
     pub async fn rebind_all(&self, rebind: &Arc<AdminRebindEntry>) {
-        let msgs = self.drain_timeq();
+        let msgs = if rebind.request.meta.is_empty() {
+            self.drain_timeq()
+        } else {
+            self.drain_timeq_matching(|msg| rebind.matches_meta(msg.id()))
+                .await
+        };
         let count = msgs.len();
         if count > 0 {
             for msg in msgs {
@@ -1365,9 +1452,42 @@ impl Queue {
         }
     }
 
+    /// Makes the messages whose next delivery attempt is scheduled
+    /// after `deadline` immediately eligible for delivery.
+    /// Returns the number of messages that were affected.
+    #[instrument(skip(self))]
+    pub async fn expedite_due_after(&self, deadline: DateTime<Utc>) -> usize {
+        let msgs = self
+            .drain_timeq_matching(|msg| msg.get_due().map(|due| due > deadline).unwrap_or(false))
+            .await;
+        let count = msgs.len();
+        for msg in msgs {
+            msg.set_due(None).await.ok();
+            if let Err(err) = self
+                .requeue_message_internal(
+                    msg,
+                    IncrementAttempts::No,
+                    Some(chrono::Duration::zero()),
+                )
+                .await
+            {
+                tracing::error!(
+                    "failed to requeue message to {} while expediting: {err:#}",
+                    self.name
+                );
+            }
+        }
+        count
+    }
+
     #[instrument(skip(self))]
     pub async fn bounce_all(&self, bounce: &AdminBounceEntry) {
-        let msgs = self.drain_timeq();
+        let msgs = if bounce.meta.is_empty() {
+            self.drain_timeq()
+        } else {
+            self.drain_timeq_matching(|msg| bounce.matches_meta(msg.id()))
+                .await
+        };
         let count = msgs.len();
         if count > 0 {
             let name = self.name.clone();
@@ -1661,6 +1781,44 @@ impl Queue {
             return Ok(());
         }
 
+        let send_window = self.queue_config.borrow().send_window;
+        if let Some(window) = send_window {
+            let now = Utc::now();
+            if let Some(release) = window.release_time(now, rand::random::<f64>()) {
+                let delay = release - now;
+                tracing::trace!(
+                    "{} is outside its send window, release={release:?}",
+                    self.name
+                );
+                msg.add_annotation(format!("{} is outside its send window", self.name))
+                    .ok();
+
+                Box::pin(QueueManager::requeue_message(
+                    msg,
+                    IncrementAttempts::No,
+                    Some(delay),
+                    Response {
+                        code: 451,
+                        enhanced_code: Some(EnhancedStatusCode {
+                            class: 4,
+                            subject: 4,
+                            detail: 4,
+                        }),
+                        content: format!(
+                            "KumoMTA internal: {} is outside its send window, release={release:?}",
+                            self.name
+                        ),
+                        command: None,
+                    },
+                ))
+                .await?;
+
+                self.metrics().delay_due_to_send_window().inc();
+
+                return Ok(());
+            }
+        }
+
         if let Some(result) = self.check_message_rate_throttle().await? {
             if let Some(delay) = result.retry_after {
                 tracing::trace!("{} throttled message rate, delay={delay:?}", self.name);
@@ -1668,6 +1826,8 @@ impl Queue {
                 // ideally result in smooth message flow and the jitter will
                 // (intentionally) perturb that.
                 let delay = chrono::Duration::from_std(delay).unwrap_or(kumo_chrono_helper::MINUTE);
+                msg.add_annotation(format!("{} throttled message rate", self.name))
+                    .ok();
 
                 Box::pin(QueueManager::requeue_message(
                     msg,
@@ -1707,6 +1867,11 @@ impl Queue {
                     self.name
                 );
                 self.metrics().delay_due_to_throttle_insert_ready().inc();
+                msg.add_annotation(format!(
+                    "{} throttle_insert_ready_queue event delayed the message",
+                    self.name
+                ))
+                .ok();
 
                 Box::pin(QueueManager::requeue_message(
                     msg,
@@ -2034,7 +2199,8 @@ impl Queue {
             *self.last_change.lock() = Instant::now();
 
             tracing::trace!("insert msg {}", msg.id());
-            if let Some(b) = AdminBounceEntry::get_for_queue_name(&self.name) {
+            crate::meta_index::record(&msg);
+            if let Some(b) = AdminBounceEntry::get_for_message(&self.name, msg.id()) {
                 let id = *msg.id();
                 b.log(msg, Some(&self.name)).await;
                 SpoolManager::remove_from_spool(id).await?;
@@ -2057,6 +2223,12 @@ impl Queue {
         }
     }
 
+    /// Returns the messages that are currently held in this queue,
+    /// without removing them.  This is O(n) in the size of the queue.
+    pub fn snapshot_messages(&self) -> Vec<Message> {
+        self.queue.snapshot()
+    }
+
     pub fn get_config(&self) -> &ConfigHandle<QueueConfig> {
         &self.queue_config
     }
@@ -2357,6 +2529,11 @@ impl QueueManager {
                             // and ensure that the message is due now
                             msg.set_due(None).await?;
 
+                            msg.add_annotation(format!(
+                                "requeue_message event rebound from {queue_name} to {queue_name_after}"
+                            ))
+                            .ok();
+
                             // and use the new queue name
                             queue_name = queue_name_after;
                         }
@@ -2482,6 +2659,48 @@ impl QueueManager {
         mgr.named.keys().map(|s| s.to_string()).collect()
     }
 
+    /// Returns the ids of the messages that are held by the scheduled
+    /// queues, the ready queues and their dispatchers.
+    /// This is O(n) in the number of queued messages.
+    pub fn queued_ids() -> HashSet<SpoolId> {
+        let mut ids = HashSet::new();
+        for name in Self::all_queue_names() {
+            if let Some(queue) = Self::get_opt(&name) {
+                ids.extend(queue.snapshot_messages().iter().map(|msg| *msg.id()));
+            }
+        }
+        for queue in ReadyQueueManager::all_queues() {
+            queue.collect_ids(&mut ids);
+        }
+        ids
+    }
+
+    /// Returns the number of messages across all scheduled queues
+    pub fn scheduled_count_total() -> usize {
+        TOTAL_DELAY_GAUGE.get().max(0) as usize
+    }
+
+    /// Returns the number of messages in the scheduled queues
+    /// for each tenant, as tracked by the scheduled_by_tenant gauge
+    pub fn scheduled_count_by_tenant() -> HashMap<String, usize> {
+        let tenants: HashSet<String> = Self::all_queue_names()
+            .iter()
+            .filter_map(|name| {
+                QueueNameComponents::parse(name)
+                    .tenant
+                    .map(|tenant| tenant.to_string())
+            })
+            .collect();
+        tenants
+            .into_iter()
+            .filter_map(|tenant| {
+                let key = BorrowedTenantKey { tenant: &tenant };
+                let count = TENANT_GAUGE.get(&key as &dyn TenantKeyTrait)?.get();
+                (count > 0).then_some((tenant, count))
+            })
+            .collect()
+    }
+
     /// Coupled with Queue::check_reap!
     pub fn remove(name: &str) {
         let mut mgr = MANAGER.lock();
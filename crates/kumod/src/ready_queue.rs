@@ -15,11 +15,13 @@ use crate::queue::{
 use crate::smtp_dispatcher::{MxListEntry, OpportunisticInsecureTlsHandshakeError, SmtpDispatcher};
 use crate::smtp_server::DeferredSmtpInjectionDispatcher;
 use crate::spool::SpoolManager;
+use crate::suppression::{check_recipient, SuppressionStage};
 use anyhow::Context;
 use async_trait::async_trait;
 use config::epoch::ConfigEpoch;
 use config::{load_config, CallbackSignature};
 use dns_resolver::MailExchanger;
+use kumo_api_types::config_snapshot::ConfigSnapshotV1EgressPath;
 use kumo_api_types::egress_path::{ConfigRefreshStrategy, EgressPathConfig};
 use kumo_server_common::config_handle::ConfigHandle;
 use kumo_server_lifecycle::{is_shutting_down, Activity, ShutdownSubcription};
@@ -30,7 +32,8 @@ use message::Message;
 use parking_lot::FairMutex as StdMutex;
 use rfc5321::{EnhancedStatusCode, Response};
 use serde::Serialize;
-use std::collections::HashMap;
+use spool::SpoolId;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock};
@@ -39,7 +42,7 @@ use throttle::limit::{LimitLease, LimitSpec};
 use throttle::ThrottleSpec;
 use tokio::sync::Notify;
 use tokio::task::JoinHandle;
-use tracing::instrument;
+use tracing::{instrument, Instrument};
 use uuid::Uuid;
 
 static MANAGER: LazyLock<StdMutex<ReadyQueueManager>> =
@@ -62,6 +65,12 @@ pub struct Fifo {
     list: StdMutex<MessageList>,
     count: ReadyCountBundle,
     capacity: AtomicUsize,
+    /// The ids of the messages that were most recently popped by each
+    /// of the dispatchers, keyed by their session id.  This is updated
+    /// once per batch, rather than once per message, and may retain ids
+    /// of messages that have since left the dispatcher; that only makes
+    /// the spool janitor more conservative.
+    dispatching: StdMutex<HashMap<Uuid, Vec<SpoolId>>>,
 }
 
 impl Fifo {
@@ -70,6 +79,26 @@ impl Fifo {
             count,
             list: StdMutex::new(MessageList::new()),
             capacity: AtomicUsize::new(capacity),
+            dispatching: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the messages that are held by a dispatcher
+    fn set_dispatching(&self, session_id: Uuid, msgs: &[Message]) {
+        let ids = msgs.iter().map(|msg| *msg.id()).collect();
+        self.dispatching.lock().insert(session_id, ids);
+    }
+
+    fn clear_dispatching(&self, session_id: Uuid) {
+        self.dispatching.lock().remove(&session_id);
+    }
+
+    /// Adds the ids of the messages that are in the fifo, or
+    /// that are held by its dispatchers, to ids
+    pub fn collect_ids(&self, ids: &mut HashSet<SpoolId>) {
+        ids.extend(self.list.lock().ids());
+        for batch in self.dispatching.lock().values() {
+            ids.extend(batch.iter().copied());
         }
     }
 
@@ -266,7 +295,7 @@ impl ReadyQueueManager {
 
         let egress_source = EgressSource::resolve(egress_source, &mut config).await?;
 
-        let path_config: EgressPathConfig = config
+        let mut path_config: EgressPathConfig = config
             .async_call_callback(
                 &GET_EGRESS_PATH_CONFIG_SIG,
                 (
@@ -281,6 +310,22 @@ impl ReadyQueueManager {
                 err
             })?;
 
+        crate::http_server::admin_tuning_v1::apply_ready_queue_tuning(
+            &name,
+            &site_name,
+            &mut path_config,
+        );
+
+        if path_config.provider_name.is_none() {
+            path_config.provider_name = mx
+                .as_deref()
+                .and_then(kumo_api_types::provider::identify_provider_for_mx);
+        }
+
+        if let Some(schedule) = &egress_source.warmup {
+            crate::warmup::apply_warmup(&egress_source.name, schedule, &mut path_config);
+        }
+
         Ok(ReadyQueueConfig {
             name,
             site_name,
@@ -317,7 +362,7 @@ impl ReadyQueueManager {
     ) -> anyhow::Result<ReadyQueueHandle> {
         let ReadyQueueConfig {
             name,
-            site_name: _,
+            site_name,
             path_config,
             egress_source,
             mx,
@@ -352,6 +397,7 @@ impl ReadyQueueManager {
             Arc::new(ReadyQueue {
                 name: name.clone(),
                 queue_name_for_config_change_purposes_only: queue_name.to_string(),
+                site_name,
                 ready,
                 mx,
                 notify_dispatcher,
@@ -527,11 +573,21 @@ pub type ReadyQueueHandle = Arc<ReadyQueue>;
 pub struct ReadyQueueStates {
     pub connection_rate_throttled: Option<QueueState>,
     pub connection_limited: Option<QueueState>,
+    pub mx_pinned: Option<QueueState>,
+}
+
+/// Returns the site name portion of a ready queue name,
+/// which has the form `SOURCE->SITE@PROTOCOL`
+pub fn site_name_of(ready_queue_name: &str) -> Option<&str> {
+    let (_source, remainder) = ready_queue_name.split_once("->")?;
+    let (site, _proto) = remainder.rsplit_once('@')?;
+    Some(site)
 }
 
 pub struct ReadyQueue {
     name: String,
     queue_name_for_config_change_purposes_only: String,
+    site_name: String,
     ready: Arc<Fifo>,
     mx: Option<Arc<MailExchanger>>,
     notify_maintainer: Arc<Notify>,
@@ -554,6 +610,28 @@ impl ReadyQueue {
         &self.name
     }
 
+    /// Adds the ids of the messages held by this queue
+    /// and its dispatchers to ids
+    pub fn collect_ids(&self, ids: &mut HashSet<SpoolId>) {
+        self.ready.collect_ids(ids);
+    }
+
+    /// Returns the parameters that were passed to get_egress_path_config
+    /// for this queue, along with the configuration that is in effect
+    pub fn egress_path_snapshot(&self) -> ConfigSnapshotV1EgressPath {
+        let components =
+            QueueNameComponents::parse(&self.queue_name_for_config_change_purposes_only);
+        ConfigSnapshotV1EgressPath {
+            routing_domain: components
+                .routing_domain
+                .unwrap_or(components.domain)
+                .to_string(),
+            egress_source: self.egress_source.name.to_string(),
+            site_name: self.site_name.clone(),
+            config: crate::config_snapshot::snapshot_value(&**self.path_config.borrow()),
+        }
+    }
+
     pub async fn insert(&self, msg: Message) -> Result<(), Message> {
         if low_memory() {
             msg.save_and_shrink().await.ok();
@@ -742,6 +820,21 @@ impl ReadyQueue {
                     },
                 ));
             }
+
+            // Keyed by the site alone, so that it is shared by all sources,
+            // and, via redis, by all nodes
+            let cluster_limit_name;
+            if let Some(limit) = path_config.cluster_connection_limit {
+                cluster_limit_name = format!("kumomta.cluster_connection_limit.{}", self.site_name);
+                limits.push((
+                    &cluster_limit_name,
+                    LimitSpec {
+                        limit,
+                        duration: lease_duration,
+                    },
+                ));
+            }
+
             // Check limits from smallest to largest so that we avoid
             // taking up a slot from a larger one only to hit a smaller
             // one and not do anything useful with the larger one
@@ -801,9 +894,19 @@ impl ReadyQueue {
                 let consecutive_connection_failures = self.consecutive_connection_failures.clone();
                 let states = self.states.clone();
 
+                // Allows diagnostic logging to be scoped to an egress pool
+                // via kumo_server_common::diagnostic_logging
+                let span = tracing::debug_span!(
+                    "dispatcher",
+                    ready_queue = %name,
+                    egress_pool = %egress_pool,
+                    egress_source = %egress_source.name,
+                );
+
                 tracing::trace!("spawning client for {name}");
-                if let Ok(handle) =
-                    READYQ_RUNTIME.spawn(format!("smtp client {name}"), async move {
+                if let Ok(handle) = READYQ_RUNTIME.spawn(
+                    format!("smtp client {name}"),
+                    async move {
                         if let Err(err) = Dispatcher::run(
                             &name,
                             queue_name_for_config_change_purposes_only,
@@ -826,8 +929,9 @@ impl ReadyQueue {
                          (consecutive_connection_failures={consecutive_connection_failures:?})"
                             );
                         }
-                    })
-                {
+                    }
+                    .instrument(span),
+                ) {
                     self.connections.lock().push(handle);
                 }
             }
@@ -872,6 +976,13 @@ impl ReadyQueue {
         }
     }
 
+    /// Re-evaluates the egress path configuration now, rather than
+    /// waiting for the next scheduled refresh
+    pub async fn refresh_config_now(&self) {
+        let epoch = self.config_epoch.lock().clone();
+        self.perform_config_refresh(&epoch).await;
+    }
+
     async fn perform_config_refresh(&self, epoch: &ConfigEpoch) {
         *self.config_epoch.lock() = epoch.clone();
         tracing::trace!("perform_config_refresh for {}", self.name);
@@ -979,6 +1090,8 @@ impl Drop for Dispatcher {
         let activity = self.activity.clone();
         let name = self.name.to_string();
         let notify_dispatcher = self.notify_dispatcher.clone();
+        let ready = self.ready.clone();
+        let session_id = self.session_id;
         READYQ_RUNTIME
             .spawn("Dispatcher::drop".to_string(), async move {
                 let had_msgs = !msgs.is_empty();
@@ -1011,6 +1124,8 @@ impl Drop for Dispatcher {
                         }
                     }
                 }
+                // Only now are the messages back in the scheduled queue
+                ready.clear_dispatching(session_id);
 
                 if !had_msgs {
                     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -1279,19 +1394,25 @@ impl Dispatcher {
         // guard, so that a delay due to throttling doesn't result
         // in a delay of shutdown
         let path_config = self.path_config.borrow();
-        let num_throttles = if path_config.max_message_rate.is_some() {
-            1
-        } else {
-            0
-        } + path_config.additional_message_rate_throttles.len();
+        let num_throttles = usize::from(path_config.max_message_rate.is_some())
+            + usize::from(path_config.cluster_max_message_rate.is_some())
+            + path_config.additional_message_rate_throttles.len();
         if num_throttles > 0 {
             let mut throttles = Vec::with_capacity(num_throttles);
             let message_rate_name;
+            let cluster_message_rate_name;
 
             if let Some(throttle) = &path_config.max_message_rate {
                 message_rate_name = format!("kumomta.max_message_rate.{}", self.name);
                 throttles.push((&message_rate_name, throttle));
             }
+            if let Some(throttle) = &path_config.cluster_max_message_rate {
+                cluster_message_rate_name = format!(
+                    "kumomta.cluster_max_message_rate.{}",
+                    site_name_of(&self.name).unwrap_or(&self.name)
+                );
+                throttles.push((&cluster_message_rate_name, throttle));
+            }
             for (key, throttle) in &path_config.additional_message_rate_throttles {
                 throttles.push((key, throttle));
             }
@@ -1330,10 +1451,43 @@ impl Dispatcher {
 
         self.delivered_this_connection += self.msgs.len();
 
-        if let Err(err) = queue_dispatcher
+        // Allows diagnostic logging to be scoped to a domain or tenant
+        // via kumo_server_common::diagnostic_logging
+        let span = match self.msgs.first().map(|msg| msg.get_queue_name()) {
+            Some(Ok(queue_name)) => {
+                let components = QueueNameComponents::parse(&queue_name);
+                tracing::debug_span!(
+                    "message",
+                    domain = components.domain,
+                    tenant = components.tenant,
+                    campaign = components.campaign,
+                )
+            }
+            _ => tracing::Span::none(),
+        };
+
+        // The time spent on the batch is metered against the
+        // tenants of its messages by crate::tenant_usage
+        let queue_names: Vec<String> = self
+            .msgs
+            .iter()
+            .filter_map(|msg| msg.get_queue_name().ok())
+            .collect();
+        let started = Instant::now();
+
+        let result = queue_dispatcher
             .deliver_message(self.msgs.clone(), self)
-            .await
-        {
+            .instrument(span)
+            .await;
+
+        if !queue_names.is_empty() {
+            let share = started.elapsed() / queue_names.len() as u32;
+            for queue_name in &queue_names {
+                crate::tenant_usage::record_connection_time(queue_name, share);
+            }
+        }
+
+        if let Err(err) = result {
             // Transient failure; continue with another host
             tracing::debug!("failed to send message batch to {}: {err:#}", self.name,);
             return Err(err.into());
@@ -1525,11 +1679,49 @@ impl Dispatcher {
         while self.msgs.len() < queue_dispatcher.max_batch_size() {
             if let Some(msg) = self.ready.pop() {
                 if let Ok(queue_name) = msg.get_queue_name() {
-                    if let Some(entry) = AdminBounceEntry::get_for_queue_name(&queue_name) {
+                    if let Some(entry) = AdminBounceEntry::get_for_message(&queue_name, msg.id()) {
                         entry.log(msg.clone(), Some(&queue_name)).await;
                         SpoolManager::remove_from_spool(*msg.id()).await.ok();
                         continue;
                     }
+                    let suppressed = match msg.recipient() {
+                        Ok(recipient) => {
+                            check_recipient(SuppressionStage::Dispatch, &recipient).await
+                        }
+                        Err(_) => None,
+                    };
+                    if let Some(entry) = suppressed {
+                        log_disposition(LogDisposition {
+                            kind: RecordType::Bounce,
+                            msg: msg.clone(),
+                            site: &self.name,
+                            peer_address: None,
+                            response: rfc5321::Response {
+                                code: 550,
+                                enhanced_code: Some(rfc5321::EnhancedStatusCode {
+                                    class: 5,
+                                    subject: 7,
+                                    detail: 1,
+                                }),
+                                content: format!(
+                                    "KumoMTA internal: recipient is suppressed: {}",
+                                    entry.reason
+                                ),
+                                command: None,
+                            },
+                            egress_source: None,
+                            egress_pool: None,
+                            relay_disposition: None,
+                            delivery_protocol: None,
+                            provider: None,
+                            tls_info: None,
+                            source_address: None,
+                            session_id: Some(self.session_id),
+                        })
+                        .await;
+                        SpoolManager::remove_from_spool(*msg.id()).await.ok();
+                        continue;
+                    }
                     if let Some(suspend) = AdminSuspendEntry::get_for_queue_name(&queue_name) {
                         let response = rfc5321::Response {
                             code: 451,
@@ -1572,6 +1764,7 @@ impl Dispatcher {
                 break;
             }
         }
+        self.ready.set_dispatching(self.session_id, &self.msgs);
 
         tracing::trace!(
             "now have {} messages. min batch {}, max {}",
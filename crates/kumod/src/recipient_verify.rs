@@ -0,0 +1,362 @@
+//! This module implements recipient verification by callout:
+//! connecting to the MX of the recipient domain and probing the
+//! address with RCPT TO, without sending a message.
+//! Verdicts are cached, and callouts are rate limited per domain,
+//! so that policy can use this to reject unknown recipients at the
+//! edge without hammering the destination.
+use dns_resolver::{MailExchanger, ResolvedMxAddresses};
+use message::EnvelopeAddress;
+use prometheus::IntCounterVec;
+use rfc5321::{Command, ForwardPath, Response, ReversePath, SmtpClient, SmtpClientTimeouts};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, OnceLock};
+use std::time::{Duration, Instant};
+use throttle::ThrottleSpec;
+
+static VERIFIER: OnceLock<RecipientVerifier> = OnceLock::new();
+
+static VERDICTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "recipient_verify_verdicts",
+        "total number of kumo.verify_recipient verdicts, by verdict and \
+         whether the verdict was cached",
+        &["verdict", "cached"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RecipientVerifyParams {
+    /// The name to use in the EHLO command
+    #[serde(default = "RecipientVerifyParams::default_ehlo_domain")]
+    pub ehlo_domain: String,
+
+    /// The address to use in the MAIL FROM command;
+    /// the default is the null sender
+    #[serde(default)]
+    pub mail_from: String,
+
+    /// The maximum rate of callouts to each destination domain
+    #[serde(default = "RecipientVerifyParams::default_rate_limit")]
+    pub rate_limit: ThrottleSpec,
+
+    #[serde(default = "SmtpClientTimeouts::short_timeouts")]
+    pub client_timeouts: SmtpClientTimeouts,
+
+    /// How long a Valid verdict is cached
+    #[serde(
+        default = "RecipientVerifyParams::default_valid_ttl",
+        with = "duration_serde"
+    )]
+    pub valid_ttl: Duration,
+
+    /// How long an Invalid verdict is cached
+    #[serde(
+        default = "RecipientVerifyParams::default_invalid_ttl",
+        with = "duration_serde"
+    )]
+    pub invalid_ttl: Duration,
+
+    /// How long an Unknown verdict is cached
+    #[serde(
+        default = "RecipientVerifyParams::default_unknown_ttl",
+        with = "duration_serde"
+    )]
+    pub unknown_ttl: Duration,
+
+    /// The maximum number of cached verdicts
+    #[serde(default = "RecipientVerifyParams::default_capacity")]
+    pub capacity: usize,
+
+    /// Whether callouts are made to domains that are not
+    /// listed in `domains`
+    #[serde(default = "RecipientVerifyParams::default_enabled")]
+    pub enabled_by_default: bool,
+
+    /// Enables or disables callouts for specific domains
+    #[serde(default)]
+    pub domains: HashMap<String, bool>,
+}
+
+impl RecipientVerifyParams {
+    fn default_ehlo_domain() -> String {
+        crate::smtp_server::EsmtpListenerParams::default_hostname()
+    }
+
+    fn default_rate_limit() -> ThrottleSpec {
+        ThrottleSpec::try_from("10/m").expect("valid default rate")
+    }
+
+    fn default_valid_ttl() -> Duration {
+        Duration::from_secs(86400)
+    }
+
+    fn default_invalid_ttl() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_unknown_ttl() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn default_capacity() -> usize {
+        100_000
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn is_enabled_for(&self, domain: &str) -> bool {
+        self.domains
+            .iter()
+            .find_map(|(name, enabled)| name.eq_ignore_ascii_case(domain).then_some(*enabled))
+            .unwrap_or(self.enabled_by_default)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecipientVerdict {
+    /// The destination accepted the recipient
+    Valid,
+    /// The destination reported that the recipient does not exist
+    Invalid,
+    /// The recipient could not be verified; the callout was disabled
+    /// or rate limited, or the destination could not be reached,
+    /// deferred, or rejected the probe for policy reasons
+    Unknown,
+}
+
+impl RecipientVerdict {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Valid => "Valid",
+            Self::Invalid => "Invalid",
+            Self::Unknown => "Unknown",
+        }
+    }
+
+    /// Classifies the response to the RCPT TO probe
+    fn from_rcpt_response(response: &Response) -> Self {
+        if response.code >= 200 && response.code < 300 {
+            return Self::Valid;
+        }
+        if !response.is_permanent() {
+            return Self::Unknown;
+        }
+        match &response.enhanced_code {
+            // 5.7.x is a security or policy rejection, such as our
+            // address being blocked, which says nothing about the
+            // recipient
+            Some(enh) if enh.subject == 7 => Self::Unknown,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RecipientVerification {
+    pub verdict: RecipientVerdict,
+    /// The response to the probe, or a description of why the
+    /// recipient could not be verified
+    pub response: String,
+    /// true if this verdict was satisfied from the cache
+    pub cached: bool,
+}
+
+struct RecipientVerifier {
+    params: RecipientVerifyParams,
+    cache: lruttl::LruCacheWithTtl<String, RecipientVerification>,
+}
+
+pub fn configure_recipient_verification(params: RecipientVerifyParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    ReversePath::try_from(params.mail_from.as_str())
+        .map_err(|err| anyhow::anyhow!("invalid mail_from {}: {err}", params.mail_from))?;
+
+    let cache = lruttl::LruCacheWithTtl::new_named("recipient_verify", params.capacity);
+    VERIFIER
+        .set(RecipientVerifier { params, cache })
+        .map_err(|_| anyhow::anyhow!("configure_recipient_verification has already been called"))
+}
+
+impl RecipientVerifier {
+    fn unknown(response: impl Into<String>) -> RecipientVerification {
+        RecipientVerification {
+            verdict: RecipientVerdict::Unknown,
+            response: response.into(),
+            cached: false,
+        }
+    }
+
+    async fn verify(&self, recipient: &EnvelopeAddress) -> RecipientVerification {
+        let key = recipient.to_string().to_ascii_lowercase();
+        if let Some(mut result) = self.cache.get(&key) {
+            result.cached = true;
+            return result;
+        }
+
+        let domain = recipient.domain().to_ascii_lowercase();
+        if !self.params.is_enabled_for(&domain) {
+            // Neither this nor the rate limited verdict below are
+            // cached, as they say nothing about the recipient
+            return Self::unknown(format!("verification is disabled for {domain}"));
+        }
+
+        match self
+            .params
+            .rate_limit
+            .throttle(format!("kumo.verify_recipient.{domain}"))
+            .await
+        {
+            Ok(result) if result.throttled => {
+                return Self::unknown(format!("verification rate limited for {domain}"));
+            }
+            Ok(_) => {}
+            Err(err) => return Self::unknown(format!("rate limit check failed: {err:#}")),
+        }
+
+        let result = match self.callout(recipient, &domain).await {
+            Ok(response) => RecipientVerification {
+                verdict: RecipientVerdict::from_rcpt_response(&response),
+                response: response.to_single_line(),
+                cached: false,
+            },
+            Err(err) => Self::unknown(format!("{err:#}")),
+        };
+
+        let ttl = match result.verdict {
+            RecipientVerdict::Valid => self.params.valid_ttl,
+            RecipientVerdict::Invalid => self.params.invalid_ttl,
+            RecipientVerdict::Unknown => self.params.unknown_ttl,
+        };
+        self.cache.insert(key, result.clone(), Instant::now() + ttl);
+        result
+    }
+
+    /// Connects to the most preferred MX of the domain, and returns
+    /// the response to RCPT TO
+    async fn callout(&self, recipient: &EnvelopeAddress, domain: &str) -> anyhow::Result<Response> {
+        let mx = MailExchanger::resolve(domain).await?;
+        let mut addresses = match mx.resolve_addresses().await {
+            ResolvedMxAddresses::NullMx => anyhow::bail!("{domain} has a null MX"),
+            ResolvedMxAddresses::Addresses(addresses) => addresses,
+        };
+        // The most preferred addresses are at the end
+        let address = addresses
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("{domain} has no MX addresses"))?;
+        let ip = address
+            .addr
+            .ip()
+            .ok_or_else(|| anyhow::anyhow!("{address} is not an IP address"))?;
+
+        let timeouts = self.params.client_timeouts.clone();
+        let target = SocketAddr::new(ip, 25);
+        let mut client = tokio::time::timeout(
+            timeouts.connect_timeout,
+            SmtpClient::new(target, timeouts.clone()),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting to {address}"))??;
+
+        let banner = client.read_response(None, timeouts.banner_timeout).await?;
+        anyhow::ensure!(
+            banner.code == 220,
+            "{address} banner: {}",
+            banner.to_single_line()
+        );
+        client.ehlo(&self.params.ehlo_domain).await?;
+
+        let mail_from = client
+            .send_command(&Command::MailFrom {
+                address: ReversePath::try_from(self.params.mail_from.as_str())
+                    .map_err(|err| anyhow::anyhow!("{err}"))?,
+                parameters: vec![],
+            })
+            .await?;
+        anyhow::ensure!(
+            mail_from.code == 250,
+            "{address} MAIL FROM: {}",
+            mail_from.to_single_line()
+        );
+
+        let rcpt_to = client
+            .send_command(&Command::RcptTo {
+                address: ForwardPath::try_from(recipient.to_string().as_str())
+                    .map_err(|err| anyhow::anyhow!("{err}"))?,
+                parameters: vec![],
+            })
+            .await?;
+
+        // We have what we came for; don't let a problem with QUIT
+        // affect the verdict
+        client.send_command(&Command::Quit).await.ok();
+
+        Ok(rcpt_to)
+    }
+}
+
+/// Returns the cached verdict for the recipient, or performs a callout
+/// to verify it
+pub async fn verify_recipient(
+    recipient: &EnvelopeAddress,
+) -> anyhow::Result<RecipientVerification> {
+    let verifier = VERIFIER.get().ok_or_else(|| {
+        anyhow::anyhow!("kumo.configure_recipient_verification has not been called")
+    })?;
+    let result = verifier.verify(recipient).await;
+    VERDICTS
+        .with_label_values(&[
+            result.verdict.as_str(),
+            if result.cached { "true" } else { "false" },
+        ])
+        .inc();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        for (code, message, verdict) in [
+            (250, "2.1.5 OK", RecipientVerdict::Valid),
+            (550, "5.1.1 no such user", RecipientVerdict::Invalid),
+            (550, "no such user", RecipientVerdict::Invalid),
+            (
+                550,
+                "5.7.1 your address is blocked",
+                RecipientVerdict::Unknown,
+            ),
+            (450, "4.2.1 try again later", RecipientVerdict::Unknown),
+        ] {
+            let response = rfc5321::Response::with_code_and_message(code, message);
+            assert_eq!(
+                RecipientVerdict::from_rcpt_response(&response),
+                verdict,
+                "{code} {message}"
+            );
+        }
+    }
+
+    #[test]
+    fn enabled_domains() {
+        let mut params: RecipientVerifyParams =
+            serde_json::from_value(serde_json::json!({})).unwrap();
+        params.domains.insert("Example.com".to_string(), false);
+        assert!(!params.is_enabled_for("example.com"));
+        assert!(params.is_enabled_for("example.net"));
+
+        params.enabled_by_default = false;
+        params.domains.insert("example.net".to_string(), true);
+        assert!(params.is_enabled_for("example.net"));
+        assert!(!params.is_enabled_for("example.org"));
+    }
+}
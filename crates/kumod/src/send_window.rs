@@ -0,0 +1,100 @@
+//! Implements the `send_window` queue config option, which restricts
+//! the times at which the messages of a scheduled queue can be
+//! promoted to the ready queue, and spreads out the release of the
+//! messages that were held while the window was closed.
+use chrono::{DateTime, Utc};
+use message::scheduling::{ScheduleRestriction, Scheduling};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct SendWindow {
+    /// The permitted days of the week and time of day, in the same
+    /// form as message:set_scheduling
+    #[serde(flatten)]
+    pub restriction: ScheduleRestriction,
+
+    /// Messages that were held while the window was closed are
+    /// released at random times spread over this period after
+    /// the window opens, rather than all at once
+    #[serde(default, with = "duration_serde")]
+    pub ramp: Option<Duration>,
+}
+
+impl SendWindow {
+    /// If `now` falls outside of the window, returns the time at which
+    /// a message should be released. `fraction` is a number in the
+    /// range 0.0..1.0 that selects the position within the ramp.
+    pub fn release_time(&self, now: DateTime<Utc>, fraction: f64) -> Option<DateTime<Utc>> {
+        let schedule = Scheduling {
+            restriction: Some(self.restriction),
+            first_attempt: None,
+        };
+        if schedule.is_within_schedule(now) {
+            return None;
+        }
+
+        let open = schedule.adjust_for_schedule(now);
+        if open <= now {
+            // The window never opens, for example, because dow is
+            // empty; there is nothing useful that we can wait for
+            tracing::error!("send_window {:?} has no permitted times", self.restriction);
+            return None;
+        }
+
+        let Some(ramp) = self.ramp else {
+            return Some(open);
+        };
+        let release = chrono::Duration::from_std(ramp.mul_f64(fraction.clamp(0.0, 1.0)))
+            .ok()
+            .and_then(|spread| open.checked_add_signed(spread))
+            .unwrap_or(open);
+        // Don't let the ramp push the message beyond the end
+        // of the window that it is waiting for
+        if schedule.is_within_schedule(release) {
+            Some(release)
+        } else {
+            Some(open)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window() -> SendWindow {
+        serde_json::from_value(serde_json::json!({
+            "dow": "Mon,Tue,Wed,Thu,Fri",
+            "tz": "America/Phoenix",
+            "start": "09:00:00",
+            "end": "17:00:00",
+            "ramp": "30m",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn release_time() {
+        let window = window();
+
+        // Wednesday 2024-01-10 12:00 in Phoenix (UTC-7) is within the window
+        let now = Utc.with_ymd_and_hms(2024, 1, 10, 19, 0, 0).unwrap();
+        assert_eq!(window.release_time(now, 0.5), None);
+
+        // Wednesday 18:00 in Phoenix: held until Thursday 09:00
+        let now = Utc.with_ymd_and_hms(2024, 1, 11, 1, 0, 0).unwrap();
+        let open = Utc.with_ymd_and_hms(2024, 1, 11, 16, 0, 0).unwrap();
+        assert_eq!(window.release_time(now, 0.0), Some(open));
+        assert_eq!(
+            window.release_time(now, 0.5),
+            Some(open + chrono::Duration::minutes(15))
+        );
+
+        // Saturday is not permitted: held until Monday 09:00
+        let now = Utc.with_ymd_and_hms(2024, 1, 13, 19, 0, 0).unwrap();
+        let open = Utc.with_ymd_and_hms(2024, 1, 15, 16, 0, 0).unwrap();
+        assert_eq!(window.release_time(now, 0.0), Some(open));
+    }
+}
@@ -1,14 +1,17 @@
 use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::header_rewrite::RewriteStage;
+use crate::http_server::admin_mx_pin_v1::AdminMxPinEntry;
 use crate::http_server::admin_trace_smtp_client_v1::{
     SmtpClientTraceEventPayload, SmtpClientTracerImpl,
 };
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
 use crate::queue::{IncrementAttempts, QueueManager, QueueState};
-use crate::ready_queue::{Dispatcher, QueueDispatcher};
+use crate::ready_queue::{site_name_of, Dispatcher, QueueDispatcher, READYQ_RUNTIME};
 use crate::spool::SpoolManager;
 use anyhow::Context;
 use async_trait::async_trait;
-use config::{load_config, CallbackSignature};
+use chrono::{DateTime, Utc};
+use config::{load_config, CallbackSignature, SerdeWrappedValue};
 use dns_resolver::{resolve_a_or_aaaa, ResolvedMxAddresses};
 use kumo_address::socket::SocketAddress;
 use kumo_api_types::egress_path::{EgressPathConfig, Tls};
@@ -19,6 +22,7 @@ use lruttl::LruCacheWithTtl;
 use message::message::QueueNameComponents;
 use message::Message;
 use mta_sts::policy::PolicyMode;
+use prometheus::IntCounterVec;
 use rfc5321::{
     ClientError, EnhancedStatusCode, ForwardPath, Response, ReversePath, SmtpClient,
     TlsInformation, TlsOptions, TlsStatus,
@@ -32,6 +36,107 @@ use tracing::Level;
 static BROKEN_TLS_BY_SITE: LazyLock<LruCacheWithTtl<String, ()>> =
     LazyLock::new(|| LruCacheWithTtl::new_named("smtp_dispatcher_broken_tls", 64 * 1024));
 
+static TLS_SESSIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "smtp_client_tls_sessions",
+        "total number of successful outbound TLS handshakes, by site and \
+         whether an earlier session was resumed",
+        &["site", "resumed"]
+    )
+    .unwrap()
+});
+
+static MX_PINNED_PLANS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "smtp_client_mx_pinned_plans",
+        "total number of connection plans that were restricted to a host \
+         pinned via the mx-pin admin API, by site",
+        &["site"]
+    )
+    .unwrap()
+});
+
+static NEXT_HOST_FAILOVERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "smtp_client_next_host_failovers",
+        "total number of messages that were immediately retried against \
+         the next candidate host due to try_next_host_on_response_codes, by site",
+        &["site"]
+    )
+    .unwrap()
+});
+
+fn record_tls_session(queue_name: &str, info: &TlsInformation) {
+    TLS_SESSIONS
+        .with_label_values(&[
+            site_name_of(queue_name).unwrap_or(queue_name),
+            if info.session_resumed {
+                "true"
+            } else {
+                "false"
+            },
+        ])
+        .inc();
+}
+
+/// Describes an established outbound SMTP session.
+/// It is passed to the smtp_client_connected and
+/// smtp_client_disconnected events.
+#[derive(Serialize, Debug, Clone)]
+pub struct SmtpClientSession {
+    pub session_id: String,
+    pub site: String,
+    pub ready_queue: String,
+    pub egress_pool: String,
+    pub egress_source: String,
+    pub source_address: MaybeProxiedSourceAddress,
+    pub mx_host: String,
+    pub mx_address: ResolvedAddress,
+    /// The initial 220 banner, as a single line
+    pub banner: String,
+    /// Information about the TLS session, if STARTTLS was used
+    pub tls: Option<TlsInformation>,
+    pub connected_at: DateTime<Utc>,
+}
+
+impl SmtpClientSession {
+    async fn connected(&self) {
+        let sig = CallbackSignature::<SerdeWrappedValue<SmtpClientSession>, ()>::new(
+            "smtp_client_connected",
+        );
+        let result = async {
+            let mut config = load_config().await?;
+            config
+                .async_call_callback(&sig, SerdeWrappedValue(self.clone()))
+                .await?;
+            config.put();
+            anyhow::Result::<()>::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!("smtp_client_connected event failed: {err:#}");
+        }
+    }
+
+    async fn disconnected(self, reason: &str) {
+        let sig = CallbackSignature::<(SerdeWrappedValue<SmtpClientSession>, String), ()>::new(
+            "smtp_client_disconnected",
+        );
+        let result = async {
+            let mut config = load_config().await?;
+            config
+                .async_call_callback(&sig, (SerdeWrappedValue(self), reason.to_string()))
+                .await?;
+            config.put();
+            anyhow::Result::<()>::Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!("smtp_client_disconnected event failed: {err:#}");
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct SmtpProtocol {
     #[serde(default)]
@@ -58,6 +163,7 @@ pub struct SmtpDispatcher {
     ehlo_name: String,
     tls_info: Option<TlsInformation>,
     tracer: Arc<SmtpClientTracerImpl>,
+    session: Option<SmtpClientSession>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -100,7 +206,7 @@ impl SmtpDispatcher {
             },
         };
 
-        let addresses = if proto_config.mx_list.is_empty() {
+        let mut addresses = if proto_config.mx_list.is_empty() {
             dispatcher
                 .mx
                 .as_ref()
@@ -126,12 +232,48 @@ impl SmtpDispatcher {
             ResolvedMxAddresses::Addresses(addresses)
         };
 
+        // Apply any administrative pin, so that the plan consists solely
+        // of the pinned host. If the pinned host is not among the
+        // addresses, the pin is ignored rather than blocking delivery.
+        let site = site_name_of(&dispatcher.name).unwrap_or(&dispatcher.name);
+        let mut pinned = None;
+        if let (Some(pin), ResolvedMxAddresses::Addresses(addresses)) =
+            (AdminMxPinEntry::get_for_site(site), &mut addresses)
+        {
+            if addresses.iter().any(|addr| pin.matches(addr)) {
+                addresses.retain(|addr| pin.matches(addr));
+                MX_PINNED_PLANS.with_label_values(&[site]).inc();
+                pinned.replace(pin);
+            } else {
+                tracing::warn!(
+                    "{site} is pinned to {}, but that is not one of its addresses {addresses:?}; \
+                     ignoring the pin",
+                    pin.target
+                );
+            }
+        }
+        {
+            let mut states = dispatcher.states.lock();
+            match &pinned {
+                Some(pin) => {
+                    let context = format!("pinned to {}: {}", pin.target, pin.reason);
+                    if states.mx_pinned.as_ref().map(|state| &state.context) != Some(&context) {
+                        states.mx_pinned.replace(QueueState::new(context));
+                    }
+                }
+                None => {
+                    states.mx_pinned.take();
+                }
+            }
+        }
+
         let tracer = Arc::new(SmtpClientTracerImpl::new(serde_json::json!({
             "egress_pool": dispatcher.egress_pool.to_string(),
             "egress_source": dispatcher.egress_source.name.to_string(),
             "id": dispatcher.session_id.to_string(),
             "ready_queue_name": dispatcher.name.to_string(),
             "mx_plan": addresses.clone(),
+            "mx_pin": pinned.as_ref().map(|pin| &pin.target),
         })));
 
         tracing::trace!("mx resolved to {addresses:?}");
@@ -223,6 +365,7 @@ impl SmtpDispatcher {
             tls_info: None,
             source_address: None,
             tracer,
+            session: None,
         }))
     }
 
@@ -375,17 +518,18 @@ impl SmtpDispatcher {
                     .await
                     .context("reading banner")?;
                 if banner.code != 220 {
-                    return anyhow::Result::<(SmtpClient, MaybeProxiedSourceAddress)>::Err(
+                    return anyhow::Result::<(SmtpClient, MaybeProxiedSourceAddress, Response)>::Err(
                         ClientError::Rejected(banner).into(),
                     );
                 }
 
-                Ok((client, source_address))
+                Ok((client, source_address, banner))
             })
         };
 
         self.source_address.take();
-        let (mut client, source_address) = tokio::select! {
+        self.tls_info.take();
+        let (mut client, source_address, banner) = tokio::select! {
             _ = shutdown.shutting_down() => {
                 anyhow::bail!("shutting down");
             }
@@ -412,6 +556,11 @@ impl SmtpDispatcher {
         let openssl_cipher_list = path_config.openssl_cipher_list.clone();
         let openssl_cipher_suites = path_config.openssl_cipher_suites.clone();
         let rustls_cipher_suites = path_config.rustls_cipher_suites.clone();
+        let session_cache_key = path_config.tls_session_resumption.then(|| {
+            site_name_of(&dispatcher.name)
+                .unwrap_or(&dispatcher.name)
+                .to_string()
+        });
 
         if path_config.enable_dane {
             if let Some(mx) = &dispatcher.mx {
@@ -546,6 +695,7 @@ impl SmtpDispatcher {
                         openssl_cipher_list,
                         openssl_cipher_suites,
                         rustls_cipher_suites,
+                        session_cache_key,
                     })
                     .await?
                 {
@@ -573,6 +723,7 @@ impl SmtpDispatcher {
                     TlsStatus::Info(info) => {
                         // TLS is available
                         tracing::trace!("TLS: {info:?}");
+                        record_tls_session(&dispatcher.name, &info);
                         self.tls_info.replace(info);
                         (true, "OK".to_string())
                     }
@@ -619,6 +770,7 @@ impl SmtpDispatcher {
                         openssl_cipher_list,
                         openssl_cipher_suites,
                         rustls_cipher_suites,
+                        session_cache_key,
                     })
                     .await?
                 {
@@ -650,6 +802,7 @@ impl SmtpDispatcher {
                         self.tracer
                             .diagnostic(Level::INFO, || format!("TLS: {info:?}"));
                         tracing::trace!("TLS: {info:?}");
+                        record_tls_session(&dispatcher.name, &info);
                         self.tls_info.replace(info);
                     }
                 }
@@ -706,9 +859,30 @@ impl SmtpDispatcher {
                 })?;
         }
 
+        let session = SmtpClientSession {
+            session_id: dispatcher.session_id.to_string(),
+            site: site_name_of(&dispatcher.name)
+                .unwrap_or(&dispatcher.name)
+                .to_string(),
+            ready_queue: dispatcher.name.to_string(),
+            egress_pool: dispatcher.egress_pool.to_string(),
+            egress_source: dispatcher.egress_source.name.to_string(),
+            source_address: self
+                .source_address
+                .clone()
+                .expect("source_address was set above"),
+            mx_host: address.name.to_string(),
+            mx_address: address.clone(),
+            banner: banner.to_single_line(),
+            tls: self.tls_info.clone(),
+            connected_at: Utc::now(),
+        };
+        session.connected().await;
+
         self.client
             .replace(connection_wrapper.map_connection(client));
         self.client_address.replace(address);
+        self.session.replace(session);
         dispatcher.delivered_this_connection = 0;
         Ok(())
     }
@@ -760,11 +934,28 @@ impl SmtpDispatcher {
     }
 }
 
+impl Drop for SmtpDispatcher {
+    fn drop(&mut self) {
+        // The connection is being torn down without having been
+        // closed via close_connection, for example, due to an error
+        if let Some(session) = self.session.take() {
+            READYQ_RUNTIME
+                .spawn("smtp_client_disconnected".to_string(), async move {
+                    session.disconnected("dropped").await;
+                })
+                .ok();
+        }
+    }
+}
+
 #[async_trait]
 impl QueueDispatcher for SmtpDispatcher {
     async fn close_connection(&mut self, _dispatcher: &mut Dispatcher) -> anyhow::Result<bool> {
         if let Some(mut client) = self.client.take() {
             client.send_command(&rfc5321::Command::Quit).await.ok();
+            if let Some(session) = self.session.take() {
+                session.disconnected("closed").await;
+            }
             // Close out this dispatcher and let the maintainer spawn
             // a new connection
             Ok(true)
@@ -800,9 +991,13 @@ impl QueueDispatcher for SmtpDispatcher {
         msg.load_meta_if_needed().await.context("loading meta")?;
         msg.load_data_if_needed().await.context("loading data")?;
 
-        let data = msg.get_data();
-        let sender: ReversePath = msg
-            .sender()?
+        let data = match crate::header_rewrite::rewrite(RewriteStage::Dispatch, &msg)
+            .context("applying header rewrite rules")?
+        {
+            Some(data) => Arc::new(data.into_boxed_slice()),
+            None => msg.get_data(),
+        };
+        let sender: ReversePath = crate::batv::tag_sender(msg.sender()?)?
             .try_into()
             .map_err(|err| anyhow::anyhow!("{err}"))?;
         let recipient: ForwardPath = msg
@@ -838,11 +1033,29 @@ impl QueueDispatcher for SmtpDispatcher {
         self.tracer
             .submit(|| SmtpClientTraceEventPayload::MessageObtained);
 
+        // Pass on any DSN parameters that were provided at reception,
+        // but only if the peer can accept them
+        let (mail_parameters, rcpt_parameters) =
+            if self.client.as_ref().unwrap().has_capability("DSN") {
+                (
+                    crate::dsn::mail_parameters(&msg),
+                    crate::dsn::rcpt_parameters(&msg),
+                )
+            } else {
+                (vec![], vec![])
+            };
+
+        let max_bandwidth = dispatcher.path_config.borrow().max_bandwidth;
+        self.client.as_mut().unwrap().set_data_pacer(
+            crate::bandwidth::BandwidthPacer::for_message(&dispatcher.name, max_bandwidth, &msg)
+                .map(|pacer| pacer as _),
+        );
+
         match self
             .client
             .as_mut()
             .unwrap()
-            .send_mail(sender, recipient, &*data)
+            .send_mail_with_parameters(sender, mail_parameters, recipient, rcpt_parameters, &*data)
             .await
         {
             Err(ClientError::Rejected(mut response)) => {
@@ -885,6 +1098,45 @@ impl QueueDispatcher for SmtpDispatcher {
                     }
                 }
 
+                if !self.addresses.is_empty()
+                    && dispatcher
+                        .path_config
+                        .borrow()
+                        .try_next_host_on_response_codes
+                        .contains(&response.code)
+                {
+                    // Leave the message in the dispatcher so that it
+                    // is sent via the next candidate host, without
+                    // consuming a retry attempt
+                    tracing::debug!(
+                        "{} {:?} responded with {response:?}, trying the next host",
+                        dispatcher.name,
+                        self.client_address,
+                    );
+                    if let Some(msg) = dispatcher.msgs.last() {
+                        self.log_disposition(
+                            dispatcher,
+                            RecordType::TransientFailure,
+                            msg.clone(),
+                            response.clone(),
+                        )
+                        .await;
+                    }
+                    dispatcher.metrics.inc_transfail();
+                    NEXT_HOST_FAILOVERS
+                        .with_label_values(&[
+                            site_name_of(&dispatcher.name).unwrap_or(&dispatcher.name)
+                        ])
+                        .inc();
+                    if let Some(mut client) = self.client.take() {
+                        client.send_command(&rfc5321::Command::Quit).await.ok();
+                    }
+                    if let Some(session) = self.session.take() {
+                        session.disconnected("try_next_host").await;
+                    }
+                    return Ok(());
+                }
+
                 if response.code == 503 || (response.code >= 300 && response.code < 400) {
                     // 503 is a "permanent" failure response but it indicates
                     // that there was a protocol synchronization issue.
@@ -1,4 +1,5 @@
 use crate::delivery_metrics::MetricsWrappedConnection;
+use crate::dsn::{DsnMailParams, DsnRcptParams};
 use crate::http_server::admin_trace_smtp_server_v1::{
     SmtpServerTraceEvent, SmtpServerTraceEventPayload, SmtpServerTraceManager,
 };
@@ -16,15 +17,18 @@ use data_encoding::BASE64;
 use data_loader::KeySource;
 use kumo_log_types::ResolvedAddress;
 use kumo_prometheus::AtomicCounter;
+use kumo_server_common::http_server::client_cert::TlsPeerIdentity;
 use kumo_server_lifecycle::{Activity, ShutdownSubcription};
 use kumo_server_runtime::{spawn, Runtime};
+use kumo_spf::{CheckHostParams, SpfDisposition};
 use mailparsing::ConformanceDisposition;
 use memchr::memmem::Finder;
+use message::data_file::DataFile;
 use message::{EnvelopeAddress, Message};
 use mlua::prelude::LuaUserData;
 use mlua::{FromLuaMulti, IntoLuaMulti, LuaSerdeExt, UserData, UserDataMethods};
 use parking_lot::FairMutex as Mutex;
-use prometheus::{Histogram, HistogramTimer};
+use prometheus::{Histogram, HistogramTimer, IntCounter};
 use rfc5321::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Response};
 use rustls::ServerConfig;
 use serde::{Deserialize, Serialize};
@@ -33,6 +37,7 @@ use spool::SpoolId;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock, OnceLock};
 use std::time::Duration;
@@ -68,6 +73,14 @@ static READ_DATA_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
     )
     .unwrap()
 });
+static DATA_SPOOLED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "smtpsrv_data_spooled",
+        "total number of incoming messages whose DATA exceeded \
+         data_spool_threshold and was spooled to disk during reception"
+    )
+    .unwrap()
+});
 static PROCESS_DATA_LATENCY: LazyLock<Histogram> = LazyLock::new(|| {
     prometheus::register_histogram!(
         "smtpsrv_process_data_duration",
@@ -102,6 +115,14 @@ pub struct EsmtpDomain {
     pub relay_to: bool,
     #[serde(default)]
     pub relay_from: CidrSet,
+    /// Permit relaying from this domain when the client presents a
+    /// verified TLS certificate with one of these identities
+    #[serde(default)]
+    pub relay_from_tls_identities: Vec<String>,
+    /// Permit relaying from this domain when the SPF check of the
+    /// MAIL FROM address passes for the client
+    #[serde(default)]
+    pub relay_from_spf_pass: bool,
 
     // Deprecated and no longer used
     #[serde(default = "default_ttl", with = "duration_serde")]
@@ -202,6 +223,8 @@ pub struct EsmtpListenerParams {
     pub tls_certificate: Option<KeySource>,
     #[serde(default)]
     pub tls_private_key: Option<KeySource>,
+    #[serde(default)]
+    pub tls_client_ca_certificate: Option<KeySource>,
 
     #[serde(default)]
     pub deferred_spool: bool,
@@ -212,6 +235,9 @@ pub struct EsmtpListenerParams {
     #[serde(default)]
     pub trace_headers: TraceHeaders,
 
+    #[serde(default)]
+    pub greylist: bool,
+
     #[serde(
         default = "EsmtpListenerParams::default_client_timeout",
         with = "duration_serde"
@@ -241,6 +267,15 @@ pub struct EsmtpListenerParams {
     #[serde(default = "EsmtpListenerParams::default_data_buffer_size")]
     data_buffer_size: usize,
 
+    /// When the DATA of a message being received exceeds this size,
+    /// the rest of it is written to a temporary file in
+    /// data_spool_directory, rather than being buffered in memory
+    #[serde(default)]
+    data_spool_threshold: Option<usize>,
+
+    #[serde(default)]
+    data_spool_directory: Option<PathBuf>,
+
     #[serde(default)]
     invalid_line_endings: ConformanceDisposition,
 
@@ -297,10 +332,12 @@ impl EsmtpListenerParams {
             return Ok(TlsAcceptor::from(config.clone()));
         }
 
-        let config = kumo_server_common::tls_helpers::make_server_config(
+        let config = kumo_server_common::tls_helpers::make_server_config_with_client_auth(
             &self.hostname,
             &self.tls_private_key,
             &self.tls_certificate,
+            &self.tls_client_ca_certificate,
+            false,
         )
         .await?;
 
@@ -324,19 +361,43 @@ impl EsmtpListenerParams {
         // Pre-create the acceptor so that we can share it across
         // the various listeners
         self.build_tls_acceptor().await?;
+        if self.data_spool_threshold.is_some() {
+            let dir = self.data_spool_directory.as_ref().ok_or_else(|| {
+                anyhow!("data_spool_directory must be set when data_spool_threshold is set")
+            })?;
+            tokio::fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("creating data_spool_directory {}", dir.display()))?;
+        }
         self.connection_gauge();
 
-        let listener = TcpListener::bind(&self.listen)
-            .await
-            .with_context(|| format!("failed to bind to {}", self.listen))?;
+        let listener = match kumo_server_common::listeners::take_inherited_listener(&self.listen)? {
+            Some(listener) => TcpListener::from_std(listener)?,
+            None => TcpListener::bind(&self.listen)
+                .await
+                .with_context(|| format!("failed to bind to {}", self.listen))?,
+        };
 
         let addr = listener.local_addr()?;
+        kumo_server_common::listeners::register_active_listener(&listener)?;
         tracing::info!("smtp listener on {addr:?}");
 
         let mut shutting_down = ShutdownSubcription::get();
         let connection_limiter = Arc::new(tokio::sync::Semaphore::new(self.max_connections));
         spawn(format!("esmtp_listener {addr:?}"), async move {
             let denied = self.connection_denied_counter();
+            if kumo_server_common::handoff::takeover_deadline().is_some() {
+                // Rather than telling clients to wait for spool startup,
+                // leave them in the listen queue until we have taken
+                // over the spool from the previous instance
+                tokio::select! {
+                    _ = shutting_down.shutting_down() => {
+                        tracing::info!("smtp listener on {addr:?} -> stopping");
+                        return Ok::<(), anyhow::Error>(());
+                    }
+                    _ = SpoolManager::get().wait_for_spool_started() => {}
+                }
+            }
             loop {
                 tokio::select! {
                     _ = shutting_down.shutting_down() => {
@@ -475,6 +536,7 @@ pub struct SmtpServer {
     peer_address: SocketAddr,
     my_address: SocketAddr,
     tls_active: bool,
+    tls_peer_identity: TlsPeerIdentity,
     read_buffer: DebugabbleReadBuffer,
     params: EsmtpListenerParams,
     shutdown: ShutdownSubcription,
@@ -486,12 +548,14 @@ pub struct SmtpServer {
     reception_count: AtomicCounter,
     session_id: Uuid,
     domains: HashMap<String, Option<EsmtpDomain>>,
+    spf_mail_from: Option<(String, SpfDisposition)>,
 }
 
 #[derive(Debug)]
 struct TransactionState {
     sender: EnvelopeAddress,
-    recipients: Vec<EnvelopeAddress>,
+    dsn_params: DsnMailParams,
+    recipients: Vec<(EnvelopeAddress, DsnRcptParams)>,
     _timer: HistogramTimer,
 }
 
@@ -538,6 +602,7 @@ impl SmtpServer {
             peer_address,
             my_address,
             tls_active: false,
+            tls_peer_identity: TlsPeerIdentity::default(),
             read_buffer: DebugabbleReadBuffer(Vec::with_capacity(1024)),
             params,
             shutdown: ShutdownSubcription::get(),
@@ -551,6 +616,7 @@ impl SmtpServer {
             ),
             session_id: Uuid::new_v4(),
             domains: HashMap::new(),
+            spf_mail_from: None,
         };
 
         server.params.connection_gauge().inc();
@@ -659,6 +725,62 @@ impl SmtpServer {
         Ok(value)
     }
 
+    /// Records the identity of the verified client certificate,
+    /// and exposes it via the connection metadata
+    fn set_tls_peer_identity(&mut self, der: &[u8]) {
+        match TlsPeerIdentity::from_der(der) {
+            Ok(identity) => {
+                if let Some(cn) = &identity.common_name {
+                    self.meta.set_meta("tls_client_common_name", cn.clone());
+                }
+                if !identity.subject_alt_names.is_empty() {
+                    self.meta.set_meta(
+                        "tls_client_subject_alt_names",
+                        identity.subject_alt_names.clone(),
+                    );
+                }
+                self.tls_peer_identity = identity;
+            }
+            Err(err) => {
+                tracing::error!("failed to parse client certificate: {err:#}");
+            }
+        }
+    }
+
+    /// Evaluates SPF for the MAIL FROM address and the client,
+    /// recording the result in the connection metadata.
+    /// The result is cached for the sender domain, as check_relaying
+    /// is called for each recipient.
+    async fn check_spf_mail_from(&mut self, sender: &EnvelopeAddress) -> SpfDisposition {
+        let domain = sender.domain();
+        if let Some((cached_domain, disposition)) = &self.spf_mail_from {
+            if cached_domain.eq_ignore_ascii_case(domain) {
+                return *disposition;
+            }
+        }
+
+        let resolver = dns_resolver::get_resolver();
+        let result = CheckHostParams {
+            domain: domain.to_string(),
+            sender: Some(sender.to_string()),
+            client_ip: self.peer_address.ip(),
+        }
+        .check(&**resolver)
+        .await;
+
+        tracing::debug!(
+            "check_spf_mail_from: {} {}: {} {}",
+            sender.to_string(),
+            self.peer_address.ip(),
+            result.disposition,
+            result.context
+        );
+        self.meta
+            .set_meta("spf_mail_from", result.disposition.as_str());
+        self.spf_mail_from = Some((domain.to_string(), result.disposition));
+        result.disposition
+    }
+
     async fn check_relaying(
         &mut self,
         sender: &EnvelopeAddress,
@@ -670,7 +792,15 @@ impl SmtpServer {
         let mut relay_from_allowed = false;
 
         if let Some(dom) = self.lookup_listener_domain(&sender_domain).await? {
-            relay_from_allowed = self.peer_in_cidr_list(&dom.relay_from);
+            relay_from_allowed = self.peer_in_cidr_list(&dom.relay_from)
+                || self.tls_peer_identity.identities().any(|id| {
+                    dom.relay_from_tls_identities
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(id))
+                });
+            if !relay_from_allowed && dom.relay_from_spf_pass {
+                relay_from_allowed = self.check_spf_mail_from(sender).await == SpfDisposition::Pass;
+            }
         }
 
         let recipient_domain = recipient.domain();
@@ -734,7 +864,7 @@ impl SmtpServer {
                 let mut recipient = None;
                 if let Some(state) = &self.state {
                     sender.replace(state.sender.to_string());
-                    recipient = state.recipients.last().map(|r| r.to_string());
+                    recipient = state.recipients.last().map(|(r, _)| r.to_string());
                 }
 
                 log_rejection(LogRejection {
@@ -790,59 +920,29 @@ impl SmtpServer {
 
     #[instrument(skip(self))]
     async fn read_data(&mut self) -> anyhow::Result<ReadData> {
-        let mut too_big = false;
         tracing::trace!("reading data");
 
-        static CRLFDOTCRLF: LazyLock<Finder> = LazyLock::new(|| Finder::new("\r\n.\r\n"));
         let mut data = DebugabbleReadBuffer(vec![0u8; self.params.data_buffer_size]);
-        let mut next_index = 0;
+        let mut reader = DataReader::default();
 
         loop {
-            if let Some(i) = CRLFDOTCRLF.find(&self.read_buffer[next_index..]) {
-                let i = i + next_index;
-
-                if too_big {
-                    self.read_buffer.drain(0..i + 5);
-                    SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
-                        conn_meta: self.meta.clone_inner(),
-                        payload: SmtpServerTraceEventPayload::Diagnostic {
-                            level: Level::ERROR,
-                            message: "Data too big".to_string(),
-                        },
-                        when: Utc::now(),
-                    });
-                    return Ok(ReadData::TooBig);
-                }
-
-                let mut tail = self.read_buffer.split_off(i + 2);
-                std::mem::swap(&mut tail, &mut self.read_buffer);
-                self.read_buffer.drain(0..3);
-
-                let data = unstuff(tail);
-
-                if !check_line_lengths(&data, self.params.line_length_hard_limit) {
+            if let Some(result) = reader.advance(&mut self.read_buffer, &self.params).await? {
+                let diagnostic = match &result {
+                    ReadData::TooBig => Some("Data too big"),
+                    ReadData::TooLong => Some("Line too long"),
+                    _ => None,
+                };
+                if let Some(message) = diagnostic {
                     SmtpServerTraceManager::submit(|| SmtpServerTraceEvent {
                         conn_meta: self.meta.clone_inner(),
                         payload: SmtpServerTraceEventPayload::Diagnostic {
                             level: Level::ERROR,
-                            message: "Line too long".to_string(),
+                            message: message.to_string(),
                         },
                         when: Utc::now(),
                     });
-                    return Ok(ReadData::TooLong);
                 }
-
-                tracing::trace!("returning ReadData::Data {:?}", DebugPrintBuffer(&data));
-                return Ok(ReadData::Data(data));
-            }
-
-            tracing::trace!("read_buffer len is {}", self.read_buffer.len());
-            let buf_len = self.read_buffer.len();
-            next_index = buf_len.saturating_sub(5);
-            if buf_len >= self.params.max_message_size {
-                too_big = true;
-                self.read_buffer.drain(0..next_index);
-                next_index = 0;
+                return Ok(result);
             }
 
             // Didn't find terminator, fill up the buffer
@@ -1083,6 +1183,24 @@ impl SmtpServer {
             .await?;
             return Ok(());
         }
+        if let Some((code, message)) = crate::http_server::admin_drain_v1::check_reject() {
+            self.write_response(code, message, None).await?;
+            return Ok(());
+        }
+        if spool::quota::is_over_soft_quota() {
+            self.params.connection_denied_counter().inc();
+
+            self.write_response(
+                421,
+                format!(
+                    "4.3.2 {} spool quota exceeded. Try later",
+                    self.params.hostname
+                ),
+                None,
+            )
+            .await?;
+            return Ok(());
+        }
 
         if !SpoolManager::get().spool_started() {
             // We don't bump the connection_denied_counter here, because
@@ -1181,6 +1299,14 @@ impl SmtpServer {
                     {
                         Ok(stream) => {
                             self.tls_active = true;
+                            if let Some(cert) = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|certs| certs.first())
+                            {
+                                self.set_tls_peer_identity(cert.as_ref());
+                            }
                             Box::new(stream)
                         }
                         Err((err, stream)) => {
@@ -1365,7 +1491,7 @@ impl SmtpServer {
                         continue;
                     }
 
-                    let mut extensions = vec!["PIPELINING", "ENHANCEDSTATUSCODES"];
+                    let mut extensions = vec!["PIPELINING", "ENHANCEDSTATUSCODES", "DSN"];
                     if !self.tls_active {
                         extensions.push("STARTTLS");
                     } else {
@@ -1406,7 +1532,7 @@ impl SmtpServer {
                 }
                 Ok(Command::MailFrom {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
                     if self.state.is_some() {
                         self.write_response(
@@ -1418,6 +1544,35 @@ impl SmtpServer {
                         continue;
                     }
 
+                    if spool::quota::is_over_soft_quota() {
+                        self.write_response(
+                            452,
+                            "4.3.1 spool quota exceeded. Try later",
+                            Some(line),
+                        )
+                        .await?;
+                        continue;
+                    }
+
+                    if let Some((code, message)) =
+                        crate::http_server::admin_drain_v1::check_reject()
+                    {
+                        self.write_response(code, message, Some(line)).await?;
+                        if code == 421 {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+
+                    let dsn_params = match DsnMailParams::parse(&parameters) {
+                        Ok(params) => params,
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err:#}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
+
                     let address = EnvelopeAddress::parse(&address.to_string())?;
                     if let Err(rej) = self
                         .call_callback::<(), _, _>(
@@ -1433,6 +1588,7 @@ impl SmtpServer {
 
                     self.state.replace(TransactionState {
                         sender: address.clone(),
+                        dsn_params,
                         recipients: vec![],
                         _timer: TXN_LATENCY.start_timer(),
                     });
@@ -1441,7 +1597,7 @@ impl SmtpServer {
                 }
                 Ok(Command::RcptTo {
                     address,
-                    parameters: _,
+                    parameters,
                 }) => {
                     if self.state.is_none() {
                         self.write_response(
@@ -1452,9 +1608,26 @@ impl SmtpServer {
                         .await?;
                         continue;
                     }
+                    let dsn_params = match DsnRcptParams::parse(&parameters) {
+                        Ok(params) => params,
+                        Err(err) => {
+                            self.write_response(501, format!("5.5.4 {err:#}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
                     let address = EnvelopeAddress::parse(&address.to_string())?;
 
                     let sender = self.state.as_ref().unwrap().sender.clone();
+                    let address = match crate::batv::check_recipient(&sender, &address) {
+                        Ok(Some(untagged)) => untagged,
+                        Ok(None) => address,
+                        Err(reason) => {
+                            self.write_response(550, format!("5.7.1 {reason}"), Some(line))
+                                .await?;
+                            continue;
+                        }
+                    };
                     let relay_disposition = self.check_relaying(&sender, &address).await?;
 
                     if !relay_disposition.accept_rcpt_to() {
@@ -1505,6 +1678,18 @@ impl SmtpServer {
                         .await?;
                         continue;
                     }
+                    if crate::suppression::check_recipient(
+                        crate::suppression::SuppressionStage::Reception,
+                        &address,
+                    )
+                    .await
+                    .is_some()
+                    {
+                        self.write_response(550, "5.7.1 recipient is suppressed", Some(line))
+                            .await?;
+                        continue;
+                    }
+
                     self.rcpt_count += 1;
                     if let Err(rej) = self
                         .call_callback::<(), _, _>(
@@ -1517,13 +1702,32 @@ impl SmtpServer {
                             .await?;
                         continue;
                     }
+                    if self.params.greylist
+                        && self.authentication_id.is_none()
+                        && !self.peer_in_cidr_list(&self.params.relay_hosts)
+                    {
+                        let sender = &self.state.as_ref().expect("checked state above").sender;
+                        if let Some(decision) =
+                            crate::greylist::check(self.peer_address.ip(), sender, &address).await
+                        {
+                            if !decision.accepted() {
+                                self.write_response(
+                                    451,
+                                    "4.7.1 Greylisted, please try again later",
+                                    Some(line),
+                                )
+                                .await?;
+                                continue;
+                            }
+                        }
+                    }
                     self.write_response(250, format!("OK {address:?}"), None)
                         .await?;
                     self.state
                         .as_mut()
                         .expect("checked state above")
                         .recipients
-                        .push(address);
+                        .push((address, dsn_params));
                 }
                 Ok(Command::Data) => {
                     if self.state.is_none() {
@@ -1604,7 +1808,7 @@ impl SmtpServer {
         }
     }
 
-    async fn process_data(&mut self, mut data: Vec<u8>) -> anyhow::Result<()> {
+    async fn process_data(&mut self, mut data: ReceivedData) -> anyhow::Result<()> {
         self.reception_count.inc();
         self.global_reception_count.inc();
         let state = self
@@ -1614,7 +1818,10 @@ impl SmtpServer {
 
         tracing::trace!(?state);
 
-        let lone_lf = mailparsing::has_lone_cr_or_lf(&data);
+        let lone_lf = match &data {
+            ReceivedData::Memory(data) => mailparsing::has_lone_cr_or_lf(data),
+            ReceivedData::Spooled { lone_cr_or_lf, .. } => *lone_cr_or_lf,
+        };
         if lone_lf {
             match self.params.invalid_line_endings {
                 ConformanceDisposition::Deny => {
@@ -1646,7 +1853,10 @@ impl SmtpServer {
                         when: Utc::now(),
                     });
 
-                    mailparsing::normalize_crlf_in_place(&mut data);
+                    // Spooled data was fixed as it was written
+                    if let ReceivedData::Memory(data) = &mut data {
+                        mailparsing::normalize_crlf_in_place(data);
+                    }
                 }
             }
         }
@@ -1661,42 +1871,58 @@ impl SmtpServer {
 
         let datestamp = Utc::now().to_rfc2822();
 
-        for recip in state.recipients {
+        for (recip, rcpt_dsn_params) in state.recipients {
             let id = SpoolId::new();
             let protocol = "ESMTP"; // FIXME: update SmtpServer ctor if we change this.
                                     // OR: just read this from self.meta?
 
-            let mut body = if self.params.trace_headers.received_header {
-                let received = {
-                    let from_domain = self.said_hello.as_deref().unwrap_or("unspecified");
-                    let peer_address = self.peer_address.ip();
-                    let my_address = self.my_address.ip();
-                    let hostname = &self.params.hostname;
-                    let recip = recip.to_string();
-                    format!(
-                        "Received: from {from_domain} ({peer_address})\r\n  \
-                                       by {hostname} (KumoMTA {my_address}) \r\n  \
-                                       with {protocol} id {id} for <{recip}>;\r\n  \
-                                       {datestamp}\r\n"
-                    )
-                };
-
-                let mut body = Vec::with_capacity(data.len() + received.len());
-                body.extend_from_slice(received.as_bytes());
-                body
+            let received = if self.params.trace_headers.received_header {
+                let from_domain = self.said_hello.as_deref().unwrap_or("unspecified");
+                let peer_address = self.peer_address.ip();
+                let my_address = self.my_address.ip();
+                let hostname = &self.params.hostname;
+                let recip = recip.to_string();
+                format!(
+                    "Received: from {from_domain} ({peer_address})\r\n  \
+                                   by {hostname} (KumoMTA {my_address}) \r\n  \
+                                   with {protocol} id {id} for <{recip}>;\r\n  \
+                                   {datestamp}\r\n"
+                )
             } else {
-                Vec::with_capacity(data.len())
+                String::new()
             };
 
-            body.extend_from_slice(&data);
-
-            let message = Message::new_dirty(
-                id,
-                state.sender.clone(),
-                recip,
-                self.meta.clone_inner(),
-                Arc::new(body.into_boxed_slice()),
-            )?;
+            let message = match &data {
+                ReceivedData::Memory(data) => {
+                    let mut body = Vec::with_capacity(data.len() + received.len());
+                    body.extend_from_slice(received.as_bytes());
+                    body.extend_from_slice(data);
+
+                    Message::new_dirty(
+                        id,
+                        state.sender.clone(),
+                        recip,
+                        self.meta.clone_inner(),
+                        Arc::new(body.into_boxed_slice()),
+                    )?
+                }
+                // The data remains on disk unless the policy
+                // needs to examine it
+                ReceivedData::Spooled { file, .. } => Message::new_dirty_from_file(
+                    id,
+                    state.sender.clone(),
+                    recip,
+                    self.meta.clone_inner(),
+                    received.into_bytes(),
+                    Arc::clone(file),
+                )?,
+            };
+            state.dsn_params.apply_to_message(&message)?;
+            rcpt_dsn_params.apply_to_message(&message)?;
+            // Applied before smtp_server_message_received, so that
+            // the rewritten headers are covered by any DKIM signature
+            // that is added by that event
+            crate::header_rewrite::apply_at_reception(&message)?;
 
             if self.params.deferred_queue {
                 message.set_meta("queue", DEFERRED_QUEUE_NAME)?;
@@ -1761,7 +1987,13 @@ impl SmtpServer {
                 }
             }
 
-            if relay_disposition.log_arf && matches!(message.parse_rfc5965(), Ok(Some(_))) {
+            let feedback_report = if relay_disposition.log_arf {
+                message.parse_rfc5965().ok().flatten()
+            } else {
+                None
+            };
+
+            if feedback_report.is_some() {
                 was_arf_or_oob = true;
             } else if relay_disposition.log_oob && matches!(message.parse_rfc3464(), Ok(Some(_))) {
                 was_arf_or_oob = true;
@@ -1791,6 +2023,9 @@ impl SmtpServer {
                 session_id: Some(self.session_id),
             })
             .await;
+            if let Some(report) = feedback_report {
+                crate::feedback::dispatch_feedback_report(&message, report).await;
+            }
             if queue_name != "null" {
                 if relay_disposition.relay {
                     messages.push((queue_name, message));
@@ -1912,9 +2147,8 @@ enum ReadLine {
     Disconnected,
 }
 
-#[derive(PartialEq)]
 enum ReadData {
-    Data(Vec<u8>),
+    Data(ReceivedData),
     TooLong,
     TooBig,
     ShuttingDown,
@@ -1922,19 +2156,295 @@ enum ReadData {
     Disconnected,
 }
 
-fn unstuff(data: Vec<u8>) -> Vec<u8> {
-    static CRLFDOTDOT: LazyLock<Finder> = LazyLock::new(|| Finder::new("\r\n.."));
-    let mut stuffing_finder = CRLFDOTDOT.find_iter(&data);
-    if let Some(stuffed) = stuffing_finder.next() {
-        let mut unstuffed = Vec::with_capacity(data.len());
-        unstuffed.extend_from_slice(&data[0..stuffed + 3]);
-        let mut last_pos = stuffed + 4;
-        while let Some(stuffed) = stuffing_finder.next() {
-            unstuffed.extend_from_slice(&data[last_pos..stuffed + 3]);
-            last_pos = stuffed + 4;
+/// The DATA of a message, as returned by read_data
+enum ReceivedData {
+    Memory(Vec<u8>),
+    /// The DATA was written to a file because it exceeded
+    /// data_spool_threshold. It has already been unstuffed, and
+    /// its line endings have been fixed if invalid_line_endings
+    /// is set to Fix.
+    Spooled {
+        file: Arc<DataFile>,
+        lone_cr_or_lf: bool,
+    },
+}
+
+/// Tracks the progress of read_data through the DATA of a message.
+/// Once the DATA exceeds data_spool_threshold, it is moved out of
+/// the read buffer and into a SpooledData as it is read.
+#[derive(Default)]
+struct DataReader {
+    /// Where to resume searching the read buffer for the terminator
+    next_index: usize,
+    too_big: bool,
+    spooled: Option<SpooledData>,
+}
+
+impl DataReader {
+    /// Examines read_buffer, returning the outcome once the terminator
+    /// has been found, or None if more data needs to be read into it
+    async fn advance(
+        &mut self,
+        read_buffer: &mut Vec<u8>,
+        params: &EsmtpListenerParams,
+    ) -> anyhow::Result<Option<ReadData>> {
+        static CRLFDOTCRLF: LazyLock<Finder> = LazyLock::new(|| Finder::new("\r\n.\r\n"));
+
+        if let Some(i) = CRLFDOTCRLF.find(&read_buffer[self.next_index..]) {
+            let i = i + self.next_index;
+
+            if self.too_big {
+                read_buffer.drain(0..i + 5);
+                return Ok(Some(ReadData::TooBig));
+            }
+
+            let mut tail = read_buffer.split_off(i + 2);
+            std::mem::swap(&mut tail, read_buffer);
+            read_buffer.drain(0..3);
+
+            if let Some(spooled) = self.spooled.take() {
+                return spooled.finish(&tail).await.map(Some);
+            }
+
+            let data = unstuff(tail);
+
+            if !check_line_lengths(&data, params.line_length_hard_limit) {
+                return Ok(Some(ReadData::TooLong));
+            }
+
+            tracing::trace!("returning ReadData::Data {:?}", DebugPrintBuffer(&data));
+            return Ok(Some(ReadData::Data(ReceivedData::Memory(data))));
+        }
+
+        tracing::trace!("read_buffer len is {}", read_buffer.len());
+        let buf_len = read_buffer.len();
+        self.next_index = buf_len.saturating_sub(5);
+        let spooled_len = self.spooled.as_ref().map(|s| s.len).unwrap_or(0);
+        if buf_len + spooled_len >= params.max_message_size {
+            self.too_big = true;
+            self.spooled.take();
+            read_buffer.drain(0..self.next_index);
+            self.next_index = 0;
+        } else if let (Some(threshold), Some(dir)) =
+            (params.data_spool_threshold, &params.data_spool_directory)
+        {
+            if buf_len >= threshold.max(5) {
+                // Keep the last 4 bytes in memory, as they may be
+                // the start of the terminator
+                let keep = buf_len - 4;
+                if self.spooled.is_none() {
+                    let filter = DataFilter::new(
+                        params.line_length_hard_limit,
+                        matches!(params.invalid_line_endings, ConformanceDisposition::Fix),
+                    );
+                    match SpooledData::create(dir, filter).await {
+                        Ok(s) => {
+                            DATA_SPOOLED.inc();
+                            self.spooled.replace(s);
+                        }
+                        Err(err) => {
+                            tracing::error!("{err:#}; buffering DATA in memory instead");
+                        }
+                    }
+                }
+                if let Some(s) = self.spooled.as_mut() {
+                    s.append(&read_buffer[0..keep]).await?;
+                    read_buffer.drain(0..keep);
+                    self.next_index = 0;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Holds the DATA of a message that is being written to disk by
+/// read_data because it exceeded data_spool_threshold.
+/// The file is removed when this is dropped, unless it has been
+/// handed over to a DataFile by finish.
+struct SpooledData {
+    file: tokio::fs::File,
+    path: PathBuf,
+    filter: DataFilter,
+    buffer: Vec<u8>,
+    /// The number of bytes of DATA that have been appended
+    len: usize,
+    /// The number of bytes that have been written to the file
+    written: u64,
+}
+
+impl SpooledData {
+    async fn create(dir: &Path, filter: DataFilter) -> anyhow::Result<Self> {
+        let path = dir.join(format!("{}.data", Uuid::new_v4().simple()));
+        // The message may hold sensitive content, so make sure that
+        // it is only readable by us, whatever our umask may be
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await
+            .with_context(|| format!("creating {}", path.display()))?;
+        Ok(Self {
+            file,
+            path,
+            filter,
+            buffer: vec![],
+            len: 0,
+            written: 0,
+        })
+    }
+
+    async fn write_buffer(&mut self) -> anyhow::Result<()> {
+        self.file
+            .write_all(&self.buffer)
+            .await
+            .with_context(|| format!("writing to {}", self.path.display()))?;
+        self.written += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    async fn append(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.filter.process(data, &mut self.buffer);
+        self.write_buffer().await?;
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Appends tail, which is the remainder of the DATA, and returns
+    /// the outcome of reading it
+    async fn finish(mut self, tail: &[u8]) -> anyhow::Result<ReadData> {
+        self.filter.process(tail, &mut self.buffer);
+        self.filter.finish(&mut self.buffer);
+        self.write_buffer().await?;
+        self.file
+            .flush()
+            .await
+            .with_context(|| format!("writing to {}", self.path.display()))?;
+
+        if self.filter.line_too_long {
+            return Ok(ReadData::TooLong);
+        }
+
+        let path = std::mem::take(&mut self.path);
+        Ok(ReadData::Data(ReceivedData::Spooled {
+            file: Arc::new(DataFile::new(path, self.written)),
+            lone_cr_or_lf: self.filter.lone_cr_or_lf,
+        }))
+    }
+}
+
+impl Drop for SpooledData {
+    fn drop(&mut self) {
+        if self.path.as_os_str().is_empty() {
+            return;
         }
-        unstuffed.extend_from_slice(&data[last_pos..]);
-        return unstuffed;
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::error!("removing {}: {err:#}", self.path.display());
+        }
+    }
+}
+
+/// Applies the same transformations and checks to spooled DATA as
+/// are applied to DATA that is held in memory, one chunk at a time,
+/// so that the DATA never needs to be held in memory in its entirety.
+/// The result of unstuffing is checked with check_line_lengths and
+/// has_lone_cr_or_lf, and then has its line endings normalized if
+/// fix_line_endings is set.
+struct DataFilter {
+    line_length_limit: usize,
+    fix_line_endings: bool,
+    /// How much of the "\r\n.." stuffing sequence has been seen
+    stuffing: usize,
+    /// The last byte that was unstuffed
+    prev: Option<u8>,
+    /// The number of bytes that have been unstuffed
+    pos: usize,
+    /// The offset of the last CRLF
+    last_crlf: usize,
+    line_too_long: bool,
+    lone_cr_or_lf: bool,
+}
+
+impl DataFilter {
+    fn new(line_length_limit: usize, fix_line_endings: bool) -> Self {
+        Self {
+            line_length_limit,
+            fix_line_endings,
+            stuffing: 0,
+            prev: None,
+            pos: 0,
+            last_crlf: 0,
+            line_too_long: false,
+            lone_cr_or_lf: false,
+        }
+    }
+
+    fn process(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        out.reserve(data.len());
+        for &b in data {
+            if self.stuffing == 3 && b == b'.' {
+                self.stuffing = 0;
+                continue;
+            }
+            self.stuffing = match (b, self.stuffing) {
+                (b'\r', _) => 1,
+                (b'\n', 1) => 2,
+                (b'.', 2) => 3,
+                _ => 0,
+            };
+
+            let after_cr = self.prev == Some(b'\r');
+            if b == b'\n' && after_cr {
+                let idx = self.pos - 1;
+                if idx - self.last_crlf > self.line_length_limit {
+                    self.line_too_long = true;
+                }
+                self.last_crlf = idx;
+            } else if b == b'\n' || after_cr {
+                self.lone_cr_or_lf = true;
+                if self.fix_line_endings {
+                    out.push(if after_cr { b'\n' } else { b'\r' });
+                }
+            }
+
+            out.push(b);
+            self.prev = Some(b);
+            self.pos += 1;
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if self.prev == Some(b'\r') {
+            self.lone_cr_or_lf = true;
+            if self.fix_line_endings {
+                out.push(b'\n');
+            }
+        }
+        if self.pos - self.last_crlf > self.line_length_limit {
+            self.line_too_long = true;
+        }
+    }
+}
+
+/// Removes the dot stuffing from data. This is performed in place,
+/// as data may hold a large message.
+fn unstuff(mut data: Vec<u8>) -> Vec<u8> {
+    static CRLFDOTDOT: LazyLock<Finder> = LazyLock::new(|| Finder::new("\r\n.."));
+    let mut read_pos = 0;
+    let mut write_pos = 0;
+    while let Some(stuffed) = CRLFDOTDOT.find(&data[read_pos..]) {
+        let stuffed = read_pos + stuffed;
+        // Keep the CRLF and the first dot, dropping the second
+        data.copy_within(read_pos..stuffed + 3, write_pos);
+        write_pos += stuffed + 3 - read_pos;
+        read_pos = stuffed + 4;
+    }
+    if read_pos > 0 {
+        let len = data.len();
+        data.copy_within(read_pos..len, write_pos);
+        data.truncate(write_pos + len - read_pos);
     }
     data
 }
@@ -2105,6 +2615,7 @@ impl QueueDispatcher for DeferredSmtpInjectionDispatcher {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
 
     #[test]
     fn unstuffer() {
@@ -2116,6 +2627,9 @@ mod test {
 
         let stuffed = b"hello".to_vec();
         assert_eq!(unstuff(stuffed).as_slice(), b"hello");
+
+        let stuffed = b"\r\n..\r\n...\r\n..".to_vec();
+        assert_eq!(unstuff(stuffed).as_slice(), b"\r\n.\r\n..\r\n.");
     }
 
     #[test]
@@ -2133,4 +2647,185 @@ mod test {
             12
         ));
     }
+
+    #[test]
+    fn data_filter() {
+        // The filter must produce the same result as processing the
+        // whole of the data in memory, wherever the chunks are split
+        let cases: &[&[u8]] = &[
+            b"hello\r\n..dot\r\nthere\r\n..more dot",
+            b"\r\n..\r\n...\r\n..",
+            b"lone\nlf\r\nlone\rcr\r\r\n",
+            b"short\r\na line that is too long\r\nok\r\n",
+            b"trailing cr\r",
+        ];
+        for case in cases {
+            let unstuffed = unstuff(case.to_vec());
+            let mut normalized = unstuffed.clone();
+            mailparsing::normalize_crlf_in_place(&mut normalized);
+
+            for split in 0..=case.len() {
+                for fix in [false, true] {
+                    let mut filter = DataFilter::new(12, fix);
+                    let mut out = vec![];
+                    filter.process(&case[..split], &mut out);
+                    filter.process(&case[split..], &mut out);
+                    filter.finish(&mut out);
+
+                    let expected = if fix { &normalized } else { &unstuffed };
+                    assert_eq!(
+                        String::from_utf8_lossy(&out),
+                        String::from_utf8_lossy(expected),
+                        "case {case:?} split at {split}"
+                    );
+                    assert_eq!(
+                        filter.line_too_long,
+                        !check_line_lengths(&unstuffed, 12),
+                        "case {case:?} split at {split}"
+                    );
+                    assert_eq!(
+                        filter.lone_cr_or_lf,
+                        mailparsing::has_lone_cr_or_lf(&unstuffed),
+                        "case {case:?} split at {split}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn spool_params(dir: &Path, threshold: usize) -> EsmtpListenerParams {
+        serde_json::from_value(json!({
+            "data_spool_threshold": threshold,
+            "data_spool_directory": dir,
+        }))
+        .unwrap()
+    }
+
+    /// Feeds input to a DataReader in chunks of chunk_size bytes,
+    /// as though it were being read from a client
+    async fn read_data_in_chunks(
+        input: &[u8],
+        chunk_size: usize,
+        read_buffer: &mut Vec<u8>,
+        params: &EsmtpListenerParams,
+    ) -> ReadData {
+        let mut reader = DataReader::default();
+        let mut chunks = input.chunks(chunk_size);
+        loop {
+            if let Some(result) = reader.advance(read_buffer, params).await.unwrap() {
+                return result;
+            }
+            read_buffer.extend_from_slice(chunks.next().expect("terminator to be found"));
+        }
+    }
+
+    fn spooled_content(result: ReadData) -> (Arc<DataFile>, Vec<u8>) {
+        match result {
+            ReadData::Data(ReceivedData::Spooled { file, .. }) => {
+                let content = std::fs::read(file.path()).unwrap();
+                assert_eq!(file.len(), content.len() as u64);
+                (file, content)
+            }
+            ReadData::Data(ReceivedData::Memory(_)) => panic!("data was not spooled"),
+            _ => panic!("data was not read"),
+        }
+    }
+
+    #[tokio::test]
+    async fn spooled_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = spool_params(dir.path(), 64);
+
+        let body = "Subject: big\r\n\r\n..stuffed line\r\nplain line\r\n".repeat(20);
+        let input = format!("{body}.\r\nQUIT\r\n");
+        let expected = unstuff(body.as_bytes().to_vec());
+
+        for chunk_size in [1, 3, 7, 64, 1024] {
+            let mut read_buffer = vec![];
+            let result =
+                read_data_in_chunks(input.as_bytes(), chunk_size, &mut read_buffer, &params).await;
+            let (file, content) = spooled_content(result);
+            assert_eq!(
+                String::from_utf8_lossy(&content),
+                String::from_utf8_lossy(&expected),
+                "chunk_size {chunk_size}"
+            );
+            // Only the DATA is consumed; the pipelined command remains
+            assert_eq!(read_buffer, b"QUIT\r\n", "chunk_size {chunk_size}");
+
+            let mode = file.path().metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            drop(file);
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        }
+
+        // Below the threshold, the data is held in memory
+        let mut read_buffer = vec![];
+        let result = read_data_in_chunks(
+            b"Subject: small\r\n\r\nhi\r\n.\r\n",
+            7,
+            &mut read_buffer,
+            &params,
+        )
+        .await;
+        assert!(matches!(result, ReadData::Data(ReceivedData::Memory(_))));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn spooled_data_split_terminator() {
+        let dir = tempfile::tempdir().unwrap();
+        let params = spool_params(dir.path(), 64);
+
+        let body = "Subject: big\r\n\r\n".to_string() + &"0123456789\r\n".repeat(10);
+        let expected = body.as_bytes().to_vec();
+        let terminator = b".\r\nQUIT\r\n";
+
+        // The DATA is spooled when the first read completes, while
+        // the terminator, which begins with the final CRLF of the
+        // body, is only partially received
+        for split in 0..3 {
+            let mut read_buffer = body.as_bytes().to_vec();
+            read_buffer.extend_from_slice(&terminator[..split]);
+
+            let mut reader = DataReader::default();
+            assert!(reader
+                .advance(&mut read_buffer, &params)
+                .await
+                .unwrap()
+                .is_none());
+            assert!(reader.spooled.is_some(), "split {split}");
+            assert!(read_buffer.len() <= 4, "split {split}");
+
+            read_buffer.extend_from_slice(&terminator[split..]);
+            let result = reader.advance(&mut read_buffer, &params).await.unwrap();
+            let (_file, content) = spooled_content(result.expect("terminator to be found"));
+            assert_eq!(
+                String::from_utf8_lossy(&content),
+                String::from_utf8_lossy(&expected),
+                "split {split}"
+            );
+            assert_eq!(read_buffer, b"QUIT\r\n", "split {split}");
+        }
+
+        // Dot stuffing that is split between the file and
+        // memory is removed
+        let mut read_buffer = body.as_bytes().to_vec();
+        read_buffer.extend_from_slice(b"..abc");
+        let mut reader = DataReader::default();
+        assert!(reader
+            .advance(&mut read_buffer, &params)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(read_buffer, b".abc");
+        read_buffer.extend_from_slice(b"\r\n.\r\n");
+        let result = reader.advance(&mut read_buffer, &params).await.unwrap();
+        let (_file, content) = spooled_content(result.expect("terminator to be found"));
+        assert_eq!(
+            String::from_utf8_lossy(&content),
+            String::from_utf8_lossy(format!("{body}.abc\r\n").as_bytes())
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use crate::logging::disposition::{log_disposition, LogDisposition, RecordType};
 use crate::queue::QueueManager;
 use anyhow::Context;
+use arc_swap::ArcSwap;
 use chrono::{DateTime, Utc};
 use config::{any_err, from_lua_value, get_or_create_module, CallbackSignature};
 use humansize::{format_size, DECIMAL};
@@ -11,9 +12,15 @@ use kumo_server_memory::subscribe_to_memory_status_changes_async;
 use kumo_server_runtime::spawn;
 use message::Message;
 use mlua::{Lua, Value};
+use prometheus::{IntCounter, IntCounterVec, IntGauge};
 use rfc5321::{EnhancedStatusCode, Response};
 use serde::Deserialize;
+use spool::compressed::{CompressedSpool, SpoolCompression};
+use spool::dedup::DedupSpool;
+use spool::fsck::{FsckParams, FsckReport};
+use spool::janitor::{JanitorParams, JanitorReport};
 use spool::local_disk::LocalDiskSpool;
+use spool::quota::{QuotaSpool, QuotaState, SpoolQuota};
 use spool::rocks::{RocksSpool, RocksSpoolParams};
 use spool::{get_data_spool, get_meta_spool, Spool as SpoolTrait, SpoolEntry, SpoolId};
 use std::collections::HashMap;
@@ -21,16 +28,135 @@ use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock, Mutex as StdMutex};
 use std::time::{Duration, Instant};
+use throttle::ThrottleSpec;
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 
 static MANAGER: LazyLock<SpoolManager> = LazyLock::new(SpoolManager::new);
 static SPOOLIN_THREADS: AtomicUsize = AtomicUsize::new(0);
+static FSCK_MODE: AtomicBool = AtomicBool::new(false);
+static SPOOLIN_LIMIT: LazyLock<ArcSwap<Option<ThrottleSpec>>> = LazyLock::new(ArcSwap::default);
+pub static SPOOL_IN_PROGRESS: LazyLock<SpoolInProgress> = LazyLock::new(SpoolInProgress::new);
+static JANITOR_PARAMS: LazyLock<ArcSwap<Option<SpoolJanitorParams>>> =
+    LazyLock::new(ArcSwap::default);
+
+static SPOOLIN_ENUMERATED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "spoolin_enumerated_count",
+        "total number of messages spooled in during startup enumeration"
+    )
+    .unwrap()
+});
+static SPOOLIN_FAILED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "spoolin_failed_count",
+        "total number of messages that failed to spool in during startup enumeration"
+    )
+    .unwrap()
+});
+static SPOOLIN_COMPLETE: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "spoolin_complete",
+        "set to 1 once startup spool enumeration has completed"
+    )
+    .unwrap()
+});
+
+static JANITOR_ORPHANS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_janitor_orphans_count",
+        "total number of orphaned spool entries found by the spool janitor",
+        &["spool"]
+    )
+    .unwrap()
+});
+static JANITOR_REMOVED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "spool_janitor_removed_count",
+        "total number of orphaned spool entries removed by the spool janitor"
+    )
+    .unwrap()
+});
+static JANITOR_QUARANTINED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "spool_janitor_quarantined_count",
+        "total number of orphaned spool entries quarantined by the spool janitor"
+    )
+    .unwrap()
+});
 
 pub fn set_spoolin_threads(n: usize) {
     SPOOLIN_THREADS.store(n, Ordering::SeqCst);
 }
 
+/// Limits the rate at which messages are spooled in during
+/// startup enumeration. None means no limit.
+pub fn set_spoolin_rate_limit(spec: Option<ThrottleSpec>) {
+    SPOOLIN_LIMIT.store(Arc::new(spec));
+}
+
+/// Tracks the progress of the background spool enumeration
+/// that happens at startup
+pub struct SpoolInProgress {
+    started: StdMutex<Option<DateTime<Utc>>>,
+    finished: StdMutex<Option<DateTime<Utc>>>,
+    spooled_in: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl SpoolInProgress {
+    fn new() -> Self {
+        Self {
+            started: StdMutex::new(None),
+            finished: StdMutex::new(None),
+            spooled_in: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+
+    fn start(&self) {
+        self.started.lock().unwrap().replace(Utc::now());
+        SPOOLIN_COMPLETE.set(0);
+    }
+
+    fn finish(&self) {
+        self.finished.lock().unwrap().replace(Utc::now());
+        SPOOLIN_COMPLETE.set(1);
+    }
+
+    fn record_spooled_in(&self) {
+        self.spooled_in.fetch_add(1, Ordering::SeqCst);
+        SPOOLIN_ENUMERATED.inc();
+    }
+
+    fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::SeqCst);
+        SPOOLIN_FAILED.inc();
+    }
+
+    pub fn spooled_in(&self) -> usize {
+        self.spooled_in.load(Ordering::SeqCst)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    pub fn started(&self) -> Option<DateTime<Utc>> {
+        *self.started.lock().unwrap()
+    }
+
+    pub fn finished(&self) -> Option<DateTime<Utc>> {
+        *self.finished.lock().unwrap()
+    }
+}
+
+/// When set, define_spool will open the spool even though
+/// we are running in validation mode, so that it can be checked
+pub fn set_fsck_mode(enable: bool) {
+    FSCK_MODE.store(enable, Ordering::SeqCst);
+}
+
 #[derive(Clone)]
 pub struct SpoolHandle(Arc<Spool>);
 
@@ -44,6 +170,7 @@ impl std::ops::Deref for SpoolHandle {
 pub struct Spool {
     maintainer: StdMutex<Option<JoinHandle<()>>>,
     spool: Arc<dyn SpoolTrait + Send + Sync>,
+    path: PathBuf,
 }
 
 impl std::ops::Deref for Spool {
@@ -86,12 +213,134 @@ pub struct DefineSpoolParams {
     #[serde(default)]
     pub rocks_params: Option<RocksSpoolParams>,
 
+    #[serde(default)]
+    pub dedup: bool,
+    #[serde(default = "DefineSpoolParams::default_dedup_min_size")]
+    pub dedup_min_size: usize,
+
+    #[serde(default)]
+    pub compression: SpoolCompression,
+    #[serde(default)]
+    pub compression_level: i32,
+
+    #[serde(default)]
+    pub quota: SpoolQuota,
+    #[serde(
+        default = "DefineSpoolParams::default_quota_check_interval",
+        with = "duration_serde"
+    )]
+    pub quota_check_interval: Duration,
+
     #[serde(default)]
     pub min_free_space: MinFree,
     #[serde(default)]
     pub min_free_inodes: MinFree,
 }
 
+impl DefineSpoolParams {
+    fn default_quota_check_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn default_dedup_min_size() -> usize {
+        4096
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolJanitorParams {
+    /// How often to scan the spool for orphans
+    #[serde(
+        default = "SpoolJanitorParams::default_interval",
+        with = "duration_serde"
+    )]
+    pub interval: Duration,
+    /// How old an entry must be before it can be considered an orphan
+    #[serde(
+        default = "SpoolJanitorParams::default_grace_period",
+        with = "duration_serde"
+    )]
+    pub grace_period: Duration,
+    #[serde(default)]
+    pub quarantine_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl SpoolJanitorParams {
+    fn default_interval() -> Duration {
+        Duration::from_secs(6 * 3600)
+    }
+
+    fn default_grace_period() -> Duration {
+        Duration::from_secs(3600)
+    }
+}
+
+/// Configures the periodic removal of orphaned spool entries.
+/// None disables the janitor.
+pub fn set_spool_janitor(params: Option<SpoolJanitorParams>) {
+    JANITOR_PARAMS.store(Arc::new(params));
+}
+
+pub static SPOOL_QUOTA_STATE_CHANGED_SIG: LazyLock<CallbackSignature<(String, String, u64), ()>> =
+    LazyLock::new(|| CallbackSignature::new_with_multiple("spool_quota_state_changed"));
+
+fn notify_quota_state_change(name: &str, state: QuotaState, used: u64) {
+    let name = name.to_string();
+    let state = format!("{state:?}");
+    if let Err(err) = spawn("spool_quota_state_changed", async move {
+        let result = async {
+            let mut config = config::load_config().await?;
+            config
+                .async_call_callback(&SPOOL_QUOTA_STATE_CHANGED_SIG, (name, state, used))
+                .await
+        };
+        if let Err(err) = result.await {
+            tracing::error!("Error in spool_quota_state_changed event: {err:#}");
+        }
+    }) {
+        tracing::error!("failed to spawn spool_quota_state_changed event: {err:#}");
+    }
+}
+
+/// Opens a spool. When we are taking over from a previous instance,
+/// that instance holds the lock on the spool until it exits, so we
+/// wait for that to happen, and keep trying until the takeover deadline
+/// in case the lock is released a little after we learn of the exit.
+async fn open_with_takeover_retry<F>(
+    name: &str,
+    open: F,
+) -> anyhow::Result<Arc<dyn SpoolTrait + Send + Sync>>
+where
+    F: Fn() -> anyhow::Result<Arc<dyn SpoolTrait + Send + Sync>>,
+{
+    if kumo_server_common::handoff::takeover_deadline().is_some() {
+        tracing::info!("waiting for the previous instance to exit and release spool {name}");
+        kumo_server_common::handoff::wait_for_previous_instance().await;
+    }
+
+    let mut logged = false;
+    loop {
+        match open() {
+            Ok(spool) => return Ok(spool),
+            Err(err) => match kumo_server_common::handoff::takeover_deadline() {
+                Some(deadline) if Instant::now() < deadline => {
+                    if !logged {
+                        tracing::info!(
+                            "waiting for the previous instance to release spool {name}: {err:#}"
+                        );
+                        logged = true;
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
 async fn define_spool(params: DefineSpoolParams) -> anyhow::Result<()> {
     MonitoredPath {
         name: format!("{} spool", params.name),
@@ -112,7 +361,7 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         "define_spool",
         lua.create_async_function(|lua, params: Value| async move {
             let params = from_lua_value(&lua, params)?;
-            if config::is_validating() {
+            if config::is_validating() && !FSCK_MODE.load(Ordering::SeqCst) {
                 return Ok(());
             }
 
@@ -127,6 +376,14 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
             .map_err(any_err)
         })?,
     )?;
+    kumo_mod.set(
+        "configure_spool_janitor",
+        lua.create_function(|lua, params: Value| {
+            let params: Option<SpoolJanitorParams> = from_lua_value(lua, params)?;
+            set_spool_janitor(params);
+            Ok(())
+        })?,
+    )?;
     Ok(())
 }
 
@@ -147,6 +404,31 @@ impl SpoolManager {
         &MANAGER
     }
 
+    /// Verifies that each of the defined spools can be written to,
+    /// by creating and then removing a probe file in its directory
+    pub async fn check_writable(&self) -> anyhow::Result<()> {
+        let paths: Vec<(String, PathBuf)> = self
+            .named
+            .lock()
+            .await
+            .iter()
+            .map(|(name, handle)| (name.clone(), handle.0.path.clone()))
+            .collect();
+
+        tokio::task::spawn_blocking(move || {
+            for (name, path) in paths {
+                let probe = path.join(".kumod-write-probe");
+                std::fs::write(&probe, b"probe")
+                    .and_then(|()| std::fs::remove_file(&probe))
+                    .with_context(|| {
+                        format!("spool {name} at {} is not writable", path.display())
+                    })?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
     async fn take() -> HashMap<String, SpoolHandle> {
         Self::get().named.lock().await.drain().collect()
     }
@@ -194,29 +476,78 @@ impl SpoolManager {
             params.name,
             params.path.display()
         );
+        let spool = open_with_takeover_retry(&params.name, || {
+            let spool: Arc<dyn SpoolTrait + Send + Sync> = match params.kind {
+                SpoolKind::LocalDisk => Arc::new(LocalDiskSpool::new(
+                    &params.path,
+                    params.flush,
+                    kumo_server_runtime::get_main_runtime(),
+                )?),
+                SpoolKind::RocksDB => Arc::new(RocksSpool::new(
+                    &params.path,
+                    params.flush,
+                    params.rocks_params.clone(),
+                    kumo_server_runtime::get_main_runtime(),
+                )?),
+            };
+            Ok(spool)
+        })
+        .await
+        .with_context(|| format!("Opening spool {}", params.name))?;
+
+        let spool: Arc<dyn SpoolTrait + Send + Sync> = if params.quota.is_enabled() {
+            let spool = QuotaSpool::new(
+                &params.name,
+                &params.path,
+                spool,
+                params.quota,
+                Box::new(notify_quota_state_change),
+            );
+            spool.spawn_monitor(
+                params.quota_check_interval,
+                &kumo_server_runtime::get_main_runtime(),
+            );
+            Arc::new(spool)
+        } else {
+            spool
+        };
+
+        let spool: Arc<dyn SpoolTrait + Send + Sync> = match params.compression {
+            SpoolCompression::None => spool,
+            SpoolCompression::Zstd => Arc::new(CompressedSpool::new(
+                &params.name,
+                spool,
+                params.compression_level,
+                kumo_server_runtime::get_main_runtime(),
+            )),
+        };
+
+        // Dedup splits the message into headers and body, so it must
+        // see the payload before it is compressed. It stays in place
+        // after being turned off, so that existing references resolve.
+        let spool: Arc<dyn SpoolTrait + Send + Sync> =
+            if params.dedup || DedupSpool::is_present(&params.path) {
+                Arc::new(DedupSpool::new(
+                    &params.name,
+                    &params.path,
+                    spool,
+                    params.dedup.then_some(params.dedup_min_size),
+                    match params.compression {
+                        SpoolCompression::None => None,
+                        SpoolCompression::Zstd => Some(params.compression_level),
+                    },
+                    kumo_server_runtime::get_main_runtime(),
+                )?)
+            } else {
+                spool
+            };
+
         self.named.lock().await.insert(
             params.name.to_string(),
             SpoolHandle(Arc::new(Spool {
                 maintainer: StdMutex::new(None),
-                spool: match params.kind {
-                    SpoolKind::LocalDisk => Arc::new(
-                        LocalDiskSpool::new(
-                            &params.path,
-                            params.flush,
-                            kumo_server_runtime::get_main_runtime(),
-                        )
-                        .with_context(|| format!("Opening spool {}", params.name))?,
-                    ),
-                    SpoolKind::RocksDB => Arc::new(
-                        RocksSpool::new(
-                            &params.path,
-                            params.flush,
-                            params.rocks_params,
-                            kumo_server_runtime::get_main_runtime(),
-                        )
-                        .with_context(|| format!("Opening spool {}", params.name))?,
-                    ),
-                },
+                spool,
+                path: params.path.clone(),
             })),
         );
         Ok(())
@@ -243,11 +574,43 @@ impl SpoolManager {
         (get_meta_spool(), get_data_spool())
     }
 
+    /// Check the consistency of the defined meta and data spools,
+    /// optionally moving problematic entries into quarantine_dir.
+    /// This must only be used when the spool is not being started.
+    pub async fn fsck(&self, quarantine_dir: Option<PathBuf>) -> anyhow::Result<FsckReport> {
+        anyhow::ensure!(
+            !self.spool_started(),
+            "cannot fsck a spool that has been started"
+        );
+        let meta = self.get_named_impl("meta").await?;
+        let data = self.get_named_impl("data").await?;
+
+        spool::fsck::fsck(
+            &*meta,
+            &*data,
+            &FsckParams { quarantine_dir },
+            |id, meta| {
+                let msg = Message::new_from_spool(id, meta.to_vec())?;
+                msg.get_queue_name()?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
     pub fn spool_started(&self) -> bool {
         self.started.load(Ordering::SeqCst)
     }
 
+    /// Waits until start_spool has been called
+    pub async fn wait_for_spool_started(&self) {
+        while !self.spool_started() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
     pub async fn remove_from_spool(id: SpoolId) -> anyhow::Result<()> {
+        crate::meta_index::forget(&id);
         let (data_spool, meta_spool) = Self::get_data_meta();
         let res_data = data_spool.remove(id).await;
         let res_meta = meta_spool.remove(id).await;
@@ -263,6 +626,7 @@ impl SpoolManager {
     }
 
     pub async fn remove_from_spool_impl(&self, id: SpoolId) -> anyhow::Result<()> {
+        crate::meta_index::forget(&id);
         let (data_spool, meta_spool) = Self::get_data_meta();
         let res_data = data_spool.remove(id).await;
         let res_meta = meta_spool.remove(id).await;
@@ -275,12 +639,7 @@ impl SpoolManager {
         Ok(())
     }
 
-    async fn spool_in_thread(
-        &self,
-        rx: flume::Receiver<SpoolEntry>,
-        spooled_in: Arc<AtomicUsize>,
-        failed_spool_in: Arc<AtomicUsize>,
-    ) -> anyhow::Result<()> {
+    async fn spool_in_thread(&self, rx: flume::Receiver<SpoolEntry>) -> anyhow::Result<()> {
         let mut shutdown = ShutdownSubcription::get();
         let mut config = config::load_config().await?;
         let egress_pool = None;
@@ -295,11 +654,33 @@ impl SpoolManager {
                 entry = rx.recv_async() => { entry },
             }?;
 
+            let limit = SPOOLIN_LIMIT.load();
+            if let Some(limit) = limit.as_ref() {
+                // This limit is always node-local; it is not
+                // meaningful to share it across nodes
+                let limit = ThrottleSpec {
+                    force_local: true,
+                    ..*limit
+                };
+                loop {
+                    let result = limit.throttle("kumomta.spoolin.ratelimit").await?;
+                    match result.retry_after {
+                        Some(delay) => {
+                            tokio::select! {
+                                _ = shutdown.shutting_down() => anyhow::bail!("shutting down"),
+                                _ = tokio::time::sleep(delay) => {}
+                            };
+                        }
+                        None => break,
+                    }
+                }
+            }
+
             let now = Utc::now();
             match entry {
                 SpoolEntry::Item { id, data } => match Message::new_from_spool(id, data) {
                     Ok(msg) => {
-                        spooled_in.fetch_add(1, Ordering::SeqCst);
+                        SPOOL_IN_PROGRESS.record_spooled_in();
 
                         config
                             .async_call_callback(&spool_message_enumerated, msg.clone())
@@ -315,7 +696,7 @@ impl SpoolManager {
                                         "failed to resolve queue {queue_name}: {err:#}. \
                                         Ignoring message until kumod is restarted."
                                     );
-                                    failed_spool_in.fetch_add(1, Ordering::SeqCst);
+                                    SPOOL_IN_PROGRESS.record_failed();
                                 }
                                 Ok(queue) => {
                                     let queue_config = queue.get_config();
@@ -379,7 +760,7 @@ impl SpoolManager {
                                              to queue {queue_name}: {err:#}. \
                                              Ignoring message until kumod is restarted"
                                         );
-                                        failed_spool_in.fetch_add(1, Ordering::SeqCst);
+                                        SPOOL_IN_PROGRESS.record_failed();
                                     }
                                 }
                             },
@@ -495,30 +876,127 @@ impl SpoolManager {
         // otherwise we'll deadlock ourselves in the loop below
         drop(tx);
 
+        // Enumeration happens in the background, so that we can
+        // accept new messages while the existing messages are
+        // streamed back into the scheduled queues
+        kumo_server_runtime::spawn("spool enumeration", async move {
+            if let Err(err) = Self::get().enumerate_spool(rx).await {
+                tracing::error!("error during spool enumeration: {err:#}");
+            }
+        })?;
+
+        kumo_server_runtime::spawn("spool janitor", Self::janitor())?;
+
+        Ok(())
+    }
+
+    /// Periodically removes orphaned spool entries, when configured
+    /// via kumo.configure_spool_janitor
+    async fn janitor() {
+        let mut shutdown = ShutdownSubcription::get();
+
+        // Don't compete with startup enumeration
+        while SPOOL_IN_PROGRESS.finished().is_none() {
+            tokio::select! {
+                _ = shutdown.shutting_down() => return,
+                _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+            };
+        }
+
+        loop {
+            let interval = match JANITOR_PARAMS.load().as_ref() {
+                Some(params) => params.interval,
+                // Check again later, in case it gets configured
+                None => Duration::from_secs(60),
+            };
+            tokio::select! {
+                _ = shutdown.shutting_down() => return,
+                _ = tokio::time::sleep(interval) => {}
+            };
+
+            let Some(params) = JANITOR_PARAMS.load_full().as_ref().clone() else {
+                continue;
+            };
+            match Self::get().collect_orphans(&params).await {
+                Ok(report) => {
+                    if report.num_orphans() > 0 {
+                        tracing::warn!(
+                            "spool janitor: found {} orphans ({} data, {} meta); \
+                             removed {}, quarantined {}",
+                            report.num_orphans(),
+                            report.orphaned_data.len(),
+                            report.orphaned_meta.len(),
+                            report.num_removed,
+                            report.num_quarantined
+                        );
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("spool janitor: {err:#}");
+                }
+            }
+        }
+    }
+
+    /// Find and remove (or quarantine) spool entries that have no
+    /// corresponding queue entry
+    pub async fn collect_orphans(
+        &self,
+        params: &SpoolJanitorParams,
+    ) -> anyhow::Result<JanitorReport> {
+        let meta = self.get_named_impl("meta").await?;
+        let data = self.get_named_impl("data").await?;
+
+        let report = spool::janitor::collect_orphans(
+            &*meta,
+            &*data,
+            QueueManager::queued_ids,
+            &JanitorParams {
+                grace_period: params.grace_period,
+                quarantine_dir: params.quarantine_dir.clone(),
+                dry_run: params.dry_run,
+            },
+        )
+        .await?;
+
+        for id in &report.orphaned_data {
+            tracing::debug!("spool janitor: data {id} has no queue entry");
+        }
+        for id in &report.orphaned_meta {
+            tracing::debug!("spool janitor: meta {id} has no queue entry");
+        }
+
+        JANITOR_ORPHANS
+            .with_label_values(&["data"])
+            .inc_by(report.orphaned_data.len() as u64);
+        JANITOR_ORPHANS
+            .with_label_values(&["meta"])
+            .inc_by(report.orphaned_meta.len() as u64);
+        JANITOR_REMOVED.inc_by(report.num_removed as u64);
+        JANITOR_QUARANTINED.inc_by(report.num_quarantined as u64);
+
+        Ok(report)
+    }
+
+    async fn enumerate_spool(&self, rx: flume::Receiver<SpoolEntry>) -> anyhow::Result<()> {
         let activity = Activity::get("spool enumeration".to_string())?;
-        let spooled_in = Arc::new(AtomicUsize::new(0));
-        let failed_spool_in = Arc::new(AtomicUsize::new(0));
+        SPOOL_IN_PROGRESS.start();
         tracing::debug!("start_spool: waiting for enumeration");
         let start = Instant::now();
         let interval = std::time::Duration::from_secs(30);
 
         let (complete_tx, complete_rx) = flume::bounded(1);
 
-        let spooled_in_clone = Arc::clone(&spooled_in);
-        let failed_spool_in_clone = Arc::clone(&failed_spool_in);
-
         let spool_in =
             kumo_server_runtime::Runtime::new("spoolin", |cpus| cpus / 2, &SPOOLIN_THREADS)?;
 
         for idx in 0..spool_in.get_num_threads() {
             spool_in.spawn(format!("spoolin-{idx}"), {
-                let spooled_in = Arc::clone(&spooled_in_clone);
-                let failed_spool_in = Arc::clone(&failed_spool_in_clone);
                 let rx = rx.clone();
                 let complete_tx = complete_tx.clone();
                 async move {
                     let mgr = Self::get();
-                    let result = mgr.spool_in_thread(rx, spooled_in, failed_spool_in).await;
+                    let result = mgr.spool_in_thread(rx).await;
                     complete_tx.send_async(result).await
                 }
             })?;
@@ -530,8 +1008,7 @@ impl SpoolManager {
             tokio::select! {
                 _ = tokio::time::sleep(interval) => {
                     let elapsed = start.elapsed();
-                    let total =
-                        spooled_in.load(Ordering::SeqCst);
+                    let total = SPOOL_IN_PROGRESS.spooled_in();
                     let rate = (total as f64 / elapsed.as_secs_f64()).ceil() as u64;
                     tracing::info!(
                         "start_spool: still enumerating. {total} items in {elapsed:?} {rate}/s"
@@ -549,10 +1026,11 @@ impl SpoolManager {
             "done"
         };
         drop(activity);
+        SPOOL_IN_PROGRESS.finish();
 
         let elapsed = start.elapsed();
-        let total = spooled_in.load(Ordering::SeqCst);
-        let failed = failed_spool_in.load(Ordering::SeqCst);
+        let total = SPOOL_IN_PROGRESS.spooled_in();
+        let failed = SPOOL_IN_PROGRESS.failed();
         let rate = (total as f64 / elapsed.as_secs_f64()).ceil() as u64;
         tracing::info!(
             "start_spool: enumeration {label}, spooled in {total} msgs over {elapsed:?} {rate}/s"
@@ -0,0 +1,506 @@
+//! The purpose of this module is to maintain the suppression list;
+//! the addresses and domains to which messages must not be sent.
+//! The list is held in a local sqlite database, and can optionally
+//! be shared with other instances via redis, in which case redis is
+//! the source of truth for the list.
+//! When configured, the list is enforced when recipients are
+//! received, and/or when messages are about to be delivered.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use kumo_api_types::suppression::{
+    SuppressionV1AddRequest, SuppressionV1Entry, SuppressionV1QueryRequest,
+};
+use message::EnvelopeAddress;
+use mod_redis::{cmd, FromRedisValue, RedisConnKey, RedisConnection};
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use sqlite::{Connection, ConnectionThreadSafe, State};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, OnceLock};
+
+static SUPPRESSION: OnceLock<SuppressionList> = OnceLock::new();
+
+static SUPPRESSION_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "suppression_hits_count",
+        "total number of recipients that were rejected or bounced because they are suppressed",
+        &["stage"]
+    )
+    .unwrap()
+});
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// Where the suppression list is enforced
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SuppressionEnforcement {
+    /// Suppressed recipients are rejected when they are received
+    #[default]
+    Reception,
+    /// Messages to suppressed recipients are bounced when they
+    /// are about to be delivered
+    Dispatch,
+    /// Enforced at both reception and dispatch
+    Both,
+    /// The list is not enforced automatically
+    Never,
+}
+
+/// The point at which the suppression list is being checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuppressionStage {
+    Reception,
+    Dispatch,
+}
+
+impl SuppressionStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reception => "reception",
+            Self::Dispatch => "dispatch",
+        }
+    }
+}
+
+impl SuppressionEnforcement {
+    fn applies_to(self, stage: SuppressionStage) -> bool {
+        matches!(
+            (self, stage),
+            (Self::Both, _)
+                | (Self::Reception, SuppressionStage::Reception)
+                | (Self::Dispatch, SuppressionStage::Dispatch)
+        )
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SuppressionParams {
+    /// The path to the sqlite database that holds the list
+    #[serde(default = "SuppressionParams::default_path")]
+    pub path: PathBuf,
+
+    /// Where the list is enforced
+    #[serde(default)]
+    pub enforce: SuppressionEnforcement,
+
+    /// If set, entries are also stored in this redis instance
+    /// so that they are shared with the other instances that
+    /// use it
+    #[serde(default)]
+    pub redis: Option<RedisConnKey>,
+
+    /// The prefix for the redis keys that hold the entries
+    #[serde(default = "SuppressionParams::default_redis_key_prefix")]
+    pub redis_key_prefix: String,
+}
+
+impl SuppressionParams {
+    fn default_path() -> PathBuf {
+        "/var/spool/kumomta/suppression.db".into()
+    }
+
+    fn default_redis_key_prefix() -> String {
+        "kumo-suppression".to_string()
+    }
+}
+
+struct SuppressionList {
+    params: SuppressionParams,
+    db: Arc<ConnectionThreadSafe>,
+    redis: Option<RedisConnection>,
+}
+
+/// Opens the suppression list and enables its enforcement.
+/// This can only be called once.
+pub fn configure_suppression(params: SuppressionParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    if SUPPRESSION.get().is_some() {
+        anyhow::bail!("configure_suppression has already been called");
+    }
+
+    let db = Arc::new(open_suppression_db(&params)?);
+    let redis = params.redis.as_ref().map(|key| key.open()).transpose()?;
+
+    SUPPRESSION
+        .set(SuppressionList { params, db, redis })
+        .map_err(|_| anyhow::anyhow!("configure_suppression has already been called"))
+}
+
+fn get_list() -> anyhow::Result<&'static SuppressionList> {
+    SUPPRESSION
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("the suppression list has not been configured"))
+}
+
+fn open_suppression_db(params: &SuppressionParams) -> anyhow::Result<ConnectionThreadSafe> {
+    let path = &params.path;
+    let db = Connection::open_thread_safe(path)
+        .with_context(|| format!("opening suppression database {path:?}"))?;
+
+    let query = r#"
+PRAGMA journal_mode = WAL;
+CREATE TABLE IF NOT EXISTS suppression (
+    recipient TEXT NOT NULL PRIMARY KEY,
+    reason TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER
+);
+CREATE INDEX IF NOT EXISTS suppression_expires ON suppression (expires);
+    "#;
+
+    db.execute(query)
+        .with_context(|| format!("setting up suppression database {path:?}"))?;
+
+    Ok(db)
+}
+
+/// Normalizes an email address or domain into the form
+/// in which it is stored in the list
+fn normalize_recipient(recipient: &str) -> anyhow::Result<String> {
+    let recipient = recipient.trim().to_lowercase();
+    let domain = match recipient.rsplit_once('@') {
+        Some((user, domain)) => {
+            if user.is_empty() {
+                anyhow::bail!("invalid suppression recipient {recipient:?}");
+            }
+            domain
+        }
+        None => recipient.as_str(),
+    };
+    if domain.is_empty() || domain.contains(char::is_whitespace) {
+        anyhow::bail!("invalid suppression recipient {recipient:?}");
+    }
+    Ok(recipient)
+}
+
+/// Returns the entries that could suppress recipient; the
+/// recipient itself and, for an email address, its domain
+fn candidates(recipient: &str) -> anyhow::Result<Vec<String>> {
+    let recipient = normalize_recipient(recipient)?;
+    match recipient.rsplit_once('@') {
+        Some((_user, domain)) => {
+            let domain = domain.to_string();
+            Ok(vec![recipient, domain])
+        }
+        None => Ok(vec![recipient]),
+    }
+}
+
+fn timestamp_to_datetime(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default()
+}
+
+fn add_db(db: &ConnectionThreadSafe, entry: &SuppressionV1Entry) -> anyhow::Result<()> {
+    // Take the opportunity to remove expired entries
+    let mut stmt = db.prepare("DELETE FROM suppression WHERE expires <= $now")?;
+    stmt.bind(("$now", Utc::now().timestamp()))?;
+    stmt.next()?;
+
+    let mut stmt = db.prepare(
+        "INSERT OR REPLACE INTO suppression (recipient, reason, created, expires)
+            values ($recipient, $reason, $created, $expires)",
+    )?;
+    stmt.bind(("$recipient", entry.recipient.as_str()))?;
+    stmt.bind(("$reason", entry.reason.as_str()))?;
+    stmt.bind(("$created", entry.created.timestamp()))?;
+    stmt.bind(("$expires", entry.expires.map(|t| t.timestamp())))?;
+    stmt.next()?;
+    Ok(())
+}
+
+fn remove_db(db: &ConnectionThreadSafe, recipient: &str) -> anyhow::Result<bool> {
+    let mut stmt = db.prepare("DELETE FROM suppression WHERE recipient=$recipient")?;
+    stmt.bind(("$recipient", recipient))?;
+    stmt.next()?;
+    Ok(db.change_count() > 0)
+}
+
+/// Returns the unexpired entries for the candidates, or all unexpired
+/// entries when candidates is None. The entries for more specific
+/// candidates are returned first.
+fn query_db(
+    db: &ConnectionThreadSafe,
+    candidates: Option<&[String]>,
+    limit: usize,
+) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+    let mut query = "SELECT recipient, reason, created, expires FROM suppression
+        WHERE (expires IS NULL OR expires > $now)"
+        .to_string();
+    if let Some(candidates) = candidates {
+        let names: Vec<String> = (0..candidates.len()).map(|i| format!("$r{i}")).collect();
+        query.push_str(&format!(" AND recipient IN ({})", names.join(", ")));
+    }
+    query.push_str(" ORDER BY length(recipient) DESC, recipient LIMIT $limit");
+
+    let mut stmt = db.prepare(&query)?;
+    stmt.bind(("$now", Utc::now().timestamp()))?;
+    for (i, candidate) in candidates.unwrap_or_default().iter().enumerate() {
+        stmt.bind((format!("$r{i}").as_str(), candidate.as_str()))?;
+    }
+    stmt.bind(("$limit", limit as i64))?;
+
+    let mut entries = vec![];
+    while stmt.next()? == State::Row {
+        entries.push(SuppressionV1Entry {
+            recipient: stmt.read::<String, _>("recipient")?,
+            reason: stmt.read::<String, _>("reason")?,
+            created: timestamp_to_datetime(stmt.read::<i64, _>("created")?),
+            expires: stmt
+                .read::<Option<i64>, _>("expires")?
+                .map(timestamp_to_datetime),
+        });
+    }
+    Ok(entries)
+}
+
+impl SuppressionList {
+    fn redis_key(&self, recipient: &str) -> String {
+        format!("{}:{recipient}", self.params.redis_key_prefix)
+    }
+
+    async fn with_db<T, F>(&self, func: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&ConnectionThreadSafe) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || func(&db)).await?
+    }
+
+    async fn add(&self, entry: SuppressionV1Entry) -> anyhow::Result<()> {
+        if let Some(redis) = &self.redis {
+            let mut set = cmd("SET");
+            set.arg(self.redis_key(&entry.recipient))
+                .arg(serde_json::to_string(&entry)?);
+            if let Some(expires) = &entry.expires {
+                set.arg("PXAT").arg(expires.timestamp_millis());
+            }
+            redis.query(set).await?;
+        }
+        self.with_db(move |db| add_db(db, &entry)).await
+    }
+
+    async fn remove(&self, recipient: String) -> anyhow::Result<bool> {
+        let mut removed = false;
+        if let Some(redis) = &self.redis {
+            // Since redis is the source of truth, this takes effect
+            // for all of the instances that share it
+            let mut del = cmd("DEL");
+            del.arg(self.redis_key(&recipient));
+            let count: i64 = FromRedisValue::from_redis_value(&redis.query(del).await?)?;
+            removed = count > 0;
+        }
+        let removed_local = self.with_db(move |db| remove_db(db, &recipient)).await?;
+        Ok(removed || removed_local)
+    }
+
+    /// Looks up the entries for the recipients in redis, returning
+    /// the unexpired entries in the same order as recipients.
+    /// The lookups are batched into a single MGET; when using a
+    /// redis cluster, the client splits it across the nodes that
+    /// hold the keys.
+    async fn query_redis(
+        &self,
+        redis: &RedisConnection,
+        recipients: &[String],
+    ) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        if recipients.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut mget = cmd("MGET");
+        for recipient in recipients {
+            mget.arg(self.redis_key(recipient));
+        }
+        let values: Vec<Option<String>> =
+            FromRedisValue::from_redis_value(&redis.query(mget).await?)?;
+
+        let now = Utc::now();
+        let mut entries = vec![];
+        for (recipient, value) in recipients.iter().zip(values) {
+            if let Some(value) = value {
+                let entry: SuppressionV1Entry = serde_json::from_str(&value)
+                    .with_context(|| format!("parsing suppression entry for {recipient}"))?;
+                if entry.expires.map(|expires| expires > now).unwrap_or(true) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn query(
+        &self,
+        candidates: Option<Vec<String>>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+        match (&self.redis, candidates) {
+            // The candidates are ordered most specific first,
+            // which is the order in which the entries are returned
+            (Some(redis), Some(candidates)) => {
+                let mut entries = self.query_redis(redis, &candidates).await?;
+                entries.truncate(limit);
+                Ok(entries)
+            }
+            // Redis cannot efficiently enumerate the list, so list the
+            // entries known locally, but only those that are still present
+            // in redis, as they may have been removed by another instance
+            (Some(redis), None) => {
+                let local = self.with_db(move |db| query_db(db, None, limit)).await?;
+                let recipients: Vec<String> =
+                    local.into_iter().map(|entry| entry.recipient).collect();
+                self.query_redis(redis, &recipients).await
+            }
+            (None, candidates) => {
+                self.with_db(move |db| query_db(db, candidates.as_deref(), limit))
+                    .await
+            }
+        }
+    }
+}
+
+/// Adds an entry to the suppression list, replacing any existing
+/// entry for the same recipient
+pub async fn add(request: SuppressionV1AddRequest) -> anyhow::Result<SuppressionV1Entry> {
+    let list = get_list()?;
+    let created = Utc::now();
+    let expires = match request.duration {
+        Some(duration) => Some(
+            created
+                + chrono::Duration::from_std(duration)
+                    .with_context(|| format!("duration {duration:?} is out of range"))?,
+        ),
+        None => None,
+    };
+    let entry = SuppressionV1Entry {
+        recipient: normalize_recipient(&request.recipient)?,
+        reason: request.reason,
+        created,
+        expires,
+    };
+    list.add(entry.clone()).await?;
+    Ok(entry)
+}
+
+/// Removes the entry for recipient from the suppression list.
+/// Returns true if there was such an entry.
+pub async fn remove(recipient: &str) -> anyhow::Result<bool> {
+    let list = get_list()?;
+    list.remove(normalize_recipient(recipient)?).await
+}
+
+/// Returns the entries that match the request, the most
+/// specific entries first
+pub async fn query(request: SuppressionV1QueryRequest) -> anyhow::Result<Vec<SuppressionV1Entry>> {
+    let list = get_list()?;
+    let candidates = request.recipient.as_deref().map(candidates).transpose()?;
+    list.query(candidates, request.limit.unwrap_or(DEFAULT_QUERY_LIMIT))
+        .await
+}
+
+/// Returns the entry that suppresses recipient, if any
+pub async fn lookup(recipient: &str) -> anyhow::Result<Option<SuppressionV1Entry>> {
+    let list = get_list()?;
+    let mut entries = list.query(Some(candidates(recipient)?), 1).await?;
+    Ok(entries.pop())
+}
+
+/// Called at reception and dispatch time to determine whether
+/// recipient is suppressed at that stage. Returns the matching
+/// entry, if any.
+/// Errors are logged and the recipient is treated as not suppressed,
+/// so that a problem with the list does not stop mail flow.
+pub async fn check_recipient(
+    stage: SuppressionStage,
+    recipient: &EnvelopeAddress,
+) -> Option<SuppressionV1Entry> {
+    let list = SUPPRESSION.get()?;
+    if !list.params.enforce.applies_to(stage) {
+        return None;
+    }
+
+    let candidates = candidates(&recipient.to_string()).ok()?;
+    match list.query(Some(candidates), 1).await {
+        Ok(mut entries) => {
+            let entry = entries.pop()?;
+            SUPPRESSION_HITS.with_label_values(&[stage.as_str()]).inc();
+            Some(entry)
+        }
+        Err(err) => {
+            tracing::error!("failed to check suppression list for {recipient:?}: {err:#}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            candidates(" User@Example.COM ").unwrap(),
+            vec!["user@example.com".to_string(), "example.com".to_string()]
+        );
+        assert_eq!(
+            candidates("example.com").unwrap(),
+            vec!["example.com".to_string()]
+        );
+        assert!(normalize_recipient("@example.com").is_err());
+        assert!(normalize_recipient("user@").is_err());
+        assert!(normalize_recipient("").is_err());
+    }
+
+    #[test]
+    fn add_and_query() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let params = SuppressionParams {
+            path: dir.path().join("suppression.db"),
+            enforce: SuppressionEnforcement::Reception,
+            redis: None,
+            redis_key_prefix: SuppressionParams::default_redis_key_prefix(),
+        };
+        let db = open_suppression_db(&params)?;
+        let now = Utc::now();
+
+        let entry = |recipient: &str, expires: Option<i64>| SuppressionV1Entry {
+            recipient: recipient.to_string(),
+            reason: format!("{recipient} reason"),
+            created: timestamp_to_datetime(now.timestamp()),
+            expires: expires.map(|secs| timestamp_to_datetime(now.timestamp() + secs)),
+        };
+
+        add_db(&db, &entry("example.com", None))?;
+        add_db(&db, &entry("user@example.com", Some(3600)))?;
+        add_db(&db, &entry("expired@example.com", Some(-10)))?;
+
+        let results = query_db(&db, Some(&candidates("USER@example.com")?), 10)?;
+        assert_eq!(
+            results,
+            vec![
+                entry("user@example.com", Some(3600)),
+                entry("example.com", None)
+            ]
+        );
+
+        let results = query_db(&db, Some(&candidates("expired@example.com")?), 10)?;
+        assert_eq!(results, vec![entry("example.com", None)]);
+
+        let results = query_db(&db, Some(&candidates("user@example.org")?), 10)?;
+        assert!(results.is_empty());
+
+        let results = query_db(&db, None, 10)?;
+        assert_eq!(results.len(), 2);
+
+        assert!(remove_db(&db, "example.com")?);
+        assert!(!remove_db(&db, "example.com")?);
+        let results = query_db(&db, Some(&candidates("other@example.com")?), 10)?;
+        assert!(results.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+//! The purpose of this module is to meter the resources consumed by
+//! each tenant, so that multi-tenant operators can bill for usage and
+//! enforce fair use without reconstructing it from the delivery logs.
+//!
+//! Usage is exposed as metrics labelled by tenant, and is periodically
+//! logged as a TenantUsage record for each tenant that was active in
+//! the reporting period.
+
+use crate::logging::Logger;
+use chrono::{DateTime, Utc};
+use kumo_log_types::{JsonLogRecord, RecordType, TenantUsage};
+use kumo_server_lifecycle::ShutdownSubcription;
+use message::message::QueueNameComponents;
+use message::Message;
+use parking_lot::Mutex;
+use prometheus::{CounterVec, IntCounterVec};
+use rfc5321::Response;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+
+static METER: OnceLock<TenantMeter> = OnceLock::new();
+
+/// The name under which usage is recorded once the limit
+/// on the number of distinct tenants has been reached
+const OTHER_TENANT: &str = "(other)";
+
+static MESSAGES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "tenant_messages_received",
+        "total number of messages received for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+static BYTES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "tenant_bytes_received",
+        "total size in bytes of the messages received for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+static MESSAGES_DELIVERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "tenant_messages_delivered",
+        "total number of messages delivered for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+static BYTES_DELIVERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "tenant_bytes_delivered",
+        "total size in bytes of the messages delivered for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+static DELIVERY_ATTEMPTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "tenant_delivery_attempts",
+        "total number of delivery attempts made for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+static CONNECTION_SECONDS: LazyLock<CounterVec> = LazyLock::new(|| {
+    prometheus::register_counter_vec!(
+        "tenant_connection_seconds",
+        "total time in seconds spent in delivery attempts for a tenant",
+        &["tenant"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TenantUsageParams {
+    /// How often a TenantUsage record is logged for each tenant
+    #[serde(
+        default = "TenantUsageParams::default_report_interval",
+        with = "duration_serde"
+    )]
+    pub report_interval: Duration,
+
+    /// The maximum number of distinct tenants that are metered
+    #[serde(default = "TenantUsageParams::default_max_tenants")]
+    pub max_tenants: usize,
+}
+
+impl TenantUsageParams {
+    fn default_report_interval() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_max_tenants() -> usize {
+        1000
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+struct Usage {
+    messages_received: u64,
+    bytes_received: u64,
+    messages_delivered: u64,
+    bytes_delivered: u64,
+    delivery_attempts: u64,
+    connection_seconds: f64,
+}
+
+struct Period {
+    start: DateTime<Utc>,
+    usage: HashMap<String, Usage>,
+}
+
+impl Period {
+    fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            usage: HashMap::new(),
+        }
+    }
+}
+
+struct TenantMeter {
+    max_tenants: usize,
+    period: Mutex<Period>,
+    /// The tenants that have been metered since startup;
+    /// used to bound the cardinality of the metrics
+    tenants: Mutex<HashSet<String>>,
+}
+
+impl TenantMeter {
+    fn new(max_tenants: usize, now: DateTime<Utc>) -> Self {
+        Self {
+            max_tenants,
+            period: Mutex::new(Period::new(now)),
+            tenants: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the name under which usage for the tenant is recorded
+    fn resolve_tenant<'a>(&self, tenant: &'a str) -> &'a str {
+        let mut tenants = self.tenants.lock();
+        if tenants.contains(tenant) {
+            return tenant;
+        }
+        if tenants.len() >= self.max_tenants {
+            return OTHER_TENANT;
+        }
+        tenants.insert(tenant.to_string());
+        tenant
+    }
+
+    /// Updates the usage of the tenant in the current period,
+    /// returning the name under which it was recorded
+    fn add<'a>(&self, tenant: &'a str, apply: impl FnOnce(&mut Usage)) -> &'a str {
+        let tenant = self.resolve_tenant(tenant);
+        let mut period = self.period.lock();
+        apply(period.usage.entry(tenant.to_string()).or_default());
+        tenant
+    }
+
+    /// Ends the current period, returning its start and usage
+    fn take_period(&self, now: DateTime<Utc>) -> Period {
+        std::mem::replace(&mut *self.period.lock(), Period::new(now))
+    }
+}
+
+/// Enables metering and starts periodically logging the usage.
+/// This can only be called once.
+pub fn configure_tenant_usage(params: TenantUsageParams) -> anyhow::Result<()> {
+    if config::is_validating() {
+        return Ok(());
+    }
+    anyhow::ensure!(
+        !params.report_interval.is_zero(),
+        "report_interval must be greater than zero"
+    );
+    METER
+        .set(TenantMeter::new(params.max_tenants, Utc::now()))
+        .map_err(|_| anyhow::anyhow!("configure_tenant_usage has already been called"))?;
+
+    kumo_server_runtime::rt_spawn("tenant usage reporter", reporter(params.report_interval))?;
+    Ok(())
+}
+
+fn tenant_for_queue(queue_name: &str) -> Option<String> {
+    QueueNameComponents::parse(queue_name)
+        .tenant
+        .map(|tenant| tenant.to_string())
+}
+
+/// Called by the logging layer to meter the reception or delivery
+/// of a message, if metering has been configured
+pub async fn record(kind: RecordType, msg: &Message) {
+    let Some(meter) = METER.get() else {
+        return;
+    };
+    if !matches!(
+        kind,
+        RecordType::Reception
+            | RecordType::Delivery
+            | RecordType::TransientFailure
+            | RecordType::Bounce
+    ) {
+        return;
+    }
+
+    msg.load_meta_if_needed().await.ok();
+    let Some(tenant) = msg.get_queue_name().ok().and_then(|q| tenant_for_queue(&q)) else {
+        return;
+    };
+    let size = msg.get_data_len() as u64;
+
+    let tenant = meter.add(&tenant, |usage| match kind {
+        RecordType::Reception => {
+            usage.messages_received += 1;
+            usage.bytes_received += size;
+        }
+        RecordType::Delivery => {
+            usage.messages_delivered += 1;
+            usage.bytes_delivered += size;
+            usage.delivery_attempts += 1;
+        }
+        _ => {
+            usage.delivery_attempts += 1;
+        }
+    });
+
+    match kind {
+        RecordType::Reception => {
+            MESSAGES_RECEIVED.with_label_values(&[tenant]).inc();
+            BYTES_RECEIVED.with_label_values(&[tenant]).inc_by(size);
+        }
+        RecordType::Delivery => {
+            MESSAGES_DELIVERED.with_label_values(&[tenant]).inc();
+            BYTES_DELIVERED.with_label_values(&[tenant]).inc_by(size);
+            DELIVERY_ATTEMPTS.with_label_values(&[tenant]).inc();
+        }
+        _ => {
+            DELIVERY_ATTEMPTS.with_label_values(&[tenant]).inc();
+        }
+    }
+}
+
+/// Called by the ready queue to meter the time spent attempting
+/// to deliver a batch of messages from the named scheduled queue
+pub fn record_connection_time(queue_name: &str, elapsed: Duration) {
+    let Some(meter) = METER.get() else {
+        return;
+    };
+    let Some(tenant) = tenant_for_queue(queue_name) else {
+        return;
+    };
+    let seconds = elapsed.as_secs_f64();
+    let tenant = meter.add(&tenant, |usage| usage.connection_seconds += seconds);
+    CONNECTION_SECONDS
+        .with_label_values(&[tenant])
+        .inc_by(seconds);
+}
+
+fn make_records(
+    period: Period,
+    now: DateTime<Utc>,
+    scheduled: &HashMap<String, usize>,
+) -> Vec<JsonLogRecord> {
+    let mut usage = period.usage;
+    // Tenants that have messages waiting in the queues are still
+    // consuming the spool, even if they were otherwise idle
+    for tenant in scheduled.keys() {
+        usage.entry(tenant.to_string()).or_default();
+    }
+
+    let mut tenants: Vec<_> = usage.into_iter().collect();
+    tenants.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let nodeid = kumo_server_common::nodeid::NodeId::get_uuid();
+    tenants
+        .into_iter()
+        .map(|(tenant, usage)| JsonLogRecord {
+            kind: RecordType::TenantUsage,
+            id: String::new(),
+            sender: String::new(),
+            recipient: String::new(),
+            queue: String::new(),
+            site: String::new(),
+            size: 0,
+            response: Response {
+                code: 0,
+                enhanced_code: None,
+                content: String::new(),
+                command: None,
+            },
+            peer_address: None,
+            timestamp: now,
+            created: period.start,
+            num_attempts: 0,
+            latency: None,
+            bounce_classification: Default::default(),
+            egress_pool: None,
+            egress_source: None,
+            source_address: None,
+            feedback_report: None,
+            meta: Default::default(),
+            headers: Default::default(),
+            delivery_protocol: None,
+            reception_protocol: None,
+            nodeid,
+            tls_cipher: None,
+            tls_protocol_version: None,
+            tls_peer_subject_name: None,
+            provider_name: None,
+            session_id: None,
+            suppressed_count: None,
+            response_category: None,
+            annotations: vec![],
+            tenant_usage: Some(Box::new(TenantUsage {
+                scheduled_messages: scheduled.get(&tenant).copied().unwrap_or(0) as u64,
+                tenant,
+                period_start: period.start,
+                period_end: now,
+                messages_received: usage.messages_received,
+                bytes_received: usage.bytes_received,
+                messages_delivered: usage.messages_delivered,
+                bytes_delivered: usage.bytes_delivered,
+                delivery_attempts: usage.delivery_attempts,
+                connection_seconds: usage.connection_seconds,
+            })),
+        })
+        .collect()
+}
+
+/// Ends the current reporting period and logs a TenantUsage record
+/// for each tenant that was active during it
+pub async fn report() {
+    let Some(meter) = METER.get() else {
+        return;
+    };
+    let now = Utc::now();
+    let period = meter.take_period(now);
+    let scheduled = crate::queue::QueueManager::scheduled_count_by_tenant();
+    for record in make_records(period, now, &scheduled) {
+        Logger::log_to_all(record).await;
+    }
+}
+
+async fn reporter(interval: Duration) {
+    let mut shutdown = ShutdownSubcription::get();
+    loop {
+        tokio::select! {
+            _ = shutdown.shutting_down() => {
+                // The final period is reported as the loggers shut down
+                break;
+            },
+            _ = tokio::time::sleep(interval) => {}
+        };
+        report().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metering() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let end = DateTime::from_timestamp(1_700_003_600, 0).unwrap();
+        let meter = TenantMeter::new(2, start);
+
+        meter.add("acme", |usage| {
+            usage.messages_received += 1;
+            usage.bytes_received += 100;
+        });
+        meter.add("acme", |usage| usage.connection_seconds += 1.5);
+        meter.add("globex", |usage| usage.delivery_attempts += 1);
+        // The limit has been reached, so this is recorded as other
+        meter.add("initech", |usage| usage.delivery_attempts += 1);
+        meter.add("soylent", |usage| usage.delivery_attempts += 1);
+
+        let mut scheduled = HashMap::new();
+        scheduled.insert("acme".to_string(), 3);
+        scheduled.insert("idle".to_string(), 7);
+
+        let records = make_records(meter.take_period(end), end, &scheduled);
+        let usage: Vec<&TenantUsage> = records
+            .iter()
+            .map(|record| record.tenant_usage.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            usage.iter().map(|u| u.tenant.as_str()).collect::<Vec<_>>(),
+            vec!["(other)", "acme", "globex", "idle"]
+        );
+
+        assert_eq!(usage[0].delivery_attempts, 2);
+        assert_eq!(usage[1].messages_received, 1);
+        assert_eq!(usage[1].bytes_received, 100);
+        assert_eq!(usage[1].connection_seconds, 1.5);
+        assert_eq!(usage[1].scheduled_messages, 3);
+        assert_eq!(usage[1].period_start, start);
+        assert_eq!(usage[1].period_end, end);
+        assert_eq!(usage[3].scheduled_messages, 7);
+        assert_eq!(usage[3].messages_received, 0);
+        assert!(records
+            .iter()
+            .all(|record| record.kind == RecordType::TenantUsage));
+
+        // A new period starts afresh
+        let records = make_records(meter.take_period(end), end, &HashMap::new());
+        assert!(records.is_empty());
+    }
+}
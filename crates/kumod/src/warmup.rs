@@ -0,0 +1,215 @@
+use chrono::{NaiveDate, Utc};
+use config::CallbackSignature;
+use kumo_api_types::egress_path::EgressPathConfig;
+use kumo_server_runtime::spawn;
+use parking_lot::Mutex;
+use prometheus::IntGaugeVec;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::LazyLock;
+use throttle::ThrottleSpec;
+
+static WARMUP_DAY: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_day",
+        "the current day of the warmup schedule of an egress source, starting from 1",
+        &["source"]
+    )
+    .unwrap()
+});
+static WARMUP_DAYS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_days",
+        "the total number of days in the warmup schedule of an egress source",
+        &["source"]
+    )
+    .unwrap()
+});
+static WARMUP_DAILY_LIMIT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_daily_limit",
+        "the number of messages per day that an egress source is currently \
+         permitted to send to a provider by its warmup schedule",
+        &["source", "provider"]
+    )
+    .unwrap()
+});
+static WARMUP_COMPLETE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "egress_source_warmup_complete",
+        "whether the warmup schedule of an egress source has been completed",
+        &["source"]
+    )
+    .unwrap()
+});
+
+/// The sources for which the egress_source_warmup_complete event
+/// has been triggered by this process
+static COMPLETED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Mutex::default);
+
+pub static WARMUP_COMPLETE_SIG: LazyLock<CallbackSignature<String, ()>> =
+    LazyLock::new(|| CallbackSignature::new_with_multiple("egress_source_warmup_complete"));
+
+/// The label used in metrics for the curve that applies to
+/// providers that have no specific curve
+const DEFAULT_PROVIDER: &str = "default";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WarmupSchedule {
+    /// The date, in UTC, of the first day of the schedule
+    pub start: NaiveDate,
+
+    /// The maximum number of messages per day that may be sent,
+    /// for each day of the schedule, to providers that are not
+    /// listed in `providers`
+    pub daily_volume: Vec<u64>,
+
+    /// Curves for specific providers, keyed by the provider_name
+    /// of the egress path
+    #[serde(default)]
+    pub providers: BTreeMap<String, Vec<u64>>,
+}
+
+impl WarmupSchedule {
+    /// Returns the zero-based index of the day of the schedule.
+    /// Dates prior to the start are considered to be the first day.
+    fn day_index(&self, today: NaiveDate) -> usize {
+        (today - self.start).num_days().max(0) as usize
+    }
+
+    /// The number of days in the longest of the curves
+    fn num_days(&self) -> usize {
+        self.providers
+            .values()
+            .map(|curve| curve.len())
+            .chain(std::iter::once(self.daily_volume.len()))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn is_complete(&self, today: NaiveDate) -> bool {
+        self.day_index(today) >= self.num_days()
+    }
+
+    /// Returns the label of the curve that applies to the provider,
+    /// and the daily limit for that curve, or None if that curve
+    /// has been completed
+    fn daily_limit(&self, provider: Option<&str>, today: NaiveDate) -> Option<(&str, u64)> {
+        let (label, curve) = match provider.and_then(|p| self.providers.get_key_value(p)) {
+            Some((label, curve)) => (label.as_str(), curve),
+            None => (DEFAULT_PROVIDER, &self.daily_volume),
+        };
+        curve
+            .get(self.day_index(today))
+            .map(|&limit| (label, limit.max(1)))
+    }
+}
+
+/// Caps the message rate of an egress path whose source is warming up,
+/// and updates the warmup metrics for the source. When the schedule
+/// has been completed, triggers the egress_source_warmup_complete event,
+/// once per source for the lifetime of the process.
+pub fn apply_warmup(source: &str, schedule: &WarmupSchedule, path_config: &mut EgressPathConfig) {
+    let today = Utc::now().date_naive();
+
+    WARMUP_DAYS
+        .with_label_values(&[source])
+        .set(schedule.num_days() as i64);
+
+    if schedule.is_complete(today) {
+        WARMUP_DAY
+            .with_label_values(&[source])
+            .set(schedule.num_days() as i64);
+        WARMUP_COMPLETE.with_label_values(&[source]).set(1);
+        if COMPLETED.lock().insert(source.to_string()) {
+            notify_warmup_complete(source);
+        }
+        return;
+    }
+
+    WARMUP_DAY
+        .with_label_values(&[source])
+        .set(schedule.day_index(today) as i64 + 1);
+    WARMUP_COMPLETE.with_label_values(&[source]).set(0);
+
+    let Some((provider, limit)) = schedule.daily_limit(path_config.provider_name.as_deref(), today)
+    else {
+        // The curve for this provider is shorter than the schedule,
+        // and has been completed
+        return;
+    };
+
+    WARMUP_DAILY_LIMIT
+        .with_label_values(&[source, provider])
+        .set(limit as i64);
+
+    // Spread the daily volume across the day, permitting bursts
+    // of up to an hour's worth of messages
+    path_config.additional_message_rate_throttles.insert(
+        format!("kumomta.warmup.{source}.{provider}"),
+        ThrottleSpec {
+            limit,
+            period: 86400,
+            max_burst: Some((limit / 24).max(1)),
+            force_local: false,
+        },
+    );
+}
+
+fn notify_warmup_complete(source: &str) {
+    let source = source.to_string();
+    if let Err(err) = spawn("egress_source_warmup_complete", async move {
+        let result = async {
+            let mut config = config::load_config().await?;
+            config
+                .async_call_callback(&WARMUP_COMPLETE_SIG, source)
+                .await
+        };
+        if let Err(err) = result.await {
+            tracing::error!("Error in egress_source_warmup_complete event: {err:#}");
+        }
+    }) {
+        tracing::error!("failed to spawn egress_source_warmup_complete event: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = WarmupSchedule {
+            start: date("2025-03-01"),
+            daily_volume: vec![100, 200, 400],
+            providers: [("gmail".to_string(), vec![50, 100, 200, 400, 800])]
+                .into_iter()
+                .collect(),
+        };
+
+        assert_eq!(
+            schedule.daily_limit(None, date("2025-02-20")),
+            Some(("default", 100))
+        );
+        assert_eq!(
+            schedule.daily_limit(Some("yahoo"), date("2025-03-02")),
+            Some(("default", 200))
+        );
+        assert_eq!(
+            schedule.daily_limit(Some("gmail"), date("2025-03-02")),
+            Some(("gmail", 100))
+        );
+        assert_eq!(schedule.daily_limit(None, date("2025-03-04")), None);
+        assert_eq!(
+            schedule.daily_limit(Some("gmail"), date("2025-03-05")),
+            Some(("gmail", 800))
+        );
+        assert!(!schedule.is_complete(date("2025-03-05")));
+        assert!(schedule.is_complete(date("2025-03-06")));
+    }
+}
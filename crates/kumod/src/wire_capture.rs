@@ -0,0 +1,370 @@
+//! Records the outgoing SMTP sessions that deliver to a given domain
+//! into a file, so that problems with a specific provider can be
+//! diagnosed without resorting to packet capture.
+//! Message content and authentication credentials are redacted.
+use crate::http_server::admin_trace_smtp_client_v1::{
+    SmtpClientTraceEvent, SmtpClientTraceEventPayload, SmtpClientTraceManager,
+};
+use anyhow::Context;
+use chrono::Utc;
+use kumo_api_types::wire_capture::{WireCaptureV1Request, WireCaptureV1Response};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// The most events that are held for a session before we
+/// know whether it delivers to the captured domain
+const MAX_PENDING_EVENTS: usize = 256;
+
+static CAPTURE_DIR: LazyLock<Mutex<PathBuf>> =
+    LazyLock::new(|| Mutex::new(PathBuf::from("/var/log/kumomta/wire-capture")));
+
+/// The active captures, keyed by domain.
+/// Notifying a capture causes it to stop.
+static CAPTURES: LazyLock<Mutex<HashMap<String, Arc<Notify>>>> = LazyLock::new(Mutex::default);
+
+pub fn set_capture_directory(path: PathBuf) {
+    *CAPTURE_DIR.lock() = path;
+}
+
+/// Checks that the request is acceptable, returning the normalized domain
+pub fn validate_request(request: &WireCaptureV1Request) -> anyhow::Result<String> {
+    let domain = request.domain.trim().to_ascii_lowercase();
+    // The domain is used as part of the file name, so be strict
+    anyhow::ensure!(
+        !domain.is_empty()
+            && !domain.starts_with('.')
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+        "invalid domain {:?}",
+        request.domain
+    );
+    anyhow::ensure!(!request.duration().is_zero(), "duration must be non-zero");
+    anyhow::ensure!(request.max_size() > 0, "max_size must be non-zero");
+    Ok(domain)
+}
+
+/// Starts capturing the sessions that deliver to the requested domain,
+/// replacing any existing capture for that domain
+pub async fn start_capture(request: WireCaptureV1Request) -> anyhow::Result<WireCaptureV1Response> {
+    let domain = validate_request(&request)?;
+    let duration = request.duration();
+
+    let dir = CAPTURE_DIR.lock().clone();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join(format!(
+        "{domain}-{}.jsonl",
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    let file = File::create(&path)
+        .await
+        .with_context(|| format!("creating {}", path.display()))?;
+
+    // Subscribe before replacing any existing capture, so that
+    // no events are missed in between the two
+    let rx = SmtpClientTraceManager::subscribe();
+    let stop = Arc::new(Notify::new());
+    if let Some(previous) = CAPTURES.lock().insert(domain.clone(), stop.clone()) {
+        previous.notify_one();
+    }
+
+    let capture = Capture {
+        domain: domain.clone(),
+        include_data: request.include_data,
+        max_size: request.max_size(),
+        written: 0,
+        file,
+        sessions: HashMap::new(),
+    };
+    tracing::info!(
+        "capturing smtp sessions for {domain} into {} for {duration:?}",
+        path.display()
+    );
+    kumo_server_runtime::spawn(
+        format!("wire capture {domain}"),
+        capture.run(rx, stop, Instant::now() + duration),
+    )?;
+
+    Ok(WireCaptureV1Response {
+        path: path.display().to_string(),
+        expires: Utc::now() + duration,
+    })
+}
+
+#[derive(Default)]
+struct Session {
+    /// The session has delivered to the captured domain
+    matched: bool,
+    /// Events recorded before we knew whether the session matches
+    pending: VecDeque<SmtpClientTraceEvent>,
+    /// The server has prompted for an authentication response
+    auth_challenge: bool,
+}
+
+struct Capture {
+    domain: String,
+    include_data: bool,
+    max_size: u64,
+    written: u64,
+    file: File,
+    sessions: HashMap<String, Session>,
+}
+
+impl Capture {
+    async fn run(
+        mut self,
+        mut rx: Receiver<SmtpClientTraceEvent>,
+        stop: Arc<Notify>,
+        deadline: Instant,
+    ) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => {
+                    tracing::info!("wire capture for {} has expired", self.domain);
+                    break;
+                }
+                _ = stop.notified() => {
+                    tracing::info!("wire capture for {} was replaced", self.domain);
+                    break;
+                }
+                event = rx.recv() => {
+                    let result = match event {
+                        Ok(event) => self.process(event).await,
+                        Err(RecvError::Lagged(n)) => {
+                            self.write(lagged_event(n)).await
+                        }
+                        Err(RecvError::Closed) => Ok(false),
+                    };
+                    match result {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            tracing::info!(
+                                "wire capture for {} has reached its size limit",
+                                self.domain
+                            );
+                            break;
+                        }
+                        Err(err) => {
+                            tracing::error!("wire capture for {}: {err:#}", self.domain);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = self.file.flush().await {
+            tracing::error!("wire capture for {}: {err:#}", self.domain);
+        }
+
+        let mut captures = CAPTURES.lock();
+        if captures
+            .get(&self.domain)
+            .is_some_and(|current| Arc::ptr_eq(current, &stop))
+        {
+            captures.remove(&self.domain);
+        }
+    }
+
+    /// Records the event if its session delivers to the captured domain.
+    /// Returns false if the size limit has been reached.
+    async fn process(&mut self, event: SmtpClientTraceEvent) -> anyhow::Result<bool> {
+        let Some(id) = event.conn_meta.get("id").and_then(|v| v.as_str()) else {
+            return Ok(true);
+        };
+        let id = id.to_string();
+        let closed = matches!(event.payload, SmtpClientTraceEventPayload::Closed);
+        let domain_matches = event
+            .conn_meta
+            .get("domain")
+            .and_then(|v| v.as_str())
+            .is_some_and(|domain| domain.eq_ignore_ascii_case(&self.domain));
+
+        let session = self.sessions.entry(id.clone()).or_default();
+        let event = redact(session, event, self.include_data);
+
+        let mut records = vec![];
+        if domain_matches && !session.matched {
+            // Include the connection setup, EHLO and STARTTLS
+            // that took place before the message was obtained
+            session.matched = true;
+            records.extend(session.pending.drain(..));
+        }
+        if session.matched {
+            records.push(event);
+        } else {
+            if session.pending.len() >= MAX_PENDING_EVENTS {
+                session.pending.pop_front();
+            }
+            session.pending.push_back(event);
+        }
+        if closed {
+            self.sessions.remove(&id);
+        }
+
+        for record in records {
+            if !self.write(record).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Appends an event to the file.
+    /// Returns false if the size limit has been reached.
+    async fn write(&mut self, event: SmtpClientTraceEvent) -> anyhow::Result<bool> {
+        let mut line = serde_json::to_string(&event.to_v1())?;
+        line.push('\n');
+        let len = line.len() as u64;
+        if self.written + len > self.max_size {
+            return Ok(false);
+        }
+        self.file.write_all(line.as_bytes()).await?;
+        self.written += len;
+        Ok(true)
+    }
+}
+
+fn lagged_event(count: u64) -> SmtpClientTraceEvent {
+    SmtpClientTraceEvent {
+        conn_meta: serde_json::json!({}),
+        payload: SmtpClientTraceEventPayload::Diagnostic {
+            level: tracing::Level::WARN,
+            message: format!("the capture fell behind, and {count} events were not recorded"),
+        },
+        when: Utc::now(),
+    }
+}
+
+fn redact(
+    session: &mut Session,
+    mut event: SmtpClientTraceEvent,
+    include_data: bool,
+) -> SmtpClientTraceEvent {
+    event.payload = match event.payload {
+        SmtpClientTraceEventPayload::WriteData(data) if !include_data => {
+            SmtpClientTraceEventPayload::WriteData(format!(
+                "<{} bytes of message content>\r\n",
+                data.len()
+            ))
+        }
+        SmtpClientTraceEventPayload::Write(line) => {
+            if std::mem::take(&mut session.auth_challenge) {
+                SmtpClientTraceEventPayload::Write("<redacted>\r\n".to_string())
+            } else {
+                SmtpClientTraceEventPayload::Write(redact_auth_command(&line))
+            }
+        }
+        SmtpClientTraceEventPayload::Read(data) => {
+            session.auth_challenge = data.starts_with(b"334");
+            SmtpClientTraceEventPayload::Read(data)
+        }
+        payload => payload,
+    };
+    event
+}
+
+/// Removes the initial response, which holds the credentials,
+/// from an AUTH command
+fn redact_auth_command(line: &str) -> String {
+    let mut words = line.split_ascii_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(verb), Some(mechanism), Some(_)) if verb.eq_ignore_ascii_case("AUTH") => {
+            format!("{verb} {mechanism} <redacted>\r\n")
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(payload: SmtpClientTraceEventPayload) -> SmtpClientTraceEvent {
+        SmtpClientTraceEvent {
+            conn_meta: serde_json::json!({"id": "1"}),
+            payload,
+            when: Utc::now(),
+        }
+    }
+
+    fn redacted_write(session: &mut Session, line: &str) -> String {
+        match redact(
+            session,
+            event(SmtpClientTraceEventPayload::Write(line.to_string())),
+            false,
+        )
+        .payload
+        {
+            SmtpClientTraceEventPayload::Write(line) => line,
+            payload => panic!("unexpected {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn redaction() {
+        let mut session = Session::default();
+
+        assert_eq!(
+            redacted_write(&mut session, "AUTH PLAIN AHVzZXIAcGFzcw==\r\n"),
+            "AUTH PLAIN <redacted>\r\n"
+        );
+        assert_eq!(
+            redacted_write(&mut session, "MAIL FROM:<a@example.com>\r\n"),
+            "MAIL FROM:<a@example.com>\r\n"
+        );
+
+        redact(
+            &mut session,
+            event(SmtpClientTraceEventPayload::Read(b"334 \r\n".to_vec())),
+            false,
+        );
+        assert_eq!(
+            redacted_write(&mut session, "c2VjcmV0\r\n"),
+            "<redacted>\r\n"
+        );
+        assert_eq!(redacted_write(&mut session, "QUIT\r\n"), "QUIT\r\n");
+
+        match redact(
+            &mut session,
+            event(SmtpClientTraceEventPayload::WriteData(
+                "Subject: hello\r\n".to_string(),
+            )),
+            false,
+        )
+        .payload
+        {
+            SmtpClientTraceEventPayload::WriteData(data) => {
+                assert_eq!(data, "<16 bytes of message content>\r\n")
+            }
+            payload => panic!("unexpected {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let request = |domain: &str| WireCaptureV1Request {
+            domain: domain.to_string(),
+            duration: None,
+            max_size: None,
+            include_data: false,
+        };
+        assert_eq!(
+            validate_request(&request(" Example.COM ")).unwrap(),
+            "example.com"
+        );
+        assert!(validate_request(&request("")).is_err());
+        assert!(validate_request(&request("../etc")).is_err());
+        assert!(validate_request(&request("a/b.com")).is_err());
+    }
+}
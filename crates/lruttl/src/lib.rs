@@ -29,6 +29,8 @@ trait CachePurger {
     fn name(&self) -> &str;
     fn purge(&self) -> usize;
     fn prune_expired(&self) -> usize;
+    fn capacity(&self) -> usize;
+    fn set_capacity(&self, capacity: usize);
 }
 
 impl<K: Clone + Hash + Eq, V: Clone> Inner<K, V> {
@@ -65,6 +67,47 @@ impl<K: Clone + Hash + Eq, V: Clone> CachePurger for Inner<K, V> {
     fn prune_expired(&self) -> usize {
         self.do_prune_expired()
     }
+    fn capacity(&self) -> usize {
+        self.cache.lock().capacity()
+    }
+    fn set_capacity(&self, capacity: usize) {
+        self.cache.lock().set_capacity(capacity);
+    }
+}
+
+fn live_caches() -> Vec<Arc<dyn CachePurger + Send + Sync>> {
+    let mut purgers = vec![];
+    let mut caches = CACHES.lock();
+    caches.retain(|entry| match entry.upgrade() {
+        Some(purger) => {
+            purgers.push(purger);
+            true
+        }
+        None => false,
+    });
+    purgers
+}
+
+/// Returns the name and capacity of each of the registered caches
+pub fn get_cache_capacities() -> Vec<(String, usize)> {
+    live_caches()
+        .iter()
+        .map(|cache| (cache.name().to_string(), cache.capacity()))
+        .collect()
+}
+
+/// Changes the capacity of the registered caches with the specified
+/// name, returning the number of caches that were changed.
+/// Reducing the capacity evicts the least recently used entries.
+pub fn set_cache_capacity(name: &str, capacity: usize) -> usize {
+    let mut changed = 0;
+    for cache in live_caches() {
+        if cache.name() == name {
+            cache.set_capacity(capacity);
+            changed += 1;
+        }
+    }
+    changed
 }
 
 pub fn purge_all_caches() {
@@ -0,0 +1,361 @@
+//! Checks the MIME structure of a message against a policy that
+//! restricts the types and sizes of its parts, such as disallowing
+//! executable attachments
+use crate::{MimePart, Result};
+use serde::{Deserialize, Serialize};
+
+/// The restrictions to apply to a message.
+/// Each restriction is disabled when it is empty or not set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttachmentPolicy {
+    /// File name extensions, such as `exe`, that are not permitted.
+    /// Matched case-insensitively against the file name of each part.
+    #[serde(default)]
+    pub blocked_extensions: Vec<String>,
+
+    /// Content types, such as `application/x-msdownload`, that are
+    /// not permitted. An entry of the form `application/*` matches
+    /// any content type with that primary type.
+    #[serde(default)]
+    pub blocked_content_types: Vec<String>,
+
+    /// File types that are not permitted, identified by the leading
+    /// bytes of the decoded content of each part, regardless of
+    /// its declared name or type
+    #[serde(default)]
+    pub blocked_file_types: Vec<FileType>,
+
+    /// The maximum size, in bytes, of the decoded content of a
+    /// single non-multipart part
+    #[serde(default)]
+    pub max_part_size: Option<usize>,
+
+    /// The maximum depth at which a part may appear in the MIME tree.
+    /// The top level of the message has a depth of 0, and the
+    /// children of a multipart part at depth N have a depth of N+1.
+    #[serde(default)]
+    pub max_nesting_depth: Option<usize>,
+}
+
+/// File types that can be recognized from their leading bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FileType {
+    /// Windows and DOS executables
+    Executable,
+    /// ELF executables and shared libraries
+    Elf,
+    /// Mach-O executables
+    MachO,
+    /// Microsoft Installer packages and legacy Office documents,
+    /// which can carry macros
+    OleCompound,
+    Zip,
+    Rar,
+    SevenZip,
+    Gzip,
+    Pdf,
+}
+
+impl FileType {
+    fn identify(data: &[u8]) -> Option<Self> {
+        const SIGNATURES: &[(&[u8], FileType)] = &[
+            (b"MZ", FileType::Executable),
+            (b"\x7fELF", FileType::Elf),
+            (b"\xfe\xed\xfa\xce", FileType::MachO),
+            (b"\xfe\xed\xfa\xcf", FileType::MachO),
+            (b"\xce\xfa\xed\xfe", FileType::MachO),
+            (b"\xcf\xfa\xed\xfe", FileType::MachO),
+            (b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", FileType::OleCompound),
+            (b"PK\x03\x04", FileType::Zip),
+            (b"PK\x05\x06", FileType::Zip),
+            (b"Rar!\x1a\x07", FileType::Rar),
+            (b"7z\xbc\xaf\x27\x1c", FileType::SevenZip),
+            (b"\x1f\x8b", FileType::Gzip),
+            (b"%PDF-", FileType::Pdf),
+        ];
+        SIGNATURES
+            .iter()
+            .find_map(|(magic, file_type)| data.starts_with(magic).then_some(*file_type))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AttachmentViolationKind {
+    BlockedExtension,
+    BlockedContentType,
+    BlockedFileType,
+    PartTooLarge,
+    NestingTooDeep,
+    /// The content of the part could not be decoded,
+    /// so it could not be checked
+    Undecodable,
+}
+
+/// Describes a part of the message that does not satisfy the policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttachmentViolation {
+    pub kind: AttachmentViolationKind,
+    /// The position of the part in the MIME tree, as the sequence
+    /// of child indices from the top level of the message.
+    /// The top level itself is the empty sequence.
+    pub part: Vec<usize>,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    /// A human readable explanation of the violation
+    pub reason: String,
+}
+
+impl<'a> MimePart<'a> {
+    /// Checks the parts of the message against the policy, returning
+    /// the violations that were found, in the order in which the
+    /// parts appear. An empty result means that the message satisfies
+    /// the policy.
+    pub fn check_attachment_policy(
+        &self,
+        policy: &AttachmentPolicy,
+    ) -> Result<Vec<AttachmentViolation>> {
+        let mut violations = vec![];
+        self.check_attachment_policy_impl(policy, &mut vec![], &mut violations)?;
+        Ok(violations)
+    }
+
+    fn check_attachment_policy_impl(
+        &self,
+        policy: &AttachmentPolicy,
+        path: &mut Vec<usize>,
+        violations: &mut Vec<AttachmentViolation>,
+    ) -> Result<()> {
+        let content_type = self.headers().content_type()?;
+        let file_name = self.file_name()?;
+        let content_type_value = content_type
+            .as_ref()
+            .map(|ct| ct.value.to_ascii_lowercase());
+
+        let mut violation = |kind, reason: String| {
+            violations.push(AttachmentViolation {
+                kind,
+                part: path.clone(),
+                file_name: file_name.clone(),
+                content_type: content_type_value.clone(),
+                reason,
+            });
+        };
+
+        if let Some(name) = &file_name {
+            if let Some((_, ext)) = name.rsplit_once('.') {
+                if policy
+                    .blocked_extensions
+                    .iter()
+                    .any(|blocked| blocked.trim_start_matches('.').eq_ignore_ascii_case(ext))
+                {
+                    violation(
+                        AttachmentViolationKind::BlockedExtension,
+                        format!("file name {name} has a blocked extension"),
+                    );
+                }
+            }
+        }
+
+        if let Some(ct) = &content_type_value {
+            if policy
+                .blocked_content_types
+                .iter()
+                .any(|blocked| content_type_matches(blocked, ct))
+            {
+                violation(
+                    AttachmentViolationKind::BlockedContentType,
+                    format!("content type {ct} is blocked"),
+                );
+            }
+        }
+
+        if self.child_parts().is_empty() {
+            let needs_body =
+                !policy.blocked_file_types.is_empty() || policy.max_part_size.is_some();
+            if needs_body {
+                match self.transfer_decoded_body() {
+                    Ok(data) => {
+                        if let Some(file_type) = FileType::identify(&data) {
+                            if policy.blocked_file_types.contains(&file_type) {
+                                violation(
+                                    AttachmentViolationKind::BlockedFileType,
+                                    format!("content is a blocked file type {file_type:?}"),
+                                );
+                            }
+                        }
+                        if let Some(max_size) = policy.max_part_size {
+                            if data.len() > max_size {
+                                violation(
+                                    AttachmentViolationKind::PartTooLarge,
+                                    format!(
+                                        "size {} exceeds the limit of {max_size} bytes",
+                                        data.len()
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        violation(
+                            AttachmentViolationKind::Undecodable,
+                            format!("content could not be decoded: {err:#}"),
+                        );
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(max_depth) = policy.max_nesting_depth {
+            if path.len() >= max_depth {
+                violation(
+                    AttachmentViolationKind::NestingTooDeep,
+                    format!("multipart nesting exceeds the limit of {max_depth} levels"),
+                );
+                // Don't report every part beneath this one
+                return Ok(());
+            }
+        }
+
+        for (idx, child) in self.child_parts().iter().enumerate() {
+            path.push(idx);
+            child.check_attachment_policy_impl(policy, path, violations)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// Returns the file name of the part, taken from the filename
+    /// parameter of the Content-Disposition header, or the name
+    /// parameter of the Content-Type header
+    fn file_name(&self) -> Result<Option<String>> {
+        if let Some(cd) = self.headers().content_disposition()? {
+            if let Some(name) = cd.get("filename") {
+                return Ok(Some(name));
+            }
+        }
+        Ok(self.headers().content_type()?.and_then(|ct| ct.get("name")))
+    }
+}
+
+/// Matches a content type against an entry of blocked_content_types
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(primary) => content_type
+            .split_once('/')
+            .map(|(ct_primary, _)| ct_primary.eq_ignore_ascii_case(primary))
+            .unwrap_or(false),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MESSAGE: &str = concat!(
+        "Subject: attachments\r\n",
+        "Content-Type: multipart/mixed; boundary=\"outer\"\r\n",
+        "\r\n",
+        "--outer\r\n",
+        "Content-Type: multipart/alternative; boundary=\"inner\"\r\n",
+        "\r\n",
+        "--inner\r\n",
+        "Content-Type: text/plain\r\n",
+        "\r\n",
+        "Hello\r\n",
+        "--inner--\r\n",
+        "--outer\r\n",
+        "Content-Type: application/octet-stream; name=\"invoice.PDF\"\r\n",
+        "Content-Transfer-Encoding: base64\r\n",
+        "\r\n",
+        "TVqQAAMAAAAEAAAA//8AALgAAAAAAAAAQAAAAAAAAAA=\r\n",
+        "--outer\r\n",
+        "Content-Type: application/pdf\r\n",
+        "Content-Disposition: attachment; filename=\"report.pdf\"\r\n",
+        "\r\n",
+        "%PDF-1.4 not really\r\n",
+        "--outer--\r\n",
+    );
+
+    #[test]
+    fn no_policy() {
+        let part = MimePart::parse(MESSAGE).unwrap();
+        assert_eq!(
+            part.check_attachment_policy(&AttachmentPolicy::default())
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn violations() {
+        let part = MimePart::parse(MESSAGE).unwrap();
+        let policy = AttachmentPolicy {
+            blocked_extensions: vec![".pdf".to_string()],
+            blocked_content_types: vec![
+                "text/*".to_string(),
+                "application/octet-stream".to_string(),
+            ],
+            blocked_file_types: vec![FileType::Executable],
+            max_part_size: Some(25),
+            max_nesting_depth: None,
+        };
+        let violations = part.check_attachment_policy(&policy).unwrap();
+        let summary: Vec<_> = violations
+            .iter()
+            .map(|v| (v.kind, v.part.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (AttachmentViolationKind::BlockedContentType, vec![0, 0]),
+                (AttachmentViolationKind::BlockedExtension, vec![1]),
+                (AttachmentViolationKind::BlockedContentType, vec![1]),
+                (AttachmentViolationKind::BlockedFileType, vec![1]),
+                (AttachmentViolationKind::PartTooLarge, vec![1]),
+                (AttachmentViolationKind::BlockedExtension, vec![2]),
+            ]
+        );
+        assert_eq!(violations[1].file_name.as_deref(), Some("invoice.PDF"));
+        assert_eq!(
+            violations[1].content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            violations[4].reason,
+            "size 32 exceeds the limit of 25 bytes"
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let part = MimePart::parse(MESSAGE).unwrap();
+        let policy = AttachmentPolicy {
+            max_nesting_depth: Some(1),
+            ..Default::default()
+        };
+        let violations = part.check_attachment_policy(&policy).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, AttachmentViolationKind::NestingTooDeep);
+        assert_eq!(violations[0].part, vec![0]);
+
+        let policy = AttachmentPolicy {
+            max_nesting_depth: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(part.check_attachment_policy(&policy).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn identify() {
+        assert_eq!(
+            FileType::identify(b"MZ\x90\x00"),
+            Some(FileType::Executable)
+        );
+        assert_eq!(FileType::identify(b"PK\x03\x04..."), Some(FileType::Zip));
+        assert_eq!(FileType::identify(b"hello"), None);
+        assert_eq!(FileType::identify(b""), None);
+    }
+}
@@ -1,13 +1,120 @@
-use crate::mimepart::AttachmentOptions;
+use crate::mimepart::{AttachmentOptions, BASE64_RFC2045};
 use crate::{HeaderMap, MailParsingError, MimePart};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// The number of bytes of streamed attachment content that are
+/// encoded at a time. This is a multiple of 57 so that each chunk
+/// encodes to complete 76 column base64 lines.
+const STREAM_CHUNK_SIZE: usize = 57 * 1024;
+
+enum StreamedSource<'a> {
+    Reader(Box<dyn Read + Send + 'a>),
+    Path(PathBuf),
+}
+
+/// An attachment whose content is read and encoded when
+/// the message is written, rather than being held in memory
+struct StreamedAttachment<'a> {
+    content_type: String,
+    source: StreamedSource<'a>,
+    opts: Option<AttachmentOptions>,
+}
+
+impl<'a> StreamedAttachment<'a> {
+    /// Returns a part with the headers for this attachment,
+    /// but without its content
+    fn placeholder(&self) -> MimePart<'static> {
+        MimePart::new_binary(&self.content_type, &[], self.opts.as_ref())
+    }
+
+    fn open(self) -> Result<Box<dyn Read + Send + 'a>, MailParsingError> {
+        match self.source {
+            StreamedSource::Reader(reader) => Ok(reader),
+            StreamedSource::Path(path) => {
+                let file = std::fs::File::open(&path).map_err(|err| {
+                    MailParsingError::ReadError(format!("{}: {err:#}", path.display()))
+                })?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    /// Read the entire content into memory to produce a regular part
+    fn into_part(self) -> Result<MimePart<'static>, MailParsingError> {
+        let content_type = self.content_type.clone();
+        let opts = self.opts.clone();
+        let mut data = vec![];
+        self.open()?
+            .read_to_end(&mut data)
+            .map_err(|err| MailParsingError::ReadError(format!("{err:#}")))?;
+        Ok(MimePart::new_binary(&content_type, &data, opts.as_ref()))
+    }
+
+    /// Read, base64 encode and write the content one chunk at a time
+    fn write_body<W: Write>(self, out: &mut W) -> Result<(), MailParsingError> {
+        let mut reader = self.open()?;
+        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+        let mut wrote_any = false;
+
+        loop {
+            let mut len = 0;
+            while len < chunk.len() {
+                match reader.read(&mut chunk[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(MailParsingError::ReadError(format!("{err:#}"))),
+                }
+            }
+            if len == 0 {
+                break;
+            }
+
+            let mut encoded = BASE64_RFC2045.encode(&chunk[..len]);
+            if !encoded.ends_with("\r\n") {
+                encoded.push_str("\r\n");
+            }
+            out.write_all(encoded.as_bytes())
+                .map_err(|_| MailParsingError::WriteMessageIOError)?;
+            wrote_any = true;
+
+            if len < chunk.len() {
+                // Reached the end of the content
+                break;
+            }
+        }
+
+        if !wrote_any {
+            // Match the empty body produced by MimePart::new_binary
+            out.write_all(b"\r\n")
+                .map_err(|_| MailParsingError::WriteMessageIOError)?;
+        }
+        Ok(())
+    }
+}
+
+enum Attachment<'a> {
+    Part(MimePart<'a>),
+    Streamed(StreamedAttachment<'a>),
+}
+
+/// The location of a part in the mime tree, as the sequence
+/// of child indices leading to it from the root
+type PartPtr = Vec<usize>;
+
+/// Maps the location of the placeholder part in the mime tree
+/// to the streamed attachment that it represents
+type StreamedMap<'a> = HashMap<PartPtr, StreamedAttachment<'a>>;
 
 #[derive(Default)]
 pub struct MessageBuilder<'a> {
     text: Option<String>,
     html: Option<String>,
     headers: HeaderMap<'a>,
-    inline: Vec<MimePart<'a>>,
-    attached: Vec<MimePart<'a>>,
+    inline: Vec<Attachment<'a>>,
+    attached: Vec<Attachment<'a>>,
     stable_content: bool,
 }
 
@@ -32,11 +139,56 @@ impl<'a> MessageBuilder<'a> {
         let is_inline = opts.map(|opt| opt.inline).unwrap_or(false);
 
         let part = MimePart::new_binary(content_type, data, opts);
+        self.push_attachment(Attachment::Part(part), is_inline);
+    }
+
+    /// Attach content that is read from `reader` when the message is
+    /// written by `write_message`, rather than being held in memory.
+    /// If the message is produced by `build` instead, the content
+    /// will be read into memory at that time.
+    pub fn attach_reader(
+        &mut self,
+        content_type: &str,
+        reader: impl Read + Send + 'a,
+        opts: Option<&AttachmentOptions>,
+    ) {
+        self.attach_streamed(content_type, StreamedSource::Reader(Box::new(reader)), opts);
+    }
 
+    /// Attach the content of the file at `path`. The file is opened
+    /// and read when the message is written by `write_message`, or
+    /// produced by `build`.
+    pub fn attach_file(
+        &mut self,
+        content_type: &str,
+        path: impl Into<PathBuf>,
+        opts: Option<&AttachmentOptions>,
+    ) {
+        self.attach_streamed(content_type, StreamedSource::Path(path.into()), opts);
+    }
+
+    fn attach_streamed(
+        &mut self,
+        content_type: &str,
+        source: StreamedSource<'a>,
+        opts: Option<&AttachmentOptions>,
+    ) {
+        let is_inline = opts.map(|opt| opt.inline).unwrap_or(false);
+        self.push_attachment(
+            Attachment::Streamed(StreamedAttachment {
+                content_type: content_type.to_string(),
+                source,
+                opts: opts.cloned(),
+            }),
+            is_inline,
+        );
+    }
+
+    fn push_attachment(&mut self, attachment: Attachment<'a>, is_inline: bool) {
         if is_inline {
-            self.inline.push(part);
+            self.inline.push(attachment);
         } else {
-            self.attached.push(part);
+            self.attached.push(attachment);
         }
     }
 
@@ -47,14 +199,60 @@ impl<'a> MessageBuilder<'a> {
             .ok()
             .and_then(|opt_cd| opt_cd.map(|cd| cd.value == "inline"))
             .unwrap_or(false);
-        if is_inline {
-            self.inline.push(part);
-        } else {
-            self.attached.push(part);
-        }
+        self.push_attachment(Attachment::Part(part), is_inline);
     }
 
     pub fn build(self) -> Result<MimePart<'a>, MailParsingError> {
+        self.build_impl(None)
+    }
+
+    /// Build the message and write it to `out`.
+    /// Content attached via `attach_reader` or `attach_file` is read
+    /// and encoded one chunk at a time as it is written, so that it
+    /// is never held in memory in its entirety.
+    pub fn write_message<W: Write>(self, out: &mut W) -> Result<(), MailParsingError> {
+        let mut streamed = StreamedMap::new();
+        let root = self.build_impl(Some(&mut streamed))?;
+        write_part(&root, &mut vec![], &mut streamed, out)
+    }
+
+    /// Builds the mime tree. When streamed is Some, streamed attachments
+    /// are represented by placeholder parts and recorded in streamed,
+    /// otherwise they are read into memory.
+    fn build_impl(
+        self,
+        mut streamed: Option<&mut StreamedMap<'a>>,
+    ) -> Result<MimePart<'a>, MailParsingError> {
+        let has_mixed = !self.attached.is_empty();
+
+        let mut resolve =
+            |attachment: Attachment<'a>, ptr: PartPtr| -> Result<MimePart<'a>, MailParsingError> {
+                match (attachment, streamed.as_mut()) {
+                    (Attachment::Part(part), _) => Ok(part),
+                    (Attachment::Streamed(attachment), Some(streamed)) => {
+                        let part = attachment.placeholder();
+                        streamed.insert(ptr, attachment);
+                        Ok(part)
+                    }
+                    (Attachment::Streamed(attachment), None) => attachment.into_part(),
+                }
+            };
+
+        let mut inline = Vec::with_capacity(self.inline.len());
+        for (idx, attachment) in self.inline.into_iter().enumerate() {
+            let ptr = if has_mixed {
+                vec![0, idx + 1]
+            } else {
+                vec![idx + 1]
+            };
+            inline.push(resolve(attachment, ptr)?);
+        }
+
+        let mut attached = Vec::with_capacity(self.attached.len());
+        for (idx, attachment) in self.attached.into_iter().enumerate() {
+            attached.push(resolve(attachment, vec![idx + 1])?);
+        }
+
         let text = self.text.as_deref().map(MimePart::new_text_plain);
         let html = self.html.as_deref().map(MimePart::new_html);
 
@@ -77,10 +275,10 @@ impl<'a> MessageBuilder<'a> {
             }
         };
 
-        let content_node = if !self.inline.is_empty() {
-            let mut parts = Vec::with_capacity(self.inline.len() + 1);
+        let content_node = if !inline.is_empty() {
+            let mut parts = Vec::with_capacity(inline.len() + 1);
             parts.push(content_node);
-            parts.extend(self.inline.into_iter());
+            parts.extend(inline.into_iter());
             MimePart::new_multipart(
                 "multipart/related",
                 parts,
@@ -94,10 +292,10 @@ impl<'a> MessageBuilder<'a> {
             content_node
         };
 
-        let mut root = if !self.attached.is_empty() {
-            let mut parts = Vec::with_capacity(self.attached.len() + 1);
+        let mut root = if !attached.is_empty() {
+            let mut parts = Vec::with_capacity(attached.len() + 1);
             parts.push(content_node);
-            parts.extend(self.attached.into_iter());
+            parts.extend(attached.into_iter());
             MimePart::new_multipart(
                 "multipart/mixed",
                 parts,
@@ -138,6 +336,49 @@ impl<'a> MessageBuilder<'a> {
     }
 }
 
+/// Write part, substituting the content of streamed attachments
+/// for their placeholders
+fn write_part<W: Write>(
+    part: &MimePart,
+    ptr: &mut PartPtr,
+    streamed: &mut StreamedMap,
+    out: &mut W,
+) -> Result<(), MailParsingError> {
+    let placeholder = streamed.remove(ptr.as_slice());
+    if placeholder.is_none() && !streamed.keys().any(|p| p.starts_with(ptr.as_slice())) {
+        return part.write_message(out);
+    }
+
+    for hdr in part.headers().iter() {
+        hdr.write_header(out)
+            .map_err(|_| MailParsingError::WriteMessageIOError)?;
+    }
+    out.write_all(b"\r\n")
+        .map_err(|_| MailParsingError::WriteMessageIOError)?;
+
+    if let Some(attachment) = placeholder {
+        return attachment.write_body(out);
+    }
+
+    // This is one of the multipart containers made by build_impl,
+    // so there is no intro or outro to preserve
+    let boundary = part
+        .headers()
+        .content_type()?
+        .and_then(|ct| ct.get("boundary"))
+        .ok_or_else(|| {
+            MailParsingError::WriteMessageWtf("expected Content-Type to have a boundary")
+        })?;
+    for (idx, child) in part.child_parts().iter().enumerate() {
+        write!(out, "--{boundary}\r\n").map_err(|_| MailParsingError::WriteMessageIOError)?;
+        ptr.push(idx);
+        write_part(child, ptr, streamed, out)?;
+        ptr.pop();
+    }
+    write!(out, "--{boundary}--\r\n").map_err(|_| MailParsingError::WriteMessageIOError)?;
+    Ok(())
+}
+
 impl<'a> std::ops::Deref for MessageBuilder<'a> {
     type Target = HeaderMap<'a>;
     fn deref(&self) -> &HeaderMap<'a> {
@@ -189,4 +430,77 @@ Content-Transfer-Encoding: quoted-printable\r
 "#
         );
     }
+
+    #[test]
+    fn streamed_attachments() {
+        // Large enough to span multiple chunks
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let inline = AttachmentOptions {
+            file_name: None,
+            inline: true,
+            content_id: Some("image".to_string()),
+        };
+        let attachment = AttachmentOptions {
+            file_name: Some("data.bin".to_string()),
+            inline: false,
+            content_id: None,
+        };
+
+        let make_builder = |streamed: bool| {
+            let mut b = MessageBuilder::new();
+            b.set_stable_content(true);
+            b.set_subject("Attachments");
+            b.text_plain("See attached");
+            if streamed {
+                b.attach_reader("image/png", &data[0..1000], Some(&inline));
+                b.attach_reader("application/octet-stream", &data[..], Some(&attachment));
+                b.attach_reader("application/octet-stream", &b""[..], None);
+            } else {
+                b.attach("image/png", &data[0..1000], Some(&inline));
+                b.attach("application/octet-stream", &data, Some(&attachment));
+                b.attach("application/octet-stream", b"", None);
+            }
+            b
+        };
+
+        let expected = make_builder(false).build().unwrap().to_message_string();
+
+        let mut written = vec![];
+        make_builder(true).write_message(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), expected);
+
+        // build reads the streamed content into memory
+        assert_eq!(
+            make_builder(true).build().unwrap().to_message_string(),
+            expected
+        );
+    }
+
+    #[test]
+    fn many_attachments() {
+        let make_builder = |streamed: bool| {
+            let mut b = MessageBuilder::new();
+            b.set_stable_content(true);
+            b.text_plain("See attached");
+            for i in 0..300 {
+                let data = format!("attachment {i}").into_bytes();
+                if streamed {
+                    b.attach_reader("text/plain", std::io::Cursor::new(data), None);
+                } else {
+                    b.attach("text/plain", &data, None);
+                }
+            }
+            b
+        };
+
+        let expected = make_builder(false).build().unwrap().to_message_string();
+        assert_eq!(
+            make_builder(true).build().unwrap().to_message_string(),
+            expected
+        );
+
+        let mut written = vec![];
+        make_builder(true).write_message(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), expected);
+    }
 }
@@ -0,0 +1,307 @@
+//! Extraction of the key fields of iCalendar (RFC 5545) content,
+//! such as meeting invitations, carried in text/calendar parts
+use crate::{DecodedBody, MimePart, Result};
+use serde::Serialize;
+
+/// The key fields of an iCalendar object.
+/// The event fields are taken from the first VEVENT component.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct CalendarInfo {
+    /// The iTIP method, such as `REQUEST`, `REPLY` or `CANCEL`
+    pub method: Option<String>,
+    pub uid: Option<String>,
+    pub summary: Option<String>,
+    /// The address of the organizer, without the `mailto:` prefix
+    pub organizer: Option<String>,
+    /// The addresses of the attendees, without the `mailto:` prefix
+    pub attendees: Vec<String>,
+    /// The start of the event, in its iCalendar form,
+    /// such as `20240315T140000Z`
+    pub dtstart: Option<String>,
+    /// The TZID parameter of the DTSTART property, if any
+    pub dtstart_tzid: Option<String>,
+}
+
+impl CalendarInfo {
+    /// Parse iCalendar text. Returns None if the text does not
+    /// contain a VCALENDAR object.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut info = Self::default();
+        let mut in_calendar = false;
+        let mut found_calendar = false;
+        let mut event_depth = 0;
+        let mut seen_event = false;
+
+        for line in unfold(text) {
+            let Some((name, params, value)) = split_content_line(&line) else {
+                continue;
+            };
+
+            if name.eq_ignore_ascii_case("BEGIN") {
+                if value.eq_ignore_ascii_case("VCALENDAR") {
+                    in_calendar = true;
+                    found_calendar = true;
+                } else if value.eq_ignore_ascii_case("VEVENT") && !seen_event {
+                    event_depth = 1;
+                    seen_event = true;
+                } else if event_depth > 0 {
+                    // A nested component, such as VALARM, whose
+                    // properties do not describe the event itself
+                    event_depth += 1;
+                }
+                continue;
+            }
+            if name.eq_ignore_ascii_case("END") {
+                if value.eq_ignore_ascii_case("VCALENDAR") {
+                    in_calendar = false;
+                } else if event_depth > 0 {
+                    event_depth -= 1;
+                }
+                continue;
+            }
+
+            if !in_calendar {
+                continue;
+            }
+
+            if event_depth == 0 {
+                if name.eq_ignore_ascii_case("METHOD") && info.method.is_none() {
+                    info.method.replace(value.trim().to_ascii_uppercase());
+                }
+                continue;
+            }
+            if event_depth > 1 {
+                continue;
+            }
+
+            if name.eq_ignore_ascii_case("UID") {
+                info.uid.replace(unescape_text(value));
+            } else if name.eq_ignore_ascii_case("SUMMARY") {
+                info.summary.replace(unescape_text(value));
+            } else if name.eq_ignore_ascii_case("ORGANIZER") {
+                info.organizer.replace(cal_address(value));
+            } else if name.eq_ignore_ascii_case("ATTENDEE") {
+                info.attendees.push(cal_address(value));
+            } else if name.eq_ignore_ascii_case("DTSTART") {
+                info.dtstart.replace(value.trim().to_string());
+                info.dtstart_tzid = get_param(params, "TZID");
+            }
+        }
+
+        found_calendar.then_some(info)
+    }
+}
+
+impl<'a> MimePart<'a> {
+    /// Returns the text/calendar parts of the message,
+    /// in the order in which they appear
+    pub fn calendar_parts(&self) -> Vec<&Self> {
+        let mut parts = vec![];
+        self.collect_calendar_parts(&mut parts);
+        parts
+    }
+
+    fn collect_calendar_parts<'b>(&'b self, parts: &mut Vec<&'b Self>) {
+        if let Ok(Some(ct)) = self.headers().content_type() {
+            if ct.value.eq_ignore_ascii_case("text/calendar") {
+                parts.push(self);
+            }
+        }
+        for child in self.child_parts() {
+            child.collect_calendar_parts(parts);
+        }
+    }
+
+    /// Locates the first text/calendar part of the message and
+    /// extracts its key fields.
+    /// When the calendar object has no METHOD property, the `method`
+    /// parameter of the Content-Type header is used instead.
+    pub fn calendar_info(&self) -> Result<Option<CalendarInfo>> {
+        let Some(part) = self.calendar_parts().into_iter().next() else {
+            return Ok(None);
+        };
+
+        let text = match part.body()? {
+            DecodedBody::Text(text) => text.as_str().to_string(),
+            DecodedBody::Binary(data) => String::from_utf8_lossy(&data).to_string(),
+        };
+
+        let Some(mut info) = CalendarInfo::parse(&text) else {
+            return Ok(None);
+        };
+        if info.method.is_none() {
+            info.method = part
+                .headers()
+                .content_type()?
+                .and_then(|ct| ct.get("method"))
+                .map(|method| method.to_ascii_uppercase());
+        }
+        Ok(Some(info))
+    }
+}
+
+/// Unfold the content lines; a line that begins with a space
+/// or tab is a continuation of the prior line
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.split('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match line.strip_prefix(|c| c == ' ' || c == '\t') {
+            Some(continuation) if !lines.is_empty() => {
+                if let Some(last) = lines.last_mut() {
+                    last.push_str(continuation);
+                }
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Split a content line into its name, parameters and value.
+/// The value begins after the first colon that is not part
+/// of a quoted parameter value.
+fn split_content_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut in_quotes = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ':' if !in_quotes => {
+                let (name_and_params, value) = (&line[..idx], &line[idx + 1..]);
+                let (name, params) = match name_and_params.find(';') {
+                    Some(semi) => (&name_and_params[..semi], &name_and_params[semi + 1..]),
+                    None => (name_and_params, ""),
+                };
+                return Some((name.trim(), params, value));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn get_param(params: &str, wanted: &str) -> Option<String> {
+    let mut in_quotes = false;
+    let mut start = 0;
+    let mut fields = vec![];
+    for (idx, c) in params.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                fields.push(&params[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&params[start..]);
+
+    fields.into_iter().find_map(|field| {
+        let (name, value) = field.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case(wanted)
+            .then(|| value.trim_matches('"').to_string())
+    })
+}
+
+/// Decode the backslash escapes of a TEXT value
+fn unescape_text(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => result.push('\n'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Returns the address from a CAL-ADDRESS value
+fn cal_address(value: &str) -> String {
+    let value = value.trim();
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => value[7..].to_string(),
+        _ => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INVITE: &str = concat!(
+        "BEGIN:VCALENDAR\r\n",
+        "PRODID:-//Example//EN\r\n",
+        "VERSION:2.0\r\n",
+        "METHOD:REQUEST\r\n",
+        "BEGIN:VEVENT\r\n",
+        "UID:1234@example.com\r\n",
+        "SUMMARY:Planning\\, and review\r\n",
+        "DTSTART;TZID=\"America/New_York\":20240315T140000\r\n",
+        "ORGANIZER;CN=\"Boss: The Big One\":mailto:boss@example.com\r\n",
+        "ATTENDEE;CN=Someone;PARTSTAT=NEEDS-ACTION:mailto:some\r\n",
+        " one@example.com\r\n",
+        "ATTENDEE;CN=Other:MAILTO:other@example.com\r\n",
+        "BEGIN:VALARM\r\n",
+        "TRIGGER:-PT15M\r\n",
+        "SUMMARY:Reminder\r\n",
+        "END:VALARM\r\n",
+        "END:VEVENT\r\n",
+        "END:VCALENDAR\r\n",
+    );
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            CalendarInfo::parse(INVITE).unwrap(),
+            CalendarInfo {
+                method: Some("REQUEST".to_string()),
+                uid: Some("1234@example.com".to_string()),
+                summary: Some("Planning, and review".to_string()),
+                organizer: Some("boss@example.com".to_string()),
+                attendees: vec![
+                    "someone@example.com".to_string(),
+                    "other@example.com".to_string()
+                ],
+                dtstart: Some("20240315T140000".to_string()),
+                dtstart_tzid: Some("America/New_York".to_string()),
+            }
+        );
+
+        assert_eq!(CalendarInfo::parse("hello: there"), None);
+    }
+
+    #[test]
+    fn message() {
+        let message = format!(
+            "Subject: Invitation\r\n\
+            Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+            \r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            You are invited\r\n\
+            --b\r\n\
+            Content-Type: text/calendar; method=CANCEL; charset=utf-8\r\n\
+            \r\n\
+            {}\
+            --b--\r\n",
+            INVITE.replace("METHOD:REQUEST\r\n", "")
+        );
+        let part = MimePart::parse(message.as_str()).unwrap();
+        assert_eq!(part.calendar_parts().len(), 1);
+
+        let info = part.calendar_info().unwrap().unwrap();
+        assert_eq!(info.method.as_deref(), Some("CANCEL"));
+        assert_eq!(info.uid.as_deref(), Some("1234@example.com"));
+
+        let part = MimePart::parse("Subject: hello\r\n\r\nNo calendar\r\n").unwrap();
+        assert_eq!(part.calendar_info().unwrap(), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Heuristic detection of the charset of textual content, used to
+//! decode parts whose charset is missing or wrong.
+//! The detector considers UTF-8, ISO-2022-JP and a small set of common
+//! legacy encodings, and picks the candidate whose decoded form looks
+//! the most like natural language text.
+use charset::Charset;
+
+/// Controls how the charset of a textual part is determined
+/// when decoding its body
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CharsetPolicy {
+    /// Use the charset declared by the Content-Type header,
+    /// defaulting to us-ascii when none is declared.
+    /// An unsupported charset is an error.
+    #[default]
+    Declared,
+    /// Use the declared charset if it is present, supported, and
+    /// decodes the content without errors, otherwise detect the charset.
+    DetectIfInvalid,
+    /// Always detect the charset from the content. The declared
+    /// charset, if any, is preferred when it is as plausible as
+    /// the other candidates.
+    Detect,
+}
+
+/// Legacy encodings that are considered by the detector.
+/// When candidates score equally, the earlier entry wins.
+const CANDIDATES: &[&str] = &[
+    "shift_jis",
+    "gbk",
+    "koi8-r",
+    "windows-1251",
+    "windows-1252",
+    "windows-1250",
+];
+
+fn charset_for_label(label: &str) -> Charset {
+    Charset::for_label_no_replacement(label.as_bytes()).expect("charset label to be known")
+}
+
+/// Returns the name of the charset that most likely produced `bytes`
+pub fn detect_charset(bytes: &[u8]) -> &'static str {
+    detect(bytes, None).name()
+}
+
+pub(crate) fn detect(bytes: &[u8], declared: Option<Charset>) -> Charset {
+    if bytes.is_ascii() {
+        // ISO-2022-JP is a 7-bit encoding that switches to JIS X 0208
+        // using escape sequences
+        if memchr::memmem::find(bytes, b"\x1b$B").is_some()
+            || memchr::memmem::find(bytes, b"\x1b$@").is_some()
+        {
+            return charset_for_label("iso-2022-jp");
+        }
+        return declared.unwrap_or_else(|| charset_for_label("utf-8"));
+    }
+
+    // Legacy multi-byte text is very unlikely to also be valid UTF-8
+    if std::str::from_utf8(bytes).is_ok() {
+        return charset_for_label("utf-8");
+    }
+
+    let mut best: Option<(i64, Charset)> = None;
+    for charset in declared
+        .into_iter()
+        .chain(CANDIDATES.iter().map(|label| charset_for_label(label)))
+    {
+        let (text, _malformed) = charset.decode_without_bom_handling(bytes);
+        let score = score(&text);
+        if best
+            .map(|(best_score, _)| score > best_score)
+            .unwrap_or(true)
+        {
+            best.replace((score, charset));
+        }
+    }
+
+    best.map(|(_, charset)| charset)
+        .unwrap_or_else(|| charset_for_label("utf-8"))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class {
+    AsciiLetter,
+    /// Whitespace, digits and punctuation that are
+    /// plausible in any of the candidates
+    Neutral,
+    /// A non-ASCII latin letter
+    Latin,
+    /// A lowercase greek or cyrillic letter
+    Lower,
+    /// An uppercase greek or cyrillic letter
+    Upper,
+    Kana,
+    Ideograph,
+    HalfwidthKana,
+    /// Other symbols, which are rare in text and are typically
+    /// the result of decoding with the wrong charset
+    Symbol,
+    /// Control characters, private use characters and replacement
+    /// characters for invalid sequences
+    Invalid,
+}
+
+fn classify(c: char) -> Class {
+    match c {
+        'a'..='z' | 'A'..='Z' => Class::AsciiLetter,
+        c if c.is_ascii_whitespace() => Class::Neutral,
+        c if c.is_ascii_control() => Class::Invalid,
+        c if c.is_ascii() => Class::Neutral,
+        '\u{fffd}' | '\u{e000}'..='\u{f8ff}' => Class::Invalid,
+        c if c.is_control() => Class::Invalid,
+        '\u{a0}'
+        | '\u{ab}'
+        | '\u{bb}'
+        | '\u{2010}'..='\u{206f}'
+        | '\u{20ac}'
+        | '\u{3000}'..='\u{303f}'
+        | '\u{ff01}'..='\u{ff60}' => Class::Neutral,
+        '\u{3040}'..='\u{30ff}' => Class::Kana,
+        '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' => Class::Ideograph,
+        '\u{ff61}'..='\u{ff9f}' => Class::HalfwidthKana,
+        c if c.is_alphabetic() && c < '\u{250}' => Class::Latin,
+        c if c.is_alphabetic() && ('\u{370}'..='\u{52f}').contains(&c) => {
+            if c.is_lowercase() {
+                Class::Lower
+            } else {
+                Class::Upper
+            }
+        }
+        _ => Class::Symbol,
+    }
+}
+
+/// Scores how plausible text is as natural language.
+/// The weights are per character, and are chosen so that the
+/// score per input byte is comparable between single byte and
+/// double byte encodings.
+fn score(text: &str) -> i64 {
+    let mut total = 0;
+    let mut prev = Class::Neutral;
+    for c in text.chars() {
+        let class = classify(c);
+        total += match (prev, class) {
+            (_, Class::Invalid) => -100,
+            (_, Class::Symbol) => -3,
+            (_, Class::HalfwidthKana) => -1,
+            (_, Class::Kana) => 4,
+            (_, Class::Ideograph) => 3,
+            // Scripts are not mixed within a word
+            (Class::AsciiLetter, Class::Lower | Class::Upper)
+            | (Class::Lower | Class::Upper, Class::AsciiLetter) => -2,
+            // Words rarely consist of consecutive accented letters
+            (Class::Latin, Class::Latin) => -1,
+            (_, Class::Latin) => 1,
+            (_, Class::Lower) => 2,
+            // Capitals are found at the start of words, or in words
+            // that are entirely capitalized
+            (Class::Lower, Class::Upper) => -2,
+            (Class::Upper, Class::Upper) => 0,
+            (_, Class::Upper) => 1,
+            (_, Class::AsciiLetter | Class::Neutral) => 0,
+        };
+        prev = class;
+    }
+    total
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detection() {
+        for (bytes, expect) in [
+            (&b"hello"[..], "UTF-8"),
+            ("caf\u{e9}".as_bytes(), "UTF-8"),
+            (&b"\x1b$B$3$s$K$A$O\x1b(B"[..], "ISO-2022-JP"),
+            // こんにちは
+            (
+                &b"\x82\xb1\x82\xf1\x82\xc9\x82\xbf\x82\xcd"[..],
+                "Shift_JIS",
+            ),
+            // 你好世界
+            (&b"\xc4\xe3\xba\xc3\xca\xc0\xbd\xe7"[..], "GBK"),
+            // привет мир
+            (&b"\xd0\xd2\xc9\xd7\xc5\xd4 \xcd\xc9\xd2"[..], "KOI8-R"),
+            (
+                &b"\xef\xf0\xe8\xe2\xe5\xf2 \xec\xe8\xf0"[..],
+                "windows-1251",
+            ),
+            // café résumé
+            (&b"caf\xe9 r\xe9sum\xe9"[..], "windows-1252"),
+        ] {
+            assert_eq!(detect_charset(bytes), expect, "{bytes:x?}");
+        }
+    }
+}
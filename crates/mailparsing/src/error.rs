@@ -22,4 +22,6 @@ pub enum MailParsingError {
     ChronoError(chrono::format::ParseError),
     #[error("Mime Tree has too many child parts")]
     TooManyParts,
+    #[error("Error reading message: {0}")]
+    ReadError(String),
 }
@@ -78,6 +78,48 @@ pub struct HeaderParseResult<'a> {
     pub overall_conformance: MessageConformance,
 }
 
+/// Controls how non-ASCII text is emitted when header values
+/// are constructed or rebuilt
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HeaderEncoding {
+    /// Use RFC 2047 encoded-words
+    #[default]
+    EncodedWord,
+    /// Emit the UTF-8 text as-is, as permitted by RFC 6532.
+    /// This is only appropriate when the message will be
+    /// relayed with SMTPUTF8.
+    EightBit,
+}
+
+/// Controls how headers and mime parts are rebuilt and serialized.
+/// The default options produce the same output as `MimePart::rebuild`
+/// and `MimePart::write_message`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// The width at which unstructured header values are folded.
+    /// A word that is longer than this is not broken unless it
+    /// exceeds the hard line length limit.
+    pub fold_width: usize,
+    /// How non-ASCII text in unstructured headers is emitted
+    pub header_encoding: HeaderEncoding,
+    /// When true, headers and parts that have not been modified
+    /// since they were parsed, and that have no conformance issues
+    /// that would require them to be rebuilt, are emitted using
+    /// their original bytes. This avoids invalidating signatures,
+    /// such as DKIM, over content that was not changed.
+    pub preserve_unmodified: bool,
+}
+
+impl Default for SerializeOptions {
+    fn default() -> Self {
+        Self {
+            fold_width: 75,
+            header_encoding: HeaderEncoding::default(),
+            preserve_unmodified: false,
+        }
+    }
+}
+
 impl<'a> Header<'a> {
     pub fn with_name_value<N: Into<SharedString<'a>>, V: Into<SharedString<'a>>>(
         name: N,
@@ -107,14 +149,26 @@ impl<'a> Header<'a> {
     pub fn new_unstructured<N: Into<SharedString<'a>>, V: Into<SharedString<'a>>>(
         name: N,
         value: V,
+    ) -> Self {
+        Self::new_unstructured_with_options(name, value, &SerializeOptions::default())
+    }
+
+    /// Construct an unstructured header, folding and encoding
+    /// the value according to the provided options
+    pub fn new_unstructured_with_options<N: Into<SharedString<'a>>, V: Into<SharedString<'a>>>(
+        name: N,
+        value: V,
+        options: &SerializeOptions,
     ) -> Self {
         let name = name.into();
         let value = value.into();
 
-        let value = if value.chars().all(|c| c.is_ascii()) {
-            crate::textwrap::wrap(&value)
+        let value = if value.chars().all(|c| c.is_ascii())
+            || options.header_encoding == HeaderEncoding::EightBit
+        {
+            crate::textwrap::wrap_width(&value, options.fold_width)
         } else {
-            crate::rfc5322_parser::qp_encode(&value)
+            crate::rfc5322_parser::qp_encode_width(&value, options.fold_width)
         }
         .into();
 
@@ -402,6 +456,25 @@ impl<'a> Header<'a> {
     /// but may come at the cost of "losing" the non-sensical or otherwise
     /// out of spec elements in the rebuilt header
     pub fn rebuild(&self) -> Result<Self> {
+        self.rebuild_with_options(&SerializeOptions::default())
+    }
+
+    /// Re-constitute the header, as `rebuild` does, using the provided
+    /// options to fold and encode unstructured header values.
+    /// When `options.preserve_unmodified` is set, a well-formed header
+    /// is validated, but returned in its original form.
+    pub fn rebuild_with_options(&self, options: &SerializeOptions) -> Result<Self> {
+        let rebuilt = self.rebuild_impl(options)?;
+        if options.preserve_unmodified
+            && self.conformance.is_empty()
+            && (self.value.is_ascii() || options.header_encoding == HeaderEncoding::EightBit)
+        {
+            return Ok(self.clone());
+        }
+        Ok(rebuilt)
+    }
+
+    fn rebuild_impl(&self, options: &SerializeOptions) -> Result<Self> {
         let name = self.get_name();
 
         macro_rules! hdr {
@@ -422,7 +495,11 @@ impl<'a> Header<'a> {
                             "rebuilding '{name}' header: {err:#}"
                         ))
                     })?;
-                    return Ok(Self::new_unstructured($header_name, value));
+                    return Ok(Self::new_unstructured_with_options(
+                        $header_name,
+                        value,
+                        options,
+                    ));
                 }
             };
         }
@@ -457,7 +534,11 @@ impl<'a> Header<'a> {
         let value = self.as_unstructured().map_err(|err| {
             MailParsingError::HeaderParse(format!("rebuilding '{name}' header: {err:#}"))
         })?;
-        Ok(Self::new_unstructured(name.to_string(), value))
+        Ok(Self::new_unstructured_with_options(
+            name.to_string(),
+            value,
+            options,
+        ))
     }
 }
 
@@ -729,6 +810,65 @@ Some(
         }
     }
 
+    #[test]
+    fn unstructured_options() {
+        let narrow = SerializeOptions {
+            fold_width: 20,
+            ..Default::default()
+        };
+        let header = Header::new_unstructured_with_options(
+            "Subject",
+            "hello there world, how are you",
+            &narrow,
+        );
+        assert_eq!(
+            header.get_raw_value(),
+            "hello there world,\r\n\thow are you"
+        );
+
+        let header = Header::new_unstructured("Subject", "Grüße aus Köln");
+        assert_eq!(
+            header.get_raw_value(),
+            "=?UTF-8?q?Gr=C3=BC=C3=9Fe_aus_K=C3=B6ln?="
+        );
+
+        let eight_bit = SerializeOptions {
+            header_encoding: HeaderEncoding::EightBit,
+            ..Default::default()
+        };
+        let header = Header::new_unstructured_with_options("Subject", "Grüße aus Köln", &eight_bit);
+        assert_eq!(header.get_raw_value(), "Grüße aus Köln");
+        assert_eq!(header.as_unstructured().unwrap(), "Grüße aus Köln");
+    }
+
+    #[test]
+    fn rebuild_preserve_unmodified() {
+        let (header, _) = Header::parse("Subject: hello   there\r\n").unwrap();
+        assert_eq!(header.rebuild().unwrap().get_raw_value(), "hello there");
+
+        let preserve = SerializeOptions {
+            preserve_unmodified: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            header
+                .rebuild_with_options(&preserve)
+                .unwrap()
+                .to_header_string(),
+            "Subject: hello   there\r\n"
+        );
+
+        // Non-conforming headers are still rebuilt
+        let (header, _) = Header::parse("Subject : hello   there\r\n").unwrap();
+        assert_eq!(
+            header
+                .rebuild_with_options(&preserve)
+                .unwrap()
+                .to_header_string(),
+            "Subject: hello there\r\n"
+        );
+    }
+
     #[test]
     fn test_date() {
         let header = Header::with_name_value("Date", "Tue, 1 Jul 2003 10:52:37 +0200");
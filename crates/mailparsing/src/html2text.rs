@@ -0,0 +1,562 @@
+//! Renders HTML as plain text, for use as the text/plain alternative
+//! of an HTML-only message. This is not a complete HTML parser; it aims
+//! to produce readable text from the kind of markup that is typically
+//! found in email, preserving paragraphs, line breaks, lists, quotes
+//! and the targets of links.
+
+const WRAP_WIDTH: usize = 76;
+
+/// Returns a plain text rendition of html, with CRLF line endings
+pub fn html_to_text(html: &str) -> String {
+    let mut renderer = Renderer::default();
+    let mut rest = html;
+    while !rest.is_empty() {
+        match rest.find('<') {
+            Some(0) => {
+                rest = renderer.tag(rest);
+            }
+            Some(idx) => {
+                renderer.text(&rest[..idx]);
+                rest = &rest[idx..];
+            }
+            None => {
+                renderer.text(rest);
+                rest = "";
+            }
+        }
+    }
+    renderer.finish()
+}
+
+#[derive(Default)]
+struct Renderer {
+    /// The completed lines
+    out: String,
+    /// The current line, which will be wrapped when it is flushed
+    line: String,
+    pending_space: bool,
+    pre_depth: usize,
+    quote_depth: usize,
+    /// For each open list, None if it is unordered, or the number
+    /// of the most recent item if it is ordered
+    lists: Vec<Option<usize>>,
+    /// The href and accumulated text of each open link
+    links: Vec<(Option<String>, String)>,
+}
+
+impl Renderer {
+    /// Processes the tag at the start of input, returning the
+    /// remainder of the input
+    fn tag<'a>(&mut self, input: &'a str) -> &'a str {
+        if let Some(comment) = input.strip_prefix("<!--") {
+            return match comment.find("-->") {
+                Some(end) => &comment[end + 3..],
+                None => "",
+            };
+        }
+
+        let Some(end) = find_tag_end(input) else {
+            // There are no more tags
+            self.text(input);
+            return "";
+        };
+        let tag = &input[1..end];
+        let rest = &input[end + 1..];
+
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name_len = tag
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(tag.len());
+        if name_len == 0 {
+            if tag.starts_with('!') || tag.starts_with('?') {
+                // A DOCTYPE or processing instruction
+                return rest;
+            }
+            // Not a tag, just a stray "<"
+            self.text("<");
+            return &input[1..];
+        }
+
+        let name = tag[..name_len].to_ascii_lowercase();
+        if closing {
+            self.close_tag(&name);
+            return rest;
+        }
+        if matches!(name.as_str(), "script" | "style" | "head" | "title") {
+            return skip_element(rest, &name);
+        }
+        self.open_tag(&name, &tag[name_len..]);
+        rest
+    }
+
+    fn open_tag(&mut self, name: &str, attrs: &str) {
+        match name {
+            "br" => self.line_break(),
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "dl" => {
+                self.paragraph_break()
+            }
+            "pre" => {
+                self.paragraph_break();
+                self.pre_depth += 1;
+            }
+            "blockquote" => {
+                self.paragraph_break();
+                self.quote_depth += 1;
+            }
+            "ul" | "ol" => {
+                if self.lists.is_empty() {
+                    self.paragraph_break();
+                } else {
+                    self.block_break();
+                }
+                self.lists.push((name == "ol").then_some(0));
+            }
+            "li" => {
+                self.block_break();
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{n}. ")
+                    }
+                    _ => "* ".to_string(),
+                };
+                self.line.push_str(&indent);
+                self.line.push_str(&marker);
+                self.pending_space = false;
+            }
+            "hr" => {
+                self.paragraph_break();
+                self.line.push_str(&"-".repeat(20));
+                self.paragraph_break();
+            }
+            "div" | "tr" | "dt" | "dd" | "section" | "article" | "header" | "footer" | "nav"
+            | "main" | "center" | "form" | "address" | "figure" | "figcaption" => {
+                self.block_break()
+            }
+            "td" | "th" => self.space(),
+            "a" => {
+                self.links.push((attribute(attrs, "href"), String::new()));
+            }
+            "img" => {
+                if let Some(alt) = attribute(attrs, "alt") {
+                    self.push_text(&alt);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn close_tag(&mut self, name: &str) {
+        match name {
+            "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "dl" => {
+                self.paragraph_break()
+            }
+            "pre" => {
+                self.paragraph_break();
+                self.pre_depth = self.pre_depth.saturating_sub(1);
+            }
+            "blockquote" => {
+                self.paragraph_break();
+                self.quote_depth = self.quote_depth.saturating_sub(1);
+            }
+            "ul" | "ol" => {
+                self.lists.pop();
+                if self.lists.is_empty() {
+                    self.paragraph_break();
+                } else {
+                    self.block_break();
+                }
+            }
+            "li" | "div" | "tr" | "dt" | "dd" | "section" | "article" | "header" | "footer"
+            | "nav" | "main" | "center" | "form" | "address" | "figure" | "figcaption" => {
+                self.block_break()
+            }
+            "td" | "th" => self.space(),
+            "a" => {
+                if let Some((Some(href), text)) = self.links.pop() {
+                    let text = text.trim();
+                    let is_web = href.starts_with("http://") || href.starts_with("https://");
+                    if is_web && text != href {
+                        if text.is_empty() {
+                            self.push_text(&href);
+                        } else {
+                            self.push_text(&format!(" ({href})"));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        self.push_text(&decode_entities(text));
+    }
+
+    /// Appends text whose entities have already been decoded
+    fn push_text(&mut self, text: &str) {
+        for c in text.chars() {
+            // Zero width characters are often used as padding
+            // in the preview text of marketing messages
+            if matches!(
+                c,
+                '\u{200b}'..='\u{200d}' | '\u{feff}' | '\u{034f}' | '\u{ad}'
+            ) {
+                continue;
+            }
+            if let Some((_, link_text)) = self.links.last_mut() {
+                link_text.push(c);
+            }
+            if self.pre_depth > 0 {
+                match c {
+                    '\n' => self.line_break(),
+                    '\r' => {}
+                    c => self.line.push(c),
+                }
+            } else if c.is_whitespace() && c != '\u{a0}' {
+                self.space();
+            } else {
+                if self.pending_space {
+                    self.line.push(' ');
+                    self.pending_space = false;
+                }
+                self.line.push(c);
+            }
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.line.is_empty() && !self.line.ends_with(' ') {
+            self.pending_space = true;
+        }
+    }
+
+    /// Writes the current line to the output
+    fn flush(&mut self) {
+        self.pending_space = false;
+        if self.line.is_empty() {
+            return;
+        }
+        let prefix = "> ".repeat(self.quote_depth);
+        let line = std::mem::take(&mut self.line).replace('\u{a0}', " ");
+        if self.pre_depth > 0 {
+            self.out.push_str(&prefix);
+            self.out.push_str(&line);
+            self.out.push('\n');
+        } else {
+            for wrapped in wrap(&line, WRAP_WIDTH.saturating_sub(prefix.len())) {
+                self.out.push_str(&prefix);
+                self.out.push_str(wrapped.trim_end());
+                self.out.push('\n');
+            }
+        }
+    }
+
+    /// An explicit line break, which produces an empty
+    /// line if there is no text on the current line
+    fn line_break(&mut self) {
+        if self.line.is_empty() {
+            self.out.push_str(&"> ".repeat(self.quote_depth));
+            self.out.push('\n');
+        } else {
+            self.flush();
+        }
+    }
+
+    /// Ends the current line, if it has any text
+    fn block_break(&mut self) {
+        self.flush();
+    }
+
+    /// Ends the current line and ensures that a blank line follows it
+    fn paragraph_break(&mut self) {
+        self.flush();
+        if !self.out.is_empty() {
+            while !self.out.ends_with("\n\n") {
+                self.out.push('\n');
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        let text = self.out.trim();
+        if text.is_empty() {
+            return String::new();
+        }
+
+        // Collapse runs of blank lines that result from
+        // nested block elements
+        let mut result = String::with_capacity(text.len() + 2);
+        let mut blank_lines = 0;
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() {
+                blank_lines += 1;
+                if blank_lines > 1 {
+                    continue;
+                }
+            } else {
+                blank_lines = 0;
+            }
+            result.push_str(line);
+            result.push_str("\r\n");
+        }
+        result
+    }
+}
+
+/// Returns the index of the ">" that ends the tag at the start of
+/// input, ignoring any that are inside quoted attribute values
+pub(crate) fn find_tag_end(input: &str) -> Option<usize> {
+    let mut quote = None;
+    for (idx, c) in input.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(idx),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Skips the content of an element whose content is not rendered,
+/// returning the input that follows its closing tag
+fn skip_element<'a>(input: &'a str, name: &str) -> &'a str {
+    let closing = format!("</{name}");
+    let lower = input.to_ascii_lowercase();
+    match lower.find(&closing) {
+        Some(idx) => match input[idx..].find('>') {
+            Some(end) => &input[idx + end + 1..],
+            None => "",
+        },
+        None => "",
+    }
+}
+
+/// Extracts the value of the named attribute from the attributes of a tag
+fn attribute(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let attr_name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                match after.chars().next() {
+                    Some(q @ ('"' | '\'')) => {
+                        let end = after[1..].find(q).map(|i| i + 1).unwrap_or(after.len());
+                        rest = after.get(end + 1..).unwrap_or("");
+                        &after[1..end]
+                    }
+                    _ => {
+                        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                        rest = &after[end..];
+                        &after[..end]
+                    }
+                }
+            }
+            None => "",
+        };
+
+        if attr_name.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value).trim().to_string());
+        }
+    }
+}
+
+pub(crate) fn decode_entities(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(idx) = rest.find('&') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        let decoded = rest
+            .get(1..rest.len().min(12))
+            .and_then(|candidate| candidate.find(';'))
+            .and_then(|semi| Some((decode_entity(&rest[1..semi + 1])?, semi + 2)));
+        match decoded {
+            Some((c, len)) => {
+                result.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(num) = entity.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "mdash" => '—',
+        "ndash" => '–',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "bull" => '•',
+        "middot" => '·',
+        "euro" => '€',
+        "pound" => '£',
+        "yen" => '¥',
+        "cent" => '¢',
+        "zwnj" => '\u{200c}',
+        "zwj" => '\u{200d}',
+        "shy" => '\u{ad}',
+        _ => return None,
+    })
+}
+
+/// Greedily wraps text at spaces so that lines are no longer
+/// than width characters, unless they contain a longer word.
+/// Any leading indentation is applied to each of the lines.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let body = text.trim_start_matches(' ');
+    let indent = &text[..text.len() - body.len()];
+
+    let mut lines = vec![];
+    let mut line = indent.to_string();
+    let mut line_len = indent.len();
+    let mut empty = true;
+    for word in body.split(' ') {
+        let word_len = word.chars().count();
+        if !empty && line_len + 1 + word_len > width {
+            lines.push(std::mem::replace(&mut line, indent.to_string()));
+            line_len = indent.len();
+            empty = true;
+        }
+        if !empty {
+            line.push(' ');
+            line_len += 1;
+        }
+        line.push_str(word);
+        line_len += word_len;
+        empty = false;
+    }
+    if !empty {
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let html = r#"<!DOCTYPE html>
+<html>
+<head><title>Ignored</title><style>p { color: red; }</style></head>
+<body>
+<!-- a comment -->
+<h1>Hello &amp; welcome</h1>
+<p>This is   the <b>first</b>
+paragraph.<br>It has a line break.</p>
+<p>Visit <a href="https://example.com/offer">our offer</a> or
+<a href="https://example.com/">https://example.com/</a>.</p>
+<ul>
+  <li>One</li>
+  <li>Two
+    <ol><li>Nested</li></ol>
+  </li>
+</ul>
+<blockquote>Quoted text</blockquote>
+<p><img src="logo.png" alt="Logo"> &copy; 2024&nbsp;Example&#8482;</p>
+<script>alert("ignored")</script>
+</body>
+</html>"#;
+
+        k9::snapshot!(
+            html_to_text(html),
+            r#"
+Hello & welcome\r
+\r
+This is the first paragraph.\r
+It has a line break.\r
+\r
+Visit our offer (https://example.com/offer) or https://example.com/.\r
+\r
+* One\r
+* Two\r
+  1. Nested\r
+\r
+> Quoted text\r
+\r
+Logo © 2024 Example™\r
+
+"#
+        );
+    }
+
+    #[test]
+    fn wrapping() {
+        let html = format!("<p>{}</p>", "word ".repeat(30));
+        let text = html_to_text(&html);
+        for line in text.lines() {
+            assert!(line.len() <= WRAP_WIDTH, "{line:?} is too long");
+        }
+        assert_eq!(text.split_whitespace().count(), 30);
+    }
+
+    #[test]
+    fn entities_and_stray_brackets() {
+        assert_eq!(
+            decode_entities("a &lt; b &amp;&amp; c &#x41;&#66;"),
+            "a < b && c AB"
+        );
+        assert_eq!(decode_entities("AT&T &bogus; &"), "AT&T &bogus; &");
+        assert_eq!(html_to_text("1 < 2 and 3 > 2"), "1 < 2 and 3 > 2\r\n");
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            attribute(
+                r#" class=x href='https://example.com/?a=1&amp;b=2' "#,
+                "href"
+            ),
+            Some("https://example.com/?a=1&b=2".to_string())
+        );
+        assert_eq!(attribute(" alt=Logo/", "alt"), Some("Logo/".to_string()));
+        assert_eq!(attribute(" hidden alt=\"x\"", "alt"), Some("x".to_string()));
+        assert_eq!(attribute(" hidden", "alt"), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Rewrites the targets of links in HTML, such as to apply click
+//! tracking. Everything other than the rewritten `href` values is
+//! preserved exactly as it was in the input.
+use crate::html2text::{decode_entities, find_tag_end};
+
+/// Calls `rewrite` with the target of each `href` attribute of the
+/// `a` and `area` elements in html. When it returns Some, the target
+/// is replaced by the returned url.
+/// Returns the rewritten html and the number of targets that
+/// were replaced.
+pub fn rewrite_html_links(
+    html: &str,
+    mut rewrite: impl FnMut(&str) -> Option<String>,
+) -> (String, usize) {
+    let mut result = String::with_capacity(html.len());
+    let mut count = 0;
+    let mut rest = html;
+
+    while let Some(idx) = rest.find('<') {
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let Some(end) = find_tag_end(rest) else {
+            break;
+        };
+        let tag = &rest[1..end];
+        let name_len = tag
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(tag.len());
+        let name = &tag[..name_len];
+
+        if name.eq_ignore_ascii_case("a") || name.eq_ignore_ascii_case("area") {
+            result.push('<');
+            result.push_str(name);
+            result.push_str(&rewrite_href(&tag[name_len..], &mut rewrite, &mut count));
+            result.push('>');
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let mut next = end + 1;
+        if name.eq_ignore_ascii_case("script") || name.eq_ignore_ascii_case("style") {
+            // The content of these elements is not markup
+            let closing = format!("</{}", name.to_ascii_lowercase());
+            next = rest[next..]
+                .to_ascii_lowercase()
+                .find(&closing)
+                .map(|idx| next + idx)
+                .unwrap_or(rest.len());
+        }
+        result.push_str(&rest[..next]);
+        rest = &rest[next..];
+    }
+    result.push_str(rest);
+
+    (result, count)
+}
+
+/// Rewrites the value of the href attribute, if any, in the
+/// attributes portion of a tag
+fn rewrite_href(
+    attrs: &str,
+    rewrite: &mut impl FnMut(&str) -> Option<String>,
+    count: &mut usize,
+) -> String {
+    let mut result = String::with_capacity(attrs.len());
+    // The start of the input that has not yet been copied to result
+    let mut copied = 0;
+    let mut idx = 0;
+
+    let skip_space = |idx: usize| {
+        let rest = &attrs[idx..];
+        idx + rest.len() - rest.trim_start().len()
+    };
+
+    loop {
+        let rest = &attrs[idx..];
+        idx += rest.len()
+            - rest
+                .trim_start_matches(|c: char| c.is_whitespace() || c == '/')
+                .len();
+        if idx >= attrs.len() {
+            break;
+        }
+
+        let name_end = attrs[idx..]
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .map(|i| idx + i)
+            .unwrap_or(attrs.len());
+        if name_end == idx {
+            // A stray "="; skip over it
+            idx += 1;
+            continue;
+        }
+        let name = &attrs[idx..name_end];
+
+        let equals = skip_space(name_end);
+        if !attrs[equals..].starts_with('=') {
+            // An attribute without a value
+            idx = name_end;
+            continue;
+        }
+
+        let value_start = skip_space(equals + 1);
+        let (value_start, value_end, quoted, next) = match attrs[value_start..].chars().next() {
+            Some(q @ ('"' | '\'')) => {
+                let start = value_start + 1;
+                let end = attrs[start..]
+                    .find(q)
+                    .map(|i| start + i)
+                    .unwrap_or(attrs.len());
+                (start, end, true, (end + 1).min(attrs.len()))
+            }
+            _ => {
+                let end = attrs[value_start..]
+                    .find(char::is_whitespace)
+                    .map(|i| value_start + i)
+                    .unwrap_or(attrs.len());
+                (value_start, end, false, end)
+            }
+        };
+
+        if name.eq_ignore_ascii_case("href") {
+            let url = decode_entities(&attrs[value_start..value_end]);
+            if let Some(new_url) = rewrite(url.trim()) {
+                *count += 1;
+                result.push_str(&attrs[copied..value_start]);
+                if !quoted {
+                    result.push('"');
+                }
+                result.push_str(&encode_attribute(&new_url));
+                if !quoted {
+                    result.push('"');
+                }
+                copied = value_end;
+            }
+        }
+
+        idx = next;
+    }
+
+    result.push_str(&attrs[copied..]);
+    result
+}
+
+/// Escapes a value so that it can be placed in a quoted attribute
+fn encode_attribute(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(url: &str) -> Option<String> {
+        if url.starts_with("http") {
+            Some(format!(
+                "https://track.example.com/c?u={}",
+                url.replace('&', "%26")
+            ))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn rewrite() {
+        let html = concat!(
+            "<html><body>\n",
+            "<!-- <a href=\"http://comment.example.com\"> -->\n",
+            "<p>Visit <A class=\"link\" HREF=\"http://example.com/?a=1&amp;b=2\">us</A>,\n",
+            "<a href='https://example.com/it'>this</a>,\n",
+            "<a href=http://example.com/bare title=bare>bare</a>\n",
+            "or <a name=\"anchor\">an anchor</a> or <a href=\"mailto:someone@example.com\">mail</a>\n",
+            "<map><area shape=rect href=\"https://example.com/area\"/></map>\n",
+            "<script>var s = '<a href=\"http://script.example.com\">';</script>\n",
+            "</body></html>\n",
+        );
+
+        let (rewritten, count) = rewrite_html_links(html, track);
+        assert_eq!(count, 4);
+        assert_eq!(
+            rewritten,
+            concat!(
+                "<html><body>\n",
+                "<!-- <a href=\"http://comment.example.com\"> -->\n",
+                "<p>Visit <A class=\"link\" HREF=\"https://track.example.com/c?u=http://example.com/?a=1%26b=2\">us</A>,\n",
+                "<a href='https://track.example.com/c?u=https://example.com/it'>this</a>,\n",
+                "<a href=\"https://track.example.com/c?u=http://example.com/bare\" title=bare>bare</a>\n",
+                "or <a name=\"anchor\">an anchor</a> or <a href=\"mailto:someone@example.com\">mail</a>\n",
+                "<map><area shape=rect href=\"https://track.example.com/c?u=https://example.com/area\"/></map>\n",
+                "<script>var s = '<a href=\"http://script.example.com\">';</script>\n",
+                "</body></html>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let (rewritten, count) = rewrite_html_links("<a href=\"http://a\">a</a>", |_| {
+            Some("http://b/?x=1&y=\"2\"".to_string())
+        });
+        assert_eq!(count, 1);
+        assert_eq!(
+            rewritten,
+            "<a href=\"http://b/?x=1&amp;y=&quot;2&quot;\">a</a>"
+        );
+    }
+}
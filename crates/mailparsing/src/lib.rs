@@ -1,23 +1,37 @@
+mod attachment_policy;
 mod builder;
+mod calendar;
+mod charset_detect;
 mod conformance;
 mod error;
 mod header;
 mod headermap;
+mod html2text;
+mod html_links;
 mod mimepart;
 mod nom_utils;
 mod normalize;
+mod received;
 mod rfc5322_parser;
+mod streaming;
 mod strings;
 mod textwrap;
 
 pub use error::MailParsingError;
 pub type Result<T> = std::result::Result<T, MailParsingError>;
 
+pub use attachment_policy::*;
 pub use builder::*;
+pub use calendar::CalendarInfo;
+pub use charset_detect::{detect_charset, CharsetPolicy};
 pub use conformance::*;
-pub use header::{Header, HeaderParseResult, MessageConformance};
+pub use header::{Header, HeaderEncoding, HeaderParseResult, MessageConformance, SerializeOptions};
 pub use headermap::*;
+pub use html2text::html_to_text;
+pub use html_links::rewrite_html_links;
 pub use mimepart::*;
 pub use normalize::*;
+pub use received::*;
 pub use rfc5322_parser::*;
+pub use streaming::*;
 pub use strings::SharedString;
@@ -1,4 +1,5 @@
-use crate::header::{HeaderParseResult, MessageConformance};
+use crate::charset_detect::{detect, CharsetPolicy};
+use crate::header::{HeaderParseResult, MessageConformance, SerializeOptions};
 use crate::headermap::HeaderMap;
 use crate::strings::IntoSharedString;
 use crate::{
@@ -9,7 +10,7 @@ use std::str::FromStr;
 
 /// Define our own because data_encoding::BASE64_MIME, despite its name,
 /// is not RFC2045 compliant, and will not ignore spaces
-const BASE64_RFC2045: data_encoding::Encoding = data_encoding_macro::new_encoding! {
+pub(crate) const BASE64_RFC2045: data_encoding::Encoding = data_encoding_macro::new_encoding! {
     symbols: "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/",
     padding: '=',
     ignore: " \r\n\t",
@@ -32,11 +33,17 @@ pub struct MimePart<'a> {
     intro: SharedString<'a>,
     /// For multipart, the content the follows the last boundary
     outro: SharedString<'a>,
+    /// true when bytes holds the complete, unmodified serialization
+    /// of this part, as it was parsed
+    pristine: bool,
 }
 
 struct Rfc2045Info {
     encoding: ContentTransferEncoding,
     charset: Charset,
+    /// The charset label from the Content-Type header,
+    /// if it was present and supported
+    declared_charset: Option<String>,
     content_type: Option<MimeParameters>,
     is_text: bool,
     is_multipart: bool,
@@ -45,6 +52,10 @@ struct Rfc2045Info {
 
 impl Rfc2045Info {
     fn new(headers: &HeaderMap) -> Result<Self> {
+        Self::new_with_charset_policy(headers, CharsetPolicy::Declared)
+    }
+
+    fn new_with_charset_policy(headers: &HeaderMap, policy: CharsetPolicy) -> Result<Self> {
         let content_transfer_encoding = headers.content_transfer_encoding()?;
 
         let encoding = match &content_transfer_encoding {
@@ -58,10 +69,22 @@ impl Rfc2045Info {
         } else {
             None
         };
-        let charset = charset.unwrap_or_else(|| "us-ascii".to_string());
-
-        let charset = Charset::for_label_no_replacement(charset.as_bytes())
-            .ok_or_else(|| MailParsingError::BodyParse(format!("unsupported charset {charset}")))?;
+        let (charset, declared_charset) = match charset
+            .map(|label| (Charset::for_label_no_replacement(label.as_bytes()), label))
+        {
+            Some((Some(charset), label)) => (charset, Some(label)),
+            Some((None, label)) if policy == CharsetPolicy::Declared => {
+                return Err(MailParsingError::BodyParse(format!(
+                    "unsupported charset {label}"
+                )));
+            }
+            // An unsupported charset is treated as missing,
+            // and will be detected when decoding the body
+            Some((None, _)) | None => (
+                Charset::for_label_no_replacement(b"us-ascii").expect("us-ascii to be supported"),
+                None,
+            ),
+        };
 
         let (is_text, is_multipart) = if let Some(ct) = &content_type {
             (ct.is_text(), ct.is_multipart())
@@ -88,12 +111,45 @@ impl Rfc2045Info {
         Ok(Self {
             encoding,
             charset,
+            declared_charset,
             content_type,
             is_text,
             is_multipart,
             attachment_options,
         })
     }
+
+    /// Determine the charset to use to decode bytes according to policy
+    fn resolve_charset(&self, bytes: &[u8], policy: CharsetPolicy) -> Charset {
+        match policy {
+            CharsetPolicy::Declared => self.charset,
+            CharsetPolicy::DetectIfInvalid => {
+                let valid = match &self.declared_charset {
+                    // us-ascii is decoded as windows-1252, so we need
+                    // to check for 8-bit content ourselves
+                    Some(label)
+                        if label.eq_ignore_ascii_case("us-ascii")
+                            || label.eq_ignore_ascii_case("ascii") =>
+                    {
+                        bytes.is_ascii()
+                    }
+                    Some(_) => {
+                        let (_decoded, malformed) = self.charset.decode_without_bom_handling(bytes);
+                        !malformed
+                    }
+                    None => bytes.is_ascii(),
+                };
+                if valid {
+                    self.charset
+                } else {
+                    detect(bytes, None)
+                }
+            }
+            CharsetPolicy::Detect => {
+                detect(bytes, self.declared_charset.as_ref().map(|_| self.charset))
+            }
+        }
+    }
 }
 
 impl<'a> MimePart<'a> {
@@ -172,6 +228,7 @@ impl<'a> MimePart<'a> {
             parts: vec![],
             intro: SharedString::Borrowed(""),
             outro: SharedString::Borrowed(""),
+            pristine: true,
         };
 
         part.recursive_parse()?;
@@ -180,7 +237,10 @@ impl<'a> MimePart<'a> {
     }
 
     fn recursive_parse(&mut self) -> Result<()> {
-        let info = Rfc2045Info::new(&self.headers)?;
+        // Only the structure is needed here, so don't fail on an
+        // unsupported charset
+        let info =
+            Rfc2045Info::new_with_charset_policy(&self.headers, CharsetPolicy::DetectIfInvalid)?;
         if let Some((boundary, true)) = info
             .content_type
             .as_ref()
@@ -256,6 +316,7 @@ impl<'a> MimePart<'a> {
 
     /// Obtain a mutable reference to the child parts
     pub fn child_parts_mut(&mut self) -> &mut Vec<Self> {
+        self.pristine = false;
         &mut self.parts
     }
 
@@ -266,6 +327,7 @@ impl<'a> MimePart<'a> {
 
     /// Obtain a mutable reference to the headers
     pub fn headers_mut<'b>(&'b mut self) -> &'b mut HeaderMap<'a> {
+        self.pristine = false;
         &mut self.headers
     }
 
@@ -277,7 +339,16 @@ impl<'a> MimePart<'a> {
 
     /// Decode transfer decoding and return the body
     pub fn body(&self) -> Result<DecodedBody> {
-        let info = Rfc2045Info::new(&self.headers)?;
+        self.body_with_charset_policy(CharsetPolicy::Declared)
+    }
+
+    /// Decode transfer decoding and return the body, using the
+    /// provided policy to determine the charset of textual content.
+    /// Charset detection applies to quoted-printable and base64
+    /// encoded content; unencoded 8-bit content is decoded as UTF-8
+    /// when the message is parsed.
+    pub fn body_with_charset_policy(&self, policy: CharsetPolicy) -> Result<DecodedBody> {
+        let info = Rfc2045Info::new_with_charset_policy(&self.headers, policy)?;
 
         let bytes = match info.encoding {
             ContentTransferEncoding::Base64 => {
@@ -313,7 +384,13 @@ impl<'a> MimePart<'a> {
             }
         };
 
-        let (decoded, _malformed) = info.charset.decode_without_bom_handling(&bytes);
+        let charset = if info.is_text {
+            info.resolve_charset(&bytes, policy)
+        } else {
+            info.charset
+        };
+
+        let (decoded, _malformed) = charset.decode_without_bom_handling(&bytes);
 
         if info.is_text {
             Ok(DecodedBody::Text(decoded.to_string().into()))
@@ -322,6 +399,30 @@ impl<'a> MimePart<'a> {
         }
     }
 
+    /// Decode the transfer encoding and return the body as bytes,
+    /// without applying any charset conversion
+    pub(crate) fn transfer_decoded_body(&self) -> Result<Vec<u8>> {
+        let encoding = match self.headers.content_transfer_encoding()? {
+            Some(cte) => ContentTransferEncoding::from_str(&cte.value)?,
+            None => ContentTransferEncoding::SevenBit,
+        };
+        let data = self.raw_body();
+        match encoding {
+            ContentTransferEncoding::Base64 => BASE64_RFC2045
+                .decode(data.as_bytes())
+                .map_err(|err| MailParsingError::BodyParse(format!("base64 decode: {err:#}"))),
+            ContentTransferEncoding::QuotedPrintable => {
+                quoted_printable::decode(data.as_bytes(), quoted_printable::ParseMode::Robust)
+                    .map_err(|err| {
+                        MailParsingError::BodyParse(format!("quoted printable decode: {err:#}"))
+                    })
+            }
+            ContentTransferEncoding::SevenBit
+            | ContentTransferEncoding::EightBit
+            | ContentTransferEncoding::Binary => Ok(data.as_bytes().to_vec()),
+        }
+    }
+
     /// Re-constitute the message.
     /// Each element will be parsed out, and the parsed form used
     /// to build a new message.
@@ -329,11 +430,30 @@ impl<'a> MimePart<'a> {
     /// but may come at the cost of "losing" the non-sensical or otherwise
     /// out of spec elements in the rebuilt message
     pub fn rebuild(&self) -> Result<Self> {
+        self.rebuild_with_options(&SerializeOptions::default())
+    }
+
+    /// Re-constitute the message, as `rebuild` does, using the provided
+    /// options to fold and encode the rebuilt headers.
+    /// When `options.preserve_unmodified` is set, unmodified parts
+    /// and headers that have no conformance issues are retained in
+    /// their original form, and only the problematic elements are
+    /// rebuilt.
+    pub fn rebuild_with_options(&self, options: &SerializeOptions) -> Result<Self> {
+        // Missing headers are not fixed by rebuilding
+        let needs_rebuild = self.conformance
+            - (MessageConformance::MISSING_DATE_HEADER
+                | MessageConformance::MISSING_MESSAGE_ID_HEADER
+                | MessageConformance::MISSING_MIME_VERSION);
+        if options.preserve_unmodified && self.pristine && needs_rebuild.is_empty() {
+            return Ok(self.clone());
+        }
+
         let info = Rfc2045Info::new(&self.headers)?;
 
         let mut children = vec![];
         for part in &self.parts {
-            children.push(part.rebuild()?);
+            children.push(part.rebuild_with_options(options)?);
         }
 
         let mut rebuilt = if children.is_empty() {
@@ -377,7 +497,7 @@ impl<'a> MimePart<'a> {
                 continue;
             }
 
-            if let Ok(hdr) = hdr.rebuild() {
+            if let Ok(hdr) = hdr.rebuild_with_options(options) {
                 rebuilt.headers_mut().push(hdr);
             }
         }
@@ -387,6 +507,25 @@ impl<'a> MimePart<'a> {
 
     /// Write the message content to the provided output stream
     pub fn write_message<W: std::io::Write>(&self, out: &mut W) -> Result<()> {
+        self.write_message_with_options(out, &SerializeOptions::default())
+    }
+
+    /// Write the message content to the provided output stream.
+    /// When `options.preserve_unmodified` is set, parts that have not
+    /// been modified since they were parsed are written using their
+    /// original bytes, rather than being re-serialized from their
+    /// parsed form.
+    pub fn write_message_with_options<W: std::io::Write>(
+        &self,
+        out: &mut W,
+        options: &SerializeOptions,
+    ) -> Result<()> {
+        if options.preserve_unmodified && self.pristine {
+            return out
+                .write_all(self.bytes.as_bytes())
+                .map_err(|_| MailParsingError::WriteMessageIOError);
+        }
+
         let line_ending = if self
             .conformance
             .contains(MessageConformance::NON_CANONICAL_LINE_ENDINGS)
@@ -407,7 +546,12 @@ impl<'a> MimePart<'a> {
             out.write_all(self.raw_body().as_bytes())
                 .map_err(|_| MailParsingError::WriteMessageIOError)?;
         } else {
-            let info = Rfc2045Info::new(&self.headers)?;
+            // Only the structure is needed here, so don't fail on an
+            // unsupported charset
+            let info = Rfc2045Info::new_with_charset_policy(
+                &self.headers,
+                CharsetPolicy::DetectIfInvalid,
+            )?;
             let ct = info.content_type.ok_or_else(|| {
                 MailParsingError::WriteMessageWtf(
                     "expected to have Content-Type when there are child parts",
@@ -421,7 +565,7 @@ impl<'a> MimePart<'a> {
             for p in &self.parts {
                 write!(out, "--{boundary}{line_ending}")
                     .map_err(|_| MailParsingError::WriteMessageIOError)?;
-                p.write_message(out)?;
+                p.write_message_with_options(out, options)?;
             }
             write!(out, "--{boundary}--{line_ending}")
                 .map_err(|_| MailParsingError::WriteMessageIOError)?;
@@ -434,13 +578,20 @@ impl<'a> MimePart<'a> {
     /// Convenience method wrapping write_message that returns
     /// the formatted message as a standalone string
     pub fn to_message_string(&self) -> String {
+        self.to_message_string_with_options(&SerializeOptions::default())
+    }
+
+    /// Convenience method wrapping write_message_with_options that
+    /// returns the formatted message as a standalone string
+    pub fn to_message_string_with_options(&self, options: &SerializeOptions) -> String {
         let mut out = vec![];
-        self.write_message(&mut out).unwrap();
+        self.write_message_with_options(&mut out, options).unwrap();
         String::from_utf8_lossy(&out).to_string()
     }
 
     pub fn replace_text_body(&mut self, content_type: &str, content: &str) {
         let mut new_part = Self::new_text(content_type, content);
+        self.pristine = false;
         self.bytes = new_part.bytes;
         self.body_offset = new_part.body_offset;
         self.body_len = new_part.body_len;
@@ -453,6 +604,82 @@ impl<'a> MimePart<'a> {
         self.headers.append(&mut new_part.headers.headers);
     }
 
+    /// If the message has a text/html part but no text/plain part,
+    /// renders the html as text and replaces the html part with a
+    /// multipart/alternative that holds both the text and the
+    /// original html part.
+    /// Returns true if the message was changed.
+    pub fn add_text_plain_alternative(&mut self) -> Result<bool> {
+        let parts = self.simplified_structure_pointers()?;
+        if parts.text_part.is_some() {
+            return Ok(false);
+        }
+        let Some(html_part) = parts.html_part.and_then(|p| self.resolve_ptr_mut(p)) else {
+            return Ok(false);
+        };
+
+        let text = match html_part.body()? {
+            DecodedBody::Text(html) => Self::new_text_plain(&crate::html_to_text(html.as_str())),
+            DecodedBody::Binary(_) => {
+                return Err(MailParsingError::BodyParse(
+                    "expected text/html part to be text, but it is binary".to_string(),
+                ))
+            }
+        };
+
+        // When the html is the top level of the message, the non-content
+        // headers, such as Subject, need to remain at the top level
+        let mut html = html_part.clone();
+        let mut outer_headers = vec![];
+        html.headers.headers.retain(|hdr| {
+            let name = hdr.get_name();
+            let is_content = name
+                .get(..8)
+                .map(|prefix| prefix.eq_ignore_ascii_case("Content-"))
+                .unwrap_or(false);
+            if !is_content {
+                outer_headers.push(hdr.clone());
+            }
+            is_content
+        });
+
+        let mut alternative = Self::new_multipart("multipart/alternative", vec![text, html], None);
+        outer_headers.append(&mut alternative.headers.headers);
+        alternative.headers.headers = outer_headers;
+
+        *html_part = alternative;
+        Ok(true)
+    }
+
+    /// Rewrites the targets of the links in the primary text/html
+    /// part of the message using `rewrite`; see `rewrite_html_links`.
+    /// The part is re-encoded if any links were changed.
+    /// Returns the number of links that were changed.
+    pub fn rewrite_html_links(
+        &mut self,
+        rewrite: impl FnMut(&str) -> Option<String>,
+    ) -> Result<usize> {
+        let parts = self.simplified_structure_pointers()?;
+        let Some(part) = parts.html_part.and_then(|p| self.resolve_ptr_mut(p)) else {
+            return Ok(0);
+        };
+
+        let html = match part.body()? {
+            DecodedBody::Text(html) => html,
+            DecodedBody::Binary(_) => {
+                return Err(MailParsingError::BodyParse(
+                    "expected text/html part to be text, but it is binary".to_string(),
+                ))
+            }
+        };
+
+        let (html, count) = crate::rewrite_html_links(html.as_str(), rewrite);
+        if count > 0 {
+            part.replace_text_body("text/html", &html);
+        }
+        Ok(count)
+    }
+
     /// Constructs a new part with textual utf8 content.
     /// quoted-printable transfer encoding will be applied,
     /// unless it is smaller to represent the text in base64
@@ -506,6 +733,7 @@ impl<'a> MimePart<'a> {
             parts: vec![],
             intro: "".into(),
             outro: "".into(),
+            pristine: false,
         }
     }
 
@@ -543,6 +771,7 @@ impl<'a> MimePart<'a> {
             parts,
             intro: "".into(),
             outro: "".into(),
+            pristine: false,
         }
     }
 
@@ -583,6 +812,7 @@ impl<'a> MimePart<'a> {
             parts: vec![],
             intro: "".into(),
             outro: "".into(),
+            pristine: false,
         }
     }
 
@@ -655,11 +885,13 @@ impl<'a> MimePart<'a> {
     }
 
     /// Resolve a PartPointer to the corresponding MimePart, for mutable access
+    /// Each part along the path is considered to be modified.
     pub fn resolve_ptr_mut(&mut self, ptr: PartPointer) -> Option<&mut Self> {
         let mut current = self;
         let mut cursor = ptr.0.as_slice();
 
         loop {
+            current.pristine = false;
             match cursor.get(0) {
                 Some(&idx) => {
                     current = current.parts.get_mut(idx as usize)?;
@@ -1092,6 +1324,150 @@ After the final boundary stuff gets ignored.\r
         );
     }
 
+    #[test]
+    fn add_text_plain_alternative() {
+        let message = concat!(
+            "Subject: html only\r\n",
+            "Mime-Version: 1.0\r\n",
+            "Content-Type: text/html; charset=us-ascii\r\n",
+            "\r\n",
+            "<p>Hello <b>there</b></p>\r\n",
+        );
+        let mut part = MimePart::parse(message).unwrap();
+        assert!(part.add_text_plain_alternative().unwrap());
+
+        // Re-parse the result to verify its structure
+        let rebuilt = part.to_message_string();
+        let part = MimePart::parse(rebuilt.as_str()).unwrap();
+        assert_eq!(
+            part.headers().subject().unwrap().as_deref(),
+            Some("html only")
+        );
+        assert_eq!(
+            part.headers().content_type().unwrap().unwrap().value,
+            "multipart/alternative"
+        );
+        let structure = part.simplified_structure().unwrap();
+        assert_eq!(structure.text.unwrap().as_str(), "Hello there\r\n");
+        assert_eq!(
+            structure.html.unwrap().as_str(),
+            "<p>Hello <b>there</b></p>\r\n"
+        );
+
+        // There is now a text part, so nothing changes
+        let mut part = part;
+        assert!(!part.add_text_plain_alternative().unwrap());
+
+        // A message without html is left alone
+        let mut part = MimePart::parse("Subject: text\r\n\r\nJust text\r\n").unwrap();
+        assert!(!part.add_text_plain_alternative().unwrap());
+    }
+
+    #[test]
+    fn charset_detection() {
+        // Shift_JIS without a declared charset
+        let message = concat!(
+            "Content-Type: text/plain\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "grGC8YLJgr+CzQ==\r\n",
+        );
+        let part = MimePart::parse(message).unwrap();
+        assert_ne!(part.body().unwrap(), DecodedBody::Text("こんにちは".into()));
+        for policy in [CharsetPolicy::DetectIfInvalid, CharsetPolicy::Detect] {
+            assert_eq!(
+                part.body_with_charset_policy(policy).unwrap(),
+                DecodedBody::Text("こんにちは".into())
+            );
+        }
+
+        // KOI8-R that is mislabeled as UTF-8
+        let message = concat!(
+            "Content-Type: text/plain; charset=utf-8\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "0NLJ18XUIM3J0g==\r\n",
+        );
+        let part = MimePart::parse(message).unwrap();
+        assert_eq!(
+            part.body_with_charset_policy(CharsetPolicy::DetectIfInvalid)
+                .unwrap(),
+            DecodedBody::Text("привет мир".into())
+        );
+
+        // An unsupported charset is only an error for the Declared policy
+        let message = concat!(
+            "Content-Type: text/plain; charset=x-bogus\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "7/Do4uXyIOzo8A==\r\n",
+        );
+        let part = MimePart::parse(message).unwrap();
+        assert!(part.body().is_err());
+        assert_eq!(
+            part.body_with_charset_policy(CharsetPolicy::DetectIfInvalid)
+                .unwrap(),
+            DecodedBody::Text("привет мир".into())
+        );
+    }
+
+    #[test]
+    fn preserve_unmodified() {
+        let message = concat!(
+            "Subject: hello\r\n",
+            "Content-Type: multipart/mixed; boundary=\"xxx\"\r\n",
+            "\r\n",
+            "--xxx  \r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "first\r\n",
+            "--xxx\t\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "second\r\n",
+            "--xxx--\r\n",
+            "outro\r\n",
+        );
+        let preserve = SerializeOptions {
+            preserve_unmodified: true,
+            ..Default::default()
+        };
+
+        let mut part = MimePart::parse(message).unwrap();
+        // The default serialization normalizes the boundary lines
+        assert_ne!(part.to_message_string(), message);
+        assert_eq!(part.to_message_string_with_options(&preserve), message);
+        assert_eq!(
+            part.rebuild_with_options(&preserve)
+                .unwrap()
+                .to_message_string_with_options(&preserve),
+            message
+        );
+
+        part.child_parts_mut()[1]
+            .headers_mut()
+            .push(Header::with_name_value("X-Test", "1"));
+        assert_eq!(
+            part.to_message_string_with_options(&preserve),
+            concat!(
+                "Subject: hello\r\n",
+                "Content-Type: multipart/mixed; boundary=\"xxx\"\r\n",
+                "\r\n",
+                "--xxx\r\n",
+                "Content-Type: text/plain\r\n",
+                "\r\n",
+                "first\r\n",
+                "--xxx\r\n",
+                "Content-Type: text/plain\r\n",
+                "X-Test: 1\r\n",
+                "\r\n",
+                "second\r\n",
+                "--xxx--\r\n",
+                "outro\r\n",
+            )
+        );
+    }
+
     #[test]
     fn replace_text_body() {
         let mut part = MimePart::new_text_plain("Hello 👻\r\n");
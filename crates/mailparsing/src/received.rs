@@ -0,0 +1,310 @@
+//! Parses the chain of Received headers (RFC 5321 section 4.4)
+//! into structured hops, so that the path taken by a message
+//! can be examined without resorting to ad-hoc regex parsing
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceivedChainParams {
+    /// A hop whose timestamp is earlier than that of the preceding
+    /// hop by more than this number of seconds is considered to
+    /// have a skewed clock
+    #[serde(default = "ReceivedChainParams::default_max_clock_skew")]
+    pub max_clock_skew: i64,
+}
+
+impl Default for ReceivedChainParams {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: Self::default_max_clock_skew(),
+        }
+    }
+}
+
+impl ReceivedChainParams {
+    fn default_max_clock_skew() -> i64 {
+        300
+    }
+}
+
+/// A single Received header
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct ReceivedHop {
+    /// The name that the sending host gave in its HELO or EHLO
+    pub from_helo: Option<String>,
+    /// The name of the sending host, as resolved by the receiving host
+    pub from_host: Option<String>,
+    /// The IP address of the sending host
+    pub from_ip: Option<IpAddr>,
+    /// The name of the receiving host
+    pub by_host: Option<String>,
+    /// The protocol used to transfer the message, such as `ESMTPS`
+    pub protocol: Option<String>,
+    /// The identifier assigned to the message by the receiving host
+    pub id: Option<String>,
+    /// The recipient address that the message was received for
+    pub for_address: Option<String>,
+    /// The time at which the message was received
+    pub timestamp: Option<DateTime<FixedOffset>>,
+    /// The number of seconds between the preceding hop
+    /// and this one, when both have a timestamp
+    pub delay: Option<i64>,
+    /// true if the timestamp is earlier than that of the
+    /// preceding hop by more than the permitted clock skew
+    pub clock_skew: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct ReceivedChain {
+    /// The hops, in the order in which the headers appear in
+    /// the message; the most recent hop is first
+    pub hops: Vec<ReceivedHop>,
+    /// true if any hop has clock_skew set
+    pub clock_skew_detected: bool,
+}
+
+impl ReceivedChain {
+    /// Parses the values of the Received headers of a message,
+    /// which must be provided in the order that they appear
+    /// in the message
+    pub fn parse<'a>(
+        values: impl IntoIterator<Item = &'a str>,
+        params: &ReceivedChainParams,
+    ) -> Self {
+        let mut hops: Vec<ReceivedHop> = values.into_iter().map(ReceivedHop::parse).collect();
+
+        // The preceding hop is the one below this one in the message
+        for idx in 0..hops.len().saturating_sub(1) {
+            let (Some(this), Some(prior)) = (hops[idx].timestamp, hops[idx + 1].timestamp) else {
+                continue;
+            };
+            let delay = (this - prior).num_seconds();
+            hops[idx].delay.replace(delay);
+            hops[idx].clock_skew = delay < -params.max_clock_skew;
+        }
+
+        let clock_skew_detected = hops.iter().any(|hop| hop.clock_skew);
+        Self {
+            hops,
+            clock_skew_detected,
+        }
+    }
+}
+
+enum Token<'a> {
+    Word(&'a str),
+    Comment(&'a str),
+}
+
+/// Splits the clauses of a Received header into words and
+/// parenthesized comments, which may be nested
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == '(' {
+            let mut depth = 1;
+            let mut end = text.len();
+            for (idx, c) in chars.by_ref() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            end = idx;
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            tokens.push(Token::Comment(&text[start + 1..end]));
+            continue;
+        }
+        let mut end = text.len();
+        while let Some(&(idx, c)) = chars.peek() {
+            if c.is_whitespace() || c == '(' {
+                end = idx;
+                break;
+            }
+            chars.next();
+        }
+        tokens.push(Token::Word(&text[start..end]));
+    }
+    tokens
+}
+
+/// Extracts the address from an address literal such as `[192.0.2.1]`
+/// or `[IPv6:2001:db8::1]`
+fn parse_address_literal(text: &str) -> Option<IpAddr> {
+    let literal = text.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let literal = literal
+        .get(..5)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("IPv6:"))
+        .map(|_| &literal[5..])
+        .unwrap_or(literal);
+    literal.parse().ok()
+}
+
+/// Removes comments, such as `(PDT)`, from a date-time
+fn strip_comments(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => result.push(c),
+            _ => {}
+        }
+    }
+    result.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl ReceivedHop {
+    /// Parses the value of a Received header.  Fields that are not
+    /// present, or cannot be parsed, are left as None.
+    pub fn parse(value: &str) -> Self {
+        let mut hop = Self::default();
+        let (clauses, date) = match value.rsplit_once(';') {
+            Some((clauses, date)) => (clauses, Some(date)),
+            None => (value, None),
+        };
+
+        if let Some(date) = date {
+            hop.timestamp = DateTime::parse_from_rfc2822(&strip_comments(date)).ok();
+        }
+
+        let mut keyword = String::new();
+        let mut have_value = false;
+        for token in tokenize(clauses) {
+            match token {
+                Token::Word(word)
+                    if ["from", "by", "via", "with", "id", "for"]
+                        .iter()
+                        .any(|k| k.eq_ignore_ascii_case(word)) =>
+                {
+                    keyword = word.to_ascii_lowercase();
+                    have_value = false;
+                }
+                Token::Word(word) if !have_value => {
+                    have_value = true;
+                    let word = word.to_string();
+                    match keyword.as_str() {
+                        "from" => {
+                            if let Some(ip) = parse_address_literal(&word) {
+                                hop.from_ip.replace(ip);
+                            } else {
+                                hop.from_helo.replace(word);
+                            }
+                        }
+                        "by" => {
+                            hop.by_host.replace(word);
+                        }
+                        "with" => {
+                            hop.protocol.replace(word);
+                        }
+                        "id" => {
+                            hop.id.replace(word);
+                        }
+                        "for" => {
+                            hop.for_address.replace(
+                                word.trim_start_matches('<')
+                                    .trim_end_matches('>')
+                                    .to_string(),
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+                Token::Comment(comment) if keyword == "from" => {
+                    // Typically `(host.example.com [192.0.2.1])`,
+                    // `(unknown [192.0.2.1])` or `([192.0.2.1])`,
+                    // but some hosts add extra detail such as `helo=`
+                    for word in comment.split_whitespace() {
+                        if let Some(ip) = parse_address_literal(word) {
+                            if hop.from_ip.is_none() {
+                                hop.from_ip.replace(ip);
+                            }
+                        } else if hop.from_host.is_none()
+                            && word.contains('.')
+                            && !word.contains('=')
+                            && !word.starts_with('[')
+                        {
+                            hop.from_host
+                                .replace(word.trim_end_matches('.').to_string());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        hop
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_hop() {
+        let hop = ReceivedHop::parse(
+            "from mail.example.com (mail.example.com. [192.0.2.1])\r\n\
+             \tby mx.example.net (Postfix) with ESMTPS id 4ABC123\r\n\
+             \tfor <user@example.net>; Tue, 5 Mar 2024 10:15:30 -0800 (PST)",
+        );
+        assert_eq!(
+            hop,
+            ReceivedHop {
+                from_helo: Some("mail.example.com".to_string()),
+                from_host: Some("mail.example.com".to_string()),
+                from_ip: Some("192.0.2.1".parse().unwrap()),
+                by_host: Some("mx.example.net".to_string()),
+                protocol: Some("ESMTPS".to_string()),
+                id: Some("4ABC123".to_string()),
+                for_address: Some("user@example.net".to_string()),
+                timestamp: Some(DateTime::parse_from_rfc3339("2024-03-05T10:15:30-08:00").unwrap()),
+                delay: None,
+                clock_skew: false,
+            }
+        );
+
+        let hop = ReceivedHop::parse(
+            "from [IPv6:2001:db8::1] (unknown [IPv6:2001:db8::1]) by relay; garbage",
+        );
+        assert_eq!(hop.from_helo, None);
+        assert_eq!(hop.from_host, None);
+        assert_eq!(hop.from_ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(hop.by_host.as_deref(), Some("relay"));
+        assert_eq!(hop.timestamp, None);
+    }
+
+    #[test]
+    fn chain() {
+        let chain = ReceivedChain::parse(
+            [
+                "by c.example.com; Tue, 5 Mar 2024 18:30:00 +0000",
+                "from b by b.example.com; Tue, 5 Mar 2024 18:50:00 +0000",
+                "from a by a.example.com; Tue, 5 Mar 2024 10:40:00 -0800",
+            ],
+            &ReceivedChainParams::default(),
+        );
+        let summary: Vec<_> = chain
+            .hops
+            .iter()
+            .map(|hop| (hop.delay, hop.clock_skew))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(Some(-1200), true), (Some(600), false), (None, false)]
+        );
+        assert!(chain.clock_skew_detected);
+    }
+}
@@ -1780,9 +1780,17 @@ static HEX_CHARS: &[u8] = &[
 ];
 
 pub(crate) fn qp_encode(s: &str) -> String {
+    qp_encode_width(s, 75)
+}
+
+/// Encode s as a sequence of RFC 2047 encoded-words, folding
+/// so that each line is no longer than width, including the
+/// leading whitespace of continuation lines
+pub(crate) fn qp_encode_width(s: &str, width: usize) -> String {
     let prefix = b"=?UTF-8?q?";
     let suffix = b"?=";
-    let limit = 74 - (prefix.len() + suffix.len());
+    // Always leave room for at least one encoded byte per line
+    let limit = width.saturating_sub(1 + prefix.len() + suffix.len()).max(3);
 
     let mut result = Vec::with_capacity(s.len());
 
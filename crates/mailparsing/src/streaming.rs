@@ -0,0 +1,468 @@
+//! A pull parser that walks the MIME structure of a message as it is
+//! read from an `io::BufRead`, without holding the whole message in
+//! memory. This is useful for very large messages where only the
+//! headers, or a running digest of the part bodies, are required;
+//! building a `MimePart` tree for such a message requires a copy of
+//! the entire message.
+use crate::header::HeaderParseResult;
+use crate::{Header, HeaderMap, MailParsingError, Result};
+use std::io::BufRead;
+
+/// The default size of the body chunks produced by `MimeStreamParser`
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// The default limit on the size of the header block of a part
+const DEFAULT_MAX_HEADER_SIZE: usize = 1024 * 1024;
+/// Lines that are longer than this are read in pieces, so that
+/// a message with no line breaks cannot cause unbounded memory usage
+const MAX_LINE_READ: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MimeEvent {
+    /// The start of a part, along with its parsed headers.
+    /// The top level of the message has a depth of 0, its
+    /// children have a depth of 1 and so on.
+    PartStart {
+        depth: usize,
+        headers: HeaderMap<'static>,
+    },
+    /// A chunk of the raw, transfer-encoded body of a part that is not
+    /// multipart. Concatenating the chunks of a part produces the same
+    /// content as `MimePart::raw_body`.
+    BodyChunk { depth: usize, data: Vec<u8> },
+    /// The end of a part
+    PartEnd { depth: usize },
+}
+
+struct Frame {
+    /// For multipart, the boundary that delimits the children
+    boundary: Option<Vec<u8>>,
+}
+
+enum State {
+    /// Reading the header block of a part that will be at
+    /// the depth given by the length of the stack
+    Headers(Vec<u8>),
+    /// Reading the body of the part at the top of the stack
+    Body,
+    /// Reading the content that follows the final boundary of a
+    /// multipart. The parts above the multipart have been closed.
+    Epilogue,
+    Done,
+}
+
+/// Produces a sequence of `MimeEvent`s from a message
+pub struct MimeStreamParser<R> {
+    reader: R,
+    state: State,
+    stack: Vec<Frame>,
+    events: std::collections::VecDeque<MimeEvent>,
+    body: Vec<u8>,
+    line: Vec<u8>,
+    at_line_start: bool,
+    chunk_size: usize,
+    max_header_size: usize,
+}
+
+impl<R: BufRead> MimeStreamParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            state: State::Headers(vec![]),
+            stack: vec![],
+            events: Default::default(),
+            body: vec![],
+            line: vec![],
+            at_line_start: true,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+        }
+    }
+
+    /// Sets the size at which body data is emitted as a `BodyChunk`
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Sets the maximum size of the header block of any part.
+    /// Parsing fails if it is exceeded.
+    pub fn with_max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+
+    /// Returns the next event, or None once the end of the
+    /// message has been reached
+    pub fn next_event(&mut self) -> Result<Option<MimeEvent>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if matches!(self.state, State::Done) {
+                return Ok(None);
+            }
+
+            let was_line_start = self.at_line_start;
+            if !self.read_line()? {
+                self.finish()?;
+                continue;
+            }
+
+            match &mut self.state {
+                State::Headers(block) => {
+                    block.extend_from_slice(&self.line);
+                    if block.len() > self.max_header_size {
+                        return Err(MailParsingError::HeaderParse(format!(
+                            "header block exceeds {} bytes",
+                            self.max_header_size
+                        )));
+                    }
+                    if was_line_start && (self.line == b"\r\n" || self.line == b"\n") {
+                        let block = std::mem::take(block);
+                        self.start_part(block)?;
+                    }
+                }
+                State::Body | State::Epilogue => {
+                    if was_line_start && self.line.starts_with(b"--") {
+                        if let Some((idx, is_final)) = self.match_boundary() {
+                            self.handle_boundary(idx, is_final);
+                            continue;
+                        }
+                    }
+                    if matches!(self.state, State::Body) && self.is_leaf() {
+                        self.body.extend_from_slice(&self.line);
+                        if self.body.len() >= self.chunk_size {
+                            self.flush_body();
+                        }
+                    }
+                }
+                State::Done => {}
+            }
+        }
+    }
+
+    /// Reads the next line, or the next piece of an overly long line,
+    /// into self.line. Returns false at the end of the input.
+    fn read_line(&mut self) -> Result<bool> {
+        self.line.clear();
+        loop {
+            let available = self
+                .reader
+                .fill_buf()
+                .map_err(|err| MailParsingError::ReadError(format!("{err:#}")))?;
+            if available.is_empty() {
+                break;
+            }
+            let room = MAX_LINE_READ - self.line.len();
+            let (len, complete) = match memchr::memchr(b'\n', available) {
+                Some(idx) if idx < room => (idx + 1, true),
+                _ => (available.len().min(room), false),
+            };
+            self.line.extend_from_slice(&available[..len]);
+            self.reader.consume(len);
+            if complete {
+                self.at_line_start = true;
+                return Ok(true);
+            }
+            if self.line.len() == MAX_LINE_READ {
+                self.at_line_start = false;
+                return Ok(true);
+            }
+        }
+        self.at_line_start = true;
+        Ok(!self.line.is_empty())
+    }
+
+    fn depth(&self) -> usize {
+        self.stack.len().saturating_sub(1)
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.stack
+            .last()
+            .map(|frame| frame.boundary.is_none())
+            .unwrap_or(false)
+    }
+
+    fn start_part(&mut self, block: Vec<u8>) -> Result<()> {
+        let block = match String::from_utf8(block) {
+            Ok(block) => block,
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).to_string(),
+        };
+        let HeaderParseResult { headers, .. } = Header::parse_headers(block)?;
+
+        let boundary = match headers.content_type() {
+            Ok(Some(ct)) if ct.is_multipart() => ct.get("boundary").map(|b| b.into_bytes()),
+            _ => None,
+        };
+
+        self.events.push_back(MimeEvent::PartStart {
+            depth: self.stack.len(),
+            headers,
+        });
+        self.stack.push(Frame { boundary });
+        self.state = State::Body;
+        Ok(())
+    }
+
+    /// Determines whether the current line is a delimiter for any
+    /// of the enclosing multiparts, considering the innermost first,
+    /// returning the index of that multipart in the stack and whether
+    /// it is the final delimiter
+    fn match_boundary(&self) -> Option<(usize, bool)> {
+        let line = &self.line[2..];
+        for (idx, frame) in self.stack.iter().enumerate().rev() {
+            let Some(boundary) = &frame.boundary else {
+                continue;
+            };
+            let Some(remainder) = line.strip_prefix(boundary.as_slice()) else {
+                continue;
+            };
+            let (is_final, remainder) = match remainder.strip_prefix(b"--") {
+                Some(remainder) => (true, remainder),
+                None => (false, remainder),
+            };
+            if remainder.iter().all(|b| b.is_ascii_whitespace()) {
+                return Some((idx, is_final));
+            }
+        }
+        None
+    }
+
+    fn flush_body(&mut self) {
+        if !self.body.is_empty() {
+            self.events.push_back(MimeEvent::BodyChunk {
+                depth: self.depth(),
+                data: std::mem::take(&mut self.body),
+            });
+        }
+    }
+
+    /// Closes the parts above the multipart at idx in the stack
+    fn close_children(&mut self, idx: usize) {
+        self.flush_body();
+        while self.stack.len() > idx + 1 {
+            self.events.push_back(MimeEvent::PartEnd {
+                depth: self.depth(),
+            });
+            self.stack.pop();
+        }
+    }
+
+    fn handle_boundary(&mut self, idx: usize, is_final: bool) {
+        self.close_children(idx);
+        if is_final {
+            self.state = State::Epilogue;
+        } else {
+            self.state = State::Headers(vec![]);
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let State::Headers(block) = &mut self.state {
+            // A part that has no body, or a message that is
+            // comprised solely of headers
+            if !block.is_empty() {
+                let block = std::mem::take(block);
+                self.start_part(block)?;
+            }
+        }
+        self.flush_body();
+        while !self.stack.is_empty() {
+            self.events.push_back(MimeEvent::PartEnd {
+                depth: self.depth(),
+            });
+            self.stack.pop();
+        }
+        self.state = State::Done;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for MimeStreamParser<R> {
+    type Item = Result<MimeEvent>;
+
+    fn next(&mut self) -> Option<Result<MimeEvent>> {
+        match self.next_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => None,
+            Err(err) => {
+                self.state = State::Done;
+                self.events.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Reads just the header block of the top level of a message,
+/// without reading the body
+pub fn read_headers<R: BufRead>(reader: R) -> Result<HeaderMap<'static>> {
+    let mut parser = MimeStreamParser::new(reader);
+    match parser.next_event()? {
+        Some(MimeEvent::PartStart { headers, .. }) => Ok(headers),
+        _ => Ok(HeaderMap::default()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MimePart;
+
+    /// Collects the concatenated body of each part, in the order
+    /// in which the parts start, along with the depth of the part
+    fn collect_bodies(message: &str, chunk_size: usize) -> Vec<(usize, String)> {
+        let parser = MimeStreamParser::new(message.as_bytes()).with_chunk_size(chunk_size);
+        let mut parts = vec![];
+        let mut open = vec![];
+        for event in parser {
+            match event.unwrap() {
+                MimeEvent::PartStart { depth, .. } => {
+                    open.push(parts.len());
+                    parts.push((depth, String::new()));
+                }
+                MimeEvent::BodyChunk { depth, data } => {
+                    let idx = *open.last().unwrap();
+                    assert_eq!(parts[idx].0, depth);
+                    parts[idx].1.push_str(std::str::from_utf8(&data).unwrap());
+                }
+                MimeEvent::PartEnd { depth } => {
+                    let idx = open.pop().unwrap();
+                    assert_eq!(parts[idx].0, depth);
+                }
+            }
+        }
+        assert!(open.is_empty());
+        parts
+    }
+
+    /// Flattens the tree into the same form as collect_bodies
+    fn tree_bodies(part: &MimePart, depth: usize, parts: &mut Vec<(usize, String)>) {
+        let body = if part.child_parts().is_empty() {
+            part.raw_body().to_string()
+        } else {
+            String::new()
+        };
+        parts.push((depth, body));
+        for child in part.child_parts() {
+            tree_bodies(child, depth + 1, parts);
+        }
+    }
+
+    const NESTED: &str = concat!(
+        "Subject: nested\r\n",
+        "Content-Type: multipart/mixed; boundary=outer\r\n",
+        "\r\n",
+        "This is the preamble\r\n",
+        "--outer\r\n",
+        "Content-Type: multipart/alternative; boundary=\"inner\"\r\n",
+        "\r\n",
+        "--inner\r\n",
+        "Content-Type: text/plain\r\n",
+        "\r\n",
+        "plain text\r\n",
+        "--inner\r\n",
+        "Content-Type: text/html\r\n",
+        "\r\n",
+        "<b>html</b>\r\n",
+        "--inner--\r\n",
+        "--outer\r\n",
+        "Content-Type: application/octet-stream\r\n",
+        "Content-Transfer-Encoding: base64\r\n",
+        "\r\n",
+        "aGVsbG8K\r\n",
+        "aGVsbG8K\r\n",
+        "--outer--\r\n",
+        "This is the epilogue\r\n",
+    );
+
+    #[test]
+    fn matches_mime_part() {
+        let part = MimePart::parse(NESTED).unwrap();
+        let mut expected = vec![];
+        tree_bodies(&part, 0, &mut expected);
+
+        assert_eq!(
+            expected,
+            vec![
+                (0, String::new()),
+                (1, String::new()),
+                (2, "plain text\r\n".to_string()),
+                (2, "<b>html</b>\r\n".to_string()),
+                (1, "aGVsbG8K\r\naGVsbG8K\r\n".to_string()),
+            ]
+        );
+
+        for chunk_size in [1, 7, DEFAULT_CHUNK_SIZE] {
+            assert_eq!(collect_bodies(NESTED, chunk_size), expected);
+        }
+    }
+
+    #[test]
+    fn headers() {
+        let mut parser = MimeStreamParser::new(NESTED.as_bytes());
+        let mut content_types = vec![];
+        while let Some(event) = parser.next_event().unwrap() {
+            if let MimeEvent::PartStart { depth, headers } = event {
+                content_types.push((depth, headers.content_type().unwrap().unwrap().value));
+            }
+        }
+        assert_eq!(
+            content_types,
+            vec![
+                (0, "multipart/mixed".to_string()),
+                (1, "multipart/alternative".to_string()),
+                (2, "text/plain".to_string()),
+                (2, "text/html".to_string()),
+                (1, "application/octet-stream".to_string()),
+            ]
+        );
+
+        let headers = read_headers(NESTED.as_bytes()).unwrap();
+        assert_eq!(headers.subject().unwrap().unwrap(), "nested");
+    }
+
+    #[test]
+    fn simple_and_truncated() {
+        let message = "Subject: hello\nFrom: someone@example.com\n\nI am the body";
+        assert_eq!(
+            collect_bodies(message, 4),
+            vec![(0, "I am the body".to_string())]
+        );
+
+        // No body, and no blank line after the headers
+        let message = "Subject: hello\r\n";
+        assert_eq!(collect_bodies(message, 4), vec![(0, String::new())]);
+
+        // A multipart that is missing its final boundary
+        let message = concat!(
+            "Content-Type: multipart/mixed; boundary=b\r\n",
+            "\r\n",
+            "--b\r\n",
+            "\r\n",
+            "truncated\r\n",
+        );
+        assert_eq!(
+            collect_bodies(message, 4),
+            vec![(0, String::new()), (1, "truncated\r\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn long_lines() {
+        let long_line = "a".repeat(MAX_LINE_READ * 2 + 10);
+        let message = format!("Subject: long\r\n\r\n{long_line}\r\n--not-a-boundary\r\n");
+        assert_eq!(
+            collect_bodies(&message, DEFAULT_CHUNK_SIZE),
+            vec![(0, format!("{long_line}\r\n--not-a-boundary\r\n"))]
+        );
+    }
+
+    #[test]
+    fn header_limit() {
+        let message = format!("Subject: {}\r\n\r\nbody\r\n", "a".repeat(100));
+        let mut parser = MimeStreamParser::new(message.as_bytes()).with_max_header_size(50);
+        assert!(parser.next_event().is_err());
+    }
+}
@@ -1,7 +1,12 @@
 pub fn wrap(value: &str) -> String {
     const SOFT_WIDTH: usize = 75;
+    wrap_width(value, SOFT_WIDTH)
+}
+
+/// Wrap value, preferring to fold lines at soft_width
+pub fn wrap_width(value: &str, soft_width: usize) -> String {
     const HARD_WIDTH: usize = 1000;
-    wrap_impl(value, SOFT_WIDTH, HARD_WIDTH)
+    wrap_impl(value, soft_width, HARD_WIDTH)
 }
 
 /// We can't use textwrap::fill here because it will prefer to break
@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+
+/// A file holding the data of one or more messages that has not
+/// been loaded into memory, such as a large message that was written
+/// to disk while it was being received.
+/// The file is removed when this is dropped.
+#[derive(Debug)]
+pub struct DataFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl DataFile {
+    /// Takes ownership of the file at path, which holds len bytes
+    pub fn new(path: PathBuf, len: u64) -> Self {
+        Self { path, len }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for DataFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::error!("removing {}: {err:#}", self.path.display());
+        }
+    }
+}
@@ -1,18 +1,22 @@
 use anyhow::Context;
 use config::{any_err, from_lua_value, get_or_create_sub_module};
 use data_loader::KeySource;
+use futures::StreamExt;
 use kumo_dkim::DkimPrivateKey;
 use lruttl::LruCacheWithTtl;
 use mlua::prelude::LuaUserData;
 use mlua::{Lua, Value};
-use prometheus::{Counter, Histogram};
+use prometheus::{Counter, Histogram, HistogramVec, IntCounterVec};
 use serde::Deserialize;
-use std::sync::{Arc, LazyLock, OnceLock};
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 
-static SIGNER_CACHE: LazyLock<LruCacheWithTtl<SignerConfig, Arc<CFSigner>>> =
+static SIGNER_CACHE: LazyLock<LruCacheWithTtl<SignerCacheKey, CachedSigner>> =
     LazyLock::new(|| LruCacheWithTtl::new_named("dkim_signer_cache", 1024));
+/// The signers that are currently being refreshed in the background
+static SIGNER_REFRESHING: LazyLock<Mutex<HashSet<SignerCacheKey>>> = LazyLock::new(Mutex::default);
 static SIGNER_KEY_FETCH: LazyLock<Histogram> = LazyLock::new(|| {
     prometheus::register_histogram!(
         "dkim_signer_key_fetch",
@@ -20,6 +24,30 @@ static SIGNER_KEY_FETCH: LazyLock<Histogram> = LazyLock::new(|| {
     )
     .unwrap()
 });
+static SIGNER_KEY_FETCH_BY_SOURCE: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "dkim_signer_key_fetch_by_source",
+        "how long it takes to obtain a dkim key, by the kind of keysource",
+        &["source"]
+    )
+    .unwrap()
+});
+static SIGNER_KEY_FETCH_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "dkim_signer_key_fetch_errors",
+        "how many attempts to obtain a dkim key failed, by the kind of keysource",
+        &["source"]
+    )
+    .unwrap()
+});
+static SIGNER_STALE: LazyLock<Counter> = LazyLock::new(|| {
+    prometheus::register_counter!(
+        "dkim_signer_stale",
+        "how many dkim signer requests were satisfied by a signer that is \
+         past its ttl, while it is refreshed in the background"
+    )
+    .unwrap()
+});
 static SIGNER_CREATE: LazyLock<Histogram> = LazyLock::new(|| {
     prometheus::register_histogram!(
         "dkim_signer_creation",
@@ -109,6 +137,11 @@ pub struct SignerConfig {
 
     #[serde(default = "SignerConfig::default_ttl")]
     ttl: u64,
+
+    /// How long past its ttl a cached signer may continue to be
+    /// used while its key is refreshed in the background
+    #[serde(default)]
+    stale_ttl: u64,
 }
 
 impl SignerConfig {
@@ -193,37 +226,12 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     dkim_mod.set(
         "rsa_sha256_signer",
         lua.create_async_function(|lua, params: Value| async move {
-            let params: SignerConfig = from_lua_value(&lua, params)?;
-
-            SIGNER_CACHE_LOOKUP.inc();
-            if let Some(inner) = SIGNER_CACHE.get(&params) {
-                SIGNER_CACHE_HIT.inc();
-                return Ok(Signer(inner));
-            }
-            SIGNER_CACHE_MISS.inc();
-
-            let signer_creation_timer = SIGNER_CREATE.start_timer();
-            let key_fetch_timer = SIGNER_KEY_FETCH.start_timer();
-            let data = params
-                .key
-                .get()
-                .await
-                .map_err(|err| mlua::Error::external(format!("{:?}: {err:#}", params.key)))?;
-
-            let key = DkimPrivateKey::rsa_key(&data)
-                .map_err(|err| mlua::Error::external(format!("{:?}: {err}", params.key)))?;
-            key_fetch_timer.stop_and_record();
-
-            let signer = params
-                .configure_kumo_dkim(key)
-                .map_err(|err| mlua::Error::external(format!("{err:#}")))?;
-
-            let inner = Arc::new(CFSigner { signer });
-
-            let expiration = Instant::now() + Duration::from_secs(params.ttl);
-            SIGNER_CACHE.insert(params, Arc::clone(&inner), expiration);
-
-            signer_creation_timer.stop_and_record();
+            let config: SignerConfig = from_lua_value(&lua, params)?;
+            let key = SignerCacheKey {
+                algo: KeyAlgo::RsaSha256,
+                config,
+            };
+            let inner = key.get().await.map_err(any_err)?;
             Ok(Signer(inner))
         })?,
     )?;
@@ -231,38 +239,160 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
     dkim_mod.set(
         "ed25519_signer",
         lua.create_async_function(|lua, params: Value| async move {
-            let params: SignerConfig = from_lua_value(&lua, params)?;
+            let config: SignerConfig = from_lua_value(&lua, params)?;
+            let key = SignerCacheKey {
+                algo: KeyAlgo::Ed25519,
+                config,
+            };
+            let inner = key.get().await.map_err(any_err)?;
+            Ok(Signer(inner))
+        })?,
+    )?;
 
-            if let Some(inner) = SIGNER_CACHE.get(&params) {
-                return Ok(Signer(inner));
+    dkim_mod.set(
+        "prefetch_signers",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: PrefetchParams = from_lua_value(&lua, params)?;
+            let keys: Vec<SignerCacheKey> = params
+                .rsa_sha256
+                .into_iter()
+                .map(|config| SignerCacheKey {
+                    algo: KeyAlgo::RsaSha256,
+                    config,
+                })
+                .chain(params.ed25519.into_iter().map(|config| SignerCacheKey {
+                    algo: KeyAlgo::Ed25519,
+                    config,
+                }))
+                .collect();
+            let total = keys.len();
+
+            let errors: Vec<String> = futures::stream::iter(keys)
+                .map(|key| async move { key.fetch().await.err().map(|err| format!("{err:#}")) })
+                .buffer_unordered(params.concurrency.max(1))
+                .filter_map(|err| async move { err })
+                .collect()
+                .await;
+
+            if !errors.is_empty() {
+                return Err(mlua::Error::external(format!(
+                    "failed to prefetch {} of {total} signers: {}",
+                    errors.len(),
+                    errors.join(", ")
+                )));
             }
+            Ok(())
+        })?,
+    )?;
+    Ok(())
+}
 
-            let signer_creation_timer = SIGNER_CREATE.start_timer();
-            let key_fetch_timer = SIGNER_KEY_FETCH.start_timer();
-            let data = params
-                .key
-                .get()
-                .await
-                .map_err(|err| mlua::Error::external(format!("{:?}: {err:#}", params.key)))?;
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+enum KeyAlgo {
+    RsaSha256,
+    Ed25519,
+}
 
-            let key = DkimPrivateKey::ed25519_key(&data)
-                .map_err(|err| mlua::Error::external(format!("{:?}: {err}", params.key)))?;
-            key_fetch_timer.stop_and_record();
+#[derive(Hash, PartialEq, Eq, Clone)]
+struct SignerCacheKey {
+    algo: KeyAlgo,
+    config: SignerConfig,
+}
 
-            let signer = params
-                .configure_kumo_dkim(key)
-                .map_err(|err| mlua::Error::external(format!("{err:#}")))?;
+#[derive(Clone)]
+struct CachedSigner {
+    signer: Arc<CFSigner>,
+    refresh_after: Instant,
+}
 
-            let inner = Arc::new(CFSigner { signer });
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrefetchParams {
+    #[serde(default)]
+    rsa_sha256: Vec<SignerConfig>,
+    #[serde(default)]
+    ed25519: Vec<SignerConfig>,
+    #[serde(default = "PrefetchParams::default_concurrency")]
+    concurrency: usize,
+}
 
-            let expiration = Instant::now() + Duration::from_secs(params.ttl);
-            SIGNER_CACHE.insert(params, Arc::clone(&inner), expiration);
+impl PrefetchParams {
+    fn default_concurrency() -> usize {
+        16
+    }
+}
 
-            signer_creation_timer.stop_and_record();
-            Ok(Signer(inner))
-        })?,
-    )?;
-    Ok(())
+impl SignerCacheKey {
+    /// Returns the cached signer, loading its key if it is not cached.
+    /// A signer that is past its ttl, but still within its stale_ttl,
+    /// is returned immediately while its key is refreshed in the
+    /// background, so that a slow keysource doesn't hold up the caller.
+    async fn get(self) -> anyhow::Result<Arc<CFSigner>> {
+        SIGNER_CACHE_LOOKUP.inc();
+        if let Some(cached) = SIGNER_CACHE.get(&self) {
+            SIGNER_CACHE_HIT.inc();
+            if Instant::now() >= cached.refresh_after {
+                SIGNER_STALE.inc();
+                self.refresh_in_background();
+            }
+            return Ok(cached.signer);
+        }
+        SIGNER_CACHE_MISS.inc();
+        self.fetch().await
+    }
+
+    fn refresh_in_background(self) {
+        if !SIGNER_REFRESHING.lock().unwrap().insert(self.clone()) {
+            // Already being refreshed
+            return;
+        }
+        tokio::spawn(async move {
+            if let Err(err) = self.fetch().await {
+                // Keep using the stale signer until it expires from
+                // the cache; the next request after that will try again
+                tracing::error!("failed to refresh dkim signer: {err:#}");
+            }
+            SIGNER_REFRESHING.lock().unwrap().remove(&self);
+        });
+    }
+
+    /// Loads the key, and creates and caches the signer
+    async fn fetch(&self) -> anyhow::Result<Arc<CFSigner>> {
+        let source = self.config.key.kind();
+        let signer_creation_timer = SIGNER_CREATE.start_timer();
+        let key_fetch_timer = SIGNER_KEY_FETCH.start_timer();
+        let source_timer = SIGNER_KEY_FETCH_BY_SOURCE
+            .with_label_values(&[source])
+            .start_timer();
+        let data = self.config.key.get().await.map_err(|err| {
+            SIGNER_KEY_FETCH_ERRORS.with_label_values(&[source]).inc();
+            anyhow::anyhow!("{:?}: {err:#}", self.config.key)
+        })?;
+        source_timer.stop_and_record();
+
+        let key = match self.algo {
+            KeyAlgo::RsaSha256 => DkimPrivateKey::rsa_key(&data),
+            KeyAlgo::Ed25519 => DkimPrivateKey::ed25519_key(&data),
+        }
+        .map_err(|err| anyhow::anyhow!("{:?}: {err}", self.config.key))?;
+        key_fetch_timer.stop_and_record();
+
+        let signer = self.config.configure_kumo_dkim(key)?;
+        let inner = Arc::new(CFSigner { signer });
+
+        let refresh_after = Instant::now() + Duration::from_secs(self.config.ttl);
+        SIGNER_CACHE.insert(
+            self.clone(),
+            CachedSigner {
+                signer: Arc::clone(&inner),
+                refresh_after,
+            },
+            refresh_after + Duration::from_secs(self.config.stale_ttl),
+        );
+
+        signer_creation_timer.stop_and_record();
+        Ok(inner)
+    }
 }
 
 pub struct CFSigner {
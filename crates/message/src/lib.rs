@@ -1,6 +1,8 @@
 pub mod address;
+pub mod data_file;
 #[cfg(feature = "impl")]
 pub mod dkim;
+pub mod loop_detection;
 pub mod message;
 pub mod queue_name;
 pub mod scheduling;
@@ -0,0 +1,226 @@
+//! Helpers to recognize auto-generated messages, such as vacation
+//! replies, and messages that are looping between systems, so that
+//! policy can avoid responding to or relaying them
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LoopDetectionParams {
+    /// The message is considered to be looping when it has more
+    /// than this many Received headers
+    #[serde(default = "LoopDetectionParams::default_max_received")]
+    pub max_received: usize,
+
+    /// The name of the header that we add to mark that we have
+    /// handled the message
+    #[serde(default = "LoopDetectionParams::default_marker_header")]
+    pub marker_header: String,
+
+    /// The value of the marker header. The default is the
+    /// envelope recipient of the message.
+    #[serde(default)]
+    pub marker_value: Option<String>,
+
+    /// The message is considered to be looping when it has at least
+    /// this many of our marker headers
+    #[serde(default = "LoopDetectionParams::default_max_markers")]
+    pub max_markers: usize,
+}
+
+impl Default for LoopDetectionParams {
+    fn default() -> Self {
+        Self {
+            max_received: Self::default_max_received(),
+            marker_header: Self::default_marker_header(),
+            marker_value: None,
+            max_markers: Self::default_max_markers(),
+        }
+    }
+}
+
+impl LoopDetectionParams {
+    fn default_max_received() -> usize {
+        50
+    }
+
+    fn default_marker_header() -> String {
+        "Delivered-To".to_string()
+    }
+
+    fn default_max_markers() -> usize {
+        1
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoopVerdict {
+    /// true if the headers indicate that the message was generated
+    /// automatically, rather than by a person
+    pub auto_generated: bool,
+    /// The headers that caused the message to be considered as
+    /// auto-generated, in `Name: value` form
+    pub auto_generated_reasons: Vec<String>,
+    pub received_count: usize,
+    /// The number of our marker headers whose value matched
+    pub marker_count: usize,
+    /// true if the message is looping
+    pub is_loop: bool,
+    /// Explains why the message is considered to be looping
+    pub loop_reason: Option<String>,
+}
+
+/// Returns the reason that the header marks the message as
+/// auto-generated, if it does
+fn auto_generated_reason(name: &str, value: &str) -> Option<String> {
+    let value = value.trim();
+    let auto_generated = if name.eq_ignore_ascii_case("Auto-Submitted") {
+        // RFC 3834: any value other than "no" indicates that
+        // the message was generated automatically.
+        // Ignore any parameters, such as "auto-replied; owner=..."
+        let keyword = value.split(';').next().unwrap_or("").trim();
+        !keyword.is_empty() && !keyword.eq_ignore_ascii_case("no")
+    } else if name.eq_ignore_ascii_case("Precedence") {
+        ["bulk", "list", "junk", "auto_reply"]
+            .iter()
+            .any(|p| value.eq_ignore_ascii_case(p))
+    } else if name.eq_ignore_ascii_case("X-Auto-Response-Suppress") {
+        // Set by Microsoft products on messages, such as out-of-office
+        // replies, that should not themselves be replied to
+        value.split(',').map(|v| v.trim()).any(|v| {
+            ["All", "OOF", "AutoReply"]
+                .iter()
+                .any(|s| v.eq_ignore_ascii_case(s))
+        })
+    } else {
+        false
+    };
+    auto_generated.then(|| format!("{name}: {value}"))
+}
+
+/// Normalizes a marker value for comparison, so that `<a@example.com>`
+/// and `A@example.com` are considered to be the same
+fn normalize_marker(value: &str) -> String {
+    value
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_ascii_lowercase()
+}
+
+/// Computes the verdict for a message with the provided headers.
+/// marker_value is the value of our marker header for this message.
+pub fn check_loop<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    params: &LoopDetectionParams,
+    marker_value: &str,
+) -> LoopVerdict {
+    let mut verdict = LoopVerdict::default();
+    let marker_value = normalize_marker(marker_value);
+
+    for (name, value) in headers {
+        if let Some(reason) = auto_generated_reason(name, value) {
+            verdict.auto_generated = true;
+            verdict.auto_generated_reasons.push(reason);
+        }
+        if name.eq_ignore_ascii_case("Received") {
+            verdict.received_count += 1;
+        }
+        if name.eq_ignore_ascii_case(&params.marker_header)
+            && normalize_marker(value) == marker_value
+        {
+            verdict.marker_count += 1;
+        }
+    }
+
+    if verdict.received_count > params.max_received {
+        verdict.is_loop = true;
+        verdict.loop_reason.replace(format!(
+            "too many Received headers ({} > {})",
+            verdict.received_count, params.max_received
+        ));
+    } else if params.max_markers > 0 && verdict.marker_count >= params.max_markers {
+        verdict.is_loop = true;
+        verdict.loop_reason.replace(format!(
+            "already has {} {}: {marker_value} header(s)",
+            verdict.marker_count, params.marker_header
+        ));
+    }
+
+    verdict
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn auto_generated() {
+        for (name, value, expected) in [
+            ("Auto-Submitted", "auto-replied", true),
+            (
+                "auto-submitted",
+                "Auto-Generated; owner-email=a@example.com",
+                true,
+            ),
+            ("Auto-Submitted", "no", false),
+            ("Auto-Submitted", "No ; comment", false),
+            ("Precedence", "bulk", true),
+            ("Precedence", "first-class", false),
+            ("X-Auto-Response-Suppress", "DR, OOF", true),
+            ("X-Auto-Response-Suppress", "DR", false),
+            ("Subject", "auto-replied", false),
+        ] {
+            assert_eq!(
+                auto_generated_reason(name, value).is_some(),
+                expected,
+                "{name}: {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let params = LoopDetectionParams {
+            max_received: 2,
+            ..Default::default()
+        };
+        let headers = [
+            ("Received", "from a"),
+            ("Delivered-To", "<Other@example.com>"),
+            ("Received", "from b"),
+            ("Auto-Submitted", "auto-replied"),
+        ];
+        let verdict = check_loop(headers, &params, "user@example.com");
+        assert_eq!(
+            verdict,
+            LoopVerdict {
+                auto_generated: true,
+                auto_generated_reasons: vec!["Auto-Submitted: auto-replied".to_string()],
+                received_count: 2,
+                marker_count: 0,
+                is_loop: false,
+                loop_reason: None,
+            }
+        );
+
+        let verdict = check_loop(headers, &params, "other@example.com");
+        assert_eq!(verdict.marker_count, 1);
+        assert!(verdict.is_loop);
+        assert_eq!(
+            verdict.loop_reason.as_deref(),
+            Some("already has 1 Delivered-To: other@example.com header(s)")
+        );
+
+        let headers = [
+            ("Received", "from a"),
+            ("Received", "from b"),
+            ("Received", "from c"),
+        ];
+        let verdict = check_loop(headers, &params, "user@example.com");
+        assert!(verdict.is_loop);
+        assert_eq!(
+            verdict.loop_reason.as_deref(),
+            Some("too many Received headers (3 > 2)")
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use crate::address::HeaderAddressList;
+use crate::data_file::DataFile;
 #[cfg(feature = "impl")]
 use crate::dkim::Signer;
 #[cfg(feature = "impl")]
 use crate::dkim::SIGN_POOL;
+use crate::loop_detection::{check_loop, LoopDetectionParams, LoopVerdict};
 pub use crate::queue_name::QueueNameComponents;
 use crate::scheduling::Scheduling;
 use crate::EnvelopeAddress;
@@ -15,9 +17,13 @@ use intrusive_collections::{intrusive_adapter, LinkedList, LinkedListAtomicLink}
 use kumo_chrono_helper::*;
 use kumo_log_types::rfc3464::Report;
 use kumo_log_types::rfc5965::ARFReport;
+use kumo_log_types::Annotation;
+use mailparsing::{
+    AttachmentPolicy, AttachmentViolation, CalendarInfo, DecodedBody, Header, HeaderParseResult,
+    MessageConformance, MimePart, ReceivedChain, ReceivedChainParams,
+};
 #[cfg(feature = "impl")]
 use mailparsing::{AuthenticationResult, AuthenticationResults, EncodeHeaderValue};
-use mailparsing::{DecodedBody, Header, HeaderParseResult, MessageConformance, MimePart};
 #[cfg(feature = "impl")]
 use mlua::{LuaSerdeExt, UserData, UserDataMethods};
 use prometheus::{Histogram, IntGauge};
@@ -82,15 +88,52 @@ static LOAD_META_HIST: LazyLock<Histogram> = LazyLock::new(|| {
     .unwrap()
 });
 
+/// The maximum number of annotations that are retained for a message.
+/// Once reached, the oldest annotations are discarded.
+const MAX_ANNOTATIONS: usize = 64;
+
 #[derive(Debug)]
 struct MessageInner {
     metadata: Option<Box<MetaData>>,
     data: Arc<Box<[u8]>>,
+    /// Holds the data while it has not been loaded into memory
+    data_file: Option<FileBackedData>,
     flags: MessageFlags,
     num_attempts: u16,
     due: Option<DateTime<Utc>>,
 }
 
+/// Message data that is held in a file, rather than in memory.
+/// The data is the prefix, which holds any headers that have been
+/// prepended since the message was created, followed by the content
+/// of the file.
+#[derive(Debug)]
+struct FileBackedData {
+    prefix: Vec<u8>,
+    file: Arc<DataFile>,
+}
+
+impl FileBackedData {
+    fn load(&self) -> anyhow::Result<Vec<u8>> {
+        let path = self.file.path();
+        let mut data = Vec::with_capacity(self.prefix.len() + self.file.len() as usize);
+        data.extend_from_slice(&self.prefix);
+        std::fs::File::open(path)
+            .and_then(|mut file| std::io::Read::read_to_end(&mut file, &mut data))
+            .with_context(|| format!("failed to read message data from {}", path.display()))?;
+        Ok(data)
+    }
+}
+
+/// The data of a message that needs to be saved
+enum DirtyData {
+    Memory(Arc<Box<[u8]>>),
+    File {
+        prefix: Vec<u8>,
+        file: Arc<DataFile>,
+    },
+}
+
 #[derive(Debug)]
 struct MessageWithId {
     id: SpoolId,
@@ -181,6 +224,12 @@ impl MessageList {
             self.push_back(msg)
         }
     }
+
+    /// Returns the ids of the messages in the list, without
+    /// removing them from it
+    pub fn ids(&self) -> impl Iterator<Item = SpoolId> + '_ {
+        self.list.iter().map(|msg_and_id| msg_and_id.id)
+    }
 }
 
 impl IntoIterator for MessageList {
@@ -246,6 +295,8 @@ struct MetaData {
     meta: serde_json::Value,
     #[serde(default)]
     schedule: Option<Scheduling>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<Annotation>,
 }
 
 impl Drop for MessageInner {
@@ -269,10 +320,49 @@ impl Message {
         recipient: EnvelopeAddress,
         meta: serde_json::Value,
         data: Arc<Box<[u8]>>,
+    ) -> anyhow::Result<Self> {
+        let message = Self::new_dirty_impl(id, sender, recipient, meta, data, None)?;
+        DATA_COUNT.inc();
+        Ok(message)
+    }
+
+    /// Create a new message whose data is prefix followed by the
+    /// content of the supplied file. The file is only loaded into
+    /// memory if the data is accessed; saving the message copies it
+    /// to the spool.
+    /// The message meta and data are marked as dirty
+    pub fn new_dirty_from_file(
+        id: SpoolId,
+        sender: EnvelopeAddress,
+        recipient: EnvelopeAddress,
+        meta: serde_json::Value,
+        prefix: Vec<u8>,
+        file: Arc<DataFile>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !prefix.is_empty() || !file.is_empty(),
+            "message data must not be empty"
+        );
+        Self::new_dirty_impl(
+            id,
+            sender,
+            recipient,
+            meta,
+            NO_DATA.clone(),
+            Some(FileBackedData { prefix, file }),
+        )
+    }
+
+    fn new_dirty_impl(
+        id: SpoolId,
+        sender: EnvelopeAddress,
+        recipient: EnvelopeAddress,
+        meta: serde_json::Value,
+        data: Arc<Box<[u8]>>,
+        data_file: Option<FileBackedData>,
     ) -> anyhow::Result<Self> {
         anyhow::ensure!(meta.is_object(), "metadata must be a json object");
         MESSAGE_COUNT.inc();
-        DATA_COUNT.inc();
         META_COUNT.inc();
         Ok(Self {
             msg_and_id: Arc::new(MessageWithId {
@@ -283,8 +373,10 @@ impl Message {
                         recipient,
                         meta,
                         schedule: None,
+                        annotations: vec![],
                     })),
                     data,
+                    data_file,
                     flags: MessageFlags::META_DIRTY | MessageFlags::DATA_DIRTY,
                     num_attempts: 0,
                     due: None,
@@ -320,6 +412,7 @@ impl Message {
                 inner: Mutex::new(MessageInner {
                     metadata: Some(Box::new(metadata)),
                     data: NO_DATA.clone(),
+                    data_file: None,
                     flags,
                     num_attempts: 0,
                     due: None,
@@ -434,12 +527,17 @@ impl Message {
         }
     }
 
-    fn get_data_if_dirty(&self) -> Option<Arc<Box<[u8]>>> {
+    fn get_data_if_dirty(&self) -> Option<DirtyData> {
         let inner = self.msg_and_id.inner.lock().unwrap();
-        if inner.flags.contains(MessageFlags::DATA_DIRTY) {
-            Some(Arc::clone(&inner.data))
-        } else {
-            None
+        if !inner.flags.contains(MessageFlags::DATA_DIRTY) {
+            return None;
+        }
+        match &inner.data_file {
+            Some(backing) if inner.data.is_empty() => Some(DirtyData::File {
+                prefix: backing.prefix.clone(),
+                file: Arc::clone(&backing.file),
+            }),
+            _ => Some(DirtyData::Memory(Arc::clone(&inner.data))),
         }
     }
 
@@ -481,14 +579,20 @@ impl Message {
             .flags
             .contains(MessageFlags::FORCE_SYNC);
 
-        let data_fut = if let Some(data) = self.get_data_if_dirty() {
-            anyhow::ensure!(!data.is_empty(), "message data must not be empty");
-            data_spool
-                .store(self.msg_and_id.id, data, force_sync)
+        let dirty_data = self.get_data_if_dirty();
+        let data_fut = match &dirty_data {
+            Some(DirtyData::Memory(data)) => {
+                anyhow::ensure!(!data.is_empty(), "message data must not be empty");
+                data_spool
+                    .store(self.msg_and_id.id, Arc::clone(data), force_sync)
+                    .map(|_| true)
+                    .boxed()
+            }
+            Some(DirtyData::File { prefix, file }) => data_spool
+                .store_file(self.msg_and_id.id, prefix, file.path(), force_sync)
                 .map(|_| true)
-                .boxed()
-        } else {
-            futures::future::ready(false).boxed()
+                .boxed(),
+            None => futures::future::ready(false).boxed(),
         };
         let meta_fut = if let Some(meta) = self.get_meta_if_dirty() {
             let meta = Arc::new(serde_json::to_vec(&meta)?.into_boxed_slice());
@@ -551,6 +655,9 @@ impl Message {
             inner.data = NO_DATA.clone();
             did_shrink = true;
         }
+        if inner.data_file.take().is_some() {
+            did_shrink = true;
+        }
         Ok(did_shrink)
     }
 
@@ -598,8 +705,12 @@ impl Message {
         self.msg_and_id.inner.lock().unwrap().metadata.is_some()
     }
 
+    /// Returns true if the data is available without loading it
+    /// from the spool, either because it is in memory or because
+    /// it is backed by a DataFile
     pub fn is_data_loaded(&self) -> bool {
-        !self.msg_and_id.inner.lock().unwrap().data.is_empty()
+        let inner = self.msg_and_id.inner.lock().unwrap();
+        !inner.data.is_empty() || inner.data_file.is_some()
     }
 
     pub async fn load_meta_if_needed(&self) -> anyhow::Result<()> {
@@ -650,6 +761,7 @@ impl Message {
         let mut inner = self.msg_and_id.inner.lock().unwrap();
         let was_empty = inner.data.is_empty();
         inner.data = Arc::new(data.into_boxed_slice());
+        inner.data_file.take();
         if was_empty {
             DATA_COUNT.inc();
         }
@@ -660,14 +772,43 @@ impl Message {
         let mut inner = self.msg_and_id.inner.lock().unwrap();
         let was_empty = inner.data.is_empty();
         inner.data = Arc::new(data.into_boxed_slice());
+        inner.data_file.take();
         inner.flags.set(MessageFlags::DATA_DIRTY, true);
         if was_empty {
             DATA_COUNT.inc();
         }
     }
 
-    pub fn get_data(&self) -> Arc<Box<[u8]>> {
+    /// Returns the size of the data, without loading it into memory
+    /// if it is backed by a DataFile
+    pub fn get_data_len(&self) -> usize {
         let inner = self.msg_and_id.inner.lock().unwrap();
+        match &inner.data_file {
+            Some(backing) if inner.data.is_empty() => {
+                backing.prefix.len() + backing.file.len() as usize
+            }
+            _ => inner.data.len(),
+        }
+    }
+
+    /// Returns the data of the message. If it is backed by a DataFile,
+    /// it is loaded into memory first.
+    pub fn get_data(&self) -> Arc<Box<[u8]>> {
+        let mut inner = self.msg_and_id.inner.lock().unwrap();
+        if inner.data.is_empty() {
+            if let Some(result) = inner.data_file.as_ref().map(FileBackedData::load) {
+                match result {
+                    Ok(data) => {
+                        inner.data = Arc::new(data.into_boxed_slice());
+                        inner.data_file.take();
+                        DATA_COUNT.inc();
+                    }
+                    Err(err) => {
+                        tracing::error!("{}: {err:#}", self.id());
+                    }
+                }
+            }
+        }
         inner.data.clone()
     }
 
@@ -696,6 +837,48 @@ impl Message {
         }
     }
 
+    /// Records an annotation against the message, so that it
+    /// is retained in the spool and included in its log records.
+    /// An annotation that is the same as the most recent one
+    /// is not recorded again, so that repeated decisions, such
+    /// as throttling, don't flood the timeline.
+    pub fn add_annotation<S: Into<String>>(&self, text: S) -> anyhow::Result<()> {
+        let mut inner = self.msg_and_id.inner.lock().unwrap();
+        match &mut inner.metadata {
+            None => anyhow::bail!("metadata must be loaded first"),
+            Some(meta) => {
+                let text = text.into();
+                if meta
+                    .annotations
+                    .last()
+                    .map(|a| a.text == text)
+                    .unwrap_or(false)
+                {
+                    return Ok(());
+                }
+                if meta.annotations.len() >= MAX_ANNOTATIONS {
+                    meta.annotations.remove(0);
+                }
+                meta.annotations.push(Annotation {
+                    timestamp: Utc::now(),
+                    text,
+                });
+                inner.flags.set(MessageFlags::META_DIRTY, true);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the annotations that have been recorded against
+    /// the message, oldest first
+    pub fn get_annotations(&self) -> anyhow::Result<Vec<Annotation>> {
+        let inner = self.msg_and_id.inner.lock().unwrap();
+        match &inner.metadata {
+            None => anyhow::bail!("metadata must be loaded first"),
+            Some(meta) => Ok(meta.annotations.clone()),
+        }
+    }
+
     /// Retrieve `key` as a String.
     pub fn get_meta_string<S: serde_json::value::Index + std::fmt::Display + Copy>(
         &self,
@@ -801,7 +984,38 @@ impl Message {
         ARFReport::parse(&data)
     }
 
+    pub fn get_calendar_info(&self) -> anyhow::Result<Option<CalendarInfo>> {
+        let data = self.get_data();
+        let msg = MimePart::parse(data.as_ref().as_ref())?;
+        Ok(msg.calendar_info()?)
+    }
+
+    pub fn check_attachment_policy(
+        &self,
+        policy: &AttachmentPolicy,
+    ) -> anyhow::Result<Vec<AttachmentViolation>> {
+        let data = self.get_data();
+        let msg = MimePart::parse(data.as_ref().as_ref())?;
+        Ok(msg.check_attachment_policy(policy)?)
+    }
+
     pub fn prepend_header(&self, name: Option<&str>, value: &str) {
+        {
+            // Avoid loading file backed data just to add a header
+            let mut guard = self.msg_and_id.inner.lock().unwrap();
+            let inner = &mut *guard;
+            if inner.data.is_empty() {
+                if let Some(backing) = &mut inner.data_file {
+                    let mut prefix =
+                        Vec::with_capacity(size_header(name, value) + 2 + backing.prefix.len());
+                    emit_header(&mut prefix, name, value);
+                    prefix.extend_from_slice(&backing.prefix);
+                    backing.prefix = prefix;
+                    inner.flags.set(MessageFlags::DATA_DIRTY, true);
+                    return;
+                }
+            }
+        }
         let data = self.get_data();
         let mut new_data = Vec::with_capacity(size_header(name, value) + 2 + data.len());
         emit_header(&mut new_data, name, value);
@@ -876,6 +1090,44 @@ impl Message {
         Ok(values)
     }
 
+    /// Parses the Received headers of the message into structured hops
+    pub fn get_received_chain(
+        &self,
+        params: &ReceivedChainParams,
+    ) -> anyhow::Result<ReceivedChain> {
+        let values = self.get_all_named_header_values("Received")?;
+        Ok(ReceivedChain::parse(
+            values.iter().map(|v| v.as_str()),
+            params,
+        ))
+    }
+
+    /// Returns the value of our loop marker header for this message
+    fn loop_marker_value(&self, params: &LoopDetectionParams) -> anyhow::Result<String> {
+        match &params.marker_value {
+            Some(value) => Ok(value.clone()),
+            None => Ok(self.recipient()?.to_string()),
+        }
+    }
+
+    pub fn check_mail_loop(&self, params: &LoopDetectionParams) -> anyhow::Result<LoopVerdict> {
+        let marker_value = self.loop_marker_value(params)?;
+        let headers = self.get_all_headers()?;
+        Ok(check_loop(
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+            params,
+            &marker_value,
+        ))
+    }
+
+    pub fn add_loop_marker(&self, params: &LoopDetectionParams) -> anyhow::Result<()> {
+        let marker_value = self.loop_marker_value(params)?;
+        self.prepend_header(Some(&params.marker_header), &marker_value);
+        Ok(())
+    }
+
     pub fn retain_headers<F: FnMut(&Header) -> bool>(&self, mut func: F) -> anyhow::Result<()> {
         let data = self.get_data();
         let mut new_data = Vec::with_capacity(data.len());
@@ -1038,6 +1290,51 @@ impl Message {
         }
     }
 
+    pub fn add_text_plain_alternative(&self) -> anyhow::Result<bool> {
+        let data = self.get_data();
+        let mut msg = MimePart::parse(data.as_ref().as_ref())?;
+        if msg.add_text_plain_alternative()? {
+            let new_data = msg.to_message_string();
+            self.assign_data(new_data.into_bytes());
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Rewrites the targets of the links in the primary html part.
+    /// If rewrite returns an error, the message is left unchanged.
+    pub fn rewrite_html_links(
+        &self,
+        mut rewrite: impl FnMut(&str) -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<usize> {
+        let data = self.get_data();
+        let mut msg = MimePart::parse(data.as_ref().as_ref())?;
+
+        let mut error = None;
+        let count = msg.rewrite_html_links(|url| {
+            if error.is_some() {
+                return None;
+            }
+            match rewrite(url) {
+                Ok(result) => result,
+                Err(err) => {
+                    error.replace(err);
+                    None
+                }
+            }
+        })?;
+        if let Some(err) = error {
+            return Err(err);
+        }
+
+        if count > 0 {
+            let new_data = msg.to_message_string();
+            self.assign_data(new_data.into_bytes());
+        }
+        Ok(count)
+    }
+
     pub fn check_fix_conformance(
         &self,
         check: MessageConformance,
@@ -1150,6 +1447,13 @@ impl UserData for Message {
             let value = this.get_meta(name).map_err(any_err)?;
             Ok(Some(lua.to_value_with(&value, serialize_options())?))
         });
+        methods.add_method("annotate", move |_, this, text: String| {
+            this.add_annotation(text).map_err(any_err)
+        });
+        methods.add_method("get_annotations", move |lua, this, _: ()| {
+            let annotations = this.get_annotations().map_err(any_err)?;
+            lua.to_value_with(&annotations, serialize_options())
+        });
         methods.add_method("get_data", move |lua, this, _: ()| {
             let data = this.get_data();
             lua.create_string(&*data)
@@ -1167,6 +1471,18 @@ impl UserData for Message {
             this.append_text_html(&data).map_err(any_err)
         });
 
+        methods.add_method("add_text_plain_alternative", move |_lua, this, _: ()| {
+            this.add_text_plain_alternative().map_err(any_err)
+        });
+
+        methods.add_method(
+            "rewrite_html_links",
+            move |_lua, this, rewrite: mlua::Function| {
+                this.rewrite_html_links(|url| Ok(rewrite.call(url.to_string())?))
+                    .map_err(any_err)
+            },
+        );
+
         methods.add_method("id", move |_, this, _: ()| Ok(this.id().to_string()));
         methods.add_method("sender", move |_, this, _: ()| {
             Ok(this.sender().map_err(any_err)?)
@@ -1339,6 +1655,56 @@ impl UserData for Message {
             }
         });
 
+        methods.add_method("get_calendar_info", move |lua, this, _: ()| {
+            let info = this.get_calendar_info().map_err(any_err)?;
+            match info {
+                Some(info) => lua.to_value_with(&info, serialize_options()),
+                None => Ok(mlua::Value::Nil),
+            }
+        });
+
+        methods.add_method(
+            "check_attachment_policy",
+            move |lua, this, policy: mlua::Value| {
+                let policy: AttachmentPolicy = from_lua_value(lua, policy)?;
+                let violations = this.check_attachment_policy(&policy).map_err(any_err)?;
+                if violations.is_empty() {
+                    Ok(mlua::Value::Nil)
+                } else {
+                    lua.to_value_with(&violations, serialize_options())
+                }
+            },
+        );
+
+        methods.add_method("check_mail_loop", move |lua, this, params: mlua::Value| {
+            let params: LoopDetectionParams = match params {
+                mlua::Value::Nil => LoopDetectionParams::default(),
+                params => from_lua_value(lua, params)?,
+            };
+            let verdict = this.check_mail_loop(&params).map_err(any_err)?;
+            lua.to_value_with(&verdict, serialize_options())
+        });
+
+        methods.add_method(
+            "get_received_chain",
+            move |lua, this, params: mlua::Value| {
+                let params: ReceivedChainParams = match params {
+                    mlua::Value::Nil => ReceivedChainParams::default(),
+                    params => from_lua_value(lua, params)?,
+                };
+                let chain = this.get_received_chain(&params).map_err(any_err)?;
+                lua.to_value_with(&chain, serialize_options())
+            },
+        );
+
+        methods.add_method("add_loop_marker", move |lua, this, params: mlua::Value| {
+            let params: LoopDetectionParams = match params {
+                mlua::Value::Nil => LoopDetectionParams::default(),
+                params => from_lua_value(lua, params)?,
+            };
+            this.add_loop_marker(&params).map_err(any_err)
+        });
+
         methods.add_async_method("save", |_, this, ()| async move {
             this.save().await.map_err(any_err)
         });
@@ -1416,6 +1782,76 @@ mod test {
     const X_HDR_CONTENT: &str =
         "X-Hello: there\r\nX-Header: value\r\nSubject: Hello\r\nFrom :Someone\r\n\r\nBody";
 
+    #[test]
+    fn annotations() {
+        let msg = new_msg_body(X_HDR_CONTENT);
+        for i in 0..MAX_ANNOTATIONS + 2 {
+            msg.add_annotation(format!("note {i}")).unwrap();
+        }
+        let texts = |msg: &Message| {
+            msg.get_annotations()
+                .unwrap()
+                .into_iter()
+                .map(|a| a.text)
+                .collect::<Vec<_>>()
+        };
+        let annotations = texts(&msg);
+        k9::assert_equal!(annotations.len(), MAX_ANNOTATIONS);
+        k9::assert_equal!(annotations[0], "note 2");
+
+        // Consecutive duplicates are collapsed
+        msg.add_annotation(format!("note {}", MAX_ANNOTATIONS + 1))
+            .unwrap();
+        k9::assert_equal!(texts(&msg), annotations);
+
+        // Annotations are retained in the spooled metadata
+        let meta = msg.get_meta_if_dirty().unwrap();
+        let loaded =
+            Message::new_from_spool(*msg.id(), serde_json::to_vec(&meta).unwrap()).unwrap();
+        k9::assert_equal!(texts(&loaded), annotations);
+    }
+
+    #[test]
+    fn file_backed_data() {
+        let id = SpoolId::new();
+        let path = std::env::temp_dir().join(format!("message-file-backed-{id}"));
+        let content = "Subject: hello\r\n\r\nbody\r\n";
+        std::fs::write(&path, content).unwrap();
+        let file = Arc::new(DataFile::new(path.clone(), content.len() as u64));
+
+        let msg = Message::new_dirty_from_file(
+            id,
+            EnvelopeAddress::parse("sender@example.com").unwrap(),
+            EnvelopeAddress::parse("recip@example.com").unwrap(),
+            serde_json::json!({}),
+            b"Received: from somewhere\r\n".to_vec(),
+            file,
+        )
+        .unwrap();
+        assert!(msg.is_data_loaded());
+
+        // Prepending a header doesn't load the data
+        msg.prepend_header(Some("X-Hello"), "there");
+        assert!(msg.msg_and_id.inner.lock().unwrap().data.is_empty());
+        let expected = format!("X-Hello: there\r\nReceived: from somewhere\r\n{content}");
+        k9::assert_equal!(msg.get_data_len(), expected.len());
+        assert!(matches!(
+            msg.get_data_if_dirty(),
+            Some(DirtyData::File { .. })
+        ));
+
+        // Accessing it does
+        k9::assert_equal!(data_as_string(&msg), expected);
+        assert!(msg.msg_and_id.inner.lock().unwrap().data_file.is_none());
+        assert!(matches!(
+            msg.get_data_if_dirty(),
+            Some(DirtyData::Memory(_))
+        ));
+
+        // The file is removed once the message no longer needs it
+        assert!(!path.exists());
+    }
+
     #[test]
     fn import_all_x_headers() {
         let msg = new_msg_body(X_HDR_CONTENT);
@@ -1625,6 +2061,53 @@ AAECAw==\r\n\
 --my-boundary--\r\n\
 \r\n";
 
+    #[test]
+    fn add_text_plain_alternative() {
+        // There is already a text part
+        let msg = new_msg_body(MIXED_CONTENT);
+        assert!(!msg.add_text_plain_alternative().unwrap());
+
+        let msg = new_msg_body(
+            "Subject: Hello\r\n\
+             Content-Type: text/html;\r\n\
+             \tcharset=\"us-ascii\"\r\n\
+             \r\n\
+             <p>rich text</p>\r\n",
+        );
+        assert!(msg.add_text_plain_alternative().unwrap());
+        let data = data_as_string(&msg);
+        let part = MimePart::parse(data.as_str()).unwrap();
+        let structure = part.simplified_structure().unwrap();
+        k9::assert_equal!(structure.text.unwrap().as_str(), "rich text\r\n");
+        k9::assert_equal!(structure.html.unwrap().as_str(), "<p>rich text</p>\r\n");
+    }
+
+    #[test]
+    fn rewrite_html_links() {
+        let msg = new_msg_body(
+            "Subject: links\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <a href=\"http://example.com/\">link</a>\r\n",
+        );
+        let count = msg
+            .rewrite_html_links(|url| Ok(Some(format!("https://track.example.com/?u={url}"))))
+            .unwrap();
+        k9::assert_equal!(count, 1);
+
+        let data = data_as_string(&msg);
+        let part = MimePart::parse(data.as_str()).unwrap();
+        let structure = part.simplified_structure().unwrap();
+        k9::assert_equal!(
+            structure.html.unwrap().as_str(),
+            "<a href=\"https://track.example.com/?u=http://example.com/\">link</a>\r\n"
+        );
+
+        // An error leaves the message unchanged
+        assert!(msg.rewrite_html_links(|_| anyhow::bail!("failed")).is_err());
+        k9::assert_equal!(data_as_string(&msg), data);
+    }
+
     #[test]
     fn append_text_html() {
         let msg = new_msg_body(MIXED_CONTENT);
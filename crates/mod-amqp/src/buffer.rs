@@ -0,0 +1,134 @@
+//! A directory of publishes that could not be delivered to the
+//! broker, so that they can be retried later on.
+//! Each publish is stored as its own json file, named so that
+//! listing the directory yields them in the order they were written.
+use prometheus::IntCounter;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+static BUFFER_WRITTEN: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "amqp_buffer_written_count",
+        "total number of failed amqp publishes that were written to a disk buffer"
+    )
+    .unwrap()
+});
+static BUFFER_REPLAYED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "amqp_buffer_replayed_count",
+        "total number of buffered amqp publishes that were successfully retried"
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone)]
+pub struct DiskBuffer {
+    path: PathBuf,
+}
+
+impl DiskBuffer {
+    pub async fn open(path: PathBuf) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&path).await.map_err(|err| {
+            anyhow::anyhow!(
+                "failed to create amqp buffer dir {}: {err:#}",
+                path.display()
+            )
+        })?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes an entry to the buffer. The entry is written to a
+    /// temporary file first, so that a partially written entry
+    /// is never picked up for retry.
+    pub async fn write<T: Serialize>(&self, entry: &T) -> anyhow::Result<PathBuf> {
+        let data = serde_json::to_vec(entry)?;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let seq = SEQUENCE.fetch_add(1, Ordering::SeqCst);
+        let name = format!("{:020}-{seq:010}.json", now.as_nanos());
+
+        let temp = self.path.join(format!(".{name}.tmp"));
+        let path = self.path.join(name);
+        tokio::fs::write(&temp, &data).await?;
+        tokio::fs::rename(&temp, &path).await?;
+
+        BUFFER_WRITTEN.inc();
+        Ok(path)
+    }
+
+    /// Returns the paths of the buffered entries, oldest first
+    pub async fn list(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        let mut dir = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map(|ext| ext == "json").unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub async fn read<T: DeserializeOwned>(&self, path: &Path) -> anyhow::Result<T> {
+        let data = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Removes an entry that has been successfully retried
+    pub async fn remove(&self, path: &Path) -> anyhow::Result<()> {
+        tokio::fs::remove_file(path).await?;
+        BUFFER_REPLAYED.inc();
+        Ok(())
+    }
+
+    /// Moves aside an entry that cannot be parsed, so that it
+    /// doesn't block the retry of the other entries
+    pub async fn quarantine(&self, path: &Path) -> anyhow::Result<()> {
+        let mut bad = path.as_os_str().to_owned();
+        bad.push(".bad");
+        tokio::fs::rename(path, bad).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn ordering() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = DiskBuffer::open(dir.path().join("buffer")).await.unwrap();
+
+        let first = buffer.write(&"first").await.unwrap();
+        let second = buffer.write(&"second").await.unwrap();
+        let third = buffer.write(&"third").await.unwrap();
+        assert_eq!(
+            buffer.list().await.unwrap(),
+            vec![first, second.clone(), third]
+        );
+
+        let entry: String = buffer.read(&second).await.unwrap();
+        assert_eq!(entry, "second");
+
+        buffer.remove(&second).await.unwrap();
+        let remaining = buffer.list().await.unwrap();
+        assert_eq!(remaining.len(), 2);
+
+        buffer.quarantine(&remaining[0]).await.unwrap();
+        let remaining = buffer.list().await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        let entry: String = buffer.read(&remaining[0]).await.unwrap();
+        assert_eq!(entry, "third");
+    }
+}
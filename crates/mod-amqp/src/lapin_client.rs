@@ -1,14 +1,41 @@
+use crate::buffer::DiskBuffer;
 use config::{any_err, from_lua_value};
-use lapin::options::BasicPublishOptions;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
 use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
 use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
 use mlua::prelude::LuaUserData;
-use mlua::{Lua, LuaSerdeExt, UserDataMethods, Value};
+use mlua::{LuaSerdeExt, UserDataMethods, Value};
+use prometheus::{IntCounter, IntGauge};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::Duration;
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
-#[derive(Deserialize, Debug)]
+static UNCONFIRMED: LazyLock<IntGauge> = LazyLock::new(|| {
+    prometheus::register_int_gauge!(
+        "amqp_publish_unconfirmed",
+        "number of amqp publishes that are awaiting confirmation from the broker"
+    )
+    .unwrap()
+});
+static RETURNED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "amqp_publish_returned_count",
+        "total number of mandatory amqp publishes that were returned as unroutable"
+    )
+    .unwrap()
+});
+static NACKED: LazyLock<IntCounter> = LazyLock::new(|| {
+    prometheus::register_int_counter!(
+        "amqp_publish_nack_count",
+        "total number of amqp publishes that were negatively acknowledged by the broker"
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 struct PublishParams {
     routing_key: String,
     payload: String,
@@ -21,9 +48,197 @@ struct PublishParams {
     properties: BasicProperties,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientOptions {
+    /// Put the channel into confirm mode, so that the broker
+    /// acknowledges each publish
+    #[serde(default)]
+    pub confirm: bool,
+    /// A directory in which to buffer publishes that could not
+    /// be delivered, so that they can be retried
+    #[serde(default)]
+    pub buffer_path: Option<PathBuf>,
+    /// How often to retry the publishes held in the buffer
+    #[serde(
+        default = "ClientOptions::default_retry_interval",
+        with = "duration_serde"
+    )]
+    pub retry_interval: Duration,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            confirm: false,
+            buffer_path: None,
+            retry_interval: Self::default_retry_interval(),
+        }
+    }
+}
+
+impl ClientOptions {
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
 struct ChannelHolder {
     channel: Channel,
     connection: Connection,
+    buffer: Option<DiskBuffer>,
+}
+
+impl ChannelHolder {
+    async fn basic_publish(&self, params: &PublishParams) -> lapin::Result<PublisherConfirm> {
+        self.channel
+            .basic_publish(
+                &params.exchange,
+                &params.routing_key,
+                params.options,
+                params.payload.as_bytes(),
+                params.properties.clone(),
+            )
+            .await
+    }
+
+    /// Waits for the broker to confirm a publish.
+    /// If it was rejected or returned, and a buffer is configured,
+    /// the publish is written to the buffer.
+    async fn resolve(
+        &self,
+        params: &PublishParams,
+        confirm: PublisherConfirm,
+    ) -> anyhow::Result<ConfirmResult> {
+        let result = wait_confirmation(confirm).await?;
+        if result.returned {
+            RETURNED.inc();
+        }
+        if matches!(result.status, ConfirmStatus::Nack) {
+            NACKED.inc();
+        }
+
+        if result.failed() {
+            if let Some(buffer) = &self.buffer {
+                buffer.write(params).await?;
+                return Ok(ConfirmResult {
+                    status: ConfirmStatus::Buffered,
+                    ..result
+                });
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Writes a publish that could not be sent to the buffer,
+    /// or returns the error if there is no buffer
+    async fn buffer_failed(
+        &self,
+        params: &PublishParams,
+        err: anyhow::Error,
+    ) -> anyhow::Result<ConfirmResult> {
+        match &self.buffer {
+            Some(buffer) => {
+                tracing::debug!("amqp publish failed, buffering for retry: {err:#}");
+                buffer.write(params).await?;
+                Ok(ConfirmResult {
+                    status: ConfirmStatus::Buffered,
+                    reply_code: None,
+                    reply_text: None,
+                    returned: false,
+                })
+            }
+            None => Err(err),
+        }
+    }
+
+    async fn publish_and_resolve(&self, params: &PublishParams) -> anyhow::Result<ConfirmResult> {
+        match self.basic_publish(params).await {
+            Ok(confirm) => self.resolve(params, confirm).await,
+            Err(err) => self.buffer_failed(params, err.into()).await,
+        }
+    }
+
+    /// Attempts to publish each of the entries in the buffer,
+    /// stopping at the first one that is not accepted by the broker
+    async fn replay_buffer(&self, buffer: &DiskBuffer) -> anyhow::Result<()> {
+        for path in buffer.list().await? {
+            if !self.channel.status().connected() {
+                anyhow::bail!("channel is not connected");
+            }
+
+            let params: PublishParams = match buffer.read(&path).await {
+                Ok(params) => params,
+                Err(err) => {
+                    tracing::error!(
+                        "failed to parse buffered amqp publish {}: {err:#}. \
+                         Moving it aside.",
+                        path.display()
+                    );
+                    buffer.quarantine(&path).await?;
+                    continue;
+                }
+            };
+
+            let confirm = self.basic_publish(&params).await?;
+            let result = wait_confirmation(confirm).await?;
+            if result.failed() {
+                anyhow::bail!(
+                    "buffered publish {} was not accepted: {:?} {:?} {:?}",
+                    path.display(),
+                    result.status,
+                    result.reply_code,
+                    result.reply_text
+                );
+            }
+
+            buffer.remove(&path).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks a publish in the unconfirmed gauge for as long as it is
+/// alive, so that the gauge remains accurate if the wait is cancelled
+struct UnconfirmedGuard;
+
+impl UnconfirmedGuard {
+    fn new() -> Self {
+        UNCONFIRMED.inc();
+        Self
+    }
+}
+
+impl Drop for UnconfirmedGuard {
+    fn drop(&mut self) {
+        UNCONFIRMED.dec();
+    }
+}
+
+async fn wait_confirmation(confirm: PublisherConfirm) -> anyhow::Result<ConfirmResult> {
+    let _guard = UnconfirmedGuard::new();
+    Ok(ConfirmResult::from_confirmation(confirm.await?))
+}
+
+/// Periodically retries the publishes held in the buffer,
+/// until the client is dropped
+fn spawn_replay(holder: Weak<ChannelHolder>, buffer: DiskBuffer, interval: Duration) {
+    kumo_server_runtime::get_main_runtime().spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(holder) = holder.upgrade() else {
+                break;
+            };
+            if let Err(err) = holder.replay_buffer(&buffer).await {
+                tracing::error!(
+                    "error retrying buffered amqp publishes from {}: {err:#}. \
+                     Will retry in {interval:?}",
+                    buffer.path().display()
+                );
+            }
+        }
+    });
 }
 
 #[derive(Clone)]
@@ -36,21 +251,27 @@ impl LuaUserData for AMQPClient {
         methods.add_async_method("publish", |lua, this, value: Value| async move {
             let params: PublishParams = from_lua_value(&lua, value)?;
 
-            let confirm = this
-                .holder
-                .channel
-                .basic_publish(
-                    &params.exchange,
-                    &params.routing_key,
-                    params.options,
-                    params.payload.as_bytes(),
-                    params.properties,
-                )
-                .await
-                .map_err(any_err)?;
+            let runtime = kumo_server_runtime::get_main_runtime();
+            let task = match this.holder.basic_publish(&params).await {
+                Ok(confirm) => {
+                    // Resolve the confirmation in the background, so
+                    // that failed publishes are buffered even if the
+                    // caller never waits for the confirmation
+                    let holder = this.holder.clone();
+                    runtime.spawn(async move { holder.resolve(&params, confirm).await })
+                }
+                Err(err) => {
+                    let result = this
+                        .holder
+                        .buffer_failed(&params, err.into())
+                        .await
+                        .map_err(any_err)?;
+                    runtime.spawn(async move { Ok(result) })
+                }
+            };
 
             Ok(Confirm {
-                confirm: Arc::new(Mutex::new(Some(confirm))),
+                task: Arc::new(Mutex::new(Some(task))),
             })
         });
 
@@ -59,28 +280,18 @@ impl LuaUserData for AMQPClient {
             |lua, this, (value, duration_millis): (Value, u64)| async move {
                 let params: PublishParams = from_lua_value(&lua, value)?;
 
-                let publish = async {
-                    let confirm = this
+                let duration = std::time::Duration::from_millis(duration_millis);
+                let result = match timeout(duration, this.holder.publish_and_resolve(&params)).await
+                {
+                    Ok(result) => result.map_err(any_err)?,
+                    Err(err) => this
                         .holder
-                        .channel
-                        .basic_publish(
-                            &params.exchange,
-                            &params.routing_key,
-                            params.options,
-                            params.payload.as_bytes(),
-                            params.properties,
-                        )
+                        .buffer_failed(&params, err.into())
                         .await
-                        .map_err(any_err)?;
-
-                    wait_confirmation(&lua, confirm).await
+                        .map_err(any_err)?,
                 };
 
-                let duration = std::time::Duration::from_millis(duration_millis);
-                timeout(duration, publish)
-                    .await
-                    .map_err(any_err)?
-                    .map_err(any_err)
+                lua.to_value_with(&result, config::serialize_options())
             },
         );
 
@@ -98,7 +309,7 @@ impl LuaUserData for AMQPClient {
 
 #[derive(Clone)]
 struct Confirm {
-    confirm: Arc<Mutex<Option<PublisherConfirm>>>,
+    task: Arc<Mutex<Option<JoinHandle<anyhow::Result<ConfirmResult>>>>>,
 }
 
 #[derive(Serialize, Debug)]
@@ -106,18 +317,8 @@ enum ConfirmStatus {
     Ack,
     Nack,
     NotRequested,
-}
-
-impl ConfirmStatus {
-    fn from_confirmation(confirm: &Confirmation) -> Self {
-        if confirm.is_ack() {
-            Self::Ack
-        } else if confirm.is_nack() {
-            Self::Nack
-        } else {
-            Self::NotRequested
-        }
-    }
+    /// The publish failed, but was written to the buffer
+    Buffered,
 }
 
 #[derive(Serialize, Debug)]
@@ -125,47 +326,62 @@ struct ConfirmResult {
     status: ConfirmStatus,
     reply_code: Option<u64>,
     reply_text: Option<String>,
+    /// The broker returned the message because it was published
+    /// as mandatory, but could not be routed to any queue
+    returned: bool,
 }
 
-async fn wait_confirmation(lua: &Lua, confirm: PublisherConfirm) -> mlua::Result<Value> {
-    let confirmation = confirm.await.map_err(any_err)?;
-    let status = ConfirmStatus::from_confirmation(&confirmation);
-    let (reply_code, reply_text) = if let Some(msg) = confirmation.take_message() {
-        (
-            Some(msg.reply_code.into()),
-            Some(msg.reply_text.as_str().to_string()),
-        )
-    } else {
-        (None, None)
-    };
+impl ConfirmResult {
+    fn from_confirmation(confirmation: Confirmation) -> Self {
+        let status = if confirmation.is_ack() {
+            ConfirmStatus::Ack
+        } else if confirmation.is_nack() {
+            ConfirmStatus::Nack
+        } else {
+            ConfirmStatus::NotRequested
+        };
 
-    let confirmation = ConfirmResult {
-        status,
-        reply_code,
-        reply_text,
-    };
+        let (reply_code, reply_text, returned) = match confirmation.take_message() {
+            Some(msg) => (
+                Some(msg.reply_code.into()),
+                Some(msg.reply_text.as_str().to_string()),
+                true,
+            ),
+            None => (None, None, false),
+        };
 
-    let result = lua.to_value_with(&confirmation, config::serialize_options())?;
-    Ok(result)
+        Self {
+            status,
+            reply_code,
+            reply_text,
+            returned,
+        }
+    }
+
+    /// Returns true if the broker did not accept the message
+    fn failed(&self) -> bool {
+        self.returned || matches!(self.status, ConfirmStatus::Nack)
+    }
 }
 
 impl LuaUserData for Confirm {
     fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
         methods.add_async_method("wait", |lua, this, _: ()| async move {
-            let confirm = this
-                .confirm
+            let task = this
+                .task
                 .lock()
                 .unwrap()
                 .take()
                 .ok_or_else(|| mlua::Error::external("confirmation already taken!?"))?;
 
-            wait_confirmation(&lua, confirm).await
+            let result = task.await.map_err(any_err)?.map_err(any_err)?;
+            lua.to_value_with(&result, config::serialize_options())
         })
     }
 }
 
-pub async fn build_client(uri: String) -> anyhow::Result<AMQPClient> {
-    let options = ConnectionProperties::default()
+pub async fn build_client(uri: String, options: ClientOptions) -> anyhow::Result<AMQPClient> {
+    let props = ConnectionProperties::default()
         .with_executor(
             tokio_executor_trait::Tokio::default()
                 .with_handle(kumo_server_runtime::get_main_runtime()),
@@ -174,7 +390,7 @@ pub async fn build_client(uri: String) -> anyhow::Result<AMQPClient> {
 
     let connect_timeout = tokio::time::Duration::from_secs(20);
 
-    let connection = timeout(connect_timeout, Connection::connect(&uri, options))
+    let connection = timeout(connect_timeout, Connection::connect(&uri, props))
         .await
         .map_err(any_err)?
         .map_err(any_err)?;
@@ -184,11 +400,27 @@ pub async fn build_client(uri: String) -> anyhow::Result<AMQPClient> {
     });
 
     let channel = connection.create_channel().await.map_err(any_err)?;
+    if options.confirm {
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await
+            .map_err(any_err)?;
+    }
+
+    let buffer = match options.buffer_path {
+        Some(path) => Some(DiskBuffer::open(path).await?),
+        None => None,
+    };
+
+    let holder = Arc::new(ChannelHolder {
+        connection,
+        channel,
+        buffer: buffer.clone(),
+    });
+
+    if let Some(buffer) = buffer {
+        spawn_replay(Arc::downgrade(&holder), buffer, options.retry_interval);
+    }
 
-    Ok(AMQPClient {
-        holder: Arc::new(ChannelHolder {
-            connection,
-            channel,
-        }),
-    })
+    Ok(AMQPClient { holder })
 }
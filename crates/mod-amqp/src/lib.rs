@@ -1,7 +1,8 @@
-use config::{any_err, get_or_create_sub_module};
+use config::{any_err, from_lua_value, get_or_create_sub_module};
 use mlua::{Lua, LuaSerdeExt};
 
 mod amqprs_client;
+mod buffer;
 mod lapin_client;
 
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
@@ -9,9 +10,17 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 
     amqp_mod.set(
         "build_client",
-        lua.create_async_function(|_, uri: String| async move {
-            lapin_client::build_client(uri).await.map_err(any_err)
-        })?,
+        lua.create_async_function(
+            |lua, (uri, options): (String, Option<mlua::Value>)| async move {
+                let options = match options {
+                    Some(options) => from_lua_value(&lua, options)?,
+                    None => lapin_client::ClientOptions::default(),
+                };
+                lapin_client::build_client(uri, options)
+                    .await
+                    .map_err(any_err)
+            },
+        )?,
     )?;
 
     amqp_mod.set(
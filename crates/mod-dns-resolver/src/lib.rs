@@ -1,12 +1,13 @@
 use anyhow::Context;
 use config::{any_err, get_or_create_sub_module, serialize_options};
+use dns_resolver::dnsbl::DnsblList;
 use dns_resolver::{
     get_resolver, resolve_a_or_aaaa, HickoryResolver, MailExchanger, TestResolver, UnboundResolver,
 };
 use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
 use hickory_resolver::{Name, TokioAsyncResolver};
 use mlua::{Lua, LuaSerdeExt};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let dns_mod = get_or_create_sub_module(lua, "dns")?;
@@ -40,6 +41,25 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    let dnsbl_mod = get_or_create_sub_module(lua, "dnsbl")?;
+
+    dnsbl_mod.set(
+        "check",
+        lua.create_async_function(|lua, (ip, lists): (String, mlua::Value)| async move {
+            // Allow passing the received_from metadata, which includes the port
+            let ip: IpAddr = match ip.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip(),
+                Err(_) => ip
+                    .parse()
+                    .with_context(|| format!("invalid IP address '{ip}'"))
+                    .map_err(any_err)?,
+            };
+            let lists: Vec<DnsblList> = lua.from_value(lists)?;
+            let result = dns_resolver::dnsbl::check(ip, &lists).await;
+            lua.to_value_with(&result, serialize_options())
+        })?,
+    )?;
+
     #[derive(serde::Deserialize, Debug)]
     #[serde(deny_unknown_fields)]
     struct DnsConfig {
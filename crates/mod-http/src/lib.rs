@@ -1,16 +1,31 @@
 use config::{any_err, from_lua_value, get_or_create_sub_module};
+use data_loader::KeySource;
 use futures_util::StreamExt;
 use mlua::prelude::LuaUserData;
 use mlua::{Lua, LuaSerdeExt, MetaMethod, UserDataMethods, Value};
+use prometheus::HistogramVec;
 use reqwest::header::HeaderMap;
-use reqwest::{Body, Client, ClientBuilder, RequestBuilder, Response, StatusCode, Url};
+use reqwest::{
+    Body, Certificate, Client, ClientBuilder, Identity, Method, NoProxy, Proxy, RequestBuilder,
+    Response, StatusCode, Url,
+};
 use serde::Deserialize;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex as TokioMutex;
 use tokio_tungstenite::tungstenite::Message;
 
+static REQUEST_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "http_client_request_latency",
+        "how long it takes for an http request made from lua to receive \
+         the response headers, per attempt",
+        &["host"]
+    )
+    .unwrap()
+});
+
 // Client ----
 
 #[derive(Deserialize, Debug, Clone)]
@@ -21,13 +36,133 @@ struct ClientOptions {
     connection_verbose: Option<bool>,
     #[serde(default, with = "duration_serde")]
     pool_idle_timeout: Option<Duration>,
+    #[serde(default)]
+    pool_max_idle_per_host: Option<usize>,
     #[serde(default, with = "duration_serde")]
     timeout: Option<Duration>,
+    #[serde(default, with = "duration_serde")]
+    connect_timeout: Option<Duration>,
+    #[serde(default)]
+    proxy: Option<String>,
+    #[serde(default)]
+    no_proxy: Option<String>,
+    #[serde(default)]
+    client_certificate: Option<KeySource>,
+    #[serde(default)]
+    client_private_key: Option<KeySource>,
+    #[serde(default)]
+    ca_certificate: Option<KeySource>,
+    #[serde(default)]
+    retry: Option<RetryOptions>,
+}
+
+/// How to retry idempotent requests that fail due to
+/// a transient error
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct RetryOptions {
+    /// The maximum number of attempts, including the first
+    #[serde(default = "RetryOptions::default_max_attempts")]
+    max_attempts: usize,
+    /// How long to wait before the first retry; the delay
+    /// doubles after each subsequent attempt
+    #[serde(
+        default = "RetryOptions::default_initial_backoff",
+        with = "duration_serde"
+    )]
+    initial_backoff: Duration,
+    /// The upper bound on the delay between attempts
+    #[serde(default = "RetryOptions::default_max_backoff", with = "duration_serde")]
+    max_backoff: Duration,
+}
+
+impl RetryOptions {
+    fn default_max_attempts() -> usize {
+        3
+    }
+
+    fn default_initial_backoff() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    fn default_max_backoff() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    /// Returns true if the outcome of an attempt is worth retrying
+    fn should_retry(result: &reqwest::Result<Response>) -> bool {
+        match result {
+            Ok(response) => matches!(
+                response.status(),
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        }
+    }
+}
+
+impl ClientOptions {
+    async fn build(self) -> anyhow::Result<Client> {
+        let mut builder = ClientBuilder::new().timeout(
+            self.timeout
+                .unwrap_or_else(|| std::time::Duration::from_secs(60)),
+        );
+
+        if let Some(verbose) = self.connection_verbose {
+            builder = builder.connection_verbose(verbose);
+        }
+
+        if let Some(idle) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(idle);
+        }
+
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(user_agent) = self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        if let Some(proxy) = self.proxy {
+            let proxy = Proxy::all(proxy)?.no_proxy(self.no_proxy.and_then(NoProxy::from_string));
+            builder = builder.proxy(proxy);
+        }
+
+        match (self.client_certificate, self.client_private_key) {
+            (Some(cert), Some(key)) => {
+                let mut pem = cert.get().await?;
+                pem.push(b'\n');
+                pem.append(&mut key.get().await?);
+                builder = builder.identity(Identity::from_pem(&pem)?);
+            }
+            (None, None) => {}
+            _ => anyhow::bail!(
+                "client_certificate and client_private_key must be specified together"
+            ),
+        }
+
+        if let Some(ca) = self.ca_certificate {
+            for cert in Certificate::from_pem_bundle(&ca.get().await?)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(builder.build()?)
+    }
 }
 
 #[derive(Clone)]
 struct ClientWrapper {
     client: Arc<Mutex<Option<Arc<Client>>>>,
+    retry: Option<RetryOptions>,
 }
 
 impl ClientWrapper {
@@ -38,22 +173,30 @@ impl ClientWrapper {
             .map(Arc::clone)
             .ok_or_else(|| mlua::Error::external("client was closed"))
     }
+
+    fn request(&self, method: Method, url: String) -> mlua::Result<RequestWrapper> {
+        let host = Url::parse(&url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_default();
+        // Only idempotent requests can be safely retried
+        let retry = if method.is_idempotent() {
+            self.retry.clone()
+        } else {
+            None
+        };
+        let builder = self.get_client()?.request(method, url);
+        Ok(RequestWrapper::new(builder, host, retry))
+    }
 }
 
 impl LuaUserData for ClientWrapper {
     fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method("get", |_, this, url: String| {
-            let builder = this.get_client()?.get(url);
-            Ok(RequestWrapper::new(builder))
-        });
+        methods.add_method("get", |_, this, url: String| this.request(Method::GET, url));
         methods.add_method("post", |_, this, url: String| {
-            let builder = this.get_client()?.post(url);
-            Ok(RequestWrapper::new(builder))
-        });
-        methods.add_method("put", |_, this, url: String| {
-            let builder = this.get_client()?.put(url);
-            Ok(RequestWrapper::new(builder))
+            this.request(Method::POST, url)
         });
+        methods.add_method("put", |_, this, url: String| this.request(Method::PUT, url));
         methods.add_method("close", |_, this, _: ()| {
             this.client.lock().unwrap().take();
             Ok(())
@@ -66,12 +209,16 @@ impl LuaUserData for ClientWrapper {
 #[derive(Clone)]
 struct RequestWrapper {
     builder: Arc<Mutex<Option<RequestBuilder>>>,
+    host: String,
+    retry: Option<RetryOptions>,
 }
 
 impl RequestWrapper {
-    fn new(builder: RequestBuilder) -> Self {
+    fn new(builder: RequestBuilder, host: String, retry: Option<RetryOptions>) -> Self {
         Self {
             builder: Arc::new(Mutex::new(Some(builder))),
+            host,
+            retry,
         }
     }
 
@@ -93,14 +240,42 @@ impl RequestWrapper {
     }
 
     async fn send(&self) -> mlua::Result<Response> {
-        let b = self
+        let mut b = self
             .builder
             .lock()
             .unwrap()
             .take()
             .ok_or_else(|| mlua::Error::external("broken request builder"))?;
 
-        b.send().await.map_err(any_err)
+        let latency = REQUEST_LATENCY.with_label_values(&[&self.host]);
+        let mut attempt = 1;
+        let mut backoff = self
+            .retry
+            .as_ref()
+            .map(|retry| retry.initial_backoff)
+            .unwrap_or_default();
+
+        loop {
+            // Keep a copy of the request in case we need to retry it
+            let next = match &self.retry {
+                Some(retry) if attempt < retry.max_attempts => b.try_clone(),
+                _ => None,
+            };
+
+            let start = Instant::now();
+            let result = b.send().await;
+            latency.observe(start.elapsed().as_secs_f64());
+
+            match (next, &self.retry) {
+                (Some(next), Some(retry)) if RetryOptions::should_retry(&result) => {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(retry.max_backoff);
+                    attempt += 1;
+                    b = next;
+                }
+                _ => return result.map_err(any_err),
+            }
+        }
     }
 }
 
@@ -424,29 +599,13 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 
     http_mod.set(
         "build_client",
-        lua.create_function(|lua, options: Value| {
-            let options: ClientOptions = from_lua_value(lua, options)?;
-            let mut builder = ClientBuilder::new().timeout(
-                options
-                    .timeout
-                    .unwrap_or_else(|| std::time::Duration::from_secs(60)),
-            );
-
-            if let Some(verbose) = options.connection_verbose {
-                builder = builder.connection_verbose(verbose);
-            }
-
-            if let Some(idle) = options.pool_idle_timeout {
-                builder = builder.pool_idle_timeout(idle);
-            }
-
-            if let Some(user_agent) = options.user_agent {
-                builder = builder.user_agent(user_agent);
-            }
-
-            let client = builder.build().map_err(any_err)?;
+        lua.create_async_function(|lua, options: Value| async move {
+            let options: ClientOptions = from_lua_value(&lua, options)?;
+            let retry = options.retry.clone();
+            let client = options.build().await.map_err(any_err)?;
             Ok(ClientWrapper {
                 client: Arc::new(Mutex::new(Some(Arc::new(client)))),
+                retry,
             })
         })?,
     )?;
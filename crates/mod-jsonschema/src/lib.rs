@@ -0,0 +1,156 @@
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use jsonschema::Validator;
+use lruttl::LruCacheWithTtl;
+use mlua::{Lua, LuaSerdeExt, UserData, UserDataMethods, Value};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+/// Compiled validators, keyed by the json text of their schema, so
+/// that event handlers can call compile without paying the cost of
+/// compiling the same schema each time
+static VALIDATORS: LazyLock<LruCacheWithTtl<String, Arc<Validator>>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("jsonschema_validators", 128));
+
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    /// The JSON pointer to the part of the instance that is invalid
+    pub path: String,
+    /// The JSON pointer to the part of the schema that was violated
+    pub schema_path: String,
+    pub message: String,
+}
+
+pub fn compile(schema: &JsonValue) -> anyhow::Result<Arc<Validator>> {
+    let key = serde_json::to_string(schema)?;
+    if let Some(validator) = VALIDATORS.get(&key) {
+        return Ok(validator);
+    }
+
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|err| anyhow::anyhow!("invalid schema: {err} at {}", err.instance_path))?;
+    Ok(VALIDATORS.insert(key, Arc::new(validator), Instant::now() + CACHE_TTL))
+}
+
+pub fn validate(validator: &Validator, instance: &JsonValue) -> Vec<SchemaError> {
+    validator
+        .iter_errors(instance)
+        .map(|err| SchemaError {
+            path: err.instance_path.to_string(),
+            schema_path: err.schema_path.to_string(),
+            message: err.to_string(),
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+struct SchemaValidator(Arc<Validator>);
+
+impl UserData for SchemaValidator {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_valid", |lua, this, value: Value| {
+            let instance: JsonValue = from_lua_value(lua, value)?;
+            Ok(this.0.is_valid(&instance))
+        });
+
+        methods.add_method("validate", |lua, this, value: Value| {
+            let instance: JsonValue = from_lua_value(lua, value)?;
+            lua.to_value(&validate(&this.0, &instance))
+        });
+
+        methods.add_method("assert_valid", |lua, this, value: Value| {
+            let instance: JsonValue = from_lua_value(lua, value)?;
+            let errors = validate(&this.0, &instance);
+            if errors.is_empty() {
+                return Ok(());
+            }
+            let errors: Vec<String> = errors
+                .into_iter()
+                .map(|err| {
+                    let path = if err.path.is_empty() {
+                        "/"
+                    } else {
+                        err.path.as_str()
+                    };
+                    format!("{path}: {}", err.message)
+                })
+                .collect();
+            Err(mlua::Error::external(format!(
+                "validation failed: {}",
+                errors.join(", ")
+            )))
+        });
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let jsonschema_mod = get_or_create_sub_module(lua, "jsonschema")?;
+
+    jsonschema_mod.set(
+        "compile",
+        lua.create_function(|lua, schema: Value| {
+            let schema: JsonValue = match schema {
+                // Allow passing the schema as json text, such as
+                // when it has been loaded from a file
+                Value::String(s) => serde_json::from_slice(&s.as_bytes()).map_err(any_err)?,
+                schema => from_lua_value(lua, schema)?,
+            };
+            Ok(SchemaValidator(compile(&schema).map_err(any_err)?))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn error_paths() {
+        let validator = compile(&json!({
+            "type": "object",
+            "required": ["recipients"],
+            "properties": {
+                "recipients": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["email"],
+                        "properties": {
+                            "email": {"type": "string"}
+                        }
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(validate(
+            &validator,
+            &json!({"recipients": [{"email": "a@example.com"}]})
+        )
+        .is_empty());
+
+        let errors = validate(
+            &validator,
+            &json!({"recipients": [{"email": "a@example.com"}, {"email": 42}]}),
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "/recipients/1/email");
+        assert_eq!(
+            errors[0].schema_path,
+            "/properties/recipients/items/properties/email/type"
+        );
+
+        let errors = validate(&validator, &json!({}));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "");
+
+        assert!(compile(&json!({"type": "not-a-type"})).is_err());
+    }
+}
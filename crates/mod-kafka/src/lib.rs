@@ -1,13 +1,13 @@
-use config::{any_err, get_or_create_sub_module};
+use config::{any_err, get_or_create_sub_module, load_config, CallbackSignature};
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
 use mlua::prelude::LuaUserData;
-use mlua::{Lua, LuaSerdeExt, UserDataMethods, Value};
+use mlua::{FromLuaMulti, Lua, LuaSerdeExt, MultiValue, UserDataMethods, Value};
 use rdkafka::message::{Header, OwnedHeaders};
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::util::Timeout;
 use rdkafka::ClientConfig;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
@@ -55,47 +55,156 @@ struct Record {
     #[serde(default)]
     #[serde(with = "duration_serde")]
     timeout: Option<Duration>,
+
+    /// Optional name of an event to trigger with the delivery
+    /// report of a record that is sent via `enqueue`
+    #[serde(default)]
+    delivery_report_event: Option<String>,
+}
+
+impl Record {
+    /// Accepts either a single table describing the record, or
+    /// the positional `topic, key, payload, headers` parameters
+    fn from_lua_params(lua: &Lua, params: MultiValue) -> mlua::Result<Self> {
+        if let Some(Value::Table(_)) = params.front() {
+            let value: Value = FromLuaMulti::from_lua_multi(params, lua)?;
+            return lua.from_value(value);
+        }
+
+        let (topic, key, payload, headers): (
+            String,
+            Option<String>,
+            Option<String>,
+            Option<HashMap<String, String>>,
+        ) = FromLuaMulti::from_lua_multi(params, lua)?;
+
+        Ok(Self {
+            topic,
+            partition: None,
+            payload,
+            key,
+            headers: headers.unwrap_or_default(),
+            timeout: None,
+            delivery_report_event: None,
+        })
+    }
+
+    fn headers(&self) -> Option<OwnedHeaders> {
+        if self.headers.is_empty() {
+            return None;
+        }
+        let mut headers = OwnedHeaders::new();
+        for (key, v) in &self.headers {
+            headers = headers.insert(Header {
+                key,
+                value: Some(v),
+            });
+        }
+        Some(headers)
+    }
+
+    fn future_record(&self) -> FutureRecord<String, String> {
+        FutureRecord {
+            topic: &self.topic,
+            partition: self.partition,
+            payload: self.payload.as_ref(),
+            key: self.key.as_ref(),
+            headers: self.headers(),
+            timestamp: None,
+        }
+    }
+
+    fn timeout(&self) -> Timeout {
+        Timeout::After(self.timeout.unwrap_or(Duration::from_secs(60)))
+    }
+}
+
+/// The outcome of delivering a record that was sent via `enqueue`
+#[derive(Serialize, Debug)]
+struct DeliveryReport {
+    topic: String,
+    key: Option<String>,
+    partition: Option<i32>,
+    offset: Option<i64>,
+    error: Option<String>,
+}
+
+impl DeliveryReport {
+    async fn dispatch(self, event_name: Option<String>) {
+        let Some(event_name) = event_name else {
+            if let Some(error) = &self.error {
+                tracing::error!(
+                    "failed to deliver record with key {:?} to kafka topic {}: {error}",
+                    self.key,
+                    self.topic
+                );
+            }
+            return;
+        };
+
+        let result = async {
+            let mut config = load_config().await?;
+            let sig = CallbackSignature::<Value, ()>::new(event_name.clone());
+            config.convert_args_and_call_callback(&sig, &self).await
+        };
+        if let Err(err) = result.await {
+            tracing::error!("error while dispatching {event_name} for {self:?}: {err:#}");
+        }
+    }
 }
 
 impl LuaUserData for Producer {
     fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
-        methods.add_async_method("send", |lua, this, value: Value| async move {
-            let record: Record = lua.from_value(value)?;
-
-            let headers = if record.headers.is_empty() {
-                None
-            } else {
-                let mut headers = OwnedHeaders::new();
-                for (key, v) in &record.headers {
-                    headers = headers.insert(Header {
-                        key,
-                        value: Some(v),
-                    });
-                }
-                Some(headers)
-            };
-
-            let future_record = FutureRecord {
-                topic: &record.topic,
-                partition: record.partition,
-                payload: record.payload.as_ref(),
-                key: record.key.as_ref(),
-                headers,
-                timestamp: None,
-            };
+        methods.add_async_method("send", |lua, this, params: MultiValue| async move {
+            let record = Record::from_lua_params(&lua, params)?;
 
             let (partition, offset) = this
                 .get_producer()?
-                .send(
-                    future_record,
-                    Timeout::After(record.timeout.unwrap_or(Duration::from_secs(60))),
-                )
+                .send(record.future_record(), record.timeout())
                 .await
                 .map_err(|(code, _msg)| any_err(code))?;
 
             Ok((partition, offset))
         });
 
+        methods.add_method("enqueue", |lua, this, params: MultiValue| {
+            let record = Record::from_lua_params(lua, params)?;
+
+            // This fails immediately if the producer queue is full,
+            // rather than waiting for space to become available
+            let delivery = this
+                .get_producer()?
+                .send_result(record.future_record())
+                .map_err(|(code, _record)| any_err(code))?;
+
+            tokio::spawn(async move {
+                let mut report = DeliveryReport {
+                    topic: record.topic,
+                    key: record.key,
+                    partition: None,
+                    offset: None,
+                    error: None,
+                };
+                match delivery.await {
+                    Ok(Ok((partition, offset))) => {
+                        report.partition.replace(partition);
+                        report.offset.replace(offset);
+                    }
+                    Ok(Err((error, _msg))) => {
+                        report.error.replace(format!("{error:#}"));
+                    }
+                    Err(_canceled) => {
+                        report
+                            .error
+                            .replace("producer was closed before delivery".to_string());
+                    }
+                }
+                report.dispatch(record.delivery_report_event).await;
+            });
+
+            Ok(())
+        });
+
         methods.add_async_method("send_batch", |lua, this, values: Vec<Value>| async move {
             let mut tasks = FuturesOrdered::new();
             let producer = this.get_producer()?;
@@ -103,34 +212,11 @@ impl LuaUserData for Producer {
             for value in values {
                 let record: Record = lua.from_value(value)?;
 
-                let headers = if record.headers.is_empty() {
-                    None
-                } else {
-                    let mut headers = OwnedHeaders::new();
-                    for (key, v) in &record.headers {
-                        headers = headers.insert(Header {
-                            key,
-                            value: Some(v),
-                        });
-                    }
-                    Some(headers)
-                };
-
                 let producer = producer.clone();
 
                 tasks.push_back(tokio::spawn(async move {
                     producer
-                        .send(
-                            FutureRecord {
-                                topic: &record.topic,
-                                partition: record.partition,
-                                payload: record.payload.as_ref(),
-                                key: record.key.as_ref(),
-                                headers,
-                                timestamp: None,
-                            },
-                            Timeout::After(record.timeout.unwrap_or(Duration::from_secs(60))),
-                        )
+                        .send(record.future_record(), record.timeout())
                         .await
                 }));
             }
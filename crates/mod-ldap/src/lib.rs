@@ -0,0 +1,365 @@
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use data_loader::KeySource;
+use deadpool::managed::{Manager, Metrics, Pool, RecycleError, RecycleResult};
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, SearchOptions};
+use mlua::{Lua, LuaSerdeExt, UserData, UserDataMethods, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+static POOLS: LazyLock<Mutex<HashMap<LdapParams, Pool<LdapManager>>>> =
+    LazyLock::new(Mutex::default);
+
+/// The result code that indicates that a search returned more
+/// entries than its size limit permits
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+
+#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LdapParams {
+    /// The server to connect to, such as `ldaps://ldap.example.com`
+    pub url: String,
+    /// The DN to bind as after connecting. If omitted, the
+    /// connection is anonymous.
+    #[serde(default)]
+    pub bind_dn: Option<String>,
+    #[serde(default)]
+    pub bind_password: Option<KeySource>,
+    /// Upgrade an `ldap://` connection to TLS via StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// Don't verify the server certificate
+    #[serde(default)]
+    pub no_tls_verify: bool,
+    #[serde(
+        default = "LdapParams::default_connect_timeout",
+        with = "duration_serde"
+    )]
+    pub connect_timeout: Duration,
+    /// How long to wait for the server to respond to an operation
+    #[serde(
+        default = "LdapParams::default_operation_timeout",
+        with = "duration_serde"
+    )]
+    pub operation_timeout: Duration,
+    /// How long to wait for a connection from the pool
+    #[serde(default = "LdapParams::default_wait_timeout", with = "duration_serde")]
+    pub wait_timeout: Duration,
+    /// The maximum number of connections
+    #[serde(default = "LdapParams::default_pool_size")]
+    pub pool_size: usize,
+}
+
+impl LdapParams {
+    fn default_connect_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_operation_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_wait_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn default_pool_size() -> usize {
+        8
+    }
+
+    /// Establishes a new connection, without binding
+    async fn connect(&self) -> anyhow::Result<Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.connect_timeout)
+            .set_starttls(self.starttls)
+            .set_no_tls_verify(self.no_tls_verify);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+
+        // The connection needs to be driven in the background
+        // in order for operations on ldap to make progress
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            if let Err(err) = conn.drive().await {
+                tracing::debug!("LDAP connection to {url} failed: {err:#}");
+            }
+        });
+
+        Ok(ldap)
+    }
+
+    async fn simple_bind(&self, ldap: &mut Ldap, dn: &str, password: &str) -> anyhow::Result<()> {
+        ldap.with_timeout(self.operation_timeout)
+            .simple_bind(dn, password)
+            .await?
+            .success()?;
+        Ok(())
+    }
+
+    fn get_pool(&self) -> anyhow::Result<Pool<LdapManager>> {
+        let mut pools = POOLS.lock().unwrap();
+        if let Some(pool) = pools.get(self) {
+            return Ok(pool.clone());
+        }
+
+        let pool = Pool::builder(LdapManager(self.clone()))
+            .runtime(deadpool::Runtime::Tokio1)
+            .create_timeout(Some(self.connect_timeout))
+            .recycle_timeout(Some(self.operation_timeout))
+            .wait_timeout(Some(self.wait_timeout))
+            .max_size(self.pool_size)
+            .build()?;
+
+        pools.insert(self.clone(), pool.clone());
+        Ok(pool)
+    }
+}
+
+pub struct LdapManager(LdapParams);
+
+impl Manager for LdapManager {
+    type Type = Ldap;
+    type Error = anyhow::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let mut ldap = self.0.connect().await?;
+        if let Some(dn) = &self.0.bind_dn {
+            let password = match &self.0.bind_password {
+                Some(source) => String::from_utf8(source.get().await?)?,
+                None => String::new(),
+            };
+            self.0.simple_bind(&mut ldap, dn, &password).await?;
+        }
+        Ok(ldap)
+    }
+
+    async fn recycle(
+        &self,
+        ldap: &mut Self::Type,
+        _metrics: &Metrics,
+    ) -> RecycleResult<Self::Error> {
+        if ldap.is_closed() {
+            return Err(RecycleError::message("connection is closed"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchScope {
+    /// Only the entry named by the base DN
+    Base,
+    /// The entries immediately below the base DN
+    One,
+    /// The base DN and all entries below it
+    #[default]
+    Sub,
+}
+
+impl From<SearchScope> for Scope {
+    fn from(scope: SearchScope) -> Scope {
+        match scope {
+            SearchScope::Base => Scope::Base,
+            SearchScope::One => Scope::OneLevel,
+            SearchScope::Sub => Scope::Subtree,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SearchParams {
+    pub base: String,
+    #[serde(default)]
+    pub scope: SearchScope,
+    #[serde(default = "SearchParams::default_filter")]
+    pub filter: String,
+    /// The attributes to return. If empty, all user
+    /// attributes are returned.
+    #[serde(default)]
+    pub attributes: Vec<String>,
+    /// The maximum number of entries to return
+    #[serde(default)]
+    pub limit: Option<i32>,
+}
+
+impl SearchParams {
+    fn default_filter() -> String {
+        "(objectClass=*)".to_string()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub dn: String,
+    /// The textual values of each of the attributes of the entry
+    pub attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl From<SearchEntry> for Entry {
+    fn from(entry: SearchEntry) -> Entry {
+        Entry {
+            dn: entry.dn,
+            attributes: entry.attrs.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LdapConnection(Arc<Mutex<Option<Arc<LdapParams>>>>);
+
+impl LdapConnection {
+    fn get_params(&self) -> anyhow::Result<Arc<LdapParams>> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow::anyhow!("connection was closed"))
+    }
+
+    async fn search(&self, search: &SearchParams) -> anyhow::Result<Vec<Entry>> {
+        let params = self.get_params()?;
+        let pool = params.get_pool()?;
+        let mut ldap = pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))?;
+
+        let mut options = SearchOptions::new();
+        if let Some(limit) = search.limit {
+            options = options.sizelimit(limit);
+        }
+
+        let result = ldap
+            .with_timeout(params.operation_timeout)
+            .with_search_options(options)
+            .search(
+                &search.base,
+                search.scope.into(),
+                &search.filter,
+                &search.attributes,
+            )
+            .await?;
+
+        // Exceeding the size limit still produces the entries
+        // up to that limit, so it is not an error
+        let entries = if result.1.rc == SIZE_LIMIT_EXCEEDED {
+            result.0
+        } else {
+            result.success()?.0
+        };
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| SearchEntry::construct(entry).into())
+            .collect())
+    }
+
+    /// Returns the attributes of the entry named by dn,
+    /// or None if there is no such entry
+    async fn get_attributes(
+        &self,
+        dn: String,
+        attributes: Vec<String>,
+    ) -> anyhow::Result<Option<BTreeMap<String, Vec<String>>>> {
+        let search = SearchParams {
+            base: dn,
+            scope: SearchScope::Base,
+            filter: SearchParams::default_filter(),
+            attributes,
+            limit: None,
+        };
+        match self.search(&search).await {
+            Ok(entries) => Ok(entries.into_iter().next().map(|entry| entry.attributes)),
+            Err(err) if is_no_such_object(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Verifies credentials by binding as dn on a dedicated connection,
+    /// so that the identity of the pooled connections is unaffected.
+    /// Returns false if the server rejected the credentials.
+    async fn bind(&self, dn: &str, password: &str) -> anyhow::Result<bool> {
+        // An empty password would result in an unauthenticated
+        // bind, which succeeds without verifying anything
+        if password.is_empty() {
+            return Ok(false);
+        }
+
+        let params = self.get_params()?;
+        let mut ldap = params.connect().await?;
+        let result = params.simple_bind(&mut ldap, dn, password).await;
+        ldap.unbind().await.ok();
+        match result {
+            Ok(()) => Ok(true),
+            Err(err) if is_invalid_credentials(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn result_code(err: &anyhow::Error) -> Option<u32> {
+    match err.downcast_ref::<ldap3::LdapError>() {
+        Some(ldap3::LdapError::LdapResult { result }) => Some(result.rc),
+        _ => None,
+    }
+}
+
+fn is_no_such_object(err: &anyhow::Error) -> bool {
+    result_code(err) == Some(32)
+}
+
+fn is_invalid_credentials(err: &anyhow::Error) -> bool {
+    result_code(err) == Some(49)
+}
+
+impl UserData for LdapConnection {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("search", |lua, this, params: Value| async move {
+            let params: SearchParams = from_lua_value(&lua, params)?;
+            let entries = this.search(&params).await.map_err(any_err)?;
+            lua.to_value_with(&entries, config::serialize_options())
+        });
+
+        methods.add_async_method(
+            "get_attributes",
+            |lua, this, (dn, attributes): (String, Option<Vec<String>>)| async move {
+                let attributes = this
+                    .get_attributes(dn, attributes.unwrap_or_default())
+                    .await
+                    .map_err(any_err)?;
+                lua.to_value_with(&attributes, config::serialize_options())
+            },
+        );
+
+        methods.add_async_method(
+            "bind",
+            |_lua, this, (dn, password): (String, String)| async move {
+                this.bind(&dn, &password).await.map_err(any_err)
+            },
+        );
+
+        methods.add_method("close", |_lua, this, _: ()| {
+            this.0.lock().unwrap().take();
+            Ok(())
+        });
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let ldap_mod = get_or_create_sub_module(lua, "ldap")?;
+
+    ldap_mod.set(
+        "open",
+        lua.create_function(move |lua, params: Value| {
+            let params: LdapParams = from_lua_value(lua, params)?;
+            Ok(LdapConnection(Arc::new(Mutex::new(Some(Arc::new(params))))))
+        })?,
+    )?;
+
+    ldap_mod.set(
+        "escape",
+        lua.create_function(move |_lua, value: String| Ok(ldap3::ldap_escape(value).to_string()))?,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,456 @@
+//! A memcached client that speaks the meta text protocol, and
+//! which distributes keys across a set of servers using consistent
+//! hashing, so that adding or removing a server only remaps the
+//! keys that hashed to that server.
+use config::{any_err, from_lua_value, get_or_create_sub_module};
+use data_encoding::BASE64;
+use deadpool::managed::{Manager, Metrics, Object, Pool, RecycleError, RecycleResult};
+use mlua::{Lua, UserData, UserDataMethods, Value};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+static CLUSTERS: LazyLock<Mutex<HashMap<MemcachedParams, Arc<Cluster>>>> =
+    LazyLock::new(Mutex::default);
+
+/// The number of points that each server occupies on the hash ring
+const POINTS_PER_SERVER: usize = 160;
+
+/// The maximum length of a key, after it has been base64 encoded
+const MAX_KEY_LEN: usize = 250;
+
+/// memcached interprets expiration times that are longer than
+/// this as absolute unix timestamps rather than as durations
+const MAX_RELATIVE_TTL: Duration = Duration::from_secs(30 * 86400);
+
+#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MemcachedParams {
+    /// The `host:port` addresses of the servers
+    pub servers: Vec<String>,
+    /// The maximum number of connections to each server
+    #[serde(default = "MemcachedParams::default_pool_size")]
+    pub pool_size: usize,
+    #[serde(
+        default = "MemcachedParams::default_connect_timeout",
+        with = "duration_serde"
+    )]
+    pub connect_timeout: Duration,
+    /// How long to wait for the response to a command
+    #[serde(default = "MemcachedParams::default_timeout", with = "duration_serde")]
+    pub timeout: Duration,
+    /// How long to wait for a connection from the pool
+    #[serde(
+        default = "MemcachedParams::default_wait_timeout",
+        with = "duration_serde"
+    )]
+    pub wait_timeout: Duration,
+}
+
+impl MemcachedParams {
+    fn default_pool_size() -> usize {
+        8
+    }
+
+    fn default_connect_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_wait_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn get_cluster(&self) -> anyhow::Result<Arc<Cluster>> {
+        let mut clusters = CLUSTERS.lock().unwrap();
+        if let Some(cluster) = clusters.get(self) {
+            return Ok(cluster.clone());
+        }
+
+        let cluster = Arc::new(Cluster::new(self)?);
+        clusters.insert(self.clone(), cluster.clone());
+        Ok(cluster)
+    }
+}
+
+/// 64-bit FNV-1a, which is used rather than the std hasher
+/// because the placement of keys must be the same in every
+/// process that shares the servers
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Maps keys to servers
+#[derive(Debug)]
+struct HashRing {
+    /// (point, server index), sorted by point
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    fn new(servers: &[String]) -> Self {
+        let mut points = Vec::with_capacity(servers.len() * POINTS_PER_SERVER);
+        for (idx, server) in servers.iter().enumerate() {
+            for point in 0..POINTS_PER_SERVER {
+                points.push((fnv1a(format!("{server}-{point}").as_bytes()), idx));
+            }
+        }
+        points.sort();
+        Self { points }
+    }
+
+    /// Returns the index of the server that holds key
+    fn server_for_key(&self, key: &[u8]) -> usize {
+        let hash = fnv1a(key);
+        let idx = self.points.partition_point(|(point, _)| *point < hash);
+        // Wrap around to the start of the ring
+        self.points[idx % self.points.len()].1
+    }
+}
+
+struct Cluster {
+    ring: HashRing,
+    pools: Vec<Pool<ServerManager>>,
+    timeout: Duration,
+}
+
+impl Cluster {
+    fn new(params: &MemcachedParams) -> anyhow::Result<Self> {
+        anyhow::ensure!(!params.servers.is_empty(), "servers must not be empty");
+        let mut pools = vec![];
+        for server in &params.servers {
+            pools.push(
+                Pool::builder(ServerManager {
+                    address: server.clone(),
+                    connect_timeout: params.connect_timeout,
+                })
+                .runtime(deadpool::Runtime::Tokio1)
+                .create_timeout(Some(params.connect_timeout))
+                .wait_timeout(Some(params.wait_timeout))
+                .max_size(params.pool_size)
+                .build()?,
+            );
+        }
+        Ok(Self {
+            ring: HashRing::new(&params.servers),
+            pools,
+            timeout: params.timeout,
+        })
+    }
+
+    /// Returns a connection to the server that holds key,
+    /// along with the encoded form of the key
+    async fn connection(&self, key: &str) -> anyhow::Result<(Object<ServerManager>, String)> {
+        let encoded = BASE64.encode(key.as_bytes());
+        anyhow::ensure!(
+            encoded.len() <= MAX_KEY_LEN,
+            "memcached key `{key}` is too long"
+        );
+
+        let pool = &self.pools[self.ring.server_for_key(key.as_bytes())];
+        let mut conn = pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))?;
+
+        // If the command fails or times out part way through, the
+        // state of the connection is unknown, so it remains marked
+        // as broken and is discarded rather than being reused.
+        // Each command clears this once it has read its response.
+        conn.broken = true;
+        Ok((conn, encoded))
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let (mut conn, key) = self.connection(key).await?;
+        let value = tokio::time::timeout(self.timeout, conn.get(&key)).await??;
+        conn.broken = false;
+        Ok(value)
+    }
+
+    async fn set(&self, key: &str, value: &[u8], exptime: u64) -> anyhow::Result<()> {
+        let (mut conn, key) = self.connection(key).await?;
+        tokio::time::timeout(self.timeout, conn.set(&key, value, exptime)).await??;
+        conn.broken = false;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<bool> {
+        let (mut conn, key) = self.connection(key).await?;
+        let deleted = tokio::time::timeout(self.timeout, conn.delete(&key)).await??;
+        conn.broken = false;
+        Ok(deleted)
+    }
+}
+
+struct ServerManager {
+    address: String,
+    connect_timeout: Duration,
+}
+
+impl Manager for ServerManager {
+    type Type = ServerConnection;
+    type Error = anyhow::Error;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let stream =
+            tokio::time::timeout(self.connect_timeout, TcpStream::connect(&self.address)).await??;
+        stream.set_nodelay(true)?;
+        Ok(ServerConnection {
+            stream: BufReader::new(stream),
+            broken: false,
+        })
+    }
+
+    async fn recycle(
+        &self,
+        conn: &mut Self::Type,
+        _metrics: &Metrics,
+    ) -> RecycleResult<Self::Error> {
+        if conn.broken {
+            return Err(RecycleError::message("connection is broken"));
+        }
+        Ok(())
+    }
+}
+
+struct ServerConnection {
+    stream: BufReader<TcpStream>,
+    broken: bool,
+}
+
+impl ServerConnection {
+    async fn command(&mut self, command: &[u8]) -> anyhow::Result<String> {
+        self.stream.write_all(command).await?;
+        self.stream.flush().await?;
+        read_status_line(&mut self.stream).await
+    }
+
+    async fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let status = self.command(format!("mg {key} b v\r\n").as_bytes()).await?;
+        read_value(&mut self.stream, &status).await
+    }
+
+    async fn set(&mut self, key: &str, value: &[u8], ttl: u64) -> anyhow::Result<()> {
+        let mut command = format!("ms {key} {} b T{ttl}\r\n", value.len()).into_bytes();
+        command.extend_from_slice(value);
+        command.extend_from_slice(b"\r\n");
+        match self.command(&command).await?.as_str() {
+            "HD" => Ok(()),
+            status => anyhow::bail!("unexpected response to ms: {status}"),
+        }
+    }
+
+    async fn delete(&mut self, key: &str) -> anyhow::Result<bool> {
+        match self
+            .command(format!("md {key} b\r\n").as_bytes())
+            .await?
+            .as_str()
+        {
+            "HD" => Ok(true),
+            "NF" => Ok(false),
+            status => anyhow::bail!("unexpected response to md: {status}"),
+        }
+    }
+}
+
+/// Reads a response line, returning it without its line ending,
+/// or an error if the server reported an error
+async fn read_status_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("connection closed by server");
+    }
+    let line = line.trim_end_matches(['\r', '\n']).to_string();
+    if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+        anyhow::bail!("memcached error: {line}");
+    }
+    Ok(line)
+}
+
+/// Reads the value that follows the status line of an mg response
+async fn read_value<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    status: &str,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if status == "EN" {
+        return Ok(None);
+    }
+    let size: usize = match status.strip_prefix("VA ") {
+        Some(rest) => rest
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|err| anyhow::anyhow!("invalid mg response `{status}`: {err:#}"))?,
+        None => anyhow::bail!("unexpected response to mg: {status}"),
+    };
+
+    // The value is followed by \r\n
+    let mut value = vec![0u8; size + 2];
+    reader.read_exact(&mut value).await?;
+    anyhow::ensure!(value.ends_with(b"\r\n"), "value is not terminated by CRLF");
+    value.truncate(size);
+    Ok(Some(value))
+}
+
+/// Converts a ttl to the form expected by memcached, where 0
+/// means that the item doesn't expire, and values longer than
+/// 30 days are absolute unix timestamps
+fn ttl_to_exptime(ttl: Option<Duration>) -> u64 {
+    match ttl {
+        None => 0,
+        Some(ttl) if ttl > MAX_RELATIVE_TTL => (SystemTime::now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        // Round up so that a sub-second ttl doesn't become 0,
+        // which would mean that the item never expires
+        Some(ttl) => ttl.as_secs().max(1),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Ttl(#[serde(with = "duration_serde")] Duration);
+
+#[derive(Clone)]
+struct MemcachedClient(Arc<Mutex<Option<Arc<Cluster>>>>);
+
+impl MemcachedClient {
+    fn get_cluster(&self) -> anyhow::Result<Arc<Cluster>> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or_else(|| anyhow::anyhow!("client was closed"))
+    }
+}
+
+impl UserData for MemcachedClient {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("get", |lua, this, key: String| async move {
+            let cluster = this.get_cluster().map_err(any_err)?;
+            let value = cluster.get(&key).await.map_err(any_err)?;
+            match value {
+                Some(value) => Ok(Value::String(lua.create_string(&value)?)),
+                None => Ok(Value::Nil),
+            }
+        });
+
+        methods.add_async_method(
+            "set",
+            |lua, this, (key, value, ttl): (String, mlua::String, Option<Value>)| async move {
+                let ttl = match ttl {
+                    Some(ttl) => Some(from_lua_value::<Ttl>(&lua, ttl)?.0),
+                    None => None,
+                };
+                let exptime = ttl_to_exptime(ttl);
+                let value = value.as_bytes().to_vec();
+                let cluster = this.get_cluster().map_err(any_err)?;
+                cluster.set(&key, &value, exptime).await.map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("delete", |_lua, this, key: String| async move {
+            let cluster = this.get_cluster().map_err(any_err)?;
+            cluster.delete(&key).await.map_err(any_err)
+        });
+
+        methods.add_method("close", |_lua, this, _: ()| {
+            this.0.lock().unwrap().take();
+            Ok(())
+        });
+    }
+}
+
+pub fn register(lua: &Lua) -> anyhow::Result<()> {
+    let memcached_mod = get_or_create_sub_module(lua, "memcached")?;
+
+    memcached_mod.set(
+        "open",
+        lua.create_function(move |lua, params: Value| {
+            let params: MemcachedParams = from_lua_value(lua, params)?;
+            let cluster = params.get_cluster().map_err(any_err)?;
+            Ok(MemcachedClient(Arc::new(Mutex::new(Some(cluster)))))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring() {
+        let servers: Vec<String> = ["a:11211", "b:11211", "c:11211"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let ring = HashRing::new(&servers);
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{i}")).collect();
+        let placement: Vec<usize> = keys
+            .iter()
+            .map(|key| ring.server_for_key(key.as_bytes()))
+            .collect();
+
+        // Every server gets a reasonable share of the keys
+        for idx in 0..servers.len() {
+            let count = placement.iter().filter(|&&p| p == idx).count();
+            assert!(count > 200, "server {idx} has only {count} keys");
+        }
+
+        // Removing a server only moves the keys that it held
+        let ring2 = HashRing::new(&servers[0..2]);
+        for (key, &before) in keys.iter().zip(placement.iter()) {
+            if before != 2 {
+                assert_eq!(ring2.server_for_key(key.as_bytes()), before);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_responses() {
+        let mut reader: &[u8] = b"VA 5 b\r\nhello\r\n";
+        let status = read_status_line(&mut reader).await.unwrap();
+        assert_eq!(
+            read_value(&mut reader, &status).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+
+        let mut reader: &[u8] = b"EN\r\n";
+        let status = read_status_line(&mut reader).await.unwrap();
+        assert_eq!(read_value(&mut reader, &status).await.unwrap(), None);
+
+        let mut reader: &[u8] = b"SERVER_ERROR out of memory\r\n";
+        assert!(read_status_line(&mut reader).await.is_err());
+
+        let mut reader: &[u8] = b"VA 5\r\nhello";
+        let status = read_status_line(&mut reader).await.unwrap();
+        assert!(read_value(&mut reader, &status).await.is_err());
+    }
+
+    #[test]
+    fn ttl() {
+        assert_eq!(ttl_to_exptime(None), 0);
+        assert_eq!(ttl_to_exptime(Some(Duration::from_millis(100))), 1);
+        assert_eq!(ttl_to_exptime(Some(Duration::from_secs(60))), 60);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(ttl_to_exptime(Some(Duration::from_secs(60 * 86400))) >= now + 60 * 86400);
+    }
+}
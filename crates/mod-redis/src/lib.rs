@@ -1,7 +1,9 @@
+use crate::streams::{consume_stream, ConsumeStreamParams, ReadGroupParams};
 use anyhow::Context;
 use config::{any_err, from_lua_value, get_or_create_module};
 use deadpool::managed::{Manager, Metrics, Pool, RecycleError, RecycleResult};
-use mlua::{Lua, MultiValue, UserData, UserDataMethods, Value};
+use mlua::{Lua, LuaSerdeExt, MultiValue, UserData, UserDataMethods, Value, Variadic};
+use prometheus::{IntCounterVec, IntGaugeVec};
 use redis::aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig};
 use redis::cluster::ClusterClient;
 use redis::cluster_async::ClusterConnection;
@@ -9,28 +11,80 @@ pub use redis::{
     cmd, Cmd, FromRedisValue, RedisError, Script, ScriptInvocation, Value as RedisValue,
 };
 use redis::{
-    Client, ConnectionInfo, IntoConnectionInfo, Pipeline, RedisFuture, RedisWrite, ToRedisArgs,
+    Client, ConnectionInfo, ErrorKind, IntoConnectionInfo, Pipeline, RedisFuture, RedisWrite,
+    ToRedisArgs,
 };
 use serde::Deserialize;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, LazyLock, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+pub mod streams;
 pub mod test;
 
 static POOLS: LazyLock<Mutex<HashMap<RedisConnKey, Pool<ClientManager>>>> =
     LazyLock::new(Mutex::default);
 
+/// The source of the scripts loaded via script_load, keyed by their
+/// sha1 hash, so that evalsha can reload them when the server
+/// reports that it doesn't know the script
+static SCRIPTS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Mutex::default);
+
+static POOL_SIZE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "redis_pool_size",
+        "number of connections currently held by a redis connection pool",
+        &["pool"]
+    )
+    .unwrap()
+});
+static POOL_AVAILABLE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "redis_pool_available",
+        "number of idle connections in a redis connection pool",
+        &["pool"]
+    )
+    .unwrap()
+});
+static POOL_WAITING: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "redis_pool_waiting",
+        "number of tasks waiting for a connection from a redis connection pool",
+        &["pool"]
+    )
+    .unwrap()
+});
+static POOL_MAX_SIZE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "redis_pool_max_size",
+        "maximum number of connections in a redis connection pool",
+        &["pool"]
+    )
+    .unwrap()
+});
+static HEALTH_CHECK_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "redis_pool_health_check_errors",
+        "number of times that a redis connection pool health check \
+         failed to obtain a working connection",
+        &["pool"]
+    )
+    .unwrap()
+});
+
 pub struct ClientManager(ClientWrapper);
 
 impl Manager for ClientManager {
-    type Type = ConnectionWrapper;
+    type Type = PooledConnection;
     type Error = anyhow::Error;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
         let c = self.0.connect().await?;
-        Ok(c)
+        Ok(PooledConnection {
+            conn: c,
+            last_used: Instant::now(),
+        })
     }
 
     async fn recycle(
@@ -68,6 +122,64 @@ impl RedisConnection {
         let mut conn = pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))?;
         Ok(script.invoke_async(&mut *conn).await?)
     }
+
+    /// Sends all of the commands in pipe in a single round trip,
+    /// returning the result of each command
+    pub async fn query_pipeline(&self, pipe: Pipeline) -> anyhow::Result<Vec<RedisValue>> {
+        let pool = self.0.get_pool()?;
+        let mut conn = pool.get().await.map_err(|err| anyhow::anyhow!("{err:#}"))?;
+        Ok(pipe.query_async(&mut *conn).await?)
+    }
+
+    /// Loads a lua script into the server, returning its sha1 hash.
+    /// The source is retained so that `evalsha` can transparently
+    /// reload it if the server has since lost it.
+    pub async fn script_load(&self, source: String) -> anyhow::Result<String> {
+        let mut load = cmd("SCRIPT");
+        load.arg("LOAD").arg(&source);
+        let sha = String::from_redis_value(&self.query(load).await?)?;
+        SCRIPTS.lock().unwrap().insert(sha.clone(), source);
+        Ok(sha)
+    }
+
+    /// Runs the script identified by sha. If the server doesn't know
+    /// the script, but it was previously loaded via `script_load`,
+    /// it is loaded again and the call is retried.
+    pub async fn evalsha(
+        &self,
+        sha: &str,
+        keys: &[JsonValue],
+        args: &[JsonValue],
+    ) -> anyhow::Result<RedisValue> {
+        let mut eval = cmd("EVALSHA");
+        eval.arg(sha).arg(keys.len());
+        for k in keys {
+            eval.arg(RedisJsonValue(k));
+        }
+        for a in args {
+            eval.arg(RedisJsonValue(a));
+        }
+
+        match self.query(eval.clone()).await {
+            Err(err) if is_no_script(&err) => {
+                let source = SCRIPTS.lock().unwrap().get(sha).cloned();
+                let Some(source) = source else {
+                    return Err(err);
+                };
+                let mut load = cmd("SCRIPT");
+                load.arg("LOAD").arg(source);
+                self.query(load).await?;
+                self.query(eval).await
+            }
+            result => result,
+        }
+    }
+}
+
+fn is_no_script(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<RedisError>()
+        .map(|err| err.kind() == ErrorKind::NoScriptError)
+        .unwrap_or(false)
 }
 
 fn redis_value_to_lua(lua: &Lua, value: RedisValue) -> mlua::Result<Value> {
@@ -156,6 +268,68 @@ impl UserData for RedisConnection {
             let result = this.query(cmd).await.map_err(any_err)?;
             redis_value_to_lua(&lua, result)
         });
+
+        methods.add_async_method("pipeline", |lua, this, commands: Value| async move {
+            let commands: Vec<Vec<JsonValue>> = from_lua_value(&lua, commands)?;
+            let mut pipe = redis::pipe();
+            for args in commands {
+                pipe.add_command(build_cmd(args).map_err(any_err)?);
+            }
+            let results = this.query_pipeline(pipe).await.map_err(any_err)?;
+            let array = lua.create_table()?;
+            for result in results {
+                array.push(redis_value_to_lua(&lua, result)?)?;
+            }
+            Ok(array)
+        });
+
+        methods.add_async_method(
+            "xadd",
+            |lua, this, (stream, fields, max_len): (String, Value, Option<usize>)| async move {
+                let fields: BTreeMap<String, JsonValue> = from_lua_value(&lua, fields)?;
+                this.xadd(&stream, &fields, max_len).await.map_err(any_err)
+            },
+        );
+
+        methods.add_async_method(
+            "xgroup_create",
+            |_lua, this, (stream, group): (String, String)| async move {
+                this.xgroup_create(&stream, &group).await.map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("xreadgroup", |lua, this, params: Value| async move {
+            let params: ReadGroupParams = from_lua_value(&lua, params)?;
+            let entries = this.xreadgroup(&params).await.map_err(any_err)?;
+            lua.to_value(&entries)
+        });
+
+        methods.add_async_method(
+            "xack",
+            |_lua, this, (stream, group, ids): (String, String, Variadic<String>)| async move {
+                this.xack(&stream, &group, &ids).await.map_err(any_err)
+            },
+        );
+
+        methods.add_async_method("script_load", |_lua, this, source: String| async move {
+            this.script_load(source).await.map_err(any_err)
+        });
+
+        methods.add_async_method(
+            "evalsha",
+            |lua, this, (sha, keys, args): (String, Option<Value>, Option<Value>)| async move {
+                let keys: Vec<JsonValue> = match keys {
+                    Some(keys) => from_lua_value(&lua, keys)?,
+                    None => vec![],
+                };
+                let args: Vec<JsonValue> = match args {
+                    Some(args) => from_lua_value(&lua, args)?,
+                    None => vec![],
+                };
+                let result = this.evalsha(&sha, &keys, &args).await.map_err(any_err)?;
+                redis_value_to_lua(&lua, result)
+            },
+        );
     }
 }
 
@@ -238,6 +412,15 @@ pub struct RedisConnKey {
     pub wait_timeout: Option<Duration>,
     #[serde(default, with = "duration_serde")]
     pub response_timeout: Option<Duration>,
+    /// Idle connections that have not been used for this long
+    /// are closed by the health check
+    #[serde(default, with = "duration_serde")]
+    pub idle_timeout: Option<Duration>,
+    /// How often to check the health of the idle connections
+    /// in the pool and to update the pool metrics.
+    /// Default is 30 seconds
+    #[serde(default, with = "duration_serde")]
+    pub health_check_interval: Option<Duration>,
 }
 
 pub enum ClientWrapper {
@@ -296,6 +479,42 @@ impl ConnectionLike for ConnectionWrapper {
     }
 }
 
+/// A connection held by the pool, which tracks when it was last
+/// used to issue a command, so that idle connections can be closed.
+/// The PING issued by the pool when recycling the connection doesn't
+/// count as a use.
+pub struct PooledConnection {
+    conn: ConnectionWrapper,
+    last_used: Instant,
+}
+
+impl PooledConnection {
+    pub async fn ping(&mut self) -> anyhow::Result<()> {
+        self.conn.ping().await
+    }
+}
+
+impl ConnectionLike for PooledConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, RedisValue> {
+        self.last_used = Instant::now();
+        self.conn.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a crate::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<RedisValue>> {
+        self.last_used = Instant::now();
+        self.conn.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
 impl RedisConnKey {
     pub fn build_client(&self) -> anyhow::Result<ClientWrapper> {
         let cluster = self
@@ -375,15 +594,95 @@ impl RedisConnKey {
 
         pools.insert(self.clone(), pool.clone());
 
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(maintain_pool(
+                self.pool_label(),
+                pool.clone(),
+                self.idle_timeout,
+                self.health_check_interval
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+            ));
+        }
+
         Ok(pool)
     }
 
+    /// Returns the label used for the metrics of the pool.
+    /// Only the addresses of the nodes are included, so that
+    /// credentials embedded in the urls are not revealed.
+    fn pool_label(&self) -> String {
+        let nodes = match &self.node {
+            NodeSpec::Single(node) => vec![node.as_str()],
+            NodeSpec::Cluster(nodes) => nodes.iter().map(|n| n.as_str()).collect(),
+        };
+        nodes
+            .into_iter()
+            .map(|node| match node.into_connection_info() {
+                Ok(info) => info.addr.to_string(),
+                Err(_) => "?".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
     pub fn open(&self) -> anyhow::Result<RedisConnection> {
         self.build_client()?;
         Ok(RedisConnection(Arc::new(self.clone())))
     }
 }
 
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Periodically closes connections that have been idle for longer
+/// than idle_timeout, checks that the remaining idle connections are
+/// still working, and updates the pool metrics.
+/// Obtaining a connection from the pool PINGs it, and the pool replaces
+/// any connection that fails the PING with a new connection, so by
+/// obtaining each of the idle connections, broken connections are
+/// re-established before they are needed for a query.
+async fn maintain_pool(
+    label: String,
+    pool: Pool<ClientManager>,
+    idle_timeout: Option<Duration>,
+    interval: Duration,
+) {
+    let size = POOL_SIZE.with_label_values(&[&label]);
+    let available = POOL_AVAILABLE.with_label_values(&[&label]);
+    let waiting = POOL_WAITING.with_label_values(&[&label]);
+    let max_size = POOL_MAX_SIZE.with_label_values(&[&label]);
+    let errors = HEALTH_CHECK_ERRORS.with_label_values(&[&label]);
+
+    loop {
+        if let Some(idle_timeout) = idle_timeout {
+            pool.retain(|conn, _| conn.last_used.elapsed() < idle_timeout);
+        }
+
+        let idle = pool.status().available;
+        let mut checked = Vec::with_capacity(idle);
+        for _ in 0..idle {
+            match tokio::time::timeout(interval, pool.get()).await {
+                Ok(Ok(conn)) => checked.push(conn),
+                Ok(Err(_)) => {
+                    errors.inc();
+                    break;
+                }
+                // The connections were taken by other tasks in the
+                // meantime, which is a health check of its own
+                Err(_) => break,
+            }
+        }
+        drop(checked);
+
+        let status = pool.status();
+        size.set(status.size as i64);
+        available.set(status.available as i64);
+        waiting.set(status.waiting as i64);
+        max_size.set(status.max_size as i64);
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
 pub fn register(lua: &Lua) -> anyhow::Result<()> {
     let redis_mod = get_or_create_module(lua, "redis")?;
 
@@ -395,5 +694,75 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    redis_mod.set(
+        "consume_stream",
+        lua.create_function(move |lua, (params, event_name): (Value, String)| {
+            let params: ConsumeStreamParams = from_lua_value(lua, params)?;
+            if !config::is_validating() {
+                consume_stream(params, event_name).map_err(any_err)?;
+            }
+            Ok(())
+        })?,
+    )?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::RedisServer;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn pipeline_and_scripts() {
+        if !RedisServer::is_available() {
+            return;
+        }
+
+        let redis = RedisServer::spawn("").await.unwrap();
+        let conn = redis.connection().await.unwrap();
+
+        let mut pipe = redis::pipe();
+        pipe.add_command(build_cmd(vec![json!("INCRBY"), json!("counter"), json!(2)]).unwrap());
+        pipe.add_command(build_cmd(vec![json!("INCR"), json!("counter")]).unwrap());
+        pipe.add_command(build_cmd(vec![json!("GET"), json!("counter")]).unwrap());
+        let results = conn.query_pipeline(pipe).await.unwrap();
+        assert_eq!(
+            results,
+            vec![
+                RedisValue::Int(2),
+                RedisValue::Int(3),
+                RedisValue::BulkString(b"3".to_vec())
+            ]
+        );
+
+        let sha = conn
+            .script_load("return redis.call('INCRBY', KEYS[1], ARGV[1])".to_string())
+            .await
+            .unwrap();
+        let result = conn
+            .evalsha(&sha, &[json!("counter")], &[json!(10)])
+            .await
+            .unwrap();
+        assert_eq!(result, RedisValue::Int(13));
+
+        // Simulate the server losing the script; evalsha should
+        // transparently load it again
+        conn.query(cmd("SCRIPT").arg("FLUSH").clone())
+            .await
+            .unwrap();
+        let result = conn
+            .evalsha(&sha, &[json!("counter")], &[json!(1)])
+            .await
+            .unwrap();
+        assert_eq!(result, RedisValue::Int(14));
+
+        // An unknown script is reported as an error
+        let err = conn
+            .evalsha("0000000000000000000000000000000000000000", &[], &[])
+            .await
+            .unwrap_err();
+        assert!(is_no_script(&err), "{err:#}");
+    }
+}
@@ -0,0 +1,364 @@
+//! Helpers for working with redis streams and consumer groups,
+//! along with a task that dispatches the entries of a stream
+//! to a lua event handler.
+use crate::{cmd, RedisConnKey, RedisConnection, RedisJsonValue, RedisValue};
+use config::{load_config, CallbackSignature};
+use mlua::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::task::LocalSet;
+
+/// An entry read from a stream
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamEntry {
+    pub id: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ReadGroupParams {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    /// The maximum number of entries to return
+    #[serde(default = "ReadGroupParams::default_count")]
+    pub count: usize,
+    /// How long to wait for entries to become available.
+    /// If omitted, don't wait.
+    #[serde(default, with = "duration_serde")]
+    pub block: Option<Duration>,
+    /// Which entries to read; `>` reads entries that have never been
+    /// delivered to any consumer in the group, while `0` re-reads the
+    /// entries that were delivered to this consumer but that have
+    /// not yet been acknowledged.
+    #[serde(default = "ReadGroupParams::default_start_id")]
+    pub start_id: String,
+}
+
+impl ReadGroupParams {
+    fn default_count() -> usize {
+        10
+    }
+
+    fn default_start_id() -> String {
+        ">".to_string()
+    }
+}
+
+fn value_to_string(value: &RedisValue) -> anyhow::Result<String> {
+    match value {
+        RedisValue::BulkString(bytes) => Ok(String::from_utf8_lossy(bytes).to_string()),
+        RedisValue::SimpleString(s) => Ok(s.to_string()),
+        RedisValue::VerbatimString { text, .. } => Ok(text.to_string()),
+        RedisValue::Int(i) => Ok(i.to_string()),
+        wat => anyhow::bail!("unexpected {wat:?} in stream reply"),
+    }
+}
+
+/// Returns the elements of an array, or the flattened key/value
+/// pairs of a map
+fn flatten(value: RedisValue) -> anyhow::Result<Vec<RedisValue>> {
+    match value {
+        RedisValue::Nil => Ok(vec![]),
+        RedisValue::Array(values) => Ok(values),
+        RedisValue::Map(pairs) => Ok(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect()),
+        wat => anyhow::bail!("unexpected {wat:?} in stream reply"),
+    }
+}
+
+/// Parses a list of `[id, [field, value, ...]]` entries
+fn parse_entries(value: RedisValue) -> anyhow::Result<Vec<StreamEntry>> {
+    let mut entries = vec![];
+    for entry in flatten(value)? {
+        let mut parts = flatten(entry)?.into_iter();
+        let id = match parts.next() {
+            Some(id) => value_to_string(&id)?,
+            None => anyhow::bail!("stream entry has no id"),
+        };
+        let mut fields = BTreeMap::new();
+        // The fields are nil if the entry was deleted after it
+        // was delivered but before it was acknowledged
+        if let Some(values) = parts.next() {
+            let mut values = flatten(values)?.into_iter();
+            while let (Some(k), Some(v)) = (values.next(), values.next()) {
+                fields.insert(value_to_string(&k)?, value_to_string(&v)?);
+            }
+        }
+        entries.push(StreamEntry { id, fields });
+    }
+    Ok(entries)
+}
+
+/// Parses a list (or map, with RESP3) of stream name to entries
+fn parse_read_reply(value: RedisValue) -> anyhow::Result<Vec<StreamEntry>> {
+    let mut entries = vec![];
+    let mut streams = flatten(value)?.into_iter();
+    while let (Some(_name), Some(stream_entries)) = (streams.next(), streams.next()) {
+        entries.append(&mut parse_entries(stream_entries)?);
+    }
+    Ok(entries)
+}
+
+fn parse_nested_read_reply(value: RedisValue) -> anyhow::Result<Vec<StreamEntry>> {
+    // With RESP2, each stream is a nested [name, entries] array,
+    // whereas with RESP3 the reply is a map of name => entries
+    match value {
+        RedisValue::Array(streams) => {
+            let mut entries = vec![];
+            for stream in streams {
+                entries.append(&mut parse_read_reply(stream)?);
+            }
+            Ok(entries)
+        }
+        value => parse_read_reply(value),
+    }
+}
+
+impl RedisConnection {
+    /// Appends an entry to a stream, returning its id.
+    /// If max_len is specified, the stream is approximately
+    /// trimmed to that many entries.
+    pub async fn xadd(
+        &self,
+        stream: &str,
+        fields: &BTreeMap<String, JsonValue>,
+        max_len: Option<usize>,
+    ) -> anyhow::Result<String> {
+        let mut xadd = cmd("XADD");
+        xadd.arg(stream);
+        if let Some(max_len) = max_len {
+            xadd.arg("MAXLEN").arg("~").arg(max_len);
+        }
+        xadd.arg("*");
+        for (k, v) in fields {
+            xadd.arg(k).arg(RedisJsonValue(v));
+        }
+        value_to_string(&self.query(xadd).await?)
+    }
+
+    /// Creates the consumer group for a stream, creating the stream
+    /// if it doesn't already exist. It is not an error for the
+    /// group to already exist.
+    pub async fn xgroup_create(&self, stream: &str, group: &str) -> anyhow::Result<()> {
+        let mut create = cmd("XGROUP");
+        create
+            .arg("CREATE")
+            .arg(stream)
+            .arg(group)
+            .arg("$")
+            .arg("MKSTREAM");
+        match self.query(create).await {
+            Ok(_) => Ok(()),
+            Err(err) if format!("{err:#}").contains("BUSYGROUP") => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn xreadgroup(&self, params: &ReadGroupParams) -> anyhow::Result<Vec<StreamEntry>> {
+        let mut read = cmd("XREADGROUP");
+        read.arg("GROUP")
+            .arg(&params.group)
+            .arg(&params.consumer)
+            .arg("COUNT")
+            .arg(params.count);
+        if let Some(block) = params.block {
+            read.arg("BLOCK").arg(block.as_millis() as u64);
+        }
+        read.arg("STREAMS")
+            .arg(&params.stream)
+            .arg(&params.start_id);
+        parse_nested_read_reply(self.query(read).await?)
+    }
+
+    /// Acknowledges the entries with the specified ids, returning
+    /// the number of entries that were acknowledged
+    pub async fn xack(&self, stream: &str, group: &str, ids: &[String]) -> anyhow::Result<i64> {
+        let mut ack = cmd("XACK");
+        ack.arg(stream).arg(group);
+        for id in ids {
+            ack.arg(id);
+        }
+        match self.query(ack).await? {
+            RedisValue::Int(n) => Ok(n),
+            wat => anyhow::bail!("unexpected {wat:?} in XACK reply"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ConsumeStreamParams {
+    /// How to connect to redis
+    pub redis: RedisConnKey,
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    /// The maximum number of entries to read at once
+    #[serde(default = "ReadGroupParams::default_count")]
+    pub count: usize,
+    /// How long each read waits for new entries
+    #[serde(
+        default = "ConsumeStreamParams::default_block",
+        with = "duration_serde"
+    )]
+    pub block: Duration,
+    /// How long to wait before trying again after an error
+    /// communicating with redis
+    #[serde(
+        default = "ConsumeStreamParams::default_retry_interval",
+        with = "duration_serde"
+    )]
+    pub retry_interval: Duration,
+}
+
+impl ConsumeStreamParams {
+    fn default_block() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn default_retry_interval() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn read_params(&self, start_id: &str) -> ReadGroupParams {
+        ReadGroupParams {
+            stream: self.stream.clone(),
+            group: self.group.clone(),
+            consumer: self.consumer.clone(),
+            count: self.count,
+            block: Some(self.block),
+            start_id: start_id.to_string(),
+        }
+    }
+
+    async fn run(&self, event_name: &str) -> anyhow::Result<()> {
+        let conn = self.redis.open()?;
+        conn.xgroup_create(&self.stream, &self.group).await?;
+
+        let sig = CallbackSignature::<Value, ()>::new(event_name.to_string());
+
+        // Start by processing any entries that were delivered to this
+        // consumer, but that were not acknowledged; perhaps we were
+        // restarted part way through processing them.
+        // Once there are no more of those, read new entries.
+        let mut pending_after = Some("0".to_string());
+
+        loop {
+            let start_id = pending_after.as_deref().unwrap_or(">");
+            let entries = conn.xreadgroup(&self.read_params(start_id)).await?;
+            if pending_after.is_some() {
+                pending_after = entries.last().map(|entry| entry.id.clone());
+            }
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut config = load_config().await?;
+            for entry in entries {
+                match config.convert_args_and_call_callback(&sig, &entry).await {
+                    Ok(()) => {
+                        conn.xack(&self.stream, &self.group, &[entry.id]).await?;
+                    }
+                    Err(err) => {
+                        // Leave the entry pending; it will be retried
+                        // when the consumer is next started
+                        tracing::error!(
+                            "{event_name}: error processing entry {} of stream {}: {err:#}",
+                            entry.id,
+                            self.stream
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Spawns a thread that reads entries from a stream as a member of
+/// a consumer group, and passes each of them to the event handler
+/// registered for event_name.
+pub fn consume_stream(params: ConsumeStreamParams, event_name: String) -> anyhow::Result<()> {
+    std::thread::Builder::new()
+        .name(format!("redis-stream-{event_name}"))
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .enable_time()
+                .build()
+                .unwrap();
+            let local_set = LocalSet::new();
+            local_set.block_on(&runtime, async move {
+                loop {
+                    if let Err(err) = params.run(&event_name).await {
+                        tracing::error!(
+                            "{event_name}: error consuming stream {}: {err:#}. \
+                             Will retry in {:?}",
+                            params.stream,
+                            params.retry_interval
+                        );
+                    }
+                    tokio::time::sleep(params.retry_interval).await;
+                }
+            });
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bulk(s: &str) -> RedisValue {
+        RedisValue::BulkString(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn parse() {
+        let entry = |id: &str, k: &str, v: &str| {
+            RedisValue::Array(vec![bulk(id), RedisValue::Array(vec![bulk(k), bulk(v)])])
+        };
+        let expected = vec![
+            StreamEntry {
+                id: "1-0".to_string(),
+                fields: [("a".to_string(), "1".to_string())].into_iter().collect(),
+            },
+            StreamEntry {
+                id: "2-0".to_string(),
+                fields: [("b".to_string(), "2".to_string())].into_iter().collect(),
+            },
+        ];
+
+        // RESP2
+        let reply = RedisValue::Array(vec![RedisValue::Array(vec![
+            bulk("stream"),
+            RedisValue::Array(vec![entry("1-0", "a", "1"), entry("2-0", "b", "2")]),
+        ])]);
+        assert_eq!(parse_nested_read_reply(reply).unwrap(), expected);
+
+        // RESP3
+        let reply = RedisValue::Map(vec![(
+            bulk("stream"),
+            RedisValue::Array(vec![entry("1-0", "a", "1"), entry("2-0", "b", "2")]),
+        )]);
+        assert_eq!(parse_nested_read_reply(reply).unwrap(), expected);
+
+        // Timed out waiting for entries
+        assert!(parse_nested_read_reply(RedisValue::Nil).unwrap().is_empty());
+
+        // A deleted, but pending, entry
+        let reply = RedisValue::Array(vec![RedisValue::Array(vec![
+            bulk("stream"),
+            RedisValue::Array(vec![RedisValue::Array(vec![bulk("3-0"), RedisValue::Nil])]),
+        ])]);
+        assert_eq!(
+            parse_nested_read_reply(reply).unwrap(),
+            vec![StreamEntry {
+                id: "3-0".to_string(),
+                fields: BTreeMap::new()
+            }]
+        );
+    }
+}
@@ -123,6 +123,8 @@ impl RedisServer {
             recycle_timeout: None,
             wait_timeout: None,
             response_timeout: None,
+            idle_timeout: None,
+            health_check_interval: None,
         };
         key.open()
     }
@@ -228,6 +230,8 @@ impl RedisCluster {
             recycle_timeout: None,
             wait_timeout: None,
             response_timeout: None,
+            idle_timeout: None,
+            health_check_interval: None,
         };
         key.open()
     }
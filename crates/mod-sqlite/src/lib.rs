@@ -1,10 +1,13 @@
 use anyhow::Context;
 use config::{any_err, from_lua_value, get_or_create_module};
 use mlua::{Lua, LuaSerdeExt, MultiValue, UserData, UserDataMethods, Value};
+use pool::{Pool, PoolParams};
 use serde_json::{Map, Value as JsonValue};
-use sqlite::{Connection, ConnectionThreadSafe, ParameterIndex, State, Statement, Type};
+use sqlite::{Connection, ParameterIndex, State, Statement, Type};
 use std::sync::{Arc, Mutex};
 
+mod pool;
+
 fn bind_param<I: ParameterIndex>(
     stmt: &mut Statement,
     index: I,
@@ -49,7 +52,7 @@ fn params_to_json(lua: &Lua, mut params: MultiValue) -> mlua::Result<JsonValue>
     }
 }
 
-fn bind_params(stmt: &mut Statement, params: &JsonValue) -> anyhow::Result<()> {
+pub(crate) fn bind_params(stmt: &mut Statement, params: &JsonValue) -> anyhow::Result<()> {
     match params {
         JsonValue::Object(obj) => {
             for (name, value) in obj.iter() {
@@ -91,11 +94,61 @@ fn get_column(stmt: &Statement, index: usize) -> anyhow::Result<JsonValue> {
     }
 }
 
+/// Executes a prepared statement, returning either its rows or,
+/// if it cannot return any rows, the number of affected rows
+fn execute_statement(
+    conn: &Connection,
+    stmt: &mut Statement,
+    sql: &str,
+    params: &JsonValue,
+) -> anyhow::Result<JsonValue> {
+    bind_params(stmt, params)
+        .with_context(|| format!("bind parameters {params:?} in query `{sql}'"))?;
+
+    let state = stmt.next()?;
+    if state == State::Done && stmt.column_count() == 0 {
+        // Query cannot return any rows, so we'll return
+        // the affected row count
+        return Ok(conn.change_count().into());
+    }
+
+    let mut table = vec![];
+    // Query has rows. Decide whether we are returning a simple
+    // array of single column results, or an array of objects
+    let col_count = stmt.column_count();
+    if col_count == 1 {
+        loop {
+            let value = get_column(stmt, 0)?;
+            table.push(value);
+
+            if stmt.next()? == State::Done {
+                break;
+            }
+        }
+    } else {
+        loop {
+            let mut obj = Map::new();
+            let col_names = stmt.column_names();
+            for i in 0..col_count {
+                let value = get_column(stmt, i)?;
+                obj.insert(col_names[i].to_string(), value);
+            }
+            table.push(JsonValue::Object(obj));
+
+            if stmt.next()? == State::Done {
+                break;
+            }
+        }
+    }
+
+    Ok(JsonValue::Array(table))
+}
+
 #[derive(Clone)]
-struct Conn(Arc<Mutex<Option<Arc<ConnectionThreadSafe>>>>);
+struct Conn(Arc<Mutex<Option<Arc<Pool>>>>);
 
 impl Conn {
-    fn get_conn(&self) -> anyhow::Result<Arc<ConnectionThreadSafe>> {
+    fn get_pool(&self) -> anyhow::Result<Arc<Pool>> {
         self.0
             .lock()
             .unwrap()
@@ -108,48 +161,7 @@ impl Conn {
     // function, so we push the work over to this blocking function
     // via spawn_blocking.
     fn execute(&self, sql: String, params: JsonValue) -> anyhow::Result<JsonValue> {
-        let conn = self.get_conn()?;
-        let mut stmt = conn.prepare(&sql)?;
-        bind_params(&mut stmt, &params)
-            .with_context(|| format!("bind parameters {params:?} in query `{sql}'"))?;
-
-        let state = stmt.next()?;
-        if state == State::Done && stmt.column_count() == 0 {
-            // Query cannot return any rows, so we'll return
-            // the affected row count
-            return Ok(conn.change_count().into());
-        }
-
-        let mut table = vec![];
-        // Query has rows. Decide whether we are returning a simple
-        // array of single column results, or an array of objects
-        let col_count = stmt.column_count();
-        if col_count == 1 {
-            loop {
-                let value = get_column(&mut stmt, 0)?;
-                table.push(value);
-
-                if stmt.next()? == State::Done {
-                    break;
-                }
-            }
-        } else {
-            loop {
-                let mut obj = Map::new();
-                let col_names = stmt.column_names();
-                for i in 0..col_count {
-                    let value = get_column(&stmt, i)?;
-                    obj.insert(col_names[i].to_string(), value);
-                }
-                table.push(JsonValue::Object(obj));
-
-                if stmt.next()? == State::Done {
-                    break;
-                }
-            }
-        }
-
-        Ok(JsonValue::Array(table))
+        self.get_pool()?.execute(&sql, &params)
     }
 
     async fn async_execute(self, sql: String, params: JsonValue) -> anyhow::Result<JsonValue> {
@@ -193,11 +205,18 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
 
     sqlite_mod.set(
         "open",
-        lua.create_function(move |_, (path, busy_timeout): (String, Option<usize>)| {
-            let mut db = Connection::open_thread_safe(path).map_err(any_err)?;
-            db.set_busy_timeout(busy_timeout.unwrap_or(500))
-                .map_err(any_err)?;
-            Ok(Conn(Arc::new(Mutex::new(Some(Arc::new(db))))))
+        lua.create_function(move |lua, (path, options): (String, Option<Value>)| {
+            let params = match options {
+                None => PoolParams::default(),
+                // For backwards compatibility, a number is the busy timeout
+                Some(Value::Integer(busy_timeout)) => PoolParams {
+                    busy_timeout: busy_timeout.try_into().map_err(any_err)?,
+                    ..PoolParams::default()
+                },
+                Some(options) => from_lua_value(lua, options)?,
+            };
+            let pool = Pool::get(path, params).map_err(any_err)?;
+            Ok(Conn(Arc::new(Mutex::new(Some(pool)))))
         })?,
     )?;
 
@@ -0,0 +1,345 @@
+//! A pool of connections to a sqlite database, each of which
+//! holds a cache of prepared statements.
+//! The pool is used from blocking threads, so it uses plain
+//! std synchronization primitives rather than async ones.
+use crate::bind_params;
+use prometheus::{HistogramVec, IntCounterVec, IntGaugeVec};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use sqlite::{Connection, ConnectionThreadSafe, Statement};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::time::Instant;
+
+/// The pools that are shared by all of the handles that
+/// opened the same database with the same parameters
+static POOLS: LazyLock<Mutex<HashMap<(String, PoolParams), Arc<Pool>>>> =
+    LazyLock::new(Mutex::default);
+
+static POOL_SIZE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "sqlite_pool_size",
+        "number of connections currently held by a sqlite connection pool",
+        &["path"]
+    )
+    .unwrap()
+});
+static POOL_IN_USE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "sqlite_pool_in_use",
+        "number of connections of a sqlite connection pool that are executing a query",
+        &["path"]
+    )
+    .unwrap()
+});
+static POOL_WAITING: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "sqlite_pool_waiting",
+        "number of queries waiting for a connection from a sqlite connection pool",
+        &["path"]
+    )
+    .unwrap()
+});
+static STATEMENT_CACHE_HIT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "sqlite_statement_cache_hit",
+        "number of sqlite queries that used a cached prepared statement",
+        &["path"]
+    )
+    .unwrap()
+});
+static STATEMENT_CACHE_MISS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "sqlite_statement_cache_miss",
+        "number of sqlite queries that needed to prepare their statement",
+        &["path"]
+    )
+    .unwrap()
+});
+static QUERY_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    prometheus::register_histogram_vec!(
+        "sqlite_query_latency",
+        "how long it takes to execute a sqlite query, including \
+         the time spent waiting for a connection",
+        &["path"]
+    )
+    .unwrap()
+});
+
+#[derive(Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PoolParams {
+    /// The time in milliseconds over which sqlite should retry
+    /// operations when it is unable to get a lock
+    #[serde(default = "PoolParams::default_busy_timeout")]
+    pub busy_timeout: usize,
+    /// The maximum number of connections to the database
+    #[serde(default = "PoolParams::default_pool_size")]
+    pub pool_size: usize,
+    /// The maximum number of prepared statements to cache
+    /// for each connection
+    #[serde(default = "PoolParams::default_statement_cache_size")]
+    pub statement_cache_size: usize,
+}
+
+impl Default for PoolParams {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Self::default_busy_timeout(),
+            pool_size: Self::default_pool_size(),
+            statement_cache_size: Self::default_statement_cache_size(),
+        }
+    }
+}
+
+impl PoolParams {
+    fn default_busy_timeout() -> usize {
+        500
+    }
+
+    fn default_pool_size() -> usize {
+        4
+    }
+
+    fn default_statement_cache_size() -> usize {
+        32
+    }
+}
+
+struct CachedStatement {
+    stmt: Statement<'static>,
+    /// The parameters that were most recently bound to stmt
+    params: JsonValue,
+}
+
+struct PooledConnection {
+    // NOTE: statements must be declared before conn so that they
+    // are dropped before the connection that they borrow from
+    statements: HashMap<String, CachedStatement>,
+    conn: Box<ConnectionThreadSafe>,
+}
+
+// SAFETY: the connection is opened in serialized mode, so sqlite
+// permits it and its statements to be used from any thread, and
+// the pool only ever allows a single thread at a time to use it.
+unsafe impl Send for PooledConnection {}
+
+/// Returns params with all of its values replaced by null
+fn null_params(params: &JsonValue) -> JsonValue {
+    match params {
+        JsonValue::Object(obj) => JsonValue::Object(
+            obj.keys()
+                .map(|name| (name.to_string(), JsonValue::Null))
+                .collect(),
+        ),
+        JsonValue::Array(arr) => JsonValue::Array(vec![JsonValue::Null; arr.len()]),
+        JsonValue::Null => JsonValue::Null,
+        _ => JsonValue::Array(vec![JsonValue::Null]),
+    }
+}
+
+impl PooledConnection {
+    fn open(path: &str, params: &PoolParams) -> anyhow::Result<Self> {
+        let mut conn = Connection::open_thread_safe(path)?;
+        conn.set_busy_timeout(params.busy_timeout)?;
+        Ok(Self {
+            statements: HashMap::new(),
+            conn: Box::new(conn),
+        })
+    }
+
+    fn prepare(&mut self, pool: &Pool, sql: &str) -> anyhow::Result<CachedStatement> {
+        if let Some(mut cached) = self.statements.remove(sql) {
+            STATEMENT_CACHE_HIT.with_label_values(&[&pool.path]).inc();
+            // sqlite retains bindings across resets, so clear out
+            // those from the prior use of the statement to avoid
+            // leaking them into a query that doesn't bind them all
+            bind_params(&mut cached.stmt, &null_params(&cached.params))?;
+            cached.params = JsonValue::Null;
+            return Ok(cached);
+        }
+
+        STATEMENT_CACHE_MISS.with_label_values(&[&pool.path]).inc();
+        let stmt = self.conn.prepare(sql)?;
+        // SAFETY: the statement borrows from the boxed connection,
+        // whose address is stable, and the statement is always
+        // dropped before the connection: either explicitly, or
+        // because statements is declared before conn.
+        let stmt: Statement<'static> = unsafe { std::mem::transmute(stmt) };
+        Ok(CachedStatement {
+            stmt,
+            params: JsonValue::Null,
+        })
+    }
+
+    fn execute(&mut self, pool: &Pool, sql: &str, params: &JsonValue) -> anyhow::Result<JsonValue> {
+        let mut cached = self.prepare(pool, sql)?;
+        let result = crate::execute_statement(&self.conn, &mut cached.stmt, sql, params);
+
+        // Resetting releases any locks held by a statement that
+        // didn't run to completion
+        let reset = cached.stmt.reset();
+
+        // Only retain statements whose parameters were all bound
+        // successfully, so that they can be cleared on the next use
+        let cache_size = pool.params.statement_cache_size;
+        if result.is_ok() && reset.is_ok() && cache_size > 0 {
+            cached.params = params.clone();
+            if self.statements.len() >= cache_size {
+                if let Some(victim) = self.statements.keys().next().cloned() {
+                    self.statements.remove(&victim);
+                }
+            }
+            self.statements.insert(sql.to_string(), cached);
+        }
+
+        result
+    }
+}
+
+struct PoolState {
+    idle: Vec<PooledConnection>,
+    /// The number of connections, both idle and in use
+    size: usize,
+}
+
+pub struct Pool {
+    path: String,
+    params: PoolParams,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+impl Pool {
+    /// Returns the pool for the specified database, creating it
+    /// if necessary. Each in-memory database is private to the
+    /// connection that created it, so those are never shared and
+    /// are limited to a single connection.
+    pub fn get(path: String, mut params: PoolParams) -> anyhow::Result<Arc<Self>> {
+        anyhow::ensure!(params.pool_size > 0, "pool_size must be at least 1");
+
+        if path == ":memory:" {
+            params.pool_size = 1;
+            return Ok(Arc::new(Self::new(path, params)));
+        }
+
+        let mut pools = POOLS.lock().unwrap();
+        Ok(pools
+            .entry((path.clone(), params.clone()))
+            .or_insert_with(|| Arc::new(Self::new(path, params)))
+            .clone())
+    }
+
+    fn new(path: String, params: PoolParams) -> Self {
+        Self {
+            path,
+            params,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                size: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Obtains a connection from the pool, opening a new one if
+    /// there are no idle connections and the pool is not yet full,
+    /// or waiting for a connection to be returned to the pool.
+    fn take(&self) -> anyhow::Result<PooledConnection> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.idle.pop() {
+                POOL_IN_USE.with_label_values(&[&self.path]).inc();
+                return Ok(conn);
+            }
+
+            if state.size < self.params.pool_size {
+                state.size += 1;
+                drop(state);
+                return match PooledConnection::open(&self.path, &self.params) {
+                    Ok(conn) => {
+                        POOL_SIZE.with_label_values(&[&self.path]).inc();
+                        POOL_IN_USE.with_label_values(&[&self.path]).inc();
+                        Ok(conn)
+                    }
+                    Err(err) => {
+                        self.state.lock().unwrap().size -= 1;
+                        self.available.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+
+            let waiting = POOL_WAITING.with_label_values(&[&self.path]);
+            waiting.inc();
+            state = self.available.wait(state).unwrap();
+            waiting.dec();
+        }
+    }
+
+    fn put(&self, conn: PooledConnection) {
+        POOL_IN_USE.with_label_values(&[&self.path]).dec();
+        self.state.lock().unwrap().idle.push(conn);
+        self.available.notify_one();
+    }
+
+    /// Executes a query using a connection from the pool.
+    /// This blocks, so it must only be called from a blocking thread.
+    pub fn execute(&self, sql: &str, params: &JsonValue) -> anyhow::Result<JsonValue> {
+        let start = Instant::now();
+        let mut conn = self.take()?;
+        let result = conn.execute(self, sql, params);
+        self.put(conn);
+        QUERY_LATENCY
+            .with_label_values(&[&self.path])
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        POOL_SIZE
+            .with_label_values(&[&self.path])
+            .sub(state.size as i64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn statement_cache() {
+        let pool = Pool::get(":memory:".to_string(), PoolParams::default()).unwrap();
+        pool.execute("CREATE TABLE people (name, age)", &JsonValue::Null)
+            .unwrap();
+
+        let insert = "INSERT INTO people (name, age) values (:name, :age)";
+        pool.execute(insert, &json!({"name": "john", "age": 42}))
+            .unwrap();
+        // The cached statement must not remember the age from the
+        // prior insert
+        pool.execute(insert, &json!({"name": "fred"})).unwrap();
+
+        assert_eq!(
+            pool.execute(
+                "SELECT name, age FROM people WHERE name = ? ORDER BY name",
+                &json!("fred")
+            )
+            .unwrap(),
+            json!([{"name": "fred", "age": null}])
+        );
+        assert_eq!(
+            pool.execute("SELECT name FROM people ORDER BY name", &JsonValue::Null)
+                .unwrap(),
+            json!(["fred", "john"])
+        );
+
+        let state = pool.state.lock().unwrap();
+        assert_eq!(state.size, 1);
+        assert_eq!(state.idle[0].statements.len(), 4);
+    }
+}
@@ -0,0 +1,160 @@
+//! Client authorization for the proxy: restricting the networks
+//! from which clients may connect, and the SOCKS5 username/password
+//! authentication method described by <https://www.rfc-editor.org/rfc/rfc1929>
+use anyhow::Context;
+use cidr_map::CidrSet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Default)]
+pub struct AuthConfig {
+    /// If set, only clients connecting from these networks
+    /// may use the proxy
+    allowed_networks: Option<CidrSet>,
+    /// If set, clients must authenticate using one of these
+    /// username/password pairs
+    users: Option<HashMap<String, String>>,
+}
+
+impl AuthConfig {
+    pub fn new(allow: Vec<String>, auth_file: Option<&Path>) -> anyhow::Result<Self> {
+        let allowed_networks = if allow.is_empty() {
+            None
+        } else {
+            Some(
+                CidrSet::try_from(allow)
+                    .map_err(|err| anyhow::anyhow!("invalid --allow value: {err}"))?,
+            )
+        };
+
+        let users = match auth_file {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Some(
+                    parse_credentials(&data)
+                        .with_context(|| format!("failed to parse {}", path.display()))?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            allowed_networks,
+            users,
+        })
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        match &self.allowed_networks {
+            Some(networks) => networks.contains(ip),
+            None => true,
+        }
+    }
+
+    pub fn requires_password(&self) -> bool {
+        self.users.is_some()
+    }
+
+    fn check_password(&self, username: &str, password: &[u8]) -> bool {
+        match self.users.as_ref().and_then(|users| users.get(username)) {
+            Some(expected) => constant_time_eq(expected.as_bytes(), password),
+            None => false,
+        }
+    }
+
+    /// Performs the username/password sub-negotiation with the client,
+    /// returning the authenticated username
+    pub async fn authenticate(&self, stream: &mut TcpStream) -> anyhow::Result<String> {
+        let version = stream.read_u8().await?;
+        anyhow::ensure!(
+            version == 1,
+            "unsupported username/password auth version {version}"
+        );
+        let username = read_field(stream).await?;
+        let password = read_field(stream).await?;
+        let username = String::from_utf8(username).context("username is not UTF-8")?;
+
+        let ok = self.check_password(&username, &password);
+        stream.write_all(&[1, if ok { 0 } else { 1 }]).await?;
+
+        anyhow::ensure!(ok, "authentication failed for user {username:?}");
+        Ok(username)
+    }
+}
+
+async fn read_field(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u8().await?;
+    let mut field = vec![0u8; len as usize];
+    stream.read_exact(&mut field).await?;
+    Ok(field)
+}
+
+/// Compares a and b in a time that doesn't depend on how
+/// many of their leading bytes match
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+/// Parses a credentials file, which has one `username:password`
+/// entry per line. Blank lines and lines starting with `#` are ignored.
+fn parse_credentials(data: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+    for (idx, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (username, password) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("line {}: expected username:password", idx + 1))?;
+        anyhow::ensure!(
+            !username.is_empty() && username.len() < 256 && password.len() < 256,
+            "line {}: username must be between 1 and 255 bytes, \
+             and password must be at most 255 bytes",
+            idx + 1
+        );
+        users.insert(username.to_string(), password.to_string());
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn credentials() {
+        let users = parse_credentials(
+            "# comment\n\
+             alice:secret\n\
+             \n\
+             bob:with:colon\n",
+        )
+        .unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["bob"], "with:colon");
+
+        assert!(parse_credentials("nocolon").is_err());
+        assert!(parse_credentials(":password").is_err());
+
+        let config = AuthConfig {
+            allowed_networks: Some(CidrSet::try_from(vec!["10.0.0.0/8"]).unwrap()),
+            users: Some(users),
+        };
+        assert!(config.check_password("alice", b"secret"));
+        assert!(!config.check_password("alice", b"secreT"));
+        assert!(!config.check_password("carol", b"secret"));
+        assert!(config.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!config.is_allowed("192.168.1.1".parse().unwrap()));
+    }
+}
@@ -1,7 +1,11 @@
+use crate::auth::AuthConfig;
 use anyhow::Context;
 use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 
+mod auth;
 mod proxy_handler;
 
 /// KumoProxy SOCKS5 Proxy Server
@@ -16,6 +20,18 @@ pub struct Opt {
 
     #[arg(long, default_value = "60")]
     timeout_seconds: u64,
+
+    /// Only accept clients whose address is within the specified
+    /// CIDR block. May be specified multiple times.
+    /// If omitted, clients may connect from any address.
+    #[arg(long)]
+    allow: Vec<String>,
+
+    /// Require clients to authenticate using one of the
+    /// `username:password` pairs listed in the specified file,
+    /// one per line.
+    #[arg(long)]
+    auth_file: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -27,11 +43,14 @@ async fn main() -> anyhow::Result<()> {
         anyhow::bail!("No listeners defined! use the --listen option to specify at least one!");
     }
 
+    let auth = Arc::new(AuthConfig::new(opts.allow, opts.auth_file.as_deref())?);
+
     for endpoint in &opts.listen {
         start_listener(
             endpoint,
             std::time::Duration::from_secs(opts.timeout_seconds),
             opts.no_splice,
+            auth.clone(),
         )
         .await?;
     }
@@ -45,6 +64,7 @@ async fn start_listener(
     endpoint: &str,
     timeout: std::time::Duration,
     no_splice: bool,
+    auth: Arc<AuthConfig>,
 ) -> anyhow::Result<()> {
     let listener = TcpListener::bind(endpoint)
         .await
@@ -63,10 +83,21 @@ async fn start_listener(
                 }
             };
 
+            if !auth.is_allowed(peer_address.ip()) {
+                log::warn!("rejecting client {peer_address:?} that is not permitted by --allow");
+                continue;
+            }
+
+            let auth = auth.clone();
             tokio::spawn(async move {
-                if let Err(err) =
-                    proxy_handler::handle_proxy_client(socket, peer_address, timeout, no_splice)
-                        .await
+                if let Err(err) = proxy_handler::handle_proxy_client(
+                    socket,
+                    peer_address,
+                    timeout,
+                    no_splice,
+                    &auth,
+                )
+                .await
                 {
                     log::error!("proxy session error: {err:#}");
                 }
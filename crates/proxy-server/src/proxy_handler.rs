@@ -1,3 +1,4 @@
+use crate::auth::AuthConfig;
 use anyhow::Context;
 use socksv5::v5::{
     SocksV5AuthMethod, SocksV5Command, SocksV5Host, SocksV5Request, SocksV5RequestStatus,
@@ -14,6 +15,7 @@ pub async fn handle_proxy_client(
     peer_address: SocketAddr,
     timeout_duration: std::time::Duration,
     #[cfg_attr(not(target_os = "linux"), allow(unused_variables))] no_splice: bool,
+    auth: &AuthConfig,
 ) -> anyhow::Result<()> {
     let mut state = ClientState::None;
 
@@ -24,23 +26,36 @@ pub async fn handle_proxy_client(
     .with_context(|| format!("timeout reading client handshake from {peer_address:?}"))?
     .with_context(|| format!("failed to read client handshake from {peer_address:?}"))?;
 
-    if handshake
-        .methods
-        .into_iter()
-        .find(|m| *m == socksv5::v5::SocksV5AuthMethod::Noauth)
-        .is_none()
-    {
+    // The socks5 crate doesn't allow copying the auth method,
+    // so we keep its name around for use in error messages
+    let (method, method_name) = if auth.requires_password() {
+        (SocksV5AuthMethod::UsernamePassword, "UsernamePassword")
+    } else {
+        (SocksV5AuthMethod::Noauth, "Noauth")
+    };
+
+    if !handshake.methods.contains(&method) {
         return Err(anyhow::anyhow!(
-            "client {peer_address:?} requested authentication, but this proxy only supports NOAUTH"
+            "client {peer_address:?} offered auth methods {:?}, \
+             but this proxy requires {method_name}",
+            handshake.methods
         ));
     }
 
     timeout(timeout_duration, async {
-        socksv5::v5::write_auth_method(&mut stream, SocksV5AuthMethod::Noauth).await
+        socksv5::v5::write_auth_method(&mut stream, method).await
     })
     .await
-    .with_context(|| format!("timeout sending Noauth response to {peer_address:?}"))?
-    .with_context(|| format!("failed to send Noauth response to {peer_address:?}"))?;
+    .with_context(|| format!("timeout sending {method_name} response to {peer_address:?}"))?
+    .with_context(|| format!("failed to send {method_name} response to {peer_address:?}"))?;
+
+    if auth.requires_password() {
+        let username = timeout(timeout_duration, auth.authenticate(&mut stream))
+            .await
+            .with_context(|| format!("timeout authenticating {peer_address:?}"))?
+            .with_context(|| format!("failed to authenticate {peer_address:?}"))?;
+        log::trace!("peer={peer_address:?} authenticated as {username:?}");
+    }
 
     loop {
         let request = timeout(timeout_duration, async {
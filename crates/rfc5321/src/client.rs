@@ -1,21 +1,30 @@
 use crate::client_types::*;
-use crate::{AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Domain, ForwardPath, ReversePath};
+use crate::tls::{
+    OpenSslContext, OPENSSL_CONTEXTS, OPENSSL_CONTEXT_TTL, OPENSSL_SESSIONS, OPENSSL_SESSION_KEY,
+};
+use crate::{
+    AsyncReadAndWrite, BoxedAsyncReadAndWrite, Command, Domain, EsmtpParameter, ForwardPath,
+    ReversePath,
+};
 use hickory_proto::rr::rdata::tlsa::{CertUsage, Matching, Selector};
 use hickory_proto::rr::rdata::TLSA;
 use memchr::memmem::Finder;
-use openssl::ssl::{DaneMatchType, DaneSelector, DaneUsage, SslOptions};
+use openssl::ssl::{DaneMatchType, DaneSelector, DaneUsage, SslOptions, SslSessionCacheMode};
 use openssl::x509::{X509Ref, X509};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::IpAddr;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream, ToSocketAddrs};
 use tokio::time::timeout;
 use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::HandshakeKind;
 use tracing::Level;
 
 pub use crate::tls::TlsOptions;
@@ -70,6 +79,8 @@ pub enum SmtpClientTraceEvent {
     Closed,
     Read(Vec<u8>),
     Write(String),
+    /// Message content written after the DATA command
+    WriteData(String),
     Diagnostic {
         level: tracing::Level,
         message: String,
@@ -85,6 +96,18 @@ pub trait SmtpClientTracer: std::fmt::Debug {
     fn lazy_trace(&self, deferred: &dyn DeferredTracer);
 }
 
+/// Paces the transmission of message data, for example, to
+/// enforce a bandwidth limit
+pub trait DataPacer: std::fmt::Debug {
+    /// Returns a future that completes once `len` more bytes
+    /// of message data may be sent
+    fn pace(&self, len: usize) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+/// When a DataPacer is set, message data is written in chunks
+/// of at most this size, pacing each one
+const PACED_CHUNK_SIZE: usize = 64 * 1024;
+
 // helper to avoid making a second copy of every write buffer
 struct WriteTracer<'a> {
     data: &'a str,
@@ -106,7 +129,7 @@ struct BinWriteTracer<'a> {
 impl<'a> DeferredTracer for BinWriteTracer<'a> {
     fn trace(&self) -> SmtpClientTraceEvent {
         let data = String::from_utf8_lossy(&self.data).to_string();
-        SmtpClientTraceEvent::Write(data)
+        SmtpClientTraceEvent::WriteData(data)
     }
 }
 impl<'a> BinWriteTracer<'a> {
@@ -134,6 +157,7 @@ pub struct SmtpClient {
     read_buffer: Vec<u8>,
     timeouts: SmtpClientTimeouts,
     tracer: Option<Arc<dyn SmtpClientTracer + Send + Sync>>,
+    pacer: Option<Arc<dyn DataPacer + Send + Sync>>,
 }
 
 fn extract_hostname(hostname: &str) -> &str {
@@ -177,6 +201,7 @@ impl SmtpClient {
             read_buffer: Vec::with_capacity(1024),
             timeouts,
             tracer: None,
+            pacer: None,
         }
     }
 
@@ -188,10 +213,20 @@ impl SmtpClient {
         self.tracer.replace(tracer);
     }
 
+    pub fn set_data_pacer(&mut self, pacer: Option<Arc<dyn DataPacer + Send + Sync>>) {
+        self.pacer = pacer;
+    }
+
     pub fn timeouts(&self) -> &SmtpClientTimeouts {
         &self.timeouts
     }
 
+    /// Returns true if the named extension was advertised in the
+    /// most recent EHLO response
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.contains_key(&name.to_ascii_uppercase())
+    }
+
     async fn read_line(
         &mut self,
         timeout_duration: Duration,
@@ -551,6 +586,7 @@ impl SmtpClient {
                     None => String::new(),
                 };
                 tls_info.protocol_version = ssl_stream.ssl().version_str().to_string();
+                tls_info.session_resumed = ssl_stream.ssl().session_reused();
 
                 if let Some(cert) = ssl_stream.ssl().peer_certificate() {
                     tls_info.subject_name = subject_name(&cert);
@@ -603,6 +639,9 @@ impl SmtpClient {
                             None => String::new(),
                         };
 
+                        tls_info.session_resumed =
+                            conn.handshake_kind() == Some(HandshakeKind::Resumed);
+
                         if let Some(certs) = conn.peer_certificates() {
                             let peer_cert = &certs[0];
                             if let Ok(cert) = X509::from_der(peer_cert.as_ref()) {
@@ -641,17 +680,36 @@ impl SmtpClient {
         sender: SENDER,
         recipient: RECIP,
         data: B,
+    ) -> Result<Response, ClientError> {
+        self.send_mail_with_parameters(sender, vec![], recipient, vec![], data)
+            .await
+    }
+
+    /// Like `send_mail`, but passes ESMTP parameters with the MAIL FROM
+    /// and RCPT TO commands. The caller is responsible for verifying that
+    /// the peer advertised the corresponding extensions.
+    pub async fn send_mail_with_parameters<
+        B: AsRef<[u8]>,
+        SENDER: Into<ReversePath>,
+        RECIP: Into<ForwardPath>,
+    >(
+        &mut self,
+        sender: SENDER,
+        mail_parameters: Vec<EsmtpParameter>,
+        recipient: RECIP,
+        rcpt_parameters: Vec<EsmtpParameter>,
+        data: B,
     ) -> Result<Response, ClientError> {
         let mut responses = self
             .pipeline_commands(vec![
                 Command::Rset,
                 Command::MailFrom {
                     address: sender.into(),
-                    parameters: vec![],
+                    parameters: mail_parameters,
                 },
                 Command::RcptTo {
                     address: recipient.into(),
-                    parameters: vec![],
+                    parameters: rcpt_parameters,
                 },
                 Command::Data,
             ])
@@ -718,7 +776,15 @@ impl SmtpClient {
 
         tracing::trace!("message data is {} bytes", data.len());
 
-        self.write_data_with_timeout(&data).await?;
+        match self.pacer.clone() {
+            Some(pacer) => {
+                for chunk in data.chunks(PACED_CHUNK_SIZE) {
+                    pacer.pace(chunk.len()).await;
+                    self.write_data_with_timeout(chunk).await?;
+                }
+            }
+            None => self.write_data_with_timeout(&data).await?,
+        }
 
         let marker = if needs_newline { "\r\n.\r\n" } else { ".\r\n" };
 
@@ -750,6 +816,10 @@ pub struct TlsInformation {
     pub protocol_version: String,
     pub subject_name: Vec<String>,
     pub provider_name: String,
+    /// true if an earlier session was resumed, rather than
+    /// performing a full handshake
+    #[serde(default)]
+    pub session_resumed: bool,
 }
 
 impl Drop for SmtpClient {
@@ -785,35 +855,42 @@ impl TlsOptions {
         hostname: &str,
     ) -> Result<openssl::ssl::ConnectConfiguration, ClientError> {
         tracing::trace!("build_openssl_connector for {hostname}");
-        let mut builder =
-            openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client())?;
-
-        if let Some(list) = &self.openssl_cipher_list {
-            builder.set_cipher_list(&list)?;
-        }
 
-        if let Some(suites) = &self.openssl_cipher_suites {
-            builder.set_ciphersuites(&suites)?;
-        }
-
-        if let Some(options) = &self.openssl_options {
-            builder.clear_options(SslOptions::all());
-            builder.set_options(*options);
-        }
+        let (connector, context_id) = match self.openssl_context_key() {
+            Some(key) => match OPENSSL_CONTEXTS.get(&key) {
+                Some(context) => (context.connector, Some(context.id)),
+                None => {
+                    let context = OpenSslContext {
+                        id: OpenSslContext::next_id(),
+                        connector: self.build_openssl_context(true)?,
+                    };
+                    OPENSSL_CONTEXTS.insert(
+                        key,
+                        context.clone(),
+                        Instant::now() + OPENSSL_CONTEXT_TTL,
+                    );
+                    (context.connector, Some(context.id))
+                }
+            },
+            None => (self.build_openssl_context(false)?, None),
+        };
 
-        if self.insecure {
-            builder.set_verify(openssl::ssl::SslVerifyMode::NONE);
-        }
+        let mut config = connector.configure()?;
 
-        if !self.dane_tlsa.is_empty() {
-            builder.dane_enable()?;
-            builder.set_no_dane_ee_namechecks();
+        if let Some(id) = context_id {
+            let session_key = (id, hostname.to_string());
+            if let Some(session) = OPENSSL_SESSIONS.get(&session_key) {
+                // SAFETY: the session must have been established by a
+                // context compatible with this one.  Sessions are keyed
+                // by the id of the context whose new session callback
+                // recorded them, and that is the context we are using.
+                unsafe {
+                    config.set_session(&session)?;
+                }
+            }
+            config.set_ex_data(*OPENSSL_SESSION_KEY, session_key);
         }
 
-        let connector = builder.build();
-
-        let mut config = connector.configure()?;
-
         if !self.dane_tlsa.is_empty() {
             config.dane_enable(hostname)?;
             let mut any_usable = false;
@@ -859,6 +936,50 @@ impl TlsOptions {
 
         Ok(config)
     }
+
+    /// Build the openssl context for these options.  When `cache_sessions`
+    /// is true, the context records the sessions that it establishes in
+    /// OPENSSL_SESSIONS, under the key associated with each connection.
+    fn build_openssl_context(
+        &self,
+        cache_sessions: bool,
+    ) -> Result<openssl::ssl::SslConnector, ClientError> {
+        let mut builder =
+            openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client())?;
+
+        if let Some(list) = &self.openssl_cipher_list {
+            builder.set_cipher_list(&list)?;
+        }
+
+        if let Some(suites) = &self.openssl_cipher_suites {
+            builder.set_ciphersuites(&suites)?;
+        }
+
+        if let Some(options) = &self.openssl_options {
+            builder.clear_options(SslOptions::all());
+            builder.set_options(*options);
+        }
+
+        if self.insecure {
+            builder.set_verify(openssl::ssl::SslVerifyMode::NONE);
+        }
+
+        if !self.dane_tlsa.is_empty() {
+            builder.dane_enable()?;
+            builder.set_no_dane_ee_namechecks();
+        }
+
+        if cache_sessions {
+            builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+            builder.set_new_session_callback(|ssl, session| {
+                if let Some(key) = ssl.ex_data(*OPENSSL_SESSION_KEY) {
+                    OPENSSL_SESSIONS.insert(key.clone(), session, TlsOptions::session_cache_ttl());
+                }
+            });
+        }
+
+        Ok(builder.build())
+    }
 }
 
 fn apply_dot_stuffing(data: &[u8]) -> Option<Vec<u8>> {
@@ -1,11 +1,14 @@
 #![cfg(feature = "client")]
 use hickory_proto::rr::rdata::TLSA;
 use lruttl::LruCacheWithTtl;
-use openssl::ssl::SslOptions;
+use openssl::ex_data::Index;
+use openssl::ssl::{Ssl, SslConnector, SslOptions, SslSession};
 use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, LazyLock};
 use std::time::{Duration, Instant};
 use tokio_rustls::rustls::client::danger::ServerCertVerifier;
+use tokio_rustls::rustls::client::{ClientSessionMemoryCache, ClientSessionStore, Resumption};
 use tokio_rustls::rustls::crypto::{aws_lc_rs as provider, CryptoProvider};
 use tokio_rustls::rustls::{ClientConfig, SupportedCipherSuite};
 use tokio_rustls::TlsConnector;
@@ -71,6 +74,77 @@ impl RustlsCacheKey {
     }
 }
 
+/// Identifies the set of connections that may resume each other's
+/// sessions.  A session established without verifying the peer,
+/// or without DANE, must not be resumed by a connection that
+/// requires them, so those are part of the key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SessionCacheKey {
+    site: String,
+    insecure: bool,
+    dane: bool,
+}
+
+/// How long cached sessions are retained.
+/// The server decides how long a session can actually be resumed;
+/// an expired session simply results in a full handshake.
+const SESSION_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The per-site rustls session stores. The store is itself keyed by
+/// server name, so it holds sessions for each of the hosts of the site.
+static RUSTLS_SESSIONS: LazyLock<LruCacheWithTtl<SessionCacheKey, Arc<dyn ClientSessionStore>>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("rfc5321_rustls_sessions", 16 * 1024));
+
+/// Identifies an openssl client context that is shared by the
+/// connections to a site.  openssl only permits a session to be
+/// resumed by a context compatible with the one that established it,
+/// so every option that is applied to the context is part of the key.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct OpenSslContextKey {
+    session: SessionCacheKey,
+    cipher_list: Option<String>,
+    cipher_suites: Option<String>,
+    options: Option<SslOptions>,
+}
+
+/// A shared openssl client context, along with an id that uniquely
+/// identifies it for the lifetime of the process.
+#[derive(Clone)]
+pub(crate) struct OpenSslContext {
+    pub id: u64,
+    pub connector: SslConnector,
+}
+
+static NEXT_OPENSSL_CONTEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl OpenSslContext {
+    pub fn next_id() -> u64 {
+        NEXT_OPENSSL_CONTEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// The shared openssl contexts.  As with the rustls configuration,
+/// they are rebuilt periodically so that we have an opportunity to
+/// reload the system certificates as/when they are updated.
+pub(crate) static OPENSSL_CONTEXTS: LazyLock<LruCacheWithTtl<OpenSslContextKey, OpenSslContext>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("rfc5321_openssl_contexts", 1024));
+
+pub(crate) const OPENSSL_CONTEXT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The most recent openssl session for each host, keyed by the id of
+/// the context that established it and the hostname.  Since the id
+/// is never reused, a session can only ever be resumed by the context
+/// that produced it, even after that context has been evicted and
+/// rebuilt.
+pub(crate) static OPENSSL_SESSIONS: LazyLock<LruCacheWithTtl<(u64, String), SslSession>> =
+    LazyLock::new(|| LruCacheWithTtl::new_named("rfc5321_openssl_sessions", 64 * 1024));
+
+/// Associates a connection with its OPENSSL_SESSIONS key, so that the
+/// new session callback of the shared context knows where to record
+/// the sessions that it produces.
+pub(crate) static OPENSSL_SESSION_KEY: LazyLock<Index<Ssl, (u64, String)>> =
+    LazyLock::new(|| Ssl::new_ex_index().expect("failed to allocate ssl ex_data index"));
+
 #[derive(Debug, Clone, Default)]
 pub struct TlsOptions {
     pub insecure: bool,
@@ -81,9 +155,35 @@ pub struct TlsOptions {
     pub openssl_cipher_suites: Option<String>,
     pub openssl_options: Option<SslOptions>,
     pub rustls_cipher_suites: Vec<SupportedCipherSuite>,
+    /// When set, sessions are cached under this key, typically the
+    /// name of the destination site, so that subsequent connections
+    /// with the same key can resume them rather than performing a
+    /// full handshake
+    pub session_cache_key: Option<String>,
 }
 
 impl TlsOptions {
+    pub(crate) fn session_cache_key(&self) -> Option<SessionCacheKey> {
+        self.session_cache_key.as_ref().map(|site| SessionCacheKey {
+            site: site.clone(),
+            insecure: self.insecure,
+            dane: !self.dane_tlsa.is_empty(),
+        })
+    }
+
+    pub(crate) fn openssl_context_key(&self) -> Option<OpenSslContextKey> {
+        self.session_cache_key().map(|session| OpenSslContextKey {
+            session,
+            cipher_list: self.openssl_cipher_list.clone(),
+            cipher_suites: self.openssl_cipher_suites.clone(),
+            options: self.openssl_options,
+        })
+    }
+
+    pub(crate) fn session_cache_ttl() -> Instant {
+        Instant::now() + SESSION_CACHE_TTL
+    }
+
     /// Produce a TlsConnector for this set of TlsOptions.
     /// We need to employ a cache around the verifier as loading
     /// the system certificate store can be a non-trivial operation
@@ -91,13 +191,32 @@ impl TlsOptions {
     /// path.  The cache does unfortunately complicate some of the
     /// internals here.
     pub fn build_tls_connector(&self) -> TlsConnector {
+        let config = self.rustls_config();
+        match self.session_cache_key() {
+            Some(key) => {
+                let store = RUSTLS_SESSIONS.get_or_insert(
+                    key,
+                    SESSION_CACHE_TTL,
+                    || -> Arc<dyn ClientSessionStore> {
+                        Arc::new(ClientSessionMemoryCache::new(32))
+                    },
+                );
+                let mut config = (*config).clone();
+                config.resumption = Resumption::store(store);
+                TlsConnector::from(Arc::new(config))
+            }
+            None => TlsConnector::from(config),
+        }
+    }
+
+    fn rustls_config(&self) -> Arc<ClientConfig> {
         let key = RustlsCacheKey {
             insecure: self.insecure,
             rustls_cipher_suites: self.rustls_cipher_suites.clone(),
         };
 
         if let Some(config) = key.get() {
-            return TlsConnector::from(config);
+            return config;
         }
         let cipher_suites = if self.rustls_cipher_suites.is_empty() {
             provider::DEFAULT_CIPHER_SUITES
@@ -126,7 +245,7 @@ impl TlsOptions {
         );
         key.set(config.clone());
 
-        TlsConnector::from(config)
+        config
     }
 }
 
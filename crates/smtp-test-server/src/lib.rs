@@ -0,0 +1,852 @@
+//! A scriptable fake SMTP server, intended to reproduce the edge
+//! cases that an SMTP client has to deal with, such as slow greetings,
+//! multi-line and unusual replies, connections that are dropped in
+//! the middle of DATA and failed TLS negotiation, in a deterministic
+//! way.
+//!
+//! The behavior of the server is described by an [SmtpScript], which
+//! can be constructed in code or deserialized from JSON, so that the
+//! same scripts can be used by integration tests and by
+//! `kcli smtp-probe`.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use smtp_test_server::{FakeSmtpServer, Reply, Rule, SmtpScript};
+//!
+//! let server = FakeSmtpServer::start(SmtpScript {
+//!     rules: vec![Rule {
+//!         command: "RCPT".to_string(),
+//!         occurrence: Some(2),
+//!         reply: Some(Reply::new(452, "too many recipients").with_enhanced_code("4.5.3")),
+//!         ..Default::default()
+//!     }],
+//!     ..Default::default()
+//! })
+//! .await?;
+//! println!("listening on {}", server.addr());
+//! # Ok(())
+//! # }
+//! ```
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+/// A fatal TLS alert record (handshake_failure), which is sent in
+/// place of a ServerHello to make the client's handshake fail
+const TLS_HANDSHAKE_FAILURE_ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+
+/// An SMTP reply
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Reply {
+    pub code: u16,
+    /// An enhanced status code, such as `5.1.1`, which is
+    /// prefixed to each line of the reply
+    #[serde(default)]
+    pub enhanced_code: Option<String>,
+    /// The text of the reply. A multi-line reply is produced
+    /// when this contains newlines.
+    #[serde(default)]
+    pub text: String,
+}
+
+impl Reply {
+    pub fn new<T: Into<String>>(code: u16, text: T) -> Self {
+        Self {
+            code,
+            enhanced_code: None,
+            text: text.into(),
+        }
+    }
+
+    pub fn with_enhanced_code<T: Into<String>>(mut self, enhanced_code: T) -> Self {
+        self.enhanced_code.replace(enhanced_code.into());
+        self
+    }
+
+    fn is_positive(&self) -> bool {
+        (200..400).contains(&self.code)
+    }
+
+    /// Returns the lines of the reply, without line endings
+    fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<&str> = self.text.lines().collect();
+        if lines.is_empty() {
+            lines.push("");
+        }
+        let last = lines.len() - 1;
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(idx, line)| {
+                let sep = if idx == last { ' ' } else { '-' };
+                match &self.enhanced_code {
+                    Some(enhanced) => format!("{}{sep}{enhanced} {line}", self.code),
+                    None => format!("{}{sep}{line}", self.code),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How the server responds to STARTTLS
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartTls {
+    /// STARTTLS is not advertised and is rejected with a 502 reply
+    #[default]
+    Unsupported,
+    /// STARTTLS is advertised, and the handshake is completed
+    /// using a self-signed certificate
+    Accept,
+    /// STARTTLS is advertised and accepted with a 220 reply, but the
+    /// server then sends a TLS alert rather than completing the
+    /// handshake, and closes the connection
+    HandshakeFailure,
+}
+
+/// Overrides the behavior of the server for a command. A rule
+/// applies to a command when all of the specified conditions match.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The verb of the command, such as `EHLO`, `MAIL` or `RCPT`.
+    /// `CONNECT` refers to the greeting, and `.` to the end of the
+    /// message data.
+    pub command: String,
+    /// Only apply to commands whose text contains this string,
+    /// ignoring case
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// Only apply to the Nth instance of the command in a session,
+    /// counting from 1
+    #[serde(default)]
+    pub occurrence: Option<usize>,
+    /// Only apply to the Nth connection to the server, counting from 1
+    #[serde(default)]
+    pub session: Option<usize>,
+    /// Wait this long before replying
+    #[serde(default, with = "duration_serde")]
+    pub delay: Option<Duration>,
+    /// Send this reply, rather than the usual one
+    #[serde(default)]
+    pub reply: Option<Reply>,
+    /// Close the connection after sending the reply, or
+    /// without replying when `reply` is not set
+    #[serde(default)]
+    pub disconnect: bool,
+}
+
+impl Rule {
+    fn matches(&self, verb: &str, line: &str, occurrence: usize, session: usize) -> bool {
+        self.command.eq_ignore_ascii_case(verb)
+            && self
+                .contains
+                .as_ref()
+                .map(|s| line.to_ascii_lowercase().contains(&s.to_ascii_lowercase()))
+                .unwrap_or(true)
+            && self.occurrence.map(|n| n == occurrence).unwrap_or(true)
+            && self.session.map(|n| n == session).unwrap_or(true)
+    }
+}
+
+/// Describes the behavior of the server. Commands that do not match
+/// any of the rules receive a conventional, successful reply.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpScript {
+    /// The name that the server uses in its greeting and EHLO reply
+    #[serde(default = "SmtpScript::default_hostname")]
+    pub hostname: String,
+    /// The extensions advertised in the EHLO reply, in addition
+    /// to STARTTLS, which is controlled by `starttls`
+    #[serde(default = "SmtpScript::default_extensions")]
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    pub starttls: StartTls,
+    /// Close the connection once this many bytes of message data
+    /// have been received, without replying
+    #[serde(default)]
+    pub drop_data_after_bytes: Option<usize>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl Default for SmtpScript {
+    fn default() -> Self {
+        Self {
+            hostname: Self::default_hostname(),
+            extensions: Self::default_extensions(),
+            starttls: StartTls::default(),
+            drop_data_after_bytes: None,
+            rules: vec![],
+        }
+    }
+}
+
+impl SmtpScript {
+    fn default_hostname() -> String {
+        "fake-smtp.example.com".to_string()
+    }
+
+    fn default_extensions() -> Vec<String> {
+        ["PIPELINING", "8BITMIME", "SMTPUTF8", "ENHANCEDSTATUSCODES"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    fn find_rule(
+        &self,
+        verb: &str,
+        line: &str,
+        occurrence: usize,
+        session: usize,
+    ) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(verb, line, occurrence, session))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the client
+    Client,
+    /// Sent by the server
+    Server,
+    /// Describes something that happened, such as the
+    /// connection being closed
+    Note,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptEntry {
+    pub direction: Direction,
+    pub text: String,
+}
+
+/// The record of a connection to the server
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    /// The connection number, counting from 1
+    pub session: usize,
+    pub peer_address: SocketAddr,
+    /// true if TLS was successfully negotiated
+    pub tls: bool,
+    pub transcript: Vec<TranscriptEntry>,
+}
+
+/// A message that was accepted by the server
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    /// The connection number, counting from 1
+    pub session: usize,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// The message data, with dot-stuffing removed
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: Vec<SessionRecord>,
+    messages: Vec<ReceivedMessage>,
+}
+
+/// A running fake SMTP server. The server is stopped when this
+/// is dropped.
+pub struct FakeSmtpServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for FakeSmtpServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl FakeSmtpServer {
+    /// Starts a server on a random port of the loopback interface
+    pub async fn start(script: SmtpScript) -> anyhow::Result<Self> {
+        Self::bind("127.0.0.1:0", script).await
+    }
+
+    pub async fn bind<A: ToSocketAddrs>(addr: A, script: SmtpScript) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let acceptor = match script.starttls {
+            StartTls::Accept => Some(make_acceptor(&script.hostname)?),
+            _ => None,
+        };
+        let script = Arc::new(script);
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, peer_address)) = listener.accept().await {
+                    let session = {
+                        let mut state = state.lock().unwrap();
+                        let session = state.sessions.len() + 1;
+                        state.sessions.push(SessionRecord {
+                            session,
+                            peer_address,
+                            tls: false,
+                            transcript: vec![],
+                        });
+                        session
+                    };
+                    let session = Session {
+                        session,
+                        script: script.clone(),
+                        state: state.clone(),
+                        acceptor: acceptor.clone(),
+                        stream: Some(BufReader::new(Box::new(stream))),
+                        counts: HashMap::new(),
+                        tls: false,
+                        sender: None,
+                        recipients: vec![],
+                    };
+                    tokio::spawn(session.run());
+                }
+            }
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// The address on which the server is listening
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the sessions that have been started so far,
+    /// including those that are still in progress
+    pub fn sessions(&self) -> Vec<SessionRecord> {
+        self.state.lock().unwrap().sessions.clone()
+    }
+
+    /// Returns the messages that have been accepted so far
+    pub fn messages(&self) -> Vec<ReceivedMessage> {
+        self.state.lock().unwrap().messages.clone()
+    }
+}
+
+fn make_acceptor(hostname: &str) -> anyhow::Result<TlsAcceptor> {
+    let key = rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+    let certificates = vec![CertificateDer::from_slice(key.cert.der()).into_owned()];
+    let private_key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key.key_pair.serialize_der()));
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl Stream for TcpStream {}
+impl Stream for tokio_rustls::server::TlsStream<Box<dyn Stream>> {}
+
+/// Returns the address from a `MAIL FROM:<address>` or
+/// `RCPT TO:<address>` command
+fn extract_address(line: &str) -> String {
+    match (line.find('<'), line.find('>')) {
+        (Some(start), Some(end)) if start < end => line[start + 1..end].to_string(),
+        _ => line
+            .split_once(':')
+            .map(|(_, addr)| addr.split_whitespace().next().unwrap_or(""))
+            .unwrap_or("")
+            .to_string(),
+    }
+}
+
+enum Outcome {
+    Continue,
+    Close,
+}
+
+struct Session {
+    session: usize,
+    script: Arc<SmtpScript>,
+    state: Arc<Mutex<State>>,
+    acceptor: Option<TlsAcceptor>,
+    stream: Option<BufReader<Box<dyn Stream>>>,
+    counts: HashMap<String, usize>,
+    tls: bool,
+    sender: Option<String>,
+    recipients: Vec<String>,
+}
+
+impl Session {
+    fn record(&self, direction: Direction, text: impl Into<String>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(record) = state.sessions.get_mut(self.session - 1) {
+            record.transcript.push(TranscriptEntry {
+                direction,
+                text: text.into(),
+            });
+            record.tls = self.tls;
+        }
+    }
+
+    async fn run(mut self) {
+        if let Err(err) = self.run_impl().await {
+            self.record(Direction::Note, format!("error: {err:#}"));
+        }
+        self.record(Direction::Note, "connection closed");
+    }
+
+    fn stream(&mut self) -> anyhow::Result<&mut BufReader<Box<dyn Stream>>> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("not connected"))
+    }
+
+    async fn write_reply(&mut self, reply: &Reply) -> anyhow::Result<()> {
+        let mut text = String::new();
+        for line in reply.lines() {
+            text.push_str(&line);
+            text.push_str("\r\n");
+            self.record(Direction::Server, line);
+        }
+        let stream = self.stream()?;
+        stream.write_all(text.as_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Applies any matching rule, then sends either the reply from
+    /// the rule or the default reply. Returns the reply that was
+    /// sent, or None if the connection should be closed without
+    /// replying.
+    async fn respond(
+        &mut self,
+        verb: &str,
+        line: &str,
+        default_reply: Reply,
+    ) -> anyhow::Result<(Option<Reply>, Outcome)> {
+        let occurrence = {
+            let count = self.counts.entry(verb.to_string()).or_default();
+            *count += 1;
+            *count
+        };
+        let rule = self
+            .script
+            .find_rule(verb, line, occurrence, self.session)
+            .cloned();
+
+        let Some(rule) = rule else {
+            self.write_reply(&default_reply).await?;
+            return Ok((Some(default_reply), Outcome::Continue));
+        };
+
+        if let Some(delay) = rule.delay {
+            tokio::time::sleep(delay).await;
+        }
+        let reply = match rule.reply {
+            Some(reply) => Some(reply),
+            None if rule.disconnect => None,
+            None => Some(default_reply),
+        };
+        if let Some(reply) = &reply {
+            self.write_reply(reply).await?;
+        }
+        let outcome = if rule.disconnect {
+            Outcome::Close
+        } else {
+            Outcome::Continue
+        };
+        Ok((reply, outcome))
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut line = vec![];
+        let size = self.stream()?.read_until(b'\n', &mut line).await?;
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    async fn run_impl(&mut self) -> anyhow::Result<()> {
+        let greeting = Reply::new(220, format!("{} ESMTP fake-smtp", self.script.hostname));
+        if let (_, Outcome::Close) = self.respond("CONNECT", "", greeting).await? {
+            return Ok(());
+        }
+
+        while let Some(line) = self.read_line().await? {
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            self.record(Direction::Client, &line);
+            let verb = line
+                .split_whitespace()
+                .next()
+                .unwrap_or("")
+                .to_ascii_uppercase();
+
+            if let Outcome::Close = self.command(&verb, &line).await? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn ehlo_reply(&self) -> Reply {
+        let mut lines = vec![self.script.hostname.clone()];
+        lines.extend(self.script.extensions.iter().cloned());
+        if self.script.starttls != StartTls::Unsupported && !self.tls {
+            lines.push("STARTTLS".to_string());
+        }
+        Reply::new(250, lines.join("\n"))
+    }
+
+    async fn command(&mut self, verb: &str, line: &str) -> anyhow::Result<Outcome> {
+        let ok = |enhanced: &str| Reply::new(250, "OK").with_enhanced_code(enhanced);
+
+        match verb {
+            "EHLO" | "LHLO" => {
+                let reply = self.ehlo_reply();
+                Ok(self.respond(verb, line, reply).await?.1)
+            }
+            "HELO" => {
+                let reply = Reply::new(250, self.script.hostname.clone());
+                Ok(self.respond(verb, line, reply).await?.1)
+            }
+            "MAIL" => {
+                let (reply, outcome) = self.respond(verb, line, ok("2.1.0")).await?;
+                if reply.map(|r| r.is_positive()).unwrap_or(false) {
+                    self.sender.replace(extract_address(line));
+                    self.recipients.clear();
+                }
+                Ok(outcome)
+            }
+            "RCPT" => {
+                let (reply, outcome) = self.respond(verb, line, ok("2.1.5")).await?;
+                if reply.map(|r| r.is_positive()).unwrap_or(false) {
+                    self.recipients.push(extract_address(line));
+                }
+                Ok(outcome)
+            }
+            "DATA" => {
+                let default_reply = if self.sender.is_some() && !self.recipients.is_empty() {
+                    Reply::new(354, "Send it")
+                } else {
+                    Reply::new(503, "need MAIL and RCPT first").with_enhanced_code("5.5.1")
+                };
+                let (reply, outcome) = self.respond(verb, line, default_reply).await?;
+                match (reply, outcome) {
+                    (Some(reply), Outcome::Continue) if reply.code == 354 => self.data().await,
+                    (_, outcome) => Ok(outcome),
+                }
+            }
+            "RSET" => {
+                self.sender.take();
+                self.recipients.clear();
+                Ok(self.respond(verb, line, ok("2.0.0")).await?.1)
+            }
+            "NOOP" => Ok(self.respond(verb, line, ok("2.0.0")).await?.1),
+            "VRFY" => {
+                let reply = Reply::new(252, "cannot verify").with_enhanced_code("2.5.0");
+                Ok(self.respond(verb, line, reply).await?.1)
+            }
+            "QUIT" => {
+                let reply = Reply::new(221, "bye").with_enhanced_code("2.0.0");
+                self.respond(verb, line, reply).await?;
+                Ok(Outcome::Close)
+            }
+            "STARTTLS" => self.starttls(line).await,
+            _ => {
+                let reply = Reply::new(500, "command not recognized").with_enhanced_code("5.5.2");
+                Ok(self.respond(verb, line, reply).await?.1)
+            }
+        }
+    }
+
+    async fn data(&mut self) -> anyhow::Result<Outcome> {
+        let mut data = vec![];
+        loop {
+            let Some(line) = self.read_line().await? else {
+                self.record(
+                    Direction::Note,
+                    format!(
+                        "client closed the connection after {} bytes of data",
+                        data.len()
+                    ),
+                );
+                return Ok(Outcome::Close);
+            };
+            if line == b".\r\n" || line == b".\n" {
+                break;
+            }
+            let line = line.strip_prefix(b".").unwrap_or(&line);
+            data.extend_from_slice(line);
+
+            if let Some(limit) = self.script.drop_data_after_bytes {
+                if data.len() >= limit {
+                    self.record(
+                        Direction::Note,
+                        format!("dropping the connection after {} bytes of data", data.len()),
+                    );
+                    return Ok(Outcome::Close);
+                }
+            }
+        }
+        self.record(Direction::Client, format!("({} bytes of data)", data.len()));
+        self.record(Direction::Client, ".");
+
+        let (reply, outcome) = self
+            .respond(".", ".", Reply::new(250, "OK").with_enhanced_code("2.0.0"))
+            .await?;
+        if reply.map(|r| r.is_positive()).unwrap_or(false) {
+            let message = ReceivedMessage {
+                session: self.session,
+                sender: self.sender.take().unwrap_or_default(),
+                recipients: std::mem::take(&mut self.recipients),
+                data,
+            };
+            self.state.lock().unwrap().messages.push(message);
+        }
+        Ok(outcome)
+    }
+
+    async fn starttls(&mut self, line: &str) -> anyhow::Result<Outcome> {
+        let default_reply = if self.script.starttls == StartTls::Unsupported || self.tls {
+            Reply::new(502, "STARTTLS not available").with_enhanced_code("5.5.1")
+        } else {
+            Reply::new(220, "Ready to start TLS").with_enhanced_code("2.0.0")
+        };
+        let (reply, outcome) = self.respond("STARTTLS", line, default_reply).await?;
+        if let Outcome::Close = outcome {
+            return Ok(outcome);
+        }
+        if reply.map(|r| r.code != 220).unwrap_or(true) {
+            return Ok(Outcome::Continue);
+        }
+
+        match self.script.starttls {
+            StartTls::Unsupported => Ok(Outcome::Continue),
+            StartTls::HandshakeFailure => {
+                self.record(Direction::Note, "failing the TLS handshake");
+                let stream = self.stream()?;
+                stream.write_all(TLS_HANDSHAKE_FAILURE_ALERT).await?;
+                stream.flush().await?;
+                Ok(Outcome::Close)
+            }
+            StartTls::Accept => {
+                let acceptor = self
+                    .acceptor
+                    .clone()
+                    .ok_or_else(|| anyhow::anyhow!("no TLS acceptor"))?;
+                let stream = self
+                    .stream
+                    .take()
+                    .ok_or_else(|| anyhow::anyhow!("not connected"))?
+                    .into_inner();
+                let stream = acceptor.accept(stream).await?;
+                let stream: Box<dyn Stream> = Box::new(stream);
+                self.stream.replace(BufReader::new(stream));
+                self.tls = true;
+                // The session is reset after STARTTLS
+                self.sender.take();
+                self.recipients.clear();
+                self.record(Direction::Note, "TLS negotiated");
+                Ok(Outcome::Continue)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rfc5321::{ClientError, SmtpClient, SmtpClientTimeouts, TlsOptions, TlsStatus};
+
+    async fn connect(server: &FakeSmtpServer) -> SmtpClient {
+        let mut client = SmtpClient::new(server.addr(), SmtpClientTimeouts::short_timeouts())
+            .await
+            .unwrap();
+        let banner = client
+            .read_response(None, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(banner.code, 220);
+        client
+    }
+
+    #[test]
+    fn reply_lines() {
+        let reply = Reply::new(250, "mx.example.com\nPIPELINING\nSIZE 1000");
+        assert_eq!(
+            reply.lines(),
+            vec!["250-mx.example.com", "250-PIPELINING", "250 SIZE 1000"]
+        );
+        let reply = Reply::new(550, "no such user\nreally").with_enhanced_code("5.1.1");
+        assert_eq!(
+            reply.lines(),
+            vec!["550-5.1.1 no such user", "550 5.1.1 really"]
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_message() {
+        let server = FakeSmtpServer::start(SmtpScript::default()).await.unwrap();
+        let mut client = connect(&server).await;
+        client.ehlo("client.example.com").await.unwrap();
+        assert!(client.has_capability("PIPELINING"));
+        assert!(!client.has_capability("STARTTLS"));
+
+        let response = client
+            .send_mail(
+                rfc5321::ReversePath::try_from("sender@example.com").unwrap(),
+                rfc5321::ForwardPath::try_from("recip@example.com").unwrap(),
+                "Subject: hello\r\n\r\n.leading dot\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.code, 250);
+
+        let messages = server.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sender, "sender@example.com");
+        assert_eq!(messages[0].recipients, vec!["recip@example.com"]);
+        assert_eq!(
+            String::from_utf8_lossy(&messages[0].data),
+            "Subject: hello\r\n\r\n.leading dot\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn scripted_rejection() {
+        let script: SmtpScript = serde_json::from_value(serde_json::json!({
+            "rules": [{
+                "command": "RCPT",
+                "contains": "bad@",
+                "reply": {"code": 550, "enhanced_code": "5.1.1", "text": "no such user\nat all"},
+            }],
+        }))
+        .unwrap();
+        let server = FakeSmtpServer::start(script).await.unwrap();
+        let mut client = connect(&server).await;
+        client.ehlo("client.example.com").await.unwrap();
+
+        let err = client
+            .send_mail(
+                rfc5321::ReversePath::try_from("sender@example.com").unwrap(),
+                rfc5321::ForwardPath::try_from("bad@example.com").unwrap(),
+                "Subject: hello\r\n\r\nhi\r\n",
+            )
+            .await
+            .unwrap_err();
+        match err {
+            ClientError::Rejected(response) => {
+                assert_eq!(response.code, 550);
+                assert_eq!(response.content, "no such user\nat all");
+            }
+            err => panic!("unexpected {err:#}"),
+        }
+        assert!(server.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drop_during_data() {
+        let server = FakeSmtpServer::start(SmtpScript {
+            drop_data_after_bytes: Some(10),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut client = connect(&server).await;
+        client.ehlo("client.example.com").await.unwrap();
+
+        let result = client
+            .send_mail(
+                rfc5321::ReversePath::try_from("sender@example.com").unwrap(),
+                rfc5321::ForwardPath::try_from("recip@example.com").unwrap(),
+                "Subject: a message that is long enough to be dropped\r\n\r\nhi\r\n",
+            )
+            .await;
+        assert!(result.is_err(), "{result:?}");
+        assert!(server.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn greeting_delay() {
+        let server = FakeSmtpServer::start(SmtpScript {
+            rules: vec![Rule {
+                command: "CONNECT".to_string(),
+                delay: Some(Duration::from_secs(2)),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let mut client = SmtpClient::new(server.addr(), SmtpClientTimeouts::short_timeouts())
+            .await
+            .unwrap();
+        let err = client
+            .read_response(None, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, ClientError::TimeOutResponse { .. }),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn starttls() {
+        let server = FakeSmtpServer::start(SmtpScript {
+            starttls: StartTls::Accept,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut client = connect(&server).await;
+        client.ehlo("client.example.com").await.unwrap();
+        assert!(client.has_capability("STARTTLS"));
+        let status = client
+            .starttls(TlsOptions {
+                insecure: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(matches!(status, TlsStatus::Info(_)), "{status:?}");
+        client.ehlo("client.example.com").await.unwrap();
+        assert!(!client.has_capability("STARTTLS"));
+        assert!(server.sessions()[0].tls);
+    }
+
+    #[tokio::test]
+    async fn starttls_handshake_failure() {
+        let server = FakeSmtpServer::start(SmtpScript {
+            starttls: StartTls::HandshakeFailure,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut client = connect(&server).await;
+        client.ehlo("client.example.com").await.unwrap();
+        let status = client
+            .starttls(TlsOptions {
+                insecure: true,
+                ..Default::default()
+            })
+            .await;
+        assert!(
+            matches!(status, Ok(TlsStatus::FailedHandshake(_)) | Err(_)),
+            "{status:?}"
+        );
+        assert!(!server.sessions()[0].tls);
+    }
+}
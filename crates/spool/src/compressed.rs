@@ -0,0 +1,275 @@
+use crate::{Spool, SpoolEntry, SpoolId};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flume::Sender;
+use prometheus::{Gauge, GaugeVec, IntCounter, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, LazyLock};
+use tokio::runtime::Handle;
+
+/// The magic number that begins every zstd frame.
+/// We use this to distinguish compressed payloads from those that
+/// were written before compression was enabled for a spool.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolCompression {
+    #[default]
+    None,
+    Zstd,
+}
+
+static UNCOMPRESSED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_compression_uncompressed_bytes",
+        "Total number of bytes passed to the spool for compression",
+        &["spool"]
+    )
+    .unwrap()
+});
+static COMPRESSED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_compression_compressed_bytes",
+        "Total number of bytes written to the spool after compression",
+        &["spool"]
+    )
+    .unwrap()
+});
+static COMPRESSION_RATIO: LazyLock<GaugeVec> = LazyLock::new(|| {
+    prometheus::register_gauge_vec!(
+        "spool_compression_ratio",
+        "Ratio of uncompressed to compressed bytes written to the spool",
+        &["spool"]
+    )
+    .unwrap()
+});
+
+/// Wraps another Spool implementation, transparently compressing
+/// payloads on store and decompressing them on load/enumerate.
+pub struct CompressedSpool {
+    inner: Arc<dyn Spool + Send + Sync>,
+    level: i32,
+    runtime: Handle,
+    uncompressed: IntCounter,
+    compressed: IntCounter,
+    ratio: Gauge,
+}
+
+impl CompressedSpool {
+    pub fn new(
+        name: &str,
+        inner: Arc<dyn Spool + Send + Sync>,
+        level: i32,
+        runtime: Handle,
+    ) -> Self {
+        Self {
+            inner,
+            level,
+            runtime,
+            uncompressed: UNCOMPRESSED_BYTES
+                .get_metric_with_label_values(&[name])
+                .unwrap(),
+            compressed: COMPRESSED_BYTES
+                .get_metric_with_label_values(&[name])
+                .unwrap(),
+            ratio: COMPRESSION_RATIO
+                .get_metric_with_label_values(&[name])
+                .unwrap(),
+        }
+    }
+
+    fn update_ratio(&self, uncompressed: usize, compressed: usize) {
+        self.uncompressed.inc_by(uncompressed as u64);
+        self.compressed.inc_by(compressed as u64);
+        let total_compressed = self.compressed.get();
+        if total_compressed > 0 {
+            self.ratio
+                .set(self.uncompressed.get() as f64 / total_compressed as f64);
+        }
+    }
+}
+
+/// Returns true if data appears to be a zstd frame
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&ZSTD_MAGIC)
+}
+
+/// Decompress data if it is a zstd frame, otherwise return it unchanged.
+/// This allows compression to be enabled for a spool that already
+/// holds uncompressed entries.
+pub fn maybe_decompress(id: SpoolId, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if is_compressed(&data) {
+        zstd::stream::decode_all(data.as_slice())
+            .with_context(|| format!("failed to decompress {id}"))
+    } else {
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl Spool for CompressedSpool {
+    async fn load(&self, id: SpoolId) -> anyhow::Result<Vec<u8>> {
+        let data = self.inner.load(id).await?;
+        if !is_compressed(&data) {
+            return Ok(data);
+        }
+        tokio::task::Builder::new()
+            .name("CompressedSpool load")
+            .spawn_blocking_on(move || maybe_decompress(id, data), &self.runtime)?
+            .await?
+    }
+
+    async fn remove(&self, id: SpoolId) -> anyhow::Result<()> {
+        self.inner.remove(id).await
+    }
+
+    async fn store(
+        &self,
+        id: SpoolId,
+        data: Arc<Box<[u8]>>,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        let level = self.level;
+        let uncompressed_len = data.len();
+        let compressed = tokio::task::Builder::new()
+            .name("CompressedSpool store")
+            .spawn_blocking_on(
+                move || {
+                    zstd::bulk::compress(&data, level)
+                        .with_context(|| format!("failed to compress {id}"))
+                },
+                &self.runtime,
+            )?
+            .await??;
+
+        self.update_ratio(uncompressed_len, compressed.len());
+
+        self.inner
+            .store(id, Arc::new(compressed.into_boxed_slice()), force_sync)
+            .await
+    }
+
+    fn enumerate(
+        &self,
+        sender: Sender<SpoolEntry>,
+        start_time: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = flume::bounded(1024);
+        self.inner.enumerate(tx, start_time)?;
+
+        tokio::task::Builder::new()
+            .name("CompressedSpool enumerate")
+            .spawn_blocking_on(
+                move || -> anyhow::Result<()> {
+                    while let Ok(entry) = rx.recv() {
+                        let entry = match entry {
+                            SpoolEntry::Item { id, data } => match maybe_decompress(id, data) {
+                                Ok(data) => SpoolEntry::Item { id, data },
+                                Err(err) => SpoolEntry::Corrupt {
+                                    id,
+                                    error: format!("{err:#}"),
+                                },
+                            },
+                            corrupt @ SpoolEntry::Corrupt { .. } => corrupt,
+                        };
+                        sender
+                            .send(entry)
+                            .map_err(|err| anyhow::anyhow!("failed to send SpoolEntry: {err:#}"))?;
+                    }
+                    Ok(())
+                },
+                &self.runtime,
+            )?;
+        Ok(())
+    }
+
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>> {
+        self.inner.list_ids(start_time).await
+    }
+
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        self.inner.cleanup().await
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    async fn advise_low_memory(&self) -> anyhow::Result<isize> {
+        self.inner.advise_low_memory().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+
+    #[tokio::test]
+    async fn compressed_spool() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let disk: Arc<dyn Spool + Send + Sync> = Arc::new(LocalDiskSpool::new(
+            &location.path(),
+            false,
+            Handle::current(),
+        )?);
+        let spool = CompressedSpool::new("test", disk.clone(), 0, Handle::current());
+
+        let payload = "<html><body>hello</body></html>\r\n".repeat(100);
+
+        let id = SpoolId::new();
+        spool
+            .store(
+                id,
+                Arc::new(payload.as_bytes().to_vec().into_boxed_slice()),
+                false,
+            )
+            .await?;
+
+        // The raw data is compressed
+        let raw = disk.load(id).await?;
+        assert!(is_compressed(&raw));
+        assert!(raw.len() < payload.len());
+
+        // but reads back uncompressed
+        assert_eq!(String::from_utf8(spool.load(id).await?)?, payload);
+
+        // Entries that were stored before compression was enabled
+        // can still be read
+        let legacy = SpoolId::new();
+        disk.store(
+            legacy,
+            Arc::new(b"legacy".to_vec().into_boxed_slice()),
+            false,
+        )
+        .await?;
+        assert_eq!(spool.load(legacy).await?, b"legacy");
+
+        let (tx, rx) = flume::bounded(32);
+        spool.enumerate(tx, Utc::now())?;
+        let mut count = 0;
+        while let Ok(item) = rx.recv_async().await {
+            match item {
+                SpoolEntry::Item { id: item_id, data } => {
+                    if item_id == id {
+                        assert_eq!(String::from_utf8(data)?, payload);
+                    } else {
+                        assert_eq!(item_id, legacy);
+                        assert_eq!(data, b"legacy");
+                    }
+                    count += 1;
+                }
+                SpoolEntry::Corrupt { id, error } => {
+                    anyhow::bail!("Corrupt: {id}: {error}");
+                }
+            }
+        }
+        assert_eq!(count, 2);
+
+        assert!(spool.ratio.get() > 1.0);
+
+        Ok(())
+    }
+}
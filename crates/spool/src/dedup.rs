@@ -0,0 +1,700 @@
+use crate::compressed::maybe_decompress;
+use crate::{Spool, SpoolEntry, SpoolId};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flume::Sender;
+use prometheus::{IntCounter, IntCounterVec};
+use sha2::{Digest, Sha256};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
+use tokio::runtime::Handle;
+
+/// Prefix of the entries that are stored in the inner spool in place
+/// of deduplicated payloads. It is followed by the hex encoded sha256
+/// digest of the body, a newline, and the headers of the payload.
+const REFERENCE_MAGIC: &[u8] = b"KUMODEDUP1:";
+
+/// Prefix of the entries whose payloads are stored in the inner spool
+/// rather than being deduplicated, but which would otherwise be mistaken
+/// for a tagged entry. It is followed by the payload. All other payloads
+/// are stored as-is, so that the inner spool remains readable without
+/// a DedupSpool for as long as no references have been written.
+const INLINE_MAGIC: &[u8] = b"KUMODEDUP1;";
+
+/// Separates the headers of a message from its body
+const HEADER_BODY_SEPARATOR: &[u8] = b"\r\n\r\n";
+
+/// Content that has no references is only removed once it is
+/// at least this old, so that cleanup doesn't race with a store
+/// that has written the content but not yet linked to it
+const CONTENT_GRACE_PERIOD: Duration = Duration::from_secs(300);
+
+static DEDUP_HITS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_dedup_hits",
+        "Total number of payloads stored by referencing identical content \
+         that was already present in the spool",
+        &["spool"]
+    )
+    .unwrap()
+});
+static DEDUP_SAVED_BYTES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_dedup_saved_bytes",
+        "Total number of bytes that were not written to the spool because \
+         identical content was already present",
+        &["spool"]
+    )
+    .unwrap()
+});
+static DEDUP_REMOVED_CONTENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    prometheus::register_int_counter_vec!(
+        "spool_dedup_removed_content",
+        "Total number of shared payloads removed from the spool once \
+         they were no longer referenced",
+        &["spool"]
+    )
+    .unwrap()
+});
+
+/// Wraps another Spool implementation, storing identical message bodies
+/// once, no matter how many entries contain them.
+///
+/// Each payload is split into its headers and its body, as messages that
+/// share a body typically differ in their headers. Bodies are stored in
+/// a content addressed directory, named by their sha256 digest, and each
+/// entry that holds a given body is a hard link to that content. The
+/// filesystem therefore maintains the reference count, so that it is
+/// durable and needs no state to be rebuilt when the spool is opened.
+/// The inner spool holds the headers together with a small reference
+/// to the body, so that it continues to track the set of entries.
+///
+/// Content that is no longer referenced is removed by `cleanup`.
+pub struct DedupSpool {
+    inner: Arc<dyn Spool + Send + Sync>,
+    content_dir: PathBuf,
+    refs_dir: PathBuf,
+    new_dir: PathBuf,
+    min_size: Option<usize>,
+    compression_level: Option<i32>,
+    runtime: Handle,
+    hits: IntCounter,
+    saved_bytes: IntCounter,
+    removed_content: IntCounter,
+}
+
+impl DedupSpool {
+    /// Creates a DedupSpool that keeps its content in the `dedup`
+    /// directory of path. Payloads whose body is smaller than min_size
+    /// are passed through to the inner spool unchanged.
+    ///
+    /// When min_size is None, no new payloads are deduplicated, but the
+    /// references held by existing entries continue to be resolved, so
+    /// that deduplication can be turned off for a spool that uses it.
+    ///
+    /// When compression_level is set, content is compressed with zstd
+    /// at that level.
+    pub fn new(
+        name: &str,
+        path: &Path,
+        inner: Arc<dyn Spool + Send + Sync>,
+        min_size: Option<usize>,
+        compression_level: Option<i32>,
+        runtime: Handle,
+    ) -> anyhow::Result<Self> {
+        let dir = Self::dir(path);
+        let content_dir = dir.join("content");
+        let refs_dir = dir.join("refs");
+        let new_dir = dir.join("new");
+        for dir in [&content_dir, &refs_dir, &new_dir] {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        Ok(Self {
+            inner,
+            content_dir,
+            refs_dir,
+            new_dir,
+            min_size,
+            compression_level,
+            runtime,
+            hits: DEDUP_HITS.get_metric_with_label_values(&[name]).unwrap(),
+            saved_bytes: DEDUP_SAVED_BYTES
+                .get_metric_with_label_values(&[name])
+                .unwrap(),
+            removed_content: DEDUP_REMOVED_CONTENT
+                .get_metric_with_label_values(&[name])
+                .unwrap(),
+        })
+    }
+
+    fn dir(path: &Path) -> PathBuf {
+        path.join("dedup")
+    }
+
+    /// Returns true if a DedupSpool has been used with the spool at path,
+    /// in which case its entries may hold references to its content.
+    pub fn is_present(path: &Path) -> bool {
+        Self::dir(path).exists()
+    }
+
+    fn ref_path(&self, id: SpoolId) -> PathBuf {
+        id.compute_path(&self.refs_dir)
+    }
+
+    /// Stores data in the inner spool, rather than deduplicating it
+    async fn store_inline(
+        &self,
+        id: SpoolId,
+        data: Arc<Box<[u8]>>,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        let data = if needs_tag(&data) {
+            Arc::new(encode_inline(&data).into_boxed_slice())
+        } else {
+            data
+        };
+        self.inner.store(id, data, force_sync).await?;
+        // If this entry was previously deduplicated, release
+        // its reference to the old content
+        let ref_path = self.ref_path(id);
+        tokio::task::Builder::new()
+            .name("DedupSpool store")
+            .spawn_blocking_on(move || unlink_ref(&ref_path), &self.runtime)?
+            .await?
+    }
+}
+
+/// How the payload of an entry is held by the inner spool
+#[derive(Debug, PartialEq)]
+enum Stored {
+    /// The payload is held by the inner spool. This is also the case
+    /// for untagged entries, which were written before dedup was enabled.
+    Inline(Vec<u8>),
+    /// The inner spool holds the headers, and a reference to the
+    /// deduplicated body
+    Reference { headers: Vec<u8> },
+}
+
+impl Stored {
+    fn decode(mut data: Vec<u8>) -> Self {
+        if data.starts_with(INLINE_MAGIC) {
+            data.drain(0..INLINE_MAGIC.len());
+            Self::Inline(data)
+        } else if data.starts_with(REFERENCE_MAGIC) {
+            let headers = match data.iter().position(|&b| b == b'\n') {
+                Some(idx) => data.split_off(idx + 1),
+                None => vec![],
+            };
+            Self::Reference { headers }
+        } else {
+            Self::Inline(data)
+        }
+    }
+}
+
+/// Returns true if data would be mistaken for a tagged entry
+fn needs_tag(data: &[u8]) -> bool {
+    data.starts_with(INLINE_MAGIC) || data.starts_with(REFERENCE_MAGIC)
+}
+
+fn encode_inline(data: &[u8]) -> Vec<u8> {
+    let mut inline = Vec::with_capacity(INLINE_MAGIC.len() + data.len());
+    inline.extend_from_slice(INLINE_MAGIC);
+    inline.extend_from_slice(data);
+    inline
+}
+
+/// Returns the offset at which the body of the message in data begins.
+/// If there is no body separator, the whole of data is the body.
+fn body_offset(data: &[u8]) -> usize {
+    data.windows(HEADER_BODY_SEPARATOR.len())
+        .position(|window| window == HEADER_BODY_SEPARATOR)
+        .map(|idx| idx + HEADER_BODY_SEPARATOR.len())
+        .unwrap_or(0)
+}
+
+fn compute_content_path(content_dir: &Path, digest: &str) -> PathBuf {
+    content_dir.join(&digest[0..2]).join(digest)
+}
+
+/// Removes the reference for id, if any
+fn unlink_ref(ref_path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(ref_path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("failed to remove {}", ref_path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes data to content_path, compressing it if a compression_level
+/// is provided
+fn write_content(
+    new_dir: &Path,
+    content_path: &Path,
+    data: &[u8],
+    compression_level: Option<i32>,
+    flush: bool,
+) -> anyhow::Result<()> {
+    let compressed;
+    let data = match compression_level {
+        Some(level) => {
+            compressed = zstd::bulk::compress(data, level).context("failed to compress content")?;
+            &compressed[..]
+        }
+        None => data,
+    };
+    let mut temp = NamedTempFile::new_in(new_dir)
+        .context("failed to create a temporary file to store content")?;
+    temp.write_all(data)
+        .context("failed to write content to temporary file")?;
+    if flush {
+        temp.as_file_mut()
+            .sync_data()
+            .context("failed to sync content")?;
+    }
+    std::fs::create_dir_all(content_path.parent().unwrap())?;
+    // Since the content is addressed by its digest, replacing
+    // a concurrently written copy is harmless
+    temp.persist(content_path)
+        .with_context(|| format!("failed to move content to {}", content_path.display()))?;
+    Ok(())
+}
+
+/// Links ref_path to the content of data, whose digest is provided,
+/// writing the content if it is not already present.
+/// Returns true if the content was already present.
+fn store_ref(
+    content_dir: &Path,
+    new_dir: &Path,
+    ref_path: &Path,
+    digest: &str,
+    data: &[u8],
+    compression_level: Option<i32>,
+    flush: bool,
+) -> anyhow::Result<bool> {
+    let content_path = compute_content_path(content_dir, digest);
+
+    // The entry may be replacing a previous version of itself
+    unlink_ref(ref_path)?;
+    std::fs::create_dir_all(ref_path.parent().unwrap())?;
+
+    let mut existed = true;
+    loop {
+        match std::fs::hard_link(&content_path, ref_path) {
+            Ok(()) => break,
+            // The content is either new, or was removed by cleanup
+            // after we found it; either way, it must be written
+            Err(err) if err.kind() == ErrorKind::NotFound && existed => {
+                existed = false;
+                write_content(new_dir, &content_path, data, compression_level, flush)?;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "failed to link {} to {}",
+                        ref_path.display(),
+                        content_path.display()
+                    )
+                });
+            }
+        }
+    }
+    Ok(existed)
+}
+
+/// Resolves a reference, returning the headers followed by the content
+fn load_ref(id: SpoolId, ref_path: &Path, mut headers: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let content = std::fs::read(ref_path)
+        .with_context(|| format!("failed to read deduplicated content for {id}"))?;
+    headers.extend_from_slice(&maybe_decompress(id, content)?);
+    Ok(headers)
+}
+
+/// Removes content that is no longer referenced by any entry,
+/// returning the number of items that were removed
+fn remove_unreferenced(content_dir: &Path) -> usize {
+    let mut removed = 0;
+    let now = SystemTime::now();
+    for entry in jwalk::WalkDir::new(content_dir) {
+        let Ok(entry) = entry else {
+            continue;
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(meta) = path.metadata() else {
+            continue;
+        };
+        // The only link is the content itself
+        if meta.nlink() > 1 {
+            continue;
+        }
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < CONTENT_GRACE_PERIOD {
+            continue;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(err) => tracing::error!("failed to remove {}: {err:#}", path.display()),
+        }
+    }
+    removed
+}
+
+#[async_trait]
+impl Spool for DedupSpool {
+    async fn load(&self, id: SpoolId) -> anyhow::Result<Vec<u8>> {
+        let headers = match Stored::decode(self.inner.load(id).await?) {
+            Stored::Inline(data) => return Ok(data),
+            Stored::Reference { headers } => headers,
+        };
+        let ref_path = self.ref_path(id);
+        tokio::task::Builder::new()
+            .name("DedupSpool load")
+            .spawn_blocking_on(move || load_ref(id, &ref_path, headers), &self.runtime)?
+            .await?
+    }
+
+    async fn remove(&self, id: SpoolId) -> anyhow::Result<()> {
+        self.inner.remove(id).await?;
+        let ref_path = self.ref_path(id);
+        tokio::task::Builder::new()
+            .name("DedupSpool remove")
+            .spawn_blocking_on(move || unlink_ref(&ref_path), &self.runtime)?
+            .await?
+    }
+
+    async fn store(
+        &self,
+        id: SpoolId,
+        data: Arc<Box<[u8]>>,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        let body_offset = body_offset(&data);
+        let body_len = data.len() - body_offset;
+        match self.min_size {
+            Some(min_size) if body_len >= min_size => {}
+            _ => return self.store_inline(id, data, force_sync).await,
+        }
+
+        let ref_path = self.ref_path(id);
+        let content_dir = self.content_dir.clone();
+        let new_dir = self.new_dir.clone();
+        let compression_level = self.compression_level;
+        let (existed, reference) = tokio::task::Builder::new()
+            .name("DedupSpool store")
+            .spawn_blocking_on(
+                move || -> anyhow::Result<(bool, Vec<u8>)> {
+                    let (headers, body) = data.split_at(body_offset);
+                    let digest = data_encoding::HEXLOWER.encode(&Sha256::digest(body));
+                    let existed = store_ref(
+                        &content_dir,
+                        &new_dir,
+                        &ref_path,
+                        &digest,
+                        body,
+                        compression_level,
+                        force_sync,
+                    )
+                    .with_context(|| format!("failed to store {id}"))?;
+
+                    let mut reference = Vec::with_capacity(
+                        REFERENCE_MAGIC.len() + digest.len() + 1 + headers.len(),
+                    );
+                    reference.extend_from_slice(REFERENCE_MAGIC);
+                    reference.extend_from_slice(digest.as_bytes());
+                    reference.push(b'\n');
+                    reference.extend_from_slice(headers);
+                    Ok((existed, reference))
+                },
+                &self.runtime,
+            )?
+            .await??;
+
+        if existed {
+            self.hits.inc();
+            self.saved_bytes.inc_by(body_len as u64);
+        }
+
+        self.inner
+            .store(id, Arc::new(reference.into_boxed_slice()), force_sync)
+            .await
+    }
+
+    fn enumerate(
+        &self,
+        sender: Sender<SpoolEntry>,
+        start_time: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = flume::bounded(1024);
+        self.inner.enumerate(tx, start_time)?;
+
+        let refs_dir = self.refs_dir.clone();
+        tokio::task::Builder::new()
+            .name("DedupSpool enumerate")
+            .spawn_blocking_on(
+                move || -> anyhow::Result<()> {
+                    while let Ok(entry) = rx.recv() {
+                        let entry = match entry {
+                            SpoolEntry::Item { id, data } => match Stored::decode(data) {
+                                Stored::Inline(data) => SpoolEntry::Item { id, data },
+                                Stored::Reference { headers } => {
+                                    match load_ref(id, &id.compute_path(&refs_dir), headers) {
+                                        Ok(data) => SpoolEntry::Item { id, data },
+                                        Err(err) => SpoolEntry::Corrupt {
+                                            id,
+                                            error: format!("{err:#}"),
+                                        },
+                                    }
+                                }
+                            },
+                            entry => entry,
+                        };
+                        sender
+                            .send(entry)
+                            .map_err(|err| anyhow::anyhow!("failed to send SpoolEntry: {err:#}"))?;
+                    }
+                    Ok(())
+                },
+                &self.runtime,
+            )?;
+        Ok(())
+    }
+
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>> {
+        self.inner.list_ids(start_time).await
+    }
+
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        self.inner.cleanup().await?;
+        let content_dir = self.content_dir.clone();
+        let removed = tokio::task::Builder::new()
+            .name("DedupSpool cleanup")
+            .spawn_blocking_on(move || remove_unreferenced(&content_dir), &self.runtime)?
+            .await?;
+        self.removed_content.inc_by(removed as u64);
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    async fn advise_low_memory(&self) -> anyhow::Result<isize> {
+        self.inner.advise_low_memory().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+    use std::collections::HashMap;
+
+    fn count_files(dir: &Path) -> usize {
+        jwalk::WalkDir::new(dir)
+            .into_iter()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map(|entry| entry.file_type().is_file())
+                    .unwrap_or(false)
+            })
+            .count()
+    }
+
+    fn boxed(data: &[u8]) -> Arc<Box<[u8]>> {
+        Arc::new(data.to_vec().into_boxed_slice())
+    }
+
+    fn new_disk_spool(path: &Path) -> anyhow::Result<Arc<dyn Spool + Send + Sync>> {
+        Ok(Arc::new(LocalDiskSpool::new(
+            path,
+            false,
+            Handle::current(),
+        )?))
+    }
+
+    #[tokio::test]
+    async fn dedup_spool() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let disk = new_disk_spool(location.path())?;
+        let spool = DedupSpool::new(
+            "test",
+            location.path(),
+            disk.clone(),
+            Some(64),
+            None,
+            Handle::current(),
+        )?;
+
+        // The messages share a body, but have their own headers
+        let body = "<html><body>hello</body></html>\r\n".repeat(100);
+        let mut payloads = HashMap::new();
+        for i in 0..3 {
+            let id = SpoolId::new();
+            let payload = format!("To: user{i}@example.com\r\nSubject: hello\r\n\r\n{body}");
+            spool.store(id, boxed(payload.as_bytes()), false).await?;
+            payloads.insert(id, payload);
+        }
+        let ids: Vec<SpoolId> = payloads.keys().copied().collect();
+
+        // The body is stored once, and the inner spool only
+        // holds the headers and a reference to it
+        assert_eq!(count_files(&spool.content_dir), 1);
+        let Stored::Reference { headers } = Stored::decode(disk.load(ids[0]).await?) else {
+            anyhow::bail!("expected a reference");
+        };
+        assert_eq!(
+            String::from_utf8(headers)?,
+            payloads[&ids[0]].strip_suffix(&body).unwrap()
+        );
+        assert_eq!(spool.hits.get(), 2);
+        assert_eq!(spool.saved_bytes.get(), 2 * body.len() as u64);
+        for id in &ids {
+            assert_eq!(String::from_utf8(spool.load(*id).await?)?, payloads[id]);
+        }
+
+        // Small payloads are not deduplicated, and are stored as-is
+        let small = SpoolId::new();
+        spool.store(small, boxed(b"small"), false).await?;
+        assert_eq!(disk.load(small).await?, b"small");
+        assert_eq!(spool.load(small).await?, b"small");
+
+        // A payload that looks like a reference is not mistaken for one
+        let lookalike = SpoolId::new();
+        let lookalike_data = b"KUMODEDUP1:not-a-reference".to_vec();
+        spool
+            .store(lookalike, boxed(&lookalike_data), false)
+            .await?;
+        assert!(disk.load(lookalike).await?.starts_with(INLINE_MAGIC));
+        assert_eq!(spool.load(lookalike).await?, lookalike_data);
+        spool.remove(lookalike).await?;
+
+        // Entries written before dedup was enabled are readable
+        let legacy = SpoolId::new();
+        disk.store(legacy, boxed(b"legacy"), false).await?;
+        assert_eq!(spool.load(legacy).await?, b"legacy");
+        spool.remove(legacy).await?;
+
+        let (tx, rx) = flume::bounded(32);
+        spool.enumerate(tx, Utc::now())?;
+        let mut count = 0;
+        while let Ok(item) = rx.recv_async().await {
+            match item {
+                SpoolEntry::Item { id, data } => {
+                    if id == small {
+                        assert_eq!(data, b"small");
+                    } else {
+                        assert_eq!(String::from_utf8(data)?, payloads[&id]);
+                    }
+                    count += 1;
+                }
+                SpoolEntry::Corrupt { id, error } => {
+                    anyhow::bail!("Corrupt: {id}: {error}");
+                }
+            }
+        }
+        assert_eq!(count, 4);
+
+        // Content remains while it is referenced
+        for id in &ids[0..2] {
+            spool.remove(*id).await?;
+        }
+        assert_eq!(remove_unreferenced(&spool.content_dir), 0);
+        assert_eq!(
+            String::from_utf8(spool.load(ids[2]).await?)?,
+            payloads[&ids[2]]
+        );
+
+        // Replacing the last reference with a small payload releases it
+        spool.store(ids[2], boxed(b"edited"), false).await?;
+        assert_eq!(spool.load(ids[2]).await?, b"edited");
+        let content = jwalk::WalkDir::new(&spool.content_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_type().is_file())
+            .unwrap()
+            .path();
+        assert_eq!(content.metadata()?.nlink(), 1);
+
+        // It is only removed once the grace period has elapsed
+        assert_eq!(remove_unreferenced(&spool.content_dir), 0);
+        std::fs::File::options()
+            .write(true)
+            .open(&content)?
+            .set_modified(SystemTime::now() - CONTENT_GRACE_PERIOD)?;
+        assert_eq!(remove_unreferenced(&spool.content_dir), 1);
+        assert_eq!(count_files(&spool.content_dir), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dedup_spool_disabled() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let disk = new_disk_spool(location.path())?;
+        assert!(!DedupSpool::is_present(location.path()));
+
+        let payload = format!(
+            "Subject: hello\r\n\r\n{}",
+            "<html><body>hello</body></html>\r\n".repeat(100)
+        );
+        let id = SpoolId::new();
+        {
+            let spool = DedupSpool::new(
+                "test",
+                location.path(),
+                disk.clone(),
+                Some(64),
+                Some(0),
+                Handle::current(),
+            )?;
+            spool.store(id, boxed(payload.as_bytes()), false).await?;
+        }
+        assert!(DedupSpool::is_present(location.path()));
+
+        // With dedup turned off, existing references are still
+        // resolved, but new payloads are stored as-is
+        let spool = DedupSpool::new(
+            "test",
+            location.path(),
+            disk.clone(),
+            None,
+            None,
+            Handle::current(),
+        )?;
+        assert_eq!(String::from_utf8(spool.load(id).await?)?, payload);
+
+        let other = SpoolId::new();
+        spool.store(other, boxed(payload.as_bytes()), false).await?;
+        assert_eq!(String::from_utf8(disk.load(other).await?)?, payload);
+
+        // Rewriting the deduplicated entry releases its reference
+        spool.store(id, boxed(payload.as_bytes()), false).await?;
+        assert_eq!(String::from_utf8(disk.load(id).await?)?, payload);
+        let content = jwalk::WalkDir::new(&spool.content_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_type().is_file())
+            .unwrap()
+            .path();
+        assert_eq!(content.metadata()?.nlink(), 1);
+        assert!(crate::compressed::is_compressed(&std::fs::read(&content)?));
+
+        Ok(())
+    }
+}
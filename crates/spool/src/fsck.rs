@@ -0,0 +1,272 @@
+//! Consistency checking for a pair of meta/data spools.
+//!
+//! The checker enumerates both spools and cross-references their
+//! contents, reporting entries that cannot be successfully spooled in.
+//! Problematic entries can optionally be moved into a quarantine
+//! directory so that they can be examined or restored manually,
+//! rather than being silently discarded during startup enumeration.
+use crate::{Spool, SpoolEntry, SpoolId};
+use anyhow::Context;
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssueKind {
+    /// There is a data entry but no corresponding metadata
+    OrphanedData,
+    /// There is a metadata entry but no corresponding data
+    MissingData,
+    /// The metadata entry exists but has zero length
+    TruncatedMeta,
+    /// The data entry exists but has zero length
+    TruncatedData,
+    /// The metadata could not be read or failed validation
+    CorruptMeta(String),
+    /// The data could not be read
+    CorruptData(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsckIssue {
+    pub id: SpoolId,
+    pub kind: FsckIssueKind,
+}
+
+impl std::fmt::Display for FsckIssue {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let id = self.id;
+        match &self.kind {
+            FsckIssueKind::OrphanedData => write!(fmt, "{id}: data has no corresponding meta"),
+            FsckIssueKind::MissingData => write!(fmt, "{id}: meta has no corresponding data"),
+            FsckIssueKind::TruncatedMeta => write!(fmt, "{id}: meta is empty"),
+            FsckIssueKind::TruncatedData => write!(fmt, "{id}: data is empty"),
+            FsckIssueKind::CorruptMeta(err) => write!(fmt, "{id}: meta is corrupt: {err}"),
+            FsckIssueKind::CorruptData(err) => write!(fmt, "{id}: data is corrupt: {err}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckParams {
+    /// If set, entries with issues will have whatever can be read
+    /// of their meta and data copied into this directory as
+    /// `<id>.meta` and `<id>.data`, and will then be removed from
+    /// both spools.
+    pub quarantine_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of entries found in the meta spool
+    pub num_meta: usize,
+    /// Number of entries found in the data spool
+    pub num_data: usize,
+    /// Number of entries that were moved to the quarantine directory
+    pub num_quarantined: usize,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Check the consistency of the meta and data spools.
+///
+/// `validate_meta` is called for each metadata entry and allows the
+/// caller to apply deeper validation than is possible in this crate,
+/// such as verifying that the metadata can be parsed into a message.
+///
+/// Both spools must be quiescent while this runs; it is not safe
+/// to run it against the spool of a live instance.
+pub async fn fsck<F>(
+    meta: &dyn Spool,
+    data: &dyn Spool,
+    params: &FsckParams,
+    validate_meta: F,
+) -> anyhow::Result<FsckReport>
+where
+    F: Fn(SpoolId, &[u8]) -> anyhow::Result<()>,
+{
+    let start_time = Utc::now();
+    let mut report = FsckReport::default();
+    let mut issues: HashMap<SpoolId, FsckIssueKind> = HashMap::new();
+    let mut meta_ids = HashSet::new();
+
+    let (tx, rx) = flume::bounded(1024);
+    meta.enumerate(tx, start_time)?;
+    while let Ok(entry) = rx.recv_async().await {
+        report.num_meta += 1;
+        match entry {
+            SpoolEntry::Item { id, data } => {
+                meta_ids.insert(id);
+                if data.is_empty() {
+                    issues.insert(id, FsckIssueKind::TruncatedMeta);
+                } else if let Err(err) = serde_json::from_slice::<serde_json::Value>(&data)
+                    .context("invalid JSON")
+                    .and_then(|_| validate_meta(id, &data))
+                {
+                    issues.insert(id, FsckIssueKind::CorruptMeta(format!("{err:#}")));
+                }
+            }
+            SpoolEntry::Corrupt { id, error } => {
+                meta_ids.insert(id);
+                issues.insert(id, FsckIssueKind::CorruptMeta(error));
+            }
+        }
+    }
+
+    let (tx, rx) = flume::bounded(1024);
+    data.enumerate(tx, start_time)?;
+    while let Ok(entry) = rx.recv_async().await {
+        report.num_data += 1;
+        let (id, problem) = match entry {
+            SpoolEntry::Item { id, data } => (
+                id,
+                if data.is_empty() {
+                    Some(FsckIssueKind::TruncatedData)
+                } else {
+                    None
+                },
+            ),
+            SpoolEntry::Corrupt { id, error } => (id, Some(FsckIssueKind::CorruptData(error))),
+        };
+
+        if !meta_ids.remove(&id) {
+            issues.entry(id).or_insert(FsckIssueKind::OrphanedData);
+            continue;
+        }
+        if let Some(problem) = problem {
+            // A problem with the meta takes precedence, as it is
+            // generally more informative
+            issues.entry(id).or_insert(problem);
+        }
+    }
+
+    // Anything left over has meta but no data
+    for id in meta_ids {
+        issues.entry(id).or_insert(FsckIssueKind::MissingData);
+    }
+
+    let mut issues: Vec<FsckIssue> = issues
+        .into_iter()
+        .map(|(id, kind)| FsckIssue { id, kind })
+        .collect();
+    issues.sort_by_key(|issue| issue.id.created());
+
+    if let Some(dir) = &params.quarantine_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating quarantine dir {}", dir.display()))?;
+        for issue in &issues {
+            quarantine(meta, data, issue.id, dir).await?;
+            report.num_quarantined += 1;
+        }
+    }
+
+    report.issues = issues;
+    Ok(report)
+}
+
+pub(crate) async fn quarantine(
+    meta: &dyn Spool,
+    data: &dyn Spool,
+    id: SpoolId,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    for (spool, ext) in [(meta, "meta"), (data, "data")] {
+        if let Ok(content) = spool.load(id).await {
+            let path = dir.join(format!("{id}.{ext}"));
+            tokio::fs::write(&path, content)
+                .await
+                .with_context(|| format!("quarantining {id} to {}", path.display()))?;
+        }
+        // The entry may legitimately not exist in this spool,
+        // so we don't care if this fails
+        spool.remove(id).await.ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+    use std::sync::Arc;
+    use tokio::runtime::Handle;
+
+    async fn store(spool: &dyn Spool, id: SpoolId, data: &[u8]) -> anyhow::Result<()> {
+        spool
+            .store(id, Arc::new(data.to_vec().into_boxed_slice()), false)
+            .await
+    }
+
+    #[tokio::test]
+    async fn fsck_spool() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let meta = LocalDiskSpool::new(&location.path().join("meta"), false, Handle::current())?;
+        let data = LocalDiskSpool::new(&location.path().join("data"), false, Handle::current())?;
+
+        let good = SpoolId::new();
+        store(&meta, good, b"{}").await?;
+        store(&data, good, b"hello").await?;
+
+        let orphan = SpoolId::new();
+        store(&data, orphan, b"orphan").await?;
+
+        let missing = SpoolId::new();
+        store(&meta, missing, b"{}").await?;
+
+        let corrupt = SpoolId::new();
+        store(&meta, corrupt, b"{\"sender\":").await?;
+        store(&data, corrupt, b"corrupt").await?;
+
+        let truncated = SpoolId::new();
+        store(&meta, truncated, b"{}").await?;
+        store(&data, truncated, b"").await?;
+
+        let quarantine_dir = location.path().join("quarantine");
+        let report = fsck(
+            &meta,
+            &data,
+            &FsckParams {
+                quarantine_dir: Some(quarantine_dir.clone()),
+            },
+            |_, _| Ok(()),
+        )
+        .await?;
+
+        assert_eq!(report.num_meta, 4);
+        assert_eq!(report.num_data, 4);
+        assert_eq!(report.num_quarantined, 4);
+
+        let kinds: HashMap<SpoolId, FsckIssueKind> = report
+            .issues
+            .into_iter()
+            .map(|issue| (issue.id, issue.kind))
+            .collect();
+        assert_eq!(kinds.len(), 4);
+        assert_eq!(kinds[&orphan], FsckIssueKind::OrphanedData);
+        assert_eq!(kinds[&missing], FsckIssueKind::MissingData);
+        assert!(matches!(kinds[&corrupt], FsckIssueKind::CorruptMeta(_)));
+        assert_eq!(kinds[&truncated], FsckIssueKind::TruncatedData);
+
+        // The good entry is untouched
+        assert_eq!(data.load(good).await?, b"hello");
+
+        // The others were moved to quarantine
+        assert!(meta.load(corrupt).await.is_err());
+        assert!(data.load(orphan).await.is_err());
+        assert_eq!(
+            std::fs::read(quarantine_dir.join(format!("{orphan}.data")))?,
+            b"orphan"
+        );
+        assert_eq!(
+            std::fs::read(quarantine_dir.join(format!("{corrupt}.meta")))?,
+            b"{\"sender\":"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+//! Detection of orphaned spool entries.
+//!
+//! If the process encounters an error or crashes part way through
+//! receiving, delivering or removing a message, the spool can be left
+//! holding entries for which there is no queue entry. Such an entry will
+//! never be delivered, and would otherwise occupy space in the spool
+//! indefinitely.
+//!
+//! The janitor lists the ids present in the spools, without reading or
+//! modifying the entries, and compares them against the set of ids that
+//! the caller finds in its queues. Only entries that are older than a
+//! grace period are considered. Messages move between queues, so an entry
+//! is only treated as an orphan if it is absent from a set taken before the
+//! spool is listed, and from a fresh set taken immediately prior to acting
+//! upon it, so that it can be run against a live spool.
+use crate::fsck::quarantine;
+use crate::{Spool, SpoolId};
+use anyhow::Context;
+use chrono::Utc;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct JanitorParams {
+    /// Only entries that were created longer ago than this
+    /// are eligible to be considered orphans
+    pub grace_period: Duration,
+    /// If set, orphaned entries will be copied into this directory
+    /// as `<id>.meta` or `<id>.data` before they are removed
+    pub quarantine_dir: Option<PathBuf>,
+    /// If true, orphans are reported but left in place
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct JanitorReport {
+    /// Entries present in the data spool with no queue entry
+    pub orphaned_data: Vec<SpoolId>,
+    /// Entries present in the meta spool with no queue entry
+    pub orphaned_meta: Vec<SpoolId>,
+    /// Number of orphaned ids that were removed from the spool
+    pub num_removed: usize,
+    /// Number of orphaned ids that were moved to the quarantine directory
+    pub num_quarantined: usize,
+}
+
+impl JanitorReport {
+    /// Returns the number of distinct orphaned ids; an id whose meta
+    /// and data are both present is counted once
+    pub fn num_orphans(&self) -> usize {
+        self.orphaned_ids().len()
+    }
+
+    fn orphaned_ids(&self) -> Vec<SpoolId> {
+        let mut seen = HashSet::new();
+        self.orphaned_data
+            .iter()
+            .chain(self.orphaned_meta.iter())
+            .copied()
+            .filter(|id| seen.insert(*id))
+            .collect()
+    }
+}
+
+/// Find entries in the meta and data spools whose ids are not returned
+/// by `queued_ids`, and remove or quarantine them according to params.
+/// `queued_ids` returns the ids of the messages that are currently queued;
+/// it is called once before the spools are listed and once more before
+/// any entry is acted upon.
+pub async fn collect_orphans<F>(
+    meta: &dyn Spool,
+    data: &dyn Spool,
+    queued_ids: F,
+    params: &JanitorParams,
+) -> anyhow::Result<JanitorReport>
+where
+    F: Fn() -> HashSet<SpoolId>,
+{
+    let cutoff = Utc::now()
+        - chrono::Duration::from_std(params.grace_period).context("invalid grace_period")?;
+
+    let queued = queued_ids();
+    let mut report = JanitorReport::default();
+    report.orphaned_meta = meta.list_ids(cutoff).await?;
+    report.orphaned_meta.retain(|id| !queued.contains(id));
+    report.orphaned_data = data.list_ids(cutoff).await?;
+    report.orphaned_data.retain(|id| !queued.contains(id));
+
+    // The listing is not atomic with respect to concurrent
+    // activity, so confirm that each entry is still an orphan
+    let queued = queued_ids();
+    report.orphaned_meta.retain(|id| !queued.contains(id));
+    report.orphaned_data.retain(|id| !queued.contains(id));
+
+    report.orphaned_data.sort_by_key(|id| id.created());
+    report.orphaned_meta.sort_by_key(|id| id.created());
+
+    if params.dry_run {
+        return Ok(report);
+    }
+
+    if let Some(dir) = &params.quarantine_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating quarantine dir {}", dir.display()))?;
+    }
+
+    for id in report.orphaned_ids() {
+        match &params.quarantine_dir {
+            Some(dir) => {
+                quarantine(meta, data, id, dir).await?;
+                report.num_quarantined += 1;
+            }
+            None => {
+                // The entry may legitimately not exist in one of
+                // the spools, so we don't care if these fail
+                data.remove(id).await.ok();
+                meta.remove(id).await.ok();
+                report.num_removed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::Handle;
+
+    async fn store(spool: &dyn Spool, id: SpoolId, data: &[u8]) -> anyhow::Result<()> {
+        spool
+            .store(id, Arc::new(data.to_vec().into_boxed_slice()), false)
+            .await
+    }
+
+    #[tokio::test]
+    async fn janitor() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let meta = LocalDiskSpool::new(&location.path().join("meta"), false, Handle::current())?;
+        let data = LocalDiskSpool::new(&location.path().join("data"), false, Handle::current())?;
+
+        let queued = SpoolId::new();
+        store(&meta, queued, b"{}").await?;
+        store(&data, queued, b"hello").await?;
+
+        let unqueued = SpoolId::new();
+        store(&meta, unqueued, b"{}").await?;
+        store(&data, unqueued, b"not queued").await?;
+
+        let no_meta = SpoolId::new();
+        store(&data, no_meta, b"no meta").await?;
+
+        // A file that is being written by a concurrent store
+        let in_flight = location.path().join("data/new/in-flight");
+        std::fs::write(&in_flight, b"partial")?;
+
+        let queue: HashSet<SpoolId> = [queued].into_iter().collect();
+        let queued_ids = || queue.clone();
+
+        // Nothing is old enough to be considered
+        let report = collect_orphans(
+            &meta,
+            &data,
+            queued_ids,
+            &JanitorParams {
+                grace_period: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(report.num_orphans(), 0);
+
+        let report = collect_orphans(
+            &meta,
+            &data,
+            queued_ids,
+            &JanitorParams {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(report.orphaned_data, vec![unqueued, no_meta]);
+        assert_eq!(report.orphaned_meta, vec![unqueued]);
+        assert_eq!(report.num_orphans(), 2);
+        assert_eq!(report.num_removed, 0);
+        assert_eq!(data.load(no_meta).await?, b"no meta");
+
+        let report = collect_orphans(&meta, &data, queued_ids, &JanitorParams::default()).await?;
+        assert_eq!(report.num_orphans(), 2);
+        assert_eq!(report.num_removed, 2);
+        assert!(data.load(no_meta).await.is_err());
+        assert!(data.load(unqueued).await.is_err());
+        assert!(meta.load(unqueued).await.is_err());
+
+        // The queued entry is untouched
+        assert_eq!(data.load(queued).await?, b"hello");
+        assert_eq!(meta.load(queued).await?, b"{}");
+
+        // as is the file being written by a concurrent store
+        assert_eq!(std::fs::read(&in_flight)?, b"partial");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn janitor_concurrent_activity() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let meta = LocalDiskSpool::new(&location.path().join("meta"), false, Handle::current())?;
+        let data = LocalDiskSpool::new(&location.path().join("data"), false, Handle::current())?;
+
+        // Delivered, dropped and removed from the spool while the janitor runs
+        let delivered = SpoolId::new();
+        // Dropped from the queue, leaving its entries behind, while the janitor runs
+        let abandoned = SpoolId::new();
+        // In transit between queues when the janitor starts
+        let moving = SpoolId::new();
+        for id in [delivered, abandoned, moving] {
+            store(&meta, id, b"{}").await?;
+            store(&data, id, b"hello").await?;
+        }
+
+        let calls = AtomicUsize::new(0);
+        let queued_ids = || -> HashSet<SpoolId> {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                return [delivered, abandoned].into_iter().collect();
+            }
+            tokio::task::block_in_place(|| {
+                Handle::current().block_on(async {
+                    data.remove(delivered).await.unwrap();
+                    meta.remove(delivered).await.unwrap();
+                })
+            });
+            [moving].into_iter().collect()
+        };
+
+        let report = collect_orphans(&meta, &data, queued_ids, &JanitorParams::default()).await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(report.num_orphans(), 0);
+        assert_eq!(report.num_removed, 0);
+        assert_eq!(data.load(moving).await?, b"hello");
+        assert_eq!(data.load(abandoned).await?, b"hello");
+        assert!(data.load(delivered).await.is_err());
+
+        // The abandoned entry is found by the next run
+        let queued_ids = || -> HashSet<SpoolId> { [moving].into_iter().collect() };
+        let report = collect_orphans(&meta, &data, queued_ids, &JanitorParams::default()).await?;
+        assert_eq!(report.orphaned_data, vec![abandoned]);
+        assert_eq!(report.num_removed, 1);
+        assert!(data.load(abandoned).await.is_err());
+        assert!(meta.load(abandoned).await.is_err());
+
+        Ok(())
+    }
+}
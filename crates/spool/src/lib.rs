@@ -1,9 +1,17 @@
+use anyhow::Context;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use flume::Sender;
+use std::path::Path;
 use std::sync::{Arc, OnceLock};
+use tokio::io::AsyncReadExt;
 
+pub mod compressed;
+pub mod dedup;
+pub mod fsck;
+pub mod janitor;
 pub mod local_disk;
+pub mod quota;
 #[cfg(feature = "rocksdb")]
 pub mod rocks;
 pub mod spool_id;
@@ -32,6 +40,30 @@ pub trait Spool: Send + Sync {
         force_sync: bool,
     ) -> anyhow::Result<()>;
 
+    /// Write/Replace the data associated with the provided Id with
+    /// prefix followed by the content of the file at path, which is
+    /// left in place.
+    /// The default implementation reads the file into memory and
+    /// calls store; implementations that can copy the file without
+    /// holding all of it in memory should override it.
+    async fn store_file(
+        &self,
+        id: SpoolId,
+        prefix: &[u8],
+        path: &Path,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        let mut data = prefix.to_vec();
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("failed to open {path:?} to store {id}"))?;
+        file.read_to_end(&mut data)
+            .await
+            .with_context(|| format!("failed to read {path:?} to store {id}"))?;
+        self.store(id, Arc::new(data.into_boxed_slice()), force_sync)
+            .await
+    }
+
     /// Scan the contents of the spool, and emit a SpoolEntry for each item
     /// to the provided channel sender.
     /// The items are enumerated in an unspecified order.
@@ -45,6 +77,15 @@ pub trait Spool: Send + Sync {
         start_time: DateTime<Utc>,
     ) -> anyhow::Result<()>;
 
+    /// Return the ids of the items in the spool that were created
+    /// before start_time, in an unspecified order.
+    ///
+    /// Unlike enumerate, this does not read the data of the items and
+    /// does not modify the spool, so it is safe to call concurrently
+    /// with load/remove/store operations, although the result may or
+    /// may not reflect those that are in progress.
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>>;
+
     /// Perform some periodic cleanup/maintenance
     async fn cleanup(&self) -> anyhow::Result<()>;
 
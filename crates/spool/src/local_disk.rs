@@ -133,6 +133,53 @@ impl Spool for LocalDiskSpool {
             .await?
     }
 
+    async fn store_file(
+        &self,
+        id: SpoolId,
+        prefix: &[u8],
+        source: &Path,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        let path = self.compute_path(id);
+        let new_dir = self.path.join("new");
+        let flush = force_sync || self.flush;
+        let prefix = prefix.to_vec();
+        let source = source.to_path_buf();
+        tokio::task::Builder::new()
+            .name("LocalDiskSpool store_file")
+            .spawn_blocking_on(
+                move || {
+                    let mut temp = NamedTempFile::new_in(new_dir).with_context(|| {
+                        format!("failed to create a temporary file to store {id}")
+                    })?;
+
+                    temp.write_all(&prefix)
+                        .with_context(|| format!("failed to write data for {id}"))?;
+                    let mut source = File::open(&source)
+                        .with_context(|| format!("failed to open {source:?} to store {id}"))?;
+                    std::io::copy(&mut source, temp.as_file_mut())
+                        .with_context(|| format!("failed to write data for {id}"))?;
+
+                    if flush {
+                        temp.as_file_mut()
+                            .sync_data()
+                            .with_context(|| format!("failed to sync data for {id}"))?;
+                    }
+
+                    std::fs::create_dir_all(path.parent().unwrap()).with_context(|| {
+                        format!("failed to create dir structure for {id} {path:?}")
+                    })?;
+
+                    temp.persist(&path).with_context(|| {
+                        format!("failed to move temp file for {id} to {path:?}")
+                    })?;
+                    Ok(())
+                },
+                &self.runtime,
+            )?
+            .await?
+    }
+
     fn enumerate(
         &self,
         sender: Sender<SpoolEntry>,
@@ -187,6 +234,32 @@ impl Spool for LocalDiskSpool {
         Ok(())
     }
 
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>> {
+        let data_dir = self.path.join("data");
+        Ok(tokio::task::Builder::new()
+            .name("LocalDiskSpool list_ids")
+            .spawn_blocking_on(
+                move || {
+                    let mut ids = vec![];
+                    for entry in jwalk::WalkDir::new(data_dir) {
+                        if let Ok(entry) = entry {
+                            if !entry.file_type().is_file() {
+                                continue;
+                            }
+                            if let Some(id) = SpoolId::from_path(&entry.path()) {
+                                if id.created() < start_time {
+                                    ids.push(id);
+                                }
+                            }
+                        }
+                    }
+                    ids
+                },
+                &self.runtime,
+            )?
+            .await?)
+    }
+
     async fn cleanup(&self) -> anyhow::Result<()> {
         let data_dir = self.path.join("data");
         Ok(tokio::task::Builder::new()
@@ -374,4 +447,29 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn store_file() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let spool = LocalDiskSpool::new(&location.path(), false, Handle::current())?;
+
+        let source = location.path().join("source");
+        std::fs::write(&source, b"the body\r\n")?;
+
+        let id = SpoolId::new();
+        spool
+            .store_file(id, b"Received: somewhere\r\n", &source, false)
+            .await?;
+
+        let data = spool.load(id).await?;
+        assert_eq!(
+            String::from_utf8(data)?,
+            "Received: somewhere\r\nthe body\r\n"
+        );
+
+        // The source file belongs to the caller and is left alone
+        assert!(source.exists());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,387 @@
+use crate::{Spool, SpoolEntry, SpoolId};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use flume::Sender;
+use prometheus::{IntGauge, IntGaugeVec};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Weak};
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+/// The number of spools that are currently over their soft quota
+static NUM_OVER_SOFT_QUOTA: AtomicUsize = AtomicUsize::new(0);
+
+static USED_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "spool_used_bytes",
+        "Approximate number of bytes of storage used by a spool",
+        &["spool"]
+    )
+    .unwrap()
+});
+static QUOTA_STATE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    prometheus::register_int_gauge_vec!(
+        "spool_quota_state",
+        "Quota state of a spool. 0 is normal, 1 is over the soft limit, \
+         2 is over the hard limit",
+        &["spool"]
+    )
+    .unwrap()
+});
+
+/// Returns true if any spool is currently over its soft (or hard) quota
+pub fn is_over_soft_quota() -> bool {
+    NUM_OVER_SOFT_QUOTA.load(Ordering::SeqCst) > 0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaState {
+    /// Usage is below the soft limit
+    Normal = 0,
+    /// Usage is above the soft limit; reception should be paused
+    Soft = 1,
+    /// Usage is above the hard limit; writes are refused
+    Hard = 2,
+}
+
+impl QuotaState {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            0 => Self::Normal,
+            1 => Self::Soft,
+            _ => Self::Hard,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SpoolQuota {
+    /// When usage exceeds this many bytes, new reception
+    /// should be deferred until usage drops below it again
+    #[serde(default)]
+    pub soft_limit: Option<u64>,
+    /// When usage exceeds this many bytes, further writes
+    /// to the spool will fail
+    #[serde(default)]
+    pub hard_limit: Option<u64>,
+}
+
+impl SpoolQuota {
+    pub fn is_enabled(&self) -> bool {
+        self.soft_limit.is_some() || self.hard_limit.is_some()
+    }
+
+    fn compute_state(&self, used: u64) -> QuotaState {
+        if matches!(self.hard_limit, Some(limit) if used >= limit) {
+            QuotaState::Hard
+        } else if matches!(self.soft_limit, Some(limit) if used >= limit) {
+            QuotaState::Soft
+        } else {
+            QuotaState::Normal
+        }
+    }
+}
+
+pub type QuotaStateCallback = Box<dyn Fn(&str, QuotaState, u64) + Send + Sync>;
+
+struct QuotaTracker {
+    name: String,
+    quota: SpoolQuota,
+    used: AtomicU64,
+    state: AtomicU8,
+    used_gauge: IntGauge,
+    state_gauge: IntGauge,
+    on_state_change: QuotaStateCallback,
+}
+
+impl QuotaTracker {
+    /// Update the state based on the current usage,
+    /// and notify if it has changed
+    fn update_state(&self) {
+        let used = self.used.load(Ordering::SeqCst);
+        self.used_gauge.set(used as i64);
+        let new_state = self.quota.compute_state(used);
+        let prior = QuotaState::from_u8(self.state.swap(new_state as u8, Ordering::SeqCst));
+        if prior == new_state {
+            return;
+        }
+        self.state_gauge.set(new_state as i64);
+
+        match (prior, new_state) {
+            (QuotaState::Normal, _) => {
+                NUM_OVER_SOFT_QUOTA.fetch_add(1, Ordering::SeqCst);
+            }
+            (_, QuotaState::Normal) => {
+                NUM_OVER_SOFT_QUOTA.fetch_sub(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+
+        tracing::error!(
+            "spool {} quota state changed from {prior:?} to {new_state:?}, \
+             {used} bytes used",
+            self.name
+        );
+        (self.on_state_change)(&self.name, new_state, used);
+    }
+
+    fn state(&self) -> QuotaState {
+        QuotaState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+}
+
+/// Compute the number of bytes used by the files under path
+pub fn compute_dir_size(path: &Path) -> u64 {
+    let mut total = 0;
+    for entry in jwalk::WalkDir::new(path).into_iter().flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Ok(meta) = entry.metadata() {
+            total += meta.len();
+        }
+    }
+    total
+}
+
+/// Wraps another Spool implementation, tracking the amount
+/// of storage that it uses and enforcing soft and hard limits.
+///
+/// Usage is measured by periodically walking the spool directory.
+/// In between measurements, the size of stored items is added to
+/// the running total, so that the estimate errs on the side of
+/// over-counting during bursts of writes.
+pub struct QuotaSpool {
+    inner: Arc<dyn Spool + Send + Sync>,
+    path: PathBuf,
+    tracker: Arc<QuotaTracker>,
+}
+
+impl QuotaSpool {
+    pub fn new(
+        name: &str,
+        path: &Path,
+        inner: Arc<dyn Spool + Send + Sync>,
+        quota: SpoolQuota,
+        on_state_change: QuotaStateCallback,
+    ) -> Self {
+        let tracker = Arc::new(QuotaTracker {
+            name: name.to_string(),
+            quota,
+            used: AtomicU64::new(0),
+            state: AtomicU8::new(QuotaState::Normal as u8),
+            used_gauge: USED_BYTES.get_metric_with_label_values(&[name]).unwrap(),
+            state_gauge: QUOTA_STATE.get_metric_with_label_values(&[name]).unwrap(),
+            on_state_change,
+        });
+
+        Self {
+            inner,
+            path: path.to_path_buf(),
+            tracker,
+        }
+    }
+
+    /// Spawn a task that will periodically re-measure the
+    /// storage used by the spool
+    pub fn spawn_monitor(&self, check_interval: Duration, runtime: &Handle) {
+        runtime.spawn(quota_monitor(
+            Arc::downgrade(&self.tracker),
+            self.path.clone(),
+            check_interval,
+        ));
+    }
+
+    fn check_hard_limit(&self, id: SpoolId) -> anyhow::Result<()> {
+        if self.tracker.state() == QuotaState::Hard {
+            anyhow::bail!(
+                "spool {} is over its hard quota of {} bytes; cannot store {id}",
+                self.tracker.name,
+                self.tracker.quota.hard_limit.unwrap_or(0)
+            );
+        }
+        Ok(())
+    }
+
+    /// Re-measure the storage used by the spool
+    pub async fn refresh_usage(&self) -> anyhow::Result<()> {
+        refresh_usage(&self.tracker, self.path.clone()).await
+    }
+
+    pub fn state(&self) -> QuotaState {
+        self.tracker.state()
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.tracker.used.load(Ordering::SeqCst)
+    }
+}
+
+async fn refresh_usage(tracker: &QuotaTracker, path: PathBuf) -> anyhow::Result<()> {
+    let before = tracker.used.load(Ordering::SeqCst);
+    let scanned = tokio::task::spawn_blocking(move || compute_dir_size(&path)).await?;
+    // Preserve any writes that were accounted while we were scanning;
+    // some of them may also have been picked up by the scan, but
+    // over-counting is preferable to under-counting here
+    let during_scan = tracker.used.load(Ordering::SeqCst).saturating_sub(before);
+    tracker.used.store(scanned + during_scan, Ordering::SeqCst);
+    tracker.update_state();
+    Ok(())
+}
+
+async fn quota_monitor(tracker: Weak<QuotaTracker>, path: PathBuf, interval: Duration) {
+    loop {
+        match tracker.upgrade() {
+            Some(tracker) => {
+                if let Err(err) = refresh_usage(&tracker, path.clone()).await {
+                    tracing::error!("error computing size of spool {}: {err:#}", tracker.name);
+                }
+            }
+            None => {
+                // Dead
+                return;
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[async_trait]
+impl Spool for QuotaSpool {
+    async fn load(&self, id: SpoolId) -> anyhow::Result<Vec<u8>> {
+        self.inner.load(id).await
+    }
+
+    async fn remove(&self, id: SpoolId) -> anyhow::Result<()> {
+        self.inner.remove(id).await
+    }
+
+    async fn store(
+        &self,
+        id: SpoolId,
+        data: Arc<Box<[u8]>>,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        self.check_hard_limit(id)?;
+        let size = data.len() as u64;
+        self.inner.store(id, data, force_sync).await?;
+        self.tracker.used.fetch_add(size, Ordering::SeqCst);
+        self.tracker.update_state();
+        Ok(())
+    }
+
+    async fn store_file(
+        &self,
+        id: SpoolId,
+        prefix: &[u8],
+        path: &Path,
+        force_sync: bool,
+    ) -> anyhow::Result<()> {
+        self.check_hard_limit(id)?;
+        let size = prefix.len() as u64 + tokio::fs::metadata(path).await?.len();
+        self.inner.store_file(id, prefix, path, force_sync).await?;
+        self.tracker.used.fetch_add(size, Ordering::SeqCst);
+        self.tracker.update_state();
+        Ok(())
+    }
+
+    fn enumerate(
+        &self,
+        sender: Sender<SpoolEntry>,
+        start_time: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        self.inner.enumerate(sender, start_time)
+    }
+
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>> {
+        self.inner.list_ids(start_time).await
+    }
+
+    async fn cleanup(&self) -> anyhow::Result<()> {
+        self.inner.cleanup().await
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    async fn advise_low_memory(&self) -> anyhow::Result<isize> {
+        self.inner.advise_low_memory().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::local_disk::LocalDiskSpool;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn quota_spool() -> anyhow::Result<()> {
+        let location = tempfile::tempdir()?;
+        let disk: Arc<dyn Spool + Send + Sync> = Arc::new(LocalDiskSpool::new(
+            &location.path(),
+            false,
+            Handle::current(),
+        )?);
+
+        let transitions = Arc::new(Mutex::new(vec![]));
+        let spool = QuotaSpool::new(
+            "test",
+            location.path(),
+            disk,
+            SpoolQuota {
+                soft_limit: Some(100),
+                hard_limit: Some(200),
+            },
+            Box::new({
+                let transitions = transitions.clone();
+                move |_name, state, _used| transitions.lock().unwrap().push(state)
+            }),
+        );
+        spool.refresh_usage().await?;
+        // The only thing in the spool is the small lock file
+        assert!(spool.used_bytes() < 50);
+
+        let store = |size: usize| {
+            let id = SpoolId::new();
+            let data = Arc::new(vec![b'a'; size].into_boxed_slice());
+            let spool = &spool;
+            async move { spool.store(id, data, false).await }
+        };
+
+        store(50).await?;
+        assert_eq!(spool.state(), QuotaState::Normal);
+
+        store(60).await?;
+        assert_eq!(spool.state(), QuotaState::Soft);
+        assert!(is_over_soft_quota());
+
+        store(100).await?;
+        assert_eq!(spool.state(), QuotaState::Hard);
+
+        // Writes are refused while over the hard limit
+        assert!(store(1).await.is_err());
+
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            vec![QuotaState::Soft, QuotaState::Hard]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compute_state() {
+        let quota = SpoolQuota {
+            soft_limit: Some(10),
+            hard_limit: None,
+        };
+        assert_eq!(quota.compute_state(0), QuotaState::Normal);
+        assert_eq!(quota.compute_state(10), QuotaState::Soft);
+        assert_eq!(quota.compute_state(1000), QuotaState::Soft);
+    }
+}
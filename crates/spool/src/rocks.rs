@@ -13,7 +13,7 @@ use std::sync::{Arc, LazyLock, Weak};
 use std::time::Duration;
 use tokio::runtime::Handle;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RocksSpoolParams {
     pub increase_parallelism: Option<i32>,
 
@@ -87,7 +87,7 @@ impl RocksSpoolParams {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DBCompressionTypeDef {
     None,
     Snappy,
@@ -118,7 +118,7 @@ impl Default for DBCompressionTypeDef {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum LogLevelDef {
     Debug,
     Info,
@@ -279,6 +279,33 @@ impl Spool for RocksSpool {
         }
     }
 
+    async fn list_ids(&self, start_time: DateTime<Utc>) -> anyhow::Result<Vec<SpoolId>> {
+        let db = self.db.clone();
+        tokio::task::Builder::new()
+            .name("rocksdb list_ids")
+            .spawn_blocking_on(
+                move || -> anyhow::Result<Vec<SpoolId>> {
+                    let mut ids = vec![];
+                    // The raw iterator allows us to visit just the keys,
+                    // without copying the values
+                    let mut iter = db.raw_iterator();
+                    iter.seek_to_first();
+                    while let Some(key) = iter.key() {
+                        let id = SpoolId::from_slice(key)
+                            .ok_or_else(|| anyhow::anyhow!("invalid spool id {key:?}"))?;
+                        if id.created() < start_time {
+                            ids.push(id);
+                        }
+                        iter.next();
+                    }
+                    iter.status()?;
+                    Ok(ids)
+                },
+                &self.runtime,
+            )?
+            .await?
+    }
+
     async fn cleanup(&self) -> anyhow::Result<()> {
         Ok(())
     }
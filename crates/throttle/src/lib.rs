@@ -59,12 +59,26 @@ mod redis {
             .map_err(|_| Error::Generic("redis already configured for throttles".to_string()))?;
         Ok(())
     }
+
+    /// Verifies connectivity to the redis server used for throttles.
+    /// Returns false if throttles have not been configured to use redis.
+    pub async fn ping_redis() -> Result<bool, Error> {
+        match REDIS.get() {
+            Some(cx) => {
+                let mut cmd = Cmd::new();
+                cmd.arg("PING");
+                cx.query(cmd).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 }
 
-#[cfg(feature = "redis")]
-pub use redis::use_redis;
 #[cfg(feature = "redis")]
 pub(crate) use redis::REDIS;
+#[cfg(feature = "redis")]
+pub use redis::{ping_redis, use_redis};
 
 #[derive(Error, Debug)]
 pub enum Error {
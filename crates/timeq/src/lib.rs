@@ -16,6 +16,9 @@ pub struct TimeQ<EntryType: TimerEntryWithDelay> {
     start: Instant,
     last_check: u128,
     len: usize,
+    /// Items that were already due when they were re-inserted
+    /// by `snapshot`; they are returned by the next `pop`
+    due: Vec<EntryType>,
 }
 
 #[must_use]
@@ -35,6 +38,7 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
             start: Instant::now(),
             last_check: 0,
             len: 0,
+            due: vec![],
         }
     }
 
@@ -47,7 +51,7 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
 
     /// Returns true if the wheel is empty
     pub fn is_empty(&self) -> bool {
-        matches!(self.wheel.can_skip(), Skip::Empty)
+        self.due.is_empty() && matches!(self.wheel.can_skip(), Skip::Empty)
     }
 
     pub fn len(&self) -> usize {
@@ -63,10 +67,9 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
 
     /// Returns the set of items that need immediate action
     pub fn pop(&mut self) -> PopResult<EntryType> {
+        let mut items = std::mem::take(&mut self.due);
         let elapsed = self.elapsed();
         if elapsed > 0 {
-            let mut items = vec![];
-
             let mut elapsed = elapsed as u32;
             while elapsed > 0 {
                 match self.wheel.can_skip() {
@@ -82,11 +85,11 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
                     }
                 }
             }
+        }
 
-            if !items.is_empty() {
-                self.len -= items.len();
-                return PopResult::Items(items);
-            }
+        if !items.is_empty() {
+            self.len -= items.len();
+            return PopResult::Items(items);
         }
 
         match self.wheel.can_skip() {
@@ -99,7 +102,7 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
     /// Drains the entire contents of the queue, returning all of the
     /// contained items
     pub fn drain(&mut self) -> Vec<EntryType> {
-        let mut items = vec![];
+        let mut items = std::mem::take(&mut self.due);
         loop {
             match self.wheel.can_skip() {
                 Skip::Empty => {
@@ -118,6 +121,28 @@ impl<EntryType: TimerEntryWithDelay> TimeQ<EntryType> {
         }
         items
     }
+
+    /// Returns a copy of each of the contained items, leaving them
+    /// in the queue.  Every item is removed and re-inserted, so this
+    /// is intended for occasional diagnostic use rather than for
+    /// use in a hot path.
+    pub fn snapshot(&mut self) -> Vec<EntryType>
+    where
+        EntryType: Clone,
+    {
+        let items = self.drain();
+        let mut copies = Vec::with_capacity(items.len());
+        for item in items {
+            copies.push(item.clone());
+            match self.wheel.insert(item) {
+                Ok(()) => {}
+                Err(TimerError::Expired(item)) => self.due.push(item),
+                Err(TimerError::NotFound) => unreachable!(),
+            }
+            self.len += 1;
+        }
+        copies
+    }
 }
 
 #[cfg(test)]
@@ -166,6 +191,33 @@ mod tests {
         assert_eq!(items, vec![&item1, &item3, &item2]);
     }
 
+    #[test]
+    fn snapshot() {
+        let item1 = Entry {
+            id: 1,
+            value: "foo",
+            delay: Duration::from_millis(1),
+        };
+        let item2 = Entry {
+            id: 2,
+            value: "bar",
+            delay: Duration::from_millis(10),
+        };
+
+        let mut queue = TimeQ::new();
+        queue.insert(&item1).unwrap();
+        queue.insert(&item2).unwrap();
+
+        let items = queue.snapshot();
+        assert_eq!(items, vec![&item1, &item2]);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.is_empty(), false);
+
+        let items = queue.drain();
+        assert_eq!(queue.len(), 0);
+        assert_eq!(items, vec![&item1, &item2]);
+    }
+
     #[test]
     fn basic_queue() {
         let mut queue = TimeQ::new();
@@ -91,6 +91,7 @@ async fn main() -> anyhow::Result<()> {
                         openssl_cipher_list: probe.openssl_cipher_list,
                         openssl_cipher_suites: probe.openssl_cipher_suites,
                         openssl_options: probe.openssl_options,
+                        session_cache_key: None,
                     })
                     .await?;
                 println!("{tls_result:?}");
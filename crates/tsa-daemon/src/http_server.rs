@@ -1,4 +1,5 @@
 use crate::publish::submit_record;
+use crate::redis_state::{ConfigOverride, Decision, PoolOverride};
 use crate::shaping_config::get_shaping;
 use anyhow::{anyhow, Context};
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
@@ -6,13 +7,15 @@ use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use chrono::{DateTime, Utc};
-use kumo_api_types::shaping::{Action, EgressPathConfigValue, Regex, Rule, Shaping, Trigger};
+use kumo_api_types::shaping::{
+    record_site_name, Action, BounceRate, EgressPathConfigValue, Rule, Shaping, Trigger,
+};
 use kumo_api_types::tsa::{
-    ReadyQSuspension, SchedQBounce, SchedQSuspension, SubscriptionItem, SuspensionEntry,
-    Suspensions,
+    DryRunDecision, ReadyQSuspension, SchedQBounce, SchedQSuspension, SubscriptionItem,
+    SuspensionEntry, Suspensions,
 };
 use kumo_log_types::*;
-use kumo_server_common::http_server::auth::TrustedIpRequired;
+use kumo_server_common::http_server::auth::AdminRequired;
 use kumo_server_common::http_server::{AppError, RouterAndDocs};
 use message::message::QueueNameComponents;
 use rfc5321::ForwardPath;
@@ -22,6 +25,7 @@ use sha2::{Digest, Sha256};
 use sqlite::{Connection, ConnectionThreadSafe};
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{LazyLock, Mutex};
 use tokio::sync::broadcast::{channel, Sender};
 use toml_edit::{value, Value as TomlValue};
@@ -31,6 +35,9 @@ pub static DB_PATH: LazyLock<Mutex<String>> =
     LazyLock::new(|| Mutex::new("/var/spool/kumomta/tsa.db".to_string()));
 static HISTORY: LazyLock<ConnectionThreadSafe> = LazyLock::new(|| open_history_db().unwrap());
 static SUSPENSION_TX: LazyLock<SubscriberMgr> = LazyLock::new(|| SubscriberMgr::new());
+/// When true, all automation rules behave as though they were
+/// configured with dry_run = true
+pub static DRY_RUN: AtomicBool = AtomicBool::new(false);
 
 pub fn open_history_db() -> anyhow::Result<ConnectionThreadSafe> {
     let path = DB_PATH.lock().unwrap().clone();
@@ -88,6 +95,34 @@ CREATE TABLE IF NOT EXISTS sched_q_bounces (
     expires DATETIME,
     PRIMARY KEY (rule_hash, campaign, tenant, domain)
 );
+
+CREATE TABLE IF NOT EXISTS outcome_history (
+    condition_hash text,
+    record_hash text,
+    bounced bool,
+    ts int,
+    PRIMARY KEY (condition_hash, record_hash)
+);
+
+CREATE TABLE IF NOT EXISTS pool_overrides (
+    rule_hash text,
+    domain text,
+    pool text,
+    reason text,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, domain)
+);
+
+CREATE TABLE IF NOT EXISTS dry_run_decisions (
+    rule_hash text,
+    action text,
+    reason text,
+    first_triggered DATETIME,
+    last_triggered DATETIME,
+    count int,
+    expires DATETIME,
+    PRIMARY KEY (rule_hash, action)
+);
     "#;
 
     db.execute(query)?;
@@ -112,6 +147,10 @@ pub fn make_router() -> RouterAndDocs {
             .route("/get_suspension_v1/suspended.json", get(get_suspension_v1))
             .route("/subscribe_suspension_v1", get(subscribe_suspension_v1))
             .route("/get_bounce_v1/bounced.json", get(get_bounce_v1))
+            .route(
+                "/get_dry_run_decisions_v1/decisions.json",
+                get(get_dry_run_decisions_v1),
+            )
             .route("/subscribe_event_v1", get(subscribe_event_v1)),
         docs: ApiDoc::openapi(),
     }
@@ -132,6 +171,7 @@ fn create_config(
     domain: &str,
     source: &str,
     prefer_rollup: PreferRollup,
+    decisions: &mut Vec<Decision>,
 ) -> anyhow::Result<()> {
     let mut upsert = db.prepare(
         "INSERT INTO config
@@ -142,47 +182,38 @@ fn create_config(
                  DO UPDATE SET expires=$expires",
     )?;
 
-    let expires = (record.timestamp + chrono::Duration::from_std(rule.duration)?).to_rfc3339();
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+    let mx_rollup = prefer_rollup == PreferRollup::Yes && rule.was_rollup;
 
     upsert.bind(("$hash", rule_hash))?;
     upsert.bind(("$site", record.site.as_str()))?;
     upsert.bind(("$domain", domain))?;
-    upsert.bind((
-        "$mx_rollup",
-        if prefer_rollup == PreferRollup::Yes && rule.was_rollup {
-            1
-        } else {
-            0
-        },
-    ))?;
+    upsert.bind(("$mx_rollup", if mx_rollup { 1 } else { 0 }))?;
     upsert.bind(("$source", source))?;
     upsert.bind(("$name", config.name.as_str()))?;
     let value = serde_json::to_string(&config.value)?;
     upsert.bind(("$value", value.as_str()))?;
 
-    let reason = format!("automation rule: {}", regex_list_to_string(&rule.regex));
+    let reason = format!("automation rule: {}", rule.describe());
     upsert.bind(("$reason", reason.as_str()))?;
-    upsert.bind(("$expires", expires.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
 
     upsert.next()?;
 
-    Ok(())
-}
+    decisions.push(Decision::Config(ConfigOverride {
+        rule_hash: rule_hash.to_string(),
+        site_name: record.site.to_string(),
+        domain: domain.to_string(),
+        mx_rollup,
+        source: source.to_string(),
+        name: config.name.clone(),
+        value,
+        reason,
+        expires,
+    }));
 
-fn regex_list_to_string(list: &[Regex]) -> String {
-    if list.len() == 1 {
-        list[0].to_string()
-    } else {
-        let mut result = "(".to_string();
-        for (n, r) in list.iter().enumerate() {
-            if n > 0 {
-                result.push(',');
-            }
-            result.push_str(&r.to_string());
-        }
-        result.push(')');
-        result
-    }
+    Ok(())
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -204,7 +235,7 @@ fn create_bounce(
     record: &JsonLogRecord,
     use_tenant: UseTenant,
     use_campaign: UseCampaign,
-    events: &mut Vec<SubscriptionItem>,
+    decisions: &mut Vec<Decision>,
 ) -> anyhow::Result<()> {
     let components = QueueNameComponents::parse(&record.queue);
 
@@ -248,7 +279,7 @@ fn create_bounce(
 
     let mut reason = format!(
         "automation rule: {} domain={}",
-        regex_list_to_string(&rule.regex),
+        rule.describe(),
         components.domain
     );
     if let Some(tenant) = &tenant {
@@ -262,7 +293,7 @@ fn create_bounce(
 
     upsert.next().context("execute sched_q_bounces upsert")?;
 
-    events.push(SubscriptionItem::SchedQBounce(SchedQBounce {
+    decisions.push(Decision::SchedQBounce(SchedQBounce {
         rule_hash: rule_hash.to_string(),
         domain: components.domain.to_string(),
         tenant: tenant.map(|s| s.to_string()),
@@ -280,7 +311,7 @@ fn create_tenant_suspension(
     rule: &Rule,
     record: &JsonLogRecord,
     use_campaign: UseCampaign,
-    events: &mut Vec<SubscriptionItem>,
+    decisions: &mut Vec<Decision>,
 ) -> anyhow::Result<()> {
     let components = QueueNameComponents::parse(&record.queue);
     let Some(tenant) = components.tenant else {
@@ -319,7 +350,7 @@ fn create_tenant_suspension(
 
     let mut reason = format!(
         "automation rule: {} tenant={tenant} domain={}",
-        regex_list_to_string(&rule.regex),
+        rule.describe(),
         components.domain
     );
     if let Some(campaign) = &campaign {
@@ -332,7 +363,7 @@ fn create_tenant_suspension(
         .next()
         .context("execute sched_q_suspensions upsert")?;
 
-    events.push(SubscriptionItem::SchedQSuspension(SchedQSuspension {
+    decisions.push(Decision::SchedQSuspension(SchedQSuspension {
         rule_hash: rule_hash.to_string(),
         domain: components.domain.to_string(),
         tenant: tenant.to_string(),
@@ -350,7 +381,7 @@ fn create_ready_q_suspension(
     rule: &Rule,
     record: &JsonLogRecord,
     source: &str,
-    events: &mut Vec<SubscriptionItem>,
+    decisions: &mut Vec<Decision>,
 ) -> anyhow::Result<()> {
     let mut upsert = db.prepare(
         "INSERT INTO ready_q_suspensions
@@ -368,13 +399,13 @@ fn create_ready_q_suspension(
     upsert.bind(("$site", record.site.as_str()))?;
     upsert.bind(("$source", source))?;
 
-    let reason = format!("automation rule: {}", regex_list_to_string(&rule.regex));
+    let reason = format!("automation rule: {}", rule.describe());
     upsert.bind(("$reason", reason.as_str()))?;
     upsert.bind(("$expires", expires_str.as_str()))?;
 
     upsert.next()?;
 
-    events.push(SubscriptionItem::ReadyQSuspension(ReadyQSuspension {
+    decisions.push(Decision::ReadyQSuspension(ReadyQSuspension {
         rule_hash: rule_hash.to_string(),
         site_name: record.site.to_string(),
         reason,
@@ -385,6 +416,243 @@ fn create_ready_q_suspension(
     Ok(())
 }
 
+fn create_pool_override(
+    db: &ConnectionThreadSafe,
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+    domain: &str,
+    pool: &str,
+    decisions: &mut Vec<Decision>,
+) -> anyhow::Result<()> {
+    let mut upsert = db
+        .prepare(
+            "INSERT INTO pool_overrides
+                 (rule_hash, domain, pool, reason, expires)
+                 VALUES
+                 ($hash, $domain, $pool, $reason, $expires)
+                 ON CONFLICT (rule_hash, domain)
+                 DO UPDATE SET expires=$expires",
+        )
+        .context("prepare pool_overrides upsert")?;
+
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$domain", domain))?;
+    upsert.bind(("$pool", pool))?;
+    let reason = format!("automation rule: {}", rule.describe());
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
+
+    upsert.next().context("execute pool_overrides upsert")?;
+
+    decisions.push(Decision::PoolOverride(PoolOverride {
+        rule_hash: rule_hash.to_string(),
+        domain: domain.to_string(),
+        pool: pool.to_string(),
+        reason,
+        expires,
+    }));
+
+    Ok(())
+}
+
+/// Describes the effect of an action, for the purposes of dry-run mode
+async fn describe_action(
+    shaping: &Shaping,
+    action: &Action,
+    record: &JsonLogRecord,
+    domain: &str,
+    source: &str,
+) -> anyhow::Result<String> {
+    let components = QueueNameComponents::parse(&record.queue);
+    let domain_scope = format!("domain={}", components.domain);
+    let mut tenant_scope = domain_scope.clone();
+    if let Some(tenant) = components.tenant {
+        tenant_scope.push_str(&format!(" tenant={tenant}"));
+    }
+    let mut campaign_scope = tenant_scope.clone();
+    if let Some(campaign) = components.campaign {
+        campaign_scope.push_str(&format!(" campaign={campaign}"));
+    }
+
+    Ok(match action {
+        Action::Suspend => format!("suspend ready queue {source}->{}", record.site),
+        Action::SuspendTenant => format!("suspend {tenant_scope}"),
+        Action::SuspendCampaign => format!("suspend {campaign_scope}"),
+        Action::SetConfig(config) => format!(
+            "set {}={} for site {}",
+            config.name, *config.value, record.site
+        ),
+        Action::SetDomainConfig(config) => {
+            format!("set {}={} for domain {domain}", config.name, *config.value)
+        }
+        Action::Bounce => format!("bounce {domain_scope}"),
+        Action::BounceTenant => format!("bounce {tenant_scope}"),
+        Action::BounceCampaign => format!("bounce {campaign_scope}"),
+        Action::ReduceRate { percent } => {
+            match reduced_message_rate(shaping, record, domain, source, *percent).await? {
+                Some(config) => format!(
+                    "set {}={} for site {}",
+                    config.name, *config.value, record.site
+                ),
+                None => format!(
+                    "reduce max_message_rate to {percent}% for site {}, \
+                     but none is configured",
+                    record.site
+                ),
+            }
+        }
+        Action::SwitchPool(pool) => format!("route domain {domain} via egress pool {pool}"),
+    })
+}
+
+/// Records the decision that the rule would have made for the action,
+/// had it not been in dry-run mode. The decision is logged when it
+/// is first made, and again when it is made after it would have expired.
+async fn record_dry_run_decision(
+    db: &ConnectionThreadSafe,
+    shaping: &Shaping,
+    rule_hash: &str,
+    rule: &Rule,
+    record: &JsonLogRecord,
+    action: &Action,
+    domain: &str,
+    source: &str,
+) -> anyhow::Result<()> {
+    let action = describe_action(shaping, action, record, domain, source).await?;
+    let reason = format!("automation rule: {}", rule.describe());
+    let triggered = record.timestamp.to_rfc3339();
+    let expires = record.timestamp + chrono::Duration::from_std(rule.duration)?;
+    let expires_str = expires.to_rfc3339();
+
+    let mut query = db.prepare(
+        "SELECT 1 FROM dry_run_decisions
+         WHERE rule_hash = $hash AND action = $action
+         AND unixepoch(expires) - unixepoch() > 0",
+    )?;
+    query.bind(("$hash", rule_hash))?;
+    query.bind(("$action", action.as_str()))?;
+    let active = matches!(query.next()?, sqlite::State::Row);
+
+    if active {
+        let mut update = db.prepare(
+            "UPDATE dry_run_decisions
+             SET count = count + 1, last_triggered = $triggered, expires = $expires
+             WHERE rule_hash = $hash AND action = $action",
+        )?;
+        update.bind(("$hash", rule_hash))?;
+        update.bind(("$action", action.as_str()))?;
+        update.bind(("$triggered", triggered.as_str()))?;
+        update.bind(("$expires", expires_str.as_str()))?;
+        update.next()?;
+        return Ok(());
+    }
+
+    let mut upsert = db.prepare(
+        "INSERT INTO dry_run_decisions
+             (rule_hash, action, reason, first_triggered, last_triggered, count, expires)
+             VALUES
+             ($hash, $action, $reason, $triggered, $triggered, 1, $expires)
+             ON CONFLICT (rule_hash, action)
+             DO UPDATE SET reason=$reason, first_triggered=$triggered,
+                last_triggered=$triggered, count=1, expires=$expires",
+    )?;
+    upsert.bind(("$hash", rule_hash))?;
+    upsert.bind(("$action", action.as_str()))?;
+    upsert.bind(("$reason", reason.as_str()))?;
+    upsert.bind(("$triggered", triggered.as_str()))?;
+    upsert.bind(("$expires", expires_str.as_str()))?;
+    upsert.next()?;
+
+    tracing::info!("dry-run: would {action} until {expires}; {reason}");
+
+    Ok(())
+}
+
+/// Computes the max_message_rate override for a ReduceRate action,
+/// based on the rate that is configured by the shaping data.
+/// Returns None if no max_message_rate is configured.
+async fn reduced_message_rate(
+    shaping: &Shaping,
+    record: &JsonLogRecord,
+    domain: &str,
+    source: &str,
+    percent: u8,
+) -> anyhow::Result<Option<EgressPathConfigValue>> {
+    let params = shaping
+        .get_egress_path_params(domain, source, &record_site_name(record))
+        .await?;
+    let Some(rate) = params.max_message_rate else {
+        return Ok(None);
+    };
+
+    let reduced = throttle::ThrottleSpec {
+        limit: (rate.limit * percent as u64 / 100).max(1),
+        max_burst: None,
+        ..rate
+    };
+    let value = reduced.as_string().map_err(|err| anyhow!("{err}"))?;
+
+    Ok(Some(EgressPathConfigValue {
+        name: "max_message_rate".to_string(),
+        value: toml::Value::String(value).into(),
+    }))
+}
+
+/// Records whether the record bounced for the purposes of the
+/// BounceRate condition identified by condition_hash, then returns
+/// true if the bounce rate over its window has reached its threshold.
+fn bounce_rate_reached(
+    db: &ConnectionThreadSafe,
+    condition_hash: &str,
+    rate: &BounceRate,
+    record: &JsonLogRecord,
+    record_hash: &str,
+) -> anyhow::Result<bool> {
+    let Some(bounced) = rate.outcome(record.kind) else {
+        return Ok(false);
+    };
+    let window = rate.window.as_secs() as i64;
+
+    let unix: i64 = record.timestamp.format("%s").to_string().parse()?;
+    let mut insert = db.prepare(
+        "INSERT INTO outcome_history (condition_hash, record_hash, bounced, ts)
+         values (?, ?, ?, ?)
+         ON CONFLICT (condition_hash, record_hash) DO NOTHING",
+    )?;
+    insert.bind((1, condition_hash))?;
+    insert.bind((2, record_hash))?;
+    insert.bind((3, bounced as i64))?;
+    insert.bind((4, unix))?;
+    insert.next()?;
+
+    let mut prune = db
+        .prepare("delete from outcome_history where condition_hash = ? and ts < unixepoch() - ?")?;
+    prune.bind((1, condition_hash))?;
+    prune.bind((2, window))?;
+    prune.next()?;
+
+    let mut query = db.prepare(
+        "SELECT COUNT(ts), COALESCE(SUM(bounced), 0) from outcome_history
+         where condition_hash = ? and ts >= unixepoch() - ?",
+    )?;
+    query.bind((1, condition_hash))?;
+    query.bind((2, window))?;
+    query.next()?;
+
+    let total: i64 = query.read(0)?;
+    let bounced: i64 = query.read(1)?;
+
+    if (total as u64) < rate.min_volume || total == 0 {
+        return Ok(false);
+    }
+
+    Ok(bounced as f64 * 100.0 / total as f64 >= rate.percent)
+}
+
 fn insert_record(
     db: &ConnectionThreadSafe,
     rule_hash: &str,
@@ -448,22 +716,26 @@ pub async fn publish_log_batch(
 ) -> anyhow::Result<()> {
     let shaping = get_shaping();
 
-    let mut events = vec![];
+    let mut decisions = vec![];
 
     db.execute("BEGIN")?;
 
     let now = Utc::now();
 
     for record in records.drain(..) {
-        if let Err(err) = publish_log_v1_impl(&now, db, &shaping, record, &mut events).await {
+        if let Err(err) = publish_log_v1_impl(&now, db, &shaping, record, &mut decisions).await {
             tracing::error!("error processing record: {err:#}");
         }
     }
 
     db.execute("COMMIT")?;
 
-    for event in events {
-        SubscriberMgr::submit(event);
+    crate::redis_state::store(&decisions);
+
+    for decision in decisions {
+        if let Some(item) = decision.subscription_item() {
+            SubscriberMgr::submit(item);
+        }
     }
 
     Ok(())
@@ -474,7 +746,7 @@ async fn publish_log_v1_impl(
     db: &ConnectionThreadSafe,
     shaping: &Shaping,
     record: JsonLogRecord,
-    events: &mut Vec<SubscriptionItem>,
+    decisions: &mut Vec<Decision>,
 ) -> anyhow::Result<()> {
     tracing::trace!("got record: {record:?}");
     // Extract the domain from the recipient.
@@ -497,6 +769,7 @@ async fn publish_log_v1_impl(
 
     let matches = shaping.match_rules(&record).await?;
     let record_hash = sha256hex(&record)?;
+    let response = record.response.to_single_line();
 
     for m in &matches {
         let expires = record.timestamp + chrono::Duration::from_std(m.duration)?;
@@ -509,6 +782,15 @@ async fn publish_log_v1_impl(
 
         let rule_hash = format!("{store_key}-{m_hash}");
 
+        let satisfied = m.evaluate(&response, record.response_category.as_deref(), |rate| {
+            let condition_hash = format!("{rule_hash}-{}", hash_of(rate));
+            bounce_rate_reached(db, &condition_hash, rate, &record, &record_hash)
+        })?;
+        if !satisfied {
+            tracing::trace!("match={m:?} conditions not satisfied for {record:?}");
+            continue;
+        }
+
         let triggered = match m.trigger {
             Trigger::Immediate => true,
             Trigger::Threshold(spec) => {
@@ -523,6 +805,16 @@ async fn publish_log_v1_impl(
 
         tracing::trace!("match={m:?} triggered={triggered} for {record:?}");
 
+        if triggered && (m.dry_run || DRY_RUN.load(Ordering::Relaxed)) {
+            for action in &m.action {
+                record_dry_run_decision(
+                    db, shaping, &rule_hash, m, &record, action, &domain, source,
+                )
+                .await?;
+            }
+            continue;
+        }
+
         // To enact the action, we need to generate (or update) a row
         // in the db with its effects and its expiry
         if triggered {
@@ -530,7 +822,7 @@ async fn publish_log_v1_impl(
                 tracing::debug!("{action:?} for {record:?}");
                 match action {
                     Action::Suspend => {
-                        create_ready_q_suspension(db, &rule_hash, m, &record, &source, events)?;
+                        create_ready_q_suspension(db, &rule_hash, m, &record, &source, decisions)?;
                     }
                     Action::SuspendTenant => {
                         create_tenant_suspension(
@@ -539,7 +831,7 @@ async fn publish_log_v1_impl(
                             m,
                             &record,
                             UseCampaign::No,
-                            events,
+                            decisions,
                         )?;
                     }
                     Action::SuspendCampaign => {
@@ -549,7 +841,7 @@ async fn publish_log_v1_impl(
                             m,
                             &record,
                             UseCampaign::Yes,
-                            events,
+                            decisions,
                         )?;
                     }
                     Action::SetConfig(config) => {
@@ -562,6 +854,7 @@ async fn publish_log_v1_impl(
                             &domain,
                             &source,
                             PreferRollup::Yes,
+                            decisions,
                         )?;
                     }
                     Action::SetDomainConfig(config) => {
@@ -574,6 +867,7 @@ async fn publish_log_v1_impl(
                             &domain,
                             &source,
                             PreferRollup::No,
+                            decisions,
                         )?;
                     }
                     Action::Bounce => {
@@ -584,7 +878,7 @@ async fn publish_log_v1_impl(
                             &record,
                             UseTenant::No,
                             UseCampaign::No,
-                            events,
+                            decisions,
                         )?;
                     }
                     Action::BounceTenant => {
@@ -595,7 +889,7 @@ async fn publish_log_v1_impl(
                             &record,
                             UseTenant::Yes,
                             UseCampaign::No,
-                            events,
+                            decisions,
                         )?;
                     }
                     Action::BounceCampaign => {
@@ -606,9 +900,38 @@ async fn publish_log_v1_impl(
                             &record,
                             UseTenant::Yes,
                             UseCampaign::Yes,
-                            events,
+                            decisions,
                         )?;
                     }
+                    Action::ReduceRate { percent } => {
+                        match reduced_message_rate(shaping, &record, &domain, source, *percent)
+                            .await?
+                        {
+                            Some(config) => {
+                                create_config(
+                                    db,
+                                    &rule_hash,
+                                    m,
+                                    &record,
+                                    &config,
+                                    &domain,
+                                    &source,
+                                    PreferRollup::Yes,
+                                    decisions,
+                                )?;
+                            }
+                            None => {
+                                tracing::error!(
+                                    "Cannot reduce the rate for {m:?} because no \
+                                     max_message_rate is configured for {}",
+                                    record.site
+                                );
+                            }
+                        }
+                    }
+                    Action::SwitchPool(pool) => {
+                        create_pool_override(db, &rule_hash, m, &record, &domain, pool, decisions)?;
+                    }
                 }
             }
         }
@@ -654,14 +977,18 @@ impl std::hash::Hasher for Sha256Hasher {
     }
 }
 
-fn match_hash(m: &Rule) -> String {
+fn hash_of<T: Hash>(t: &T) -> String {
     let mut hasher = Sha256Hasher::new();
-    m.hash(&mut hasher);
+    t.hash(&mut hasher);
     hasher.get()
 }
 
+fn match_hash(m: &Rule) -> String {
+    hash_of(m)
+}
+
 async fn publish_log_v1(
-    _: TrustedIpRequired,
+    _: AdminRequired,
     // Note: Json<> must be last in the param list
     Json(record): Json<JsonLogRecord>,
 ) -> Result<(), AppError> {
@@ -762,13 +1089,44 @@ async fn do_get_config() -> anyhow::Result<String> {
         }
     }
 
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from pool_overrides where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by expires, domain",
+    )?;
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        num_entries += 1;
+        let reason: String = stmt.read("reason")?;
+        let domain: String = stmt.read("domain")?;
+        let pool: String = stmt.read("pool")?;
+        let expires: String = stmt.read("expires")?;
+
+        // If there is already an entry for this domain with mx_rollup
+        // enabled, the pool will apply to its whole site
+        let domain_entry = doc
+            .entry(&domain)
+            .or_insert_with(|| {
+                let mut tbl = toml_edit::Table::new();
+                tbl["mx_rollup"] = value(false);
+                Item::Table(tbl)
+            })
+            .as_table_mut()
+            .unwrap();
+        domain_entry.insert("egress_pool", value(pool));
+
+        if let Some(mut key) = domain_entry.key_mut("egress_pool") {
+            key.leaf_decor_mut()
+                .set_prefix(format!("# reason: {reason}\n# expires: {expires}\n"));
+        }
+    }
+
     Ok(format!(
         "# Generated by tsa-daemon\n# Number of entries: {num_entries}\n\n{}",
         doc.to_string()
     ))
 }
 
-async fn get_config_v1(_: TrustedIpRequired) -> Result<String, AppError> {
+async fn get_config_v1(_: AdminRequired) -> Result<String, AppError> {
     let result = do_get_config().await?;
     Ok(result)
 }
@@ -869,12 +1227,12 @@ async fn do_get_suspension() -> anyhow::Result<Json<Suspensions>> {
     Ok(Json(suspensions))
 }
 
-async fn get_suspension_v1(_: TrustedIpRequired) -> Result<Json<Suspensions>, AppError> {
+async fn get_suspension_v1(_: AdminRequired) -> Result<Json<Suspensions>, AppError> {
     let result = do_get_suspension().await?;
     Ok(result)
 }
 
-struct SubscriberMgr {
+pub struct SubscriberMgr {
     tx: Sender<SubscriptionItem>,
 }
 
@@ -933,10 +1291,7 @@ async fn process_suspension_subscription(socket: WebSocket) {
 
 /// This is a legacy endpoint that can only report on the old SuspensionEntry
 /// enum variants
-pub async fn subscribe_suspension_v1(
-    _: TrustedIpRequired,
-    ws: WebSocketUpgrade,
-) -> impl IntoResponse {
+pub async fn subscribe_suspension_v1(_: AdminRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
     ws.on_upgrade(|socket| process_suspension_subscription(socket))
 }
 
@@ -987,11 +1342,47 @@ async fn do_get_bounces() -> anyhow::Result<Json<Vec<SchedQBounce>>> {
     Ok(Json(bounces))
 }
 
-async fn get_bounce_v1(_: TrustedIpRequired) -> Result<Json<Vec<SchedQBounce>>, AppError> {
+async fn get_bounce_v1(_: AdminRequired) -> Result<Json<Vec<SchedQBounce>>, AppError> {
     let result = do_get_bounces().await?;
     Ok(result)
 }
 
+async fn do_get_dry_run_decisions() -> anyhow::Result<Json<Vec<DryRunDecision>>> {
+    let mut stmt = HISTORY.prepare(
+        "SELECT * from dry_run_decisions where
+                                   unixepoch(expires) - unixepoch() > 0
+                                   order by first_triggered, rule_hash, action",
+    )?;
+
+    let mut result = vec![];
+    while let Ok(sqlite::State::Row) = stmt.next() {
+        let rule_hash: String = stmt.read("rule_hash")?;
+        let action: String = stmt.read("action")?;
+        let reason: String = stmt.read("reason")?;
+        let first_triggered: String = stmt.read("first_triggered")?;
+        let last_triggered: String = stmt.read("last_triggered")?;
+        let count: i64 = stmt.read("count")?;
+        let expires: String = stmt.read("expires")?;
+
+        result.push(DryRunDecision {
+            rule_hash,
+            action,
+            reason,
+            first_triggered: DateTime::parse_from_rfc3339(&first_triggered)?.to_utc(),
+            last_triggered: DateTime::parse_from_rfc3339(&last_triggered)?.to_utc(),
+            count: count as u64,
+            expires: DateTime::parse_from_rfc3339(&expires)?.to_utc(),
+        });
+    }
+
+    Ok(Json(result))
+}
+
+async fn get_dry_run_decisions_v1(_: AdminRequired) -> Result<Json<Vec<DryRunDecision>>, AppError> {
+    let result = do_get_dry_run_decisions().await?;
+    Ok(result)
+}
+
 async fn process_event_subscription_inner(mut socket: WebSocket) -> anyhow::Result<()> {
     let mut rx = SUSPENSION_TX.tx.subscribe();
 
@@ -1030,6 +1421,6 @@ async fn process_event_subscription(socket: WebSocket) {
     }
 }
 
-pub async fn subscribe_event_v1(_: TrustedIpRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
+pub async fn subscribe_event_v1(_: AdminRequired, ws: WebSocketUpgrade) -> impl IntoResponse {
     ws.on_upgrade(|socket| process_event_subscription(socket))
 }
@@ -9,6 +9,7 @@ use std::path::PathBuf;
 mod http_server;
 mod mod_auto;
 mod publish;
+mod redis_state;
 mod shaping_config;
 
 /// KumoMTA Traffic Shaping Automation Daemon.
@@ -1,3 +1,4 @@
+use crate::redis_state::RedisStateParams;
 use config::{any_err, from_lua_value, get_or_create_module};
 use kumo_server_common::http_server::HttpListenerParams;
 use kumo_server_runtime::get_main_runtime;
@@ -26,5 +27,24 @@ pub fn register(lua: &Lua) -> anyhow::Result<()> {
         })?,
     )?;
 
+    tsa_mod.set(
+        "configure_dry_run",
+        lua.create_function(|_lua, dry_run: bool| {
+            crate::http_server::DRY_RUN.store(dry_run, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        })?,
+    )?;
+
+    tsa_mod.set(
+        "configure_redis",
+        lua.create_async_function(|lua, params: Value| async move {
+            let params: RedisStateParams = from_lua_value(&lua, params)?;
+            crate::redis_state::configure(params)
+                .await
+                .map_err(any_err)?;
+            Ok(())
+        })?,
+    )?;
+
     Ok(())
 }
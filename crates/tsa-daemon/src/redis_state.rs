@@ -0,0 +1,384 @@
+//! Shares the decisions made by the tsa-daemon via redis, so that
+//! they survive a restart of the daemon, and so that several daemons
+//! can coordinate with each other.
+//!
+//! Each decision is stored as a field of a redis hash, keyed by the
+//! table and primary key of the corresponding row in the local
+//! sqlite database. Each daemon writes its own decisions to the
+//! hash as they are made, and periodically merges the contents of
+//! the hash into its local database, notifying its subscribers of
+//! any decisions that were made by its peers.
+use crate::http_server::{open_history_db, SubscriberMgr};
+use chrono::{DateTime, Utc};
+use kumo_api_types::tsa::{ReadyQSuspension, SchedQBounce, SchedQSuspension, SubscriptionItem};
+use kumo_server_runtime::get_main_runtime;
+use mod_redis::{cmd, FromRedisValue, RedisConnKey, RedisConnection};
+use serde::{Deserialize, Serialize};
+use sqlite::{ConnectionThreadSafe, Value};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static REDIS: OnceLock<RedisState> = OnceLock::new();
+
+#[derive(Deserialize, Debug)]
+pub struct RedisStateParams {
+    #[serde(flatten)]
+    pub redis: RedisConnKey,
+
+    /// The name of the redis hash in which the decisions are stored
+    #[serde(default = "RedisStateParams::default_key")]
+    pub key: String,
+
+    /// How often to merge the decisions made by other daemons
+    #[serde(
+        default = "RedisStateParams::default_sync_interval",
+        with = "duration_serde"
+    )]
+    pub sync_interval: Duration,
+}
+
+impl RedisStateParams {
+    fn default_key() -> String {
+        "kumo-tsa-decisions".to_string()
+    }
+
+    fn default_sync_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+struct RedisState {
+    conn: RedisConnection,
+    key: String,
+}
+
+/// A row in one of the tables of the local database that
+/// records a decision made by an automation rule
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConfigOverride {
+    pub rule_hash: String,
+    pub site_name: String,
+    pub domain: String,
+    pub mx_rollup: bool,
+    pub source: String,
+    pub name: String,
+    /// The value, encoded as JSON
+    pub value: String,
+    pub reason: String,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PoolOverride {
+    pub rule_hash: String,
+    pub domain: String,
+    pub pool: String,
+    pub reason: String,
+    pub expires: DateTime<Utc>,
+}
+
+/// A decision made by an automation rule
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Decision {
+    ReadyQSuspension(ReadyQSuspension),
+    SchedQSuspension(SchedQSuspension),
+    SchedQBounce(SchedQBounce),
+    Config(ConfigOverride),
+    PoolOverride(PoolOverride),
+}
+
+/// Describes how a Decision is stored in the local database
+struct Row {
+    table: &'static str,
+    keys: Vec<(&'static str, Value)>,
+    values: Vec<(&'static str, Value)>,
+}
+
+fn text(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn optional_text(s: &Option<String>) -> Value {
+    match s {
+        Some(s) => text(s),
+        None => Value::Null,
+    }
+}
+
+impl Decision {
+    /// Returns the item that should be sent to subscribers to
+    /// announce this decision, if any
+    pub fn subscription_item(&self) -> Option<SubscriptionItem> {
+        match self {
+            Self::ReadyQSuspension(s) => Some(SubscriptionItem::ReadyQSuspension(s.clone())),
+            Self::SchedQSuspension(s) => Some(SubscriptionItem::SchedQSuspension(s.clone())),
+            Self::SchedQBounce(b) => Some(SubscriptionItem::SchedQBounce(b.clone())),
+            Self::Config(_) | Self::PoolOverride(_) => None,
+        }
+    }
+
+    fn expires(&self) -> DateTime<Utc> {
+        match self {
+            Self::ReadyQSuspension(s) => s.expires,
+            Self::SchedQSuspension(s) => s.expires,
+            Self::SchedQBounce(b) => b.expires,
+            Self::Config(c) => c.expires,
+            Self::PoolOverride(p) => p.expires,
+        }
+    }
+
+    fn row(&self) -> Row {
+        match self {
+            Self::ReadyQSuspension(s) => Row {
+                table: "ready_q_suspensions",
+                keys: vec![
+                    ("rule_hash", text(&s.rule_hash)),
+                    ("site_name", text(&s.site_name)),
+                ],
+                values: vec![("reason", text(&s.reason)), ("source", text(&s.source))],
+            },
+            Self::SchedQSuspension(s) => Row {
+                table: "sched_q_suspensions",
+                keys: vec![
+                    ("rule_hash", text(&s.rule_hash)),
+                    ("campaign", optional_text(&s.campaign)),
+                    ("tenant", text(&s.tenant)),
+                    ("domain", text(&s.domain)),
+                ],
+                values: vec![("reason", text(&s.reason))],
+            },
+            Self::SchedQBounce(b) => Row {
+                table: "sched_q_bounces",
+                keys: vec![
+                    ("rule_hash", text(&b.rule_hash)),
+                    ("campaign", optional_text(&b.campaign)),
+                    ("tenant", optional_text(&b.tenant)),
+                    ("domain", text(&b.domain)),
+                ],
+                values: vec![("reason", text(&b.reason))],
+            },
+            Self::Config(c) => Row {
+                table: "config",
+                keys: vec![
+                    ("rule_hash", text(&c.rule_hash)),
+                    ("site_name", text(&c.site_name)),
+                ],
+                values: vec![
+                    ("domain", text(&c.domain)),
+                    ("mx_rollup", Value::Integer(c.mx_rollup as i64)),
+                    ("source", text(&c.source)),
+                    ("name", text(&c.name)),
+                    ("value", text(&c.value)),
+                    ("reason", text(&c.reason)),
+                ],
+            },
+            Self::PoolOverride(p) => Row {
+                table: "pool_overrides",
+                keys: vec![
+                    ("rule_hash", text(&p.rule_hash)),
+                    ("domain", text(&p.domain)),
+                ],
+                values: vec![("pool", text(&p.pool)), ("reason", text(&p.reason))],
+            },
+        }
+    }
+
+    /// The name of the field of the redis hash that holds this decision
+    fn field(&self) -> String {
+        let row = self.row();
+        let mut field = row.table.to_string();
+        for (_, value) in &row.keys {
+            field.push(':');
+            if let Value::String(s) = value {
+                field.push_str(s);
+            }
+        }
+        field
+    }
+
+    /// Merges the decision into the local database, returning true if
+    /// it was not already present, or if it extended the expiration
+    /// of an existing entry
+    fn merge_into(&self, db: &ConnectionThreadSafe) -> anyhow::Result<bool> {
+        let row = self.row();
+        let expires = self.expires().to_rfc3339();
+
+        // Null values in the keys are distinct from each other as far
+        // as the primary key is concerned, so we can't rely on
+        // ON CONFLICT and match using IS instead
+        let matcher = row
+            .keys
+            .iter()
+            .map(|(column, _)| format!("{column} IS ${column}"))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+
+        let mut select = db.prepare(format!(
+            "SELECT unixepoch(expires) FROM {} WHERE {matcher}",
+            row.table
+        ))?;
+        for (column, value) in &row.keys {
+            select.bind((format!("${column}").as_str(), value.clone()))?;
+        }
+        let existing: Option<i64> = match select.next()? {
+            sqlite::State::Row => Some(select.read(0)?),
+            sqlite::State::Done => None,
+        };
+
+        match existing {
+            Some(existing) if existing >= self.expires().timestamp() => Ok(false),
+            Some(_) => {
+                let mut update = db.prepare(format!(
+                    "UPDATE {} SET expires=$expires WHERE {matcher}",
+                    row.table
+                ))?;
+                for (column, value) in &row.keys {
+                    update.bind((format!("${column}").as_str(), value.clone()))?;
+                }
+                update.bind(("$expires", expires.as_str()))?;
+                update.next()?;
+                Ok(true)
+            }
+            None => {
+                let columns: Vec<&str> = row
+                    .keys
+                    .iter()
+                    .chain(row.values.iter())
+                    .map(|(column, _)| *column)
+                    .collect();
+                let mut insert = db.prepare(format!(
+                    "INSERT INTO {} ({}, expires) VALUES ({}, $expires)",
+                    row.table,
+                    columns.join(", "),
+                    columns
+                        .iter()
+                        .map(|column| format!("${column}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))?;
+                for (column, value) in row.keys.iter().chain(row.values.iter()) {
+                    insert.bind((format!("${column}").as_str(), value.clone()))?;
+                }
+                insert.bind(("$expires", expires.as_str()))?;
+                insert.next()?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Configures the use of redis, loads the decisions that are
+/// already present in redis into the local database, and starts
+/// periodically merging the decisions made by other daemons.
+pub async fn configure(params: RedisStateParams) -> anyhow::Result<()> {
+    let conn = params.redis.open()?;
+    conn.ping().await?;
+
+    let state = RedisState {
+        conn,
+        key: params.key,
+    };
+    anyhow::ensure!(
+        REDIS.set(state).is_ok(),
+        "kumo.tsa.configure_redis has already been called"
+    );
+
+    let db = open_history_db()?;
+    sync(&db).await?;
+
+    let interval = params.sync_interval;
+    get_main_runtime().spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = sync(&db).await {
+                tracing::error!("error syncing decisions from redis: {err:#}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Merges the decisions that are stored in redis into the local database,
+/// and removes any expired decisions from redis
+async fn sync(db: &ConnectionThreadSafe) -> anyhow::Result<()> {
+    let Some(state) = REDIS.get() else {
+        return Ok(());
+    };
+
+    let mut hgetall = cmd("HGETALL");
+    hgetall.arg(&state.key);
+    let value = state.conn.query(hgetall).await?;
+    let entries: HashMap<String, String> = FromRedisValue::from_redis_value(&value)?;
+
+    let now = Utc::now();
+    let mut expired = vec![];
+    let mut changed = vec![];
+
+    db.execute("BEGIN")?;
+    for (field, json) in entries {
+        let decision: Decision = match serde_json::from_str(&json) {
+            Ok(decision) => decision,
+            Err(err) => {
+                tracing::error!("ignoring invalid decision {field} in redis: {err:#}");
+                continue;
+            }
+        };
+        if decision.expires() <= now {
+            expired.push(field);
+            continue;
+        }
+        match decision.merge_into(db) {
+            Ok(true) => changed.push(decision),
+            Ok(false) => {}
+            Err(err) => tracing::error!("error merging decision {field}: {err:#}"),
+        }
+    }
+    db.execute("COMMIT")?;
+
+    for decision in changed {
+        if let Some(item) = decision.subscription_item() {
+            SubscriberMgr::submit(item);
+        }
+    }
+
+    if !expired.is_empty() {
+        let mut hdel = cmd("HDEL");
+        hdel.arg(&state.key).arg(expired);
+        state.conn.query(hdel).await?;
+    }
+
+    Ok(())
+}
+
+/// Writes the decisions to redis, if it has been configured.
+/// The write happens in the background on the main runtime,
+/// and failures are logged rather than reported to the caller.
+pub fn store(decisions: &[Decision]) {
+    let Some(state) = REDIS.get() else {
+        return;
+    };
+    if decisions.is_empty() {
+        return;
+    }
+
+    let mut hset = cmd("HSET");
+    hset.arg(&state.key);
+    for decision in decisions {
+        match serde_json::to_string(decision) {
+            Ok(json) => {
+                hset.arg(decision.field()).arg(json);
+            }
+            Err(err) => {
+                tracing::error!("error serializing {decision:?}: {err:#}");
+            }
+        }
+    }
+
+    get_main_runtime().spawn(async move {
+        if let Err(err) = state.conn.query(hset).await {
+            tracing::error!("error storing decisions in redis: {err:#}");
+        }
+    });
+}
@@ -381,6 +381,10 @@ TOC = [
                 "module: kumo.dns",
                 "reference/kumo.dns",
             ),
+            Gen(
+                "module: kumo.dnsbl",
+                "reference/kumo.dnsbl",
+            ),
             Gen(
                 "module: kumo.encode",
                 "reference/kumo.encode",
@@ -397,14 +401,30 @@ TOC = [
                 "module: kumo.http",
                 "reference/kumo.http",
             ),
+            Gen(
+                "module: kumo.jsonschema",
+                "reference/kumo.jsonschema",
+            ),
             Gen(
                 "module: kumo.kafka",
                 "reference/kumo.kafka",
             ),
+            Gen(
+                "module: kumo.ldap",
+                "reference/kumo.ldap",
+            ),
+            Gen(
+                "module: kumo.memcached",
+                "reference/kumo.memcached",
+            ),
             Gen(
                 "module: kumo.regex_set_map",
                 "reference/kumo.regex_set_map",
             ),
+            Gen(
+                "module: kumo.reputation",
+                "reference/kumo.reputation",
+            ),
             Gen(
                 "module: kumo.secrets",
                 "reference/kumo.secrets",